@@ -8,6 +8,9 @@ use std::env;
 use std::path::PathBuf;
 
 fn main() {
+    // Enabled by the PyOxidizer config through the artifacts' cargo metadata.
+    println!("cargo:rustc-check-cfg=cfg(pyembed_runtime_code_override)");
+
     // We support using pre-built artifacts, in which case we emit the
     // cargo metadata lines from the "original" build to "register" the
     // artifacts with this cargo invocation.
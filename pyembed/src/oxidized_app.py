@@ -0,0 +1 @@
+../../pyoxidizer/src/pyembed/oxidized_app.py
\ No newline at end of file
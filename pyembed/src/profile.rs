@@ -0,0 +1 @@
+../../pyoxidizer/src/pyembed/profile.rs
\ No newline at end of file
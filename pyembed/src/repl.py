@@ -0,0 +1 @@
+../../pyoxidizer/src/pyembed/repl.py
\ No newline at end of file
@@ -0,0 +1 @@
+../../pyoxidizer/src/pyembed/shared.rs
\ No newline at end of file
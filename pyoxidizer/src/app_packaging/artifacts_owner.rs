@@ -0,0 +1,280 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/*!
+Which PyOxidizer version owns an artifacts directory.
+
+Artifacts generated by one PyOxidizer version can't be reused by another.
+If two versions build the same project, e.g. an older one on a developer
+machine and a newer one in CI, they would regenerate the artifacts on every
+build and could mix each other's files.
+
+So the first version generating artifacts into a directory records itself
+in `pyoxidizer-owner.json` there. Other versions generate their artifacts
+into a `pyoxidizer-<version>` subdirectory instead. Writes are serialized
+with a lock file in the artifacts directory.
+*/
+
+use anyhow::{Context, Result};
+use fs2::FileExt;
+use serde::{Deserialize, Serialize};
+use slog::warn;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+use crate::environment::BUILD_SEMVER;
+
+/// File in an artifacts directory recording the version owning it.
+pub const OWNER_FILENAME: &str = "pyoxidizer-owner.json";
+
+/// File in an artifacts directory locked while artifacts are written.
+pub const LOCK_FILENAME: &str = "pyoxidizer.lock";
+
+/// Prefix of subdirectories holding artifacts of versions not owning the
+/// artifacts directory.
+pub const VERSION_DIR_PREFIX: &str = "pyoxidizer-";
+
+/// The version artifacts are generated by.
+pub fn artifacts_version() -> &'static str {
+    BUILD_SEMVER
+}
+
+/// Records the version owning an artifacts directory.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct ArtifactsOwner {
+    pub pyoxidizer_version: String,
+}
+
+impl ArtifactsOwner {
+    /// Read the owner of an artifacts directory, if it has one.
+    pub fn from_artifacts_path(artifacts_path: &Path) -> Result<Option<Self>> {
+        let path = artifacts_path.join(OWNER_FILENAME);
+
+        if !path.exists() {
+            return Ok(None);
+        }
+
+        let data = std::fs::read(&path).with_context(|| format!("reading {}", path.display()))?;
+        let owner =
+            serde_json::from_slice(&data).with_context(|| format!("parsing {}", path.display()))?;
+
+        Ok(Some(owner))
+    }
+
+    pub fn write(&self, artifacts_path: &Path) -> Result<()> {
+        let path = artifacts_path.join(OWNER_FILENAME);
+
+        std::fs::write(&path, serde_json::to_vec_pretty(self)?)
+            .with_context(|| format!("writing {}", path.display()))
+    }
+}
+
+/// Name of the subdirectory holding artifacts of a version.
+pub fn version_dir_name(version: &str) -> String {
+    let version = version
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '.' || c == '-' || c == '_' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect::<String>();
+
+    format!("{}{}", VERSION_DIR_PREFIX, version)
+}
+
+/// Whether a file name in an artifacts directory is managed by this module.
+///
+/// Such entries belong to the artifacts directory rather than to the
+/// artifacts of its owner.
+pub fn is_ownership_entry(name: &str) -> bool {
+    name == OWNER_FILENAME || name == LOCK_FILENAME || name.starts_with(VERSION_DIR_PREFIX)
+}
+
+/// Resolve where a version reads and writes artifacts, without claiming it.
+///
+/// This is `artifacts_path` unless another version owns it.
+pub fn versioned_artifacts_path(artifacts_path: &Path, version: &str) -> Result<PathBuf> {
+    match ArtifactsOwner::from_artifacts_path(artifacts_path)? {
+        Some(ref owner) if owner.pyoxidizer_version != version => {
+            Ok(artifacts_path.join(version_dir_name(version)))
+        }
+        _ => Ok(artifacts_path.to_path_buf()),
+    }
+}
+
+/// A lock on an artifacts directory, released when dropped.
+pub struct ArtifactsLock {
+    _file: File,
+}
+
+/// Lock an artifacts directory, waiting for other processes holding it.
+pub fn lock_artifacts_path(logger: &slog::Logger, artifacts_path: &Path) -> Result<ArtifactsLock> {
+    std::fs::create_dir_all(artifacts_path)
+        .with_context(|| format!("creating {}", artifacts_path.display()))?;
+
+    let lock_path = artifacts_path.join(LOCK_FILENAME);
+    let file =
+        File::create(&lock_path).with_context(|| format!("creating {}", lock_path.display()))?;
+
+    if file.try_lock_exclusive().is_err() {
+        warn!(
+            logger,
+            "waiting for another process writing artifacts in {}",
+            artifacts_path.display()
+        );
+        file.lock_exclusive()
+            .with_context(|| format!("locking {}", lock_path.display()))?;
+    }
+
+    Ok(ArtifactsLock { _file: file })
+}
+
+/// Claim the directory a version writes artifacts into.
+///
+/// `artifacts_path` is claimed if no version owns it yet. Artifacts written
+/// before owners were recorded are claimed as well, since checking whether
+/// artifacts are current regenerates them if needed. Otherwise, the
+/// version's subdirectory is claimed. The lock on `artifacts_path` must be
+/// held.
+pub fn claim_artifacts_path(
+    logger: &slog::Logger,
+    artifacts_path: &Path,
+    version: &str,
+) -> Result<PathBuf> {
+    let path = versioned_artifacts_path(artifacts_path, version)?;
+
+    if path != artifacts_path {
+        warn!(
+            logger,
+            "artifacts in {} belong to another PyOxidizer version; using {}",
+            artifacts_path.display(),
+            path.display()
+        );
+    }
+
+    std::fs::create_dir_all(&path).with_context(|| format!("creating {}", path.display()))?;
+
+    if ArtifactsOwner::from_artifacts_path(&path)?.is_none() {
+        ArtifactsOwner {
+            pyoxidizer_version: version.to_string(),
+        }
+        .write(&path)?;
+    }
+
+    Ok(path)
+}
+
+/// Find the subdirectories holding artifacts of versions not owning `artifacts_path`.
+pub fn version_dirs(artifacts_path: &Path) -> Result<Vec<PathBuf>> {
+    if !artifacts_path.is_dir() {
+        return Ok(Vec::new());
+    }
+
+    let mut res = Vec::new();
+
+    for entry in std::fs::read_dir(artifacts_path)
+        .with_context(|| format!("reading {}", artifacts_path.display()))?
+    {
+        let path = entry?.path();
+        let is_version_dir = path.file_name().map_or(false, |name| {
+            name.to_string_lossy().starts_with(VERSION_DIR_PREFIX)
+        });
+
+        if is_version_dir && path.is_dir() {
+            res.push(path);
+        }
+    }
+
+    res.sort();
+
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::*;
+
+    #[test]
+    fn test_version_dir_name() {
+        assert_eq!(version_dir_name("0.5.0"), "pyoxidizer-0.5.0");
+        assert_eq!(
+            version_dir_name("0.5.0-pre+abc/def"),
+            "pyoxidizer-0.5.0-pre_abc_def"
+        );
+    }
+
+    #[test]
+    fn test_alternating_versions() -> Result<()> {
+        let logger = get_logger()?;
+        let temp_dir = tempdir::TempDir::new("pyoxidizer-test")?;
+        let artifacts = temp_dir.path().join("pyoxidizer");
+
+        // Nothing owns the directory, so it's used as is.
+        assert_eq!(versioned_artifacts_path(&artifacts, "0.5.0")?, artifacts);
+
+        let claim = |version: &str| -> Result<PathBuf> {
+            let _lock = lock_artifacts_path(&logger, &artifacts)?;
+            claim_artifacts_path(&logger, &artifacts, version)
+        };
+
+        assert_eq!(claim("0.5.0")?, artifacts);
+        assert_eq!(
+            ArtifactsOwner::from_artifacts_path(&artifacts)?,
+            Some(ArtifactsOwner {
+                pyoxidizer_version: "0.5.0".to_string()
+            })
+        );
+
+        // Other versions get their own subdirectory and keep it.
+        let newer = artifacts.join("pyoxidizer-0.6.0");
+        assert_eq!(claim("0.6.0")?, newer);
+        assert_eq!(claim("0.5.0")?, artifacts);
+        assert_eq!(claim("0.6.0")?, newer);
+        assert_eq!(versioned_artifacts_path(&artifacts, "0.6.0")?, newer);
+        assert_eq!(
+            ArtifactsOwner::from_artifacts_path(&newer)?
+                .unwrap()
+                .pyoxidizer_version,
+            "0.6.0"
+        );
+
+        // The owner doesn't change once recorded.
+        assert_eq!(
+            ArtifactsOwner::from_artifacts_path(&artifacts)?
+                .unwrap()
+                .pyoxidizer_version,
+            "0.5.0"
+        );
+
+        claim("0.7.0")?;
+        assert_eq!(
+            version_dirs(&artifacts)?,
+            vec![newer, artifacts.join("pyoxidizer-0.7.0")]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_legacy_artifacts_claimed() -> Result<()> {
+        let logger = get_logger()?;
+        let temp_dir = tempdir::TempDir::new("pyoxidizer-test")?;
+        let artifacts = temp_dir.path().join("pyoxidizer");
+        std::fs::create_dir_all(&artifacts)?;
+        std::fs::write(artifacts.join("cargo_metadata.txt"), "")?;
+
+        let _lock = lock_artifacts_path(&logger, &artifacts)?;
+        assert_eq!(
+            claim_artifacts_path(&logger, &artifacts, "0.6.0")?,
+            artifacts
+        );
+        assert!(version_dirs(&artifacts)?.is_empty());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,277 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/*!
+Matching the config's `application_name` to the project's bin targets.
+
+Applications are built with `cargo build --bin <application_name>` and
+packaged by looking for an executable of that name. If the bin target is
+renamed in `Cargo.toml` or the config without updating the other, builds
+fail with errors not mentioning the mismatch. So the name is validated
+against the bin targets of the project before building.
+
+`application_name="auto"` adopts the crate's bin target if it has exactly
+one.
+*/
+
+use anyhow::{anyhow, Result};
+use std::collections::BTreeSet;
+use std::path::Path;
+
+/// `application_name` value adopting the crate's sole bin target.
+pub const AUTO_APPLICATION_NAME: &str = "auto";
+
+/// Obtain the names of the bin targets of a crate.
+///
+/// Includes `[[bin]]` targets plus the targets Cargo discovers in `src/`
+/// unless `autobins = false`.
+pub fn bin_target_names(manifest: &cargo_toml::Manifest, project_path: &Path) -> BTreeSet<String> {
+    let package = manifest.package.as_ref();
+    let package_name = package.map(|p| p.name.clone());
+
+    let mut names = manifest
+        .bin
+        .iter()
+        .filter_map(|bin| bin.name.clone().or_else(|| package_name.clone()))
+        .collect::<BTreeSet<_>>();
+
+    // Cargo doesn't discover targets at paths used by `[[bin]]` targets.
+    let explicit_paths = manifest
+        .bin
+        .iter()
+        .filter_map(|bin| bin.path.as_ref())
+        .map(|p| p.replace('\\', "/").trim_start_matches("./").to_string())
+        .collect::<BTreeSet<_>>();
+
+    let mut discovered = Vec::new();
+
+    if let Some(package) = package {
+        if !package.autobins {
+            return names;
+        }
+
+        let src = project_path.join("src");
+
+        if src.join("main.rs").is_file() {
+            discovered.push((package.name.clone(), "src/main.rs".to_string()));
+        }
+
+        if let Ok(entries) = std::fs::read_dir(src.join("bin")) {
+            for entry in entries.filter_map(|e| e.ok()) {
+                let path = entry.path();
+                let file_name = entry.file_name().to_string_lossy().to_string();
+
+                if path.extension().map_or(false, |e| e == "rs") {
+                    if let Some(stem) = path.file_stem() {
+                        discovered.push((
+                            stem.to_string_lossy().to_string(),
+                            format!("src/bin/{}", file_name),
+                        ));
+                    }
+                } else if path.join("main.rs").is_file() {
+                    discovered.push((file_name.clone(), format!("src/bin/{}/main.rs", file_name)));
+                }
+            }
+        }
+    }
+
+    names.extend(
+        discovered
+            .into_iter()
+            .filter(|(_, path)| !explicit_paths.contains(path))
+            .map(|(name, _)| name),
+    );
+
+    names
+}
+
+fn describe_names(names: &BTreeSet<String>) -> String {
+    names
+        .iter()
+        .map(|n| format!("\"{}\"", n))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Resolve the name of the bin target building the application.
+///
+/// `application_name` is the config's value. Errors name both the config
+/// file and `Cargo.toml` so the user can fix whichever is wrong.
+pub fn resolve_application_name(
+    application_name: &str,
+    bin_names: &BTreeSet<String>,
+    config_path: &Path,
+    cargo_toml_path: &Path,
+) -> Result<String> {
+    if application_name == AUTO_APPLICATION_NAME {
+        return match bin_names.len() {
+            1 => Ok(bin_names.iter().next().unwrap().clone()),
+            0 => Err(anyhow!(
+                "application_name=\"{}\" in {} requires a bin target but {} defines none",
+                AUTO_APPLICATION_NAME,
+                config_path.display(),
+                cargo_toml_path.display()
+            )),
+            _ => Err(anyhow!(
+                "application_name=\"{}\" in {} requires a single bin target but {} defines {}; \
+                 set application_name to the one to build",
+                AUTO_APPLICATION_NAME,
+                config_path.display(),
+                cargo_toml_path.display(),
+                describe_names(bin_names)
+            )),
+        };
+    }
+
+    if bin_names.contains(application_name) {
+        return Ok(application_name.to_string());
+    }
+
+    if bin_names.is_empty() {
+        return Err(anyhow!(
+            "application_name \"{}\" in {} has no matching bin target: {} defines no bin targets",
+            application_name,
+            config_path.display(),
+            cargo_toml_path.display()
+        ));
+    }
+
+    Err(anyhow!(
+        "application_name \"{}\" in {} does not match a bin target of {}, which defines {}; \
+         update application_name in {} or rename the bin target in {}",
+        application_name,
+        config_path.display(),
+        cargo_toml_path.display(),
+        describe_names(bin_names),
+        config_path.display(),
+        cargo_toml_path.display()
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn write_project(path: &Path, cargo_toml: &str, sources: &[&str]) -> Result<()> {
+        fs::write(path.join("Cargo.toml"), cargo_toml)?;
+
+        for source in sources {
+            let source = path.join(source);
+            fs::create_dir_all(source.parent().unwrap())?;
+            fs::write(source, "fn main() {}\n")?;
+        }
+
+        Ok(())
+    }
+
+    fn names(project_path: &Path) -> Result<BTreeSet<String>> {
+        let manifest =
+            cargo_toml::Manifest::from_slice(&fs::read(project_path.join("Cargo.toml"))?)?;
+
+        Ok(bin_target_names(&manifest, project_path))
+    }
+
+    fn resolve(application_name: &str, project_path: &Path) -> Result<String> {
+        resolve_application_name(
+            application_name,
+            &names(project_path)?,
+            Path::new("pyoxidizer.bzl"),
+            Path::new("Cargo.toml"),
+        )
+    }
+
+    #[test]
+    fn test_bin_target_names() -> Result<()> {
+        let temp_dir = tempdir::TempDir::new("pyoxidizer-test")?;
+        let path = temp_dir.path();
+
+        write_project(
+            path,
+            "[package]\nname = \"myapp\"\nversion = \"0.1.0\"\n\n[[bin]]\nname = \"tool\"\npath = \"tool.rs\"\n",
+            &["src/main.rs", "src/bin/worker.rs", "src/bin/admin/main.rs", "tool.rs"],
+        )?;
+
+        assert_eq!(
+            names(path)?.into_iter().collect::<Vec<_>>(),
+            vec!["admin", "myapp", "tool", "worker"]
+        );
+
+        write_project(
+            path,
+            "[package]\nname = \"myapp\"\nversion = \"0.1.0\"\nautobins = false\n\n[[bin]]\nname = \"tool\"\npath = \"tool.rs\"\n",
+            &[],
+        )?;
+        assert_eq!(names(path)?.into_iter().collect::<Vec<_>>(), vec!["tool"]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_renamed_in_cargo_toml() -> Result<()> {
+        let temp_dir = tempdir::TempDir::new("pyoxidizer-test")?;
+        let path = temp_dir.path();
+
+        write_project(
+            path,
+            "[package]\nname = \"myapp\"\nversion = \"0.1.0\"\n\n[[bin]]\nname = \"renamed\"\npath = \"src/main.rs\"\n",
+            &["src/main.rs"],
+        )?;
+
+        let err = resolve("myapp", path).unwrap_err().to_string();
+        assert!(err.contains("application_name \"myapp\" in pyoxidizer.bzl"));
+        assert!(err.contains("which defines \"renamed\""));
+        assert!(err.contains("rename the bin target in Cargo.toml"));
+
+        assert_eq!(resolve("renamed", path)?, "renamed");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_renamed_in_config() -> Result<()> {
+        let temp_dir = tempdir::TempDir::new("pyoxidizer-test")?;
+        let path = temp_dir.path();
+
+        write_project(
+            path,
+            "[package]\nname = \"myapp\"\nversion = \"0.1.0\"\n",
+            &["src/main.rs"],
+        )?;
+
+        let err = resolve("newname", path).unwrap_err().to_string();
+        assert!(err.contains("application_name \"newname\" in pyoxidizer.bzl"));
+        assert!(err.contains("which defines \"myapp\""));
+        assert!(err.contains("update application_name in pyoxidizer.bzl"));
+
+        assert_eq!(resolve("myapp", path)?, "myapp");
+        assert_eq!(resolve("auto", path)?, "myapp");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_auto_ambiguous() -> Result<()> {
+        let temp_dir = tempdir::TempDir::new("pyoxidizer-test")?;
+        let path = temp_dir.path();
+
+        write_project(path, "[workspace]\nmembers = [\"a\"]\n", &[])?;
+        assert!(resolve("auto", path)
+            .unwrap_err()
+            .to_string()
+            .contains("defines none"));
+
+        write_project(
+            path,
+            "[package]\nname = \"myapp\"\nversion = \"0.1.0\"\n",
+            &["src/main.rs", "src/bin/worker.rs"],
+        )?;
+        assert!(resolve("auto", path)
+            .unwrap_err()
+            .to_string()
+            .contains("defines \"myapp\", \"worker\"; set application_name"));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,173 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/*!
+Build variables parameterizing a configuration.
+
+Build variables are given with `--var NAME=VALUE` or by environment
+variables named `PYOXIDIZER_VAR_<NAME>`, e.g. to select the files of one
+customer. Builds define the environment variables of `--var` for cargo, so
+PyOxidizer running from the build script sees the same variables. Configs
+read them from `BUILD_VARS`, and some rules substitute `{NAME}` in paths
+with them.
+*/
+
+use anyhow::{anyhow, Result};
+use std::collections::BTreeMap;
+
+/// Prefix of environment variables defining build variables.
+pub const BUILD_VAR_ENV_PREFIX: &str = "PYOXIDIZER_VAR_";
+
+/// Whether a string is a valid build variable name.
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && !name.starts_with(|c: char| c.is_ascii_digit())
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Parse a `NAME=VALUE` build variable definition.
+pub fn parse_build_var(definition: &str) -> Result<(String, String)> {
+    let mut parts = definition.splitn(2, '=');
+    let name = parts.next().unwrap_or("");
+    let value = parts
+        .next()
+        .ok_or_else(|| anyhow!("{} is not of the form NAME=VALUE", definition))?;
+
+    if !is_valid_name(name) {
+        return Err(anyhow!(
+            "{} is not a valid build variable name; use letters, digits and _",
+            name
+        ));
+    }
+
+    Ok((name.to_string(), value.to_string()))
+}
+
+/// Name of the environment variable defining a build variable.
+pub fn build_var_env(name: &str) -> String {
+    format!("{}{}", BUILD_VAR_ENV_PREFIX, name)
+}
+
+/// Obtain the build variables defined in the environment.
+pub fn build_vars() -> BTreeMap<String, String> {
+    std::env::vars()
+        .filter_map(|(key, value)| {
+            if key.starts_with(BUILD_VAR_ENV_PREFIX) {
+                let name = &key[BUILD_VAR_ENV_PREFIX.len()..];
+
+                if is_valid_name(name) {
+                    return Some((name.to_string(), value));
+                }
+            }
+
+            None
+        })
+        .collect()
+}
+
+/// Replace `{NAME}` in a template with values of variables.
+///
+/// Braces not enclosing a variable name are kept as is.
+pub fn substitute_vars(template: &str, vars: &BTreeMap<String, String>) -> Result<String> {
+    let mut res = String::new();
+    let mut rest = template;
+
+    while let Some(start) = rest.find('{') {
+        res.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        let end = match rest.find('}') {
+            Some(end) => end,
+            None => break,
+        };
+
+        let name = &rest[1..end];
+        if !is_valid_name(name) {
+            res.push('{');
+            rest = &rest[1..];
+            continue;
+        }
+
+        match vars.get(name) {
+            Some(value) => res.push_str(value),
+            None => {
+                return Err(anyhow!(
+                    "build variable {} is not defined; pass --var {}=VALUE",
+                    name,
+                    name
+                ))
+            }
+        }
+
+        rest = &rest[end + 1..];
+    }
+
+    res.push_str(rest);
+
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_build_var() -> Result<()> {
+        assert_eq!(
+            parse_build_var("customer=acme")?,
+            ("customer".to_string(), "acme".to_string())
+        );
+        assert_eq!(
+            parse_build_var("FLAGS=a=b")?,
+            ("FLAGS".to_string(), "a=b".to_string())
+        );
+        assert_eq!(
+            parse_build_var("empty=")?,
+            ("empty".to_string(), "".to_string())
+        );
+
+        assert!(parse_build_var("customer").is_err());
+        assert!(parse_build_var("=acme").is_err());
+        assert!(parse_build_var("1st=acme").is_err());
+        assert!(parse_build_var("my-var=acme").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_build_vars() {
+        std::env::set_var(build_var_env("test_build_vars"), "acme");
+        assert_eq!(
+            build_vars().get("test_build_vars"),
+            Some(&"acme".to_string())
+        );
+    }
+
+    #[test]
+    fn test_substitute_vars() -> Result<()> {
+        let mut vars = BTreeMap::new();
+        vars.insert("customer".to_string(), "acme".to_string());
+        vars.insert("env".to_string(), "prod".to_string());
+
+        assert_eq!(
+            substitute_vars("configs/{customer}-{env}.yaml", &vars)?,
+            "configs/acme-prod.yaml"
+        );
+        assert_eq!(substitute_vars("{customer}{", &vars)?, "acme{");
+        assert_eq!(
+            substitute_vars("{ not a var }/{env}", &vars)?,
+            "{ not a var }/prod"
+        );
+
+        let err = substitute_vars("configs/{region}.yaml", &vars)
+            .unwrap_err()
+            .to_string();
+        assert_eq!(
+            err,
+            "build variable region is not defined; pass --var region=VALUE"
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,401 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/*!
+Attribute compiler diagnostics from `cargo build` to generated or user code.
+
+Warnings in code PyOxidizer generates often foreshadow breakage after an
+upgrade, but they're lost among an application's own warnings. `cargo build`
+is run with `--message-format json` and each diagnostic is attributed to
+generated code if it comes from the `pyembed` crate or from a file in the
+PyOxidizer artifacts directory (e.g. the `data.rs` holding the embedded
+Python config).
+*/
+
+use anyhow::Result;
+use slog::warn;
+use std::io::{BufRead, Write};
+use std::path::{Path, PathBuf};
+
+use crate::fsutils::mentions_locked_file;
+
+/// Name of the crate PyOxidizer generates in projects.
+const GENERATED_CRATE: &str = "pyembed";
+
+/// A compiler diagnostic reported by cargo.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Diagnostic {
+    /// Severity, e.g. `warning` or `error`.
+    pub level: String,
+    pub message: String,
+    /// Human readable rendering, as printed by a build without JSON output.
+    pub rendered: Option<String>,
+    /// Name of the package the diagnostic was reported for.
+    pub package: String,
+    /// File and line of the primary span.
+    pub location: Option<(PathBuf, u64)>,
+}
+
+impl Diagnostic {
+    /// Whether the diagnostic is attributed to code generated by PyOxidizer.
+    ///
+    /// Relative span paths are relative to `project_path`.
+    pub fn is_generated(&self, project_path: &Path, artifacts_path: &Path) -> bool {
+        if self.package == GENERATED_CRATE {
+            return true;
+        }
+
+        match &self.location {
+            Some((path, _)) => project_path.join(path).starts_with(artifacts_path),
+            None => false,
+        }
+    }
+
+    fn describe(&self) -> String {
+        match &self.location {
+            Some((path, line)) => format!("{}:{}: {}", path.display(), line, self.message),
+            None => format!("{}: {}", self.package, self.message),
+        }
+    }
+}
+
+/// Obtain the package name from a cargo package ID.
+///
+/// Handles both the `name version (source)` and the
+/// `source#name@version` formats.
+fn package_name(package_id: &str) -> String {
+    if let Some(idx) = package_id.find(' ') {
+        return package_id[0..idx].to_string();
+    }
+
+    let fragment = match package_id.rfind('#') {
+        Some(idx) => &package_id[idx + 1..],
+        None => package_id,
+    };
+
+    match fragment.find('@') {
+        Some(idx) => fragment[0..idx].to_string(),
+        // Without a name in the fragment, the name is the directory's.
+        None => package_id
+            .split('#')
+            .next()
+            .unwrap_or("")
+            .trim_end_matches('/')
+            .rsplit('/')
+            .next()
+            .unwrap_or("")
+            .to_string(),
+    }
+}
+
+/// Parse a line of cargo's JSON output.
+///
+/// Returns `None` for lines that aren't compiler diagnostics.
+pub fn parse_diagnostic(line: &str) -> Option<Diagnostic> {
+    let value: serde_json::Value = serde_json::from_str(line).ok()?;
+
+    if value["reason"] != "compiler-message" {
+        return None;
+    }
+
+    let message = &value["message"];
+
+    let location = message["spans"]
+        .as_array()
+        .and_then(|spans| spans.iter().find(|span| span["is_primary"] == true))
+        .and_then(|span| {
+            Some((
+                PathBuf::from(span["file_name"].as_str()?),
+                span["line_start"].as_u64().unwrap_or(0),
+            ))
+        });
+
+    Some(Diagnostic {
+        level: message["level"].as_str().unwrap_or("").to_string(),
+        message: message["message"].as_str().unwrap_or("").to_string(),
+        rendered: message["rendered"].as_str().map(|s| s.to_string()),
+        package: package_name(value["package_id"].as_str().unwrap_or("")),
+        location,
+    })
+}
+
+/// Summary of the diagnostics of a build.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DiagnosticsSummary {
+    /// Warnings in code generated by PyOxidizer.
+    pub generated_warnings: Vec<Diagnostic>,
+    /// Number of warnings in user code.
+    pub user_warnings: usize,
+    /// Whether an error reports a file locked by another process.
+    pub locked_file: bool,
+}
+
+impl DiagnosticsSummary {
+    /// Log a summary of warnings in generated code.
+    pub fn report(&self, logger: &slog::Logger) {
+        if self.generated_warnings.is_empty() {
+            return;
+        }
+
+        warn!(
+            logger,
+            "{} warning(s) in code generated by PyOxidizer ({} in application code):",
+            self.generated_warnings.len(),
+            self.user_warnings
+        );
+        for diagnostic in &self.generated_warnings {
+            warn!(logger, "  {}", diagnostic.describe());
+        }
+        warn!(
+            logger,
+            "warnings in generated code may indicate incompatibilities with this PyOxidizer version"
+        );
+    }
+}
+
+/// Process cargo's JSON output.
+///
+/// Rendered diagnostics and lines that aren't JSON are written to `out`.
+/// Diagnostics are attributed to generated or user code and summarized.
+pub fn process_cargo_output(
+    reader: impl BufRead,
+    out: &mut dyn Write,
+    project_path: &Path,
+    artifacts_path: &Path,
+) -> Result<DiagnosticsSummary> {
+    let mut summary = DiagnosticsSummary::default();
+
+    for line in reader.lines() {
+        let line = line?;
+
+        if serde_json::from_str::<serde_json::Value>(&line).is_err() {
+            writeln!(out, "{}", line)?;
+            summary.locked_file |= mentions_locked_file(&line);
+            continue;
+        }
+
+        let diagnostic = match parse_diagnostic(&line) {
+            Some(diagnostic) => diagnostic,
+            None => continue,
+        };
+
+        if let Some(rendered) = &diagnostic.rendered {
+            write!(out, "{}", rendered)?;
+        }
+
+        if diagnostic.level == "error" {
+            summary.locked_file |=
+                mentions_locked_file(diagnostic.rendered.as_ref().unwrap_or(&diagnostic.message));
+        }
+
+        if diagnostic.level != "warning" {
+            continue;
+        }
+
+        if diagnostic.is_generated(project_path, artifacts_path) {
+            summary.generated_warnings.push(diagnostic);
+        } else {
+            summary.user_warnings += 1;
+        }
+    }
+
+    Ok(summary)
+}
+
+/// Relay the standard error of `cargo build` to `out`.
+///
+/// Returns whether it reports a file locked by another process.
+pub fn relay_cargo_stderr(mut reader: impl BufRead, out: &mut dyn Write) -> Result<bool> {
+    let mut locked_file = false;
+    let mut line = Vec::new();
+
+    while reader.read_until(b'\n', &mut line)? > 0 {
+        out.write_all(&line)?;
+        out.flush()?;
+        locked_file |= mentions_locked_file(&String::from_utf8_lossy(&line));
+        line.clear();
+    }
+
+    Ok(locked_file)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(package_id: &str, level: &str, file_name: &str, text: &str) -> String {
+        serde_json::json!({
+            "reason": "compiler-message",
+            "package_id": package_id,
+            "target": {"name": "x"},
+            "message": {
+                "message": text,
+                "level": level,
+                "spans": [
+                    {"file_name": "src/other.rs", "line_start": 1, "is_primary": false},
+                    {"file_name": file_name, "line_start": 12, "is_primary": true},
+                ],
+                "rendered": format!("{}: {}\n", level, text),
+            },
+        })
+        .to_string()
+    }
+
+    #[test]
+    fn test_package_name() {
+        assert_eq!(
+            package_name("pyembed 0.5.0-pre (path+file:///app/pyembed)"),
+            "pyembed"
+        );
+        assert_eq!(
+            package_name("path+file:///app/pyembed#0.5.0-pre"),
+            "pyembed"
+        );
+        assert_eq!(
+            package_name("path+file:///app/embedded#pyembed@0.5.0-pre"),
+            "pyembed"
+        );
+        assert_eq!(
+            package_name("registry+https://github.com/rust-lang/crates.io-index#libc@0.2.66"),
+            "libc"
+        );
+    }
+
+    #[test]
+    fn test_parse_diagnostic() {
+        let diagnostic = parse_diagnostic(&message(
+            "myapp 0.1.0 (path+file:///app)",
+            "warning",
+            "src/main.rs",
+            "unused import: `std::io`",
+        ))
+        .unwrap();
+
+        assert_eq!(diagnostic.package, "myapp");
+        assert_eq!(diagnostic.level, "warning");
+        assert_eq!(
+            diagnostic.location,
+            Some((PathBuf::from("src/main.rs"), 12))
+        );
+        assert_eq!(
+            diagnostic.describe(),
+            "src/main.rs:12: unused import: `std::io`"
+        );
+
+        assert_eq!(
+            parse_diagnostic(r#"{"reason": "compiler-artifact", "package_id": "x"}"#),
+            None
+        );
+        assert_eq!(parse_diagnostic("   Compiling myapp"), None);
+    }
+
+    #[test]
+    fn test_process_cargo_output() -> Result<()> {
+        let project = Path::new("/app");
+        let artifacts = Path::new("/app/build/target/x86_64-unknown-linux-gnu/debug/pyoxidizer");
+
+        let stream = [
+            r#"{"reason": "compiler-artifact", "package_id": "libc 0.2.66"}"#.to_string(),
+            message(
+                "pyembed 0.5.0-pre (path+file:///app/pyembed)",
+                "warning",
+                "pyembed/src/pyinterp.rs",
+                "use of deprecated item",
+            ),
+            message(
+                "myapp 0.1.0 (path+file:///app)",
+                "warning",
+                "/app/build/target/x86_64-unknown-linux-gnu/debug/pyoxidizer/data.rs",
+                "unused import: `TerminfoResolution`",
+            ),
+            message(
+                "myapp 0.1.0 (path+file:///app)",
+                "warning",
+                "src/main.rs",
+                "unused variable: `x`",
+            ),
+            message(
+                "myapp 0.1.0 (path+file:///app)",
+                "error",
+                "src/main.rs",
+                "mismatched types",
+            ),
+            "not json".to_string(),
+        ]
+        .join("\n");
+
+        let mut out = Vec::new();
+        let summary = process_cargo_output(stream.as_bytes(), &mut out, project, artifacts)?;
+
+        assert_eq!(
+            summary
+                .generated_warnings
+                .iter()
+                .map(|d| d.message.as_str())
+                .collect::<Vec<_>>(),
+            vec![
+                "use of deprecated item",
+                "unused import: `TerminfoResolution`"
+            ]
+        );
+        assert_eq!(summary.user_warnings, 1);
+        assert!(!summary.locked_file);
+        assert_eq!(
+            String::from_utf8(out)?,
+            "warning: use of deprecated item\n\
+             warning: unused import: `TerminfoResolution`\n\
+             warning: unused variable: `x`\n\
+             error: mismatched types\n\
+             not json\n"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_process_cargo_output_locked_file() -> Result<()> {
+        let project = Path::new("/app");
+        let artifacts = Path::new("/app/build/target/x86_64-pc-windows-msvc/debug/pyoxidizer");
+        let process = |lines: &[String]| -> Result<DiagnosticsSummary> {
+            process_cargo_output(
+                lines.join("\n").as_bytes(),
+                &mut Vec::new(),
+                project,
+                artifacts,
+            )
+        };
+
+        let linker = message(
+            "myapp 0.1.0 (path+file:///app)",
+            "error",
+            "src/main.rs",
+            "linking with `link.exe` failed: LINK : fatal error LNK1104: cannot open file 'myapp.exe'",
+        );
+        assert!(process(&[linker])?.locked_file);
+
+        let mut out = Vec::new();
+        let stderr = "   Compiling myapp v0.1.0\n\
+                      error: failed to remove file `C:\\app\\myapp.exe`\n\
+                      \n\
+                      Caused by:\n\
+                      \x20 Access is denied. (os error 5)\n";
+        assert!(relay_cargo_stderr(stderr.as_bytes(), &mut out)?);
+        assert_eq!(String::from_utf8(out)?, stderr);
+        assert!(!relay_cargo_stderr(
+            "error: could not compile `myapp`\n".as_bytes(),
+            &mut Vec::new()
+        )?);
+
+        // Warnings don't fail builds.
+        let warning = message(
+            "myapp 0.1.0 (path+file:///app)",
+            "warning",
+            "src/main.rs",
+            "Access is denied",
+        );
+        assert!(!process(&[warning])?.locked_file);
+
+        Ok(())
+    }
+}
@@ -3,24 +3,378 @@
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
 use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use sha2::Digest;
 use slog::warn;
+use std::collections::{BTreeMap, BTreeSet};
 use std::env;
 use std::path::{Path, PathBuf};
 
-use super::environment::EnvironmentContext;
+use super::environment::{EnvironmentContext, EvalOptions, ExternalPath};
+use super::resource::CaseCollisionPolicy;
+use super::toolchain::Toolchain;
+use crate::build_stats;
+use crate::exit_status::{categorize, ErrorCategory};
+use crate::licensing::{ExtensionModuleLicensePolicy, LicenseCheckPolicy};
 use crate::py_packaging::config::{EmbeddedPythonConfig, RawAllocator, RunMode};
 use crate::py_packaging::distribution::PythonDistributionLocation;
-use crate::starlark::eval::EvalResult;
+use crate::starlark::eval::{EvalResult, EvaluationCache};
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
 pub struct BuildConfig {
     pub application_name: String,
     pub build_path: PathBuf,
+    /// Extra flags to pass to `rustc` when building the application.
+    pub rustflags: Vec<String>,
+    /// Value for `-C target-cpu` when building the application.
+    pub target_cpu: Option<String>,
+    /// How to handle debug info of release builds.
+    pub split_debug_info: SplitDebugInfo,
+    /// Command to sign produced binaries and installers with.
+    ///
+    /// `{path}` in arguments is replaced by the path of the file to sign.
+    /// Empty if files aren't signed.
+    pub signing_command: Vec<String>,
+    /// Seconds to wait for the signing command to finish.
+    pub signing_timeout: u64,
+    /// Directories to add to the linker's library search path.
+    pub link_search_paths: Vec<PathBuf>,
+    /// Extra libraries to link the application against.
+    pub link_libraries: Vec<String>,
+    /// Functions in the application to call before Python is initialized.
+    ///
+    /// They are declared as `extern "Rust"` by the `pyembed` crate and
+    /// called by `pyembed::run_pre_init_hooks()`.
+    pub pre_init_hooks: Vec<String>,
+    /// Functions in the application to call after Python is finalized.
+    ///
+    /// They receive the process exit code and are called by
+    /// `pyembed::run_post_finalize_hooks()`.
+    pub post_finalize_hooks: Vec<String>,
+    /// Whether builds may set `RUSTC_BOOTSTRAP=1` when the Rust compiler
+    /// needs it.
+    pub allow_rustc_bootstrap: bool,
+    /// Where packaged applications are written, relative to `build_path`.
+    ///
+    /// `None` uses `DEFAULT_OUTPUT_LAYOUT`.
+    pub output_layout: Option<String>,
+    /// Whether to write `THIRD-PARTY-NOTICES.txt` into packaged applications.
+    pub third_party_notices: bool,
+    /// Directories outside the project resource rules may reference.
+    pub allow_external_paths: Vec<PathBuf>,
+    /// Rust toolchain to build with. `None` uses `cargo` on `PATH`.
+    pub rust_toolchain: Option<Toolchain>,
+}
+
+/// Default value of `BuildConfig.signing_timeout`.
+pub const DEFAULT_SIGNING_TIMEOUT: u64 = 600;
+
+/// Where to write debug info split out of release executables.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub enum SplitDebugInfo {
+    /// Debug info is not split.
+    Disabled,
+    /// Debug info is written next to the executable.
+    Adjacent,
+    /// Debug info is written to a `debug` directory.
+    Directory,
+}
+
+/// Target features that PyOxidizer controls and can't be set via rustflags.
+const RESERVED_TARGET_FEATURES: &[&str] = &["crt-static"];
+
+/// rustc flags other than `-C`/`--codegen` whose value may be a separate argument.
+const RUSTFLAGS_WITH_VALUE: &[&str] = &[
+    "-A",
+    "-D",
+    "-F",
+    "-L",
+    "-W",
+    "-Z",
+    "-l",
+    "--allow",
+    "--cap-lints",
+    "--cfg",
+    "--crate-type",
+    "--deny",
+    "--edition",
+    "--emit",
+    "--extern",
+    "--forbid",
+    "--print",
+    "--warn",
+];
+
+/// Split a list of rustc flags into normalized flags, one item per flag.
+///
+/// `-C key=value`, `-Ckey=value`, and `--codegen key=value` are all normalized
+/// to `-Ckey=value` so that they can be compared. Other flags followed by a
+/// separate value, like `--cfg foo`, are kept together as `--cfg foo`.
+fn normalize_rustflags(flags: &[String]) -> Vec<String> {
+    let mut res = Vec::new();
+    let mut iter = flags.iter().filter(|f| !f.is_empty());
+
+    while let Some(flag) = iter.next() {
+        if flag == "-C" || flag == "--codegen" {
+            if let Some(value) = iter.next() {
+                res.push(format!("-C{}", value));
+            } else {
+                res.push(flag.clone());
+            }
+        } else if flag.starts_with("--codegen=") {
+            res.push(format!("-C{}", &flag[10..]));
+        } else if RUSTFLAGS_WITH_VALUE.contains(&flag.as_str()) {
+            if let Some(value) = iter.next() {
+                res.push(format!("{} {}", flag, value));
+            } else {
+                res.push(flag.clone());
+            }
+        } else {
+            res.push(flag.clone());
+        }
+    }
+
+    res
+}
+
+/// Obtain the codegen option key for a normalized flag, if it is one.
+fn codegen_key(flag: &str) -> Option<&str> {
+    if flag.starts_with("-C") && flag.len() > 2 {
+        Some(flag[2..].split('=').next().unwrap())
+    } else {
+        None
+    }
+}
+
+/// Normalize the rustc flags of a `RUSTFLAGS` environment variable.
+pub fn normalize_env_rustflags(env_flags: Option<&str>) -> Vec<String> {
+    match env_flags {
+        Some(value) => normalize_rustflags(
+            &value
+                .split_whitespace()
+                .map(|s| s.to_string())
+                .collect::<Vec<String>>(),
+        ),
+        None => Vec::new(),
+    }
+}
+
+/// The value of `RUSTFLAGS` to invoke cargo with, if any.
+///
+/// Setting `RUSTFLAGS` makes cargo ignore `build.rustflags` of cargo configs,
+/// so it is only set if the config or the environment define flags. If the
+/// environment defines it, it's always set, so flags we've overridden don't
+/// leak through.
+pub fn rustflags_env(rustflags: &[String], env_defined: bool) -> Option<String> {
+    if rustflags.is_empty() && !env_defined {
+        None
+    } else {
+        Some(rustflags.join(" "))
+    }
+}
+
+/// Validate that config provided rustc flags don't conflict with PyOxidizer.
+pub fn validate_rustflags(flags: &[String]) -> Result<()> {
+    for flag in normalize_rustflags(flags) {
+        if codegen_key(&flag) == Some("target-feature") {
+            for feature in flag.splitn(2, '=').nth(1).unwrap_or("").split(',') {
+                if RESERVED_TARGET_FEATURES
+                    .contains(&feature.trim_start_matches(|c| c == '+' || c == '-'))
+                {
+                    return Err(anyhow!(
+                        "rustflags cannot contain {}; linking of the C runtime is controlled by PyOxidizer",
+                        flag
+                    ));
+                }
+            }
+        } else if flag == "--target" || flag.starts_with("--target=") {
+            return Err(anyhow!(
+                "rustflags cannot contain {}; use --target instead",
+                flag
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// Validate a signing command.
+pub fn validate_signing_command(command: &[String]) -> Result<()> {
+    if command.is_empty() {
+        return Ok(());
+    }
+
+    if command[0].is_empty() {
+        return Err(anyhow!("signing_command program must not be empty"));
+    }
+
+    if !command.iter().any(|arg| arg.contains("{path}")) {
+        return Err(anyhow!(
+            "signing_command must include {{path}} to receive the file to sign"
+        ));
+    }
+
+    Ok(())
+}
+
+/// Validate the program names of multi-call run modes.
+///
+/// Names become file names next to the executable. They are compared
+/// case-insensitively because they are matched that way on Windows.
+pub fn validate_multicall_names<'a>(names: impl Iterator<Item = &'a String>) -> Result<()> {
+    let mut seen = BTreeSet::new();
+
+    for name in names {
+        if name.is_empty()
+            || name.contains(|c| c == '/' || c == '\\')
+            || name == "."
+            || name == ".."
+        {
+            return Err(anyhow!(
+                "multicall_run_modes name '{}' is not a valid file name",
+                name
+            ));
+        }
+
+        if name.to_lowercase().ends_with(".exe") {
+            return Err(anyhow!(
+                "multicall_run_modes name '{}' should not include .exe; it is added automatically on Windows",
+                name
+            ));
+        }
+
+        if !seen.insert(name.to_lowercase()) {
+            return Err(anyhow!(
+                "multicall_run_modes name '{}' differs from another name only by case",
+                name
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// Validate the names of application functions called by `pyembed`.
+///
+/// `field` is the config field holding the names. Names must be Rust
+/// identifiers, as they are declared in `extern "Rust"` blocks.
+pub fn validate_hooks(field: &str, names: &[String]) -> Result<()> {
+    let mut seen = BTreeSet::new();
+
+    for name in names {
+        let mut chars = name.chars();
+        let valid = match chars.next() {
+            Some(c) if c.is_ascii_alphabetic() || c == '_' => {
+                name != "_" && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+            }
+            _ => false,
+        };
+
+        if !valid {
+            return Err(anyhow!(
+                "{} name '{}' is not a Rust function name",
+                field,
+                name
+            ));
+        }
+
+        if !seen.insert(name) {
+            return Err(anyhow!("{} name '{}' is duplicated", field, name));
+        }
+    }
+
+    Ok(())
+}
+
+impl BuildConfig {
+    /// Cargo metadata lines registering extra libraries with the linker.
+    pub fn link_cargo_metadata(&self) -> Vec<String> {
+        let mut res = Vec::new();
+
+        for path in &self.link_search_paths {
+            res.push(format!("cargo:rustc-link-search=native={}", path.display()));
+        }
+
+        for library in &self.link_libraries {
+            res.push(format!("cargo:rustc-link-lib={}", library));
+        }
+
+        res
+    }
+
+    /// Resolve the rustc flags defined by this config.
+    pub fn config_rustflags(&self) -> Vec<String> {
+        let mut flags = normalize_rustflags(&self.rustflags);
+
+        if let Some(cpu) = &self.target_cpu {
+            flags.push(format!("-Ctarget-cpu={}", cpu));
+        }
+
+        flags
+    }
+
+    /// Resolve the effective rustc flags by merging config and environment flags.
+    ///
+    /// `env_flags` is the content of a `RUSTFLAGS` environment variable.
+    /// Codegen options defined by the config take precedence over the same
+    /// option defined in the environment. Returns the effective flags and the
+    /// environment flags that were overridden.
+    ///
+    /// Release builds splitting debug info get debug info, unless a debug
+    /// info level is already set.
+    pub fn resolve_rustflags(
+        &self,
+        env_flags: Option<&str>,
+        release: bool,
+    ) -> (Vec<String>, Vec<String>) {
+        let config_flags = self.config_rustflags();
+        let config_keys = config_flags
+            .iter()
+            .filter_map(|f| codegen_key(f))
+            .collect::<Vec<&str>>();
+
+        let env_flags = normalize_env_rustflags(env_flags);
+
+        let mut effective = Vec::new();
+        let mut overridden = Vec::new();
+
+        for flag in env_flags {
+            match codegen_key(&flag) {
+                Some(key) if config_keys.contains(&key) => {
+                    if !config_flags.contains(&flag) {
+                        overridden.push(flag.clone());
+                    }
+                }
+                _ => {
+                    if !effective.contains(&flag) {
+                        effective.push(flag.clone());
+                    }
+                }
+            }
+        }
+
+        for flag in config_flags {
+            if !effective.contains(&flag) {
+                effective.push(flag);
+            }
+        }
+
+        // Release builds don't have debug info to split unless we ask for it.
+        if release
+            && self.split_debug_info != SplitDebugInfo::Disabled
+            && !effective.iter().any(|f| f.starts_with("-Cdebuginfo="))
+        {
+            effective.push("-Cdebuginfo=2".to_string());
+        }
+
+        (effective, overridden)
+    }
 }
 
 #[derive(Clone, Debug)]
 pub struct DistributionTarball {
     pub path_prefix: Option<String>,
+    pub case_collision_policy: CaseCollisionPolicy,
 }
 
 #[derive(Clone, Debug)]
@@ -38,13 +392,53 @@ pub enum Distribution {
 }
 
 /// Represents a parsed PyOxidizer configuration file.
-#[derive(Clone, Debug)]
+///
+/// Configs are serialized into prepared builds, so they can be built
+/// without evaluating the config file again.
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Config {
     pub config_path: PathBuf,
     pub build_config: BuildConfig,
     pub embedded_python_config: EmbeddedPythonConfig,
     pub python_distribution: PythonDistributionLocation,
     pub run: RunMode,
+    /// Run modes selected by the name the executable is invoked as.
+    pub multicall_run_modes: BTreeMap<String, RunMode>,
+    /// Python packages installed by `pip_install()` and their versions.
+    pub python_package_versions: BTreeMap<String, String>,
+    /// Python packages installed by `pip_install()` and the SHA-256 of their `RECORD` file.
+    pub python_package_hashes: BTreeMap<String, String>,
+    /// Data files read during evaluation of the config file.
+    pub read_files: Vec<PathBuf>,
+    /// Existing filesystem paths referenced during evaluation of the config file.
+    pub input_paths: BTreeSet<PathBuf>,
+    /// Scripts run to alter resources once they are resolved.
+    pub packaging_scripts: Vec<PathBuf>,
+    /// Licenses extension modules of the Python distribution are restricted to.
+    pub extension_module_license_policy: Option<ExtensionModuleLicensePolicy>,
+    /// Compile options the SQLite library of the Python distribution must have.
+    pub required_sqlite_options: Vec<String>,
+    /// Files embedded by `embedded_config_file()` and the SHA-256 of their content.
+    pub embedded_config_files: BTreeMap<String, String>,
+    /// Licenses embedded extension modules may carry.
+    pub license_check: LicenseCheckPolicy,
+    /// Paths outside the project referenced by resource rules and allowed
+    /// by `allow_external_paths`.
+    pub external_paths: Vec<ExternalPath>,
+}
+
+impl Config {
+    /// SHA-256 of the evaluated configuration.
+    ///
+    /// Changes whenever evaluating the config file has a different result,
+    /// even if no file changed, e.g. because of an environment variable the
+    /// config reads.
+    pub fn digest(&self) -> String {
+        let mut hasher = sha2::Sha256::new();
+        hasher.input(format!("{:#?}", self).as_bytes());
+
+        hex::encode(hasher.result())
+    }
 }
 
 pub fn default_raw_allocator(target: &str) -> RawAllocator {
@@ -87,13 +481,279 @@ pub fn find_pyoxidizer_config_file_env(logger: &slog::Logger, start_dir: &Path)
     }
 }
 
+/// The category of an error raised while evaluating a config file.
+///
+/// Starlark functions report failures through error codes. Those of
+/// functions resolving the Python distribution or packaging resources map
+/// to their phase. Everything else is a config error.
+pub fn eval_error_category(code: Option<&str>) -> ErrorCategory {
+    match code {
+        Some("PYTHON_DISTRIBUTION") | Some("no_default_distribution") => {
+            ErrorCategory::Distribution
+        }
+        Some("PIP_INSTALL_ERROR")
+        | Some("SETUP_PY_ERROR")
+        | Some("VIRTUALENV_ERROR")
+        | Some("PACKAGE_ROOT_ERROR")
+        | Some("PYOXIDIZER_INSTALL") => ErrorCategory::Packaging,
+        Some("PYOXIDIZER_BUILD") | Some("RUST_EXTENSION_ERROR") => ErrorCategory::Compile,
+        _ => ErrorCategory::Config,
+    }
+}
+
 pub fn eval_starlark_config_file(
     logger: &slog::Logger,
     path: &Path,
     build_target: &str,
+    release: bool,
+    options: &EvalOptions,
+) -> Result<EvalResult> {
+    eval_starlark_config_file_with_cache(
+        logger,
+        path,
+        build_target,
+        release,
+        options,
+        &EvaluationCache::default(),
+    )
+}
+
+/// Like `eval_starlark_config_file()`, memoizing results in `eval_cache`.
+///
+/// Evaluations sharing a cache reuse parsed data files and glob matches.
+pub fn eval_starlark_config_file_with_cache(
+    logger: &slog::Logger,
+    path: &Path,
+    build_target: &str,
+    release: bool,
+    options: &EvalOptions,
+    eval_cache: &EvaluationCache,
 ) -> Result<EvalResult> {
-    let context = EnvironmentContext::new(logger, path, build_target)?;
+    let _phase = build_stats::phase("evaluate config");
+
+    let mut context = EnvironmentContext::new(logger, path, build_target)
+        .map_err(|e| categorize(e, ErrorCategory::Config))?;
+    context.release = release;
+    context.offline = options.offline;
+    context.build_vars = options.build_vars.clone();
+    context.eval_cache = eval_cache.clone();
+
+    crate::starlark::eval::evaluate_file(logger, path, &context).or_else(|d| {
+        // Cancellation surfaces as a generic evaluation error from functions
+        // like pip_install(). Report it as such.
+        crate::cancel::check_cancelled()?;
+
+        Err(categorize(
+            anyhow!(d.message),
+            eval_error_category(d.code.as_ref().map(|s| s.as_str())),
+        ))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn build_config(rustflags: &[&str], target_cpu: Option<&str>) -> BuildConfig {
+        BuildConfig {
+            application_name: "myapp".to_string(),
+            build_path: PathBuf::from("build"),
+            rustflags: rustflags.iter().map(|s| s.to_string()).collect(),
+            target_cpu: target_cpu.map(|s| s.to_string()),
+            split_debug_info: SplitDebugInfo::Disabled,
+            signing_command: vec![],
+            signing_timeout: DEFAULT_SIGNING_TIMEOUT,
+            link_search_paths: vec![],
+            link_libraries: vec![],
+            pre_init_hooks: vec![],
+            post_finalize_hooks: vec![],
+            allow_rustc_bootstrap: true,
+            output_layout: None,
+            third_party_notices: false,
+            allow_external_paths: vec![],
+            rust_toolchain: None,
+        }
+    }
+
+    #[test]
+    fn test_eval_error_category() {
+        assert_eq!(eval_error_category(None), ErrorCategory::Config);
+        assert_eq!(eval_error_category(Some("CV00")), ErrorCategory::Config);
+        assert_eq!(
+            eval_error_category(Some("no_default_distribution")),
+            ErrorCategory::Distribution
+        );
+        assert_eq!(
+            eval_error_category(Some("PIP_INSTALL_ERROR")),
+            ErrorCategory::Packaging
+        );
+        assert_eq!(
+            eval_error_category(Some("PYOXIDIZER_BUILD")),
+            ErrorCategory::Compile
+        );
+        assert_eq!(
+            eval_error_category(Some("RUST_EXTENSION_ERROR")),
+            ErrorCategory::Compile
+        );
+    }
+
+    #[test]
+    fn test_validate_signing_command() {
+        let command = |values: &[&str]| values.iter().map(|s| s.to_string()).collect::<Vec<_>>();
 
-    crate::starlark::eval::evaluate_file(logger, path, &context)
-        .or_else(|d| Err(anyhow!(d.message)))
+        assert!(validate_signing_command(&[]).is_ok());
+        assert!(validate_signing_command(&command(&["./sign.sh", "{path}"])).is_ok());
+        assert!(validate_signing_command(&command(&["signtool", "/f:{path}"])).is_ok());
+        assert!(validate_signing_command(&command(&["./sign.sh"])).is_err());
+        assert!(validate_signing_command(&command(&["", "{path}"])).is_err());
+    }
+
+    #[test]
+    fn test_validate_multicall_names() {
+        let names = |values: &[&str]| values.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+
+        assert!(validate_multicall_names(names(&["myapp", "myapp-worker"]).iter()).is_ok());
+        assert!(validate_multicall_names(names(&["bin/myapp"]).iter()).is_err());
+        assert!(validate_multicall_names(names(&[""]).iter()).is_err());
+        assert!(validate_multicall_names(names(&["myapp.exe"]).iter()).is_err());
+        assert!(validate_multicall_names(names(&["myapp", "MyApp"]).iter()).is_err());
+    }
+
+    #[test]
+    fn test_validate_hooks() {
+        let names = |values: &[&str]| values.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        let validate = |values: &[&str]| validate_hooks("pre_init_hooks", &names(values));
+
+        assert!(validate(&["setup_logging", "_parse_args2"]).is_ok());
+        assert!(validate(&["app::setup"]).is_err());
+        assert!(validate(&["2fast"]).is_err());
+        assert!(validate(&["_", ""]).is_err());
+        assert_eq!(
+            validate(&["setup", "setup"]).unwrap_err().to_string(),
+            "pre_init_hooks name 'setup' is duplicated"
+        );
+        assert_eq!(
+            validate_hooks("post_finalize_hooks", &names(&["app::flush"]))
+                .unwrap_err()
+                .to_string(),
+            "post_finalize_hooks name 'app::flush' is not a Rust function name"
+        );
+    }
+
+    #[test]
+    fn test_link_cargo_metadata() {
+        let mut config = build_config(&[], None);
+        config.link_search_paths = vec![PathBuf::from("/opt/vendor/lib")];
+        config.link_libraries = vec!["vendor".to_string(), "static=extra".to_string()];
+
+        assert_eq!(
+            config.link_cargo_metadata(),
+            vec![
+                "cargo:rustc-link-search=native=/opt/vendor/lib".to_string(),
+                "cargo:rustc-link-lib=vendor".to_string(),
+                "cargo:rustc-link-lib=static=extra".to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_validate_rustflags() {
+        assert!(validate_rustflags(&["-C".to_string(), "lto".to_string()]).is_ok());
+        assert!(
+            validate_rustflags(&["-C".to_string(), "target-feature=+crt-static".to_string()])
+                .is_err()
+        );
+        assert!(validate_rustflags(&["-Ctarget-feature=+avx,-crt-static".to_string()]).is_err());
+        assert!(validate_rustflags(&["-Ctarget-feature=+avx".to_string()]).is_ok());
+        assert!(validate_rustflags(&["--target=foo".to_string()]).is_err());
+    }
+
+    #[test]
+    fn test_rustflags_env() {
+        // Cargo then uses build.rustflags of cargo configs.
+        assert_eq!(rustflags_env(&[], false), None);
+        assert_eq!(rustflags_env(&[], true), Some("".to_string()));
+        assert_eq!(
+            rustflags_env(&["-Clto".to_string(), "-Cdebuginfo=2".to_string()], false),
+            Some("-Clto -Cdebuginfo=2".to_string())
+        );
+    }
+
+    #[test]
+    fn test_resolve_rustflags_config_only() {
+        let c = build_config(&["-C", "codegen-units=1"], Some("x86-64-v2"));
+        let (flags, overridden) = c.resolve_rustflags(None, false);
+        assert_eq!(flags, vec!["-Ccodegen-units=1", "-Ctarget-cpu=x86-64-v2"]);
+        assert!(overridden.is_empty());
+    }
+
+    #[test]
+    fn test_resolve_rustflags_merge() {
+        let c = build_config(&["-Ccodegen-units=1"], Some("native"));
+        let (flags, overridden) =
+            c.resolve_rustflags(Some("-C target-cpu=skylake --cfg foo -Cdebuginfo=2"), false);
+        assert_eq!(
+            flags,
+            vec![
+                "--cfg foo",
+                "-Cdebuginfo=2",
+                "-Ccodegen-units=1",
+                "-Ctarget-cpu=native"
+            ]
+        );
+        assert_eq!(overridden, vec!["-Ctarget-cpu=skylake"]);
+    }
+
+    #[test]
+    fn test_resolve_rustflags_idempotent() {
+        let c = build_config(&["-Clto"], None);
+        let (flags, _) = c.resolve_rustflags(None, false);
+        let (again, overridden) = c.resolve_rustflags(Some(&flags.join(" ")), false);
+        assert_eq!(flags, again);
+        assert!(overridden.is_empty());
+    }
+
+    #[test]
+    fn test_resolve_rustflags_repeated_flags() {
+        let c = build_config(&["--cfg", "b", "-L", "/opt/lib"], None);
+        let (flags, overridden) = c.resolve_rustflags(
+            Some("--cfg a --cfg b -L /usr/lib -L /opt/lib -l foo -l bar --cap-lints warn"),
+            false,
+        );
+        assert_eq!(
+            flags,
+            vec![
+                "--cfg a",
+                "--cfg b",
+                "-L /usr/lib",
+                "-L /opt/lib",
+                "-l foo",
+                "-l bar",
+                "--cap-lints warn"
+            ]
+        );
+        assert!(overridden.is_empty());
+
+        let (again, _) = c.resolve_rustflags(Some(&flags.join(" ")), false);
+        assert_eq!(flags, again);
+    }
+
+    #[test]
+    fn test_resolve_rustflags_split_debug_info() {
+        let mut c = build_config(&["-Clto"], None);
+        c.split_debug_info = SplitDebugInfo::Adjacent;
+
+        let (flags, _) = c.resolve_rustflags(None, true);
+        assert_eq!(flags, vec!["-Clto", "-Cdebuginfo=2"]);
+        let (flags, _) = c.resolve_rustflags(None, false);
+        assert_eq!(flags, vec!["-Clto"]);
+
+        // An explicit level wins.
+        let (flags, _) = c.resolve_rustflags(Some("-C debuginfo=1"), true);
+        assert_eq!(flags, vec!["-Cdebuginfo=1", "-Clto"]);
+
+        c.split_debug_info = SplitDebugInfo::Disabled;
+        let (flags, _) = c.resolve_rustflags(None, true);
+        assert_eq!(flags, vec!["-Clto"]);
+    }
 }
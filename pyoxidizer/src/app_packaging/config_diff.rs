@@ -0,0 +1,364 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/*!
+Compare the behavior of evaluated configs.
+
+`pyoxidizer diff-config` evaluates two config files and compares the values
+that affect what gets built: the Python distribution, installed Python
+packages, embedded Python config, run modes and build settings.
+
+Values are normalized so equivalent configs compare equal:
+
+* rustflags are normalized and `target_cpu` is folded into them.
+* Paths are relative to the config file's directory when possible.
+* Which `select()` arm chose an embedded Python config value is ignored.
+* The config path and files read during evaluation aren't compared.
+*/
+
+use anyhow::Result;
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::path::Path;
+
+use super::config::Config;
+use super::lockfile::relative_key;
+use crate::py_packaging::config::RunMode;
+use crate::py_packaging::distribution::PythonDistributionLocation;
+
+/// How a value differs between two configs.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ConfigChange {
+    Added(String),
+    Removed(String),
+    Changed { old: String, new: String },
+}
+
+/// A value that differs between two configs.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ConfigDifference {
+    pub key: String,
+    pub change: ConfigChange,
+}
+
+impl fmt::Display for ConfigDifference {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.change {
+            ConfigChange::Added(value) => write!(f, "+ {} = {}", self.key, value),
+            ConfigChange::Removed(value) => write!(f, "- {} = {}", self.key, value),
+            ConfigChange::Changed { old, new } => {
+                write!(f, "~ {}: {} -> {}", self.key, old, new)
+            }
+        }
+    }
+}
+
+fn json<T: Serialize + ?Sized>(value: &T) -> Result<String> {
+    Ok(serde_json::to_string(value)?)
+}
+
+fn describe_run_mode(mode: &RunMode) -> Result<String> {
+    Ok(match mode {
+        RunMode::Noop => "noop".to_string(),
+        RunMode::Repl => "repl".to_string(),
+        RunMode::Module { module } => format!("module {}", json(module)?),
+        RunMode::Eval { code } => format!("eval {}", json(code)?),
+        RunMode::Embedding => "embedding".to_string(),
+    })
+}
+
+/// Resolve the normalized values of a config that affect what gets built.
+///
+/// Keys are dotted names grouping values by what they configure.
+pub fn config_values(config: &Config) -> Result<BTreeMap<String, String>> {
+    let base = config.config_path.parent().unwrap_or_else(|| Path::new(""));
+    let mut values = BTreeMap::new();
+
+    match &config.python_distribution {
+        PythonDistributionLocation::Url { url, sha256 } => {
+            values.insert("python_distribution.url".to_string(), json(url)?);
+            values.insert(
+                "python_distribution.sha256".to_string(),
+                json(&sha256.to_lowercase())?,
+            );
+        }
+        PythonDistributionLocation::Local { local_path, sha256 } => {
+            values.insert(
+                "python_distribution.local_path".to_string(),
+                json(&relative_key(base, Path::new(local_path)))?,
+            );
+            values.insert(
+                "python_distribution.sha256".to_string(),
+                json(&sha256.to_lowercase())?,
+            );
+        }
+    }
+
+    for (name, version) in &config.python_package_versions {
+        values.insert(format!("python_packages.{}.version", name), json(version)?);
+    }
+    for (name, sha256) in &config.python_package_hashes {
+        values.insert(
+            format!("python_packages.{}.record_sha256", name),
+            json(sha256)?,
+        );
+    }
+
+    if let serde_json::Value::Object(fields) = serde_json::to_value(&config.embedded_python_config)?
+    {
+        for (field, value) in fields {
+            if field == "target_selections" {
+                continue;
+            }
+
+            values.insert(
+                format!("embedded_python_config.{}", field),
+                value.to_string(),
+            );
+        }
+    }
+
+    values.insert("run_mode".to_string(), describe_run_mode(&config.run)?);
+    for (name, mode) in &config.multicall_run_modes {
+        values.insert(
+            format!("multicall_run_modes.{}", name),
+            describe_run_mode(mode)?,
+        );
+    }
+
+    values.insert(
+        "packaging_scripts".to_string(),
+        json(
+            &config
+                .packaging_scripts
+                .iter()
+                .map(|p| relative_key(base, p))
+                .collect::<Vec<_>>(),
+        )?,
+    );
+
+    if let Some(policy) = &config.extension_module_license_policy {
+        values.insert(
+            "extension_module_license_filter.allow".to_string(),
+            json(&policy.allow)?,
+        );
+        values.insert(
+            "extension_module_license_filter.on_conflict".to_string(),
+            format!("{:?}", policy.on_conflict),
+        );
+    }
+
+    if !config.required_sqlite_options.is_empty() {
+        values.insert(
+            "require_sqlite_options".to_string(),
+            json(&config.required_sqlite_options)?,
+        );
+    }
+
+    for (name, sha256) in &config.embedded_config_files {
+        values.insert(
+            format!("embedded_config_files.{}.sha256", name),
+            json(sha256)?,
+        );
+    }
+
+    values.insert(
+        "license_check.denylist".to_string(),
+        json(&config.license_check.denylist)?,
+    );
+    values.insert(
+        "license_check.allowlist".to_string(),
+        json(&config.license_check.allowlist)?,
+    );
+    values.insert(
+        "license_check.exempt_extensions".to_string(),
+        json(&config.license_check.exempt_extensions)?,
+    );
+
+    let build = &config.build_config;
+    values.insert(
+        "build.application_name".to_string(),
+        json(&build.application_name)?,
+    );
+    values.insert(
+        "build.build_path".to_string(),
+        json(&relative_key(base, &build.build_path))?,
+    );
+    values.insert(
+        "build.rustflags".to_string(),
+        json(&build.config_rustflags())?,
+    );
+    values.insert(
+        "build.split_debug_info".to_string(),
+        format!("{:?}", build.split_debug_info),
+    );
+    values.insert(
+        "build.signing_command".to_string(),
+        json(&build.signing_command)?,
+    );
+    values.insert(
+        "build.signing_timeout".to_string(),
+        build.signing_timeout.to_string(),
+    );
+    values.insert(
+        "build.link_search_paths".to_string(),
+        json(
+            &build
+                .link_search_paths
+                .iter()
+                .map(|p| relative_key(base, p))
+                .collect::<Vec<_>>(),
+        )?,
+    );
+    values.insert(
+        "build.link_libraries".to_string(),
+        json(&build.link_libraries)?,
+    );
+    values.insert(
+        "build.pre_init_hooks".to_string(),
+        json(&build.pre_init_hooks)?,
+    );
+    values.insert(
+        "build.post_finalize_hooks".to_string(),
+        json(&build.post_finalize_hooks)?,
+    );
+    values.insert(
+        "build.allow_rustc_bootstrap".to_string(),
+        json(&build.allow_rustc_bootstrap)?,
+    );
+    values.insert(
+        "build.output_layout".to_string(),
+        json(&build.output_layout)?,
+    );
+    values.insert(
+        "build.third_party_notices".to_string(),
+        json(&build.third_party_notices)?,
+    );
+    values.insert(
+        "build.rust_toolchain".to_string(),
+        json(&build.rust_toolchain.as_ref().map(|t| t.to_string()))?,
+    );
+
+    Ok(values)
+}
+
+/// Compare normalized config values.
+pub fn diff_config_values(
+    a: &BTreeMap<String, String>,
+    b: &BTreeMap<String, String>,
+) -> Vec<ConfigDifference> {
+    let keys = a.keys().chain(b.keys()).collect::<BTreeSet<_>>();
+
+    keys.into_iter()
+        .filter_map(|key| {
+            let change = match (a.get(key), b.get(key)) {
+                (Some(old), Some(new)) if old == new => return None,
+                (Some(old), Some(new)) => ConfigChange::Changed {
+                    old: old.clone(),
+                    new: new.clone(),
+                },
+                (Some(old), None) => ConfigChange::Removed(old.clone()),
+                (None, Some(new)) => ConfigChange::Added(new.clone()),
+                (None, None) => return None,
+            };
+
+            Some(ConfigDifference {
+                key: key.clone(),
+                change,
+            })
+        })
+        .collect()
+}
+
+/// Compare the behavior of two evaluated configs.
+pub fn diff_configs(a: &Config, b: &Config) -> Result<Vec<ConfigDifference>> {
+    Ok(diff_config_values(&config_values(a)?, &config_values(b)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::config::eval_starlark_config_file;
+    use super::super::environment::EvalOptions;
+    use super::*;
+    use crate::testutil::*;
+
+    fn eval_config(dir: &Path, name: &str, content: &str) -> Result<Config> {
+        let path = dir.join(name);
+        std::fs::write(&path, content)?;
+
+        Ok(eval_starlark_config_file(
+            &get_logger()?,
+            &path,
+            env!("HOST"),
+            false,
+            &EvalOptions::default(),
+        )?
+        .config)
+    }
+
+    fn config_content(embedded_args: &str, config_args: &str) -> String {
+        format!(
+            "CONFIG = Config(\n    application_name='myapp',\n    embedded_python_config=EmbeddedPythonConfig({}),\n    python_distribution=default_python_distribution(),\n    python_run_mode=python_run_mode_repl(),\n    {}\n)\n",
+            embedded_args, config_args
+        )
+    }
+
+    #[test]
+    fn test_diff_config_values() {
+        let values = |entries: &[(&str, &str)]| {
+            entries
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect::<BTreeMap<_, _>>()
+        };
+
+        let diff = diff_config_values(
+            &values(&[("a", "1"), ("b", "2"), ("c", "3")]),
+            &values(&[("b", "2"), ("c", "4"), ("d", "5")]),
+        );
+
+        assert_eq!(
+            diff.iter().map(|d| d.to_string()).collect::<Vec<_>>(),
+            vec!["- a = 1", "~ c: 3 -> 4", "+ d = 5"]
+        );
+    }
+
+    #[test]
+    fn test_diff_configs_normalized() -> Result<()> {
+        let temp_dir = tempdir::TempDir::new("pyoxidizer-test")?;
+        let dir = temp_dir.path();
+
+        let a = eval_config(
+            dir,
+            "a.bzl",
+            &config_content("", "rustflags=['-C', 'target-cpu=native'],"),
+        )?;
+        let b = eval_config(
+            dir,
+            "b.bzl",
+            &config_content("optimize_level=0", "target_cpu='native',"),
+        )?;
+        assert_eq!(diff_configs(&a, &b)?, vec![]);
+
+        let c = eval_config(
+            dir,
+            "c.bzl",
+            &config_content("optimize_level=2", "link_libraries=['vendor'],"),
+        )?;
+        assert_eq!(
+            diff_configs(&a, &c)?
+                .iter()
+                .map(|d| d.to_string())
+                .collect::<Vec<_>>(),
+            vec![
+                "~ build.link_libraries: [] -> [\"vendor\"]",
+                "~ build.rustflags: [\"-Ctarget-cpu=native\"] -> []",
+                "~ embedded_python_config.optimize_level: 0 -> 2",
+            ]
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,437 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Splitting debug information out of packaged executables.
+//!
+//! Release executables are shipped without debug information. The debug
+//! information is written to a separate file so crash reports can still be
+//! symbolized:
+//!
+//! * ELF: a `.debug` file produced by `objcopy --only-keep-debug`. The
+//!   executable references it via a `.gnu_debuglink` section.
+//! * Mach-O: a `.dSYM` bundle produced by `dsymutil`. The executable and the
+//!   bundle share an `LC_UUID`.
+//! * PE: the `.pdb` produced by the MSVC linker. The executable references it
+//!   via a CodeView debug directory entry.
+
+use anyhow::{anyhow, Context, Result};
+use byteorder::{BigEndian, ByteOrder, LittleEndian};
+use slog::warn;
+use std::path::{Path, PathBuf};
+
+use crate::fsutils::copy_file;
+
+/// Name of the directory debug files are written to when not adjacent.
+pub const DEBUG_INFO_DIR: &str = "debug";
+
+/// The kind of split debug information.
+#[derive(Clone, Debug, PartialEq)]
+pub enum DebugInfoKind {
+    /// ELF debug file referenced via `.gnu_debuglink`.
+    GnuDebugLink,
+    /// Mach-O `.dSYM` bundle.
+    Dsym,
+    /// MSVC program database.
+    Pdb,
+}
+
+impl DebugInfoKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DebugInfoKind::GnuDebugLink => "gnu-debuglink",
+            DebugInfoKind::Dsym => "dsym",
+            DebugInfoKind::Pdb => "pdb",
+        }
+    }
+}
+
+/// Debug information split out of a binary.
+#[derive(Clone, Debug)]
+pub struct DebugInfo {
+    pub kind: DebugInfoKind,
+    /// Path to the debug file or bundle.
+    pub path: PathBuf,
+    /// Path to the binary the debug info belongs to.
+    pub binary: PathBuf,
+    /// Identifier pairing the binary with its debug info.
+    ///
+    /// This is the GNU build-id, Mach-O UUID, or PDB signature and age.
+    pub id: Option<String>,
+}
+
+fn align4(v: usize) -> usize {
+    (v + 3) & !3
+}
+
+/// Compute the CRC-32 used by `.gnu_debuglink`.
+fn gnu_debuglink_crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xffff_ffffu32;
+
+    for byte in data {
+        crc ^= u32::from(*byte);
+        for _ in 0..8 {
+            let mask = (!(crc & 1)).wrapping_add(1);
+            crc = (crc >> 1) ^ (0xedb8_8320 & mask);
+        }
+    }
+
+    !crc
+}
+
+fn parse_elf(data: &[u8]) -> Result<goblin::elf::Elf> {
+    goblin::elf::Elf::parse(data).map_err(|e| anyhow!("parsing ELF: {}", e))
+}
+
+/// Obtain the content of a named ELF section.
+fn elf_section<'a>(data: &'a [u8], elf: &goblin::elf::Elf, name: &str) -> Option<&'a [u8]> {
+    elf.section_headers
+        .iter()
+        .find(|sh| {
+            sh.sh_type != goblin::elf::section_header::SHT_NOBITS
+                && elf.shdr_strtab.get(sh.sh_name).and_then(|r| r.ok()) == Some(name)
+        })
+        .and_then(|sh| data.get(sh.file_range()))
+}
+
+fn elf_read_u32(elf: &goblin::elf::Elf, data: &[u8]) -> u32 {
+    if elf.little_endian {
+        LittleEndian::read_u32(data)
+    } else {
+        BigEndian::read_u32(data)
+    }
+}
+
+/// Resolve the GNU build-id of an ELF binary, as hex.
+pub fn elf_build_id(data: &[u8]) -> Result<Option<String>> {
+    let elf = parse_elf(data)?;
+
+    let note = match elf_section(data, &elf, ".note.gnu.build-id") {
+        Some(note) if note.len() >= 12 => note,
+        _ => return Ok(None),
+    };
+
+    let name_size = elf_read_u32(&elf, &note[0..4]) as usize;
+    let desc_size = elf_read_u32(&elf, &note[4..8]) as usize;
+    let desc_start = 12 + align4(name_size);
+
+    Ok(note
+        .get(desc_start..desc_start + desc_size)
+        .map(hex::encode))
+}
+
+/// Resolve the file name and CRC recorded in an ELF `.gnu_debuglink` section.
+pub fn elf_debuglink(data: &[u8]) -> Result<Option<(String, u32)>> {
+    let elf = parse_elf(data)?;
+
+    let section = match elf_section(data, &elf, ".gnu_debuglink") {
+        Some(section) => section,
+        None => return Ok(None),
+    };
+
+    let name_end = section
+        .iter()
+        .position(|b| *b == 0)
+        .ok_or_else(|| anyhow!(".gnu_debuglink file name is not terminated"))?;
+    let crc_start = align4(name_end + 1);
+    let crc = section
+        .get(crc_start..crc_start + 4)
+        .ok_or_else(|| anyhow!(".gnu_debuglink is truncated"))?;
+
+    Ok(Some((
+        String::from_utf8_lossy(&section[0..name_end]).to_string(),
+        elf_read_u32(&elf, crc),
+    )))
+}
+
+/// Resolve the `LC_UUID` of a Mach-O binary.
+pub fn macho_uuid(data: &[u8]) -> Result<Option<String>> {
+    let macho =
+        match goblin::mach::Mach::parse(data).map_err(|e| anyhow!("parsing Mach-O: {}", e))? {
+            goblin::mach::Mach::Binary(macho) => macho,
+            goblin::mach::Mach::Fat(_) => return Ok(None),
+        };
+
+    Ok(macho.load_commands.iter().find_map(|lc| match lc.command {
+        goblin::mach::load_command::CommandVariant::Uuid(cmd) => Some(
+            uuid::Uuid::from_bytes(cmd.uuid)
+                .to_hyphenated()
+                .to_string()
+                .to_uppercase(),
+        ),
+        _ => None,
+    }))
+}
+
+/// Resolve the PDB file name and signature referenced by a PE binary.
+///
+/// The signature is formatted like symbol servers expect: the GUID as hex
+/// followed by the age.
+pub fn pe_pdb_reference(data: &[u8]) -> Result<Option<(String, String)>> {
+    let pe = goblin::pe::PE::parse(data).map_err(|e| anyhow!("parsing PE: {}", e))?;
+
+    let cv = match pe.debug_data.and_then(|d| d.codeview_pdb70_debug_info) {
+        Some(cv) => cv,
+        None => return Ok(None),
+    };
+
+    let filename = cv.filename.split(|b| *b == 0).next().unwrap_or(&[]);
+    let sig = &cv.signature;
+
+    let id = format!(
+        "{:08X}{:04X}{:04X}{}{:X}",
+        LittleEndian::read_u32(&sig[0..4]),
+        LittleEndian::read_u16(&sig[4..6]),
+        LittleEndian::read_u16(&sig[6..8]),
+        hex::encode_upper(&sig[8..16]),
+        cv.age
+    );
+
+    Ok(Some((String::from_utf8_lossy(filename).to_string(), id)))
+}
+
+fn run_tool(logger: &slog::Logger, program: &str, args: &[&std::ffi::OsStr]) -> Result<()> {
+    warn!(
+        logger,
+        "running {} {}",
+        program,
+        args.iter()
+            .map(|a| a.to_string_lossy().to_string())
+            .collect::<Vec<String>>()
+            .join(" ")
+    );
+
+    let output = std::process::Command::new(program)
+        .args(args)
+        .output()
+        .with_context(|| format!("running {}; is it installed?", program))?;
+
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(anyhow!(
+            "{} failed: {}",
+            program,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ))
+    }
+}
+
+fn file_name(path: &Path) -> Result<String> {
+    Ok(path
+        .file_name()
+        .ok_or_else(|| anyhow!("{} has no file name", path.display()))?
+        .to_string_lossy()
+        .to_string())
+}
+
+fn split_elf(logger: &slog::Logger, exe_path: &Path, dest_dir: &Path) -> Result<DebugInfo> {
+    let debug_path = dest_dir.join(format!("{}.debug", file_name(exe_path)?));
+
+    run_tool(
+        logger,
+        "objcopy",
+        &[
+            "--only-keep-debug".as_ref(),
+            exe_path.as_os_str(),
+            debug_path.as_os_str(),
+        ],
+    )?;
+
+    let mut debuglink = std::ffi::OsString::from("--add-gnu-debuglink=");
+    debuglink.push(&debug_path);
+
+    run_tool(
+        logger,
+        "objcopy",
+        &[
+            "--strip-debug".as_ref(),
+            debuglink.as_os_str(),
+            exe_path.as_os_str(),
+        ],
+    )?;
+
+    let data = std::fs::read(exe_path)?;
+
+    Ok(DebugInfo {
+        kind: DebugInfoKind::GnuDebugLink,
+        path: debug_path,
+        binary: exe_path.to_path_buf(),
+        id: elf_build_id(&data)?,
+    })
+}
+
+fn split_macho(logger: &slog::Logger, exe_path: &Path, dest_dir: &Path) -> Result<DebugInfo> {
+    let dsym_path = dest_dir.join(format!("{}.dSYM", file_name(exe_path)?));
+
+    run_tool(
+        logger,
+        "dsymutil",
+        &[exe_path.as_os_str(), "-o".as_ref(), dsym_path.as_os_str()],
+    )?;
+    run_tool(logger, "strip", &["-S".as_ref(), exe_path.as_os_str()])?;
+
+    let data = std::fs::read(exe_path)?;
+
+    Ok(DebugInfo {
+        kind: DebugInfoKind::Dsym,
+        path: dsym_path,
+        binary: exe_path.to_path_buf(),
+        id: macho_uuid(&data)?,
+    })
+}
+
+fn collect_pdb(logger: &slog::Logger, exe_path: &Path, dest_dir: &Path) -> Result<DebugInfo> {
+    let data = std::fs::read(exe_path)?;
+
+    let (pdb_path, id) = pe_pdb_reference(&data)?.ok_or_else(|| {
+        anyhow!(
+            "{} does not reference a PDB; was it built with debug info?",
+            exe_path.display()
+        )
+    })?;
+
+    // The PDB path is recorded with Windows separators, which Path won't
+    // split on other platforms.
+    let pdb_name = pdb_path
+        .rsplit(|c| c == '\\' || c == '/')
+        .next()
+        .unwrap_or(&pdb_path)
+        .to_string();
+
+    // Keep the file name recorded in the executable so debuggers find it.
+    let dest_path = dest_dir.join(&pdb_name);
+    warn!(logger, "copying {} to {}", pdb_path, dest_path.display());
+    copy_file(Path::new(&pdb_path), &dest_path)?;
+
+    Ok(DebugInfo {
+        kind: DebugInfoKind::Pdb,
+        path: dest_path,
+        binary: exe_path.to_path_buf(),
+        id: Some(id),
+    })
+}
+
+/// Split debug information out of an executable.
+///
+/// The executable at `exe_path` is stripped in place and its debug info
+/// written into `dest_dir`. Returns `None` if splitting debug info isn't
+/// supported for the target.
+pub fn split_debug_info(
+    logger: &slog::Logger,
+    target_triple: &str,
+    exe_path: &Path,
+    dest_dir: &Path,
+) -> Result<Option<DebugInfo>> {
+    std::fs::create_dir_all(dest_dir)?;
+
+    if target_triple.contains("-linux-") {
+        Ok(Some(split_elf(logger, exe_path, dest_dir)?))
+    } else if target_triple.contains("-apple-darwin") {
+        Ok(Some(split_macho(logger, exe_path, dest_dir)?))
+    } else if target_triple.contains("-pc-windows-msvc") {
+        Ok(Some(collect_pdb(logger, exe_path, dest_dir)?))
+    } else {
+        warn!(
+            logger,
+            "splitting debug info is not supported for {}; leaving {} as-is",
+            target_triple,
+            exe_path.display()
+        );
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::get_logger;
+
+    /// Copy the running test executable, which has debug info.
+    fn copy_test_exe(dest_dir: &Path, name: &str) -> Result<PathBuf> {
+        let exe_path = dest_dir.join(name);
+        std::fs::copy(std::env::current_exe()?, &exe_path)?;
+        Ok(exe_path)
+    }
+
+    #[test]
+    fn test_gnu_debuglink_crc32() {
+        assert_eq!(gnu_debuglink_crc32(b""), 0);
+        assert_eq!(gnu_debuglink_crc32(b"123456789"), 0xcbf4_3926);
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_split_elf() -> Result<()> {
+        let temp_dir = tempdir::TempDir::new("pyoxidizer-test")?;
+        let exe_path = copy_test_exe(temp_dir.path(), "myapp")?;
+        let debug_dir = temp_dir.path().join(DEBUG_INFO_DIR);
+
+        let original = std::fs::read(&exe_path)?;
+        let original_build_id = elf_build_id(&original)?;
+
+        let info = split_debug_info(&get_logger()?, env!("HOST"), &exe_path, &debug_dir)?.unwrap();
+        assert_eq!(info.kind, DebugInfoKind::GnuDebugLink);
+        assert_eq!(info.path, debug_dir.join("myapp.debug"));
+
+        let stripped = std::fs::read(&exe_path)?;
+        let debug = std::fs::read(&info.path)?;
+        assert!(stripped.len() < original.len());
+
+        let (link_name, link_crc) = elf_debuglink(&stripped)?.unwrap();
+        assert_eq!(link_name, "myapp.debug");
+        assert_eq!(link_crc, gnu_debuglink_crc32(&debug));
+
+        assert_eq!(info.id, original_build_id);
+        assert_eq!(elf_build_id(&debug)?, original_build_id);
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(target_os = "macos")]
+    fn test_split_macho() -> Result<()> {
+        let temp_dir = tempdir::TempDir::new("pyoxidizer-test")?;
+        let exe_path = copy_test_exe(temp_dir.path(), "myapp")?;
+
+        let info =
+            split_debug_info(&get_logger()?, env!("HOST"), &exe_path, temp_dir.path())?.unwrap();
+        assert_eq!(info.kind, DebugInfoKind::Dsym);
+        assert_eq!(info.path, temp_dir.path().join("myapp.dSYM"));
+
+        let dwarf = std::fs::read(
+            info.path
+                .join("Contents")
+                .join("Resources")
+                .join("DWARF")
+                .join("myapp"),
+        )?;
+
+        assert!(info.id.is_some());
+        assert_eq!(macho_uuid(&dwarf)?, info.id);
+        assert_eq!(macho_uuid(&std::fs::read(&exe_path)?)?, info.id);
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(all(windows, target_env = "msvc"))]
+    fn test_collect_pdb() -> Result<()> {
+        let temp_dir = tempdir::TempDir::new("pyoxidizer-test")?;
+        let exe_path = copy_test_exe(temp_dir.path(), "myapp.exe")?;
+        let debug_dir = temp_dir.path().join(DEBUG_INFO_DIR);
+
+        let (pdb_path, id) = pe_pdb_reference(&std::fs::read(&exe_path)?)?.unwrap();
+
+        let info = split_debug_info(&get_logger()?, env!("HOST"), &exe_path, &debug_dir)?.unwrap();
+        assert_eq!(info.kind, DebugInfoKind::Pdb);
+        assert_eq!(
+            info.path,
+            debug_dir.join(Path::new(&pdb_path).file_name().unwrap())
+        );
+        assert_eq!(info.id, Some(id));
+        assert_eq!(std::fs::read(&info.path)?, std::fs::read(&pdb_path)?);
+
+        Ok(())
+    }
+}
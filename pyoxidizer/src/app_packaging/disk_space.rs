@@ -0,0 +1,369 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/*!
+Checking free disk space before expensive build phases.
+
+Running out of disk space while a Python distribution is extracted or while
+`cargo build` runs leaves partial state behind and fails with I/O errors not
+mentioning disk space. Before those phases, the space they need is
+estimated and compared against the free space of the filesystems they write
+to. Requirements on the same filesystem are added up.
+
+Estimates are deliberately rough. A safety margin, configurable with the
+`PYOXIDIZER_DISK_SPACE_MARGIN` environment variable (in MB), is added per
+filesystem.
+*/
+
+use anyhow::{anyhow, Context, Result};
+use slog::warn;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use super::manifest::{BuildManifest, BUILD_MANIFEST_FILENAME};
+use super::state::BuildContext;
+
+/// Environment variable holding the safety margin in MB.
+pub const MARGIN_ENV: &str = "PYOXIDIZER_DISK_SPACE_MARGIN";
+
+const MB: u64 = 1024 * 1024;
+
+/// Safety margin if `MARGIN_ENV` isn't set.
+pub const DEFAULT_MARGIN: u64 = 256 * MB;
+
+/// Estimated size of a Python distribution archive.
+pub const DISTRIBUTION_ARCHIVE_SIZE: u64 = 100 * MB;
+
+/// Estimated size of an extracted Python distribution.
+pub const DISTRIBUTION_EXTRACTED_SIZE: u64 = 400 * MB;
+
+/// Estimated size of artifacts and the packaged application without a previous build.
+pub const DEFAULT_APPLICATION_SIZE: u64 = 150 * MB;
+
+/// Estimated size written to the target directory by a first `cargo build`.
+pub const CARGO_BUILD_SIZE: u64 = 500 * MB;
+
+/// Space a build phase needs at a path.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SpaceRequirement {
+    pub path: PathBuf,
+    pub description: String,
+    pub bytes: u64,
+}
+
+impl SpaceRequirement {
+    pub fn new(path: &Path, description: &str, bytes: u64) -> Self {
+        SpaceRequirement {
+            path: path.to_path_buf(),
+            description: description.to_string(),
+            bytes,
+        }
+    }
+}
+
+/// Probes filesystems for free space.
+pub trait SpaceProbe {
+    /// Obtain an identifier of the filesystem holding `path` and the bytes available on it.
+    ///
+    /// `path` may not exist yet.
+    fn probe(&self, path: &Path) -> Result<(String, u64)>;
+}
+
+/// Probes the filesystems of this machine.
+pub struct SystemSpaceProbe;
+
+#[cfg(unix)]
+fn filesystem_id(path: &Path) -> Result<String> {
+    use std::os::unix::fs::MetadataExt;
+
+    Ok(std::fs::metadata(path)?.dev().to_string())
+}
+
+#[cfg(windows)]
+fn filesystem_id(path: &Path) -> Result<String> {
+    // The volume is the prefix of the canonical path, e.g. `\\?\C:`.
+    Ok(std::fs::canonicalize(path)?
+        .components()
+        .next()
+        .map(|c| c.as_os_str().to_string_lossy().to_lowercase())
+        .unwrap_or_default())
+}
+
+impl SpaceProbe for SystemSpaceProbe {
+    fn probe(&self, path: &Path) -> Result<(String, u64)> {
+        let existing = path
+            .ancestors()
+            .find(|p| p.exists())
+            .ok_or_else(|| anyhow!("no existing parent of {}", path.display()))?;
+
+        let available = fs2::available_space(existing)
+            .with_context(|| format!("determining free space of {}", existing.display()))?;
+
+        Ok((filesystem_id(existing)?, available))
+    }
+}
+
+/// A filesystem not having enough free space.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Shortfall {
+    pub requirements: Vec<SpaceRequirement>,
+    pub margin: u64,
+    pub available: u64,
+}
+
+impl Shortfall {
+    pub fn required(&self) -> u64 {
+        self.requirements.iter().map(|r| r.bytes).sum::<u64>() + self.margin
+    }
+}
+
+pub(crate) fn format_mb(bytes: u64) -> String {
+    format!("{} MB", (bytes + MB - 1) / MB)
+}
+
+impl std::fmt::Display for Shortfall {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{}: {} needed, {} available, short by {}",
+            self.requirements
+                .iter()
+                .map(|r| r.path.display().to_string())
+                .collect::<Vec<_>>()
+                .join(", "),
+            format_mb(self.required()),
+            format_mb(self.available),
+            format_mb(self.required() - self.available)
+        )?;
+
+        for r in &self.requirements {
+            write!(f, "\n    {}: {}", r.description, format_mb(r.bytes))?;
+        }
+
+        write!(f, "\n    safety margin: {}", format_mb(self.margin))
+    }
+}
+
+/// Find filesystems without enough free space for requirements.
+///
+/// Requirements of 0 bytes are ignored.
+pub fn find_shortfalls(
+    probe: &dyn SpaceProbe,
+    requirements: &[SpaceRequirement],
+    margin: u64,
+) -> Result<Vec<Shortfall>> {
+    let mut filesystems: BTreeMap<String, (u64, Vec<SpaceRequirement>)> = BTreeMap::new();
+
+    for requirement in requirements.iter().filter(|r| r.bytes > 0) {
+        let (id, available) = probe.probe(&requirement.path)?;
+
+        filesystems
+            .entry(id)
+            .or_insert_with(|| (available, Vec::new()))
+            .1
+            .push(requirement.clone());
+    }
+
+    Ok(filesystems
+        .into_iter()
+        .map(|(_, (available, requirements))| Shortfall {
+            requirements,
+            margin,
+            available,
+        })
+        .filter(|s| s.required() > s.available)
+        .collect())
+}
+
+/// Obtain the safety margin from the environment.
+pub fn margin_from_env() -> Result<u64> {
+    match std::env::var(MARGIN_ENV) {
+        Ok(value) => value
+            .trim()
+            .parse::<u64>()
+            .map(|mb| mb * MB)
+            .map_err(|_| anyhow!("{} must be a number of MB; got {}", MARGIN_ENV, value)),
+        Err(_) => Ok(DEFAULT_MARGIN),
+    }
+}
+
+/// Fail if filesystems don't have enough free space for a build phase.
+pub fn check_space(
+    logger: &slog::Logger,
+    probe: &dyn SpaceProbe,
+    phase: &str,
+    requirements: &[SpaceRequirement],
+    margin: u64,
+) -> Result<()> {
+    let shortfalls = find_shortfalls(probe, requirements, margin)?;
+
+    if shortfalls.is_empty() {
+        return Ok(());
+    }
+
+    for shortfall in &shortfalls {
+        warn!(logger, "insufficient disk space: {}", shortfall);
+    }
+
+    Err(anyhow!(
+        "insufficient disk space to {}:\n  {}\nfree up space, lower the safety margin with {} or pass --skip-space-check",
+        phase,
+        shortfalls
+            .iter()
+            .map(|s| s.to_string())
+            .collect::<Vec<_>>()
+            .join("\n  "),
+        MARGIN_ENV
+    ))
+}
+
+/// Size of the application packaged by the previous build, if known.
+fn previous_application_size(context: &BuildContext) -> Option<u64> {
+    BuildManifest::from_path(&context.app_path.join(BUILD_MANIFEST_FILENAME))
+        .ok()
+        .map(|manifest| manifest.files.iter().map(|f| f.size).sum())
+}
+
+fn is_empty_dir(path: &Path) -> bool {
+    std::fs::read_dir(path).map_or(true, |mut entries| entries.next().is_none())
+}
+
+/// Space needed to generate PyOxidizer artifacts.
+pub fn artifacts_requirements(context: &BuildContext) -> Vec<SpaceRequirement> {
+    let mut res = Vec::new();
+
+    if is_empty_dir(&context.distributions_path) {
+        res.push(SpaceRequirement::new(
+            &context.distributions_path,
+            "Python distribution archive",
+            DISTRIBUTION_ARCHIVE_SIZE,
+        ));
+    }
+
+    if !context.python_distribution_path.exists() {
+        res.push(SpaceRequirement::new(
+            &context.python_distribution_path,
+            "extracted Python distribution",
+            DISTRIBUTION_EXTRACTED_SIZE,
+        ));
+    }
+
+    res.push(SpaceRequirement::new(
+        &context.pyoxidizer_artifacts_path,
+        "PyOxidizer artifacts",
+        previous_application_size(context).unwrap_or(DEFAULT_APPLICATION_SIZE),
+    ));
+
+    res
+}
+
+/// Space needed to build the project with cargo and package the application.
+pub fn cargo_requirements(context: &BuildContext) -> Vec<SpaceRequirement> {
+    let previous = previous_application_size(context);
+
+    let target_bytes = if context.app_exe_target_path.exists() {
+        // Incremental builds mostly relink the executable.
+        std::fs::metadata(&context.app_exe_target_path)
+            .map(|m| m.len())
+            .unwrap_or(CARGO_BUILD_SIZE)
+    } else {
+        CARGO_BUILD_SIZE
+    };
+
+    vec![
+        SpaceRequirement::new(&context.target_base_path, "cargo build", target_bytes),
+        SpaceRequirement::new(
+            &context.app_path,
+            "packaged application",
+            previous.unwrap_or(DEFAULT_APPLICATION_SIZE),
+        ),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::*;
+
+    struct FakeProbe(Vec<(&'static str, &'static str, u64)>);
+
+    impl SpaceProbe for FakeProbe {
+        fn probe(&self, path: &Path) -> Result<(String, u64)> {
+            self.0
+                .iter()
+                .find(|(prefix, _, _)| path.starts_with(prefix))
+                .map(|(_, id, available)| (id.to_string(), *available))
+                .ok_or_else(|| anyhow!("no filesystem for {}", path.display()))
+        }
+    }
+
+    #[test]
+    fn test_find_shortfalls() -> Result<()> {
+        let probe = FakeProbe(vec![
+            ("/cache", "fs1", 600 * MB),
+            ("/build", "fs1", 600 * MB),
+            ("/target", "fs2", 1000 * MB),
+        ]);
+
+        let requirements = vec![
+            SpaceRequirement::new(Path::new("/cache/dist"), "archive", 100 * MB),
+            SpaceRequirement::new(Path::new("/build/python"), "extraction", 400 * MB),
+            SpaceRequirement::new(Path::new("/target"), "cargo build", 500 * MB),
+            SpaceRequirement::new(Path::new("/elsewhere"), "nothing", 0),
+        ];
+
+        // Both paths on fs1 need 500 MB plus margin.
+        assert!(find_shortfalls(&probe, &requirements, 100 * MB)?.is_empty());
+
+        let shortfalls = find_shortfalls(&probe, &requirements, 200 * MB)?;
+        assert_eq!(shortfalls.len(), 1);
+        assert_eq!(shortfalls[0].required(), 700 * MB);
+        assert_eq!(shortfalls[0].available, 600 * MB);
+        assert_eq!(
+            shortfalls[0].to_string(),
+            "/cache/dist, /build/python: 700 MB needed, 600 MB available, short by 100 MB\n    \
+             archive: 100 MB\n    \
+             extraction: 400 MB\n    \
+             safety margin: 200 MB"
+        );
+
+        let shortfalls = find_shortfalls(&probe, &requirements, 600 * MB)?;
+        assert_eq!(shortfalls.len(), 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_space() -> Result<()> {
+        let logger = get_logger()?;
+        let probe = FakeProbe(vec![("/", "fs", 10 * MB)]);
+        let requirements = vec![SpaceRequirement::new(
+            Path::new("/target"),
+            "cargo build",
+            20 * MB,
+        )];
+
+        let err = check_space(&logger, &probe, "build", &requirements, 0)
+            .unwrap_err()
+            .to_string();
+        assert!(err.starts_with(
+            "insufficient disk space to build:\n  /target: 20 MB needed, 10 MB available, short by 10 MB"
+        ));
+        assert!(err.contains("--skip-space-check"));
+
+        assert!(check_space(&logger, &probe, "build", &requirements[0..0], 0).is_ok());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_system_space_probe() -> Result<()> {
+        let temp_dir = tempdir::TempDir::new("pyoxidizer-test")?;
+
+        let (id, _) = SystemSpaceProbe.probe(&temp_dir.path().join("missing").join("dir"))?;
+        assert_eq!(SystemSpaceProbe.probe(temp_dir.path())?.0, id);
+
+        Ok(())
+    }
+}
@@ -3,8 +3,71 @@
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
 use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
 use std::path::{Path, PathBuf};
 
+use super::build_vars::{build_var_env, build_vars};
+use crate::environment::{canonicalize_path, offline, OFFLINE_ENV};
+use crate::licensing::ExtensionModuleLicensePolicy;
+use crate::py_packaging::transform::SourceTransform;
+use crate::starlark::eval::EvaluationCache;
+
+/// Directory of the build directory Python distributions are downloaded to
+/// when there is no distribution cache.
+pub const PYTHON_DISTRIBUTIONS_DIR: &str = "python_distributions";
+
+/// A path outside the project directory referenced by a resource rule.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct ExternalPath {
+    /// Name of the rule referencing the path.
+    pub rule: String,
+
+    /// Path as referenced, or the symlink leading outside the project.
+    pub path: PathBuf,
+
+    /// Where the path resolves to.
+    pub target: PathBuf,
+}
+
+/// Settings of an invocation that configs are evaluated with.
+///
+/// Builds pass them to PyOxidizer running from the build script through the
+/// environment of cargo, where `from_env()` picks them up again.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct EvalOptions {
+    /// Whether to work offline.
+    pub offline: bool,
+
+    /// Build variables given with `--var` or by the environment.
+    pub build_vars: BTreeMap<String, String>,
+}
+
+impl EvalOptions {
+    /// Obtain the options defined by the environment.
+    pub fn from_env() -> EvalOptions {
+        EvalOptions {
+            offline: offline(),
+            build_vars: build_vars(),
+        }
+    }
+
+    /// Environment variables defining these options for child processes.
+    pub fn child_envs(&self) -> Vec<(String, String)> {
+        let mut envs = Vec::new();
+
+        if self.offline {
+            envs.push((OFFLINE_ENV.to_string(), "1".to_string()));
+        }
+
+        for (name, value) in &self.build_vars {
+            envs.push((build_var_env(name), value.clone()));
+        }
+
+        envs
+    }
+}
+
 /// Holds state for evaluating app packaging.
 #[derive(Debug, Clone)]
 pub struct EnvironmentContext {
@@ -26,6 +89,55 @@ pub struct EnvironmentContext {
 
     /// Path where Python distributions are written.
     pub python_distributions_path: PathBuf,
+
+    /// Whether the application is built in release mode.
+    pub release: bool,
+
+    /// Build variables given with `--var`.
+    pub build_vars: BTreeMap<String, String>,
+
+    /// Whether to work offline.
+    ///
+    /// Python distributions then come from the distribution cache and pip
+    /// doesn't use package indexes.
+    pub offline: bool,
+
+    /// Python packages installed by `pip_install()` and their versions.
+    pub python_package_versions: BTreeMap<String, String>,
+
+    /// Python packages installed by `pip_install()` and the SHA-256 of their `RECORD` file.
+    pub python_package_hashes: BTreeMap<String, String>,
+
+    /// Data files read by `read_json()` and `read_toml()`.
+    pub read_files: Vec<PathBuf>,
+
+    /// Existing filesystem paths referenced by the configuration.
+    pub input_paths: BTreeSet<PathBuf>,
+
+    /// Scripts registered by `run_python_packaging_script()`.
+    pub packaging_scripts: Vec<PathBuf>,
+
+    /// Policy registered by `extension_module_license_filter()`.
+    pub extension_module_license_policy: Option<ExtensionModuleLicensePolicy>,
+
+    /// Options registered by `require_sqlite_options()`.
+    pub required_sqlite_options: Vec<String>,
+
+    /// Transforms registered by `register_source_transform()`.
+    pub source_transforms: Vec<SourceTransform>,
+
+    /// Files embedded by `embedded_config_file()` and the SHA-256 of their content.
+    pub embedded_config_files: BTreeMap<String, String>,
+
+    /// Top-level packages collected by `pip_install()` which can't be
+    /// imported from memory and why.
+    pub filesystem_relative_packages: BTreeMap<String, String>,
+
+    /// Paths outside the project directory referenced by resource rules.
+    pub external_paths: Vec<ExternalPath>,
+
+    /// Results shared with other evaluations of the same invocation.
+    pub eval_cache: EvaluationCache,
 }
 
 impl EnvironmentContext {
@@ -46,12 +158,184 @@ impl EnvironmentContext {
             config_path: config_path.to_path_buf(),
             build_target: build_target.to_string(),
             build_path: build_path.clone(),
-            python_distributions_path: build_path.join("python_distributions"),
+            python_distributions_path: build_path.join(PYTHON_DISTRIBUTIONS_DIR),
+            release: false,
+            build_vars: build_vars(),
+            offline: offline(),
+            python_package_versions: BTreeMap::new(),
+            python_package_hashes: BTreeMap::new(),
+            read_files: Vec::new(),
+            input_paths: BTreeSet::new(),
+            packaging_scripts: Vec::new(),
+            extension_module_license_policy: None,
+            required_sqlite_options: Vec::new(),
+            source_transforms: Vec::new(),
+            embedded_config_files: BTreeMap::new(),
+            filesystem_relative_packages: BTreeMap::new(),
+            external_paths: Vec::new(),
+            eval_cache: EvaluationCache::default(),
         })
     }
 
     pub fn set_build_path(&mut self, path: &Path) {
         self.build_path = path.to_path_buf();
-        self.python_distributions_path = path.join("python_distributions");
+        self.python_distributions_path = path.join(PYTHON_DISTRIBUTIONS_DIR);
+    }
+
+    /// Resolve a path provided by the configuration file to a filesystem path.
+    ///
+    /// Relative paths are evaluated relative to the directory containing the
+    /// configuration file. A leading `~` is expanded to the home directory of
+    /// the current user.
+    pub fn resolve_path(&self, path: &str) -> PathBuf {
+        resolve_config_path(&self.cwd, home_dir().as_ref().map(|p| p.as_path()), path)
+    }
+
+    /// Resolve where a path points to if it is outside the project directory.
+    ///
+    /// Symlinks are followed, so a link in the project pointing elsewhere
+    /// is external. Missing paths are judged by their nearest existing
+    /// ancestor.
+    pub fn external_target(&self, path: &Path) -> Option<PathBuf> {
+        let root = canonicalize_path(&self.cwd).unwrap_or_else(|_| self.cwd.clone());
+
+        let target = path
+            .ancestors()
+            .filter_map(|p| canonicalize_path(p).ok())
+            .next()
+            .unwrap_or_else(|| path.to_path_buf());
+
+        if target.starts_with(&root) {
+            None
+        } else {
+            Some(target)
+        }
+    }
+}
+
+/// Obtain the home directory of the current user.
+/// Obtain the home directory of the current user.
+pub fn home_dir() -> Option<PathBuf> {
+    let var = if cfg!(windows) { "USERPROFILE" } else { "HOME" };
+
+    match std::env::var_os(var) {
+        Some(value) if !value.is_empty() => Some(PathBuf::from(value)),
+        _ => None,
+    }
+}
+
+fn resolve_config_path(cwd: &Path, home: Option<&Path>, path: &str) -> PathBuf {
+    let is_separator = |c: char| c == '/' || (cfg!(windows) && c == '\\');
+
+    if let Some(home) = home {
+        if path == "~" {
+            return home.to_path_buf();
+        } else if path.starts_with('~') && path[1..].starts_with(is_separator) {
+            return home.join(path[1..].trim_start_matches(is_separator));
+        }
+    }
+
+    let p = PathBuf::from(path);
+
+    if p.is_absolute() {
+        p
+    } else {
+        cwd.join(p)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_eval_options_child_envs() {
+        assert!(EvalOptions::default().child_envs().is_empty());
+
+        let mut options = EvalOptions::default();
+        options.offline = true;
+        options
+            .build_vars
+            .insert("customer".to_string(), "acme".to_string());
+        assert_eq!(
+            options.child_envs(),
+            vec![
+                (OFFLINE_ENV.to_string(), "1".to_string()),
+                ("PYOXIDIZER_VAR_customer".to_string(), "acme".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn test_resolve_relative() {
+        let cwd = PathBuf::from("proj");
+        assert_eq!(
+            resolve_config_path(&cwd, None, "reqs/base.txt"),
+            cwd.join("reqs/base.txt")
+        );
+        assert_eq!(resolve_config_path(&cwd, None, "~foo"), cwd.join("~foo"));
+    }
+
+    #[test]
+    fn test_resolve_home() {
+        let cwd = PathBuf::from("proj");
+        let home = PathBuf::from("home");
+
+        assert_eq!(resolve_config_path(&cwd, Some(&home), "~"), home);
+        assert_eq!(
+            resolve_config_path(&cwd, Some(&home), "~/reqs/base.txt"),
+            home.join("reqs/base.txt")
+        );
+        assert_eq!(
+            resolve_config_path(&cwd, Some(&home), "~user/foo"),
+            cwd.join("~user/foo")
+        );
+        // Without a home directory, ~ is treated literally.
+        assert_eq!(resolve_config_path(&cwd, None, "~/foo"), cwd.join("~/foo"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_resolve_unix() {
+        let cwd = PathBuf::from("/proj");
+        let home = PathBuf::from("/home/me");
+
+        assert_eq!(
+            resolve_config_path(&cwd, Some(&home), "/etc/reqs.txt"),
+            PathBuf::from("/etc/reqs.txt")
+        );
+        assert_eq!(
+            resolve_config_path(&cwd, Some(&home), "~/reqs.txt"),
+            PathBuf::from("/home/me/reqs.txt")
+        );
+        // Backslashes aren't separators on POSIX.
+        assert_eq!(
+            resolve_config_path(&cwd, Some(&home), "~\\reqs.txt"),
+            PathBuf::from("/proj/~\\reqs.txt")
+        );
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn test_resolve_windows() {
+        let cwd = PathBuf::from("C:\\proj");
+        let home = PathBuf::from("C:\\Users\\me");
+
+        assert_eq!(
+            resolve_config_path(&cwd, Some(&home), "D:\\reqs.txt"),
+            PathBuf::from("D:\\reqs.txt")
+        );
+        assert_eq!(
+            resolve_config_path(&cwd, Some(&home), "~\\reqs.txt"),
+            PathBuf::from("C:\\Users\\me\\reqs.txt")
+        );
+        assert_eq!(
+            resolve_config_path(&cwd, Some(&home), "~/reqs.txt"),
+            PathBuf::from("C:\\Users\\me\\reqs.txt")
+        );
+        assert_eq!(
+            resolve_config_path(&cwd, Some(&home), "reqs\\base.txt"),
+            PathBuf::from("C:\\proj\\reqs\\base.txt")
+        );
     }
 }
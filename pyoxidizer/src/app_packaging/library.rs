@@ -0,0 +1,146 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/*!
+Shared libraries embedding Python for use by a host application.
+
+Projects created with `pyoxidizer init --lib` are `cdylib` crates exporting
+a small C ABI. Functions are prefixed with the crate's library name, e.g.
+`myapp_init()`. A C header declaring them is written next to the packaged
+library.
+*/
+
+use anyhow::{anyhow, Result};
+
+/// Obtain the name a crate's library is built as.
+///
+/// This is the `[lib]` name if set, otherwise the package name with `-`
+/// replaced by `_`.
+pub fn library_name(manifest: &cargo_toml::Manifest) -> Result<String> {
+    if let Some(name) = manifest.lib.as_ref().and_then(|lib| lib.name.as_ref()) {
+        return Ok(name.clone());
+    }
+
+    match &manifest.package {
+        Some(package) => Ok(package.name.replace('-', "_")),
+        None => Err(anyhow!("Cargo.toml has no [package]")),
+    }
+}
+
+/// Whether a crate builds a shared library for loading from C.
+pub fn is_cdylib(manifest: &cargo_toml::Manifest) -> bool {
+    manifest
+        .lib
+        .as_ref()
+        .and_then(|lib| lib.crate_type.as_ref())
+        .map_or(false, |types| types.iter().any(|t| t == "cdylib"))
+}
+
+/// Obtain the filename of a shared library built by Cargo.
+pub fn shared_library_filename(target: &str, name: &str) -> String {
+    if target.contains("pc-windows") {
+        format!("{}.dll", name)
+    } else if target.contains("apple") {
+        format!("lib{}.dylib", name)
+    } else {
+        format!("lib{}.so", name)
+    }
+}
+
+/// Obtain the filename of the C header for a library.
+pub fn header_filename(name: &str) -> String {
+    format!("{}.h", name)
+}
+
+/// Generate the C header declaring the functions a library exports.
+pub fn library_header(name: &str) -> String {
+    let guard = format!("{}_H", name.to_uppercase());
+
+    format!(
+        "/* Generated by PyOxidizer. Do not edit. */\n\
+         \n\
+         #ifndef {guard}\n\
+         #define {guard}\n\
+         \n\
+         #ifdef __cplusplus\n\
+         extern \"C\" {{\n\
+         #endif\n\
+         \n\
+         /* Functions must not be called concurrently. The interpreter can't\n\
+         \x20* be initialized again once it is shut down. */\n\
+         \n\
+         /* Initialize the embedded Python interpreter. Returns 0 on success. */\n\
+         int {name}_init(void);\n\
+         \n\
+         /* Execute Python code in the __main__ module.\n\
+         \x20*\n\
+         \x20* Returns 0 on success, 1 if an exception was raised (it is printed\n\
+         \x20* to stderr) and -1 if the interpreter isn't initialized or code\n\
+         \x20* isn't UTF-8. */\n\
+         int {name}_eval(const char *code);\n\
+         \n\
+         /* Shut down the embedded Python interpreter. */\n\
+         void {name}_shutdown(void);\n\
+         \n\
+         #ifdef __cplusplus\n\
+         }}\n\
+         #endif\n\
+         \n\
+         #endif\n",
+        guard = guard,
+        name = name
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn manifest(data: &str) -> cargo_toml::Manifest {
+        cargo_toml::Manifest::from_slice(data.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn test_library_name() -> Result<()> {
+        let m = manifest("[package]\nname = \"my-lib\"\nversion = \"0.1.0\"\n");
+        assert_eq!(library_name(&m)?, "my_lib");
+        assert!(!is_cdylib(&m));
+
+        let m = manifest(
+            "[package]\nname = \"my-lib\"\nversion = \"0.1.0\"\n\n\
+             [lib]\nname = \"embedded\"\ncrate-type = [\"cdylib\"]\n",
+        );
+        assert_eq!(library_name(&m)?, "embedded");
+        assert!(is_cdylib(&m));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_shared_library_filename() {
+        assert_eq!(
+            shared_library_filename("x86_64-unknown-linux-gnu", "myapp"),
+            "libmyapp.so"
+        );
+        assert_eq!(
+            shared_library_filename("x86_64-apple-darwin", "myapp"),
+            "libmyapp.dylib"
+        );
+        assert_eq!(
+            shared_library_filename("x86_64-pc-windows-msvc", "myapp"),
+            "myapp.dll"
+        );
+    }
+
+    #[test]
+    fn test_library_header() {
+        let header = library_header("myapp");
+
+        assert!(header.contains("#ifndef MYAPP_H\n"));
+        assert!(header.contains("int myapp_init(void);\n"));
+        assert!(header.contains("int myapp_eval(const char *code);\n"));
+        assert!(header.contains("void myapp_shutdown(void);\n"));
+        assert!(header.contains("\n * be initialized again"));
+    }
+}
@@ -0,0 +1,707 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/*!
+Lockfiles recording the resolved inputs of a configuration.
+
+`pyoxidizer lock` writes a lockfile next to the configuration file
+recording the Python distribution, every package installed by
+`pip_install()` and the content of every file referenced by the
+configuration. Builds with `--locked` verify their inputs against it.
+
+All collections are sorted so lockfiles are stable and diff well.
+*/
+
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+use sha2::Digest;
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+
+use super::config::Config;
+use super::manifest::VerifyResult;
+use crate::cancel;
+use crate::fsutils::{sha256_file, write_file};
+use crate::py_packaging::distribution::PythonDistributionLocation;
+
+/// File name of lockfiles.
+pub const LOCKFILE_FILENAME: &str = "pyoxidizer.lock.json";
+
+/// Version of the lockfile format.
+pub const LOCKFILE_VERSION: u32 = 1;
+
+/// Directory names ignored when hashing directories.
+///
+/// These hold state that changes without the directory content changing.
+const IGNORED_DIR_NAMES: &[&str] = &["__pycache__", ".git", ".hg", ".svn"];
+
+/// The Python distribution a configuration resolved to.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct LockedDistribution {
+    pub url: Option<String>,
+    pub local_path: Option<String>,
+    pub sha256: String,
+}
+
+impl std::fmt::Display for LockedDistribution {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let location = self
+            .url
+            .as_ref()
+            .or_else(|| self.local_path.as_ref())
+            .map(String::as_str)
+            .unwrap_or("<unknown>");
+
+        write!(f, "{} (sha256 {})", location, self.sha256)
+    }
+}
+
+/// A Python package installed by `pip_install()`.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct LockedPackage {
+    pub version: String,
+    /// SHA-256 of the package's `RECORD` file, which lists the digest of
+    /// every installed file.
+    pub record_sha256: Option<String>,
+}
+
+/// Represents a `pyoxidizer.lock.json` file.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct Lockfile {
+    pub version: u32,
+    pub target_triple: String,
+    pub distribution: LockedDistribution,
+    /// Installed Python packages, keyed by name.
+    pub python_packages: BTreeMap<String, LockedPackage>,
+    /// SHA-256 of files and directories referenced by the configuration.
+    ///
+    /// Keys are paths relative to the configuration file's directory when
+    /// possible, using `/` as the separator.
+    pub files: BTreeMap<String, String>,
+}
+
+/// Obtain the path of `path` relative to `base` with `/` separators.
+pub(crate) fn relative_key(base: &Path, path: &Path) -> String {
+    match path.strip_prefix(base) {
+        Ok(relative) if relative.as_os_str().is_empty() => ".".to_string(),
+        Ok(relative) => relative
+            .components()
+            .map(|c| c.as_os_str().to_string_lossy().to_string())
+            .collect::<Vec<String>>()
+            .join("/"),
+        Err(_) => path.display().to_string().replace("\\", "/"),
+    }
+}
+
+/// Maximum number of threads hashing files.
+pub(crate) const HASH_THREADS: usize = 8;
+
+/// Fewer files than this are hashed on the calling thread.
+const PARALLEL_HASH_THRESHOLD: usize = 64;
+
+/// Compute the SHA-256 of a file unless the build was cancelled.
+fn sha256_file_cancellable(path: &Path) -> Result<String> {
+    cancel::check_cancelled()?;
+
+    sha256_file(path)
+}
+
+/// Compute the SHA-256 of files on up to `threads` threads.
+///
+/// Results are in the order of `files`.
+pub(crate) fn sha256_files(files: &[PathBuf], threads: usize) -> Vec<Result<String>> {
+    if threads < 2 || files.len() < PARALLEL_HASH_THRESHOLD {
+        return files
+            .iter()
+            .map(|path| sha256_file_cancellable(path))
+            .collect();
+    }
+
+    let chunk_size = (files.len() + threads - 1) / threads;
+
+    let handles = files
+        .chunks(chunk_size)
+        .map(|chunk| {
+            let chunk = chunk.to_vec();
+            cancel::spawn_thread(move || {
+                chunk
+                    .iter()
+                    .map(|path| sha256_file_cancellable(path))
+                    .collect::<Vec<_>>()
+            })
+        })
+        .collect::<Vec<_>>();
+
+    handles
+        .into_iter()
+        .flat_map(|handle| handle.join().expect("hashing thread panicked"))
+        .collect()
+}
+
+/// Obtain the files a directory digest covers, keyed by relative path in
+/// sorted order.
+///
+/// Paths under `exclude` are skipped.
+fn dir_files(path: &Path, exclude: &[PathBuf]) -> Result<Vec<(String, PathBuf)>> {
+    let walk = walkdir::WalkDir::new(path)
+        .sort_by(|a, b| a.file_name().cmp(b.file_name()))
+        .into_iter()
+        .filter_entry(|entry| {
+            let name = entry.file_name().to_string_lossy();
+
+            let ignored_dir =
+                entry.file_type().is_dir() && IGNORED_DIR_NAMES.contains(&name.as_ref());
+
+            !(ignored_dir
+                || name == LOCKFILE_FILENAME
+                || exclude.iter().any(|p| entry.path().starts_with(p)))
+        });
+
+    let mut res = Vec::new();
+
+    for entry in walk {
+        let entry = entry?;
+
+        if entry.file_type().is_dir() {
+            continue;
+        }
+
+        res.push((relative_key(path, entry.path()), entry.path().to_path_buf()));
+    }
+
+    Ok(res)
+}
+
+/// Compute the SHA-256 of each file or directory in `paths`, reporting
+/// errors per path.
+///
+/// Directory digests cover the relative path and content of every file in
+/// sorted order. Paths under `exclude` are skipped. Directories are listed
+/// first, then all files are hashed in parallel.
+pub(crate) fn sha256_paths_each(
+    paths: &BTreeSet<PathBuf>,
+    exclude: &[PathBuf],
+) -> BTreeMap<PathBuf, Result<String>> {
+    // None for files, the relative paths of the files inside for directories.
+    let mut listings = Vec::new();
+    let mut files = Vec::new();
+
+    for path in paths {
+        if path.is_dir() {
+            match dir_files(path, exclude) {
+                Ok(entries) => {
+                    let mut keys = Vec::with_capacity(entries.len());
+                    for (key, file) in entries {
+                        keys.push(key);
+                        files.push(file);
+                    }
+                    listings.push((path, Ok(Some(keys))));
+                }
+                Err(e) => listings.push((path, Err(e))),
+            }
+        } else {
+            files.push(path.clone());
+            listings.push((path, Ok(None)));
+        }
+    }
+
+    let mut digests = sha256_files(&files, HASH_THREADS).into_iter();
+    let mut next_digest = || digests.next().expect("a digest for every file");
+
+    listings
+        .into_iter()
+        .map(|(path, listing)| {
+            let digest = match listing {
+                Err(e) => Err(e),
+                Ok(None) => next_digest(),
+                Ok(Some(keys)) => {
+                    let mut hasher = sha2::Sha256::new();
+                    let mut error = None;
+
+                    // Every digest is consumed, even after an error, to stay
+                    // aligned with the files of later paths.
+                    for key in keys {
+                        match next_digest() {
+                            Ok(digest) => {
+                                hasher.input(key.as_bytes());
+                                hasher.input(b"\0");
+                                hasher.input(digest.as_bytes());
+                                hasher.input(b"\n");
+                            }
+                            Err(e) => {
+                                if error.is_none() {
+                                    error = Some(e);
+                                }
+                            }
+                        }
+                    }
+
+                    match error {
+                        Some(e) => Err(e),
+                        None => Ok(hex::encode(hasher.result())),
+                    }
+                }
+            };
+
+            (path.clone(), digest)
+        })
+        .collect()
+}
+
+/// Compute the SHA-256 of each file or directory in `paths`.
+pub(crate) fn sha256_paths(
+    paths: &BTreeSet<PathBuf>,
+    exclude: &[PathBuf],
+) -> Result<BTreeMap<PathBuf, String>> {
+    sha256_paths_each(paths, exclude)
+        .into_iter()
+        .map(|(path, digest)| Ok((path, digest?)))
+        .collect()
+}
+
+/// Compute the SHA-256 of the files and directories referenced by a configuration.
+///
+/// Directory digests change when files inside them are added, removed or
+/// modified. Build output, which may live inside referenced directories, is
+/// excluded.
+pub fn input_digests(config: &Config) -> Result<BTreeMap<PathBuf, String>> {
+    input_digests_reusing(config, &BTreeMap::new())
+}
+
+/// Like `input_digests()`, reusing digests in `known`.
+///
+/// `known` holds digests of paths computed without excluding anything. They
+/// are only reused for paths build output can't be inside of.
+pub fn input_digests_reusing(
+    config: &Config,
+    known: &BTreeMap<PathBuf, String>,
+) -> Result<BTreeMap<PathBuf, String>> {
+    let base = config
+        .config_path
+        .parent()
+        .ok_or_else(|| anyhow!("unable to resolve parent directory of config"))?;
+    let exclude = [base.join(&config.build_config.build_path)];
+
+    let mut res = BTreeMap::new();
+    let mut remaining = BTreeSet::new();
+
+    for path in &config.input_paths {
+        let excluded = exclude
+            .iter()
+            .any(|p| p.starts_with(path) || path.starts_with(p));
+
+        match known.get(path) {
+            Some(digest) if !excluded => {
+                res.insert(path.clone(), digest.clone());
+            }
+            _ => {
+                remaining.insert(path.clone());
+            }
+        }
+    }
+
+    res.extend(sha256_paths(&remaining, &exclude)?);
+
+    Ok(res)
+}
+
+impl Lockfile {
+    /// Path of the lockfile for a configuration file.
+    pub fn path_for_config(config_path: &Path) -> PathBuf {
+        config_path
+            .parent()
+            .unwrap_or_else(|| Path::new(""))
+            .join(LOCKFILE_FILENAME)
+    }
+
+    /// Resolve a lockfile from an evaluated configuration.
+    pub fn from_config(config: &Config, target_triple: &str) -> Result<Self> {
+        let base = config
+            .config_path
+            .parent()
+            .ok_or_else(|| anyhow!("unable to resolve parent directory of config"))?;
+
+        let distribution = match &config.python_distribution {
+            PythonDistributionLocation::Url { url, sha256 } => LockedDistribution {
+                url: Some(url.clone()),
+                local_path: None,
+                sha256: sha256.clone(),
+            },
+            PythonDistributionLocation::Local { local_path, sha256 } => LockedDistribution {
+                url: None,
+                local_path: Some(relative_key(base, Path::new(local_path))),
+                sha256: sha256.clone(),
+            },
+        };
+
+        let python_packages = config
+            .python_package_versions
+            .iter()
+            .map(|(name, version)| {
+                (
+                    name.clone(),
+                    LockedPackage {
+                        version: version.clone(),
+                        record_sha256: config.python_package_hashes.get(name).cloned(),
+                    },
+                )
+            })
+            .collect();
+
+        let files = input_digests(config)?
+            .into_iter()
+            .map(|(path, digest)| (relative_key(base, &path), digest))
+            .collect();
+
+        Ok(Lockfile {
+            version: LOCKFILE_VERSION,
+            target_triple: target_triple.to_string(),
+            distribution,
+            python_packages,
+            files,
+        })
+    }
+
+    pub fn from_path(path: &Path) -> Result<Self> {
+        let data = std::fs::read(path).with_context(|| format!("reading {}", path.display()))?;
+
+        let lockfile: Lockfile = serde_json::from_slice(&data)
+            .with_context(|| format!("parsing lockfile {}", path.display()))?;
+
+        if lockfile.version != LOCKFILE_VERSION {
+            return Err(anyhow!(
+                "lockfile {} has unsupported version {}; run `pyoxidizer lock` to regenerate it",
+                path.display(),
+                lockfile.version
+            ));
+        }
+
+        Ok(lockfile)
+    }
+
+    pub fn write_path(&self, path: &Path) -> Result<()> {
+        let mut data = serde_json::to_vec_pretty(self)?;
+        data.push(b'\n');
+
+        write_file(path, data)
+    }
+
+    /// Verify resolved inputs against this lockfile.
+    ///
+    /// Returns a result for every item in either lockfile, keyed by a
+    /// description of the item.
+    pub fn verify(&self, current: &Lockfile) -> Vec<(String, VerifyResult)> {
+        let mut res = Vec::new();
+
+        let value_result = |expected: &str, actual: &str| {
+            if expected == actual {
+                VerifyResult::Ok
+            } else {
+                VerifyResult::ValueMismatch {
+                    expected: expected.to_string(),
+                    actual: actual.to_string(),
+                }
+            }
+        };
+
+        res.push((
+            "target triple".to_string(),
+            value_result(&self.target_triple, &current.target_triple),
+        ));
+        res.push((
+            "Python distribution".to_string(),
+            value_result(
+                &self.distribution.to_string(),
+                &current.distribution.to_string(),
+            ),
+        ));
+
+        for (name, locked) in &self.python_packages {
+            let key = format!("Python package {}", name);
+
+            let result = match current.python_packages.get(name) {
+                None => VerifyResult::Missing,
+                Some(package) if package.version != locked.version => {
+                    value_result(&locked.version, &package.version)
+                }
+                Some(package) => match (&locked.record_sha256, &package.record_sha256) {
+                    (Some(expected), Some(actual)) if expected != actual => {
+                        VerifyResult::DigestMismatch {
+                            expected: expected.clone(),
+                            actual: actual.clone(),
+                        }
+                    }
+                    _ => VerifyResult::Ok,
+                },
+            };
+
+            res.push((key, result));
+        }
+
+        for name in current.python_packages.keys() {
+            if !self.python_packages.contains_key(name) {
+                res.push((format!("Python package {}", name), VerifyResult::Unexpected));
+            }
+        }
+
+        for (path, expected) in &self.files {
+            let key = format!("file {}", path);
+
+            let result = match current.files.get(path) {
+                None => VerifyResult::Missing,
+                Some(actual) if actual != expected => VerifyResult::DigestMismatch {
+                    expected: expected.clone(),
+                    actual: actual.clone(),
+                },
+                Some(_) => VerifyResult::Ok,
+            };
+
+            res.push((key, result));
+        }
+
+        for path in current.files.keys() {
+            if !self.files.contains_key(path) {
+                res.push((format!("file {}", path), VerifyResult::Unexpected));
+            }
+        }
+
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lockfile() -> Lockfile {
+        let mut lockfile = Lockfile {
+            version: LOCKFILE_VERSION,
+            target_triple: "x86_64-unknown-linux-gnu".to_string(),
+            distribution: LockedDistribution {
+                url: Some("https://example.com/cpython.tar.zst".to_string()),
+                local_path: None,
+                sha256: "abcd".to_string(),
+            },
+            ..Lockfile::default()
+        };
+
+        lockfile.python_packages.insert(
+            "black".to_string(),
+            LockedPackage {
+                version: "19.10b0".to_string(),
+                record_sha256: Some("1111".to_string()),
+            },
+        );
+        lockfile
+            .files
+            .insert("requirements.txt".to_string(), "2222".to_string());
+
+        lockfile
+    }
+
+    fn failures(results: &[(String, VerifyResult)]) -> Vec<String> {
+        results
+            .iter()
+            .filter(|(_, r)| !r.is_ok())
+            .map(|(key, r)| format!("{}: {}", key, r))
+            .collect()
+    }
+
+    #[test]
+    fn test_verify_unchanged() {
+        let locked = lockfile();
+
+        let results = locked.verify(&lockfile());
+        assert_eq!(results.len(), 4);
+        assert!(failures(&results).is_empty());
+    }
+
+    #[test]
+    fn test_verify_drift() {
+        let locked = lockfile();
+        let mut current = lockfile();
+
+        current.python_packages.get_mut("black").unwrap().version = "20.8b1".to_string();
+        current.python_packages.insert(
+            "click".to_string(),
+            LockedPackage {
+                version: "7.1.2".to_string(),
+                record_sha256: None,
+            },
+        );
+        current.files.clear();
+        current
+            .files
+            .insert("data.json".to_string(), "3333".to_string());
+
+        assert_eq!(
+            failures(&locked.verify(&current)),
+            vec![
+                "Python package black: MISMATCH (expected 19.10b0; got 20.8b1)".to_string(),
+                "Python package click: UNEXPECTED (not recorded)".to_string(),
+                "file requirements.txt: MISSING".to_string(),
+                "file data.json: UNEXPECTED (not recorded)".to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_verify_digests() {
+        let locked = lockfile();
+        let mut current = lockfile();
+
+        current.distribution.sha256 = "ef01".to_string();
+        current
+            .python_packages
+            .get_mut("black")
+            .unwrap()
+            .record_sha256 = Some("4444".to_string());
+        current
+            .files
+            .insert("requirements.txt".to_string(), "5555".to_string());
+
+        assert_eq!(
+            failures(&locked.verify(&current)),
+            vec![
+                "Python distribution: MISMATCH (expected https://example.com/cpython.tar.zst (sha256 abcd); got https://example.com/cpython.tar.zst (sha256 ef01))".to_string(),
+                "Python package black: DIGEST MISMATCH (expected sha256 1111; got 4444)".to_string(),
+                "file requirements.txt: DIGEST MISMATCH (expected sha256 2222; got 5555)".to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_sha256_dir() -> Result<()> {
+        let temp_dir = tempdir::TempDir::new("pyoxidizer-test")?;
+        let root = temp_dir.path();
+
+        std::fs::create_dir_all(root.join("pkg/__pycache__"))?;
+        std::fs::create_dir_all(root.join("build"))?;
+        std::fs::write(root.join("pkg/__init__.py"), b"")?;
+        std::fs::write(root.join("pkg/app.py"), b"print('hello')")?;
+
+        let exclude = vec![root.join("build")];
+        let sha256_dir = |path: &Path, exclude: &[PathBuf]| -> Result<String> {
+            let mut paths = BTreeSet::new();
+            paths.insert(path.to_path_buf());
+            Ok(sha256_paths(&paths, exclude)?.remove(path).unwrap())
+        };
+        let initial = sha256_dir(root, &exclude)?;
+
+        // Bytecode caches, build output and the lockfile don't affect the digest.
+        std::fs::write(root.join("pkg/__pycache__/app.cpython-37.pyc"), b"bytecode")?;
+        std::fs::write(root.join("build/app"), b"binary")?;
+        std::fs::write(root.join(LOCKFILE_FILENAME), b"{}")?;
+        assert_eq!(sha256_dir(root, &exclude)?, initial);
+
+        std::fs::write(root.join("pkg/app.py"), b"print('goodbye')")?;
+        assert_ne!(sha256_dir(root, &exclude)?, initial);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_sha256_paths() -> Result<()> {
+        let temp_dir = tempdir::TempDir::new("pyoxidizer-test")?;
+        let root = temp_dir.path();
+
+        std::fs::create_dir_all(root.join("python/myapp"))?;
+        std::fs::write(root.join("python/myapp/__init__.py"), b"")?;
+        std::fs::write(root.join("requirements.txt"), b"black")?;
+
+        let mut paths = BTreeSet::new();
+        paths.insert(root.join("python"));
+        paths.insert(root.join("requirements.txt"));
+
+        let digests = |paths: &BTreeSet<PathBuf>| sha256_paths(paths, &[]);
+        let initial = digests(&paths)?;
+        assert_eq!(
+            initial.keys().collect::<BTreeSet<_>>(),
+            paths.iter().collect()
+        );
+
+        // Adding a module to a referenced tree changes its digest.
+        std::fs::write(root.join("python/myapp/cli.py"), b"")?;
+        let added = digests(&paths)?;
+        assert_ne!(added[&root.join("python")], initial[&root.join("python")]);
+        assert_eq!(
+            added[&root.join("requirements.txt")],
+            initial[&root.join("requirements.txt")]
+        );
+
+        // As does removing one.
+        std::fs::remove_file(root.join("python/myapp/cli.py"))?;
+        assert_eq!(digests(&paths)?, initial);
+        std::fs::remove_file(root.join("python/myapp/__init__.py"))?;
+        assert_ne!(digests(&paths)?, initial);
+
+        // Missing files are an error.
+        std::fs::remove_file(root.join("requirements.txt"))?;
+        assert!(digests(&paths).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_sha256_files_parallel() -> Result<()> {
+        let temp_dir = tempdir::TempDir::new("pyoxidizer-test")?;
+        let root = temp_dir.path();
+
+        let files = (0..PARALLEL_HASH_THRESHOLD * 3 + 1)
+            .map(|i| {
+                let path = root.join(format!("{}.py", i));
+                std::fs::write(&path, format!("value = {}", i))?;
+                Ok(path)
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let serial = sha256_files(&files, 1)
+            .into_iter()
+            .collect::<Result<Vec<_>>>()?;
+        let parallel = sha256_files(&files, HASH_THREADS)
+            .into_iter()
+            .collect::<Result<Vec<_>>>()?;
+        assert_eq!(parallel, serial);
+        assert_eq!(serial[7], sha256_file(&files[7])?);
+
+        // An unreadable path doesn't prevent hashing the others.
+        let mut paths = BTreeSet::new();
+        paths.insert(root.to_path_buf());
+        paths.insert(root.join("missing.txt"));
+        paths.insert(files[0].clone());
+
+        let digests = sha256_paths_each(&paths, &[]);
+        assert!(digests[&root.join("missing.txt")].is_err());
+        assert_eq!(digests[&files[0]].as_ref().unwrap(), &serial[0]);
+        assert!(digests[root].is_ok());
+
+        // Hashing threads observe the build's cancellation.
+        let token = cancel::CancellationToken::new();
+        token.cancel();
+        let _active = token.activate();
+        assert!(sha256_files(&files, HASH_THREADS)
+            .iter()
+            .all(|digest| cancel::is_cancelled_error(digest.as_ref().unwrap_err())));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_roundtrip() -> Result<()> {
+        let temp_dir = tempdir::TempDir::new("pyoxidizer-test")?;
+        let path = temp_dir.path().join(LOCKFILE_FILENAME);
+
+        let locked = lockfile();
+        locked.write_path(&path)?;
+
+        assert_eq!(Lockfile::from_path(&path)?, locked);
+
+        // Output is deterministic.
+        let data = std::fs::read(&path)?;
+        locked.write_path(&path)?;
+        assert_eq!(std::fs::read(&path)?, data);
+
+        Ok(())
+    }
+}
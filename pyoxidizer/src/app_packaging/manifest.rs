@@ -0,0 +1,622 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Build manifests describing packaged applications.
+
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+use sha2::Digest;
+use slog::warn;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use super::build_environment::BuildEnvironment;
+use super::debuginfo::DebugInfo;
+use super::state::BuildContext;
+use crate::py_packaging::embedded_resource::{
+    parse_modules_data_length, parse_resources_data_length,
+};
+
+/// File name of build manifests written into packaged application directories.
+pub const BUILD_MANIFEST_FILENAME: &str = "build-manifest.json";
+
+/// Annotation indicating a file is modified after packaging by code signing.
+///
+/// Files with this annotation have their size and digest verification skipped.
+pub const ANNOTATION_MODIFIED_BY_SIGNING: &str = "modified-by-signing";
+
+/// Annotation indicating a file was signed by the configured signing command.
+///
+/// The recorded size and digest are of the signed file.
+pub const ANNOTATION_SIGNED: &str = "signed";
+
+/// Describes a file in a build manifest.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct ManifestFile {
+    /// Path of file relative to the manifest.
+    pub path: String,
+    pub size: u64,
+    pub sha256: String,
+    #[serde(default)]
+    pub annotations: Vec<String>,
+}
+
+/// Describes a blob of embedded resources data within a file.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct ManifestEmbeddedData {
+    /// Path of the file holding the data, relative to the manifest.
+    pub path: String,
+    /// Offset of the data within the file.
+    pub offset: u64,
+    pub size: u64,
+    pub sha256: String,
+}
+
+/// Describes debug info split out of a binary.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct ManifestDebugInfo {
+    /// Path of the debug file or bundle, relative to the manifest.
+    pub path: String,
+    /// Path of the binary the debug info belongs to, relative to the manifest.
+    pub binary: String,
+    /// The kind of debug info. One of `gnu-debuglink`, `dsym`, or `pdb`.
+    pub kind: String,
+    /// GNU build-id, Mach-O UUID, or PDB signature and age of the binary.
+    pub id: Option<String>,
+}
+
+/// Describes the output of packaging an application.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct BuildManifest {
+    pub version: u32,
+    pub application_name: String,
+    pub target_triple: String,
+    pub files: Vec<ManifestFile>,
+    /// Embedded Python modules data.
+    pub modules: Option<ManifestEmbeddedData>,
+    /// Embedded Python resources data.
+    pub resources: Option<ManifestEmbeddedData>,
+    /// Debug info split out of binaries.
+    #[serde(default)]
+    pub debug_info: Vec<ManifestDebugInfo>,
+    /// Python packages installed by pip and their versions.
+    #[serde(default)]
+    pub python_packages: BTreeMap<String, String>,
+    /// Files embedded by `embedded_config_file()` and the SHA-256 of their content.
+    #[serde(default)]
+    pub embedded_config_files: BTreeMap<String, String>,
+    /// Toolchain and invocations used for the build.
+    #[serde(default)]
+    pub build_environment: Option<BuildEnvironment>,
+    /// `debug` or `release`.
+    #[serde(default)]
+    pub profile: Option<String>,
+    /// The output layout the application directory was resolved from.
+    #[serde(default)]
+    pub output_layout: Option<String>,
+    /// The application directory, relative to the build directory.
+    #[serde(default)]
+    pub output_directory: Option<String>,
+}
+
+fn sha256_hex(data: &[u8]) -> String {
+    let mut hasher = sha2::Sha256::new();
+    hasher.input(data);
+    hex::encode(hasher.result())
+}
+
+/// Find the offset of `needle` within `haystack`.
+fn find_bytes(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    if needle.is_empty() || needle.len() > haystack.len() {
+        return None;
+    }
+
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+fn relative_path_string(path: &Path) -> String {
+    path.components()
+        .map(|c| c.as_os_str().to_string_lossy().to_string())
+        .collect::<Vec<String>>()
+        .join("/")
+}
+
+/// Locate embedded data inside a file and describe it.
+fn resolve_embedded_data(
+    rel_path: &str,
+    file_data: &[u8],
+    blob: &[u8],
+) -> Option<ManifestEmbeddedData> {
+    find_bytes(file_data, blob).map(|offset| ManifestEmbeddedData {
+        path: rel_path.to_string(),
+        offset: offset as u64,
+        size: blob.len() as u64,
+        sha256: sha256_hex(blob),
+    })
+}
+
+impl BuildManifest {
+    /// Construct a manifest describing files in a directory.
+    ///
+    /// `modules_data` and `resources_data` are the serialized blobs embedded
+    /// in the executable at `exe_path`.
+    pub fn from_directory(
+        application_name: &str,
+        target_triple: &str,
+        root: &Path,
+        exe_path: &Path,
+        modules_data: Option<&[u8]>,
+        resources_data: Option<&[u8]>,
+    ) -> Result<Self> {
+        let mut files = Vec::new();
+        let mut modules = None;
+        let mut resources = None;
+
+        let walk = walkdir::WalkDir::new(root).sort_by(|a, b| a.file_name().cmp(b.file_name()));
+
+        for entry in walk {
+            let entry = entry?;
+            let path = entry.path();
+
+            if path.is_dir() {
+                continue;
+            }
+
+            let rel_path = relative_path_string(path.strip_prefix(root)?);
+            if rel_path == BUILD_MANIFEST_FILENAME {
+                continue;
+            }
+
+            let data =
+                std::fs::read(path).with_context(|| format!("reading {}", path.display()))?;
+
+            if path == exe_path {
+                if let Some(blob) = modules_data {
+                    modules = resolve_embedded_data(&rel_path, &data, blob);
+                }
+                if let Some(blob) = resources_data {
+                    resources = resolve_embedded_data(&rel_path, &data, blob);
+                }
+            }
+
+            files.push(ManifestFile {
+                path: rel_path,
+                size: data.len() as u64,
+                sha256: sha256_hex(&data),
+                annotations: vec![],
+            });
+        }
+
+        Ok(BuildManifest {
+            version: 1,
+            application_name: application_name.to_string(),
+            target_triple: target_triple.to_string(),
+            files,
+            modules,
+            resources,
+            debug_info: vec![],
+            python_packages: BTreeMap::new(),
+            embedded_config_files: BTreeMap::new(),
+            build_environment: None,
+            profile: None,
+            output_layout: None,
+            output_directory: None,
+        })
+    }
+
+    pub fn from_path(path: &Path) -> Result<Self> {
+        let data = std::fs::read(path).with_context(|| format!("reading {}", path.display()))?;
+
+        serde_json::from_slice(&data).with_context(|| format!("parsing {}", path.display()))
+    }
+
+    pub fn write_path(&self, path: &Path) -> Result<()> {
+        let data = serde_json::to_vec_pretty(self)?;
+        std::fs::write(path, &data).with_context(|| format!("writing {}", path.display()))
+    }
+
+    /// Add an annotation to a file in the manifest.
+    pub fn annotate(&mut self, path: &str, annotation: &str) -> Result<()> {
+        let entry = self
+            .files
+            .iter_mut()
+            .find(|f| f.path == path)
+            .ok_or_else(|| anyhow!("{} is not in the build manifest", path))?;
+
+        if !entry.annotations.iter().any(|a| a == annotation) {
+            entry.annotations.push(annotation.to_string());
+        }
+
+        Ok(())
+    }
+}
+
+/// Represents the result of verifying a single file.
+#[derive(Clone, Debug, PartialEq)]
+pub enum VerifyResult {
+    Ok,
+    /// Content verification was skipped because of an annotation.
+    Skipped(String),
+    Missing,
+    SizeMismatch {
+        expected: u64,
+        actual: u64,
+    },
+    DigestMismatch {
+        expected: String,
+        actual: String,
+    },
+    EmbeddedDataInvalid(String),
+    /// The item exists but isn't recorded.
+    Unexpected,
+    ValueMismatch {
+        expected: String,
+        actual: String,
+    },
+}
+
+impl VerifyResult {
+    pub fn is_ok(&self) -> bool {
+        match self {
+            VerifyResult::Ok | VerifyResult::Skipped(_) => true,
+            _ => false,
+        }
+    }
+}
+
+impl std::fmt::Display for VerifyResult {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            VerifyResult::Ok => write!(f, "OK"),
+            VerifyResult::Skipped(annotation) => {
+                write!(f, "OK (content not verified: {})", annotation)
+            }
+            VerifyResult::Missing => write!(f, "MISSING"),
+            VerifyResult::SizeMismatch { expected, actual } => write!(
+                f,
+                "SIZE MISMATCH (expected {} bytes; got {})",
+                expected, actual
+            ),
+            VerifyResult::DigestMismatch { expected, actual } => write!(
+                f,
+                "DIGEST MISMATCH (expected sha256 {}; got {})",
+                expected, actual
+            ),
+            VerifyResult::EmbeddedDataInvalid(msg) => {
+                write!(f, "EMBEDDED RESOURCES INVALID ({})", msg)
+            }
+            VerifyResult::Unexpected => write!(f, "UNEXPECTED (not recorded)"),
+            VerifyResult::ValueMismatch { expected, actual } => {
+                write!(f, "MISMATCH (expected {}; got {})", expected, actual)
+            }
+        }
+    }
+}
+
+fn verify_embedded_data(
+    data: &[u8],
+    embedded: &ManifestEmbeddedData,
+    parse: fn(&[u8]) -> Result<usize>,
+) -> Result<()> {
+    let offset = embedded.offset as usize;
+    if offset >= data.len() {
+        return Err(anyhow!("offset {} is past end of file", offset));
+    }
+
+    let size = parse(&data[offset..])?;
+    if size as u64 != embedded.size {
+        return Err(anyhow!(
+            "index describes {} bytes; expected {}",
+            size,
+            embedded.size
+        ));
+    }
+
+    let digest = sha256_hex(&data[offset..offset + size]);
+    if digest != embedded.sha256 {
+        return Err(anyhow!(
+            "sha256 {} does not match expected {}",
+            digest,
+            embedded.sha256
+        ));
+    }
+
+    Ok(())
+}
+
+/// Verify a single file against its manifest entry.
+fn verify_file(
+    manifest: &BuildManifest,
+    entry: &ManifestFile,
+    path: &Path,
+) -> Result<VerifyResult> {
+    if !path.exists() {
+        return Ok(VerifyResult::Missing);
+    }
+
+    let data = std::fs::read(path).with_context(|| format!("reading {}", path.display()))?;
+
+    for (embedded, parse) in &[
+        (
+            &manifest.modules,
+            parse_modules_data_length as fn(&[u8]) -> Result<usize>,
+        ),
+        (&manifest.resources, parse_resources_data_length),
+    ] {
+        if let Some(embedded) = embedded {
+            if embedded.path == entry.path {
+                if let Err(e) = verify_embedded_data(&data, embedded, *parse) {
+                    return Ok(VerifyResult::EmbeddedDataInvalid(e.to_string()));
+                }
+            }
+        }
+    }
+
+    if let Some(annotation) = entry
+        .annotations
+        .iter()
+        .find(|a| a.as_str() == ANNOTATION_MODIFIED_BY_SIGNING)
+    {
+        return Ok(VerifyResult::Skipped(annotation.clone()));
+    }
+
+    if data.len() as u64 != entry.size {
+        return Ok(VerifyResult::SizeMismatch {
+            expected: entry.size,
+            actual: data.len() as u64,
+        });
+    }
+
+    let digest = sha256_hex(&data);
+    if digest != entry.sha256 {
+        return Ok(VerifyResult::DigestMismatch {
+            expected: entry.sha256.clone(),
+            actual: digest,
+        });
+    }
+
+    Ok(VerifyResult::Ok)
+}
+
+/// Verify files against a build manifest.
+///
+/// If `artifacts` is empty, all files in the manifest are verified relative
+/// to the directory containing the manifest. Otherwise only the specified
+/// files are verified. They are matched against manifest entries by their
+/// path relative to the manifest directory or by file name.
+pub fn verify_build_manifest(
+    manifest_path: &Path,
+    artifacts: &[PathBuf],
+) -> Result<Vec<(PathBuf, VerifyResult)>> {
+    let manifest = BuildManifest::from_path(manifest_path)?;
+    let root = manifest_path
+        .parent()
+        .ok_or_else(|| anyhow!("could not resolve parent directory of manifest"))?;
+
+    let mut res = Vec::new();
+
+    if artifacts.is_empty() {
+        for entry in &manifest.files {
+            let path = root.join(&entry.path);
+            let result = verify_file(&manifest, entry, &path)?;
+            res.push((path, result));
+        }
+    } else {
+        for artifact in artifacts {
+            let rel_path = artifact.strip_prefix(root).ok().map(relative_path_string);
+            let file_name = artifact
+                .file_name()
+                .map(|x| x.to_string_lossy().to_string());
+
+            let entry = manifest
+                .files
+                .iter()
+                .find(|f| Some(&f.path) == rel_path.as_ref())
+                .or_else(|| {
+                    manifest.files.iter().find(|f| {
+                        Path::new(&f.path)
+                            .file_name()
+                            .map(|x| x.to_string_lossy().to_string())
+                            == file_name
+                    })
+                })
+                .ok_or_else(|| anyhow!("{} is not in the build manifest", artifact.display()))?;
+
+            let result = verify_file(&manifest, entry, artifact)?;
+            res.push((artifact.clone(), result));
+        }
+    }
+
+    Ok(res)
+}
+
+/// Write a build manifest for a packaged application.
+///
+/// `app_path` is the directory holding the packaged application. It may
+/// differ from the context's final application path while packaging is in
+/// progress. `signed_paths` are files signed by the configured signing
+/// command.
+pub fn write_build_manifest(
+    logger: &slog::Logger,
+    context: &BuildContext,
+    app_path: &Path,
+    debug_infos: &[DebugInfo],
+    signed_paths: &[PathBuf],
+) -> Result<PathBuf> {
+    let app_exe_path = app_path.join(context.app_exe_path.strip_prefix(&context.app_path)?);
+
+    let modules_path = context.pyoxidizer_artifacts_path.join("py-modules");
+    let resources_path = context.pyoxidizer_artifacts_path.join("python-resources");
+
+    let modules_data = std::fs::read(&modules_path).ok();
+    let resources_data = std::fs::read(&resources_path).ok();
+
+    let mut manifest = BuildManifest::from_directory(
+        &context.app_name,
+        &context.target_triple,
+        app_path,
+        &app_exe_path,
+        modules_data.as_ref().map(|x| x.as_slice()),
+        resources_data.as_ref().map(|x| x.as_slice()),
+    )?;
+
+    if manifest.modules.is_none() {
+        warn!(
+            logger,
+            "could not locate embedded modules data in {}; it will not be verifiable",
+            app_exe_path.display()
+        );
+    }
+
+    for info in debug_infos {
+        manifest.debug_info.push(ManifestDebugInfo {
+            path: relative_path_string(info.path.strip_prefix(app_path)?),
+            binary: relative_path_string(info.binary.strip_prefix(app_path)?),
+            kind: info.kind.as_str().to_string(),
+            id: info.id.clone(),
+        });
+    }
+
+    for path in signed_paths {
+        manifest.annotate(
+            &relative_path_string(path.strip_prefix(app_path)?),
+            ANNOTATION_SIGNED,
+        )?;
+    }
+
+    manifest.python_packages = context.config.python_package_versions.clone();
+    manifest.embedded_config_files = context.config.embedded_config_files.clone();
+    manifest.build_environment =
+        BuildEnvironment::from_artifacts(&context.pyoxidizer_artifacts_path);
+    manifest.profile = Some(context.profile().to_string());
+    manifest.output_layout = Some(context.output_layout.clone());
+    manifest.output_directory = context
+        .app_path
+        .strip_prefix(&context.build_path)
+        .ok()
+        .map(relative_path_string);
+
+    let path = app_path.join(BUILD_MANIFEST_FILENAME);
+    warn!(logger, "writing build manifest to {}", path.display());
+    manifest.write_path(&path)?;
+
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::py_packaging::embedded_resource::{write_modules_entries, ModuleEntry};
+
+    fn modules_blob() -> Vec<u8> {
+        let mut data = Vec::new();
+        write_modules_entries(
+            &mut data,
+            &[ModuleEntry {
+                name: "foo".to_string(),
+                is_package: false,
+                source: Some(b"print('hello')".to_vec()),
+                bytecode: None,
+            }],
+        )
+        .unwrap();
+
+        data
+    }
+
+    fn write_app(root: &Path) -> (PathBuf, Vec<u8>) {
+        let blob = modules_blob();
+        let mut exe = b"header".to_vec();
+        exe.extend(&blob);
+        exe.extend(b"trailer");
+
+        let exe_path = root.join("myapp");
+        std::fs::write(&exe_path, &exe).unwrap();
+        std::fs::create_dir_all(root.join("lib")).unwrap();
+        std::fs::write(root.join("lib").join("foo.py"), b"foo").unwrap();
+
+        (exe_path, blob)
+    }
+
+    #[test]
+    fn test_manifest_roundtrip() -> Result<()> {
+        let temp_dir = tempdir::TempDir::new("pyoxidizer-test")?;
+        let root = temp_dir.path();
+        let (exe_path, blob) = write_app(root);
+
+        let manifest =
+            BuildManifest::from_directory("myapp", "target", root, &exe_path, Some(&blob), None)?;
+
+        assert_eq!(manifest.files.len(), 2);
+        assert_eq!(manifest.files[0].path, "lib/foo.py");
+        assert_eq!(manifest.files[1].path, "myapp");
+        assert_eq!(manifest.modules.as_ref().unwrap().offset, 6);
+        assert!(manifest.resources.is_none());
+
+        let manifest_path = root.join(BUILD_MANIFEST_FILENAME);
+        manifest.write_path(&manifest_path)?;
+
+        let results = verify_build_manifest(&manifest_path, &[])?;
+        assert_eq!(results.len(), 2);
+        assert!(results.iter().all(|(_, r)| *r == VerifyResult::Ok));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify_mismatch() -> Result<()> {
+        let temp_dir = tempdir::TempDir::new("pyoxidizer-test")?;
+        let root = temp_dir.path();
+        let (exe_path, blob) = write_app(root);
+
+        let mut manifest =
+            BuildManifest::from_directory("myapp", "target", root, &exe_path, Some(&blob), None)?;
+        let manifest_path = root.join(BUILD_MANIFEST_FILENAME);
+        manifest.write_path(&manifest_path)?;
+
+        std::fs::write(root.join("lib").join("foo.py"), b"bar")?;
+        std::fs::remove_file(&exe_path)?;
+
+        let results = verify_build_manifest(&manifest_path, &[])?;
+        match &results[0].1 {
+            VerifyResult::DigestMismatch { .. } => {}
+            r => panic!("unexpected result: {:?}", r),
+        }
+        assert_eq!(results[1].1, VerifyResult::Missing);
+
+        // Simulate signing appending data to the executable.
+        write_app(root);
+        let mut data = std::fs::read(&exe_path)?;
+        data.extend(b"signature");
+        std::fs::write(&exe_path, &data)?;
+
+        let results = verify_build_manifest(&manifest_path, &[exe_path.clone()])?;
+        match &results[0].1 {
+            VerifyResult::SizeMismatch { .. } => {}
+            r => panic!("unexpected result: {:?}", r),
+        }
+
+        manifest.annotate("myapp", ANNOTATION_MODIFIED_BY_SIGNING)?;
+        manifest.write_path(&manifest_path)?;
+
+        let results = verify_build_manifest(&manifest_path, &[exe_path.clone()])?;
+        assert!(results[0].1.is_ok());
+
+        // Corrupting the embedded data is detected even for signed files.
+        let mut data = std::fs::read(&exe_path)?;
+        let last = blob.len() + 5;
+        data[last] ^= 0xff;
+        std::fs::write(&exe_path, &data)?;
+
+        let results = verify_build_manifest(&manifest_path, &[exe_path])?;
+        match &results[0].1 {
+            VerifyResult::EmbeddedDataInvalid(_) => {}
+            r => panic!("unexpected result: {:?}", r),
+        }
+
+        Ok(())
+    }
+}
@@ -2,8 +2,30 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
+pub mod artifacts_owner;
+pub mod bin_target;
+pub mod build_environment;
+pub mod build_vars;
+pub mod cargo_diagnostics;
 pub mod config;
+pub mod config_diff;
+pub mod debuginfo;
+pub mod disk_space;
 pub mod environment;
+pub mod library;
+pub mod lockfile;
+pub mod manifest;
+pub mod notices;
+pub mod output_layout;
+pub mod packaging_script;
+pub mod plan;
+pub mod prepared;
+pub mod python_sys;
 pub mod repackage;
 pub mod resource;
+pub mod run_env;
+pub mod rustc_bootstrap;
+pub mod signing;
+pub mod staleness;
 pub mod state;
+pub mod toolchain;
@@ -0,0 +1,386 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/*!
+Third-party license notices for packaged applications.
+
+Components are collected from the Python distribution when artifacts are
+generated: the distribution itself and every embedded extension module
+linking a library. Their license texts are those bundled with the
+distribution. `THIRD-PARTY-NOTICES.txt` is written next to the executable
+when packaging if `third_party_notices` is enabled in the config or
+`--third-party-notices` is passed to `pyoxidizer build`.
+
+Components without license metadata are listed as `UNKNOWN`.
+*/
+
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+use slog::warn;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+
+use crate::fsutils::write_file;
+use crate::licensing::LicenseFilterDecision;
+use crate::py_packaging::distribution::{ExtensionModule, ParsedPythonDistribution};
+
+/// File name of the notices written into packaged application directories.
+pub const NOTICES_FILENAME: &str = "THIRD-PARTY-NOTICES.txt";
+
+/// How components with unknown licensing are marked.
+pub const UNKNOWN_LICENSE: &str = "UNKNOWN";
+
+const SEPARATOR: &str =
+    "================================================================================";
+
+/// The text of a license bundled with the Python distribution.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct LicenseText {
+    pub filename: String,
+    pub text: String,
+}
+
+/// Third-party software included in an application.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct NoticeComponent {
+    /// e.g. `Python 3.7.5` or `extension module _ssl`.
+    pub name: String,
+    /// Libraries linked by the component.
+    pub libraries: Vec<String>,
+    /// SPDX license shortnames. Empty if licensing is unknown.
+    pub licenses: Vec<String>,
+    pub public_domain: bool,
+    pub texts: Vec<LicenseText>,
+}
+
+impl NoticeComponent {
+    pub fn is_unknown(&self) -> bool {
+        self.licenses.is_empty() && !self.public_domain
+    }
+}
+
+/// License texts bundled with a distribution for a set of licenses.
+fn distribution_license_texts(
+    dist: &ParsedPythonDistribution,
+    licenses: &[String],
+) -> Vec<LicenseText> {
+    dist.license_infos
+        .values()
+        .flatten()
+        .find(|li| !licenses.is_empty() && li.licenses.as_slice() == licenses)
+        .map(|li| {
+            vec![LicenseText {
+                filename: li.license_filename.clone(),
+                text: li.license_text.clone(),
+            }]
+        })
+        .unwrap_or_else(Vec::new)
+}
+
+fn extension_module_component(
+    dist: &ParsedPythonDistribution,
+    em: &ExtensionModule,
+) -> Result<NoticeComponent> {
+    let mut texts = Vec::new();
+
+    for path in em.license_paths.as_ref().unwrap_or(&Vec::new()) {
+        texts.push(LicenseText {
+            filename: path
+                .file_name()
+                .map(|name| name.to_string_lossy().to_string())
+                .unwrap_or_default(),
+            text: std::fs::read_to_string(path)
+                .with_context(|| format!("reading license of {}", em.module))?,
+        });
+    }
+
+    let licenses = em.licenses.clone().unwrap_or_else(Vec::new);
+    if texts.is_empty() {
+        texts = distribution_license_texts(dist, &licenses);
+    }
+
+    Ok(NoticeComponent {
+        name: if em.variant == "default" {
+            format!("extension module {}", em.module)
+        } else {
+            format!("extension module {} ({} variant)", em.module, em.variant)
+        },
+        libraries: em.links.iter().map(|l| l.name.clone()).collect(),
+        licenses,
+        public_domain: em.license_public_domain == Some(true),
+        texts,
+    })
+}
+
+/// Collect the third-party components of an application.
+///
+/// Extension modules linking nothing are part of Python and covered by
+/// its license.
+pub fn collect_notice_components(
+    dist: &ParsedPythonDistribution,
+    extension_modules: &BTreeMap<String, ExtensionModule>,
+) -> Result<Vec<NoticeComponent>> {
+    let licenses = dist.licenses.clone().unwrap_or_else(Vec::new);
+
+    let mut texts = dist
+        .license_infos
+        .get("python")
+        .map(|lis| {
+            lis.iter()
+                .map(|li| LicenseText {
+                    filename: li.license_filename.clone(),
+                    text: li.license_text.clone(),
+                })
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_else(Vec::new);
+    if texts.is_empty() {
+        texts = distribution_license_texts(dist, &licenses);
+    }
+
+    let mut res = vec![NoticeComponent {
+        name: format!("Python {}", dist.version),
+        libraries: dist.links_core.iter().map(|l| l.name.clone()).collect(),
+        licenses,
+        public_domain: false,
+        texts,
+    }];
+
+    for em in extension_modules.values() {
+        if !em.links.is_empty() {
+            res.push(extension_module_component(dist, em)?);
+        }
+    }
+
+    Ok(res)
+}
+
+/// Render the notices of components.
+///
+/// Extension module variants removed by `extension_module_license_filter()`
+/// are listed at the end.
+pub fn render_notices(
+    components: &[NoticeComponent],
+    decisions: &[LicenseFilterDecision],
+) -> String {
+    let mut s = String::new();
+
+    writeln!(s, "THIRD-PARTY SOFTWARE NOTICES").unwrap();
+    writeln!(s).unwrap();
+    writeln!(
+        s,
+        "This application includes the following third-party software."
+    )
+    .unwrap();
+
+    for component in components {
+        writeln!(s).unwrap();
+        writeln!(s, "{}", SEPARATOR).unwrap();
+        writeln!(s, "{}", component.name).unwrap();
+        if !component.libraries.is_empty() {
+            writeln!(s, "Libraries: {}", component.libraries.join(", ")).unwrap();
+        }
+
+        if component.is_unknown() {
+            writeln!(s, "Licenses: {}", UNKNOWN_LICENSE).unwrap();
+            writeln!(
+                s,
+                "The licensing of this component could not be determined."
+            )
+            .unwrap();
+            continue;
+        }
+
+        if component.public_domain {
+            writeln!(s, "Licenses: public domain").unwrap();
+        } else {
+            writeln!(s, "Licenses: {}", component.licenses.join(", ")).unwrap();
+        }
+
+        if component.texts.is_empty() {
+            for license in &component.licenses {
+                writeln!(
+                    s,
+                    "The text of {} is not bundled with the Python distribution; see https://spdx.org/licenses/{}.html",
+                    license, license
+                )
+                .unwrap();
+            }
+        }
+
+        for text in &component.texts {
+            writeln!(s).unwrap();
+            writeln!(s, "{}:", text.filename).unwrap();
+            writeln!(s).unwrap();
+            writeln!(s, "{}", text.text.trim_end()).unwrap();
+        }
+    }
+
+    if !decisions.is_empty() {
+        writeln!(s).unwrap();
+        writeln!(s, "{}", SEPARATOR).unwrap();
+        writeln!(
+            s,
+            "Extension module variants excluded by the license policy:"
+        )
+        .unwrap();
+        for decision in decisions {
+            writeln!(s, "  {}", decision).unwrap();
+        }
+    }
+
+    s
+}
+
+/// Write `THIRD-PARTY-NOTICES.txt` into a packaged application directory.
+///
+/// Components with unknown licensing are warned about.
+pub fn write_notices(
+    logger: &slog::Logger,
+    app_path: &Path,
+    components: &[NoticeComponent],
+    decisions: &[LicenseFilterDecision],
+) -> Result<PathBuf> {
+    if components.is_empty() {
+        return Err(anyhow!(
+            "no third-party components were recorded when artifacts were generated; rebuild with --force-artifacts"
+        ));
+    }
+
+    let path = app_path.join(NOTICES_FILENAME);
+
+    for component in components.iter().filter(|c| c.is_unknown()) {
+        warn!(
+            logger,
+            "WARNING: licensing of {} is unknown; it is listed as {} in {}",
+            component.name,
+            UNKNOWN_LICENSE,
+            NOTICES_FILENAME
+        );
+    }
+
+    warn!(logger, "writing third-party notices to {}", path.display());
+    write_file(&path, render_notices(components, decisions).as_bytes())?;
+
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::*;
+
+    #[test]
+    fn test_collect_default_distribution() -> Result<()> {
+        let dist = get_default_distribution()?;
+
+        let mut extension_modules = BTreeMap::new();
+        for name in &["_json", "_ssl", "readline"] {
+            if let Some(variants) = dist.extension_modules.get(*name) {
+                extension_modules.insert(name.to_string(), variants[0].clone());
+            }
+        }
+
+        let components = collect_notice_components(&dist, &extension_modules)?;
+
+        assert!(components[0].name.starts_with("Python "));
+        assert!(!components[0].texts.is_empty());
+        // _json links nothing, so it is covered by Python's license.
+        assert!(!components
+            .iter()
+            .any(|c| c.name == "extension module _json"));
+
+        if let Some(ssl) = components
+            .iter()
+            .find(|c| c.name == "extension module _ssl")
+        {
+            assert!(!ssl.licenses.is_empty());
+            assert!(!ssl.texts.is_empty());
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_render() {
+        let components = vec![
+            NoticeComponent {
+                name: "Python 3.7.5".to_string(),
+                libraries: vec![],
+                licenses: vec!["Python-2.0".to_string()],
+                public_domain: false,
+                texts: vec![LicenseText {
+                    filename: "LICENSE.python.txt".to_string(),
+                    text: "PSF LICENSE\n".to_string(),
+                }],
+            },
+            NoticeComponent {
+                name: "extension module _sqlite3".to_string(),
+                libraries: vec!["sqlite3".to_string()],
+                licenses: vec![],
+                public_domain: true,
+                texts: vec![],
+            },
+            NoticeComponent {
+                name: "extension module _mystery".to_string(),
+                libraries: vec!["mystery".to_string()],
+                licenses: vec![],
+                public_domain: false,
+                texts: vec![],
+            },
+            NoticeComponent {
+                name: "extension module _ssl".to_string(),
+                libraries: vec!["ssl".to_string(), "crypto".to_string()],
+                licenses: vec!["OpenSSL".to_string()],
+                public_domain: false,
+                texts: vec![],
+            },
+        ];
+        let decisions = vec![LicenseFilterDecision {
+            module: "readline".to_string(),
+            variant: "default".to_string(),
+            licenses: vec!["GPL-3.0".to_string()],
+            substitute: Some("libedit".to_string()),
+            substitute_licenses: vec!["BSD-3-Clause".to_string()],
+        }];
+
+        let text = render_notices(&components, &decisions);
+
+        assert!(text.contains(
+            "Python 3.7.5\nLicenses: Python-2.0\n\nLICENSE.python.txt:\n\nPSF LICENSE\n"
+        ));
+        assert!(text
+            .contains("extension module _sqlite3\nLibraries: sqlite3\nLicenses: public domain\n"));
+        assert!(text.contains("extension module _mystery\nLibraries: mystery\nLicenses: UNKNOWN\n"));
+        assert!(text.contains("see https://spdx.org/licenses/OpenSSL.html"));
+        assert!(text.ends_with(
+            "Extension module variants excluded by the license policy:\n  readline: replaced variant default (GPL-3.0) with libedit (BSD-3-Clause)\n"
+        ));
+        assert!(components[2].is_unknown());
+        assert!(!components[1].is_unknown());
+    }
+
+    #[test]
+    fn test_write_unknown() -> Result<()> {
+        let logger = get_logger()?;
+        let temp_dir = tempdir::TempDir::new("pyoxidizer-test")?;
+
+        let components = vec![NoticeComponent {
+            name: "extension module _mystery".to_string(),
+            libraries: vec!["mystery".to_string()],
+            licenses: vec![],
+            public_domain: false,
+            texts: vec![],
+        }];
+
+        let path = write_notices(&logger, temp_dir.path(), &components, &[])?;
+        assert_eq!(path, temp_dir.path().join(NOTICES_FILENAME));
+        assert!(std::fs::read_to_string(&path)?.contains("Licenses: UNKNOWN"));
+
+        assert!(write_notices(&logger, temp_dir.path(), &[], &[]).is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,439 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/*!
+Where packaged applications are written.
+
+The directory is defined by an output layout: a path relative to the build
+directory containing tokens, `apps/{app}/{triple}/{profile}` by default. It
+can be set with `output_layout` in the config and overridden with
+`--output-layout`.
+
+Two applications mapping to the same directory would overwrite each other.
+So the build manifest in a directory records which application, target and
+profile it holds and packaging anything else into it fails.
+
+Directories written with a previous layout are recorded in
+`output-directories.json` in the build directory. When the layout changes,
+directories left behind are reported rather than silently kept around.
+*/
+
+use anyhow::{anyhow, Context, Result};
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use slog::warn;
+use std::collections::BTreeMap;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Mutex;
+
+use super::manifest::{BuildManifest, BUILD_MANIFEST_FILENAME};
+
+/// The output layout used when none is configured.
+pub const DEFAULT_OUTPUT_LAYOUT: &str = "apps/{app}/{triple}/{profile}";
+
+/// Tokens output layouts may contain.
+pub const OUTPUT_LAYOUT_TOKENS: &[&str] = &["app", "triple", "profile"];
+
+/// File in the build directory recording where applications were packaged.
+pub const OUTPUT_DIRECTORIES_FILENAME: &str = "output-directories.json";
+
+lazy_static! {
+    static ref OVERRIDE: Mutex<Option<String>> = Mutex::new(None);
+}
+
+/// Use an output layout regardless of the config.
+pub fn set_output_layout_override(layout: &str) {
+    *OVERRIDE.lock().unwrap() = Some(layout.to_string());
+}
+
+/// The output layout to use, given the config's setting.
+pub fn resolve_output_layout(config_layout: Option<&str>) -> String {
+    match &*OVERRIDE.lock().unwrap() {
+        Some(layout) => layout.clone(),
+        None => config_layout.unwrap_or(DEFAULT_OUTPUT_LAYOUT).to_string(),
+    }
+}
+
+/// Identifies what a packaged application directory holds.
+#[derive(Clone, Debug, PartialEq)]
+pub struct OutputOwner {
+    pub app: String,
+    pub triple: String,
+    /// `debug` or `release`.
+    pub profile: String,
+}
+
+impl OutputOwner {
+    fn token(&self, name: &str) -> Option<&str> {
+        match name {
+            "app" => Some(&self.app),
+            "triple" => Some(&self.triple),
+            "profile" => Some(&self.profile),
+            _ => None,
+        }
+    }
+
+    fn key(&self) -> String {
+        format!("{}/{}/{}", self.app, self.triple, self.profile)
+    }
+}
+
+fn token_list() -> String {
+    OUTPUT_LAYOUT_TOKENS
+        .iter()
+        .map(|t| format!("{{{}}}", t))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Expand the tokens of an output layout.
+///
+/// The result is relative to the build directory.
+pub fn expand_output_layout(layout: &str, owner: &OutputOwner) -> Result<PathBuf> {
+    let mut expanded = String::new();
+    let mut rest = layout;
+
+    while let Some(start) = rest.find(|c: char| c == '{' || c == '}') {
+        if rest[start..].starts_with('}') {
+            return Err(anyhow!("unmatched }} in output layout '{}'", layout));
+        }
+
+        expanded.push_str(&rest[..start]);
+        let end = rest[start..]
+            .find('}')
+            .ok_or_else(|| anyhow!("unmatched {{ in output layout '{}'", layout))?;
+        let name = &rest[start + 1..start + end];
+
+        match owner.token(name) {
+            Some(value) => expanded.push_str(value),
+            None => {
+                return Err(anyhow!(
+                    "unknown token {{{}}} in output layout '{}'; expected one of {}",
+                    name,
+                    layout,
+                    token_list()
+                ))
+            }
+        }
+
+        rest = &rest[start + end + 1..];
+    }
+    expanded.push_str(rest);
+
+    let path = PathBuf::from(&expanded);
+
+    if !path.components().all(|c| {
+        if let Component::Normal(_) = c {
+            true
+        } else {
+            false
+        }
+    }) || path.components().next().is_none()
+    {
+        return Err(anyhow!(
+            "output layout '{}' must be a relative path within the build directory; got '{}'",
+            layout,
+            expanded
+        ));
+    }
+
+    Ok(path)
+}
+
+/// Validate an output layout without expanding it for an application.
+pub fn validate_output_layout(layout: &str) -> Result<()> {
+    expand_output_layout(
+        layout,
+        &OutputOwner {
+            app: "app".to_string(),
+            triple: "triple".to_string(),
+            profile: "profile".to_string(),
+        },
+    )
+    .map(|_| ())
+}
+
+/// Ensure a directory doesn't hold another application.
+///
+/// The build manifest of a previously packaged application identifies it.
+pub fn check_output_owner(app_path: &Path, owner: &OutputOwner) -> Result<()> {
+    let manifest_path = app_path.join(BUILD_MANIFEST_FILENAME);
+    if !manifest_path.exists() {
+        return Ok(());
+    }
+
+    let manifest = BuildManifest::from_path(&manifest_path)?;
+
+    let existing = OutputOwner {
+        app: manifest.application_name.clone(),
+        triple: manifest.target_triple.clone(),
+        profile: manifest
+            .profile
+            .clone()
+            .unwrap_or_else(|| owner.profile.clone()),
+    };
+
+    if existing != *owner {
+        return Err(anyhow!(
+            "{} ({} {}) and {} ({} {}) map to the same output directory {}; include tokens distinguishing them in the output layout or remove the directory",
+            existing.app,
+            existing.triple,
+            existing.profile,
+            owner.app,
+            owner.triple,
+            owner.profile,
+            app_path.display()
+        ));
+    }
+
+    Ok(())
+}
+
+/// Where applications were packaged, keyed by application, target and profile.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct OutputDirectories {
+    /// Values are relative to the build directory.
+    pub directories: BTreeMap<String, String>,
+}
+
+impl OutputDirectories {
+    pub fn from_build_path(build_path: &Path) -> Result<Self> {
+        let path = build_path.join(OUTPUT_DIRECTORIES_FILENAME);
+
+        if !path.exists() {
+            return Ok(OutputDirectories::default());
+        }
+
+        let data = std::fs::read(&path).with_context(|| format!("reading {}", path.display()))?;
+        serde_json::from_slice(&data).with_context(|| format!("parsing {}", path.display()))
+    }
+
+    pub fn write(&self, build_path: &Path) -> Result<()> {
+        let path = build_path.join(OUTPUT_DIRECTORIES_FILENAME);
+
+        std::fs::write(&path, serde_json::to_vec_pretty(self)?)
+            .with_context(|| format!("writing {}", path.display()))
+    }
+}
+
+fn layout_key(path: &Path) -> String {
+    path.components()
+        .map(|c| c.as_os_str().to_string_lossy().to_string())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Find directories an application was packaged into by previous layouts.
+///
+/// `output_directory` is relative to the build directory. Besides recorded
+/// directories, the directory of the default layout is found, as builds
+/// before layouts were recorded used it.
+pub fn previous_layout_directories(
+    build_path: &Path,
+    owner: &OutputOwner,
+    output_directory: &Path,
+) -> Result<Vec<PathBuf>> {
+    let recorded = OutputDirectories::from_build_path(build_path)?;
+    let mut candidates = Vec::new();
+
+    if let Some(previous) = recorded.directories.get(&owner.key()) {
+        candidates.push(PathBuf::from(previous));
+    }
+    candidates.push(expand_output_layout(DEFAULT_OUTPUT_LAYOUT, owner)?);
+
+    let mut res = Vec::new();
+    for candidate in candidates {
+        if candidate == output_directory || res.contains(&build_path.join(&candidate)) {
+            continue;
+        }
+
+        let manifest_path = build_path.join(&candidate).join(BUILD_MANIFEST_FILENAME);
+        let holds_owner = match BuildManifest::from_path(&manifest_path) {
+            Ok(manifest) => {
+                manifest.application_name == owner.app && manifest.target_triple == owner.triple
+            }
+            Err(_) => false,
+        };
+
+        if holds_owner {
+            res.push(build_path.join(&candidate));
+        }
+    }
+
+    Ok(res)
+}
+
+/// Prepare packaging an application into a directory.
+///
+/// Fails if the directory holds another application and warns about
+/// directories left behind by previous layouts.
+pub fn prepare_output_directory(
+    logger: &slog::Logger,
+    build_path: &Path,
+    app_path: &Path,
+    owner: &OutputOwner,
+) -> Result<()> {
+    check_output_owner(app_path, owner)?;
+
+    if let Ok(output_directory) = app_path.strip_prefix(build_path) {
+        for path in previous_layout_directories(build_path, owner, output_directory)? {
+            warn!(
+                logger,
+                "{} holds {} from a previous output layout and is no longer updated; remove it if it isn't needed",
+                path.display(),
+                owner.app
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Record the directory an application was packaged into.
+pub fn record_output_directory(
+    build_path: &Path,
+    app_path: &Path,
+    owner: &OutputOwner,
+) -> Result<()> {
+    let output_directory = match app_path.strip_prefix(build_path) {
+        Ok(path) => path,
+        Err(_) => return Ok(()),
+    };
+
+    let mut recorded = OutputDirectories::from_build_path(build_path)?;
+    recorded
+        .directories
+        .insert(owner.key(), layout_key(output_directory));
+    recorded.write(build_path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn owner(app: &str) -> OutputOwner {
+        OutputOwner {
+            app: app.to_string(),
+            triple: "x86_64-unknown-linux-gnu".to_string(),
+            profile: "release".to_string(),
+        }
+    }
+
+    fn write_manifest(app_path: &Path, owner: &OutputOwner) -> Result<()> {
+        std::fs::create_dir_all(app_path)?;
+        let mut manifest = BuildManifest::from_directory(
+            &owner.app,
+            &owner.triple,
+            app_path,
+            app_path,
+            None,
+            None,
+        )?;
+        manifest.profile = Some(owner.profile.clone());
+        manifest.write_path(&app_path.join(BUILD_MANIFEST_FILENAME))
+    }
+
+    #[test]
+    fn test_expand() -> Result<()> {
+        assert_eq!(
+            expand_output_layout(DEFAULT_OUTPUT_LAYOUT, &owner("myapp"))?,
+            PathBuf::from("apps/myapp/x86_64-unknown-linux-gnu/release")
+        );
+        assert_eq!(
+            expand_output_layout("dist/{app}-{profile}", &owner("myapp"))?,
+            PathBuf::from("dist/myapp-release")
+        );
+        assert_eq!(
+            expand_output_layout("out", &owner("myapp"))?,
+            PathBuf::from("out")
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalid() {
+        let err = |layout: &str| validate_output_layout(layout).unwrap_err().to_string();
+
+        assert_eq!(
+            err("apps/{name}"),
+            "unknown token {name} in output layout 'apps/{name}'; expected one of {app}, {triple}, {profile}"
+        );
+        assert_eq!(err("apps/{app"), "unmatched { in output layout 'apps/{app'");
+        assert_eq!(err("apps/app}"), "unmatched } in output layout 'apps/app}'");
+        assert!(
+            err("/apps/{app}").starts_with("output layout '/apps/{app}' must be a relative path")
+        );
+        assert!(err("../{app}").starts_with("output layout '../{app}' must be a relative path"));
+        assert!(err("").starts_with("output layout '' must be a relative path"));
+    }
+
+    #[test]
+    fn test_resolve() {
+        assert_eq!(resolve_output_layout(None), DEFAULT_OUTPUT_LAYOUT);
+        assert_eq!(resolve_output_layout(Some("out/{app}")), "out/{app}");
+    }
+
+    #[test]
+    fn test_collision() -> Result<()> {
+        let temp_dir = tempdir::TempDir::new("pyoxidizer-test")?;
+        let layout = "apps/{profile}";
+
+        let first = owner("first");
+        let second = owner("second");
+        let first_path = temp_dir.path().join(expand_output_layout(layout, &first)?);
+        let second_path = temp_dir.path().join(expand_output_layout(layout, &second)?);
+        assert_eq!(first_path, second_path);
+
+        check_output_owner(&first_path, &first)?;
+        write_manifest(&first_path, &first)?;
+        check_output_owner(&first_path, &first)?;
+
+        let err = check_output_owner(&second_path, &second)
+            .unwrap_err()
+            .to_string();
+        assert!(err.starts_with(
+            "first (x86_64-unknown-linux-gnu release) and second (x86_64-unknown-linux-gnu release) map to the same output directory"
+        ));
+
+        let mut debug = first.clone();
+        debug.profile = "debug".to_string();
+        assert!(check_output_owner(&first_path, &debug).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_previous_layout() -> Result<()> {
+        let temp_dir = tempdir::TempDir::new("pyoxidizer-test")?;
+        let build_path = temp_dir.path();
+        let owner = owner("myapp");
+
+        let default_dir = expand_output_layout(DEFAULT_OUTPUT_LAYOUT, &owner)?;
+        assert!(previous_layout_directories(build_path, &owner, &default_dir)?.is_empty());
+
+        // A build with the default layout, before layouts were recorded.
+        write_manifest(&build_path.join(&default_dir), &owner)?;
+        assert!(previous_layout_directories(build_path, &owner, &default_dir)?.is_empty());
+
+        let custom_dir = expand_output_layout("dist/{app}", &owner)?;
+        assert_eq!(
+            previous_layout_directories(build_path, &owner, &custom_dir)?,
+            vec![build_path.join(&default_dir)]
+        );
+
+        write_manifest(&build_path.join(&custom_dir), &owner)?;
+        record_output_directory(build_path, &build_path.join(&custom_dir), &owner)?;
+        std::fs::remove_dir_all(build_path.join(&default_dir))?;
+
+        let other_dir = expand_output_layout("out/{app}", &owner)?;
+        assert_eq!(
+            previous_layout_directories(build_path, &owner, &other_dir)?,
+            vec![build_path.join(&custom_dir)]
+        );
+        assert!(previous_layout_directories(build_path, &owner, &custom_dir)?.is_empty());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,686 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/*!
+Running Python scripts that alter the set of packaged resources.
+
+Configurations register scripts with `run_python_packaging_script()`. Once
+resources are resolved, each script is executed by the distribution's Python
+as `python <script> <resources.json> <operations.json>`.
+
+`resources.json` describes the resources collected so far:
+
+```json
+{
+  "modules": [{"name": "foo", "is_package": false, "source": false, "bytecode": true}],
+  "resources": [{"package": "foo", "name": "data.txt", "size": 42}],
+  "extension_modules": ["_ssl"]
+}
+```
+
+The script writes a JSON array of operations to `operations.json`. Each
+operation is an object whose `op` key is one of `add_module`,
+`modify_module`, `remove_module`, `add_resource`, `modify_resource`,
+`remove_resource`, `remove_extension_module` or `depend`. Content of added
+or modified entries comes from an inline `source` (modules) or `data`
+(resources) string or from a file named by `path`.
+
+The script, files named by `path` and files declared by `depend` operations
+are dependencies of the build.
+*/
+
+use anyhow::{anyhow, Context, Result};
+use serde::Deserialize;
+use slog::warn;
+use std::collections::BTreeMap;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use crate::cancel;
+use crate::py_packaging::embedded_resource::EmbeddedPythonResources;
+use crate::py_packaging::resource::{packages_from_module_name, PackagedModuleBytecode};
+
+/// An operation emitted by a packaging script.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum Operation {
+    AddModule {
+        name: String,
+        #[serde(default)]
+        is_package: bool,
+        source: Option<String>,
+        path: Option<PathBuf>,
+    },
+    ModifyModule {
+        name: String,
+        source: Option<String>,
+        path: Option<PathBuf>,
+    },
+    RemoveModule {
+        name: String,
+    },
+    AddResource {
+        package: String,
+        name: String,
+        data: Option<String>,
+        path: Option<PathBuf>,
+    },
+    ModifyResource {
+        package: String,
+        name: String,
+        data: Option<String>,
+        path: Option<PathBuf>,
+    },
+    RemoveResource {
+        package: String,
+        name: String,
+    },
+    RemoveExtensionModule {
+        name: String,
+    },
+    Depend {
+        path: PathBuf,
+    },
+}
+
+impl Operation {
+    /// Describe the operation for error messages.
+    fn describe(&self) -> String {
+        match self {
+            Operation::AddModule { name, .. } => format!("add_module {}", name),
+            Operation::ModifyModule { name, .. } => format!("modify_module {}", name),
+            Operation::RemoveModule { name } => format!("remove_module {}", name),
+            Operation::AddResource { package, name, .. } => {
+                format!("add_resource {}:{}", package, name)
+            }
+            Operation::ModifyResource { package, name, .. } => {
+                format!("modify_resource {}:{}", package, name)
+            }
+            Operation::RemoveResource { package, name } => {
+                format!("remove_resource {}:{}", package, name)
+            }
+            Operation::RemoveExtensionModule { name } => {
+                format!("remove_extension_module {}", name)
+            }
+            Operation::Depend { path } => format!("depend {}", path.display()),
+        }
+    }
+}
+
+/// Describe resources in the format passed to packaging scripts.
+pub fn resources_manifest(resources: &EmbeddedPythonResources) -> serde_json::Value {
+    let modules = resources
+        .all_modules
+        .iter()
+        .filter(|name| !resources.built_extension_modules.contains_key(*name))
+        .map(|name| {
+            serde_json::json!({
+                "name": name,
+                "is_package": resources.all_packages.contains(name),
+                "source": resources.module_sources.contains_key(name),
+                "bytecode": resources.module_bytecodes.contains_key(name),
+            })
+        })
+        .collect::<Vec<_>>();
+
+    let data = resources
+        .resources
+        .iter()
+        .flat_map(|(package, entries)| {
+            entries.iter().map(move |(name, data)| {
+                serde_json::json!({
+                    "package": package,
+                    "name": name,
+                    "size": data.len(),
+                })
+            })
+        })
+        .collect::<Vec<_>>();
+
+    let extension_modules = resources
+        .extension_modules
+        .keys()
+        .chain(resources.built_extension_modules.keys())
+        .collect::<Vec<_>>();
+
+    serde_json::json!({
+        "modules": modules,
+        "resources": data,
+        "extension_modules": extension_modules,
+    })
+}
+
+/// Applies operations from one packaging script to resources.
+struct Applier<'a> {
+    script: &'a Path,
+    cwd: &'a Path,
+    resources: &'a mut EmbeddedPythonResources,
+    compile: &'a mut dyn FnMut(&str, &[u8]) -> Result<Vec<u8>>,
+    dependencies: Vec<PathBuf>,
+}
+
+impl<'a> Applier<'a> {
+    fn error(&self, op: &Operation, message: &str) -> anyhow::Error {
+        anyhow!(
+            "packaging script {}: {}: {}",
+            self.script.display(),
+            op.describe(),
+            message
+        )
+    }
+
+    /// Resolve the content of an added or modified entry.
+    fn content(
+        &mut self,
+        op: &Operation,
+        inline: &Option<String>,
+        path: &Option<PathBuf>,
+    ) -> Result<Vec<u8>> {
+        match (inline, path) {
+            (Some(data), None) => Ok(data.as_bytes().to_vec()),
+            (None, Some(path)) => {
+                let path = self.cwd.join(path);
+                let data = std::fs::read(&path).map_err(|e| {
+                    self.error(op, &format!("unable to read {}: {}", path.display(), e))
+                })?;
+                self.dependencies.push(path);
+
+                Ok(data)
+            }
+            _ => Err(self.error(op, "exactly one of inline content or path must be given")),
+        }
+    }
+
+    fn compile(&mut self, op: &Operation, name: &str, source: &[u8]) -> Result<Vec<u8>> {
+        (self.compile)(name, source).map_err(|e| self.error(op, &format!("{:#}", e)))
+    }
+
+    fn apply(&mut self, op: &Operation) -> Result<()> {
+        match op {
+            Operation::AddModule {
+                name,
+                is_package,
+                source,
+                path,
+            } => {
+                if self.resources.all_modules.contains(name) {
+                    return Err(self.error(op, "module already exists"));
+                }
+
+                for package in packages_from_module_name(name) {
+                    if !self.resources.all_packages.contains(&package) {
+                        return Err(
+                            self.error(op, &format!("parent package {} does not exist", package))
+                        );
+                    }
+                }
+
+                let source = self.content(op, source, path)?;
+                let bytecode = self.compile(op, name, &source)?;
+
+                self.resources.module_bytecodes.insert(
+                    name.clone(),
+                    PackagedModuleBytecode {
+                        bytecode,
+                        is_package: *is_package,
+                    },
+                );
+                self.resources.all_modules.insert(name.clone());
+                if *is_package {
+                    self.resources.all_packages.insert(name.clone());
+                }
+            }
+            Operation::ModifyModule { name, source, path } => {
+                if !self.resources.module_sources.contains_key(name)
+                    && !self.resources.module_bytecodes.contains_key(name)
+                {
+                    return Err(self.error(op, "module does not exist"));
+                }
+
+                let source = self.content(op, source, path)?;
+
+                if self.resources.module_bytecodes.contains_key(name) {
+                    let bytecode = self.compile(op, name, &source)?;
+                    self.resources
+                        .module_bytecodes
+                        .get_mut(name)
+                        .unwrap()
+                        .bytecode = bytecode;
+                }
+
+                if let Some(entry) = self.resources.module_sources.get_mut(name) {
+                    entry.source = source;
+                }
+            }
+            Operation::RemoveModule { name } => {
+                if !self.resources.module_sources.contains_key(name)
+                    && !self.resources.module_bytecodes.contains_key(name)
+                {
+                    return Err(self.error(op, "module does not exist"));
+                }
+
+                let prefix = format!("{}.", name);
+                if let Some(child) = self
+                    .resources
+                    .all_modules
+                    .iter()
+                    .find(|m| m.starts_with(&prefix))
+                {
+                    return Err(self.error(
+                        op,
+                        &format!("module {} in the package must be removed first", child),
+                    ));
+                }
+
+                self.resources.module_sources.remove(name);
+                self.resources.module_bytecodes.remove(name);
+                self.resources.all_modules.remove(name);
+                self.resources.all_packages.remove(name);
+                // Resources of a removed package can't be loaded.
+                self.resources.resources.remove(name);
+            }
+            Operation::AddResource {
+                package,
+                name,
+                data,
+                path,
+            } => {
+                if !self.resources.all_packages.contains(package) {
+                    return Err(self.error(op, "package does not exist"));
+                }
+
+                if self
+                    .resources
+                    .resources
+                    .get(package)
+                    .map_or(false, |entries| entries.contains_key(name))
+                {
+                    return Err(self.error(op, "resource already exists"));
+                }
+
+                let data = self.content(op, data, path)?;
+                self.resources
+                    .resources
+                    .entry(package.clone())
+                    .or_insert_with(BTreeMap::new)
+                    .insert(name.clone(), data);
+            }
+            Operation::ModifyResource {
+                package,
+                name,
+                data,
+                path,
+            } => {
+                if !self
+                    .resources
+                    .resources
+                    .get(package)
+                    .map_or(false, |entries| entries.contains_key(name))
+                {
+                    return Err(self.error(op, "resource does not exist"));
+                }
+
+                let data = self.content(op, data, path)?;
+                self.resources
+                    .resources
+                    .get_mut(package)
+                    .unwrap()
+                    .insert(name.clone(), data);
+            }
+            Operation::RemoveResource { package, name } => {
+                let entries = match self.resources.resources.get_mut(package) {
+                    Some(entries) if entries.contains_key(name) => entries,
+                    _ => return Err(self.error(op, "resource does not exist")),
+                };
+
+                entries.remove(name);
+                if entries.is_empty() {
+                    self.resources.resources.remove(package);
+                }
+            }
+            Operation::RemoveExtensionModule { name } => {
+                if self.resources.extension_modules.remove(name).is_none() {
+                    if self
+                        .resources
+                        .built_extension_modules
+                        .remove(name)
+                        .is_none()
+                    {
+                        return Err(self.error(op, "extension module does not exist"));
+                    }
+
+                    self.resources.all_modules.remove(name);
+                    self.resources.all_packages.remove(name);
+                }
+            }
+            Operation::Depend { path } => {
+                let path = self.cwd.join(path);
+                if !path.exists() {
+                    return Err(self.error(op, "file does not exist"));
+                }
+
+                self.dependencies.push(path);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Apply operations emitted by a packaging script to resources.
+///
+/// `compile` turns module source into bytecode. Relative paths in operations
+/// are resolved against `cwd`.
+///
+/// Returns files the script's operations depend on.
+pub fn apply_operations(
+    script: &Path,
+    cwd: &Path,
+    resources: &mut EmbeddedPythonResources,
+    operations: &[Operation],
+    compile: &mut dyn FnMut(&str, &[u8]) -> Result<Vec<u8>>,
+) -> Result<Vec<PathBuf>> {
+    let mut applier = Applier {
+        script,
+        cwd,
+        resources,
+        compile,
+        dependencies: Vec::new(),
+    };
+
+    for op in operations {
+        applier.apply(op)?;
+    }
+
+    Ok(applier.dependencies)
+}
+
+/// Run a packaging script and apply the operations it emits.
+///
+/// Returns files the script depends on, including the script itself.
+pub fn run_packaging_script(
+    logger: &slog::Logger,
+    python_exe: &Path,
+    script: &Path,
+    cwd: &Path,
+    resources: &mut EmbeddedPythonResources,
+    compile: &mut dyn FnMut(&str, &[u8]) -> Result<Vec<u8>>,
+) -> Result<Vec<PathBuf>> {
+    warn!(logger, "running packaging script {}", script.display());
+
+    let temp_dir = tempdir::TempDir::new("pyoxidizer-packaging-script")?;
+    let manifest_path = temp_dir.path().join("resources.json");
+    let operations_path = temp_dir.path().join("operations.json");
+
+    std::fs::write(
+        &manifest_path,
+        serde_json::to_vec_pretty(&resources_manifest(resources))?,
+    )?;
+
+    let mut cmd = Command::new(python_exe);
+    cmd.arg(script)
+        .arg(&manifest_path)
+        .arg(&operations_path)
+        .current_dir(cwd)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit());
+
+    let mut child = cancel::spawn(&mut cmd)
+        .with_context(|| format!("running packaging script {}", script.display()))?;
+
+    // Output is logged rather than passed through, as build scripts' stdout
+    // is interpreted by Cargo.
+    let mut output = String::new();
+    if let Some(mut stdout) = child.child_mut().stdout.take() {
+        stdout.read_to_string(&mut output)?;
+    }
+
+    let status = child.wait()?;
+
+    for line in output.lines() {
+        warn!(logger, "{}", line);
+    }
+
+    if !status.success() {
+        return Err(anyhow!(
+            "packaging script {} failed ({})",
+            script.display(),
+            status
+        ));
+    }
+
+    let operations: Vec<Operation> = if operations_path.exists() {
+        let data = std::fs::read(&operations_path)?;
+        serde_json::from_slice(&data).map_err(|e| {
+            anyhow!(
+                "packaging script {}: invalid operations: {}",
+                script.display(),
+                e
+            )
+        })?
+    } else {
+        Vec::new()
+    };
+
+    warn!(
+        logger,
+        "applying {} operations from {}",
+        operations.len(),
+        script.display()
+    );
+
+    let mut dependencies = vec![script.to_path_buf()];
+    dependencies.extend(apply_operations(
+        script,
+        cwd,
+        resources,
+        &operations,
+        compile,
+    )?);
+
+    Ok(dependencies)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::py_packaging::resource::PackagedModuleSource;
+
+    fn fixture_resources() -> EmbeddedPythonResources {
+        let mut resources = EmbeddedPythonResources::default();
+
+        for (name, is_package) in &[("foo", true), ("foo.bar", false)] {
+            resources.module_bytecodes.insert(
+                name.to_string(),
+                PackagedModuleBytecode {
+                    bytecode: b"old".to_vec(),
+                    is_package: *is_package,
+                },
+            );
+            resources.all_modules.insert(name.to_string());
+        }
+        resources.module_sources.insert(
+            "foo.bar".to_string(),
+            PackagedModuleSource {
+                source: b"old".to_vec(),
+                is_package: false,
+            },
+        );
+        resources.all_packages.insert("foo".to_string());
+
+        let mut entries = BTreeMap::new();
+        entries.insert("data.txt".to_string(), b"data".to_vec());
+        resources.resources.insert("foo".to_string(), entries);
+
+        resources
+    }
+
+    fn apply(resources: &mut EmbeddedPythonResources, operations: &str) -> Result<Vec<PathBuf>> {
+        let operations: Vec<Operation> = serde_json::from_str(operations)?;
+
+        apply_operations(
+            Path::new("prune.py"),
+            Path::new("/config"),
+            resources,
+            &operations,
+            &mut |name, source| Ok(format!("{}:{}", name, String::from_utf8_lossy(source)).into()),
+        )
+    }
+
+    #[test]
+    fn test_resources_manifest() {
+        let manifest = resources_manifest(&fixture_resources());
+
+        assert_eq!(
+            manifest["modules"][1],
+            serde_json::json!({
+                "name": "foo.bar",
+                "is_package": false,
+                "source": true,
+                "bytecode": true,
+            })
+        );
+        assert_eq!(manifest["resources"][0]["size"], 4);
+    }
+
+    #[test]
+    fn test_apply_operations() -> Result<()> {
+        let mut resources = fixture_resources();
+
+        apply(
+            &mut resources,
+            r#"[
+                {"op": "add_module", "name": "foo.baz", "source": "x = 1"},
+                {"op": "modify_module", "name": "foo.bar", "source": "y = 2"},
+                {"op": "modify_resource", "package": "foo", "name": "data.txt", "data": "new"},
+                {"op": "add_resource", "package": "foo", "name": "extra.txt", "data": "extra"}
+            ]"#,
+        )?;
+
+        assert_eq!(
+            resources.module_bytecodes["foo.baz"].bytecode,
+            b"foo.baz:x = 1"
+        );
+        assert!(resources.all_modules.contains("foo.baz"));
+        assert_eq!(
+            resources.module_bytecodes["foo.bar"].bytecode,
+            b"foo.bar:y = 2"
+        );
+        assert_eq!(resources.module_sources["foo.bar"].source, b"y = 2");
+        assert_eq!(resources.resources["foo"]["data.txt"], b"new");
+        assert_eq!(resources.resources["foo"]["extra.txt"], b"extra");
+
+        apply(
+            &mut resources,
+            r#"[
+                {"op": "remove_module", "name": "foo.bar"},
+                {"op": "remove_module", "name": "foo.baz"},
+                {"op": "remove_module", "name": "foo"}
+            ]"#,
+        )?;
+
+        assert!(resources.all_modules.is_empty());
+        assert!(resources.all_packages.is_empty());
+        assert!(resources.resources.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_apply_missing() {
+        let mut resources = fixture_resources();
+
+        let err = apply(
+            &mut resources,
+            r#"[{"op": "remove_module", "name": "missing"}]"#,
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "packaging script prune.py: remove_module missing: module does not exist"
+        );
+
+        let err = apply(
+            &mut resources,
+            r#"[{"op": "modify_resource", "package": "foo", "name": "missing.txt", "data": ""}]"#,
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "packaging script prune.py: modify_resource foo:missing.txt: resource does not exist"
+        );
+
+        let err = apply(
+            &mut resources,
+            r#"[{"op": "add_module", "name": "missing.mod", "source": ""}]"#,
+        )
+        .unwrap_err();
+        assert!(err
+            .to_string()
+            .ends_with("parent package missing does not exist"));
+
+        let err = apply(
+            &mut resources,
+            r#"[{"op": "remove_module", "name": "foo"}]"#,
+        )
+        .unwrap_err();
+        assert!(err
+            .to_string()
+            .ends_with("module foo.bar in the package must be removed first"));
+
+        let err = apply(
+            &mut resources,
+            r#"[{"op": "remove_extension_module", "name": "_ssl"}]"#,
+        )
+        .unwrap_err();
+        assert!(err.to_string().ends_with("extension module does not exist"));
+    }
+
+    #[test]
+    fn test_apply_depend() -> Result<()> {
+        let temp_dir = tempdir::TempDir::new("pyoxidizer-test")?;
+        std::fs::write(temp_dir.path().join("keep.txt"), b"foo.bar\n")?;
+        std::fs::write(temp_dir.path().join("new.py"), b"z = 3")?;
+
+        let mut resources = fixture_resources();
+        let operations: Vec<Operation> = serde_json::from_str(
+            r#"[
+                {"op": "depend", "path": "keep.txt"},
+                {"op": "modify_module", "name": "foo.bar", "path": "new.py"}
+            ]"#,
+        )?;
+
+        let dependencies = apply_operations(
+            Path::new("prune.py"),
+            temp_dir.path(),
+            &mut resources,
+            &operations,
+            &mut |_, source| Ok(source.to_vec()),
+        )?;
+
+        assert_eq!(
+            dependencies,
+            vec![
+                temp_dir.path().join("keep.txt"),
+                temp_dir.path().join("new.py")
+            ]
+        );
+        assert_eq!(resources.module_sources["foo.bar"].source, b"z = 3");
+
+        let operations: Vec<Operation> =
+            serde_json::from_str(r#"[{"op": "depend", "path": "missing.txt"}]"#)?;
+        assert!(apply_operations(
+            Path::new("prune.py"),
+            temp_dir.path(),
+            &mut resources,
+            &operations,
+            &mut |_, source| Ok(source.to_vec()),
+        )
+        .is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,490 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/*!
+Describe what a build would do without doing it.
+
+`pyoxidizer build --plan` evaluates a project's config and resolves the
+resources it packages, then stops before writing build artifacts or
+invoking cargo. The plan lists the embedded resources, the files the build
+would write, the lines (including link flags) the build script emits to
+cargo and the cargo invocation.
+
+Plans rendered as JSON are deterministic so plans of different configs or
+PyOxidizer versions can be diffed.
+*/
+
+use anyhow::Result;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::path::PathBuf;
+
+use super::build_environment::redact_env_value;
+use super::repackage::{AppPaths, ArtifactPaths};
+use super::state::{BuildContext, PackagingState};
+use crate::py_packaging::embedded_resource::EmbeddedPythonResources;
+use crate::py_packaging::libpython::LibpythonPaths;
+
+/// How much of a build to plan.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PlanMode {
+    /// Resolve the Python distribution and resources.
+    Full,
+    /// Only evaluate and validate the config.
+    Shallow,
+}
+
+impl PlanMode {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            PlanMode::Full => "full",
+            PlanMode::Shallow => "shallow",
+        }
+    }
+}
+
+/// Number and total size of a kind of resource.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct ResourceCount {
+    pub count: usize,
+    pub bytes: u64,
+}
+
+impl ResourceCount {
+    fn add(&mut self, data: &[u8]) {
+        self.count += 1;
+        self.bytes += data.len() as u64;
+    }
+}
+
+/// Resources embedded in the built binary.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct ResourcesPlan {
+    pub source_modules: ResourceCount,
+    pub bytecode_modules: ResourceCount,
+    pub resource_files: ResourceCount,
+    /// Names of extension modules from the Python distribution.
+    pub extension_modules: Vec<String>,
+    /// Names of extension modules built from source.
+    pub built_extension_modules: Vec<String>,
+}
+
+impl ResourcesPlan {
+    pub fn from_resources(resources: &EmbeddedPythonResources) -> Self {
+        let mut plan = ResourcesPlan::default();
+
+        for module in resources.module_sources.values() {
+            plan.source_modules.add(&module.source);
+        }
+
+        for module in resources.module_bytecodes.values() {
+            plan.bytecode_modules.add(&module.bytecode);
+        }
+
+        for entries in resources.resources.values() {
+            for data in entries.values() {
+                plan.resource_files.add(data);
+            }
+        }
+
+        plan.extension_modules = resources.extension_modules.keys().cloned().collect();
+        plan.built_extension_modules = resources.built_extension_modules.keys().cloned().collect();
+
+        plan
+    }
+}
+
+/// A file or directory a build writes.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct PlannedFile {
+    pub path: PathBuf,
+    pub description: String,
+}
+
+impl PlannedFile {
+    fn new(path: PathBuf, description: &str) -> Self {
+        PlannedFile {
+            path,
+            description: description.to_string(),
+        }
+    }
+}
+
+/// How cargo is invoked to build the project.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct CargoInvocation {
+    pub args: Vec<String>,
+    /// Environment variables set for cargo, with secrets redacted.
+    pub env: BTreeMap<String, String>,
+    pub cwd: PathBuf,
+}
+
+impl CargoInvocation {
+    pub fn new(args: Vec<String>, env: &[(String, String)], cwd: PathBuf) -> Self {
+        CargoInvocation {
+            args,
+            env: env
+                .iter()
+                .map(|(k, v)| (k.to_string(), redact_env_value(k, v)))
+                .collect(),
+            cwd,
+        }
+    }
+}
+
+/// Describes what a build would do.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct BuildPlan {
+    pub mode: PlanMode,
+    pub config_path: PathBuf,
+    pub target_triple: String,
+    pub release: bool,
+    pub artifacts_path: PathBuf,
+    pub app_path: PathBuf,
+    /// Resources to embed. Not resolved by shallow plans.
+    pub resources: Option<ResourcesPlan>,
+    /// Files written to the artifacts and application directories.
+    pub files: Vec<PlannedFile>,
+    /// Lines the build script emits to cargo, including link flags. Not
+    /// resolved by shallow plans.
+    pub cargo_metadata: Option<Vec<String>>,
+    /// Not resolved by shallow plans.
+    pub cargo: Option<CargoInvocation>,
+}
+
+impl BuildPlan {
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+
+    pub fn to_text(&self) -> String {
+        let mut s = String::new();
+
+        let heading = |s: &mut String, title: &str| {
+            writeln!(s).unwrap();
+            writeln!(s, "{}", title).unwrap();
+            writeln!(s, "{}", "=".repeat(title.len())).unwrap();
+            writeln!(s).unwrap();
+        };
+
+        writeln!(
+            s,
+            "Build plan ({}) for {}",
+            self.mode.as_str(),
+            self.config_path.display()
+        )
+        .unwrap();
+        writeln!(
+            s,
+            "Target: {} ({})",
+            self.target_triple,
+            if self.release { "release" } else { "debug" }
+        )
+        .unwrap();
+        writeln!(s, "Artifacts: {}", self.artifacts_path.display()).unwrap();
+        writeln!(s, "Application: {}", self.app_path.display()).unwrap();
+
+        if let Some(resources) = &self.resources {
+            heading(&mut s, "Embedded Resources");
+
+            for (name, count) in &[
+                ("Source modules", &resources.source_modules),
+                ("Bytecode modules", &resources.bytecode_modules),
+                ("Resource files", &resources.resource_files),
+            ] {
+                writeln!(
+                    s,
+                    "{:<26}{:>6} ({} bytes)",
+                    format!("{}:", name),
+                    count.count,
+                    count.bytes
+                )
+                .unwrap();
+            }
+
+            for (name, names) in &[
+                ("Extension modules", &resources.extension_modules),
+                (
+                    "Built extension modules",
+                    &resources.built_extension_modules,
+                ),
+            ] {
+                writeln!(s, "{:<26}{:>6}", format!("{}:", name), names.len()).unwrap();
+                if !names.is_empty() {
+                    writeln!(s, "  {}", names.join(", ")).unwrap();
+                }
+            }
+        }
+
+        heading(&mut s, "Files");
+        for file in &self.files {
+            writeln!(s, "{} ({})", file.path.display(), file.description).unwrap();
+        }
+
+        if let Some(cargo_metadata) = &self.cargo_metadata {
+            heading(&mut s, "Cargo Metadata");
+            for line in cargo_metadata {
+                writeln!(s, "{}", line).unwrap();
+            }
+        }
+
+        if let Some(cargo) = &self.cargo {
+            heading(&mut s, "Cargo Invocation");
+            writeln!(s, "cd {}", cargo.cwd.display()).unwrap();
+            for (k, v) in &cargo.env {
+                writeln!(s, "{}={} \\", k, v).unwrap();
+            }
+            writeln!(s, "cargo {}", cargo.args.join(" ")).unwrap();
+        }
+
+        s
+    }
+}
+
+/// Obtain the files a build writes to the artifacts directory.
+pub fn artifact_files(context: &BuildContext) -> Vec<PlannedFile> {
+    // Destructured so a file added to the artifacts must be described here.
+    let ArtifactPaths {
+        python_distribution,
+        importlib_bootstrap,
+        importlib_bootstrap_external,
+        module_names,
+        py_modules,
+        resources,
+        libpython:
+            LibpythonPaths {
+                config_c,
+                libpyembeddedconfig,
+                libpython,
+            },
+        resolved_config,
+        data_rs,
+        cargo_metadata,
+        packaging_state,
+        inputs_manifest,
+        build_environment,
+    } = ArtifactPaths::new(context);
+
+    vec![
+        PlannedFile::new(python_distribution, "extracted Python distribution"),
+        PlannedFile::new(importlib_bootstrap, "frozen importlib._bootstrap bytecode"),
+        PlannedFile::new(
+            importlib_bootstrap_external,
+            "frozen importlib._bootstrap_external bytecode",
+        ),
+        PlannedFile::new(module_names, "embedded module names"),
+        PlannedFile::new(py_modules, "packed embedded module data"),
+        PlannedFile::new(resources, "packed embedded resource data"),
+        PlannedFile::new(config_c, "extension module table"),
+        PlannedFile::new(libpyembeddedconfig, "compiled extension module table"),
+        PlannedFile::new(libpython, "static library containing Python"),
+        PlannedFile::new(resolved_config, "resolved interpreter config"),
+        PlannedFile::new(data_rs, "Rust source of interpreter config"),
+        PlannedFile::new(cargo_metadata, "cargo build script lines"),
+        PlannedFile::new(packaging_state, "packaging state"),
+        PlannedFile::new(inputs_manifest, "digests of artifact inputs"),
+        PlannedFile::new(build_environment, "record of the cargo invocation"),
+    ]
+}
+
+/// Obtain the files packaging writes to the application directory.
+///
+/// License files are only known if `state` is given.
+pub fn app_files(
+    context: &BuildContext,
+    state: Option<&PackagingState>,
+) -> Result<Vec<PlannedFile>> {
+    // Destructured so a file added to applications must be described here.
+    let AppPaths {
+        exe,
+        debug_info_dir,
+        header,
+        multicall_links,
+        licenses,
+        notices,
+        manifest,
+    } = AppPaths::new(context, &context.app_path, state)?;
+
+    let mut files = Vec::new();
+
+    files.push(PlannedFile::new(
+        exe,
+        if context.library {
+            "library"
+        } else {
+            "executable"
+        },
+    ));
+
+    if let Some(debug_info_dir) = debug_info_dir {
+        files.push(PlannedFile::new(
+            debug_info_dir,
+            "directory receiving debug info",
+        ));
+    }
+
+    if let Some(header) = header {
+        files.push(PlannedFile::new(header, "C header"));
+    }
+
+    let windows = context.target_triple.contains("pc-windows");
+    for path in multicall_links {
+        files.push(PlannedFile::new(
+            path,
+            if windows {
+                "copy of executable for multicall run mode"
+            } else {
+                "symlink to executable for multicall run mode"
+            },
+        ));
+    }
+
+    for license in licenses {
+        files.push(PlannedFile::new(
+            license.path,
+            &format!("license of {}", license.name),
+        ));
+    }
+
+    if let Some(notices) = notices {
+        files.push(PlannedFile::new(notices, "third-party license notices"));
+    }
+
+    files.push(PlannedFile::new(manifest, "build manifest"));
+
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::py_packaging::resource::{PackagedModuleBytecode, PackagedModuleSource};
+
+    fn plan() -> BuildPlan {
+        BuildPlan {
+            mode: PlanMode::Full,
+            config_path: PathBuf::from("/app/pyoxidizer.bzl"),
+            target_triple: "x86_64-unknown-linux-gnu".to_string(),
+            release: false,
+            artifacts_path: PathBuf::from("/app/build/pyoxidizer"),
+            app_path: PathBuf::from("/app/build/apps/app"),
+            resources: Some(ResourcesPlan {
+                source_modules: ResourceCount {
+                    count: 2,
+                    bytes: 30,
+                },
+                extension_modules: vec!["_ssl".to_string(), "zlib".to_string()],
+                ..ResourcesPlan::default()
+            }),
+            files: vec![PlannedFile::new(
+                PathBuf::from("/app/build/apps/app/app"),
+                "executable",
+            )],
+            cargo_metadata: Some(vec![
+                "cargo:rustc-link-lib=static=pyembeddedconfig".to_string()
+            ]),
+            cargo: Some(CargoInvocation::new(
+                vec!["build".to_string(), "--bin".to_string(), "app".to_string()],
+                &[
+                    ("RUSTFLAGS".to_string(), "".to_string()),
+                    ("GITHUB_TOKEN".to_string(), "abc".to_string()),
+                ],
+                PathBuf::from("/app"),
+            )),
+        }
+    }
+
+    #[test]
+    fn test_resources_plan() {
+        let mut resources = EmbeddedPythonResources {
+            module_sources: BTreeMap::new(),
+            module_bytecodes: BTreeMap::new(),
+            all_modules: Default::default(),
+            all_packages: Default::default(),
+            resources: BTreeMap::new(),
+            extension_modules: BTreeMap::new(),
+            built_extension_modules: BTreeMap::new(),
+            lazy_index: false,
+        };
+
+        for (name, source) in &[("foo", "import bar\n"), ("bar", "")] {
+            resources.module_sources.insert(
+                name.to_string(),
+                PackagedModuleSource {
+                    source: source.as_bytes().to_vec(),
+                    is_package: false,
+                },
+            );
+        }
+        resources.module_bytecodes.insert(
+            "foo".to_string(),
+            PackagedModuleBytecode {
+                bytecode: vec![0; 42],
+                is_package: false,
+            },
+        );
+        let mut data = BTreeMap::new();
+        data.insert("a.txt".to_string(), vec![0; 3]);
+        data.insert("b.txt".to_string(), vec![0; 4]);
+        resources.resources.insert("foo".to_string(), data);
+
+        let plan = ResourcesPlan::from_resources(&resources);
+
+        assert_eq!(
+            plan.source_modules,
+            ResourceCount {
+                count: 2,
+                bytes: 11
+            }
+        );
+        assert_eq!(
+            plan.bytecode_modules,
+            ResourceCount {
+                count: 1,
+                bytes: 42
+            }
+        );
+        assert_eq!(plan.resource_files, ResourceCount { count: 2, bytes: 7 });
+        assert!(plan.extension_modules.is_empty());
+    }
+
+    #[test]
+    fn test_plan_json() -> Result<()> {
+        let value: serde_json::Value = serde_json::from_str(&plan().to_json()?)?;
+
+        assert_eq!(value["mode"], "full");
+        assert_eq!(value["resources"]["source_modules"]["bytes"], 30);
+        assert_eq!(value["files"][0]["description"], "executable");
+        assert_eq!(value["cargo"]["env"]["GITHUB_TOKEN"], "<redacted>");
+
+        let mut shallow = plan();
+        shallow.mode = PlanMode::Shallow;
+        shallow.resources = None;
+        shallow.cargo_metadata = None;
+        shallow.cargo = None;
+        let value: serde_json::Value = serde_json::from_str(&shallow.to_json()?)?;
+
+        assert_eq!(value["mode"], "shallow");
+        assert!(value["resources"].is_null());
+        assert!(value["cargo"].is_null());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_plan_text() {
+        let text = plan().to_text();
+
+        assert!(text.starts_with("Build plan (full) for /app/pyoxidizer.bzl\n"));
+        assert!(text.contains("\nSource modules:                2 (30 bytes)\n"));
+        assert!(text.contains("\nExtension modules:             2\n  _ssl, zlib\n"));
+        assert!(text.contains("\n/app/build/apps/app/app (executable)\n"));
+        assert!(text.contains("\ncargo:rustc-link-lib=static=pyembeddedconfig\n"));
+        assert!(
+            text.ends_with("GITHUB_TOKEN=<redacted> \\\nRUSTFLAGS= \\\ncargo build --bin app\n")
+        );
+    }
+}
@@ -0,0 +1,670 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/*!
+Builds split into a prepare and a compile phase.
+
+`pyoxidizer prepare` evaluates the config, runs pip and generates the
+PyOxidizer artifacts. It writes them into a prepared directory along with
+a copy of the project, the Python distribution, the evaluated config and
+`prepared.json` describing the build. `pyoxidizer compile` runs cargo and
+packages the application from a prepared directory, e.g. on a remote build
+machine without network access. It never evaluates the config.
+
+Artifacts and the config refer to files by absolute path. In a prepared
+directory, paths inside it start with `@PYOXIDIZER_PREPARED_DIR@` instead,
+so the directory can be moved or copied anywhere. Compiling copies it into
+the target directory with the placeholder replaced by the copy's path.
+*/
+
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+use slog::warn;
+use std::collections::BTreeMap;
+use std::path::{Component, Path, PathBuf};
+
+use super::config::Config;
+use super::state::{BuildContext, PackagingState};
+use crate::environment::BUILD_SEMVER;
+use crate::fsutils::{copy_file, remove_dir_all};
+
+/// Placeholder for the path of a prepared directory in files inside it.
+pub const PREPARED_DIR_PLACEHOLDER: &str = "@PYOXIDIZER_PREPARED_DIR@";
+
+/// File in a prepared directory describing the prepared build.
+pub const PREPARED_MANIFEST_FILENAME: &str = "prepared.json";
+
+/// File in a prepared directory holding the evaluated config.
+pub const PREPARED_CONFIG_FILENAME: &str = "config.json";
+
+/// Directory of a prepared directory holding the copy of the project.
+pub const PREPARED_PROJECT_DIR: &str = "project";
+
+/// Directory of a prepared directory holding the PyOxidizer artifacts.
+pub const PREPARED_ARTIFACTS_DIR: &str = "artifacts";
+
+/// Directory of a prepared directory holding the Python distribution.
+///
+/// Distributions extracted in the artifacts directory stay there.
+pub const PREPARED_DISTRIBUTION_DIR: &str = "distribution";
+
+/// Directory of the target directory a prepared directory is copied to.
+pub const COMPILE_PREPARED_DIR: &str = "prepared";
+
+/// Version of the prepared directory format.
+const FORMAT_VERSION: u32 = 1;
+
+/// The environment a build was prepared for.
+///
+/// Paths are relative to the prepared directory and use `/` as separator.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct PreparedManifest {
+    pub format_version: u32,
+    pub pyoxidizer_version: String,
+    pub target_triple: String,
+    /// Triple of the machine the build was prepared on.
+    pub host_triple: String,
+    pub release: bool,
+    pub app_name: String,
+    pub project_path: String,
+    pub config_path: String,
+    pub artifacts_path: String,
+    pub python_distribution_path: String,
+}
+
+impl PreparedManifest {
+    /// Read the manifest of a prepared directory.
+    pub fn from_prepared_path(prepared_path: &Path) -> Result<Self> {
+        let path = prepared_path.join(PREPARED_MANIFEST_FILENAME);
+
+        if !path.exists() {
+            return Err(anyhow!(
+                "{} is not a prepared build; {} not found",
+                prepared_path.display(),
+                PREPARED_MANIFEST_FILENAME
+            ));
+        }
+
+        let data = std::fs::read(&path).with_context(|| format!("reading {}", path.display()))?;
+        let manifest: PreparedManifest =
+            serde_json::from_slice(&data).with_context(|| format!("parsing {}", path.display()))?;
+
+        if manifest.format_version != FORMAT_VERSION {
+            return Err(anyhow!(
+                "{} has format version {}; this PyOxidizer reads version {}",
+                path.display(),
+                manifest.format_version,
+                FORMAT_VERSION
+            ));
+        }
+
+        Ok(manifest)
+    }
+
+    pub fn write(&self, prepared_path: &Path) -> Result<()> {
+        let path = prepared_path.join(PREPARED_MANIFEST_FILENAME);
+
+        std::fs::write(&path, serde_json::to_vec_pretty(self)?)
+            .with_context(|| format!("writing {}", path.display()))
+    }
+
+    /// Check that the prepared build can be compiled for a target by this
+    /// PyOxidizer.
+    ///
+    /// Artifacts are only understood by the version generating them and
+    /// are specific to the target they were generated for.
+    pub fn check_compatible(&self, target: &str, pyoxidizer_version: &str) -> Result<()> {
+        if self.pyoxidizer_version != pyoxidizer_version {
+            return Err(anyhow!(
+                "build was prepared by PyOxidizer {} and can't be compiled by PyOxidizer {}",
+                self.pyoxidizer_version,
+                pyoxidizer_version
+            ));
+        }
+
+        if self.target_triple != target {
+            return Err(anyhow!(
+                "build was prepared for {}, not {}; pass --target {} to compile it",
+                self.target_triple,
+                target,
+                self.target_triple
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+/// Join a path from a prepared manifest to the prepared directory.
+fn resolve_manifest_path(prepared_path: &Path, rel: &str) -> Result<PathBuf> {
+    let rel = Path::new(rel);
+
+    let inside = rel.components().all(|c| match c {
+        Component::Normal(_) | Component::CurDir => true,
+        _ => false,
+    });
+
+    if !inside {
+        return Err(anyhow!(
+            "{} in {} is not a path inside the prepared directory",
+            rel.display(),
+            PREPARED_MANIFEST_FILENAME
+        ));
+    }
+
+    Ok(prepared_path.join(rel))
+}
+
+/// Obtain a relative path as stored in a prepared manifest.
+fn manifest_path_string(path: &Path) -> String {
+    path.components()
+        .map(|c| c.as_os_str().to_string_lossy().to_string())
+        .collect::<Vec<String>>()
+        .join("/")
+}
+
+/// Escape a string like in a JSON string literal.
+fn json_escape(s: &str) -> String {
+    let json = serde_json::to_string(s).unwrap();
+
+    json[1..json.len() - 1].to_string()
+}
+
+/// Replaces paths in files so they refer to another location.
+///
+/// Paths are replaced longest first, so a path inside another mapped path
+/// is mapped by its own rule.
+#[derive(Clone, Debug, Default)]
+pub struct Relocation {
+    mappings: Vec<(String, String)>,
+}
+
+impl Relocation {
+    pub fn new() -> Self {
+        Relocation::default()
+    }
+
+    /// Map a path and everything below it to another path.
+    pub fn map(mut self, from: &str, to: &str) -> Self {
+        self.mappings.push((from.to_string(), to.to_string()));
+        self.mappings
+            .sort_by(|a, b| b.0.len().cmp(&a.0.len()).then_with(|| a.0.cmp(&b.0)));
+
+        self
+    }
+
+    /// Map paths in text.
+    ///
+    /// An occurrence only matches if it isn't the start of a longer file
+    /// name, e.g. `/build` doesn't match in `/build2`.
+    pub fn text(&self, text: &str) -> String {
+        let mut res = text.to_string();
+
+        for (from, to) in &self.mappings {
+            res = replace_path(&res, from, to);
+        }
+
+        res
+    }
+
+    /// Map paths in JSON, where separators are escaped on Windows.
+    pub fn json(&self, json: &str) -> String {
+        let mut res = json.to_string();
+
+        for (from, to) in &self.mappings {
+            res = replace_path(&res, &json_escape(from), &json_escape(to));
+        }
+
+        res
+    }
+
+    /// Map a path.
+    pub fn path(&self, path: &Path) -> PathBuf {
+        PathBuf::from(self.text(&path.display().to_string()))
+    }
+
+    /// Map paths in a packaging state.
+    fn packaging_state(&self, state: PackagingState) -> PackagingState {
+        PackagingState {
+            input_digests: state
+                .input_digests
+                .into_iter()
+                .map(|(path, digest)| (self.path(&path), digest))
+                .collect::<BTreeMap<_, _>>(),
+            ..state
+        }
+    }
+
+    /// Map paths in a file's content.
+    ///
+    /// Returns `None` if the content doesn't change, e.g. because the file
+    /// isn't text.
+    fn file_content(&self, path: &Path, data: &[u8]) -> Result<Option<Vec<u8>>> {
+        if path.file_name().and_then(|n| n.to_str()) == Some("packaging_state.cbor") {
+            let state: PackagingState = serde_cbor::from_slice(data)
+                .with_context(|| format!("parsing {}", path.display()))?;
+            let relocated = self.packaging_state(state.clone());
+
+            return Ok(if relocated.input_digests == state.input_digests {
+                None
+            } else {
+                Some(serde_cbor::to_vec(&relocated)?)
+            });
+        }
+
+        match std::str::from_utf8(data) {
+            Ok(text) => {
+                let relocated = if path.extension().and_then(|e| e.to_str()) == Some("json") {
+                    self.json(text)
+                } else {
+                    self.text(text)
+                };
+
+                Ok(if relocated == text {
+                    None
+                } else {
+                    Some(relocated.into_bytes())
+                })
+            }
+            Err(_) => Ok(None),
+        }
+    }
+}
+
+/// Replace occurrences of a path in text that aren't part of a longer name.
+fn replace_path(text: &str, from: &str, to: &str) -> String {
+    if from.is_empty() {
+        return text.to_string();
+    }
+
+    let mut res = String::with_capacity(text.len());
+    let mut rest = text;
+
+    while let Some(pos) = rest.find(from) {
+        let end = pos + from.len();
+        let continues_name = rest[end..].chars().next().map_or(false, |c| {
+            c.is_alphanumeric() || c == '_' || c == '-' || c == '.'
+        });
+
+        res.push_str(&rest[..pos]);
+        res.push_str(if continues_name { from } else { to });
+        rest = &rest[end..];
+    }
+
+    res.push_str(rest);
+
+    res
+}
+
+/// Copy a directory tree, mapping paths in the content of files.
+///
+/// Symlinks are copied as is. Permissions and modification times are
+/// preserved, so cargo doesn't consider copied files changed. Paths in
+/// `exclude` aren't copied.
+pub fn copy_relocated(
+    from: &Path,
+    to: &Path,
+    relocation: &Relocation,
+    exclude: &[PathBuf],
+) -> Result<()> {
+    if exclude.iter().any(|p| p == from) {
+        return Ok(());
+    }
+
+    let metadata = std::fs::symlink_metadata(from)
+        .with_context(|| format!("reading metadata of {}", from.display()))?;
+
+    #[cfg(unix)]
+    {
+        if metadata.file_type().is_symlink() {
+            let target = std::fs::read_link(from)?;
+            std::os::unix::fs::symlink(&target, to)
+                .with_context(|| format!("creating symlink {}", to.display()))?;
+            return Ok(());
+        }
+    }
+
+    if metadata.is_dir() {
+        std::fs::create_dir_all(to).with_context(|| format!("creating {}", to.display()))?;
+
+        for entry in std::fs::read_dir(from)? {
+            let entry = entry?;
+            copy_relocated(
+                &entry.path(),
+                &to.join(entry.file_name()),
+                relocation,
+                exclude,
+            )?;
+        }
+
+        return Ok(());
+    }
+
+    let data = std::fs::read(from).with_context(|| format!("reading {}", from.display()))?;
+    match relocation.file_content(from, &data)? {
+        Some(relocated) => {
+            std::fs::write(to, relocated).with_context(|| format!("writing {}", to.display()))?;
+            std::fs::set_permissions(to, metadata.permissions())
+                .with_context(|| format!("setting permissions of {}", to.display()))?;
+        }
+        None => {
+            copy_file(from, to)?;
+        }
+    }
+
+    filetime::set_file_mtime(
+        to,
+        filetime::FileTime::from_last_modification_time(&metadata),
+    )
+    .with_context(|| format!("setting times of {}", to.display()))?;
+
+    Ok(())
+}
+
+/// Map paths in the content of files of a directory tree in place.
+///
+/// Modification times are preserved.
+pub fn relocate_in_place(path: &Path, relocation: &Relocation) -> Result<()> {
+    let metadata = std::fs::symlink_metadata(path)
+        .with_context(|| format!("reading metadata of {}", path.display()))?;
+
+    if metadata.file_type().is_symlink() {
+        return Ok(());
+    }
+
+    if metadata.is_dir() {
+        for entry in std::fs::read_dir(path)? {
+            relocate_in_place(&entry?.path(), relocation)?;
+        }
+
+        return Ok(());
+    }
+
+    let data = std::fs::read(path).with_context(|| format!("reading {}", path.display()))?;
+    if let Some(relocated) = relocation.file_content(path, &data)? {
+        std::fs::write(path, relocated).with_context(|| format!("writing {}", path.display()))?;
+        filetime::set_file_mtime(
+            path,
+            filetime::FileTime::from_last_modification_time(&metadata),
+        )
+        .with_context(|| format!("setting times of {}", path.display()))?;
+    }
+
+    Ok(())
+}
+
+/// Write a prepared directory for a build whose artifacts were generated.
+///
+/// The artifacts must have been generated into the `artifacts` directory
+/// of `prepared_path`.
+pub fn write_prepared(
+    logger: &slog::Logger,
+    context: &BuildContext,
+    prepared_path: &Path,
+) -> Result<PreparedManifest> {
+    let config_path = context
+        .config_path
+        .strip_prefix(&context.project_path)
+        .map_err(|_| {
+            anyhow!(
+                "config file {} is outside project {}; prepared builds only include the project",
+                context.config_path.display(),
+                context.project_path.display()
+            )
+        })?;
+
+    let artifacts_path = context
+        .pyoxidizer_artifacts_path
+        .strip_prefix(prepared_path)
+        .with_context(|| {
+            format!(
+                "artifacts {} aren't in the prepared directory",
+                context.pyoxidizer_artifacts_path.display()
+            )
+        })?;
+
+    let placeholder = |rel: &str| format!("{}/{}", PREPARED_DIR_PLACEHOLDER, rel);
+    let mut relocation = Relocation::new()
+        .map(
+            &prepared_path.display().to_string(),
+            PREPARED_DIR_PLACEHOLDER,
+        )
+        .map(
+            &context.project_path.display().to_string(),
+            &placeholder(PREPARED_PROJECT_DIR),
+        );
+
+    let python_distribution_path =
+        match context.python_distribution_path.strip_prefix(prepared_path) {
+            Ok(rel) => rel.to_path_buf(),
+            Err(_) => {
+                relocation = relocation.map(
+                    &context.python_distribution_path.display().to_string(),
+                    &placeholder(PREPARED_DISTRIBUTION_DIR),
+                );
+
+                PathBuf::from(PREPARED_DISTRIBUTION_DIR)
+            }
+        };
+
+    warn!(
+        logger,
+        "copying project {} to {}",
+        context.project_path.display(),
+        prepared_path.join(PREPARED_PROJECT_DIR).display()
+    );
+    copy_relocated(
+        &context.project_path,
+        &prepared_path.join(PREPARED_PROJECT_DIR),
+        &relocation,
+        &[
+            context.build_path.clone(),
+            context.project_path.join("target"),
+            prepared_path.to_path_buf(),
+        ],
+    )?;
+
+    if !context.python_distribution_path.starts_with(prepared_path) {
+        warn!(
+            logger,
+            "copying Python distribution {} to {}",
+            context.python_distribution_path.display(),
+            prepared_path.join(PREPARED_DISTRIBUTION_DIR).display()
+        );
+        copy_relocated(
+            &context.python_distribution_path,
+            &prepared_path.join(PREPARED_DISTRIBUTION_DIR),
+            &relocation,
+            &[],
+        )?;
+    }
+
+    relocate_in_place(&context.pyoxidizer_artifacts_path, &relocation)?;
+
+    let config = serde_json::to_string_pretty(&context.config)?;
+    let config_path_json = prepared_path.join(PREPARED_CONFIG_FILENAME);
+    std::fs::write(&config_path_json, relocation.json(&config))
+        .with_context(|| format!("writing {}", config_path_json.display()))?;
+
+    let manifest = PreparedManifest {
+        format_version: FORMAT_VERSION,
+        pyoxidizer_version: BUILD_SEMVER.to_string(),
+        target_triple: context.target_triple.clone(),
+        host_triple: context.host_triple.clone(),
+        release: context.release,
+        app_name: context.app_name.clone(),
+        project_path: PREPARED_PROJECT_DIR.to_string(),
+        config_path: manifest_path_string(&Path::new(PREPARED_PROJECT_DIR).join(config_path)),
+        artifacts_path: manifest_path_string(artifacts_path),
+        python_distribution_path: manifest_path_string(&python_distribution_path),
+    };
+    manifest.write(prepared_path)?;
+
+    Ok(manifest)
+}
+
+/// A prepared build copied to where it is compiled.
+pub struct MaterializedBuild {
+    pub project_path: PathBuf,
+    pub config: Config,
+    pub artifacts_path: PathBuf,
+    pub python_distribution_path: PathBuf,
+}
+
+/// Copy a prepared directory into a target directory to compile it.
+///
+/// The placeholder in files is replaced with the path of the copy. Builds
+/// are written to `target_dir`. A copy of a previous compile is replaced.
+pub fn materialize_prepared(
+    logger: &slog::Logger,
+    prepared_path: &Path,
+    manifest: &PreparedManifest,
+    target_dir: &Path,
+) -> Result<MaterializedBuild> {
+    let dest = target_dir.join(COMPILE_PREPARED_DIR);
+
+    if dest.exists() {
+        remove_dir_all(&dest)?;
+    }
+
+    warn!(
+        logger,
+        "copying prepared build {} to {}",
+        prepared_path.display(),
+        dest.display()
+    );
+    let relocation = Relocation::new().map(PREPARED_DIR_PLACEHOLDER, &dest.display().to_string());
+    copy_relocated(prepared_path, &dest, &relocation, &[])?;
+
+    let config_path = dest.join(PREPARED_CONFIG_FILENAME);
+    let data = std::fs::read(&config_path)
+        .with_context(|| format!("reading {}", config_path.display()))?;
+    let mut config: Config = serde_json::from_slice(&data)
+        .with_context(|| format!("parsing {}", config_path.display()))?;
+    config.build_config.build_path = target_dir.to_path_buf();
+
+    Ok(MaterializedBuild {
+        project_path: resolve_manifest_path(&dest, &manifest.project_path)?,
+        config,
+        artifacts_path: resolve_manifest_path(&dest, &manifest.artifacts_path)?,
+        python_distribution_path: resolve_manifest_path(&dest, &manifest.python_distribution_path)?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn manifest() -> PreparedManifest {
+        PreparedManifest {
+            format_version: FORMAT_VERSION,
+            pyoxidizer_version: "0.5.0".to_string(),
+            target_triple: "x86_64-unknown-linux-gnu".to_string(),
+            host_triple: "x86_64-unknown-linux-gnu".to_string(),
+            release: false,
+            app_name: "myapp".to_string(),
+            project_path: "project".to_string(),
+            config_path: "project/pyoxidizer.bzl".to_string(),
+            artifacts_path: "artifacts".to_string(),
+            python_distribution_path: "distribution".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_relocation_text() {
+        // The prepared directory is inside the project.
+        let relocation = Relocation::new()
+            .map("/src/myapp", "@PYOXIDIZER_PREPARED_DIR@/project")
+            .map("/src/myapp/prepared", PREPARED_DIR_PLACEHOLDER)
+            .map("/cache/dist", "@PYOXIDIZER_PREPARED_DIR@/distribution");
+
+        assert_eq!(
+            relocation.text(
+                "cargo:rustc-link-search=native=/src/myapp/prepared/artifacts\n\
+                 cargo:rerun-if-changed=/src/myapp/pyoxidizer.bzl\n\
+                 cargo:rerun-if-changed=/src/myapp2/pyoxidizer.bzl\n\
+                 cargo:rerun-if-changed=/cache/dist/python/install\n"
+            ),
+            "cargo:rustc-link-search=native=@PYOXIDIZER_PREPARED_DIR@/artifacts\n\
+             cargo:rerun-if-changed=@PYOXIDIZER_PREPARED_DIR@/project/pyoxidizer.bzl\n\
+             cargo:rerun-if-changed=/src/myapp2/pyoxidizer.bzl\n\
+             cargo:rerun-if-changed=@PYOXIDIZER_PREPARED_DIR@/distribution/python/install\n"
+        );
+
+        let relocation = Relocation::new().map(PREPARED_DIR_PLACEHOLDER, r"C:\build\prepared");
+        assert_eq!(
+            relocation.json(r#"{"path": "@PYOXIDIZER_PREPARED_DIR@/artifacts"}"#),
+            r#"{"path": "C:\\build\\prepared/artifacts"}"#
+        );
+    }
+
+    #[test]
+    fn test_copy_relocated() -> Result<()> {
+        let temp_dir = tempdir::TempDir::new("pyoxidizer-test")?;
+        let from = temp_dir.path().join("from");
+        let to = temp_dir.path().join("to");
+        std::fs::create_dir_all(from.join("target"))?;
+        std::fs::write(from.join("target").join("app"), "built")?;
+        std::fs::write(
+            from.join("cargo_metadata.txt"),
+            format!("cargo:rerun-if-changed={}/main.rs\n", from.display()),
+        )?;
+        std::fs::write(from.join("libpython.a"), b"\xff\x00binary")?;
+
+        let relocation =
+            Relocation::new().map(&from.display().to_string(), PREPARED_DIR_PLACEHOLDER);
+        copy_relocated(&from, &to, &relocation, &[from.join("target")])?;
+
+        assert!(!to.join("target").exists());
+        assert_eq!(
+            std::fs::read_to_string(to.join("cargo_metadata.txt"))?,
+            "cargo:rerun-if-changed=@PYOXIDIZER_PREPARED_DIR@/main.rs\n"
+        );
+        assert_eq!(std::fs::read(to.join("libpython.a"))?, b"\xff\x00binary");
+        assert_eq!(
+            std::fs::metadata(to.join("cargo_metadata.txt"))?.modified()?,
+            std::fs::metadata(from.join("cargo_metadata.txt"))?.modified()?
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_compatible() {
+        let manifest = manifest();
+
+        assert!(manifest
+            .check_compatible("x86_64-unknown-linux-gnu", "0.5.0")
+            .is_ok());
+
+        let err = manifest
+            .check_compatible("aarch64-unknown-linux-gnu", "0.5.0")
+            .unwrap_err()
+            .to_string();
+        assert_eq!(
+            err,
+            "build was prepared for x86_64-unknown-linux-gnu, not aarch64-unknown-linux-gnu; pass --target x86_64-unknown-linux-gnu to compile it"
+        );
+
+        let err = manifest
+            .check_compatible("x86_64-unknown-linux-gnu", "0.6.0")
+            .unwrap_err()
+            .to_string();
+        assert!(err.starts_with("build was prepared by PyOxidizer 0.5.0"));
+    }
+
+    #[test]
+    fn test_manifest_paths() -> Result<()> {
+        let prepared = Path::new("/prepared");
+
+        assert_eq!(
+            resolve_manifest_path(prepared, "artifacts/pyoxidizer-0.5.0")?,
+            prepared.join("artifacts").join("pyoxidizer-0.5.0")
+        );
+        assert!(resolve_manifest_path(prepared, "../outside").is_err());
+        assert!(resolve_manifest_path(prepared, "/outside").is_err());
+
+        Ok(())
+    }
+}
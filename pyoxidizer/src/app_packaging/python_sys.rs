@@ -0,0 +1,123 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/*!
+Choosing the `PYTHON_SYS_EXECUTABLE` cargo builds with.
+
+`python3-sys` runs the Python in `PYTHON_SYS_EXECUTABLE` to configure
+itself, e.g. to find the Python version and the library to link. Builds
+set it to the executable of the distribution being embedded, so the crate
+is configured for the Python it is linked with.
+
+A value already set in the environment, e.g. by a CI system or a shell
+profile, is likely a leftover for another project. So builds refuse to
+proceed rather than silently replacing or using it. Passing
+`--force-python-sys-executable` uses it anyway.
+*/
+
+use anyhow::{anyhow, Result};
+use slog::{info, warn};
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Environment variable `python3-sys` finds Python with.
+pub const PYTHON_SYS_EXECUTABLE_ENV: &str = "PYTHON_SYS_EXECUTABLE";
+
+/// Whether `--force-python-sys-executable` was passed.
+static FORCED: AtomicBool = AtomicBool::new(false);
+
+/// Use a `PYTHON_SYS_EXECUTABLE` set in the environment.
+pub fn force_python_sys_executable() {
+    FORCED.store(true, Ordering::SeqCst);
+}
+
+/// Decide the `PYTHON_SYS_EXECUTABLE` to build with.
+///
+/// `python_exe` is the executable of the embedded distribution. `preset`
+/// is the value found in the environment.
+pub fn resolve_python_sys_executable(
+    python_exe: &Path,
+    preset: Option<OsString>,
+    forced: bool,
+) -> Result<PathBuf> {
+    match preset {
+        None => Ok(python_exe.to_path_buf()),
+        Some(value) if Path::new(&value) == python_exe => Ok(python_exe.to_path_buf()),
+        Some(value) if forced => Ok(PathBuf::from(value)),
+        Some(value) => Err(anyhow!(
+            "{} is set to {} in the environment but builds configure Rust crates with the embedded Python distribution's {}; unset it or pass --force-python-sys-executable to use it anyway",
+            PYTHON_SYS_EXECUTABLE_ENV,
+            PathBuf::from(value).display(),
+            python_exe.display()
+        )),
+    }
+}
+
+/// Obtain the `PYTHON_SYS_EXECUTABLE` for a build.
+///
+/// Using a value from the environment is logged as a warning, so it never
+/// goes unnoticed.
+pub fn python_sys_executable_for_build(
+    logger: &slog::Logger,
+    python_exe: &Path,
+) -> Result<PathBuf> {
+    let forced = FORCED.load(Ordering::SeqCst);
+    let path = resolve_python_sys_executable(
+        python_exe,
+        std::env::var_os(PYTHON_SYS_EXECUTABLE_ENV),
+        forced,
+    )?;
+
+    if path == python_exe {
+        info!(
+            logger,
+            "setting {} to {}",
+            PYTHON_SYS_EXECUTABLE_ENV,
+            path.display()
+        );
+    } else {
+        warn!(
+            logger,
+            "using {}={} from the environment as forced; the embedded Python distribution's {} is bypassed and Rust crates may be configured for a different Python",
+            PYTHON_SYS_EXECUTABLE_ENV,
+            path.display(),
+            python_exe.display()
+        );
+    }
+
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve() {
+        let python_exe = Path::new("/dist/python/install/bin/python3");
+        let other = || Some(OsString::from("/usr/bin/python3"));
+
+        assert_eq!(
+            resolve_python_sys_executable(python_exe, None, false).unwrap(),
+            python_exe
+        );
+        assert_eq!(
+            resolve_python_sys_executable(python_exe, Some(python_exe.into()), false).unwrap(),
+            python_exe
+        );
+        assert_eq!(
+            resolve_python_sys_executable(python_exe, other(), true).unwrap(),
+            Path::new("/usr/bin/python3")
+        );
+
+        let err = resolve_python_sys_executable(python_exe, other(), false)
+            .unwrap_err()
+            .to_string();
+        assert!(
+            err.starts_with("PYTHON_SYS_EXECUTABLE is set to /usr/bin/python3 in the environment")
+        );
+        assert!(err.contains("--force-python-sys-executable"));
+    }
+}
@@ -11,33 +11,83 @@ use std::fs::create_dir_all;
 use std::io::{BufWriter, Write};
 use std::path::{Path, PathBuf};
 
-use super::config::{eval_starlark_config_file, find_pyoxidizer_config_file_env, Config};
-use super::state::{BuildContext, PackagingState};
-use crate::py_packaging::bytecode::{python_source_encoding, BytecodeCompiler, CompileMode};
+use super::artifacts_owner::{artifacts_version, claim_artifacts_path, lock_artifacts_path};
+use super::bin_target::{bin_target_names, resolve_application_name, AUTO_APPLICATION_NAME};
+use super::build_environment::BuildEnvironment;
+use super::config::{
+    eval_starlark_config_file, find_pyoxidizer_config_file_env, Config, SplitDebugInfo,
+};
+use super::debuginfo::{split_debug_info, DEBUG_INFO_DIR};
+use super::environment::EvalOptions;
+use super::library::{
+    header_filename, is_cdylib, library_header, library_name, shared_library_filename,
+};
+use super::lockfile::input_digests;
+use super::manifest::{write_build_manifest, BUILD_MANIFEST_FILENAME};
+use super::notices::{collect_notice_components, write_notices, NOTICES_FILENAME};
+use super::output_layout::{
+    expand_output_layout, prepare_output_directory, record_output_directory, resolve_output_layout,
+    OutputOwner,
+};
+use super::packaging_script::run_packaging_script;
+use super::signing::sign_configured;
+use super::staleness::{
+    artifact_inputs, collapse_rerun_if_changed, InputsManifest, INPUTS_MANIFEST_FILENAME,
+};
+use super::state::{BuildContext, ExtraCargoArgs, PackagingState, Verbosity};
+use super::toolchain::resolve_toolchain;
+use crate::build_events::{self, BuildEvent};
+use crate::build_stats;
+use crate::cancel;
+use crate::color;
+use crate::exit_status::{Categorize, ErrorCategory};
+use crate::fsutils::{copy_file, create_file, write_file, StagedDir};
+use crate::licensing::LicenseFilterDecision;
+use crate::py_packaging::bytecode::{decode_python_source, BytecodeCompiler, CompileMode};
+use crate::py_packaging::bytecode_cache::{
+    BytecodeCache, CachingBytecodeCompiler, DEFAULT_MAX_CACHE_SIZE,
+};
+use crate::py_packaging::capabilities::{
+    check_capabilities, required_capabilities, DistributionCapabilities,
+};
 use crate::py_packaging::distribution::{
-    resolve_python_distribution_archive, ExtensionModule, ParsedPythonDistribution,
-    PythonDistributionLocation,
+    mark_distribution_used, parse_cached_distribution, shared_distribution_dirs, ExtensionModule,
+    ParsedPythonDistribution, PythonDistributionLocation,
 };
 use crate::py_packaging::embedded_resource::{EmbeddedPythonResources, OS_IGNORE_EXTENSIONS};
-use crate::py_packaging::libpython::{derive_importlib, link_libpython};
-use crate::py_packaging::pyembed::{derive_python_config, write_data_rs};
+use crate::py_packaging::libpython::{
+    derive_importlib, libpython_license_infos, link_libpython, LibpythonPaths,
+};
+use crate::py_packaging::pyembed::{derive_python_config, write_data_rs, AppIdentifiers};
+use crate::py_packaging::references::{
+    check_references, entry_point_references, executable_references, multicall_references,
+};
 use crate::py_packaging::resource::{
     packages_from_module_name, packages_from_module_names, AppRelativeResources,
     BuiltExtensionModule, BytecodeOptimizationLevel, PackagedModuleBytecode, PackagedModuleSource,
 };
+use crate::py_packaging::sqlite::check_sqlite_options;
 
 pub const HOST: &str = env!("HOST");
 
+fn profile_name(release: bool) -> &'static str {
+    if release {
+        "release"
+    } else {
+        "debug"
+    }
+}
+
 impl BuildContext {
     #[allow(clippy::too_many_arguments)]
     pub fn new(
         project_path: &Path,
-        config: Config,
+        mut config: Config,
         host: Option<&str>,
         target: &str,
         release: bool,
         force_artifacts_path: Option<&Path>,
-        verbose: bool,
+        verbosity: Verbosity,
     ) -> Result<Self> {
         let config_parent_path = config
             .config_path
@@ -55,8 +105,6 @@ impl BuildContext {
         // Build Rust artifacts into build path, not wherever Rust chooses.
         let target_base_path = build_path.join("target");
 
-        let apps_base_path = build_path.join("apps");
-
         // This assumes we invoke as `cargo build --target`, otherwise we don't get the
         // target triple in the directory path unless cross compiling.
         let target_triple_base_path =
@@ -64,9 +112,36 @@ impl BuildContext {
                 .join(target)
                 .join(if release { "release" } else { "debug" });
 
-        let app_name = config.build_config.application_name.clone();
+        let cargo_toml_path = project_path.join("Cargo.toml");
+        if !cargo_toml_path.exists() {
+            return Err(anyhow!("{} does not exist", cargo_toml_path.display()));
+        }
+
+        let cargo_toml_data = fs::read(&cargo_toml_path)?;
+        let cargo_config = cargo_toml::Manifest::from_slice(&cargo_toml_data)?;
+
+        let library = is_cdylib(&cargo_config);
 
-        let exe_name = if target.contains("pc-windows") {
+        let app_name = if library {
+            if config.build_config.application_name == AUTO_APPLICATION_NAME {
+                library_name(&cargo_config)?
+            } else {
+                config.build_config.application_name.clone()
+            }
+        } else {
+            resolve_application_name(
+                &config.build_config.application_name,
+                &bin_target_names(&cargo_config, project_path),
+                &config.config_path,
+                &cargo_toml_path,
+            )
+            .category(ErrorCategory::Config)?
+        };
+        config.build_config.application_name = app_name.clone();
+
+        let exe_name = if library {
+            shared_library_filename(target, &library_name(&cargo_config)?)
+        } else if target.contains("pc-windows") {
             format!("{}.exe", &app_name)
         } else {
             app_name.clone()
@@ -74,12 +149,27 @@ impl BuildContext {
 
         let app_target_path = target_triple_base_path.join(&app_name);
 
-        let app_path = apps_base_path
-            .join(&app_name)
-            .join(target)
-            .join(if release { "release" } else { "debug" });
+        let output_layout = resolve_output_layout(
+            config
+                .build_config
+                .output_layout
+                .as_ref()
+                .map(String::as_str),
+        );
+        let app_path = build_path.join(
+            expand_output_layout(
+                &output_layout,
+                &OutputOwner {
+                    app: app_name.clone(),
+                    triple: target.to_string(),
+                    profile: profile_name(release).to_string(),
+                },
+            )
+            .category(ErrorCategory::Config)?,
+        );
         let app_exe_target_path = target_triple_base_path.join(&exe_name);
         let app_exe_path = app_path.join(&exe_name);
+        let third_party_notices = config.build_config.third_party_notices;
 
         // Artifacts path is:
         // 1. force_artifacts_path (if defined)
@@ -91,24 +181,23 @@ impl BuildContext {
 
         let distributions_path = build_path.join("distribution");
 
-        let distribution_hash = match &config.python_distribution {
-            PythonDistributionLocation::Local { sha256, .. } => sha256,
-            PythonDistributionLocation::Url { sha256, .. } => sha256,
-        };
-
-        // Take the prefix so paths are shorter.
-        let distribution_hash = &distribution_hash[0..12];
+        // Projects share distributions extracted in the distribution cache.
+        let python_distribution_path = match shared_distribution_dirs(&config.python_distribution) {
+            Some((_, extract_dir)) => extract_dir,
+            None => {
+                // Take the prefix so paths are shorter.
+                let distribution_hash = &config.python_distribution.sha256()[0..12];
 
-        let python_distribution_path =
-            pyoxidizer_artifacts_path.join(format!("python.{}", distribution_hash));
+                pyoxidizer_artifacts_path.join(format!("python.{}", distribution_hash))
+            }
+        };
 
-        let cargo_toml_path = project_path.join("Cargo.toml");
-        if !cargo_toml_path.exists() {
-            return Err(anyhow!("{} does not exist", cargo_toml_path.display()));
-        }
+        let env_rustflags = env::var("RUSTFLAGS").ok();
+        let (rustflags, _) = config
+            .build_config
+            .resolve_rustflags(env_rustflags.as_ref().map(String::as_str), release);
 
-        let cargo_toml_data = fs::read(&cargo_toml_path)?;
-        let cargo_config = cargo_toml::Manifest::from_slice(&cargo_toml_data)?;
+        let toolchain = resolve_toolchain(config.build_config.rust_toolchain.as_ref());
 
         Ok(BuildContext {
             project_path: project_path.to_path_buf(),
@@ -116,32 +205,84 @@ impl BuildContext {
             config_parent_path: config_parent_path.to_path_buf(),
             config,
             cargo_config,
-            verbose,
+            library,
+            verbosity,
+            // Cargo writes its messages to our stderr.
+            cargo_color: color::child_color(false, color::stderr_is_terminal()),
+            extra_cargo_args: ExtraCargoArgs::default(),
             build_path,
             app_name,
             app_path,
+            output_layout,
+            third_party_notices,
             app_exe_path,
             distributions_path,
             host_triple,
             target_triple: target.to_string(),
             release,
+            rustflags,
+            rustc_bootstrap: false,
+            toolchain,
+            deny_generated_warnings: false,
+            force_artifacts: false,
+            force_cargo: false,
+            skip_space_check: false,
+            offline: crate::environment::offline(),
+            build_vars: super::build_vars::build_vars(),
             target_base_path,
             target_triple_base_path,
             app_target_path,
             app_exe_target_path,
+            pyoxidizer_artifacts_root_path: pyoxidizer_artifacts_path.clone(),
             pyoxidizer_artifacts_path,
             python_distribution_path,
             packaging_state: None,
         })
     }
 
+    /// Options the config of this build is evaluated with.
+    pub fn eval_options(&self) -> EvalOptions {
+        EvalOptions {
+            offline: self.offline,
+            build_vars: self.build_vars.clone(),
+        }
+    }
+
+    /// Use another directory for PyOxidizer build artifacts.
+    ///
+    /// The extracted Python distribution moves along unless it is shared.
+    pub fn set_pyoxidizer_artifacts_path(&mut self, path: &Path) {
+        if let Ok(rel) = self
+            .python_distribution_path
+            .strip_prefix(&self.pyoxidizer_artifacts_path)
+        {
+            self.python_distribution_path = path.join(rel);
+        }
+
+        self.pyoxidizer_artifacts_path = path.to_path_buf();
+    }
+
+    /// The build profile, `debug` or `release`.
+    pub fn profile(&self) -> &'static str {
+        profile_name(self.release)
+    }
+
+    /// Identifies the application in its output directory.
+    pub fn output_owner(&self) -> OutputOwner {
+        OutputOwner {
+            app: self.app_name.clone(),
+            triple: self.target_triple.clone(),
+            profile: self.profile().to_string(),
+        }
+    }
+
     /// Obtain the PackagingState instance for this configuration.
     ///
     /// This basically reads the packaging_state.cbor file from the artifacts
     /// directory.
     pub fn get_packaging_state(&mut self) -> Result<PackagingState> {
         if self.packaging_state.is_none() {
-            let path = self.pyoxidizer_artifacts_path.join("packaging_state.cbor");
+            let path = ArtifactPaths::new(self).packaging_state;
             let fh = std::io::BufReader::new(std::fs::File::open(&path)?);
 
             let state: PackagingState = serde_cbor::from_reader(fh)?;
@@ -181,9 +322,9 @@ struct BytecodeRequest {
 #[allow(clippy::cognitive_complexity)]
 pub fn resolve_python_resources(
     logger: &slog::Logger,
-    _context: &BuildContext,
+    context: &BuildContext,
     dist: &ParsedPythonDistribution,
-) -> PythonResources {
+) -> Result<PythonResources> {
     // Since bytecode has a non-trivial cost to generate, our strategy is to accumulate
     // requests for bytecode then generate bytecode for the final set of inputs at the
     // end of processing. That way we don't generate bytecode only to throw it away later.
@@ -254,16 +395,9 @@ pub fn resolve_python_resources(
         // We can't just look for b"__file__ because the source file may be in
         // encodings like UTF-16. So we need to decode to Unicode first then look for
         // the code points.
-        let encoding = python_source_encoding(&request.source);
-
-        let encoder = match encoding_rs::Encoding::for_label(&encoding) {
-            Some(encoder) => encoder,
-            None => encoding_rs::UTF_8,
-        };
-
-        let (source, ..) = encoder.decode(&request.source);
+        let source = decode_python_source(&request.source, name)?;
 
-        if source.contains("__file__") {
+        if source.map_or(false, |source| source.contains("__file__")) {
             warn!(logger, "warning: {} contains __file__", name);
             file_seen = true;
         }
@@ -276,18 +410,21 @@ pub fn resolve_python_resources(
     let mut embedded_bytecodes: BTreeMap<String, PackagedModuleBytecode> = BTreeMap::new();
 
     {
-        let mut compiler = BytecodeCompiler::new(&dist.python_exe).unwrap();
+        let mut compiler = CachingBytecodeCompiler::new(
+            &dist.python_exe,
+            BytecodeCache::default_cache(),
+            context.config.python_distribution.sha256(),
+        )?;
 
         for (name, request) in embedded_bytecode_requests {
-            let bytecode = match compiler.compile(
-                &request.source,
-                &name,
-                BytecodeOptimizationLevel::from(request.optimize_level),
-                CompileMode::Bytecode,
-            ) {
-                Ok(res) => res,
-                Err(msg) => panic!("error compiling bytecode for {}: {}", name, msg),
-            };
+            let bytecode = compiler
+                .compile(
+                    &request.source,
+                    &name,
+                    BytecodeOptimizationLevel::from(request.optimize_level),
+                    CompileMode::Bytecode,
+                )
+                .with_context(|| format!("compiling bytecode for {}", name))?;
 
             embedded_bytecodes.insert(
                 name.clone(),
@@ -297,12 +434,8 @@ pub fn resolve_python_resources(
                 },
             );
         }
-    }
-
-    // Compile app-relative bytecode requests.
-    {
-        let mut compiler = BytecodeCompiler::new(&dist.python_exe).unwrap();
 
+        // Compile app-relative bytecode requests.
         for (path, requests) in app_relative_bytecode_requests {
             if !app_relative.contains_key(&path) {
                 app_relative.insert(path.clone(), AppRelativeResources::default());
@@ -311,18 +444,17 @@ pub fn resolve_python_resources(
             let app_relative = app_relative.get_mut(&path).unwrap();
 
             for (name, request) in requests {
-                let bytecode = match compiler.compile(
-                    &request.source,
-                    &name,
-                    BytecodeOptimizationLevel::from(request.optimize_level),
-                    // Bytecode in app-relative directories should never be mutated. So we
-                    // shouldn't need to verify its hash at run-time.
-                    // TODO consider making this configurable.
-                    CompileMode::PycUncheckedHash,
-                ) {
-                    Ok(res) => res,
-                    Err(msg) => panic!("error compiling bytecode for {}: {}", name, msg),
-                };
+                let bytecode = compiler
+                    .compile(
+                        &request.source,
+                        &name,
+                        BytecodeOptimizationLevel::from(request.optimize_level),
+                        // Bytecode in app-relative directories should never be mutated. So we
+                        // shouldn't need to verify its hash at run-time.
+                        // TODO consider making this configurable.
+                        CompileMode::PycUncheckedHash,
+                    )
+                    .with_context(|| format!("compiling bytecode for {}", name))?;
 
                 app_relative.module_bytecodes.insert(
                     name.clone(),
@@ -333,6 +465,27 @@ pub fn resolve_python_resources(
                 );
             }
         }
+
+        build_stats::record_cache(
+            build_stats::Cache::Bytecode,
+            compiler.hits as u64,
+            compiler.misses as u64,
+        );
+
+        if compiler.hits > 0 {
+            warn!(
+                logger,
+                "reused cached bytecode for {} of {} modules",
+                compiler.hits,
+                compiler.hits + compiler.misses
+            );
+        }
+    }
+
+    if let Some(cache) = BytecodeCache::default_cache() {
+        if let Err(e) = cache.prune(DEFAULT_MAX_CACHE_SIZE) {
+            warn!(logger, "unable to prune bytecode cache: {}", e);
+        }
     }
 
     let mut all_embedded_modules = BTreeSet::new();
@@ -393,7 +546,7 @@ pub fn resolve_python_resources(
         })
         .collect();
 
-    PythonResources {
+    Ok(PythonResources {
         embedded: EmbeddedPythonResources {
             module_sources: embedded_sources,
             module_bytecodes: embedded_bytecodes,
@@ -402,11 +555,12 @@ pub fn resolve_python_resources(
             resources: embedded_resources,
             extension_modules: embedded_extension_modules,
             built_extension_modules: embedded_built_extension_modules,
+            lazy_index: false,
         },
         app_relative,
         read_files,
         license_files_path,
-    }
+    })
 }
 
 /// Install all app-relative files next to the generated binary.
@@ -452,7 +606,7 @@ fn install_app_relative(
 
         let parent_dir = module_path.parent().unwrap();
         create_dir_all(&parent_dir)?;
-        fs::write(&module_path, &module_source.source)?;
+        write_file(&module_path, &module_source.source)?;
     }
 
     warn!(
@@ -500,7 +654,7 @@ fn install_app_relative(
         let parent_dir = module_path.parent().unwrap();
         create_dir_all(&parent_dir)?;
 
-        fs::write(&module_path, &module_bytecode.bytecode)?;
+        write_file(&module_path, &module_bytecode.bytecode)?;
     }
 
     let mut resource_count = 0;
@@ -543,29 +697,242 @@ fn install_app_relative(
 
             create_dir_all(dest_path.parent().unwrap())?;
 
-            fs::write(&dest_path, data)?;
+            write_file(&dest_path, data)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Obtain the extra names an executable with multicall run modes is invoked as.
+///
+/// On Windows targets these have an `.exe` extension. Names matching the
+/// executable are skipped.
+pub fn multicall_link_paths<'a>(
+    exe_path: &Path,
+    names: impl Iterator<Item = &'a String>,
+    windows: bool,
+) -> Result<Vec<PathBuf>> {
+    let dir = exe_path
+        .parent()
+        .ok_or_else(|| anyhow!("could not find parent directory of executable"))?;
+    let exe_name = exe_path
+        .file_name()
+        .ok_or_else(|| anyhow!("could not resolve executable filename"))?;
+
+    let mut paths = Vec::new();
+
+    for name in names {
+        let filename = if windows {
+            format!("{}.exe", name)
+        } else {
+            name.clone()
+        };
+
+        let matches_exe = if windows {
+            exe_name.to_string_lossy().to_lowercase() == filename.to_lowercase()
+        } else {
+            exe_name.to_string_lossy() == filename.as_str()
+        };
+        if matches_exe {
+            continue;
+        }
+
+        paths.push(dir.join(&filename));
+    }
+
+    Ok(paths)
+}
+
+/// Create the extra names an executable with multicall run modes is invoked as.
+///
+/// On Windows targets these are copies of the executable. Elsewhere they
+/// are symlinks relative to the executable's directory, so they survive the
+/// directory being moved or archived.
+///
+/// Returns the paths of created files.
+pub fn create_multicall_links<'a>(
+    exe_path: &Path,
+    names: impl Iterator<Item = &'a String>,
+    windows: bool,
+) -> Result<Vec<PathBuf>> {
+    let exe_name = exe_path
+        .file_name()
+        .ok_or_else(|| anyhow!("could not resolve executable filename"))?;
+
+    let paths = multicall_link_paths(exe_path, names, windows)?;
+
+    for path in &paths {
+        if fs::symlink_metadata(path).is_ok() {
+            fs::remove_file(path).with_context(|| format!("removing {}", path.display()))?;
+        }
+
+        if windows {
+            copy_file(exe_path, path)?;
+        } else {
+            symlink_or_copy(Path::new(exe_name), exe_path, path)?;
         }
     }
 
+    Ok(paths)
+}
+
+#[cfg(unix)]
+fn symlink_or_copy(target: &Path, _source: &Path, path: &Path) -> Result<()> {
+    std::os::unix::fs::symlink(target, path)
+        .with_context(|| format!("creating symlink {}", path.display()))
+}
+
+#[cfg(not(unix))]
+fn symlink_or_copy(_target: &Path, source: &Path, path: &Path) -> Result<()> {
+    copy_file(source, path)?;
     Ok(())
 }
 
+/// A license file packaging writes.
+#[derive(Clone, Debug)]
+pub struct LicenseFile {
+    /// Component the license is of.
+    pub name: String,
+    pub path: PathBuf,
+    pub text: String,
+}
+
+/// Paths of the files packaging writes to an application directory.
+///
+/// `package_project()` writes to these paths and `pyoxidizer build --plan`
+/// lists them.
+#[derive(Clone, Debug)]
+pub struct AppPaths {
+    /// The executable or library.
+    pub exe: PathBuf,
+    /// Directory receiving split debug info.
+    pub debug_info_dir: Option<PathBuf>,
+    /// C header of a library.
+    pub header: Option<PathBuf>,
+    /// Names the executable is invoked as for multicall run modes.
+    pub multicall_links: Vec<PathBuf>,
+    pub licenses: Vec<LicenseFile>,
+    pub notices: Option<PathBuf>,
+    pub manifest: PathBuf,
+}
+
+impl AppPaths {
+    /// Resolve the paths of an application assembled in `app_path`.
+    ///
+    /// License files are only known if `state` is given.
+    pub fn new(
+        context: &BuildContext,
+        app_path: &Path,
+        state: Option<&PackagingState>,
+    ) -> Result<AppPaths> {
+        let exe = app_path.join(context.app_exe_path.strip_prefix(&context.app_path)?);
+
+        let debug_info_dir = if context.release {
+            match context.config.build_config.split_debug_info {
+                SplitDebugInfo::Disabled => None,
+                SplitDebugInfo::Adjacent => Some(app_path.to_path_buf()),
+                SplitDebugInfo::Directory => Some(app_path.join(DEBUG_INFO_DIR)),
+            }
+        } else {
+            None
+        };
+
+        let header = if context.library {
+            if !context.config.multicall_run_modes.is_empty() {
+                return Err(anyhow!(
+                    "multicall_run_modes is not supported by projects building a library"
+                ));
+            }
+
+            let name = library_name(&context.cargo_config)?;
+            Some(app_path.join(header_filename(&name)))
+        } else {
+            None
+        };
+
+        let multicall_links = multicall_link_paths(
+            &exe,
+            context.config.multicall_run_modes.keys(),
+            context.target_triple.contains("pc-windows"),
+        )?;
+
+        let mut licenses = Vec::new();
+        if let Some(state) = state {
+            if let Some(licenses_path) = &state.license_files_path {
+                let licenses_path = if licenses_path.is_empty() {
+                    app_path.to_path_buf()
+                } else {
+                    app_path.join(licenses_path)
+                };
+
+                for (name, lis) in &state.license_infos {
+                    for li in lis {
+                        licenses.push(LicenseFile {
+                            name: name.clone(),
+                            path: licenses_path.join(&li.license_filename),
+                            text: li.license_text.clone(),
+                        });
+                    }
+                }
+            }
+        }
+
+        let notices = if context.third_party_notices {
+            Some(app_path.join(NOTICES_FILENAME))
+        } else {
+            None
+        };
+
+        Ok(AppPaths {
+            exe,
+            debug_info_dir,
+            header,
+            multicall_links,
+            licenses,
+            notices,
+            manifest: app_path.join(BUILD_MANIFEST_FILENAME),
+        })
+    }
+}
+
 /// Package a built Rust project into its packaging directory.
 ///
 /// This will delete all content in the application's package directory.
+///
+/// The application is assembled in a staging directory which replaces the
+/// package directory once complete. So a failed or cancelled packaging
+/// leaves no partially packaged application behind.
 pub fn package_project(logger: &slog::Logger, context: &mut BuildContext) -> Result<()> {
+    let _phase = build_stats::phase("package");
+
     warn!(
         logger,
         "packaging application into {}",
         context.app_path.display()
     );
 
-    if context.app_path.exists() {
-        warn!(logger, "purging {}", context.app_path.display());
-        std::fs::remove_dir_all(&context.app_path)?;
-    }
+    let owner = context.output_owner();
+    prepare_output_directory(logger, &context.build_path, &context.app_path, &owner)
+        .category(ErrorCategory::Config)?;
+
+    warn!(logger, "resolving packaging state...");
+    let state = context.get_packaging_state()?;
 
-    create_dir_all(&context.app_path)?;
+    let staged = StagedDir::new(&context.app_path)?;
+    let app_path = staged.path().to_path_buf();
+    let paths = AppPaths::new(context, &app_path, Some(&state))?;
+    let app_exe_path = paths.exe.clone();
+
+    // Steps are reported with the paths files have once packaging completes.
+    let final_app_path = context.app_path.clone();
+    let staged_app_path = app_path.clone();
+    let packaging_step = |step: &str, path: &Path| {
+        build_events::emit(BuildEvent::PackagingStep {
+            step: step.to_string(),
+            path: final_app_path.join(path.strip_prefix(&staged_app_path).unwrap_or(path)),
+        })
+    };
 
     warn!(
         logger,
@@ -573,32 +940,105 @@ pub fn package_project(logger: &slog::Logger, context: &mut BuildContext) -> Res
         context.app_exe_target_path.display(),
         context.app_exe_path.display()
     );
-    std::fs::copy(&context.app_exe_target_path, &context.app_exe_path)?;
+    copy_file(&context.app_exe_target_path, &app_exe_path)?;
+    packaging_step("copy-executable", &app_exe_path);
 
-    warn!(logger, "resolving packaging state...");
-    let state = context.get_packaging_state()?;
+    // This must happen before anything else modifies the executable.
+    let mut debug_infos = Vec::new();
+    if let Some(debug_dir) = &paths.debug_info_dir {
+        cancel::check_cancelled()?;
 
-    if let Some(licenses_path) = state.license_files_path {
-        let licenses_path = if licenses_path.is_empty() {
-            context.app_path.clone()
-        } else {
-            context.app_path.join(licenses_path)
-        };
+        if let Some(info) =
+            split_debug_info(logger, &context.target_triple, &app_exe_path, debug_dir)?
+        {
+            warn!(
+                logger,
+                "wrote debug info to {}",
+                context
+                    .app_path
+                    .join(info.path.strip_prefix(&app_path)?)
+                    .display()
+            );
+            packaging_step("split-debug-info", &info.path);
+            debug_infos.push(info);
+        }
+    }
 
-        for (name, lis) in &state.license_infos {
-            for li in lis {
-                let path = licenses_path.join(&li.license_filename);
-                warn!(logger, "writing license for {} to {}", name, path.display());
-                fs::write(&path, li.license_text.as_bytes())?;
-            }
+    // Sign before creating copies of the executable and before anything
+    // hashes it.
+    let mut signed_paths = Vec::new();
+    cancel::check_cancelled()?;
+    if sign_configured(logger, context, &app_exe_path)? {
+        packaging_step("sign", &app_exe_path);
+        signed_paths.push(app_exe_path.clone());
+    }
+
+    if let Some(header_path) = &paths.header {
+        let name = library_name(&context.cargo_config)?;
+        warn!(logger, "writing C header {}", header_path.display());
+        write_file(header_path, library_header(&name))?;
+        packaging_step("write-header", header_path);
+    }
+
+    let windows = context.target_triple.contains("pc-windows");
+    let links = create_multicall_links(
+        &app_exe_path,
+        context.config.multicall_run_modes.keys(),
+        windows,
+    )?;
+    for link in links {
+        // Copies of a signed executable are signed.
+        if windows && !signed_paths.is_empty() {
+            signed_paths.push(link.clone());
         }
+
+        warn!(
+            logger,
+            "created {} for multicall run mode",
+            context
+                .app_path
+                .join(link.strip_prefix(&app_path)?)
+                .display()
+        );
+        packaging_step("multicall-link", &link);
     }
 
+    for license in &paths.licenses {
+        warn!(
+            logger,
+            "writing license for {} to {}",
+            license.name,
+            license.path.display()
+        );
+        write_file(&license.path, license.text.as_bytes())?;
+        packaging_step("write-license", &license.path);
+    }
+
+    if paths.notices.is_some() {
+        let notices_path = write_notices(
+            logger,
+            &app_path,
+            &state.notice_components,
+            &state.license_filter_decisions,
+        )?;
+        packaging_step("write-notices", &notices_path);
+    }
+
+    let manifest_path =
+        write_build_manifest(logger, context, &app_path, &debug_infos, &signed_paths)?;
+    packaging_step("write-manifest", &manifest_path);
+
+    cancel::check_cancelled()?;
+    staged.commit()?;
+    record_output_directory(&context.build_path, &context.app_path, &owner)?;
+    packaging_step("commit", &app_path);
+
     warn!(
         logger,
-        "{} packaged into {}",
+        "{} packaged into {} (output layout {})",
         context.app_name,
-        context.app_path.display()
+        context.app_path.display(),
+        context.output_layout
     );
 
     Ok(())
@@ -612,7 +1052,7 @@ pub struct EmbeddedPythonConfig {
     /// Parsed starlark config.
     pub config: Config,
 
-    /// Path to archive with source Python distribution.
+    /// Path to extracted Python distribution.
     pub python_distribution_path: PathBuf,
 
     /// Path to frozen importlib._bootstrap bytecode.
@@ -644,18 +1084,27 @@ pub struct EmbeddedPythonConfig {
     pub packaging_state_path: PathBuf,
 }
 
-/// Derive build artifacts from a PyOxidizer configuration.
-///
-/// This function processes the PyOxidizer configuration and turns it into a set
-/// of derived files that can power an embedded Python interpreter.
+/// A config's Python distribution and the resources packaged from it.
+pub struct ResolvedConfig {
+    /// The Python distribution being embedded.
+    pub dist: ParsedPythonDistribution,
+
+    /// Resources to package.
+    pub resources: PythonResources,
+
+    /// `cargo:rerun-if-changed` lines for files resolution depends on.
+    pub cargo_metadata: Vec<String>,
+
+    /// Extension module variants removed by the license policy.
+    pub license_filter_decisions: Vec<LicenseFilterDecision>,
+}
+
+/// Resolve the Python distribution and resources a config packages.
 ///
-/// Returns a data structure describing the results.
+/// The distribution is fetched and extracted if it isn't already. Nothing
+/// else is written to the artifacts directory.
 #[allow(clippy::cognitive_complexity)]
-pub fn process_config(
-    logger: &slog::Logger,
-    context: &mut BuildContext,
-    opt_level: &str,
-) -> EmbeddedPythonConfig {
+pub fn resolve_config(logger: &slog::Logger, context: &BuildContext) -> Result<ResolvedConfig> {
     let mut cargo_metadata: Vec<String> = Vec::new();
 
     let config = &context.config;
@@ -672,55 +1121,108 @@ pub fn process_config(
         config.config_path.display()
     ));
 
+    for p in &config.read_files {
+        cargo_metadata.push(format!("cargo:rerun-if-changed={}", p.display()));
+    }
+
+    // Directories are included so cargo notices files being added or removed.
+    // Duplicates are removed with the files inside them when writing
+    // cargo_metadata.txt.
+    for p in &config.input_paths {
+        cargo_metadata.push(format!("cargo:rerun-if-changed={}", p.display()));
+    }
+
     if !dest_dir.exists() {
-        create_dir_all(dest_dir).unwrap();
+        create_dir_all(dest_dir)?;
     }
 
     if let PythonDistributionLocation::Local { local_path, .. } = &config.python_distribution {
         cargo_metadata.push(format!("cargo:rerun-if-changed={}", local_path));
     }
 
+    cargo_metadata.extend(config.build_config.link_cargo_metadata());
+    cargo_metadata.extend(config.embedded_python_config.cargo_metadata());
+
     // Obtain the configured Python distribution and parse it to a data structure.
     warn!(logger, "resolving Python distribution...");
-    let python_distribution_path =
-        resolve_python_distribution_archive(&config.python_distribution, &dest_dir);
-    warn!(
-        logger,
-        "Python distribution available at {}",
-        python_distribution_path.display()
-    );
-
-    let dist = ParsedPythonDistribution::from_path(
+    let phase = build_stats::phase("resolve distribution");
+    let shared_dirs = shared_distribution_dirs(&config.python_distribution);
+    let archive_dir = match &shared_dirs {
+        Some((archive_dir, _)) => archive_dir.clone(),
+        None => dest_dir.to_path_buf(),
+    };
+    let mut dist = parse_cached_distribution(
         logger,
-        &python_distribution_path,
+        &config.python_distribution,
+        &archive_dir,
         &context.python_distribution_path,
+        context.offline,
     )
-    .unwrap();
-
-    warn!(logger, "distribution info: {:#?}", dist.as_minimal_info());
+    .category(ErrorCategory::Distribution)?;
+    if shared_dirs.is_some() {
+        mark_distribution_used(&context.python_distribution_path);
+    }
+    drop(phase);
+
+    let license_filter_decisions = match &config.extension_module_license_policy {
+        Some(policy) => policy
+            .apply(logger, &mut dist.extension_modules)
+            .context("applying extension_module_license_filter()")
+            .category(ErrorCategory::Config)?,
+        None => Vec::new(),
+    };
 
-    // Produce the custom frozen importlib modules.
-    warn!(
-        logger,
-        "compiling custom importlib modules to support in-memory importing"
-    );
-    let importlib = derive_importlib(&dist).unwrap();
+    // Checked after the license policy, which may remove _sqlite3.
+    if !config.required_sqlite_options.is_empty() {
+        check_sqlite_options(&dist, &config.required_sqlite_options)
+            .context("checking require_sqlite_options()")
+            .category(ErrorCategory::Distribution)?;
+    }
 
-    let importlib_bootstrap_path = Path::new(&dest_dir).join("importlib_bootstrap");
-    let mut fh = fs::File::create(&importlib_bootstrap_path).unwrap();
-    fh.write_all(&importlib.bootstrap_bytecode).unwrap();
+    cancel::check_cancelled()?;
 
-    let importlib_bootstrap_external_path =
-        Path::new(&dest_dir).join("importlib_bootstrap_external");
-    let mut fh = fs::File::create(&importlib_bootstrap_external_path).unwrap();
-    fh.write_all(&importlib.bootstrap_external_bytecode)
-        .unwrap();
+    warn!(logger, "distribution info: {:#?}", dist.as_minimal_info());
 
     warn!(
         logger,
         "resolving Python resources (modules, extensions, resource data, etc)..."
     );
-    let resources = resolve_python_resources(logger, context, &dist);
+    let phase = build_stats::phase("resolve resources");
+    let mut resources = resolve_python_resources(logger, context, &dist)?;
+    drop(phase);
+
+    cancel::check_cancelled()?;
+
+    if !config.packaging_scripts.is_empty() {
+        let mut compiler = BytecodeCompiler::new(&dist.python_exe)?;
+        let mut compile = |name: &str, source: &[u8]| {
+            compiler
+                .compile(
+                    source,
+                    name,
+                    BytecodeOptimizationLevel::Zero,
+                    CompileMode::Bytecode,
+                )
+                .with_context(|| format!("compiling bytecode for {}", name))
+        };
+
+        for script in &config.packaging_scripts {
+            let dependencies = run_packaging_script(
+                logger,
+                &dist.python_exe,
+                script,
+                &context.config_parent_path,
+                &mut resources.embedded,
+                &mut compile,
+            )?;
+
+            for p in dependencies {
+                cargo_metadata.push(format!("cargo:rerun-if-changed={}", p.display()));
+            }
+
+            cancel::check_cancelled()?;
+        }
+    }
 
     warn!(
         logger,
@@ -741,6 +1243,19 @@ pub fn process_config(
     );
     info!(logger, "{:#?}", resources.embedded.all_modules);
 
+    let mut resource_names = resources.embedded.all_modules.clone();
+    resource_names.extend(resources.embedded.embedded_extension_module_names());
+    resource_names.extend(resources.embedded.resources.keys().cloned());
+    let mut refs = executable_references(&config.embedded_python_config, &config.run);
+    refs.extend(multicall_references(&config.multicall_run_modes));
+    refs.extend(entry_point_references(&resources.embedded.resources));
+    check_references(
+        logger,
+        &refs,
+        &resource_names,
+        config.embedded_python_config.filesystem_importer,
+    )?;
+
     let mut resource_count = 0;
     let mut resource_map = BTreeMap::new();
     for (package, entries) in &resources.embedded.resources {
@@ -758,6 +1273,26 @@ pub fn process_config(
     );
     info!(logger, "{:#?}", resource_map);
 
+    if !config.license_check.is_empty() {
+        config
+            .license_check
+            .check(&resources.embedded.extension_modules)
+            .category(ErrorCategory::Config)?;
+    }
+
+    check_capabilities(
+        &required_capabilities(
+            &config.embedded_python_config,
+            resources.embedded.extension_modules.values(),
+        ),
+        &DistributionCapabilities::from_distribution(&dist),
+        &format!(
+            "{} {} ({} {})",
+            dist.flavor, dist.version, dist.os, dist.arch
+        ),
+    )
+    .category(ErrorCategory::Config)?;
+
     let all_extension_modules = resources.embedded.embedded_extension_module_names();
     warn!(
         logger,
@@ -766,63 +1301,208 @@ pub fn process_config(
     );
     info!(logger, "{:#?}", all_extension_modules);
 
+    Ok(ResolvedConfig {
+        dist,
+        resources,
+        cargo_metadata,
+        license_filter_decisions,
+    })
+}
+
+/// Paths of the files a build writes to the artifacts directory.
+///
+/// `process_config()` writes to these paths and `pyoxidizer build --plan`
+/// lists them.
+#[derive(Clone, Debug)]
+pub struct ArtifactPaths {
+    /// Extracted Python distribution.
+    pub python_distribution: PathBuf,
+    pub importlib_bootstrap: PathBuf,
+    pub importlib_bootstrap_external: PathBuf,
+    pub module_names: PathBuf,
+    pub py_modules: PathBuf,
+    pub resources: PathBuf,
+    pub libpython: LibpythonPaths,
+    pub resolved_config: PathBuf,
+    pub data_rs: PathBuf,
+    pub cargo_metadata: PathBuf,
+    pub packaging_state: PathBuf,
+    pub inputs_manifest: PathBuf,
+    /// Written when cargo is invoked rather than by `process_config()`.
+    pub build_environment: PathBuf,
+}
+
+impl ArtifactPaths {
+    pub fn new(context: &BuildContext) -> ArtifactPaths {
+        let dir = &context.pyoxidizer_artifacts_path;
+
+        ArtifactPaths {
+            python_distribution: context.python_distribution_path.clone(),
+            importlib_bootstrap: dir.join("importlib_bootstrap"),
+            importlib_bootstrap_external: dir.join("importlib_bootstrap_external"),
+            module_names: dir.join("py-module-names"),
+            py_modules: dir.join("py-modules"),
+            resources: dir.join("python-resources"),
+            libpython: LibpythonPaths::new(dir, &context.target_triple),
+            resolved_config: dir.join("resolved-config.json"),
+            data_rs: dir.join("data.rs"),
+            cargo_metadata: dir.join("cargo_metadata.txt"),
+            packaging_state: dir.join("packaging_state.cbor"),
+            inputs_manifest: dir.join(INPUTS_MANIFEST_FILENAME),
+            build_environment: BuildEnvironment::path(dir),
+        }
+    }
+}
+
+/// Obtain the lines the build script emits to cargo.
+///
+/// `libpython` are the lines linking the libraries `link_libpython()`
+/// writes. Files inside listed directories aren't listed themselves, which
+/// keeps the lines few for large trees.
+pub fn artifacts_cargo_metadata(
+    resolved: &ResolvedConfig,
+    libpython: &[String],
+    paths: &ArtifactPaths,
+) -> Vec<String> {
+    let mut cargo_metadata = resolved.cargo_metadata.clone();
+    cargo_metadata.extend(libpython.iter().cloned());
+
+    for p in &resolved.resources.read_files {
+        cargo_metadata.push(format!("cargo:rerun-if-changed={}", p.display()));
+    }
+
+    // Define the path to the written file in an environment variable so it can
+    // be anywhere.
+    cargo_metadata.push(format!(
+        "cargo:rustc-env=PYEMBED_DATA_RS_PATH={}",
+        paths.data_rs.display()
+    ));
+
+    collapse_rerun_if_changed(&cargo_metadata)
+}
+
+/// Obtain the state packaging a build of resolved config needs.
+pub fn artifacts_packaging_state(
+    context: &BuildContext,
+    resolved: &ResolvedConfig,
+) -> Result<PackagingState> {
+    let embedded = &resolved.resources.embedded;
+
+    Ok(PackagingState {
+        license_files_path: resolved.resources.license_files_path.clone(),
+        license_infos: libpython_license_infos(&resolved.dist, embedded),
+        rustflags: context.rustflags.clone(),
+        input_digests: input_digests(&context.config)?,
+        config_digest: context.config.digest(),
+        license_filter_decisions: resolved.license_filter_decisions.clone(),
+        notice_components: collect_notice_components(&resolved.dist, &embedded.extension_modules)?,
+    })
+}
+
+/// Derive build artifacts from a PyOxidizer configuration.
+///
+/// This function processes the PyOxidizer configuration and turns it into a set
+/// of derived files that can power an embedded Python interpreter.
+///
+/// Returns a data structure describing the results.
+pub fn process_config(
+    logger: &slog::Logger,
+    context: &mut BuildContext,
+    opt_level: &str,
+) -> Result<EmbeddedPythonConfig> {
+    let resolved = resolve_config(logger, context)?;
+    let dist = &resolved.dist;
+    let resources = &resolved.resources;
+
+    let config = &context.config;
+    let dest_dir = &context.pyoxidizer_artifacts_path;
+    let paths = ArtifactPaths::new(context);
+
+    // Produce the custom frozen importlib modules.
+    warn!(
+        logger,
+        "compiling custom importlib modules to support in-memory importing"
+    );
+    let importlib = derive_importlib(dist)?;
+
+    let importlib_bootstrap_path = paths.importlib_bootstrap.clone();
+    let mut fh = create_file(&importlib_bootstrap_path)?;
+    fh.write_all(&importlib.bootstrap_bytecode)?;
+
+    let importlib_bootstrap_external_path = paths.importlib_bootstrap_external.clone();
+    let mut fh = create_file(&importlib_bootstrap_external_path)?;
+    fh.write_all(&importlib.bootstrap_external_bytecode)?;
+
+    cancel::check_cancelled()?;
+
     // Produce the packed data structures containing Python modules.
     // TODO there is tons of room to customize this behavior, including
     // reordering modules so the memory order matches import order.
 
     warn!(logger, "writing packed Python module and resource data...");
-    let module_names_path = Path::new(&dest_dir).join("py-module-names");
-    let py_modules_path = Path::new(&dest_dir).join("py-modules");
-    let resources_path = Path::new(&dest_dir).join("python-resources");
+    let module_names_path = paths.module_names.clone();
+    let py_modules_path = paths.py_modules.clone();
+    let resources_path = paths.resources.clone();
 
-    let mut module_names_fh =
-        BufWriter::new(fs::File::create(&module_names_path).expect("error creating file"));
-    let mut modules_fh =
-        BufWriter::new(fs::File::create(&py_modules_path).expect("error creating file"));
-    let mut resources_fh =
-        BufWriter::new(fs::File::create(&resources_path).expect("error creating file"));
+    // The blobs are assembled in memory so they can be digested.
+    let mut module_names = Vec::new();
+    let mut modules = Vec::new();
+    let mut resources_data = Vec::new();
 
     resources
         .embedded
-        .write_blobs(&mut module_names_fh, &mut modules_fh, &mut resources_fh);
+        .write_blobs(&mut module_names, &mut modules, &mut resources_data);
 
-    module_names_fh.flush().unwrap();
-    modules_fh.flush().unwrap();
-    resources_fh.flush().unwrap();
+    create_file(&module_names_path)?.write_all(&module_names)?;
+    create_file(&py_modules_path)?.write_all(&modules)?;
+    create_file(&resources_path)?.write_all(&resources_data)?;
+
+    let identifiers = AppIdentifiers::derive(
+        &module_names,
+        &modules,
+        &resources_data,
+        &config.embedded_python_config,
+        &config.run,
+        &config.multicall_run_modes,
+        config.python_distribution.sha256(),
+        &context.target_triple,
+    );
+    warn!(
+        logger,
+        "resources digest {}; build ID {}", identifiers.resources_digest, identifiers.build_id
+    );
 
     warn!(
         logger,
         "{} bytes of Python module data written to {}",
-        py_modules_path.metadata().unwrap().len(),
+        py_modules_path.metadata()?.len(),
         py_modules_path.display()
     );
     warn!(
         logger,
         "{} bytes of resources data written to {}",
-        resources_path.metadata().unwrap().len(),
+        resources_path.metadata()?.len(),
         resources_path.display()
     );
 
+    cancel::check_cancelled()?;
+
     // Produce a static library containing the Python bits we need.
     warn!(
         logger,
         "generating custom link library containing Python..."
     );
+    let phase = build_stats::phase("link libpython");
     let libpython_info = link_libpython(
         logger,
-        &dist,
+        dist,
         &resources.embedded,
         dest_dir,
         &context.host_triple,
         &context.target_triple,
         opt_level,
-    )
-    .unwrap();
-    cargo_metadata.extend(libpython_info.cargo_metadata);
-
-    for p in &resources.read_files {
-        cargo_metadata.push(format!("cargo:rerun-if-changed={}", p.display()));
-    }
+    )?;
+    drop(phase);
 
     warn!(logger, "processing python run mode: {:?}", config.run);
     warn!(
@@ -833,49 +1513,69 @@ pub fn process_config(
     let python_config_rs = derive_python_config(
         &config.embedded_python_config,
         &config.run,
+        &config.multicall_run_modes,
         &importlib_bootstrap_path,
         &importlib_bootstrap_external_path,
         &py_modules_path,
         &resources_path,
+        None,
+        &identifiers,
     );
 
-    let dest_path = Path::new(&dest_dir).join("data.rs");
-    write_data_rs(&dest_path, &python_config_rs).unwrap();
-    // Define the path to the written file in an environment variable so it can
-    // be anywhere.
-    cargo_metadata.push(format!(
-        "cargo:rustc-env=PYEMBED_DATA_RS_PATH={}",
-        dest_path.display()
-    ));
+    let resolved_config_path = &paths.resolved_config;
+    warn!(
+        logger,
+        "writing resolved config to {}",
+        resolved_config_path.display()
+    );
+    config
+        .embedded_python_config
+        .write_resolved_json(resolved_config_path, &context.target_triple)
+        .context("unable to write resolved-config.json")?;
+
+    write_data_rs(
+        &paths.data_rs,
+        &python_config_rs,
+        &config.build_config.pre_init_hooks,
+        &config.build_config.post_finalize_hooks,
+    )?;
 
     // Write a file containing the cargo metadata lines. This allows those
     // lines to be consumed elsewhere and re-emitted without going through all the
     // logic in this function.
-    let cargo_metadata_path = Path::new(&dest_dir).join("cargo_metadata.txt");
-    fs::write(&cargo_metadata_path, cargo_metadata.join("\n").as_bytes())
-        .expect("unable to write cargo_metadata.txt");
+    let cargo_metadata =
+        artifacts_cargo_metadata(&resolved, &libpython_info.cargo_metadata, &paths);
+    write_file(&paths.cargo_metadata, cargo_metadata.join("\n").as_bytes())
+        .context("unable to write cargo_metadata.txt")?;
+
+    // Record the content of all inputs so later builds can tell whether
+    // the artifacts are current.
+    let current_exe = env::current_exe().context("resolving pyoxidizer executable")?;
+    let inputs = artifact_inputs(
+        &cargo_metadata.join("\n"),
+        &config.config_path,
+        &current_exe,
+    );
+    InputsManifest::from_paths(&inputs)
+        .context("hashing inputs of artifacts")?
+        .write(&paths.inputs_manifest)?;
 
-    let packaging_state = PackagingState {
-        license_files_path: resources.license_files_path,
-        license_infos: libpython_info.license_infos,
-    };
+    let packaging_state = artifacts_packaging_state(context, &resolved)?;
 
-    let packaging_state_path = dest_dir.join("packaging_state.cbor");
+    let packaging_state_path = paths.packaging_state.clone();
     warn!(
         logger,
         "writing packaging state to {}",
         packaging_state_path.display()
     );
-    let mut fh = BufWriter::new(
-        fs::File::create(&packaging_state_path).expect("unable to create packaging_state.cbor"),
-    );
-    serde_cbor::to_writer(&mut fh, &packaging_state).unwrap();
+    let mut fh = BufWriter::new(create_file(&packaging_state_path)?);
+    serde_cbor::to_writer(&mut fh, &packaging_state)?;
 
     context.packaging_state = Some(packaging_state);
 
-    EmbeddedPythonConfig {
+    Ok(EmbeddedPythonConfig {
         config: config.clone(),
-        python_distribution_path,
+        python_distribution_path: context.python_distribution_path.clone(),
         importlib_bootstrap_path,
         importlib_bootstrap_external_path,
         module_names_path,
@@ -885,7 +1585,7 @@ pub fn process_config(
         cargo_metadata,
         python_config_rs,
         packaging_state_path,
-    }
+    })
 }
 
 /// Runs packaging/embedding from the context of a build script.
@@ -931,7 +1631,18 @@ pub fn run_from_build(logger: &slog::Logger, build_script: &str) {
         Err(_) => PathBuf::from(env::var("OUT_DIR").unwrap()),
     };
 
-    let res = eval_starlark_config_file(logger, &config_path, &target).unwrap();
+    // The directory may hold artifacts of another PyOxidizer version.
+    let _lock = lock_artifacts_path(logger, &dest_dir).unwrap();
+    let dest_dir = claim_artifacts_path(logger, &dest_dir, artifacts_version()).unwrap();
+
+    let res = eval_starlark_config_file(
+        logger,
+        &config_path,
+        &target,
+        profile == "release",
+        &EvalOptions::from_env(),
+    )
+    .unwrap();
 
     let mut context = BuildContext::new(
         &project_path,
@@ -941,11 +1652,81 @@ pub fn run_from_build(logger: &slog::Logger, build_script: &str) {
         profile == "release",
         // TODO Config value won't be honored here. Is that OK?
         Some(&dest_dir),
-        true,
+        Verbosity::Verbose,
     )
     .unwrap();
 
-    for line in process_config(logger, &mut context, &opt_level).cargo_metadata {
+    for line in process_config(logger, &mut context, &opt_level)
+        .unwrap()
+        .cargo_metadata
+    {
         println!("{}", line);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn multicall_names() -> Vec<String> {
+        vec![
+            "myapp".to_string(),
+            "myapp-worker".to_string(),
+            "myapp-admin".to_string(),
+        ]
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_create_multicall_links_symlinks() -> Result<()> {
+        let temp_dir = tempdir::TempDir::new("pyoxidizer-test")?;
+        let exe_path = temp_dir.path().join("myapp");
+        fs::write(&exe_path, b"executable")?;
+
+        let names = multicall_names();
+        let links = create_multicall_links(&exe_path, names.iter(), false)?;
+        assert_eq!(
+            links,
+            vec![
+                temp_dir.path().join("myapp-worker"),
+                temp_dir.path().join("myapp-admin")
+            ]
+        );
+
+        for link in &links {
+            assert_eq!(fs::read_link(link)?, PathBuf::from("myapp"));
+            assert_eq!(fs::read(link)?, b"executable");
+        }
+
+        // Recreating over existing links works.
+        assert_eq!(
+            create_multicall_links(&exe_path, names.iter(), false)?,
+            links
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_create_multicall_links_windows() -> Result<()> {
+        let temp_dir = tempdir::TempDir::new("pyoxidizer-test")?;
+        let exe_path = temp_dir.path().join("MyApp.exe");
+        fs::write(&exe_path, b"executable")?;
+
+        let links = create_multicall_links(&exe_path, multicall_names().iter(), true)?;
+        assert_eq!(
+            links,
+            vec![
+                temp_dir.path().join("myapp-worker.exe"),
+                temp_dir.path().join("myapp-admin.exe")
+            ]
+        );
+
+        for link in &links {
+            assert!(!fs::symlink_metadata(link)?.file_type().is_symlink());
+            assert_eq!(fs::read(link)?, b"executable");
+        }
+
+        Ok(())
+    }
+}
@@ -31,15 +31,104 @@ pub struct FileContent {
     pub executable: bool,
 }
 
+/// How to handle paths only differing in case.
+///
+/// Such paths refer to the same file on case-insensitive filesystems, so
+/// which of them ends up on disk depends on the order they are written in.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CaseCollisionPolicy {
+    /// Fail with a report of the colliding paths.
+    Error,
+    /// Keep the path added first and drop the others.
+    FirstWins,
+}
+
+impl CaseCollisionPolicy {
+    pub fn parse(s: &str) -> Result<CaseCollisionPolicy> {
+        match s {
+            "error" => Ok(CaseCollisionPolicy::Error),
+            "first-wins" => Ok(CaseCollisionPolicy::FirstWins),
+            _ => Err(anyhow!(
+                "invalid case collision policy {}; must be error or first-wins",
+                s
+            )),
+        }
+    }
+}
+
+impl Default for CaseCollisionPolicy {
+    fn default() -> Self {
+        CaseCollisionPolicy::Error
+    }
+}
+
+/// Whether the default filesystems of a target triple are case-insensitive.
+///
+/// This is true for Windows and macOS (NTFS and APFS/HFS+ are
+/// case-insensitive by default).
+pub fn target_case_insensitive(target: &str) -> bool {
+    target.contains("windows") || target.contains("apple")
+}
+
+/// Find paths that refer to the same file on a case-insensitive filesystem.
+///
+/// Returns groups of distinct paths differing only in case. Paths within a
+/// group are in the order they were passed in.
+pub fn case_collisions<'a>(paths: impl Iterator<Item = &'a Path>) -> Vec<Vec<PathBuf>> {
+    let mut groups: BTreeMap<String, Vec<PathBuf>> = BTreeMap::new();
+
+    for path in paths {
+        let group = groups
+            .entry(path.to_string_lossy().to_lowercase())
+            .or_insert_with(Vec::new);
+
+        if !group.iter().any(|p| p == path) {
+            group.push(path.to_path_buf());
+        }
+    }
+
+    groups
+        .into_iter()
+        .filter_map(|(_, group)| if group.len() > 1 { Some(group) } else { None })
+        .collect()
+}
+
+/// Where a file in a `FileManifest` came from.
+#[derive(Clone, Debug, PartialEq)]
+struct FileOrigin {
+    /// Position at which the file was first added.
+    order: usize,
+    /// Description of the rule that added the file.
+    rule: Option<String>,
+}
+
 /// Represents a virtual tree of files.
 #[derive(Clone, Debug, Default, PartialEq)]
 pub struct FileManifest {
     files: BTreeMap<PathBuf, FileContent>,
+    origins: BTreeMap<PathBuf, FileOrigin>,
+    next_order: usize,
 }
 
 impl FileManifest {
     /// Add a file to the manifest.
     pub fn add_file(&mut self, path: &Path, content: &FileContent) -> Result<()> {
+        self.add_file_with_origin(path, content, None)
+    }
+
+    /// Add a file to the manifest, recording the rule that added it.
+    ///
+    /// The rule is mentioned when reporting case collisions.
+    pub fn add_file_from(&mut self, path: &Path, content: &FileContent, rule: &str) -> Result<()> {
+        self.add_file_with_origin(path, content, Some(rule.to_string()))
+    }
+
+    fn add_file_with_origin(
+        &mut self,
+        path: &Path,
+        content: &FileContent,
+        rule: Option<String>,
+    ) -> Result<()> {
         let path_s = path.display().to_string();
 
         if path_s.contains("..") {
@@ -53,9 +142,137 @@ impl FileManifest {
 
         self.files.insert(path.to_path_buf(), content.clone());
 
+        let order = match self.origins.get(path) {
+            Some(origin) => origin.order,
+            None => {
+                self.next_order += 1;
+                self.next_order - 1
+            }
+        };
+        self.origins
+            .insert(path.to_path_buf(), FileOrigin { order, rule });
+
         Ok(())
     }
 
+    /// The content of a file in this manifest, if any.
+    pub fn get(&self, path: &Path) -> Option<&FileContent> {
+        self.files.get(path)
+    }
+
+    /// Find paths in this manifest that collide on case-insensitive filesystems.
+    ///
+    /// Both files and the directories containing them are considered.
+    pub fn case_collisions(&self) -> Vec<Vec<PathBuf>> {
+        let dirs = self.relative_directories();
+
+        let mut groups = case_collisions(
+            self.files
+                .keys()
+                .map(|p| p.as_path())
+                .chain(dirs.iter().map(|p| p.as_path())),
+        );
+
+        for group in groups.iter_mut() {
+            group.sort_by_key(|p| self.path_order(p));
+        }
+
+        groups
+    }
+
+    /// Files at or below a path.
+    fn files_under<'a>(&'a self, path: &'a Path) -> impl Iterator<Item = &'a PathBuf> {
+        self.files.keys().filter(move |p| p.starts_with(path))
+    }
+
+    /// Position at which the first file at or below a path was added.
+    fn path_order(&self, path: &Path) -> usize {
+        self.files_under(path)
+            .filter_map(|p| self.origins.get(p))
+            .map(|origin| origin.order)
+            .min()
+            .unwrap_or(std::usize::MAX)
+    }
+
+    /// Describe the rules that added files at or below a path.
+    fn describe_origin(&self, path: &Path) -> String {
+        let rules = self
+            .files_under(path)
+            .filter_map(|p| self.origins.get(p))
+            .map(|origin| {
+                origin
+                    .rule
+                    .clone()
+                    .unwrap_or_else(|| "unknown rule".to_string())
+            })
+            .collect::<BTreeSet<_>>();
+
+        rules.into_iter().collect::<Vec<_>>().join(", ")
+    }
+
+    /// Resolve paths colliding on the filesystems of a target.
+    ///
+    /// Nothing happens for targets with case-sensitive filesystems. Otherwise
+    /// the `Error` policy fails with a report of all colliding paths and the
+    /// rules that added them and the `FirstWins` policy keeps the path added
+    /// first, removing the files of the others. Returns the removed files.
+    pub fn resolve_case_collisions(
+        &mut self,
+        target: &str,
+        policy: CaseCollisionPolicy,
+    ) -> Result<Vec<PathBuf>> {
+        if !target_case_insensitive(target) {
+            return Ok(Vec::new());
+        }
+
+        let mut removed = Vec::new();
+
+        loop {
+            let groups = self.case_collisions();
+
+            if groups.is_empty() {
+                break;
+            }
+
+            if policy == CaseCollisionPolicy::Error {
+                let mut report = format!(
+                    "paths collide on the case-insensitive filesystems of {}:",
+                    target
+                );
+
+                for group in &groups {
+                    report.push('\n');
+                    for path in group {
+                        report.push_str(&format!(
+                            "\n  {} (from {})",
+                            path.display(),
+                            self.describe_origin(path)
+                        ));
+                    }
+                }
+                report.push_str(
+                    "\n\nuse case_collision_policy=\"first-wins\" to keep the path added first",
+                );
+
+                return Err(anyhow!(report));
+            }
+
+            for group in groups {
+                for path in &group[1..] {
+                    let files = self.files_under(path).cloned().collect::<Vec<_>>();
+
+                    for file in files {
+                        self.files.remove(&file);
+                        self.origins.remove(&file);
+                        removed.push(file);
+                    }
+                }
+            }
+        }
+
+        Ok(removed)
+    }
+
     /// All relative directories contained within files in this manifest.
     ///
     /// The root directory is not represented in the return value.
@@ -204,4 +421,115 @@ mod tests {
             ]
         )
     }
+
+    fn colliding_manifest() -> FileManifest {
+        let mut v = FileManifest::default();
+        let f = FileContent {
+            data: vec![],
+            executable: false,
+        };
+
+        v.add_file_from(&PathBuf::from("lib/PIL/Image.py"), &f, "rule a")
+            .unwrap();
+        v.add_file_from(&PathBuf::from("lib/PIL/image.py"), &f, "rule b")
+            .unwrap();
+        v.add_file_from(&PathBuf::from("lib/pil/other.py"), &f, "rule c")
+            .unwrap();
+        v.add_file_from(&PathBuf::from("lib/other.py"), &f, "rule c")
+            .unwrap();
+
+        v
+    }
+
+    #[test]
+    fn test_case_collisions() {
+        let paths = vec![
+            PathBuf::from("a/B"),
+            PathBuf::from("a/b"),
+            PathBuf::from("a/c"),
+            PathBuf::from("a/B"),
+        ];
+
+        assert_eq!(
+            case_collisions(paths.iter().map(|p| p.as_path())),
+            vec![vec![PathBuf::from("a/B"), PathBuf::from("a/b")]]
+        );
+
+        assert_eq!(
+            colliding_manifest().case_collisions(),
+            vec![
+                vec![PathBuf::from("lib/PIL"), PathBuf::from("lib/pil")],
+                vec![
+                    PathBuf::from("lib/PIL/Image.py"),
+                    PathBuf::from("lib/PIL/image.py")
+                ],
+            ]
+        );
+    }
+
+    #[test]
+    fn test_resolve_case_collisions_case_sensitive_target() {
+        let mut v = colliding_manifest();
+        let removed = v
+            .resolve_case_collisions("x86_64-unknown-linux-gnu", CaseCollisionPolicy::Error)
+            .unwrap();
+
+        assert!(removed.is_empty());
+        assert_eq!(v, colliding_manifest());
+    }
+
+    #[test]
+    fn test_resolve_case_collisions_error() {
+        let mut v = colliding_manifest();
+
+        let err = v
+            .resolve_case_collisions("x86_64-apple-darwin", CaseCollisionPolicy::Error)
+            .unwrap_err()
+            .to_string();
+
+        assert!(err.contains("lib/PIL/Image.py (from rule a)"));
+        assert!(err.contains("lib/PIL/image.py (from rule b)"));
+        assert!(err.contains("lib/PIL (from rule a, rule b)"));
+        assert!(err.contains("lib/pil (from rule c)"));
+        assert!(err.contains("first-wins"));
+        assert_eq!(v, colliding_manifest());
+    }
+
+    #[test]
+    fn test_resolve_case_collisions_first_wins() {
+        let mut v = colliding_manifest();
+
+        let removed = v
+            .resolve_case_collisions("x86_64-pc-windows-msvc", CaseCollisionPolicy::FirstWins)
+            .unwrap();
+
+        assert_eq!(
+            removed,
+            vec![
+                PathBuf::from("lib/pil/other.py"),
+                PathBuf::from("lib/PIL/image.py")
+            ]
+        );
+        assert_eq!(
+            v.entries().map(|(p, _)| p.clone()).collect_vec(),
+            vec![
+                PathBuf::from("lib/PIL/Image.py"),
+                PathBuf::from("lib/other.py")
+            ]
+        );
+        assert!(v.case_collisions().is_empty());
+    }
+
+    #[test]
+    fn test_case_collision_policy_parse() {
+        assert_eq!(
+            CaseCollisionPolicy::parse("error").unwrap(),
+            CaseCollisionPolicy::Error
+        );
+        assert_eq!(
+            CaseCollisionPolicy::parse("first-wins").unwrap(),
+            CaseCollisionPolicy::FirstWins
+        );
+        assert!(CaseCollisionPolicy::parse("last-wins").is_err());
+    }
 }
@@ -0,0 +1,198 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/*!
+The environment applications run by `pyoxidizer run` execute in.
+
+Arguments after the first bare `--` are passed to applications verbatim,
+including empty ones and further `--`. Applications inherit our environment, plus variables given with
+`--env KEY=VALUE` or read from `--env-file` files, and run in the project
+directory unless `--cwd` says otherwise.
+
+Env files contain a `KEY=VALUE` definition per line. Empty lines and lines
+starting with `#` are ignored. Values are taken literally, without
+unquoting, and may contain `=` or be empty.
+*/
+
+use anyhow::{anyhow, Context, Result};
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+/// How to run an application.
+#[derive(Clone, Debug, Default)]
+pub struct RunOptions {
+    /// Environment variables to set, in order. Later definitions win.
+    pub env: Vec<(String, String)>,
+
+    /// Directory to run in instead of the project directory.
+    pub cwd: Option<PathBuf>,
+}
+
+/// Split command line arguments at the first bare `--`.
+///
+/// Returns the arguments before it, for us to parse, and the arguments
+/// after it, if any, for the application. The first argument is the
+/// program name and never a separator.
+pub fn split_app_args(args: &[OsString]) -> Result<(&[OsString], Option<Vec<String>>)> {
+    let separator = match args.iter().skip(1).position(|arg| arg == "--") {
+        Some(i) => i + 1,
+        None => return Ok((args, None)),
+    };
+
+    let app_args = args[separator + 1..]
+        .iter()
+        .map(|arg| {
+            arg.clone().into_string().map_err(|arg| {
+                anyhow!(
+                    "argument {} after -- is not valid UTF-8",
+                    arg.to_string_lossy()
+                )
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    Ok((&args[..separator], Some(app_args)))
+}
+
+/// Parse a `KEY=VALUE` environment variable definition.
+///
+/// Everything after the first `=` is the value.
+pub fn parse_env_var(definition: &str) -> Result<(String, String)> {
+    let mut parts = definition.splitn(2, '=');
+    let key = parts.next().unwrap_or("");
+    let value = parts
+        .next()
+        .ok_or_else(|| anyhow!("{} is not of the form KEY=VALUE", definition))?;
+
+    if key.is_empty() {
+        return Err(anyhow!("{} has an empty variable name", definition));
+    }
+    if key.contains('\0') || value.contains('\0') {
+        return Err(anyhow!("{} contains a NUL character", definition));
+    }
+
+    Ok((key.to_string(), value.to_string()))
+}
+
+/// Parse the content of an env file.
+pub fn parse_env_file(content: &str) -> Result<Vec<(String, String)>> {
+    content
+        .lines()
+        .enumerate()
+        .map(|(i, line)| (i, line.trim_end_matches('\r')))
+        .filter(|(_, line)| !line.trim().is_empty() && !line.trim_start().starts_with('#'))
+        .map(|(i, line)| parse_env_var(line).with_context(|| format!("line {}", i + 1)))
+        .collect()
+}
+
+/// Read the environment variables defined in an env file.
+pub fn read_env_file(path: &Path) -> Result<Vec<(String, String)>> {
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("reading env file {}", path.display()))?;
+
+    parse_env_file(&content).with_context(|| format!("parsing env file {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn os_args(args: &[&str]) -> Vec<OsString> {
+        args.iter().map(OsString::from).collect()
+    }
+
+    #[test]
+    fn test_split_app_args() -> Result<()> {
+        let args = os_args(&["pyoxidizer", "run", "--release", "myapp"]);
+        assert_eq!(split_app_args(&args)?, (args.as_slice(), None));
+
+        let args = os_args(&[
+            "pyoxidizer",
+            "run",
+            ".",
+            "--",
+            "--help",
+            "",
+            "--",
+            "--env",
+            "a=b",
+        ]);
+        let (ours, theirs) = split_app_args(&args)?;
+        assert_eq!(ours, os_args(&["pyoxidizer", "run", "."]).as_slice());
+        assert_eq!(
+            theirs,
+            Some(vec![
+                "--help".to_string(),
+                "".to_string(),
+                "--".to_string(),
+                "--env".to_string(),
+                "a=b".to_string(),
+            ])
+        );
+
+        let args = os_args(&["pyoxidizer", "run", "--"]);
+        assert_eq!(split_app_args(&args)?.1, Some(vec![]));
+
+        // The program name isn't a separator.
+        let args = os_args(&["--", "run"]);
+        assert_eq!(split_app_args(&args)?.1, None);
+
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_split_app_args_invalid_utf8() {
+        use std::os::unix::ffi::OsStringExt;
+
+        let args = vec![
+            OsString::from("pyoxidizer"),
+            OsString::from("--"),
+            OsString::from_vec(b"caf\xe9".to_vec()),
+        ];
+        let err = split_app_args(&args).unwrap_err();
+        assert!(err.to_string().ends_with("after -- is not valid UTF-8"));
+    }
+
+    #[test]
+    fn test_parse_env_var() -> Result<()> {
+        assert_eq!(
+            parse_env_var("MYAPP_CONFIG=/etc/myapp.yaml")?,
+            ("MYAPP_CONFIG".to_string(), "/etc/myapp.yaml".to_string())
+        );
+        assert_eq!(
+            parse_env_var("OPTS=a=b=c")?,
+            ("OPTS".to_string(), "a=b=c".to_string())
+        );
+        assert_eq!(
+            parse_env_var("EMPTY=")?,
+            ("EMPTY".to_string(), "".to_string())
+        );
+
+        assert!(parse_env_var("MYAPP_CONFIG").is_err());
+        assert!(parse_env_var("=value").is_err());
+        assert!(parse_env_var("KEY=a\0b").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_env_file() -> Result<()> {
+        assert_eq!(
+            parse_env_file("# settings\r\nMYAPP_CONFIG=a=b\r\n\n  \nEMPTY=\n  # indented\n")?,
+            vec![
+                ("MYAPP_CONFIG".to_string(), "a=b".to_string()),
+                ("EMPTY".to_string(), "".to_string()),
+            ]
+        );
+
+        let err = parse_env_file("A=1\n\nmissing value\n").unwrap_err();
+        assert_eq!(
+            format!("{:#}", err),
+            "line 3: missing value is not of the form KEY=VALUE"
+        );
+
+        Ok(())
+    }
+}
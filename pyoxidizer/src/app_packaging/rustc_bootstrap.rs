@@ -0,0 +1,290 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/*!
+Deciding whether `cargo build` needs `RUSTC_BOOTSTRAP=1`.
+
+On Windows, `python3-sys` links Python with the `static-nobundle` link
+kind, which stable compilers only accept with `RUSTC_BOOTSTRAP=1`
+(https://github.com/rust-lang/rust/issues/37403). Nightly compilers
+accept it as is, and with Rust 1.61+ `python3-sys` uses the stable
+`-bundle` link modifier instead. So the variable is only needed for
+Windows targets built with older stable or beta compilers.
+
+Some build environments forbid `RUSTC_BOOTSTRAP`. Setting
+`allow_rustc_bootstrap=False` in the config or passing
+`--no-rustc-bootstrap` makes builds needing it fail instead.
+*/
+
+use anyhow::{anyhow, Result};
+use rustc_version::{Channel, VersionMeta};
+use semver::Version;
+use slog::warn;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Whether `--no-rustc-bootstrap` was passed.
+static FORBIDDEN: AtomicBool = AtomicBool::new(false);
+
+/// Forbid setting `RUSTC_BOOTSTRAP` regardless of the config.
+pub fn forbid_rustc_bootstrap() {
+    FORBIDDEN.store(true, Ordering::SeqCst);
+}
+
+/// Whether `RUSTC_BOOTSTRAP` may be set, given the config's setting.
+pub fn rustc_bootstrap_allowed(config_allows: bool) -> bool {
+    config_allows && !FORBIDDEN.load(Ordering::SeqCst)
+}
+
+/// Whether a build needs `RUSTC_BOOTSTRAP=1` and why.
+#[derive(Clone, Debug, PartialEq)]
+pub enum RustcBootstrap {
+    NotNeeded(String),
+    Required(String),
+}
+
+/// First Rust version with the stable `-bundle` link modifier.
+fn bundle_modifier_version() -> Version {
+    Version::new(1, 61, 0)
+}
+
+/// Decide whether building for `target` with a compiler needs
+/// `RUSTC_BOOTSTRAP=1`.
+///
+/// `flavor` is the flavor of the Python distribution being embedded.
+pub fn rustc_bootstrap_requirement(
+    version: &Version,
+    channel: &Channel,
+    flavor: &str,
+    target: &str,
+) -> RustcBootstrap {
+    if !target.contains("-windows-") {
+        return RustcBootstrap::NotNeeded(format!(
+            "Python is only linked with static-nobundle for Windows targets, not {}",
+            target
+        ));
+    }
+
+    match channel {
+        Channel::Nightly | Channel::Dev => {
+            return RustcBootstrap::NotNeeded(
+                "nightly compilers accept the static-nobundle link kind".to_string(),
+            )
+        }
+        Channel::Beta | Channel::Stable => {}
+    }
+
+    // Pre-releases of 1.61 already have the modifier.
+    let base = Version::new(version.major, version.minor, version.patch);
+    if base >= bundle_modifier_version() {
+        return RustcBootstrap::NotNeeded(format!(
+            "Rust {} supports the stable -bundle link modifier",
+            version
+        ));
+    }
+
+    RustcBootstrap::Required(format!(
+        "the {} Python distribution is linked statically for {} with the static-nobundle link kind, which Rust {} only accepts on nightly",
+        flavor, target, version
+    ))
+}
+
+/// The error for builds needing `RUSTC_BOOTSTRAP=1` when it isn't allowed.
+pub fn rustc_bootstrap_forbidden_error(reason: &str) -> anyhow::Error {
+    anyhow!(
+        "RUSTC_BOOTSTRAP=1 is required but not allowed: {}; use Rust {} or newer, where python3-sys uses the stable -bundle link modifier, or a nightly toolchain (e.g. `rustup run nightly`); or allow it with allow_rustc_bootstrap=True in the config and without --no-rustc-bootstrap",
+        reason,
+        bundle_modifier_version()
+    )
+}
+
+/// Resolve whether to set `RUSTC_BOOTSTRAP=1` for a build.
+///
+/// Returns the reason if it needs to be set and errors if it is needed
+/// but not allowed.
+pub fn resolve_rustc_bootstrap(
+    version: &Version,
+    channel: &Channel,
+    flavor: &str,
+    target: &str,
+    allowed: bool,
+) -> Result<Option<String>> {
+    match rustc_bootstrap_requirement(version, channel, flavor, target) {
+        RustcBootstrap::NotNeeded(_) => Ok(None),
+        RustcBootstrap::Required(reason) if allowed => Ok(Some(reason)),
+        RustcBootstrap::Required(reason) => Err(rustc_bootstrap_forbidden_error(&reason)),
+    }
+}
+
+/// Decide whether to set `RUSTC_BOOTSTRAP=1` for building for `target`
+/// with the Rust compiler described by `meta`.
+///
+/// Setting it is logged as a warning, so it never goes unnoticed.
+pub fn rustc_bootstrap_for_build(
+    logger: &slog::Logger,
+    meta: &VersionMeta,
+    flavor: &str,
+    target: &str,
+    config_allows: bool,
+) -> Result<bool> {
+    match resolve_rustc_bootstrap(
+        &meta.semver,
+        &meta.channel,
+        flavor,
+        target,
+        rustc_bootstrap_allowed(config_allows),
+    )? {
+        Some(reason) => {
+            warn!(
+                logger,
+                "setting RUSTC_BOOTSTRAP=1 to enable nightly features of Rust {}: {}; use Rust {} or newer to avoid it",
+                meta.semver,
+                reason,
+                bundle_modifier_version()
+            );
+            Ok(true)
+        }
+        None => Ok(false),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn requirement(version: &str, channel: Channel, flavor: &str, target: &str) -> bool {
+        match rustc_bootstrap_requirement(
+            &Version::parse(version).unwrap(),
+            &channel,
+            flavor,
+            target,
+        ) {
+            RustcBootstrap::Required(_) => true,
+            RustcBootstrap::NotNeeded(_) => false,
+        }
+    }
+
+    #[test]
+    fn test_requirement() {
+        let cases = vec![
+            (
+                "1.40.0",
+                Channel::Stable,
+                "cpython",
+                "x86_64-pc-windows-msvc",
+                true,
+            ),
+            (
+                "1.40.0",
+                Channel::Stable,
+                "cpython",
+                "i686-pc-windows-msvc",
+                true,
+            ),
+            (
+                "1.40.0",
+                Channel::Beta,
+                "embeddable",
+                "x86_64-pc-windows-msvc",
+                true,
+            ),
+            (
+                "1.60.0",
+                Channel::Stable,
+                "cpython",
+                "x86_64-pc-windows-msvc",
+                true,
+            ),
+            (
+                "1.61.0",
+                Channel::Stable,
+                "cpython",
+                "x86_64-pc-windows-msvc",
+                false,
+            ),
+            (
+                "1.61.0-beta.1",
+                Channel::Beta,
+                "cpython",
+                "x86_64-pc-windows-msvc",
+                false,
+            ),
+            (
+                "1.70.0",
+                Channel::Stable,
+                "embeddable",
+                "x86_64-pc-windows-msvc",
+                false,
+            ),
+            (
+                "1.40.0",
+                Channel::Nightly,
+                "cpython",
+                "x86_64-pc-windows-msvc",
+                false,
+            ),
+            (
+                "1.40.0",
+                Channel::Dev,
+                "cpython",
+                "x86_64-pc-windows-msvc",
+                false,
+            ),
+            (
+                "1.40.0",
+                Channel::Stable,
+                "cpython",
+                "x86_64-unknown-linux-gnu",
+                false,
+            ),
+            (
+                "1.40.0",
+                Channel::Stable,
+                "cpython",
+                "x86_64-apple-darwin",
+                false,
+            ),
+            (
+                "1.40.0",
+                Channel::Stable,
+                "cpython",
+                "x86_64-unknown-linux-musl",
+                false,
+            ),
+        ];
+
+        for (version, channel, flavor, target, required) in cases {
+            assert_eq!(
+                requirement(version, channel, flavor, target),
+                required,
+                "{} {} {}",
+                version,
+                flavor,
+                target
+            );
+        }
+    }
+
+    #[test]
+    fn test_resolve() {
+        let old = Version::new(1, 40, 0);
+        let target = "x86_64-pc-windows-msvc";
+
+        let reason = resolve_rustc_bootstrap(&old, &Channel::Stable, "cpython", target, true)
+            .unwrap()
+            .unwrap();
+        assert!(reason.contains("static-nobundle"));
+
+        let err = resolve_rustc_bootstrap(&old, &Channel::Stable, "cpython", target, false)
+            .unwrap_err()
+            .to_string();
+        assert!(err.starts_with("RUSTC_BOOTSTRAP=1 is required but not allowed: the cpython"));
+        assert!(err.contains("use Rust 1.61.0 or newer"));
+
+        // Not needed means no error even if not allowed.
+        assert_eq!(
+            resolve_rustc_bootstrap(&old, &Channel::Nightly, "cpython", target, false).unwrap(),
+            None
+        );
+    }
+}
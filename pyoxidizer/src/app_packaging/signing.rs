@@ -0,0 +1,293 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/*!
+Signing produced files with an external command.
+
+Signing is often performed by a separate service (e.g. one backed by an
+HSM). A configured command is invoked on each produced binary and installer
+before anything consumes it, so archives, installers and build manifests
+capture the signed file.
+
+Files are signed in the order they are produced: the executable, then each
+per-architecture MSI, then the bundle installer embedding them.
+*/
+
+use anyhow::{anyhow, Context, Result};
+use slog::warn;
+use std::io::Read;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use super::state::BuildContext;
+use crate::cancel;
+
+/// How often to check whether the signing command has finished.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// How long to wait for the output of the signing command once it exited.
+///
+/// Processes it left behind may keep its output open indefinitely.
+const OUTPUT_GRACE: Duration = Duration::from_secs(5);
+
+/// Resolve the arguments of a signing command for a file.
+fn signing_args(command: &[String], path: &Path) -> Vec<String> {
+    let path = path.display().to_string();
+
+    command
+        .iter()
+        .map(|arg| arg.replace("{path}", &path))
+        .collect()
+}
+
+/// A stream read to completion on a separate thread.
+struct OutputReader {
+    data: Arc<Mutex<Vec<u8>>>,
+    done: mpsc::Receiver<()>,
+}
+
+impl OutputReader {
+    fn new<R: Read + Send + 'static>(reader: Option<R>) -> Self {
+        let data = Arc::new(Mutex::new(Vec::new()));
+        let (sender, done) = mpsc::channel();
+
+        {
+            let data = data.clone();
+            cancel::spawn_thread(move || {
+                if let Some(mut reader) = reader {
+                    let mut buffer = [0; 8192];
+                    while let Ok(count) = reader.read(&mut buffer) {
+                        if count == 0 {
+                            break;
+                        }
+                        data.lock().unwrap().extend_from_slice(&buffer[0..count]);
+                    }
+                }
+                let _ = sender.send(());
+            });
+        }
+
+        OutputReader { data, done }
+    }
+
+    /// Wait until the stream ends or `deadline` passes.
+    ///
+    /// Returns whether the stream ended.
+    fn wait_until(&self, deadline: Instant) -> bool {
+        let now = Instant::now();
+        let timeout = if deadline > now {
+            deadline - now
+        } else {
+            Duration::from_secs(0)
+        };
+
+        self.done.recv_timeout(timeout).is_ok()
+    }
+
+    /// Obtain the data read so far.
+    fn data(&self) -> Vec<u8> {
+        self.data.lock().unwrap().clone()
+    }
+}
+
+/// Sign a file by running a command.
+///
+/// `{path}` in `command` is replaced by the path of the file. The command
+/// runs in `cwd`. It is killed along with its descendants if it doesn't
+/// finish within `timeout`. Failures include the command's output.
+pub fn sign_file(
+    logger: &slog::Logger,
+    command: &[String],
+    cwd: &Path,
+    path: &Path,
+    timeout: Duration,
+) -> Result<()> {
+    let args = signing_args(command, path);
+    let (program, args) = args
+        .split_first()
+        .ok_or_else(|| anyhow!("signing command is empty"))?;
+
+    warn!(logger, "signing {}", path.display());
+    warn!(logger, "running {} {}", program, args.join(" "));
+
+    let mut cmd = Command::new(program);
+    cmd.args(args)
+        .current_dir(cwd)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+
+    let mut child = cancel::spawn_group(&mut cmd)
+        .with_context(|| format!("running signing command {}", program))?;
+
+    let stdout = OutputReader::new(child.child_mut().stdout.take());
+    let stderr = OutputReader::new(child.child_mut().stderr.take());
+
+    let start = Instant::now();
+    let mut timed_out = false;
+
+    while child.child_mut().try_wait()?.is_none() {
+        if start.elapsed() >= timeout {
+            timed_out = true;
+            child.kill();
+            break;
+        }
+
+        std::thread::sleep(POLL_INTERVAL);
+    }
+
+    // Descendants left behind may keep the output open. Kill them rather
+    // than wait for them.
+    let deadline = Instant::now() + OUTPUT_GRACE;
+    if !stdout.wait_until(deadline) || !stderr.wait_until(deadline) {
+        warn!(logger, "killing processes left behind by signing command");
+        child.kill();
+        stdout.wait_until(Instant::now() + OUTPUT_GRACE);
+        stderr.wait_until(Instant::now() + OUTPUT_GRACE);
+    }
+
+    let status = child.wait()?;
+
+    let mut output = stdout.data();
+    output.extend(stderr.data());
+    let output = String::from_utf8_lossy(&output);
+
+    if timed_out {
+        return Err(anyhow!(
+            "signing command for {} timed out after {} seconds; output:\n{}",
+            path.display(),
+            timeout.as_secs(),
+            output.trim_end()
+        ));
+    }
+
+    if !status.success() {
+        return Err(anyhow!(
+            "signing command for {} failed ({}); output:\n{}",
+            path.display(),
+            status,
+            output.trim_end()
+        ));
+    }
+
+    if !path.exists() {
+        return Err(anyhow!(
+            "signing command removed {}; it must sign the file in place",
+            path.display()
+        ));
+    }
+
+    Ok(())
+}
+
+/// Sign a file with the signing command defined by a build's config.
+///
+/// Returns whether the file was signed. Files aren't signed if no signing
+/// command is configured.
+pub fn sign_configured(logger: &slog::Logger, context: &BuildContext, path: &Path) -> Result<bool> {
+    let build_config = &context.config.build_config;
+
+    if build_config.signing_command.is_empty() {
+        return Ok(false);
+    }
+
+    sign_file(
+        logger,
+        &build_config.signing_command,
+        &context.config_parent_path,
+        path,
+        Duration::from_secs(build_config.signing_timeout),
+    )?;
+
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command(values: &[&str]) -> Vec<String> {
+        values.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn test_signing_args() {
+        assert_eq!(
+            signing_args(
+                &command(&["./sign.sh", "{path}", "--out={path}"]),
+                Path::new("/build/myapp")
+            ),
+            command(&["./sign.sh", "/build/myapp", "--out=/build/myapp"])
+        );
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_sign_file() -> Result<()> {
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let temp_dir = tempdir::TempDir::new("pyoxidizer-test")?;
+        let path = temp_dir.path().join("myapp");
+        std::fs::write(&path, b"executable")?;
+
+        sign_file(
+            &logger,
+            &command(&["sh", "-c", "printf signature >> \"$0\"", "{path}"]),
+            temp_dir.path(),
+            &path,
+            Duration::from_secs(30),
+        )?;
+        assert_eq!(std::fs::read(&path)?, b"executablesignature");
+
+        let err = sign_file(
+            &logger,
+            &command(&["sh", "-c", "echo HSM unavailable >&2; exit 3", "{path}"]),
+            temp_dir.path(),
+            &path,
+            Duration::from_secs(30),
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("failed"));
+        assert!(err.to_string().ends_with("output:\nHSM unavailable"));
+
+        let err = sign_file(
+            &logger,
+            &command(&["sh", "-c", "exec sleep 30", "{path}"]),
+            temp_dir.path(),
+            &path,
+            Duration::from_millis(200),
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("timed out"));
+
+        // Descendants outliving the command are killed too, instead of
+        // keeping its output open.
+        let start = Instant::now();
+        let err = sign_file(
+            &logger,
+            &command(&["sh", "-c", "sleep 30 & wait", "{path}"]),
+            temp_dir.path(),
+            &path,
+            Duration::from_millis(200),
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("timed out"));
+        assert!(start.elapsed() < Duration::from_secs(10));
+
+        // Descendants of a successful command keeping its output open are
+        // killed after a grace period.
+        let start = Instant::now();
+        sign_file(
+            &logger,
+            &command(&["sh", "-c", "echo signed; sleep 30 &", "{path}"]),
+            temp_dir.path(),
+            &path,
+            Duration::from_secs(30),
+        )?;
+        assert!(start.elapsed() < Duration::from_secs(10));
+
+        Ok(())
+    }
+}
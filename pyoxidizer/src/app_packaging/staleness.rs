@@ -0,0 +1,636 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/*!
+Deciding whether PyOxidizer artifacts need to be regenerated.
+
+When artifacts are generated, the SHA-256 of every input (the config file,
+the `pyoxidizer` executable and every `cargo:rerun-if-changed` dependency)
+is recorded in an `InputsManifest` in the artifacts directory. Artifacts
+are current if all digests still match. Contents are compared rather than
+modification times, which git checkouts, build caches and CI restores
+don't preserve.
+
+Files loaded by the config file with `load()` are inputs too. A digest of
+the evaluated config is also compared, so changes only affecting the
+result of evaluating it, like an environment variable it reads, are
+noticed as well.
+
+Configs can reference trees with many thousands of files. Paths inside
+another input directory are dropped, both from the manifest and from the
+`cargo:rerun-if-changed` lines, since the directory's digest already
+changes when a file inside is added, removed or modified. Files are hashed
+on several threads.
+
+The reasons artifacts are stale are collected into a `StalenessReport`.
+Callers log it with a single summary line followed by each reason, at info
+level so `--verbose` shows them in release builds too, or inspect it to answer whether a build would do
+anything. Only problems preventing a decision, like unreadable metadata,
+are logged as warnings.
+*/
+
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+use slog::{info, warn};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use super::lockfile::{sha256_paths, sha256_paths_each};
+use crate::environment::path_identity;
+use crate::fsutils::write_file;
+
+/// File name of the inputs manifest in the artifacts directory.
+pub const INPUTS_MANIFEST_FILENAME: &str = "inputs.json";
+
+/// Version of the inputs manifest format.
+const INPUTS_MANIFEST_VERSION: u32 = 1;
+
+/// A reason artifacts need to be regenerated.
+#[derive(Clone, Debug, PartialEq)]
+pub enum StaleReason {
+    /// Regeneration was requested with `--force-artifacts`.
+    Forced,
+    /// No artifacts were generated yet.
+    NoArtifacts,
+    /// The content of an input changed, or it was removed.
+    ContentChanged(PathBuf),
+    /// Evaluating the config had a different result.
+    ConfigChanged,
+    /// The effective RUSTFLAGS changed.
+    RustflagsChanged { old: Vec<String>, new: Vec<String> },
+    /// A file needed to decide couldn't be read.
+    Unreadable { path: PathBuf, error: String },
+}
+
+impl StaleReason {
+    /// The file this reason is about, if it is a changed file.
+    pub fn changed_file(&self) -> Option<&Path> {
+        match self {
+            StaleReason::ContentChanged(path) => Some(path),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for StaleReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StaleReason::Forced => write!(f, "forcing was requested"),
+            StaleReason::NoArtifacts => write!(f, "no existing artifacts found"),
+            StaleReason::ConfigChanged => write!(f, "evaluated config changed"),
+            StaleReason::ContentChanged(path) => {
+                write!(f, "content of {} changed", path.display())
+            }
+            StaleReason::RustflagsChanged { old, new } => write!(
+                f,
+                "effective RUSTFLAGS changed from \"{}\" to \"{}\"",
+                old.join(" "),
+                new.join(" ")
+            ),
+            StaleReason::Unreadable { path, error } => {
+                write!(f, "error reading {}: {}", path.display(), error)
+            }
+        }
+    }
+}
+
+/// Whether artifacts are current and why not.
+#[derive(Clone, Debug, PartialEq)]
+pub struct StalenessReport {
+    /// Directory holding the artifacts.
+    pub artifacts_path: PathBuf,
+    /// Reasons the artifacts are stale, in the order they were found.
+    ///
+    /// Empty if the artifacts are current.
+    pub reasons: Vec<StaleReason>,
+}
+
+impl StalenessReport {
+    pub fn new(artifacts_path: &Path) -> StalenessReport {
+        StalenessReport {
+            artifacts_path: artifacts_path.to_path_buf(),
+            reasons: Vec::new(),
+        }
+    }
+
+    pub fn add(&mut self, reason: StaleReason) {
+        self.reasons.push(reason);
+    }
+
+    /// Whether artifacts need to be regenerated.
+    pub fn is_stale(&self) -> bool {
+        !self.reasons.is_empty()
+    }
+
+    /// Files whose modification made the artifacts stale.
+    pub fn changed_files(&self) -> Vec<&Path> {
+        self.reasons
+            .iter()
+            .filter_map(StaleReason::changed_file)
+            .collect()
+    }
+
+    /// Describe the decision in a single line.
+    pub fn summary(&self) -> String {
+        if !self.is_stale() {
+            return format!(
+                "artifacts current: reusing {} since the config, the files it references, RUSTFLAGS and pyoxidizer are unchanged; use --force-artifacts to regenerate them",
+                self.artifacts_path.display()
+            );
+        }
+
+        let mut parts = Vec::new();
+
+        for reason in &self.reasons {
+            match reason {
+                StaleReason::Forced => parts.push("forced".to_string()),
+                StaleReason::NoArtifacts => parts.push("none generated yet".to_string()),
+                StaleReason::ConfigChanged => parts.push("evaluated config changed".to_string()),
+                StaleReason::RustflagsChanged { .. } => parts.push("RUSTFLAGS changed".to_string()),
+                _ => {}
+            }
+        }
+
+        let changed = self.changed_files();
+        match changed.len() {
+            0 => {}
+            1 => parts.push(format!("1 changed file ({})", changed[0].display())),
+            count => parts.push(format!(
+                "{} changed files (first: {})",
+                count,
+                changed[0].display()
+            )),
+        }
+
+        let unreadable = self
+            .reasons
+            .iter()
+            .filter(|r| match r {
+                StaleReason::Unreadable { .. } => true,
+                _ => false,
+            })
+            .count();
+        match unreadable {
+            0 => {}
+            1 => parts.push("1 unreadable file".to_string()),
+            count => parts.push(format!("{} unreadable files", count)),
+        }
+
+        format!("artifacts stale: {}, rebuilding", parts.join(", "))
+    }
+
+    /// Log the report.
+    ///
+    /// Unreadable files are logged as warnings. Everything else is
+    /// informational.
+    pub fn log(&self, logger: &slog::Logger) {
+        for reason in &self.reasons {
+            if let StaleReason::Unreadable { .. } = reason {
+                warn!(logger, "{}", reason);
+            }
+        }
+
+        info!(logger, "{}", self.summary());
+
+        for reason in &self.reasons {
+            if let StaleReason::Unreadable { .. } = reason {
+                continue;
+            }
+
+            info!(logger, "artifacts stale: {}", reason);
+        }
+    }
+}
+
+const RERUN_IF_CHANGED: &str = "cargo:rerun-if-changed=";
+
+/// Drop paths inside other paths of `paths`.
+///
+/// Only path components are compared; the filesystem isn't accessed.
+pub fn collapse_covered_paths<I: IntoIterator<Item = PathBuf>>(paths: I) -> BTreeSet<PathBuf> {
+    let sorted = paths.into_iter().collect::<BTreeSet<_>>();
+    let mut res = BTreeSet::new();
+    let mut covering: Option<PathBuf> = None;
+
+    // Paths sort by component, so paths inside a directory directly follow it.
+    for path in sorted {
+        if let Some(parent) = &covering {
+            if path.starts_with(parent) {
+                continue;
+            }
+        }
+
+        covering = Some(path.clone());
+        res.insert(path);
+    }
+
+    res
+}
+
+/// Drop paths inside other paths of `paths`, comparing the files they name.
+///
+/// A file reached both through a symlink and through its target is only
+/// kept under the name seen first, so it isn't tracked twice.
+pub fn collapse_aliased_paths<I: IntoIterator<Item = PathBuf>>(paths: I) -> BTreeSet<PathBuf> {
+    let mut names = BTreeMap::new();
+    for path in paths {
+        names.entry(path_identity(&path)).or_insert(path);
+    }
+
+    collapse_covered_paths(names.keys().cloned())
+        .iter()
+        .map(|identity| names[identity].clone())
+        .collect()
+}
+
+/// Replace `cargo:rerun-if-changed` lines for paths inside other
+/// `cargo:rerun-if-changed` paths.
+///
+/// Other lines are kept in order. The remaining `cargo:rerun-if-changed`
+/// lines follow them, sorted.
+pub fn collapse_rerun_if_changed(lines: &[String]) -> Vec<String> {
+    let mut res = Vec::new();
+    let mut paths = Vec::new();
+
+    for line in lines {
+        if line.starts_with(RERUN_IF_CHANGED) {
+            paths.push(PathBuf::from(&line[RERUN_IF_CHANGED.len()..]));
+        } else {
+            res.push(line.clone());
+        }
+    }
+
+    res.extend(
+        collapse_aliased_paths(paths)
+            .into_iter()
+            .map(|path| format!("{}{}", RERUN_IF_CHANGED, path.display())),
+    );
+
+    res
+}
+
+/// Obtain the inputs of artifacts from the lines written to `cargo_metadata.txt`.
+///
+/// These are the `cargo:rerun-if-changed` dependencies, the config file and
+/// the `pyoxidizer` executable, without paths inside other inputs.
+pub fn artifact_inputs(
+    cargo_metadata: &str,
+    config_path: &Path,
+    current_exe: &Path,
+) -> BTreeSet<PathBuf> {
+    let paths = cargo_metadata
+        .lines()
+        .filter(|line| line.starts_with(RERUN_IF_CHANGED))
+        .map(|line| PathBuf::from(&line[RERUN_IF_CHANGED.len()..]))
+        .chain(vec![config_path.to_path_buf(), current_exe.to_path_buf()]);
+
+    collapse_aliased_paths(paths)
+}
+
+/// SHA-256 digests of the inputs artifacts were generated from.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct InputsManifest {
+    pub version: u32,
+    /// Digests of input files and directories.
+    ///
+    /// Directory digests cover the path and content of every file inside.
+    pub digests: BTreeMap<PathBuf, String>,
+}
+
+impl InputsManifest {
+    /// Hash the content of `paths`.
+    pub fn from_paths(paths: &BTreeSet<PathBuf>) -> Result<Self> {
+        Ok(InputsManifest {
+            version: INPUTS_MANIFEST_VERSION,
+            digests: sha256_paths(paths, &[])?,
+        })
+    }
+
+    pub fn from_path(path: &Path) -> Result<Self> {
+        let data = std::fs::read(path).with_context(|| format!("reading {}", path.display()))?;
+
+        let manifest: InputsManifest =
+            serde_json::from_slice(&data).with_context(|| format!("parsing {}", path.display()))?;
+
+        if manifest.version != INPUTS_MANIFEST_VERSION {
+            return Err(anyhow!(
+                "{} has unsupported version {}",
+                path.display(),
+                manifest.version
+            ));
+        }
+
+        Ok(manifest)
+    }
+
+    pub fn write(&self, path: &Path) -> Result<()> {
+        write_file(path, serde_json::to_string_pretty(self)?.as_bytes())
+    }
+
+    /// Add a reason to `report` for every input whose content changed.
+    ///
+    /// Every input is hashed again. Removed inputs are reported as
+    /// changed. Inputs that can't be read are reported as unreadable.
+    ///
+    /// Returns the current digests of inputs that could be hashed, so they
+    /// needn't be hashed again.
+    pub fn compare(&self, report: &mut StalenessReport) -> BTreeMap<PathBuf, String> {
+        let existing = self
+            .digests
+            .keys()
+            .filter(|path| path.exists())
+            .cloned()
+            .collect::<BTreeSet<_>>();
+        let mut current = sha256_paths_each(&existing, &[]);
+        let mut digests = BTreeMap::new();
+
+        for (path, recorded) in &self.digests {
+            match current.remove(path) {
+                None => report.add(StaleReason::ContentChanged(path.clone())),
+                Some(Ok(digest)) => {
+                    if &digest != recorded {
+                        report.add(StaleReason::ContentChanged(path.clone()));
+                    }
+                    digests.insert(path.clone(), digest);
+                }
+                Some(Err(e)) => report.add(StaleReason::Unreadable {
+                    path: path.clone(),
+                    error: format!("{:#}", e),
+                }),
+            }
+        }
+
+        digests
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{generate_tree, tree_metadata};
+    use std::fs;
+
+    #[test]
+    fn test_summary() {
+        let mut report = StalenessReport::new(Path::new("artifacts"));
+        assert!(!report.is_stale());
+        assert!(report
+            .summary()
+            .starts_with("artifacts current: reusing artifacts"));
+
+        report.add(StaleReason::ContentChanged(PathBuf::from("pyoxidizer.bzl")));
+        assert!(report.is_stale());
+        assert_eq!(
+            report.summary(),
+            "artifacts stale: 1 changed file (pyoxidizer.bzl), rebuilding"
+        );
+
+        report.add(StaleReason::ContentChanged(PathBuf::from("src/main.rs")));
+        report.add(StaleReason::ContentChanged(PathBuf::from(
+            "requirements.txt",
+        )));
+        assert_eq!(
+            report.summary(),
+            "artifacts stale: 3 changed files (first: pyoxidizer.bzl), rebuilding"
+        );
+        assert_eq!(
+            report.changed_files(),
+            vec![
+                Path::new("pyoxidizer.bzl"),
+                Path::new("src/main.rs"),
+                Path::new("requirements.txt")
+            ]
+        );
+
+        report.add(StaleReason::Unreadable {
+            path: PathBuf::from("packaging_state.cbor"),
+            error: "truncated".to_string(),
+        });
+        report.add(StaleReason::RustflagsChanged {
+            old: vec![],
+            new: vec!["-C".to_string(), "opt-level=2".to_string()],
+        });
+        assert_eq!(
+            report.summary(),
+            "artifacts stale: RUSTFLAGS changed, 3 changed files (first: pyoxidizer.bzl), 1 unreadable file, rebuilding"
+        );
+        assert_eq!(
+            report.reasons[4].to_string(),
+            "effective RUSTFLAGS changed from \"\" to \"-C opt-level=2\""
+        );
+    }
+
+    #[test]
+    fn test_forced() {
+        let mut report = StalenessReport::new(Path::new("artifacts"));
+        report.add(StaleReason::Forced);
+        assert_eq!(report.summary(), "artifacts stale: forced, rebuilding");
+
+        let mut report = StalenessReport::new(Path::new("artifacts"));
+        report.add(StaleReason::NoArtifacts);
+        assert_eq!(
+            report.summary(),
+            "artifacts stale: none generated yet, rebuilding"
+        );
+
+        let mut report = StalenessReport::new(Path::new("artifacts"));
+        report.add(StaleReason::ConfigChanged);
+        assert_eq!(
+            report.summary(),
+            "artifacts stale: evaluated config changed, rebuilding"
+        );
+    }
+
+    #[test]
+    fn test_artifact_inputs() {
+        let inputs = artifact_inputs(
+            "cargo:rustc-link-lib=static=pythonXY\n\
+             cargo:rerun-if-changed=/project/pyoxidizer.bzl\n\
+             cargo:rerun-if-changed=/project/app\n\
+             cargo:rerun-if-changed=/project/app/__init__.py\n",
+            Path::new("/project/pyoxidizer.bzl"),
+            Path::new("/bin/pyoxidizer"),
+        );
+
+        assert_eq!(
+            inputs.into_iter().collect::<Vec<_>>(),
+            vec![
+                PathBuf::from("/bin/pyoxidizer"),
+                PathBuf::from("/project/app"),
+                PathBuf::from("/project/pyoxidizer.bzl"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_collapse_rerun_if_changed() {
+        let lines = vec![
+            "cargo:rerun-if-changed=/project/pyoxidizer.bzl".to_string(),
+            "cargo:rustc-link-lib=static=pythonXY".to_string(),
+            "cargo:rerun-if-changed=/project/app/util.py".to_string(),
+            "cargo:rerun-if-changed=/project/app".to_string(),
+            "cargo:rerun-if-changed=/project/app/sub/mod.py".to_string(),
+            "cargo:rerun-if-changed=/project/application.py".to_string(),
+            "cargo:rerun-if-changed=/project/app".to_string(),
+        ];
+
+        assert_eq!(
+            collapse_rerun_if_changed(&lines),
+            vec![
+                "cargo:rustc-link-lib=static=pythonXY",
+                "cargo:rerun-if-changed=/project/app",
+                "cargo:rerun-if-changed=/project/application.py",
+                "cargo:rerun-if-changed=/project/pyoxidizer.bzl",
+            ]
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_collapse_aliased_paths() -> Result<()> {
+        let temp_dir = tempdir::TempDir::new("pyoxidizer-test")?;
+        let store = temp_dir.path().join("store");
+        std::fs::create_dir_all(store.join("app"))?;
+        std::fs::write(store.join("app").join("util.py"), b"")?;
+        std::fs::write(store.join("pyoxidizer.bzl"), b"")?;
+        let link = temp_dir.path().join("link");
+        std::os::unix::fs::symlink(&store, &link)?;
+
+        // The config evaluated through the symlink named files by their target.
+        let lines = vec![
+            format!(
+                "cargo:rerun-if-changed={}",
+                link.join("pyoxidizer.bzl").display()
+            ),
+            format!("cargo:rerun-if-changed={}", link.join("app").display()),
+            format!(
+                "cargo:rerun-if-changed={}",
+                store.join("pyoxidizer.bzl").display()
+            ),
+            format!(
+                "cargo:rerun-if-changed={}",
+                store.join("app").join("util.py").display()
+            ),
+        ];
+
+        assert_eq!(
+            collapse_rerun_if_changed(&lines),
+            vec![
+                format!("cargo:rerun-if-changed={}", link.join("app").display()),
+                format!(
+                    "cargo:rerun-if-changed={}",
+                    link.join("pyoxidizer.bzl").display()
+                ),
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_large_tree() -> Result<()> {
+        let temp_dir = tempdir::TempDir::new("pyoxidizer-test")?;
+        let dir = temp_dir.path();
+
+        let config = dir.join("pyoxidizer.bzl");
+        let exe = dir.join("pyoxidizer");
+        let tree = dir.join("app");
+        fs::write(&config, "Config()")?;
+        fs::write(&exe, "binary")?;
+        let files = generate_tree(&tree, 5000)?;
+
+        let inputs = artifact_inputs(&tree_metadata(&tree, &files), &config, &exe);
+        assert_eq!(
+            inputs,
+            vec![config.clone(), exe.clone(), tree.clone()]
+                .into_iter()
+                .collect()
+        );
+        let manifest = InputsManifest::from_paths(&inputs)?;
+
+        let compare = || {
+            let mut report = StalenessReport::new(dir);
+            manifest.compare(&mut report);
+            report.reasons
+        };
+        let changed = vec![StaleReason::ContentChanged(tree.clone())];
+
+        assert!(compare().is_empty());
+
+        let modified = &files[4321];
+        fs::write(modified, "VALUE = None\n")?;
+        assert_eq!(compare(), changed);
+        fs::write(modified, "VALUE = 4321\n")?;
+        assert!(compare().is_empty());
+
+        let added = tree.join("pkg3/sub5/new.py");
+        fs::write(&added, "")?;
+        assert_eq!(compare(), changed);
+        fs::remove_file(&added)?;
+        assert!(compare().is_empty());
+
+        fs::remove_file(&files[17])?;
+        assert_eq!(compare(), changed);
+        fs::write(&files[17], "VALUE = 17\n")?;
+        assert!(compare().is_empty());
+
+        // Renaming changes the digest even though the content is the same.
+        fs::rename(&files[0], tree.join("pkg0/sub0/renamed.py"))?;
+        assert_eq!(compare(), changed);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_inputs_manifest() -> Result<()> {
+        let temp_dir = tempdir::TempDir::new("pyoxidizer-test")?;
+        let dir = temp_dir.path();
+
+        let config = dir.join("pyoxidizer.bzl");
+        let package = dir.join("app");
+        fs::write(&config, "Config()")?;
+        fs::create_dir(&package)?;
+        fs::write(package.join("__init__.py"), "")?;
+
+        let inputs = vec![config.clone(), package.clone()]
+            .into_iter()
+            .collect::<BTreeSet<_>>();
+        let manifest = InputsManifest::from_paths(&inputs)?;
+
+        let manifest_path = dir.join(INPUTS_MANIFEST_FILENAME);
+        manifest.write(&manifest_path)?;
+        let manifest = InputsManifest::from_path(&manifest_path)?;
+
+        let compare = || {
+            let mut report = StalenessReport::new(dir);
+            manifest.compare(&mut report);
+            report.reasons
+        };
+
+        assert!(compare().is_empty());
+
+        // Rewriting identical content, like a git checkout does, isn't a change.
+        let future =
+            filetime::FileTime::from_unix_time(filetime::FileTime::now().unix_seconds() + 3600, 0);
+        filetime::set_file_mtime(&config, future)?;
+        fs::write(package.join("__init__.py"), "")?;
+        assert!(compare().is_empty());
+
+        fs::write(&config, "Config(application_name='app')")?;
+        fs::write(package.join("util.py"), "")?;
+        assert_eq!(
+            compare(),
+            vec![
+                StaleReason::ContentChanged(package.clone()),
+                StaleReason::ContentChanged(config.clone()),
+            ]
+        );
+
+        fs::remove_file(&config)?;
+        assert_eq!(compare()[1], StaleReason::ContentChanged(config));
+
+        Ok(())
+    }
+}
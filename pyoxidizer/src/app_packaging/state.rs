@@ -2,11 +2,17 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
+use anyhow::{anyhow, Result};
 use serde::{Deserialize, Serialize};
 use std::collections::BTreeMap;
 use std::path::PathBuf;
 
 use super::config::Config;
+use super::environment::EvalOptions;
+use super::notices::NoticeComponent;
+use super::toolchain::Toolchain;
+use crate::color::ColorChoice;
+use crate::licensing::LicenseFilterDecision;
 use crate::py_packaging::distribution::LicenseInfo;
 
 /// Holds state needed to perform packaging.
@@ -17,6 +23,176 @@ use crate::py_packaging::distribution::LicenseInfo;
 pub struct PackagingState {
     pub license_files_path: Option<String>,
     pub license_infos: BTreeMap<String, Vec<LicenseInfo>>,
+    pub rustflags: Vec<String>,
+    /// SHA-256 of files and directories referenced by the configuration.
+    pub input_digests: BTreeMap<PathBuf, String>,
+    /// SHA-256 of the evaluated configuration.
+    #[serde(default)]
+    pub config_digest: String,
+    /// Extension module variants removed by `extension_module_license_filter()`.
+    #[serde(default)]
+    pub license_filter_decisions: Vec<LicenseFilterDecision>,
+    /// Third-party software in the application, for license notices.
+    #[serde(default)]
+    pub notice_components: Vec<NoticeComponent>,
+}
+
+/// How much output a build produces.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Verbosity {
+    /// Only errors and the path of what was built.
+    Quiet,
+    Normal,
+    /// Debug messages and verbose cargo output.
+    Verbose,
+}
+
+impl Default for Verbosity {
+    fn default() -> Self {
+        Verbosity::Normal
+    }
+}
+
+impl Verbosity {
+    pub fn from_flags(quiet: bool, verbose: bool) -> Verbosity {
+        if quiet {
+            Verbosity::Quiet
+        } else if verbose {
+            Verbosity::Verbose
+        } else {
+            Verbosity::Normal
+        }
+    }
+
+    /// Minimum level of messages to log.
+    pub fn log_level(self) -> slog::Level {
+        match self {
+            Verbosity::Quiet => slog::Level::Error,
+            Verbosity::Normal => slog::Level::Warning,
+            Verbosity::Verbose => slog::Level::Debug,
+        }
+    }
+
+    /// Arguments controlling the output of cargo.
+    pub fn cargo_args(self) -> &'static [&'static str] {
+        match self {
+            Verbosity::Quiet => &["-q"],
+            Verbosity::Normal => &[],
+            Verbosity::Verbose => &["-vv"],
+        }
+    }
+}
+
+/// Options of `cargo build` PyOxidizer sets itself, and what to do instead.
+const RESERVED_CARGO_OPTIONS: &[(&str, &str)] = &[
+    ("--target", "use `pyoxidizer build --target`"),
+    (
+        "--target-dir",
+        "the target directory is in the build directory of the config",
+    ),
+    (
+        "--manifest-path",
+        "pass the project path to `pyoxidizer build`",
+    ),
+    ("--release", "use `pyoxidizer build --release`"),
+    (
+        "--message-format",
+        "PyOxidizer reads cargo's messages; use `pyoxidizer build --message-format`",
+    ),
+    ("--color", "use `pyoxidizer --color`"),
+];
+
+/// Arguments to `cargo build` given by the user.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ExtraCargoArgs {
+    /// Arguments passed to cargo as is.
+    pub args: Vec<String>,
+
+    /// Features to enable in addition to those PyOxidizer enables.
+    pub features: Vec<String>,
+}
+
+impl ExtraCargoArgs {
+    /// Parse arguments given after `--`.
+    ///
+    /// Features are collected so they can be merged with features PyOxidizer
+    /// enables. Options PyOxidizer sets itself are rejected.
+    pub fn parse(args: &[&str]) -> Result<Self> {
+        let mut res = ExtraCargoArgs::default();
+        let mut args = args.iter();
+
+        while let Some(arg) = args.next() {
+            let (name, value) = match arg.find('=') {
+                Some(pos) if arg.starts_with("--") => (&arg[..pos], Some(&arg[pos + 1..])),
+                _ => (*arg, None),
+            };
+
+            if let Some((_, hint)) = RESERVED_CARGO_OPTIONS.iter().find(|(o, _)| *o == name) {
+                return Err(anyhow!(
+                    "cargo argument {} conflicts with PyOxidizer; {}",
+                    name,
+                    hint
+                ));
+            }
+
+            if name == "--features" {
+                let value = match value {
+                    Some(value) => value,
+                    None => args
+                        .next()
+                        .ok_or_else(|| anyhow!("cargo argument --features requires a value"))?,
+                };
+
+                // Cargo accepts features separated by commas or spaces.
+                for feature in value.split(|c| c == ',' || c == ' ') {
+                    if !feature.is_empty() && !res.features.iter().any(|f| f == feature) {
+                        res.features.push(feature.to_string());
+                    }
+                }
+            } else {
+                res.args.push(arg.to_string());
+            }
+        }
+
+        Ok(res)
+    }
+}
+
+/// How `projectmgmt::build()` builds a project.
+#[derive(Clone, Debug, Default)]
+pub struct BuildOptions {
+    /// Whether to stop building targets after the first failing one.
+    pub fail_fast: bool,
+
+    /// Whether to build with the release profile.
+    pub release: bool,
+
+    /// How much output to produce.
+    pub verbosity: Verbosity,
+
+    /// Whether to verify the inputs of the config against its lockfile.
+    pub locked: bool,
+
+    /// Options the config is evaluated with.
+    pub eval_options: EvalOptions,
+
+    /// Whether warnings in generated code fail the build.
+    pub deny_generated_warnings: bool,
+
+    /// Whether to regenerate PyOxidizer artifacts even if they are current.
+    pub force_artifacts: bool,
+
+    /// Whether to make cargo rebuild the project even if it is current.
+    pub force_cargo: bool,
+
+    /// Whether to skip checking for free disk space before expensive phases.
+    pub skip_space_check: bool,
+
+    /// Whether to write third-party license notices, even if the config doesn't.
+    pub third_party_notices: bool,
+
+    /// Arguments to `cargo build` given by the user.
+    pub extra_cargo_args: ExtraCargoArgs,
 }
 
 /// Represents environment for a build.
@@ -36,8 +212,17 @@ pub struct BuildContext {
     /// Parsed Cargo.toml for Rust project.
     pub cargo_config: cargo_toml::Manifest,
 
-    /// Whether to operate in verbose mode.
-    pub verbose: bool,
+    /// Whether the project builds a shared library rather than an executable.
+    pub library: bool,
+
+    /// How much output to produce.
+    pub verbosity: Verbosity,
+
+    /// Whether cargo uses colors. Never `Auto`.
+    pub cargo_color: ColorChoice,
+
+    /// Arguments to `cargo build` given by the user.
+    pub extra_cargo_args: ExtraCargoArgs,
 
     /// Path to main build directory where all state is stored.
     pub build_path: PathBuf,
@@ -48,6 +233,12 @@ pub struct BuildContext {
     /// Path containing build/packaged application and all supporting files.
     pub app_path: PathBuf,
 
+    /// Output layout `app_path` was resolved from.
+    pub output_layout: String,
+
+    /// Whether to write third-party license notices when packaging.
+    pub third_party_notices: bool,
+
     /// Path to application executable in its installed/packaged directory.
     pub app_exe_path: PathBuf,
 
@@ -63,6 +254,37 @@ pub struct BuildContext {
     /// Whether compiling a release build.
     pub release: bool,
 
+    /// Effective flags to pass to rustc via `RUSTFLAGS`.
+    pub rustflags: Vec<String>,
+
+    /// Whether to set `RUSTC_BOOTSTRAP=1` when invoking cargo.
+    ///
+    /// Resolved before cargo is invoked.
+    pub rustc_bootstrap: bool,
+
+    /// Rust toolchain cargo is invoked with.
+    pub toolchain: Toolchain,
+
+    /// Whether warnings in generated code fail the build.
+    pub deny_generated_warnings: bool,
+
+    /// Whether to regenerate PyOxidizer artifacts even if they are current.
+    pub force_artifacts: bool,
+
+    /// Whether to make cargo rebuild the project even if it is current.
+    pub force_cargo: bool,
+
+    /// Whether to skip checking for free disk space before expensive phases.
+    pub skip_space_check: bool,
+
+    /// Whether to build without network access.
+    ///
+    /// Python distributions come from the distribution cache.
+    pub offline: bool,
+
+    /// Build variables given with `--var`, defined for cargo.
+    pub build_vars: BTreeMap<String, String>,
+
     /// Main output path for Rust build artifacts.
     ///
     /// Should be passed as --target to cargo build.
@@ -83,6 +305,12 @@ pub struct BuildContext {
     /// Path where PyOxidizer should write its build artifacts.
     pub pyoxidizer_artifacts_path: PathBuf,
 
+    /// Artifacts directory shared by PyOxidizer versions.
+    ///
+    /// `pyoxidizer_artifacts_path` is a subdirectory of it if another
+    /// PyOxidizer version owns it.
+    pub pyoxidizer_artifacts_root_path: PathBuf,
+
     /// State used for packaging.
     pub packaging_state: Option<PackagingState>,
 }
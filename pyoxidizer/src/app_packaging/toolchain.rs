@@ -0,0 +1,255 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/*!
+The Rust toolchain projects are built with.
+
+By default, `cargo` and `rustc` are whatever is found on `PATH`, which
+honors rustup's `rust-toolchain` files and overrides. A rustup toolchain
+name makes builds invoke `cargo +<toolchain>`. A path to a `cargo`
+executable makes builds invoke it, with the `rustc` next to it.
+
+The Rust version check and the decision to set `RUSTC_BOOTSTRAP` query the
+`rustc` of the same toolchain, so they can't be satisfied by a different
+compiler than the one cargo builds with.
+*/
+
+use anyhow::{anyhow, Context, Result};
+use lazy_static::lazy_static;
+use rustc_version::VersionMeta;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::Mutex;
+
+lazy_static! {
+    /// Toolchain given with `--toolchain`.
+    static ref OVERRIDE: Mutex<Option<Toolchain>> = Mutex::new(None);
+}
+
+/// Build with a toolchain regardless of the config.
+pub fn override_toolchain(toolchain: Toolchain) {
+    *OVERRIDE.lock().unwrap() = Some(toolchain);
+}
+
+/// Resolve the toolchain to build with, given the config's setting.
+pub fn resolve_toolchain(config: Option<&Toolchain>) -> Toolchain {
+    match &*OVERRIDE.lock().unwrap() {
+        Some(toolchain) => toolchain.clone(),
+        None => config.cloned().unwrap_or(Toolchain::Default),
+    }
+}
+
+/// A Rust toolchain.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub enum Toolchain {
+    /// `cargo` and `rustc` on `PATH`.
+    Default,
+    /// A rustup toolchain, e.g. `nightly-2020-01-01`.
+    Rustup(String),
+    /// A `cargo` executable.
+    Cargo(PathBuf),
+}
+
+impl fmt::Display for Toolchain {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Toolchain::Default => write!(f, "default"),
+            Toolchain::Rustup(name) => write!(f, "+{}", name),
+            Toolchain::Cargo(path) => write!(f, "{}", path.display()),
+        }
+    }
+}
+
+impl Toolchain {
+    /// Parse a toolchain given by a user.
+    ///
+    /// Values containing a path separator are paths to a `cargo`
+    /// executable, resolved against `base` if relative. Others are rustup
+    /// toolchain names, optionally prefixed with `+`.
+    pub fn parse(value: &str, base: &Path) -> Result<Toolchain> {
+        if value.contains('/') || value.contains(std::path::MAIN_SEPARATOR) {
+            let path = base.join(value);
+
+            if !path.is_file() {
+                return Err(anyhow!(
+                    "cargo executable {} does not exist",
+                    path.display()
+                ));
+            }
+
+            return Ok(Toolchain::Cargo(path));
+        }
+
+        let name = value.trim_start_matches('+');
+        let valid = !name.is_empty()
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.');
+        if !valid {
+            return Err(anyhow!(
+                "{} is not a rustup toolchain name or a path to cargo",
+                value
+            ));
+        }
+
+        Ok(Toolchain::Rustup(name.to_string()))
+    }
+
+    /// The `cargo` executable to run.
+    pub fn cargo_program(&self) -> PathBuf {
+        match self {
+            Toolchain::Cargo(path) => path.clone(),
+            _ => PathBuf::from("cargo"),
+        }
+    }
+
+    /// Arguments selecting the toolchain, which precede the cargo command.
+    pub fn cargo_args(&self) -> Vec<String> {
+        match self {
+            Toolchain::Rustup(name) => vec![format!("+{}", name)],
+            _ => Vec::new(),
+        }
+    }
+
+    /// The `rustc` of this toolchain when it isn't selected by rustup.
+    ///
+    /// Cargo uses `RUSTC` if set. Otherwise, the `rustc` next to an explicit
+    /// `cargo` is used, so cargo builds with it rather than with the
+    /// `rustc` on `PATH`.
+    pub fn rustc_path(&self) -> Option<PathBuf> {
+        if let Ok(rustc) = std::env::var("RUSTC") {
+            return Some(PathBuf::from(rustc));
+        }
+
+        match self {
+            Toolchain::Cargo(path) => {
+                let rustc = path.with_file_name(format!("rustc{}", std::env::consts::EXE_SUFFIX));
+
+                if rustc.is_file() {
+                    Some(rustc)
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        }
+    }
+
+    /// Environment variables to set for cargo so it uses this toolchain.
+    pub fn cargo_envs(&self) -> Vec<(&'static str, String)> {
+        match (self, self.rustc_path()) {
+            (Toolchain::Cargo(_), Some(rustc)) => vec![("RUSTC", rustc.display().to_string())],
+            _ => Vec::new(),
+        }
+    }
+
+    /// A command running `cargo` of this toolchain.
+    pub fn cargo_command(&self) -> Command {
+        let mut command = Command::new(self.cargo_program());
+        command.args(self.cargo_args());
+
+        command
+    }
+
+    /// A command running `rustc` of this toolchain.
+    pub fn rustc_command(&self) -> Command {
+        match (self, self.rustc_path()) {
+            (_, Some(rustc)) => Command::new(rustc),
+            (Toolchain::Rustup(name), None) => {
+                let mut command = Command::new("rustc");
+                command.arg(format!("+{}", name));
+                command
+            }
+            _ => Command::new("rustc"),
+        }
+    }
+
+    /// Obtain the version of this toolchain's `rustc`.
+    ///
+    /// `cwd` is the project directory, so toolchain overrides of the
+    /// project are honored.
+    pub fn version_meta(&self, cwd: &Path) -> Result<VersionMeta> {
+        let output = self
+            .rustc_command()
+            .arg("-vV")
+            .current_dir(cwd)
+            .output()
+            .with_context(|| format!("running rustc of toolchain {}; is Rust installed?", self))?;
+
+        if !output.status.success() {
+            return Err(anyhow!(
+                "rustc of toolchain {} failed: {}",
+                self,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ));
+        }
+
+        rustc_version::version_meta_for(&String::from_utf8_lossy(&output.stdout)).map_err(|e| {
+            anyhow!(
+                "unable to parse rustc version of toolchain {}: {:?}",
+                self,
+                e
+            )
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() -> Result<()> {
+        let temp_dir = tempdir::TempDir::new("pyoxidizer-test")?;
+        let cargo = temp_dir.path().join("bin").join("cargo");
+        std::fs::create_dir_all(cargo.parent().unwrap())?;
+        std::fs::write(&cargo, b"")?;
+
+        assert_eq!(
+            Toolchain::parse("nightly-2020-01-01", temp_dir.path())?,
+            Toolchain::Rustup("nightly-2020-01-01".to_string())
+        );
+        assert_eq!(
+            Toolchain::parse("+1.41.0", temp_dir.path())?,
+            Toolchain::Rustup("1.41.0".to_string())
+        );
+        assert_eq!(
+            Toolchain::parse("bin/cargo", temp_dir.path())?,
+            Toolchain::Cargo(cargo)
+        );
+
+        assert!(Toolchain::parse("bin/missing", temp_dir.path()).is_err());
+        assert!(Toolchain::parse("+", temp_dir.path()).is_err());
+        assert!(Toolchain::parse("nightly build", temp_dir.path()).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_cargo_args() {
+        let toolchain = Toolchain::Rustup("nightly".to_string());
+        assert_eq!(toolchain.cargo_program(), PathBuf::from("cargo"));
+        assert_eq!(toolchain.cargo_args(), vec!["+nightly"]);
+        assert_eq!(toolchain.to_string(), "+nightly");
+
+        let toolchain = Toolchain::Cargo(PathBuf::from("/opt/rust/bin/cargo"));
+        assert_eq!(
+            toolchain.cargo_program(),
+            PathBuf::from("/opt/rust/bin/cargo")
+        );
+        assert!(toolchain.cargo_args().is_empty());
+
+        assert!(Toolchain::Default.cargo_args().is_empty());
+    }
+
+    #[test]
+    fn test_version_meta() -> Result<()> {
+        let meta = Toolchain::Default.version_meta(Path::new("."))?;
+        assert_eq!(meta, rustc_version::version_meta()?);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,125 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/*!
+Machine-readable events of `build` and `build-artifacts`.
+
+With `--message-format json`, events are printed to stdout as lines of
+JSON. Each is an object whose `reason` field names the event, like the
+messages of `cargo build --message-format json`. Output meant for humans,
+including PyOxidizer's log, goes to stderr instead, so stdout only holds
+events.
+
+Without `--message-format json`, emitting events does nothing.
+*/
+
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static JSON_MESSAGES: AtomicBool = AtomicBool::new(false);
+
+/// Print events as JSON for the remainder of the process.
+pub fn use_json_messages() {
+    JSON_MESSAGES.store(true, Ordering::SeqCst);
+}
+
+/// Whether stdout is reserved for JSON events.
+pub fn json_messages() -> bool {
+    JSON_MESSAGES.load(Ordering::SeqCst)
+}
+
+/// An event of a build.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(tag = "reason", rename_all = "kebab-case")]
+pub enum BuildEvent {
+    /// PyOxidizer artifacts are being generated.
+    ArtifactsStarted {
+        target: String,
+        artifacts_path: PathBuf,
+    },
+    /// PyOxidizer artifacts are ready.
+    ///
+    /// `regenerated` is false if existing artifacts were current.
+    ArtifactsFinished {
+        target: String,
+        artifacts_path: PathBuf,
+        regenerated: bool,
+    },
+    /// `cargo` is about to be run.
+    CargoInvocation {
+        target: String,
+        args: Vec<String>,
+        env: BTreeMap<String, String>,
+        cwd: PathBuf,
+    },
+    /// A step of packaging the built application wrote `path`.
+    PackagingStep { step: String, path: PathBuf },
+    /// Building for a target completed.
+    ///
+    /// The application paths are absent for `build-artifacts`.
+    BuildFinished {
+        target: String,
+        release: bool,
+        artifacts_path: PathBuf,
+        app_path: Option<PathBuf>,
+        app_exe_path: Option<PathBuf>,
+    },
+}
+
+/// Serialize an event as a line of JSON.
+pub fn event_line(event: &BuildEvent) -> String {
+    serde_json::to_string(event).expect("events should always serialize")
+}
+
+/// Print an event if JSON events were requested.
+pub fn emit(event: BuildEvent) {
+    if json_messages() {
+        let stdout = std::io::stdout();
+        let mut stdout = stdout.lock();
+        // Events are consumed as they happen, so don't let them sit in a buffer.
+        let _ = writeln!(stdout, "{}", event_line(&event));
+        let _ = stdout.flush();
+    }
+}
+
+/// Print a line of output meant for humans.
+///
+/// It goes to stdout unless stdout is reserved for JSON events.
+pub fn print_human(line: &str) {
+    if json_messages() {
+        eprintln!("{}", line);
+    } else {
+        println!("{}", line);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_event_line() {
+        assert_eq!(
+            event_line(&BuildEvent::PackagingStep {
+                step: "copy-executable".to_string(),
+                path: PathBuf::from("build/apps/myapp/myapp"),
+            }),
+            r#"{"reason":"packaging-step","step":"copy-executable","path":"build/apps/myapp/myapp"}"#
+        );
+
+        assert_eq!(
+            event_line(&BuildEvent::BuildFinished {
+                target: "x86_64-unknown-linux-gnu".to_string(),
+                release: true,
+                artifacts_path: PathBuf::from("build/artifacts"),
+                app_path: None,
+                app_exe_path: None,
+            }),
+            r#"{"reason":"build-finished","target":"x86_64-unknown-linux-gnu","release":true,"artifacts_path":"build/artifacts","app_path":null,"app_exe_path":null}"#
+        );
+    }
+}
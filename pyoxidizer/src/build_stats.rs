@@ -0,0 +1,385 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/*!
+Local statistics about invocations, for tracking build performance.
+
+With `--stats-file PATH`, a `BuildStats` record is appended to `PATH` as a
+line of JSON when the invocation ends, whether it succeeded or failed. It
+holds how long each phase took, how often caches were used, whether
+artifacts were regenerated, the size of produced artifacts and the error
+category of failures. The file is only ever written locally; nothing is
+sent anywhere.
+
+Statistics are collected by the thread running the command. Without
+`--stats-file`, recording does nothing.
+*/
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use crate::app_packaging::staleness::StalenessReport;
+use crate::cancel::is_cancelled_error;
+use crate::environment::BUILD_SEMVER;
+use crate::exit_status::{error_category, ErrorCategory};
+
+/// Version of the `BuildStats` schema.
+///
+/// Incremented when fields are removed or change meaning. Fields may be
+/// added without changing it.
+pub const STATS_SCHEMA_VERSION: u32 = 1;
+
+/// Wall time of a phase of the invocation.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct PhaseTiming {
+    /// E.g. `evaluate config`, `resolve distribution` or `cargo build`.
+    pub name: String,
+    pub seconds: f64,
+}
+
+/// How often a cache was used.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+/// Use of the caches PyOxidizer relies on.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct CachesStats {
+    /// Python distribution archives, downloaded or copied from a local path.
+    pub distribution: CacheStats,
+    /// Bytecode in the machine-global cache.
+    pub bytecode: CacheStats,
+    /// Packages pip reported as cached or downloaded.
+    pub pip: CacheStats,
+}
+
+/// The outcome of deciding whether artifacts were current.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct StalenessStats {
+    pub artifacts_path: PathBuf,
+    /// Whether the artifacts were regenerated.
+    pub stale: bool,
+    /// Number of reasons the artifacts were stale.
+    pub reasons: usize,
+}
+
+/// A file produced by the invocation.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct ArtifactSize {
+    pub path: PathBuf,
+    /// Size in bytes.
+    pub size: u64,
+}
+
+/// Statistics about one invocation, written as a line of JSON.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct BuildStats {
+    /// Version of this schema, `STATS_SCHEMA_VERSION`.
+    pub schema_version: u32,
+    /// The PyOxidizer version.
+    pub pyoxidizer_version: String,
+    /// The subcommand, e.g. `build`. Empty if the command line was invalid.
+    pub command: String,
+    /// When the invocation started, in seconds since the Unix epoch.
+    pub started: u64,
+    /// Wall time of the whole invocation.
+    pub duration_seconds: f64,
+    /// Phases in the order they finished.
+    ///
+    /// `generate artifacts` includes `resolve distribution`, `resolve
+    /// resources` and `link libpython`. Phases run once per target.
+    pub phases: Vec<PhaseTiming>,
+    pub caches: CachesStats,
+    /// One entry for every artifacts directory checked.
+    pub staleness: Vec<StalenessStats>,
+    /// Executables and libraries produced.
+    pub artifacts: Vec<ArtifactSize>,
+    /// `ok`, `error` or `cancelled`.
+    pub status: String,
+    /// Category of the error on failure, e.g. `compile`, or `other`.
+    pub error_category: Option<String>,
+    pub exit_code: i32,
+}
+
+/// A cache whose use is counted.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Cache {
+    Distribution,
+    Bytecode,
+    Pip,
+}
+
+struct Recorder {
+    path: PathBuf,
+    started: Instant,
+    stats: BuildStats,
+}
+
+thread_local! {
+    static RECORDER: RefCell<Option<Recorder>> = RefCell::new(None);
+}
+
+fn with_stats<F: FnOnce(&mut BuildStats)>(f: F) {
+    RECORDER.with(|recorder| {
+        if let Some(recorder) = recorder.borrow_mut().as_mut() {
+            f(&mut recorder.stats);
+        }
+    });
+}
+
+/// Start collecting statistics to be written to `path`.
+pub fn start(path: &Path) {
+    let started = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+
+    RECORDER.with(|recorder| {
+        *recorder.borrow_mut() = Some(Recorder {
+            path: path.to_path_buf(),
+            started: Instant::now(),
+            stats: BuildStats {
+                schema_version: STATS_SCHEMA_VERSION,
+                pyoxidizer_version: BUILD_SEMVER.to_string(),
+                started,
+                ..BuildStats::default()
+            },
+        })
+    });
+}
+
+/// Record the subcommand being run.
+pub fn set_command(command: &str) {
+    with_stats(|stats| stats.command = command.to_string());
+}
+
+/// Times a phase until dropped.
+pub struct PhaseTimer {
+    name: &'static str,
+    started: Instant,
+}
+
+impl Drop for PhaseTimer {
+    fn drop(&mut self) {
+        let seconds = duration_seconds(self.started);
+        let name = self.name;
+
+        with_stats(|stats| {
+            stats.phases.push(PhaseTiming {
+                name: name.to_string(),
+                seconds,
+            })
+        });
+    }
+}
+
+/// Time a phase until the returned value is dropped.
+pub fn phase(name: &'static str) -> PhaseTimer {
+    PhaseTimer {
+        name,
+        started: Instant::now(),
+    }
+}
+
+fn duration_seconds(started: Instant) -> f64 {
+    let elapsed = started.elapsed();
+    elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9
+}
+
+/// Record hits and misses of a cache.
+pub fn record_cache(cache: Cache, hits: u64, misses: u64) {
+    with_stats(|stats| {
+        let counts = match cache {
+            Cache::Distribution => &mut stats.caches.distribution,
+            Cache::Bytecode => &mut stats.caches.bytecode,
+            Cache::Pip => &mut stats.caches.pip,
+        };
+
+        counts.hits += hits;
+        counts.misses += misses;
+    });
+}
+
+/// Record whether artifacts were current.
+pub fn record_staleness(report: &StalenessReport) {
+    with_stats(|stats| {
+        stats.staleness.push(StalenessStats {
+            artifacts_path: report.artifacts_path.clone(),
+            stale: report.is_stale(),
+            reasons: report.reasons.len(),
+        })
+    });
+}
+
+/// Record the size of a produced file.
+pub fn record_artifact(path: &Path) {
+    if let Ok(metadata) = std::fs::metadata(path) {
+        with_stats(|stats| {
+            stats.artifacts.push(ArtifactSize {
+                path: path.to_path_buf(),
+                size: metadata.len(),
+            })
+        });
+    }
+}
+
+/// Fill in the outcome of the invocation.
+fn complete(stats: &mut BuildStats, result: &Result<()>, exit_code: i32) {
+    stats.exit_code = exit_code;
+
+    match result {
+        Ok(()) => stats.status = "ok".to_string(),
+        Err(e) if is_cancelled_error(e) => stats.status = "cancelled".to_string(),
+        Err(e) => {
+            stats.status = "error".to_string();
+            stats.error_category = Some(
+                error_category(e)
+                    .map(ErrorCategory::as_str)
+                    .unwrap_or("other")
+                    .to_string(),
+            );
+        }
+    }
+}
+
+/// Append a record to a statistics file.
+pub fn append_stats(path: &Path, stats: &BuildStats) -> Result<()> {
+    let mut line = serde_json::to_string(stats)?;
+    line.push('\n');
+
+    let mut fh = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("opening {}", path.display()))?;
+
+    // A single write keeps records of concurrent invocations apart.
+    fh.write_all(line.as_bytes())
+        .with_context(|| format!("writing {}", path.display()))
+}
+
+/// Write the statistics of the invocation, if collecting them.
+pub fn finish(result: &Result<()>, exit_code: i32) -> Result<()> {
+    let recorder = match RECORDER.with(|recorder| recorder.borrow_mut().take()) {
+        Some(recorder) => recorder,
+        None => return Ok(()),
+    };
+
+    let mut stats = recorder.stats;
+    stats.duration_seconds = duration_seconds(recorder.started);
+    complete(&mut stats, result, exit_code);
+
+    append_stats(&recorder.path, &stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app_packaging::staleness::StaleReason;
+    use crate::exit_status::categorize;
+    use anyhow::anyhow;
+
+    fn read_records(path: &Path) -> Result<Vec<BuildStats>> {
+        std::fs::read_to_string(path)?
+            .lines()
+            .map(|line| Ok(serde_json::from_str(line)?))
+            .collect()
+    }
+
+    #[test]
+    fn test_stats_file() -> Result<()> {
+        let temp_dir = tempdir::TempDir::new("pyoxidizer-test")?;
+        let path = temp_dir.path().join("stats.jsonl");
+        let exe = temp_dir.path().join("myapp");
+        std::fs::write(&exe, b"binary")?;
+
+        // Nothing is recorded without a stats file.
+        set_command("build");
+        record_cache(Cache::Pip, 1, 0);
+        finish(&Ok(()), 0)?;
+        assert!(!path.exists());
+
+        start(&path);
+        set_command("build");
+        {
+            let _phase = phase("evaluate config");
+        }
+        record_cache(Cache::Distribution, 1, 0);
+        record_cache(Cache::Bytecode, 10, 2);
+        record_cache(Cache::Bytecode, 0, 1);
+        let mut report = StalenessReport::new(temp_dir.path());
+        report.add(StaleReason::NoArtifacts);
+        record_staleness(&report);
+        record_artifact(&exe);
+        record_artifact(&temp_dir.path().join("missing"));
+        finish(&Ok(()), 0)?;
+
+        start(&path);
+        set_command("run");
+        finish(
+            &Err(categorize(
+                anyhow!("cargo build failed"),
+                ErrorCategory::Compile,
+            )),
+            13,
+        )?;
+
+        let records = read_records(&path)?;
+        assert_eq!(records.len(), 2);
+
+        let ok = &records[0];
+        assert_eq!(ok.schema_version, STATS_SCHEMA_VERSION);
+        assert_eq!(ok.command, "build");
+        assert_eq!(ok.status, "ok");
+        assert_eq!(ok.error_category, None);
+        assert_eq!(
+            ok.phases
+                .iter()
+                .map(|p| p.name.as_str())
+                .collect::<Vec<_>>(),
+            vec!["evaluate config"]
+        );
+        assert_eq!(
+            ok.caches,
+            CachesStats {
+                distribution: CacheStats { hits: 1, misses: 0 },
+                bytecode: CacheStats {
+                    hits: 10,
+                    misses: 3
+                },
+                pip: CacheStats::default(),
+            }
+        );
+        assert_eq!(
+            ok.staleness,
+            vec![StalenessStats {
+                artifacts_path: temp_dir.path().to_path_buf(),
+                stale: true,
+                reasons: 1,
+            }]
+        );
+        assert_eq!(
+            ok.artifacts,
+            vec![ArtifactSize {
+                path: exe.clone(),
+                size: 6
+            }]
+        );
+
+        let failed = &records[1];
+        assert_eq!(failed.command, "run");
+        assert_eq!(failed.status, "error");
+        assert_eq!(failed.error_category, Some("compile".to_string()));
+        assert_eq!(failed.exit_code, 13);
+        assert!(failed.phases.is_empty());
+
+        Ok(())
+    }
+}
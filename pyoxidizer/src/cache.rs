@@ -0,0 +1,440 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/*!
+The machine-global cache.
+
+Artifacts that are identical across projects, like bytecode of the standard
+library, are cached in a directory shared by all projects of a user. It is
+`PYOXIDIZER_CACHE_DIR` if set. Otherwise it is `%LOCALAPPDATA%\pyoxidizer`
+on Windows, `~/Library/Caches/pyoxidizer` on macOS and
+`$XDG_CACHE_HOME/pyoxidizer` or `~/.cache/pyoxidizer` elsewhere.
+
+Python distributions are kept in its `distributions` directory, or in
+`PYOXIDIZER_DISTRIBUTION_CACHE` if set. Each distribution has a directory
+named after the SHA-256 of its archive, holding the archive and the
+extracted distribution, which all projects build with. Archives are
+downloaded there or added with `pyoxidizer cache add` for `--offline`
+builds. A lock file in the directory keeps concurrent builds from racing
+to extract or change the distribution.
+
+Entries are written atomically and cache files may be deleted at any time,
+so concurrent builds can share the cache. `pyoxidizer cache list` shows
+entries and `pyoxidizer cache purge` deletes them.
+*/
+
+use anyhow::{anyhow, Context, Result};
+use fs2::FileExt;
+use slog::warn;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use crate::app_packaging::disk_space::format_mb;
+use crate::app_packaging::environment::home_dir;
+use crate::py_packaging::bytecode_cache::{BytecodeCache, PruneStats};
+use crate::py_packaging::distribution::{
+    cache_local_distribution, EXTRACTED_DIR, EXTRACT_LOCK_FILENAME, LAST_USED_FILENAME,
+};
+
+/// Environment variable overriding the cache directory.
+pub const CACHE_DIR_ENV: &str = "PYOXIDIZER_CACHE_DIR";
+
+/// Environment variable overriding the distribution cache directory.
+pub const DISTRIBUTION_CACHE_ENV: &str = "PYOXIDIZER_DISTRIBUTION_CACHE";
+
+/// Directory of the cache holding Python distributions.
+const DISTRIBUTIONS_DIR: &str = "distributions";
+
+fn non_empty_env(name: &str) -> Option<PathBuf> {
+    match std::env::var_os(name) {
+        Some(value) if !value.is_empty() => Some(PathBuf::from(value)),
+        _ => None,
+    }
+}
+
+/// Resolve the machine-global cache directory.
+///
+/// Returns `None` if no directory could be determined, in which case
+/// nothing is cached.
+pub fn cache_dir() -> Option<PathBuf> {
+    if let Some(path) = non_empty_env(CACHE_DIR_ENV) {
+        return Some(path);
+    }
+
+    if cfg!(windows) {
+        non_empty_env("LOCALAPPDATA").map(|p| p.join("pyoxidizer"))
+    } else if cfg!(target_os = "macos") {
+        home_dir().map(|p| p.join("Library").join("Caches").join("pyoxidizer"))
+    } else {
+        non_empty_env("XDG_CACHE_HOME")
+            .or_else(|| home_dir().map(|p| p.join(".cache")))
+            .map(|p| p.join("pyoxidizer"))
+    }
+}
+
+/// Resolve the directory Python distributions are cached in.
+pub fn distribution_cache_dir() -> Option<PathBuf> {
+    non_empty_env(DISTRIBUTION_CACHE_ENV).or_else(|| cache_dir().map(|p| p.join(DISTRIBUTIONS_DIR)))
+}
+
+/// Add a Python distribution archive to the distribution cache.
+///
+/// `--offline` builds use it for distributions with its SHA-256.
+pub fn add_distribution(logger: &slog::Logger, path: &Path) -> Result<()> {
+    let dir = distribution_cache_dir().ok_or_else(|| {
+        anyhow!(
+            "no cache directory; set {} or {}",
+            DISTRIBUTION_CACHE_ENV,
+            CACHE_DIR_ENV
+        )
+    })?;
+
+    let (sha256, cache_path) = cache_local_distribution(path, &dir)?;
+
+    warn!(
+        logger,
+        "added {} with sha256 {} as {}",
+        path.display(),
+        sha256,
+        cache_path.display()
+    );
+
+    Ok(())
+}
+
+/// A Python distribution in the distribution cache.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CachedDistribution {
+    /// Directory of the distribution.
+    pub path: PathBuf,
+    /// SHA-256 of the distribution archive.
+    pub sha256: String,
+    /// File names of cached archives.
+    pub archives: Vec<String>,
+    /// Whether the distribution is extracted.
+    pub extracted: bool,
+    /// Size in bytes of all files of the distribution.
+    pub size: u64,
+    /// When a build last used the distribution.
+    pub last_used: SystemTime,
+}
+
+fn is_sha256(name: &str) -> bool {
+    name.len() == 64 && name.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// Find the distributions in a distribution cache directory.
+///
+/// Distributions not recorded as used by a build count as last used when
+/// their archive was cached.
+pub fn cached_distributions(dir: &Path) -> Result<Vec<CachedDistribution>> {
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut res = Vec::new();
+
+    for entry in std::fs::read_dir(dir).with_context(|| format!("reading {}", dir.display()))? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        if !is_sha256(&name) || !entry.file_type()?.is_dir() {
+            continue;
+        }
+
+        let path = entry.path();
+
+        let mut archives = Vec::new();
+        let mut cached = None;
+        for child in std::fs::read_dir(&path)? {
+            let child = child?.path();
+            let metadata_path = PathBuf::from(format!("{}.json", child.display()));
+            if child.is_file() && metadata_path.exists() {
+                archives.push(child.file_name().unwrap().to_string_lossy().to_string());
+                cached = cached.max(modified(&metadata_path));
+            }
+        }
+        archives.sort();
+
+        let size = walkdir::WalkDir::new(&path)
+            .into_iter()
+            .filter_map(|e| e.ok())
+            .filter(|e| e.file_type().is_file())
+            .filter_map(|e| e.metadata().ok())
+            .map(|m| m.len())
+            .sum();
+
+        let last_used = modified(&path.join(LAST_USED_FILENAME))
+            .or(cached)
+            .or_else(|| modified(&path))
+            .unwrap_or(SystemTime::UNIX_EPOCH);
+
+        res.push(CachedDistribution {
+            extracted: path
+                .join(EXTRACTED_DIR)
+                .join("python")
+                .join("PYTHON.json")
+                .exists(),
+            path,
+            sha256: name,
+            archives,
+            size,
+            last_used,
+        });
+    }
+
+    res.sort_by(|a, b| a.sha256.cmp(&b.sha256));
+
+    Ok(res)
+}
+
+/// Delete a cached distribution.
+///
+/// Returns `false` without deleting anything if a build is downloading,
+/// extracting or changing the distribution.
+pub fn remove_cached_distribution(dist: &CachedDistribution) -> Result<bool> {
+    let mut locks = Vec::new();
+
+    for entry in std::fs::read_dir(&dist.path)? {
+        let path = entry?.path();
+        let name = path.file_name().unwrap().to_string_lossy().to_string();
+        if name == EXTRACT_LOCK_FILENAME || name.ends_with(".lock") {
+            let lock = File::open(&path)?;
+            if lock.try_lock_exclusive().is_err() {
+                return Ok(false);
+            }
+            locks.push(lock);
+        }
+    }
+
+    // Locks are released before deleting their files, as open files can't
+    // be deleted on Windows.
+    drop(locks);
+
+    match std::fs::remove_dir_all(&dist.path) {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(true),
+        Err(e) => Err(e).with_context(|| format!("removing {}", dist.path.display())),
+    }
+}
+
+/// Describe how long ago a time was.
+fn format_age(time: SystemTime) -> String {
+    let days = SystemTime::now()
+        .duration_since(time)
+        .map(|d| d.as_secs() / 86400)
+        .unwrap_or(0);
+
+    match days {
+        0 => "today".to_string(),
+        1 => "1 day ago".to_string(),
+        n => format!("{} days ago", n),
+    }
+}
+
+/// Print the cached Python distributions and bytecode.
+pub fn list(logger: &slog::Logger) -> Result<()> {
+    match distribution_cache_dir() {
+        Some(dir) => {
+            let dists = cached_distributions(&dir)?;
+
+            println!("Python distributions in {}:", dir.display());
+            for dist in &dists {
+                println!(
+                    "  {}  {:>7}  {:<9}  last used {:<12}  {}",
+                    &dist.sha256[0..12],
+                    format_mb(dist.size),
+                    if dist.extracted {
+                        "extracted"
+                    } else {
+                        "archive"
+                    },
+                    format_age(dist.last_used),
+                    dist.archives.join(", ")
+                );
+            }
+            println!(
+                "  {} distributions, {}",
+                dists.len(),
+                format_mb(dists.iter().map(|d| d.size).sum())
+            );
+        }
+        None => warn!(
+            logger,
+            "no distribution cache directory; set {} or {}", DISTRIBUTION_CACHE_ENV, CACHE_DIR_ENV
+        ),
+    }
+
+    if let Some(cache) = BytecodeCache::default_cache() {
+        println!(
+            "Bytecode in {}: {}",
+            cache.path().display(),
+            format_mb(cache.size()?)
+        );
+    }
+
+    Ok(())
+}
+
+/// Delete cached entries.
+///
+/// With `older_than`, bytecode and distributions not used for that long
+/// are deleted. With `max_size`, least recently used bytecode is deleted
+/// until it uses at most `max_size` bytes. Otherwise all entries are
+/// deleted. Distributions in use by a build are kept.
+pub fn purge(
+    logger: &slog::Logger,
+    max_size: Option<u64>,
+    older_than: Option<Duration>,
+) -> Result<()> {
+    let cutoff = older_than.map(|age| {
+        SystemTime::now()
+            .checked_sub(age)
+            .unwrap_or(SystemTime::UNIX_EPOCH)
+    });
+
+    if let Some(cache) = BytecodeCache::default_cache() {
+        let mut stats = PruneStats::default();
+
+        if let Some(cutoff) = cutoff {
+            stats = cache.prune_unused_since(cutoff)?;
+        }
+        if max_size.is_some() || cutoff.is_none() {
+            let more = cache.prune(max_size.unwrap_or(0))?;
+            stats.removed_entries += more.removed_entries;
+            stats.removed_bytes += more.removed_bytes;
+            stats.remaining_bytes = more.remaining_bytes;
+        }
+
+        warn!(
+            logger,
+            "removed {} cached bytecode entries ({} bytes); {} bytes remain in {}",
+            stats.removed_entries,
+            stats.removed_bytes,
+            stats.remaining_bytes,
+            cache.path().display()
+        );
+    } else {
+        warn!(logger, "no cache directory; set {}", CACHE_DIR_ENV);
+    }
+
+    // --max-size only concerns bytecode.
+    if max_size.is_some() && cutoff.is_none() {
+        return Ok(());
+    }
+
+    let dir = match distribution_cache_dir() {
+        Some(dir) => dir,
+        None => return Ok(()),
+    };
+
+    let mut removed_bytes = 0;
+    for dist in cached_distributions(&dir)? {
+        if let Some(cutoff) = cutoff {
+            if dist.last_used >= cutoff {
+                continue;
+            }
+        }
+
+        if remove_cached_distribution(&dist)? {
+            warn!(
+                logger,
+                "removed Python distribution {}",
+                dist.path.display()
+            );
+            removed_bytes += dist.size;
+        } else {
+            warn!(
+                logger,
+                "keeping Python distribution {}: a build is using it",
+                dist.path.display()
+            );
+        }
+    }
+
+    warn!(
+        logger,
+        "removed {} of Python distributions from {}",
+        format_mb(removed_bytes),
+        dir.display()
+    );
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SHA256: &str = "a5a5a5a5a5a5a5a5a5a5a5a5a5a5a5a5a5a5a5a5a5a5a5a5a5a5a5a5a5a5a5a5";
+
+    #[test]
+    fn test_cached_distributions() -> Result<()> {
+        let temp_dir = tempdir::TempDir::new("pyoxidizer-test")?;
+        let dir = temp_dir.path();
+
+        assert!(cached_distributions(&dir.join("missing"))?.is_empty());
+
+        let entry_dir = dir.join(SHA256);
+        std::fs::create_dir_all(entry_dir.join(EXTRACTED_DIR).join("python"))?;
+        std::fs::write(entry_dir.join("cpython.tar.zst"), b"archive")?;
+        std::fs::write(entry_dir.join("cpython.tar.zst.json"), b"{}")?;
+        std::fs::write(entry_dir.join("cpython.tar.zst.lock"), b"")?;
+        std::fs::write(
+            entry_dir
+                .join(EXTRACTED_DIR)
+                .join("python")
+                .join("PYTHON.json"),
+            b"{}",
+        )?;
+        std::fs::write(entry_dir.join(LAST_USED_FILENAME), b"")?;
+        filetime::set_file_mtime(
+            entry_dir.join(LAST_USED_FILENAME),
+            filetime::FileTime::from_unix_time(1_000_000, 0),
+        )?;
+
+        // Not a distribution.
+        std::fs::create_dir_all(dir.join("other"))?;
+
+        let dists = cached_distributions(dir)?;
+        assert_eq!(dists.len(), 1);
+        assert_eq!(dists[0].sha256, SHA256);
+        assert_eq!(dists[0].archives, vec!["cpython.tar.zst".to_string()]);
+        assert!(dists[0].extracted);
+        assert_eq!(dists[0].size, 11);
+        assert_eq!(
+            dists[0].last_used,
+            SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000)
+        );
+
+        assert!(remove_cached_distribution(&dists[0])?);
+        assert!(!entry_dir.exists());
+        assert!(cached_distributions(dir)?.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_remove_locked_distribution() -> Result<()> {
+        let temp_dir = tempdir::TempDir::new("pyoxidizer-test")?;
+        let entry_dir = temp_dir.path().join(SHA256);
+        std::fs::create_dir_all(&entry_dir)?;
+
+        let lock = File::create(entry_dir.join(EXTRACT_LOCK_FILENAME))?;
+        lock.lock_exclusive()?;
+
+        let dists = cached_distributions(temp_dir.path())?;
+        assert!(!remove_cached_distribution(&dists[0])?);
+        assert!(entry_dir.exists());
+
+        lock.unlock()?;
+        assert!(remove_cached_distribution(&dists[0])?);
+
+        Ok(())
+    }
+}
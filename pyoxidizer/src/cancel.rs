@@ -0,0 +1,863 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Cancellation of in-flight builds.
+//!
+//! Builds are cancelled through a `CancellationToken`. While a token is
+//! activated on a thread, long-running operations on that thread
+//! (downloads, archive extraction, bytecode compilation, etc) periodically
+//! call `check_cancelled()` and child processes started via `spawn()` are
+//! killed along with their descendants when the token is cancelled. Threads
+//! started via `spawn_thread()` observe the token of the thread starting
+//! them.
+//!
+//! Operations interrupted by cancellation fail with `BuildError::Cancelled`.
+//!
+//! Applications launched by `pyoxidizer run` aren't cancelled. Signals
+//! asking us to stop are forwarded to them instead, see `forward_signals()`.
+
+use anyhow::{Context, Result};
+use lazy_static::lazy_static;
+use std::cell::RefCell;
+use std::process::{Child, Command, ExitStatus};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+/// How often to check whether a token was cancelled.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// How long processes have to exit after being asked to terminate.
+#[cfg(unix)]
+const TERMINATE_GRACE: Duration = Duration::from_secs(2);
+
+/// Errors with special meaning to callers of the build API.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BuildError {
+    /// The build was cancelled via its `CancellationToken`.
+    Cancelled,
+}
+
+impl std::fmt::Display for BuildError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            BuildError::Cancelled => write!(f, "build cancelled"),
+        }
+    }
+}
+
+impl std::error::Error for BuildError {}
+
+/// Whether an error was caused by cancelling a build.
+pub fn is_cancelled_error(err: &anyhow::Error) -> bool {
+    err.downcast_ref::<BuildError>() == Some(&BuildError::Cancelled)
+}
+
+/// A handle used to cancel a build.
+///
+/// Clones share state: cancelling any clone cancels all of them.
+#[derive(Clone, Debug, Default)]
+pub struct CancellationToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Request cancellation of operations observing this token.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Make this the token observed by operations on the current thread.
+    ///
+    /// The token is observed until the returned guard is dropped.
+    pub fn activate(&self) -> ActiveCancellation {
+        ActiveCancellation {
+            previous: ACTIVE.with(|active| active.borrow_mut().replace(self.clone())),
+        }
+    }
+}
+
+thread_local! {
+    static ACTIVE: RefCell<Option<CancellationToken>> = RefCell::new(None);
+}
+
+/// Restores the previously active token when dropped.
+pub struct ActiveCancellation {
+    previous: Option<CancellationToken>,
+}
+
+impl Drop for ActiveCancellation {
+    fn drop(&mut self) {
+        let previous = self.previous.take();
+        ACTIVE.with(|active| *active.borrow_mut() = previous);
+    }
+}
+
+fn active_token() -> Option<CancellationToken> {
+    ACTIVE.with(|active| active.borrow().clone())
+}
+
+/// Spawn a thread observing the token active on the current thread.
+pub fn spawn_thread<F, T>(f: F) -> std::thread::JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let token = active_token();
+
+    std::thread::spawn(move || {
+        let _active = token.as_ref().map(CancellationToken::activate);
+        f()
+    })
+}
+
+/// Whether the active token has been cancelled.
+pub fn is_cancelled() -> bool {
+    active_token().map_or(false, |t| t.is_cancelled())
+}
+
+/// Return `BuildError::Cancelled` if the active token has been cancelled.
+pub fn check_cancelled() -> Result<()> {
+    if is_cancelled() {
+        Err(BuildError::Cancelled.into())
+    } else {
+        Ok(())
+    }
+}
+
+/// A process and its descendants, which can be killed as a unit.
+///
+/// On Unix, this is a process group, unless the process stays in ours (see
+/// `ProcessGroup::in_foreground()`). Only the process itself is killed
+/// then. On Windows, it is a Job Object.
+#[derive(Debug)]
+struct ProcessGroup {
+    #[cfg(unix)]
+    pid: libc::pid_t,
+    #[cfg(unix)]
+    own_group: bool,
+    #[cfg(windows)]
+    job: usize,
+}
+
+#[cfg(unix)]
+impl ProcessGroup {
+    /// Whether we're in the foreground of a terminal.
+    ///
+    /// Processes in a background process group are stopped when they read
+    /// from the terminal, e.g. to prompt for a password. Children then stay
+    /// in our process group, so they can prompt. A Ctrl-C in the terminal
+    /// reaches them and their descendants directly.
+    fn in_foreground() -> bool {
+        unsafe { libc::isatty(0) == 1 && libc::tcgetpgrp(0) == libc::getpgrp() }
+    }
+
+    fn prepare(cmd: &mut Command) {
+        use std::os::unix::process::CommandExt;
+
+        // Put the process in its own process group so we can signal it and
+        // its descendants without signaling ourselves.
+        unsafe {
+            cmd.pre_exec(|| {
+                if libc::setpgid(0, 0) == 0 {
+                    Ok(())
+                } else {
+                    Err(std::io::Error::last_os_error())
+                }
+            });
+        }
+    }
+
+    fn new(child: &Child, own_group: bool) -> Result<Self> {
+        Ok(ProcessGroup {
+            pid: child.id() as libc::pid_t,
+            own_group,
+        })
+    }
+
+    /// The `kill()` target: the process group or the process.
+    fn target(&self) -> libc::pid_t {
+        if self.own_group {
+            -self.pid
+        } else {
+            self.pid
+        }
+    }
+
+    fn exists(&self) -> bool {
+        unsafe { libc::kill(self.target(), 0) == 0 }
+    }
+
+    fn kill(&self) {
+        unsafe {
+            libc::kill(self.target(), libc::SIGTERM);
+        }
+
+        let start = std::time::Instant::now();
+        while self.exists() && start.elapsed() < TERMINATE_GRACE {
+            std::thread::sleep(POLL_INTERVAL);
+        }
+
+        unsafe {
+            libc::kill(self.target(), libc::SIGKILL);
+        }
+    }
+}
+
+#[cfg(windows)]
+impl ProcessGroup {
+    fn in_foreground() -> bool {
+        false
+    }
+
+    fn prepare(_cmd: &mut Command) {}
+
+    fn new(child: &Child, _own_group: bool) -> Result<Self> {
+        use std::os::windows::io::AsRawHandle;
+        use winapi::um::handleapi::CloseHandle;
+        use winapi::um::jobapi2::{
+            AssignProcessToJobObject, CreateJobObjectW, SetInformationJobObject,
+        };
+        use winapi::um::winnt::{
+            JobObjectExtendedLimitInformation, JOBOBJECT_EXTENDED_LIMIT_INFORMATION,
+            JOB_OBJECT_LIMIT_KILL_ON_JOB_CLOSE,
+        };
+
+        unsafe {
+            let job = CreateJobObjectW(std::ptr::null_mut(), std::ptr::null());
+            if job.is_null() {
+                return Err(std::io::Error::last_os_error()).context("creating job object");
+            }
+
+            // Processes in the job are killed when the last handle to the job
+            // is closed, including if we exit abnormally.
+            let mut info: JOBOBJECT_EXTENDED_LIMIT_INFORMATION = std::mem::zeroed();
+            info.BasicLimitInformation.LimitFlags = JOB_OBJECT_LIMIT_KILL_ON_JOB_CLOSE;
+
+            if SetInformationJobObject(
+                job,
+                JobObjectExtendedLimitInformation,
+                &mut info as *mut _ as *mut _,
+                std::mem::size_of::<JOBOBJECT_EXTENDED_LIMIT_INFORMATION>() as u32,
+            ) == 0
+                || AssignProcessToJobObject(job, child.as_raw_handle() as _) == 0
+            {
+                let err = std::io::Error::last_os_error();
+                CloseHandle(job);
+                return Err(err).context("assigning process to job object");
+            }
+
+            Ok(ProcessGroup { job: job as usize })
+        }
+    }
+
+    fn kill(&self) {
+        unsafe {
+            winapi::um::jobapi2::TerminateJobObject(self.job as _, 1);
+        }
+    }
+}
+
+#[cfg(windows)]
+impl Drop for ProcessGroup {
+    fn drop(&mut self) {
+        unsafe {
+            winapi::um::handleapi::CloseHandle(self.job as _);
+        }
+    }
+}
+
+#[cfg(not(any(unix, windows)))]
+impl ProcessGroup {
+    fn in_foreground() -> bool {
+        false
+    }
+
+    fn prepare(_cmd: &mut Command) {}
+
+    fn new(_child: &Child, _own_group: bool) -> Result<Self> {
+        Ok(ProcessGroup {})
+    }
+
+    fn kill(&self) {}
+}
+
+/// A child process killed when the active token is cancelled.
+#[derive(Debug)]
+pub struct CancellableChild {
+    child: Child,
+    group: Option<Arc<ProcessGroup>>,
+    token: Option<CancellationToken>,
+    done: Arc<AtomicBool>,
+    watcher: Option<std::thread::JoinHandle<()>>,
+    waited: bool,
+}
+
+/// Spawn a child process that is killed if the active token is cancelled.
+///
+/// Descendants of the process are killed with it, unless we're in the
+/// foreground of a terminal. The process can prompt on the terminal then,
+/// and a Ctrl-C in the terminal reaches its descendants.
+pub fn spawn(cmd: &mut Command) -> Result<CancellableChild> {
+    spawn_impl(cmd, false)
+}
+
+/// Like `spawn()`, but the process and its descendants can always be killed
+/// as a unit with `CancellableChild::kill()`.
+///
+/// The process doesn't receive interrupts (Ctrl-C) sent to our process
+/// group. It is killed through the active token instead. On Unix, it can't
+/// prompt on the terminal.
+pub fn spawn_group(cmd: &mut Command) -> Result<CancellableChild> {
+    spawn_impl(cmd, true)
+}
+
+fn spawn_impl(cmd: &mut Command, always_group: bool) -> Result<CancellableChild> {
+    let token = active_token();
+    let use_group = always_group || token.is_some();
+    let own_group = always_group || (use_group && !ProcessGroup::in_foreground());
+
+    if own_group {
+        ProcessGroup::prepare(cmd);
+    }
+
+    let child = cmd.spawn()?;
+    let done = Arc::new(AtomicBool::new(false));
+
+    let group = if use_group {
+        match ProcessGroup::new(&child, own_group) {
+            Ok(group) => Some(Arc::new(group)),
+            Err(e) => {
+                let mut child = child;
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(e);
+            }
+        }
+    } else {
+        None
+    };
+
+    let watcher = match (&token, &group) {
+        (Some(token), Some(group)) => {
+            let token = token.clone();
+            let group = group.clone();
+            let done = done.clone();
+
+            Some(std::thread::spawn(move || {
+                while !done.load(Ordering::SeqCst) {
+                    if token.is_cancelled() {
+                        group.kill();
+                        return;
+                    }
+
+                    std::thread::sleep(POLL_INTERVAL);
+                }
+            }))
+        }
+        _ => None,
+    };
+
+    Ok(CancellableChild {
+        child,
+        group,
+        token,
+        done,
+        watcher,
+        waited: false,
+    })
+}
+
+impl CancellableChild {
+    pub fn child_mut(&mut self) -> &mut Child {
+        &mut self.child
+    }
+
+    /// Kill the process, along with its descendants if it has a group.
+    ///
+    /// The process still needs to be waited for.
+    pub fn kill(&mut self) {
+        match &self.group {
+            Some(group) => group.kill(),
+            None => {
+                let _ = self.child.kill();
+            }
+        }
+    }
+
+    fn stop_watcher(&mut self) {
+        self.done.store(true, Ordering::SeqCst);
+        if let Some(watcher) = self.watcher.take() {
+            let _ = watcher.join();
+        }
+    }
+
+    /// Wait for the process to exit.
+    ///
+    /// Returns `BuildError::Cancelled` if the process was killed due to
+    /// cancellation.
+    pub fn wait(mut self) -> Result<ExitStatus> {
+        let status = self.child.wait();
+        self.waited = true;
+        self.stop_watcher();
+
+        if self.token.as_ref().map_or(false, |t| t.is_cancelled()) {
+            return Err(BuildError::Cancelled.into());
+        }
+
+        Ok(status?)
+    }
+}
+
+impl Drop for CancellableChild {
+    fn drop(&mut self) {
+        self.stop_watcher();
+
+        // Don't leave processes behind if we bail out early.
+        if !self.waited {
+            self.kill();
+            let _ = self.child.wait();
+        }
+    }
+}
+
+/// Number of interrupts (Ctrl-C) received by the process.
+static INTERRUPTS: AtomicUsize = AtomicUsize::new(0);
+
+/// Whether an application launched by us is running.
+///
+/// Interrupts are the application's to handle then.
+static APP_RUNNING: AtomicBool = AtomicBool::new(false);
+
+/// Record an interrupt, returning whether the process should exit now.
+fn handle_interrupt() -> bool {
+    if APP_RUNNING.load(Ordering::SeqCst) {
+        return false;
+    }
+
+    // A second interrupt means the user doesn't want to wait for cleanup.
+    INTERRUPTS.fetch_add(1, Ordering::SeqCst) > 0
+}
+
+#[cfg(unix)]
+fn install_interrupt_handler() -> Result<()> {
+    extern "C" fn handler(_signal: libc::c_int) {
+        if handle_interrupt() {
+            unsafe { libc::_exit(130) };
+        }
+    }
+
+    let previous = unsafe { libc::signal(libc::SIGINT, handler as libc::sighandler_t) };
+    if previous == libc::SIG_ERR {
+        Err(std::io::Error::last_os_error()).context("installing SIGINT handler")
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(windows)]
+fn install_interrupt_handler() -> Result<()> {
+    use winapi::shared::minwindef::{BOOL, DWORD, FALSE, TRUE};
+    use winapi::um::wincon::{CTRL_BREAK_EVENT, CTRL_C_EVENT};
+
+    unsafe extern "system" fn handler(ctrl_type: DWORD) -> BOOL {
+        match ctrl_type {
+            CTRL_C_EVENT | CTRL_BREAK_EVENT => {
+                if handle_interrupt() {
+                    std::process::exit(130);
+                }
+                TRUE
+            }
+            _ => FALSE,
+        }
+    }
+
+    if unsafe { winapi::um::consoleapi::SetConsoleCtrlHandler(Some(handler), TRUE) } == 0 {
+        Err(std::io::Error::last_os_error()).context("installing Ctrl-C handler")
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(not(any(unix, windows)))]
+fn install_interrupt_handler() -> Result<()> {
+    Ok(())
+}
+
+/// Cancel a token when the process is interrupted with Ctrl-C.
+///
+/// A second Ctrl-C exits the process immediately.
+pub fn cancel_on_interrupt(token: &CancellationToken) -> Result<()> {
+    install_interrupt_handler()?;
+
+    let token = token.clone();
+    std::thread::spawn(move || loop {
+        if INTERRUPTS.load(Ordering::SeqCst) > 0 {
+            token.cancel();
+            return;
+        }
+
+        std::thread::sleep(POLL_INTERVAL);
+    });
+
+    Ok(())
+}
+
+/// Signals forwarded to running applications.
+#[cfg(unix)]
+const FORWARDED_SIGNALS: [libc::c_int; 2] = [libc::SIGINT, libc::SIGTERM];
+
+/// Number of each of `FORWARDED_SIGNALS` received and not forwarded yet.
+#[cfg(unix)]
+static PENDING_SIGNALS: [AtomicUsize; 2] = [AtomicUsize::new(0), AtomicUsize::new(0)];
+
+/// Whether a signal was sent by a process rather than by the terminal.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn sent_by_process(si_code: libc::c_int) -> bool {
+    // SI_USER, SI_QUEUE, SI_TKILL, etc are all <= 0.
+    si_code <= 0
+}
+
+#[cfg(all(unix, not(any(target_os = "linux", target_os = "android"))))]
+fn sent_by_process(si_code: libc::c_int) -> bool {
+    // SI_USER and SI_QUEUE.
+    si_code == 0x10001 || si_code == 0x10002
+}
+
+lazy_static! {
+    /// Held while forwarding signals, as handlers are process wide.
+    static ref FORWARDING: Mutex<()> = Mutex::new(());
+}
+
+/// Forwards signals asking us to stop to a running application.
+///
+/// Previous signal handlers are restored when dropped.
+pub struct SignalForwarder {
+    _guard: MutexGuard<'static, ()>,
+    done: Arc<AtomicBool>,
+    forwarder: Option<std::thread::JoinHandle<()>>,
+    #[cfg(unix)]
+    previous: Vec<(libc::c_int, libc::sigaction)>,
+}
+
+/// Forward SIGINT and SIGTERM received by us to a running application.
+///
+/// The application is in our process group, so a Ctrl-C in the terminal
+/// reaches it directly, as if it was run without us. Such interrupts are
+/// ignored rather than forwarded, so the application receives each only
+/// once. SIGINT sent to us by another process and SIGTERM are forwarded.
+///
+/// On Windows, Ctrl-C reaches every process of the console and is ignored.
+pub fn forward_signals(child: &Child) -> Result<SignalForwarder> {
+    let guard = FORWARDING.lock().unwrap_or_else(|e| e.into_inner());
+    APP_RUNNING.store(true, Ordering::SeqCst);
+
+    let done = Arc::new(AtomicBool::new(false));
+
+    #[cfg(unix)]
+    {
+        extern "C" fn handler(
+            signal: libc::c_int,
+            info: *mut libc::siginfo_t,
+            _context: *mut libc::c_void,
+        ) {
+            let from_process = info.is_null() || sent_by_process(unsafe { (*info).si_code });
+
+            if signal == libc::SIGTERM || from_process {
+                if let Some(i) = FORWARDED_SIGNALS.iter().position(|s| *s == signal) {
+                    PENDING_SIGNALS[i].fetch_add(1, Ordering::SeqCst);
+                }
+            }
+        }
+
+        let mut previous = Vec::new();
+
+        for signal in &FORWARDED_SIGNALS {
+            unsafe {
+                let mut action: libc::sigaction = std::mem::zeroed();
+                action.sa_sigaction = handler as libc::sighandler_t;
+                action.sa_flags = libc::SA_SIGINFO | libc::SA_RESTART;
+                libc::sigemptyset(&mut action.sa_mask);
+
+                let mut old: libc::sigaction = std::mem::zeroed();
+                if libc::sigaction(*signal, &action, &mut old) != 0 {
+                    let err = std::io::Error::last_os_error();
+                    restore_signal_handlers(&previous);
+                    APP_RUNNING.store(false, Ordering::SeqCst);
+                    return Err(err).context("installing signal handler");
+                }
+                previous.push((*signal, old));
+            }
+        }
+
+        for pending in &PENDING_SIGNALS {
+            pending.store(0, Ordering::SeqCst);
+        }
+
+        let pid = child.id() as libc::pid_t;
+        let forwarder = {
+            let done = done.clone();
+
+            std::thread::spawn(move || loop {
+                for (signal, pending) in FORWARDED_SIGNALS.iter().zip(PENDING_SIGNALS.iter()) {
+                    for _ in 0..pending.swap(0, Ordering::SeqCst) {
+                        unsafe {
+                            libc::kill(pid, *signal);
+                        }
+                    }
+                }
+
+                if done.load(Ordering::SeqCst) {
+                    return;
+                }
+
+                std::thread::sleep(POLL_INTERVAL);
+            })
+        };
+
+        Ok(SignalForwarder {
+            _guard: guard,
+            done,
+            forwarder: Some(forwarder),
+            previous,
+        })
+    }
+
+    #[cfg(not(unix))]
+    {
+        let _ = child;
+
+        Ok(SignalForwarder {
+            _guard: guard,
+            done,
+            forwarder: None,
+        })
+    }
+}
+
+#[cfg(unix)]
+fn restore_signal_handlers(previous: &[(libc::c_int, libc::sigaction)]) {
+    for (signal, action) in previous {
+        unsafe {
+            libc::sigaction(*signal, action, std::ptr::null_mut());
+        }
+    }
+}
+
+impl Drop for SignalForwarder {
+    fn drop(&mut self) {
+        self.done.store(true, Ordering::SeqCst);
+        if let Some(forwarder) = self.forwarder.take() {
+            let _ = forwarder.join();
+        }
+
+        #[cfg(unix)]
+        restore_signal_handlers(&self.previous);
+
+        APP_RUNNING.store(false, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fsutils::StagedDir;
+    use std::time::Instant;
+
+    /// Cancel a token after a delay.
+    fn cancel_after(token: &CancellationToken, delay: Duration) -> std::thread::JoinHandle<()> {
+        let token = token.clone();
+        std::thread::spawn(move || {
+            std::thread::sleep(delay);
+            token.cancel();
+        })
+    }
+
+    /// A deliberately slow phase writing into a staged directory.
+    fn slow_phase(dest: &std::path::Path) -> Result<()> {
+        let staged = StagedDir::new(dest)?;
+
+        for i in 0..100 {
+            check_cancelled()?;
+            std::fs::write(staged.path().join(format!("file{}", i)), b"data")?;
+            std::thread::sleep(Duration::from_millis(20));
+        }
+
+        staged.commit()
+    }
+
+    #[test]
+    fn test_check_cancelled() -> Result<()> {
+        let token = CancellationToken::new();
+
+        // No token is active.
+        token.cancel();
+        check_cancelled()?;
+
+        {
+            let _active = token.activate();
+            let err = check_cancelled().unwrap_err();
+            assert!(is_cancelled_error(&err));
+            assert_eq!(err.to_string(), "build cancelled");
+        }
+
+        check_cancelled()?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_cancel_slow_phase() -> Result<()> {
+        let temp_dir = tempdir::TempDir::new("pyoxidizer-test")?;
+        let dest = temp_dir.path().join("artifacts");
+
+        let token = CancellationToken::new();
+        let _active = token.activate();
+        let canceller = cancel_after(&token, Duration::from_millis(100));
+
+        let err = slow_phase(&dest).unwrap_err();
+        canceller.join().unwrap();
+
+        assert!(is_cancelled_error(&err));
+        assert!(!dest.exists());
+        assert_eq!(std::fs::read_dir(temp_dir.path())?.count(), 0);
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_cancel_kills_process_tree() -> Result<()> {
+        let token = CancellationToken::new();
+        let _active = token.activate();
+
+        let child = spawn(Command::new("sh").args(&["-c", "sleep 30 & sleep 30"]))?;
+        // In the foreground of a terminal, only the process itself is killed.
+        let group = ProcessGroup::new(&child.child, !ProcessGroup::in_foreground())?;
+        assert!(group.exists());
+
+        let start = Instant::now();
+        let canceller = cancel_after(&token, Duration::from_millis(200));
+        let err = child.wait().unwrap_err();
+        canceller.join().unwrap();
+
+        assert!(is_cancelled_error(&err));
+        assert!(start.elapsed() < Duration::from_secs(10));
+
+        // Orphaned descendants are reaped asynchronously.
+        let start = Instant::now();
+        while group.exists() && start.elapsed() < Duration::from_secs(5) {
+            std::thread::sleep(POLL_INTERVAL);
+        }
+        assert!(!group.exists(), "processes remain after cancellation");
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_kill_group() -> Result<()> {
+        // No token is active, yet descendants are killed.
+        let mut child = spawn_group(Command::new("sh").args(&["-c", "sleep 30 & sleep 30"]))?;
+        let group = ProcessGroup::new(&child.child, true)?;
+        assert!(group.exists());
+
+        child.kill();
+        child.wait()?;
+
+        let start = Instant::now();
+        while group.exists() && start.elapsed() < Duration::from_secs(5) {
+            std::thread::sleep(POLL_INTERVAL);
+        }
+        assert!(!group.exists(), "processes remain after kill");
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_spawn_without_cancellation() -> Result<()> {
+        let token = CancellationToken::new();
+        let _active = token.activate();
+
+        let status = spawn(Command::new("sh").args(&["-c", "exit 3"]))?.wait()?;
+        assert_eq!(status.code(), Some(3));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_spawn_thread() -> Result<()> {
+        let token = CancellationToken::new();
+        token.cancel();
+
+        assert!(!spawn_thread(is_cancelled).join().unwrap());
+
+        let _active = token.activate();
+        assert!(spawn_thread(is_cancelled).join().unwrap());
+
+        Ok(())
+    }
+
+    /// Set to run `test_forward_signals()` in the process running it.
+    #[cfg(unix)]
+    const FORWARD_SIGNALS_CHILD_ENV: &str = "PYOXIDIZER_TEST_FORWARD_SIGNALS_CHILD";
+
+    #[test]
+    #[cfg(unix)]
+    fn test_forward_signals() -> Result<()> {
+        if std::env::var_os(FORWARD_SIGNALS_CHILD_ENV).is_some() {
+            return forward_signals_child();
+        }
+
+        // Signals are sent to the whole process, which runs other tests
+        // too. Run the test on its own in a new process instead.
+        let output = Command::new(std::env::current_exe()?)
+            .args(&["--exact", "cancel::tests::test_forward_signals"])
+            .env(FORWARD_SIGNALS_CHILD_ENV, "1")
+            .output()?;
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        assert!(output.status.success(), "{}", stdout);
+        assert!(stdout.contains("1 passed"), "{}", stdout);
+
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    fn forward_signals_child() -> Result<()> {
+        for (signal, code) in &[(libc::SIGTERM, 7), (libc::SIGINT, 8)] {
+            let mut child = Command::new("sh")
+                .args(&[
+                    "-c",
+                    "trap 'exit 7' TERM; trap 'exit 8' INT; while :; do sleep 0.1; done",
+                ])
+                .spawn()?;
+
+            let status = {
+                let _forwarder = forward_signals(&child)?;
+                // Give the shell time to install its traps.
+                std::thread::sleep(Duration::from_millis(500));
+
+                // Sent by a process, so SIGINT is forwarded too.
+                unsafe {
+                    libc::kill(libc::getpid(), *signal);
+                }
+
+                child.wait()?
+            };
+
+            assert_eq!(status.code(), Some(*code));
+        }
+
+        Ok(())
+    }
+}
@@ -3,14 +3,33 @@
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
 use anyhow::{anyhow, Result};
-use clap::{App, AppSettings, Arg, SubCommand};
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use std::ffi::OsString;
 use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 use super::analyze;
-use super::environment::BUILD_SEMVER_LIGHTWEIGHT;
+use super::app_packaging::build_vars;
+use super::app_packaging::environment::EvalOptions;
+use super::app_packaging::output_layout;
+use super::app_packaging::plan::PlanMode;
+use super::app_packaging::python_sys;
+use super::app_packaging::run_env::{self, RunOptions};
+use super::app_packaging::rustc_bootstrap;
+use super::app_packaging::state::{BuildOptions, ExtraCargoArgs, Verbosity};
+use super::app_packaging::toolchain::{self, Toolchain};
+use super::build_events;
+use super::build_stats;
+use super::cache;
+use super::cancel::{self, CancellationToken};
+use super::color::{self, ColorChoice};
+use super::environment::{self, BUILD_SEMVER_LIGHTWEIGHT};
+use super::exit_status::{categorize, Categorize, ErrorCategory};
+use super::fsutils;
 use super::logging;
 use super::project_layout;
 use super::projectmgmt;
+use super::py_packaging::import_profile;
 
 const ADD_ABOUT: &str = "\
 Add PyOxidizer to an existing Rust project.
@@ -30,6 +49,21 @@ distribution URLs and dependency crate versions and locations from the
 PyOxidizer executable that runs this command.
 ";
 
+const ANALYZE_ABOUT: &str = "\
+Analyze a built binary or an import profile.
+
+The PATH argument is a filesystem path to an executable. Its shared
+library dependencies and the symbol versions it requires are analyzed
+for distribution compatibility.
+
+With --import-profile, the JSON import profile written by an application
+whose config sets `import_profile_env` is analyzed instead: imports are
+summarized by source, the slowest imports are listed, modules imported from
+the filesystem are suggested for embedding and slow modules are suggested
+for importing lazily. With --modules pointing at the `py-modules` file of
+the build artifacts, embedded modules that were never imported are listed.
+";
+
 const BUILD_ABOUT: &str = "\
 Build a PyOxidizer project.
 
@@ -38,6 +72,88 @@ existing PyOxidizer enabled project.
 
 This command will invoke Rust's build system tool (Cargo) to build
 the project.
+
+--target may be repeated to build for multiple Rust target triples in
+one invocation. Each target is built into its own directories. A target
+failing to build doesn't stop the remaining ones unless --fail-fast is
+given. A table of the executables produced for each target is printed at
+the end and the command fails if any target failed.
+
+Pressing Ctrl-C cancels the build, stopping any processes it started
+and removing partially written artifacts. Pressing Ctrl-C again exits
+immediately.
+
+With --plan, nothing is built. The config is evaluated, the resources it
+packages are resolved and a plan of the build is printed: the resources
+to embed, the files that would be written, the lines emitted to Cargo and
+the Cargo invocation. --plan=shallow only evaluates and validates the
+config. --format json prints the plan as JSON suitable for diffing.
+
+Arguments after -- are appended to the `cargo build` invocation, e.g.
+`pyoxidizer build -- --locked -j 4`. --features values are merged with
+the features PyOxidizer enables itself. Options PyOxidizer sets itself,
+such as --target and --target-dir, are rejected.
+";
+
+const CLEAN_ABOUT: &str = "\
+Remove state generated for a project.
+
+The config is evaluated to resolve the Rust target directory, the
+PyOxidizer artifacts directories and the directories applications were
+packaged into, for debug and release builds. Exactly these directories are
+removed and printed. Nothing else in the build directory is touched.
+
+Nothing is removed if any of the directories is outside the project
+directory or contains the config file or Cargo.toml, e.g. because the
+config sets an unusual build path.
+
+--dist-cache also removes the Python distributions the project uses from
+the distribution cache, which other projects using them download again.
+--dry-run prints what would be removed without removing anything.
+
+--other-versions only removes artifacts of other PyOxidizer versions. The
+first PyOxidizer version generating artifacts for a project owns the
+artifacts directory. Other versions write their artifacts into a
+`pyoxidizer-<version>` subdirectory of it instead of overwriting them.
+These subdirectories are removed for all targets and profiles. Versions
+using them regenerate their artifacts on their next build.
+";
+
+const DIFF_CONFIG_ABOUT: &str = "\
+Show behavioral differences between two PyOxidizer configs.
+
+Both config files are evaluated, without building or packaging anything,
+and the values that affect what gets built are compared: the Python
+distribution, installed Python packages, embedded Python config fields,
+run modes and build settings. Added values are prefixed with `+`, removed
+values with `-` and changed values with `~`.
+
+Values are normalized so equivalent configs compare equal. e.g. rustflags
+are normalized, paths are made relative to each config's directory and
+which `select()` arm chose a value is ignored.
+
+Both configs are evaluated for --target. With --other-target, the second
+config is evaluated for that target instead, so the same config can be
+compared across targets.
+
+The command exits with an error when differences exist.
+";
+
+const FREEZE_DEPS_ABOUT: &str = "\
+Write the versions of installed Python packages to a constraints file.
+
+The PATH argument is a filesystem path to a directory containing an
+existing PyOxidizer enabled project.
+
+The config file is evaluated for the given target and every package
+installed by pip_install(), including transitive dependencies, is pinned
+to its installed version in constraints.txt next to the config file, or
+in the file given by --output. Changes to the pins of an existing file are
+printed.
+
+Pass the file to pip_install() with constraints= so later builds resolve
+the same versions. Builds warn about installed packages the constraints
+file doesn't pin.
 ";
 
 const INIT_ABOUT: &str = "\
@@ -50,7 +166,19 @@ This command will call `cargo init PATH` and then install files and make
 modifications required to embed a Python interpreter in that application.
 
 The new project's binary will be configured to launch a Python REPL by
-default.
+default. --template creates another kind of application instead, along
+with an example Python package in app/ that the application runs:
+
+cli      A command line tool parsing its arguments with argparse. The
+         exit code is the return value of its main() function.
+service  A long-running service logging to stderr and stopping cleanly
+         on SIGTERM or Ctrl+C.
+gui      A tkinter GUI application. It is built for the Windows
+         subsystem, so no console window opens, and writes output to a
+         log file next to the executable when there is no console.
+
+With --lib, the project is instead a shared library (a `cdylib` crate)
+exporting a small C ABI for embedding Python in a host application.
 
 Created projects inherit settings such as Python distribution URLs and
 dependency crate versions and locations from the PyOxidizer executable
@@ -59,6 +187,39 @@ they were created with.
 On success, instructions on potential next steps are printed.
 ";
 
+const LOCK_ABOUT: &str = "\
+Resolve the inputs of a PyOxidizer project and write a lockfile.
+
+The PATH argument is a filesystem path to a directory containing an
+existing PyOxidizer enabled project.
+
+The config file is evaluated for the given target and the resolved
+inputs are written to pyoxidizer.lock.json next to it. The lockfile
+records the Python distribution, the exact version of every package
+installed by pip_install() and the SHA-256 of every file and directory
+referenced by the config.
+
+Builds with --locked verify their inputs against the lockfile and fail
+if anything changed. --frozen additionally forbids network access.
+";
+
+const PREPARE_ABOUT: &str = "\
+Evaluate a config and generate everything needed to compile it elsewhere.
+
+The PATH argument is a filesystem path to a directory containing an
+existing PyOxidizer enabled project.
+
+The config file is evaluated, pip_install() and other rules run and the
+PyOxidizer artifacts are generated. They are written into the directory
+given with --out along with a copy of the project, the Python distribution,
+the evaluated config and prepared.json describing the build.
+
+The directory can be moved to another machine and built there with
+`pyoxidizer compile --prepared DIR --target-dir DIR`, which only runs
+cargo and packages the application. The machine needs the same PyOxidizer
+version and must build for the same target.
+";
+
 const RUN_BUILD_SCRIPT_ABOUT: &str = "\
 Runs a crate build script to generate Python artifacts.
 
@@ -73,8 +234,128 @@ This command is essentially identical to `build-artifacts` except the
 output is tailored for the Rust build system.
 ";
 
+const UPGRADE_PROJECT_ABOUT: &str = "\
+Upgrade the PyOxidizer files of a project.
+
+The PATH argument is a filesystem path to a directory containing an
+existing PyOxidizer enabled project.
+
+Projects are created with files generated by the PyOxidizer executable
+that created them. This command regenerates them with this executable:
+the sources and Cargo.toml of the pyembed crate are replaced and code
+between BEGIN and END PYOXIDIZER GENERATED CODE markers in pyembed/build.rs
+and src/main.rs (src/lib.rs for library projects) is replaced. Code
+outside those markers is left untouched.
+
+Every updated file is printed along with the lines that changed. Files
+without markers, such as ones from projects created before markers
+existed, are reported and left alone.
+";
+
+const VERIFY_ABOUT: &str = "\
+Verify a packaged application against its build manifest.
+
+When an application is packaged, a build-manifest.json file is written
+to the application directory recording the size and SHA-256 digest of
+every file, as well as the location and digest of the embedded Python
+resources data within the executable.
+
+This command re-hashes files and validates that the embedded resources
+still parse and match their recorded digest. By default, all files in
+the manifest are verified. Use --artifact to only verify specific files.
+
+Files annotated with `modified-by-signing` in the manifest only have
+their embedded resources verified.
+
+With --lockfile, the project next to the given lockfile is instead
+evaluated and its inputs are verified against the lockfile.
+
+Exits non-zero if any file does not match.
+";
+
+/// Obtain a token cancelled when the user presses Ctrl-C.
+fn interrupt_token() -> Result<CancellationToken> {
+    let token = CancellationToken::new();
+    cancel::cancel_on_interrupt(&token)?;
+
+    Ok(token)
+}
+
+/// Obtain whether to force regenerating artifacts and rebuilding with cargo.
+fn force(args: &ArgMatches) -> (bool, bool) {
+    let force = args.is_present("force");
+
+    (force || args.is_present("force-artifacts"), force)
+}
+
+/// Obtain the options to evaluate configs with, honoring `--offline`.
+fn offline_options(options: &EvalOptions, args: &ArgMatches) -> EvalOptions {
+    let mut options = options.clone();
+    options.offline |= args.is_present("offline");
+
+    options
+}
+
+/// Resolve whether a build is locked from `--locked` and `--frozen`.
+///
+/// Frozen builds are locked builds without network access.
+fn locked(args: &ArgMatches) -> bool {
+    if args.is_present("frozen") {
+        environment::forbid_network();
+    }
+
+    args.is_present("locked") || args.is_present("frozen")
+}
+
+/// Whether `--legacy-exit-codes` was passed.
+///
+/// Arguments are inspected directly so the flag is honored when parsing
+/// them fails. Arguments after `--` are passed to other programs and
+/// ignored.
+pub fn legacy_exit_codes() -> bool {
+    std::env::args_os()
+        .skip(1)
+        .take_while(|arg| arg != "--")
+        .any(|arg| arg == "--legacy-exit-codes")
+}
+
+/// The path given with `--stats-file`, if any.
+///
+/// Arguments are inspected directly, like for `legacy_exit_codes()`, so
+/// invocations whose arguments can't be parsed are recorded too.
+pub fn stats_file() -> Option<PathBuf> {
+    let mut args = std::env::args_os().skip(1).take_while(|arg| arg != "--");
+
+    while let Some(arg) = args.next() {
+        if arg == "--stats-file" {
+            return args.next().map(PathBuf::from);
+        }
+
+        let arg = arg.to_string_lossy();
+        if arg.starts_with("--stats-file=") {
+            return Some(PathBuf::from(&arg["--stats-file=".len()..]));
+        }
+    }
+
+    None
+}
+
+/// Parse command line arguments.
+fn parse_args(app: App<'static, 'static>, args: &[OsString]) -> Result<ArgMatches<'static>> {
+    app.get_matches_from_safe(args).or_else(|e| match e.kind {
+        clap::ErrorKind::HelpDisplayed | clap::ErrorKind::VersionDisplayed => e.exit(),
+        _ => {
+            eprintln!("{}", e.message);
+            Err(categorize(
+                anyhow!("invalid command line arguments"),
+                ErrorCategory::Usage,
+            ))
+        }
+    })
+}
+
 pub fn run_cli() -> Result<()> {
-    let matches = App::new("PyOxidizer")
+    let app = App::new("PyOxidizer")
         .setting(AppSettings::ArgRequiredElseHelp)
         .version(BUILD_SEMVER_LIGHTWEIGHT)
         .author("Gregory Szorc <gregory.szorc@gmail.com>")
@@ -82,7 +363,90 @@ pub fn run_cli() -> Result<()> {
         .arg(
             Arg::with_name("verbose")
                 .long("verbose")
-                .help("Enable verbose output"),
+                .global(true)
+                .help("Enable verbose output, including verbose cargo output"),
+        )
+        .arg(
+            Arg::with_name("quiet")
+                .long("quiet")
+                .short("q")
+                .global(true)
+                .conflicts_with("verbose")
+                .help("Only print errors and the path of what was built"),
+        )
+        .arg(
+            Arg::with_name("wait_for_unlock")
+                .long("wait-for-unlock")
+                .takes_value(true)
+                .value_name("SECONDS")
+                .help("Wait up to this long for files locked by other processes (e.g. anti-virus)"),
+        )
+        .arg(
+            Arg::with_name("no_rustc_bootstrap")
+                .long("no-rustc-bootstrap")
+                .global(true)
+                .help("Fail builds needing RUSTC_BOOTSTRAP=1 instead of setting it"),
+        )
+        .arg(
+            Arg::with_name("force_python_sys_executable")
+                .long("force-python-sys-executable")
+                .global(true)
+                .help("Configure Rust crates with PYTHON_SYS_EXECUTABLE from the environment instead of refusing to build"),
+        )
+        .arg(
+            Arg::with_name("canonical_paths")
+                .long("canonical-paths")
+                .global(true)
+                .help("Resolve symlinks in the project path instead of preserving them"),
+        )
+        .arg(
+            Arg::with_name("stats_file")
+                .long("stats-file")
+                .global(true)
+                .takes_value(true)
+                .value_name("PATH")
+                .help("Append statistics about this invocation to this JSON lines file"),
+        )
+        .arg(
+            Arg::with_name("output_layout")
+                .long("output-layout")
+                .global(true)
+                .takes_value(true)
+                .value_name("LAYOUT")
+                .help("Where to write packaged applications, e.g. apps/{app}/{triple}/{profile}"),
+        )
+        .arg(
+            Arg::with_name("color")
+                .long("color")
+                .global(true)
+                .takes_value(true)
+                .value_name("WHEN")
+                .possible_values(&["auto", "always", "never"])
+                .help("Whether cargo and pip use colors; auto honors NO_COLOR and CLICOLOR_FORCE"),
+        )
+        .arg(
+            Arg::with_name("toolchain")
+                .long("toolchain")
+                .global(true)
+                .takes_value(true)
+                .value_name("TOOLCHAIN")
+                .help("Rust toolchain to build with: a rustup toolchain name or a path to cargo"),
+        )
+        .arg(
+            Arg::with_name("var")
+                .long("var")
+                .global(true)
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .value_name("NAME=VALUE")
+                .help("Define a build variable the config can read from BUILD_VARS"),
+        )
+        .arg(
+            Arg::with_name("legacy_exit_codes")
+                .long("legacy-exit-codes")
+                .global(true)
+                .help("Exit with code 1 on all errors and don't print a status line (deprecated)"),
         )
         .subcommand(
             SubCommand::with_name("add")
@@ -98,9 +462,30 @@ pub fn run_cli() -> Result<()> {
         )
         .subcommand(
             SubCommand::with_name("analyze")
-                .about("Analyze a built binary")
+                .about("Analyze a built binary or an import profile")
+                .long_about(ANALYZE_ABOUT)
                 .setting(AppSettings::ArgRequiredElseHelp)
-                .arg(Arg::with_name("path").help("Path to executable to analyze")),
+                .arg(
+                    Arg::with_name("path")
+                        .required_unless("import-profile")
+                        .help("Path to executable to analyze"),
+                )
+                .arg(
+                    Arg::with_name("import-profile")
+                        .long("import-profile")
+                        .takes_value(true)
+                        .value_name("PATH")
+                        .conflicts_with("path")
+                        .help("Analyze an import profile written by an application"),
+                )
+                .arg(
+                    Arg::with_name("modules")
+                        .long("modules")
+                        .takes_value(true)
+                        .value_name("PATH")
+                        .requires("import-profile")
+                        .help("py-modules file of the build artifacts to join the import profile with"),
+                ),
         )
         .subcommand(
             SubCommand::with_name("app-path")
@@ -123,6 +508,43 @@ pub fn run_cli() -> Result<()> {
                         .help("Directory containing project to resolve"),
                 ),
         )
+        .subcommand(
+            SubCommand::with_name("cache")
+                .setting(AppSettings::ArgRequiredElseHelp)
+                .about("Manage the cache shared by all projects")
+                .subcommand(
+                    SubCommand::with_name("add")
+                        .about("Add a Python distribution archive for --offline builds")
+                        .arg(
+                            Arg::with_name("path")
+                                .required(true)
+                                .value_name("PATH")
+                                .help("Path to a Python distribution archive"),
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name("list")
+                        .about("Show cached Python distributions and the size of cached bytecode"),
+                )
+                .subcommand(
+                    SubCommand::with_name("purge")
+                        .about("Delete cached entries")
+                        .arg(
+                            Arg::with_name("max-size")
+                                .long("max-size")
+                                .takes_value(true)
+                                .value_name("MB")
+                                .help("Only delete least recently used bytecode until it is at most this many megabytes"),
+                        )
+                        .arg(
+                            Arg::with_name("older-than")
+                                .long("older-than")
+                                .takes_value(true)
+                                .value_name("DAYS")
+                                .help("Only delete entries not used for this many days"),
+                        ),
+                ),
+        )
         .subcommand(
             SubCommand::with_name("run-build-script")
                 .setting(AppSettings::ArgRequiredElseHelp)
@@ -153,6 +575,20 @@ pub fn run_cli() -> Result<()> {
                         .number_of_values(1)
                         .help("Python packages to install via `pip install`"),
                 )
+                .arg(
+                    Arg::with_name("lib")
+                        .long("lib")
+                        .conflicts_with("python-code")
+                        .help("Create a shared library embedding Python in a host application"),
+                )
+                .arg(
+                    Arg::with_name("template")
+                        .long("template")
+                        .takes_value(true)
+                        .possible_values(project_layout::ProjectTemplate::NAMES)
+                        .conflicts_with_all(&["lib", "python-code"])
+                        .help("Kind of application to create"),
+                )
                 .arg(
                     Arg::with_name("name")
                         .required(true)
@@ -160,6 +596,23 @@ pub fn run_cli() -> Result<()> {
                         .help("Directory to be created for new project"),
                 ),
         )
+        .subcommand(
+            SubCommand::with_name("lock")
+                .about("Write a lockfile recording the inputs of a project")
+                .long_about(LOCK_ABOUT)
+                .arg(
+                    Arg::with_name("target")
+                        .long("target")
+                        .takes_value(true)
+                        .help("Rust target triple to resolve inputs for"),
+                )
+                .arg(
+                    Arg::with_name("path")
+                        .default_value(".")
+                        .value_name("PATH")
+                        .help("Directory containing project to lock"),
+                ),
+        )
         .subcommand(
             SubCommand::with_name("build")
                 .setting(AppSettings::ArgRequiredElseHelp)
@@ -169,18 +622,99 @@ pub fn run_cli() -> Result<()> {
                     Arg::with_name("target")
                         .long("target")
                         .takes_value(true)
-                        .help("Rust target triple to build for"),
+                        .multiple(true)
+                        .number_of_values(1)
+                        .help("Rust target triple to build for (may be repeated)"),
+                )
+                .arg(
+                    Arg::with_name("fail-fast")
+                        .long("fail-fast")
+                        .help("Stop building for further targets after one fails"),
                 )
                 .arg(
                     Arg::with_name("release")
                         .long("release")
                         .help("Build a release binary"),
                 )
+                .arg(
+                    Arg::with_name("locked")
+                        .long("locked")
+                        .help("Require inputs to match pyoxidizer.lock.json"),
+                )
+                .arg(
+                    Arg::with_name("frozen")
+                        .long("frozen")
+                        .help("Like --locked but also forbid network access"),
+                )
+                .arg(
+                    Arg::with_name("offline")
+                        .long("offline")
+                        .help("Forbid network access and only use cached Python distributions"),
+                )
+                .arg(
+                    Arg::with_name("force-artifacts")
+                        .long("force-artifacts")
+                        .help("Regenerate PyOxidizer artifacts even if they are current"),
+                )
+                .arg(
+                    Arg::with_name("force")
+                        .long("force")
+                        .help("Like --force-artifacts but also make cargo rebuild the project"),
+                )
+                .arg(
+                    Arg::with_name("skip-space-check")
+                        .long("skip-space-check")
+                        .help("Don't check for free disk space before building"),
+                )
+                .arg(
+                    Arg::with_name("deny-generated-warnings")
+                        .long("deny-generated-warnings")
+                        .help("Fail the build on warnings in code generated by PyOxidizer"),
+                )
+                .arg(
+                    Arg::with_name("third-party-notices")
+                        .long("third-party-notices")
+                        .help("Write third-party license notices into the application directory"),
+                )
+                .arg(
+                    Arg::with_name("plan")
+                        .long("plan")
+                        .takes_value(true)
+                        .min_values(0)
+                        .max_values(1)
+                        .require_equals(true)
+                        .possible_values(&["full", "shallow"])
+                        .value_name("MODE")
+                        .help("Print what the build would do instead of building"),
+                )
+                .arg(
+                    Arg::with_name("format")
+                        .long("format")
+                        .takes_value(true)
+                        .possible_values(&["text", "json"])
+                        .requires("plan")
+                        .help("Format of the printed plan (default: text)"),
+                )
+                .arg(
+                    Arg::with_name("message-format")
+                        .long("message-format")
+                        .takes_value(true)
+                        .possible_values(&["human", "json"])
+                        .conflicts_with("plan")
+                        .help("Format of build output; json prints events to stdout (default: human)"),
+                )
                 .arg(
                     Arg::with_name("path")
                         .default_value(".")
                         .value_name("PATH")
                         .help("Directory containing project to build"),
+                )
+                .arg(
+                    Arg::with_name("cargo_args")
+                        .multiple(true)
+                        .last(true)
+                        .value_name("CARGO_ARGS")
+                        .help("Arguments after -- to append to the cargo build invocation"),
                 ),
         )
         .subcommand(
@@ -197,12 +731,49 @@ pub fn run_cli() -> Result<()> {
                         .long("release")
                         .help("Build a release binary"),
                 )
+                .arg(
+                    Arg::with_name("locked")
+                        .long("locked")
+                        .help("Require inputs to match pyoxidizer.lock.json"),
+                )
+                .arg(
+                    Arg::with_name("frozen")
+                        .long("frozen")
+                        .help("Like --locked but also forbid network access"),
+                )
+                .arg(
+                    Arg::with_name("offline")
+                        .long("offline")
+                        .help("Forbid network access and only use cached Python distributions"),
+                )
+                .arg(
+                    Arg::with_name("force-artifacts")
+                        .long("force-artifacts")
+                        .help("Regenerate PyOxidizer artifacts even if they are current"),
+                )
+                .arg(
+                    Arg::with_name("force")
+                        .long("force")
+                        .help("Same as --force-artifacts"),
+                )
+                .arg(
+                    Arg::with_name("skip-space-check")
+                        .long("skip-space-check")
+                        .help("Don't check for free disk space before building"),
+                )
                 .arg(
                     Arg::with_name("path")
                         .default_value(".")
                         .value_name("PROJECT_PATH")
                         .help("Path to PyOxidizer config file to process"),
                 )
+                .arg(
+                    Arg::with_name("message-format")
+                        .long("message-format")
+                        .takes_value(true)
+                        .possible_values(&["human", "json"])
+                        .help("Format of build output; json prints events to stdout (default: human)"),
+                )
                 .arg(
                     Arg::with_name("dest_path")
                         .required(true)
@@ -210,6 +781,209 @@ pub fn run_cli() -> Result<()> {
                         .help("Directory to write artifacts to"),
                 ),
         )
+        .subcommand(
+            SubCommand::with_name("prepare")
+                .about("Evaluate a config and generate everything needed to compile it elsewhere")
+                .long_about(PREPARE_ABOUT)
+                .arg(
+                    Arg::with_name("out")
+                        .long("out")
+                        .takes_value(true)
+                        .required(true)
+                        .value_name("DIR")
+                        .help("Empty directory to write the prepared build to"),
+                )
+                .arg(
+                    Arg::with_name("target")
+                        .long("target")
+                        .takes_value(true)
+                        .help("Rust target triple to build for"),
+                )
+                .arg(
+                    Arg::with_name("release")
+                        .long("release")
+                        .help("Build a release binary"),
+                )
+                .arg(
+                    Arg::with_name("locked")
+                        .long("locked")
+                        .help("Require inputs to match pyoxidizer.lock.json"),
+                )
+                .arg(
+                    Arg::with_name("frozen")
+                        .long("frozen")
+                        .help("Like --locked but also forbid network access"),
+                )
+                .arg(
+                    Arg::with_name("offline")
+                        .long("offline")
+                        .help("Forbid network access and only use cached Python distributions"),
+                )
+                .arg(
+                    Arg::with_name("skip-space-check")
+                        .long("skip-space-check")
+                        .help("Don't check for free disk space before building"),
+                )
+                .arg(
+                    Arg::with_name("message-format")
+                        .long("message-format")
+                        .takes_value(true)
+                        .possible_values(&["human", "json"])
+                        .help("Format of build output; json prints events to stdout (default: human)"),
+                )
+                .arg(
+                    Arg::with_name("path")
+                        .default_value(".")
+                        .value_name("PROJECT_PATH")
+                        .help("Directory containing project to prepare"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("compile")
+                .about("Compile and package a build written by `pyoxidizer prepare`")
+                .arg(
+                    Arg::with_name("prepared")
+                        .long("prepared")
+                        .takes_value(true)
+                        .required(true)
+                        .value_name("DIR")
+                        .help("Directory written by `pyoxidizer prepare`"),
+                )
+                .arg(
+                    Arg::with_name("target-dir")
+                        .long("target-dir")
+                        .takes_value(true)
+                        .required(true)
+                        .value_name("DIR")
+                        .help("Directory to build and package the application in"),
+                )
+                .arg(
+                    Arg::with_name("target")
+                        .long("target")
+                        .takes_value(true)
+                        .help("Rust target triple the build was prepared for (default: this machine's)"),
+                )
+                .arg(
+                    Arg::with_name("offline")
+                        .long("offline")
+                        .help("Forbid network access, including cargo fetching crates"),
+                )
+                .arg(
+                    Arg::with_name("skip-space-check")
+                        .long("skip-space-check")
+                        .help("Don't check for free disk space before building"),
+                )
+                .arg(
+                    Arg::with_name("deny-generated-warnings")
+                        .long("deny-generated-warnings")
+                        .help("Fail the build on warnings in code generated by PyOxidizer"),
+                )
+                .arg(
+                    Arg::with_name("third-party-notices")
+                        .long("third-party-notices")
+                        .help("Write third-party license notices into the application directory"),
+                )
+                .arg(
+                    Arg::with_name("message-format")
+                        .long("message-format")
+                        .takes_value(true)
+                        .possible_values(&["human", "json"])
+                        .help("Format of build output; json prints events to stdout (default: human)"),
+                )
+                .arg(
+                    Arg::with_name("cargo_args")
+                        .multiple(true)
+                        .last(true)
+                        .value_name("CARGO_ARGS")
+                        .help("Arguments after -- to append to the cargo build invocation"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("clean")
+                .about("Remove build state generated for a project")
+                .long_about(CLEAN_ABOUT)
+                .arg(
+                    Arg::with_name("target")
+                        .long("target")
+                        .takes_value(true)
+                        .help("Rust target triple to evaluate the config for"),
+                )
+                .arg(
+                    Arg::with_name("dry-run")
+                        .long("dry-run")
+                        .help("Print what would be removed without removing it"),
+                )
+                .arg(
+                    Arg::with_name("dist-cache")
+                        .long("dist-cache")
+                        .conflicts_with("other-versions")
+                        .help("Also remove the project's Python distributions from the distribution cache"),
+                )
+                .arg(
+                    Arg::with_name("other-versions")
+                        .long("other-versions")
+                        .help("Only remove artifacts written by other PyOxidizer versions"),
+                )
+                .arg(
+                    Arg::with_name("path")
+                        .default_value(".")
+                        .value_name("PATH")
+                        .help("Directory containing project to clean"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("diff-config")
+                .about("Show behavioral differences between two configs")
+                .long_about(DIFF_CONFIG_ABOUT)
+                .arg(
+                    Arg::with_name("target")
+                        .long("target")
+                        .takes_value(true)
+                        .help("Rust target triple to evaluate configs for"),
+                )
+                .arg(
+                    Arg::with_name("other-target")
+                        .long("other-target")
+                        .takes_value(true)
+                        .help("Rust target triple to evaluate the second config for"),
+                )
+                .arg(
+                    Arg::with_name("config_a")
+                        .required(true)
+                        .value_name("CONFIG_A")
+                        .help("Path to the config to compare from"),
+                )
+                .arg(
+                    Arg::with_name("config_b")
+                        .required(true)
+                        .value_name("CONFIG_B")
+                        .help("Path to the config to compare to"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("freeze-deps")
+                .about("Pin installed Python packages in a constraints file")
+                .long_about(FREEZE_DEPS_ABOUT)
+                .arg(
+                    Arg::with_name("target")
+                        .long("target")
+                        .takes_value(true)
+                        .help("Rust target triple to resolve packages for"),
+                )
+                .arg(
+                    Arg::with_name("output")
+                        .long("output")
+                        .takes_value(true)
+                        .value_name("FILE")
+                        .help("Constraints file to write"),
+                )
+                .arg(
+                    Arg::with_name("path")
+                        .default_value(".")
+                        .value_name("PATH")
+                        .help("Directory containing project to freeze"),
+                ),
+        )
         .subcommand(
             SubCommand::with_name("run")
                 .setting(AppSettings::TrailingVarArg)
@@ -225,13 +999,73 @@ pub fn run_cli() -> Result<()> {
                         .long("release")
                         .help("Run a release binary"),
                 )
+                .arg(
+                    Arg::with_name("locked")
+                        .long("locked")
+                        .help("Require inputs to match pyoxidizer.lock.json"),
+                )
+                .arg(
+                    Arg::with_name("frozen")
+                        .long("frozen")
+                        .help("Like --locked but also forbid network access"),
+                )
+                .arg(
+                    Arg::with_name("offline")
+                        .long("offline")
+                        .help("Forbid network access and only use cached Python distributions"),
+                )
+                .arg(
+                    Arg::with_name("force-artifacts")
+                        .long("force-artifacts")
+                        .help("Regenerate PyOxidizer artifacts even if they are current"),
+                )
+                .arg(
+                    Arg::with_name("force")
+                        .long("force")
+                        .help("Like --force-artifacts but also make cargo rebuild the project"),
+                )
+                .arg(
+                    Arg::with_name("skip-space-check")
+                        .long("skip-space-check")
+                        .help("Don't check for free disk space before building"),
+                )
+                .arg(
+                    Arg::with_name("env")
+                        .long("env")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .value_name("KEY=VALUE")
+                        .help("Set an environment variable for the application"),
+                )
+                .arg(
+                    Arg::with_name("env-file")
+                        .long("env-file")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .value_name("PATH")
+                        .help("Set environment variables defined as KEY=VALUE lines in a file"),
+                )
+                .arg(
+                    Arg::with_name("cwd")
+                        .long("cwd")
+                        .takes_value(true)
+                        .value_name("DIR")
+                        .help("Run the application in this directory instead of the project's"),
+                )
                 .arg(
                     Arg::with_name("path")
                         .default_value(".")
                         .value_name("PATH")
                         .help("Directory containing project to build"),
                 )
-                .arg(Arg::with_name("extra").multiple(true)),
+                .arg(
+                    Arg::with_name("extra")
+                        .multiple(true)
+                        .value_name("ARGS")
+                        .help("Arguments to pass to the application, after --"),
+                ),
         )
         .subcommand(
             SubCommand::with_name("python-distribution-extract")
@@ -244,14 +1078,47 @@ pub fn run_cli() -> Result<()> {
                 )
                 .arg(
                     Arg::with_name("dest_path")
-                        .required(true)
+                        .required_unless("list")
                         .value_name("DESTINATION_PATH")
                         .help("Path to directory where distribution should be extracted"),
+                )
+                .arg(
+                    Arg::with_name("path")
+                        .long("path")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .value_name("PATTERN")
+                        .help("Only extract entries matching this path or glob pattern"),
+                )
+                .arg(
+                    Arg::with_name("list")
+                        .long("list")
+                        .conflicts_with("dest_path")
+                        .help("Print entry names and sizes instead of extracting"),
                 ),
         )
         .subcommand(
             SubCommand::with_name("python-distribution-info")
                 .about("Show information about a Python distribution archive")
+                .arg(
+                    Arg::with_name("sizes")
+                        .long("sizes")
+                        .help("Show the largest components of the distribution by category"),
+                )
+                .arg(
+                    Arg::with_name("format")
+                        .long("format")
+                        .takes_value(true)
+                        .possible_values(&["text", "json"])
+                        .default_value("text")
+                        .help("Output format"),
+                )
+                .arg(
+                    Arg::with_name("json")
+                        .long("json")
+                        .help("Same as --format json"),
+                )
                 .arg(
                     Arg::with_name("path")
                         .required(true)
@@ -269,17 +1136,183 @@ pub fn run_cli() -> Result<()> {
                         .help("Path to Python distribution to analyze"),
                 ),
         )
-        .get_matches();
+        .subcommand(
+            SubCommand::with_name("upgrade-project")
+                .about("Upgrade the PyOxidizer files of a project")
+                .long_about(UPGRADE_PROJECT_ABOUT)
+                .arg(
+                    Arg::with_name("path")
+                        .default_value(".")
+                        .value_name("PATH")
+                        .help("Directory of PyOxidizer project"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("verify")
+                .about("Verify a packaged application against its build manifest")
+                .long_about(VERIFY_ABOUT)
+                .arg(
+                    Arg::with_name("manifest")
+                        .long("manifest")
+                        .required_unless("lockfile")
+                        .conflicts_with("lockfile")
+                        .takes_value(true)
+                        .value_name("PATH")
+                        .help("Path to build manifest"),
+                )
+                .arg(
+                    Arg::with_name("artifact")
+                        .long("artifact")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .value_name("PATH")
+                        .help("Artifact to verify (defaults to all files in manifest)"),
+                )
+                .arg(
+                    Arg::with_name("lockfile")
+                        .long("lockfile")
+                        .takes_value(true)
+                        .value_name("PATH")
+                        .help("Verify project inputs against a lockfile instead"),
+                ),
+        );
 
-    let verbose = matches.is_present("verbose");
+    let argv = std::env::args_os().collect::<Vec<_>>();
+    let mut matches = parse_args(app.clone(), &argv)?;
+
+    // Arguments after `--` belong to the application run, verbatim. Left to
+    // clap, the first of them would be taken as the project path.
+    let mut app_args = Vec::new();
+    if matches.subcommand_name() == Some("run") {
+        let (ours, theirs) = run_env::split_app_args(&argv).category(ErrorCategory::Usage)?;
+        if let Some(theirs) = theirs {
+            matches = parse_args(app, ours)?;
+            app_args = theirs;
+        }
+    }
+
+    if let Some(command) = matches.subcommand_name() {
+        build_stats::set_command(command);
+    }
 
-    let log_level = if verbose {
-        slog::Level::Info
-    } else {
-        slog::Level::Warning
+    // The flags are global, so they may be given after the subcommand.
+    let global_flag = |name: &str| {
+        matches.is_present(name)
+            || matches
+                .subcommand()
+                .1
+                .map_or(false, |args| args.is_present(name))
     };
 
-    let logger_context = logging::logger_from_env(log_level);
+    let verbosity = Verbosity::from_flags(global_flag("quiet"), global_flag("verbose"));
+
+    // Only build commands accept the flag.
+    let message_format = matches
+        .subcommand()
+        .1
+        .and_then(|args| args.value_of("message-format"));
+    if message_format == Some("json") {
+        build_events::use_json_messages();
+    }
+
+    let logger_context = logging::logger_from_env(verbosity.log_level());
+
+    if let Some(value) = matches.value_of("wait_for_unlock") {
+        let seconds = value.parse::<u64>().or_else(|_| {
+            Err(categorize(
+                anyhow!("--wait-for-unlock must be a number of seconds"),
+                ErrorCategory::Usage,
+            ))
+        })?;
+        fsutils::set_wait_for_unlock(Duration::from_secs(seconds));
+    }
+
+    // The flag is global, so it may be given after the subcommand.
+    let no_rustc_bootstrap = matches.is_present("no_rustc_bootstrap")
+        || matches
+            .subcommand()
+            .1
+            .map_or(false, |args| args.is_present("no_rustc_bootstrap"));
+    if no_rustc_bootstrap {
+        rustc_bootstrap::forbid_rustc_bootstrap();
+    }
+
+    let force_python_sys_executable = matches.is_present("force_python_sys_executable")
+        || matches
+            .subcommand()
+            .1
+            .map_or(false, |args| args.is_present("force_python_sys_executable"));
+    if force_python_sys_executable {
+        python_sys::force_python_sys_executable();
+    }
+
+    let canonical_paths = matches.is_present("canonical_paths")
+        || matches
+            .subcommand()
+            .1
+            .map_or(false, |args| args.is_present("canonical_paths"));
+    if canonical_paths {
+        environment::use_canonical_paths();
+    }
+
+    let layout = matches.value_of("output_layout").or_else(|| {
+        matches
+            .subcommand()
+            .1
+            .and_then(|args| args.value_of("output_layout"))
+    });
+    if let Some(layout) = layout {
+        output_layout::validate_output_layout(layout).map_err(|e| {
+            categorize(
+                anyhow!("invalid --output-layout: {}", e),
+                ErrorCategory::Usage,
+            )
+        })?;
+        output_layout::set_output_layout_override(layout);
+    }
+
+    let color_value = matches.value_of("color").or_else(|| {
+        matches
+            .subcommand()
+            .1
+            .and_then(|args| args.value_of("color"))
+    });
+    if let Some(value) = color_value {
+        color::set_color_choice(ColorChoice::parse(value)?);
+    }
+
+    let toolchain_value = matches.value_of("toolchain").or_else(|| {
+        matches
+            .subcommand()
+            .1
+            .and_then(|args| args.value_of("toolchain"))
+    });
+    if let Some(value) = toolchain_value {
+        let toolchain = Toolchain::parse(value, &std::env::current_dir()?)
+            .map_err(|e| categorize(anyhow!("invalid --toolchain: {}", e), ErrorCategory::Usage))?;
+        toolchain::override_toolchain(toolchain);
+    }
+
+    let vars = matches
+        .values_of("var")
+        .into_iter()
+        .flatten()
+        .chain(
+            matches
+                .subcommand()
+                .1
+                .and_then(|args| args.values_of("var"))
+                .into_iter()
+                .flatten(),
+        )
+        .collect::<Vec<_>>();
+    let mut eval_options = EvalOptions::from_env();
+    for definition in vars {
+        let (name, value) = build_vars::parse_build_var(definition)
+            .map_err(|e| categorize(anyhow!("invalid --var: {}", e), ErrorCategory::Usage))?;
+        eval_options.build_vars.insert(name, value);
+    }
 
     match matches.subcommand() {
         ("add", Some(args)) => {
@@ -289,6 +1322,13 @@ pub fn run_cli() -> Result<()> {
         }
 
         ("analyze", Some(args)) => {
+            if let Some(profile) = args.value_of("import-profile") {
+                return import_profile::analyze_import_profile_path(
+                    Path::new(profile),
+                    args.value_of("modules").map(Path::new),
+                );
+            }
+
             let path = args.value_of("path").unwrap();
             let path = PathBuf::from(path);
             analyze::analyze_file(path);
@@ -308,6 +1348,7 @@ pub fn run_cli() -> Result<()> {
                 target,
                 release,
                 None,
+                verbosity,
                 false,
             )?;
 
@@ -316,6 +1357,50 @@ pub fn run_cli() -> Result<()> {
             Ok(())
         }
 
+        ("cache", Some(args)) => match args.subcommand() {
+            ("add", Some(args)) => {
+                let path = args.value_of("path").unwrap();
+
+                cache::add_distribution(&logger_context.logger, Path::new(path))
+            }
+            ("list", Some(_)) => cache::list(&logger_context.logger),
+            ("purge", Some(args)) => {
+                let max_size = match args.value_of("max-size") {
+                    Some(value) => {
+                        let megabytes = value.parse::<u64>().or_else(|_| {
+                            Err(categorize(
+                                anyhow!("--max-size must be a number of megabytes"),
+                                ErrorCategory::Usage,
+                            ))
+                        })?;
+
+                        Some(megabytes * 1024 * 1024)
+                    }
+                    None => None,
+                };
+
+                let older_than = match args.value_of("older-than") {
+                    Some(value) => {
+                        let days = value.parse::<u64>().or_else(|_| {
+                            Err(categorize(
+                                anyhow!("--older-than must be a number of days"),
+                                ErrorCategory::Usage,
+                            ))
+                        })?;
+
+                        Some(std::time::Duration::from_secs(days * 86400))
+                    }
+                    None => None,
+                };
+
+                cache::purge(&logger_context.logger, max_size, older_than)
+            }
+            _ => Err(categorize(
+                anyhow!("invalid cache sub-command"),
+                ErrorCategory::Usage,
+            )),
+        },
+
         ("build-artifacts", Some(args)) => {
             let target = args.value_of("target");
             let release = args.is_present("release");
@@ -330,16 +1415,159 @@ pub fn run_cli() -> Result<()> {
                 &dest_path,
                 target,
                 release,
-                verbose,
+                verbosity,
+                locked(args),
+                &offline_options(&eval_options, args),
+                force(args).0,
+                args.is_present("skip-space-check"),
+                &interrupt_token()?,
             )
         }
 
+        ("prepare", Some(args)) => {
+            let out_path = PathBuf::from(args.value_of("out").unwrap());
+
+            projectmgmt::prepare(
+                &logger_context.logger,
+                args.value_of("path").unwrap(),
+                &out_path,
+                args.value_of("target"),
+                args.is_present("release"),
+                verbosity,
+                locked(args),
+                &offline_options(&eval_options, args),
+                args.is_present("skip-space-check"),
+                &interrupt_token()?,
+            )
+        }
+
+        ("compile", Some(args)) => {
+            let prepared_path = PathBuf::from(args.value_of("prepared").unwrap());
+            let target_dir = PathBuf::from(args.value_of("target-dir").unwrap());
+            let cargo_args = args
+                .values_of("cargo_args")
+                .map_or_else(Vec::new, |values| values.collect::<Vec<_>>());
+            let extra_cargo_args = ExtraCargoArgs::parse(&cargo_args)
+                .map_err(|e| categorize(e, ErrorCategory::Usage))?;
+
+            projectmgmt::compile(
+                &logger_context.logger,
+                &prepared_path,
+                &target_dir,
+                args.value_of("target"),
+                verbosity,
+                &offline_options(&eval_options, args),
+                args.is_present("deny-generated-warnings"),
+                args.is_present("skip-space-check"),
+                args.is_present("third-party-notices"),
+                &extra_cargo_args,
+                &interrupt_token()?,
+            )
+            .map(|_| ())
+        }
+
         ("build", Some(args)) => {
             let release = args.is_present("release");
+            let targets = args
+                .values_of("target")
+                .map_or_else(Vec::new, |values| values.collect::<Vec<_>>());
+            let path = args.value_of("path").unwrap();
+
+            if args.is_present("plan") {
+                if targets.len() > 1 {
+                    return Err(categorize(
+                        anyhow!("--plan only supports a single --target"),
+                        ErrorCategory::Usage,
+                    ));
+                }
+                let target = targets.first().cloned();
+
+                let mode = match args.value_of("plan") {
+                    Some("shallow") => PlanMode::Shallow,
+                    _ => PlanMode::Full,
+                };
+
+                return projectmgmt::plan(
+                    &logger_context.logger,
+                    path,
+                    target,
+                    release,
+                    verbosity,
+                    locked(args),
+                    &offline_options(&eval_options, args),
+                    mode,
+                    args.value_of("format") == Some("json"),
+                    &interrupt_token()?,
+                );
+            }
+
+            let cargo_args = args
+                .values_of("cargo_args")
+                .map_or_else(Vec::new, |values| values.collect::<Vec<_>>());
+            let extra_cargo_args = ExtraCargoArgs::parse(&cargo_args)
+                .map_err(|e| categorize(e, ErrorCategory::Usage))?;
+
+            let (force_artifacts, force_cargo) = force(args);
+
+            let options = BuildOptions {
+                fail_fast: args.is_present("fail-fast"),
+                release,
+                verbosity,
+                locked: locked(args),
+                eval_options: offline_options(&eval_options, args),
+                deny_generated_warnings: args.is_present("deny-generated-warnings"),
+                force_artifacts,
+                force_cargo,
+                skip_space_check: args.is_present("skip-space-check"),
+                third_party_notices: args.is_present("third-party-notices"),
+                extra_cargo_args,
+            };
+
+            projectmgmt::build(
+                &logger_context.logger,
+                path,
+                &targets,
+                &options,
+                &interrupt_token()?,
+            )
+        }
+
+        ("clean", Some(args)) => {
+            let path = args.value_of("path").unwrap();
+
+            projectmgmt::clean(
+                &logger_context.logger,
+                path,
+                args.value_of("target"),
+                args.is_present("dry-run"),
+                args.is_present("dist-cache"),
+                args.is_present("other-versions"),
+                &eval_options,
+            )
+        }
+
+        ("diff-config", Some(args)) => {
+            let config_a = args.value_of("config_a").unwrap();
+            let config_b = args.value_of("config_b").unwrap();
+            let target = args.value_of("target");
+            let other_target = args.value_of("other-target");
+
+            projectmgmt::diff_config(
+                &logger_context.logger,
+                config_a,
+                config_b,
+                target,
+                other_target,
+                &eval_options,
+            )
+        }
+
+        ("freeze-deps", Some(args)) => {
             let target = args.value_of("target");
+            let output = args.value_of("output");
             let path = args.value_of("path").unwrap();
 
-            projectmgmt::build(&logger_context.logger, path, target, release, verbose)
+            projectmgmt::freeze_deps(&logger_context.logger, path, target, output, &eval_options)
         }
 
         ("init", Some(args)) => {
@@ -351,20 +1579,35 @@ pub fn run_cli() -> Result<()> {
             };
             let name = args.value_of("name").unwrap();
 
-            projectmgmt::init(name, code, &pip_install)
+            let template = project_layout::ProjectTemplate::from_name(
+                args.value_of("template").unwrap_or("repl"),
+            )?;
+
+            projectmgmt::init(name, code, &pip_install, args.is_present("lib"), template)
+        }
+
+        ("lock", Some(args)) => {
+            let target = args.value_of("target");
+            let path = args.value_of("path").unwrap();
+
+            projectmgmt::lock(&logger_context.logger, path, target, &eval_options)
         }
 
         ("python-distribution-extract", Some(args)) => {
             let dist_path = args.value_of("dist_path").unwrap();
-            let dest_path = args.value_of("dest_path").unwrap();
+            let dest_path = args.value_of("dest_path");
+            let filters: Vec<&str> = args.values_of("path").unwrap_or_default().collect();
+            let list = args.is_present("list");
 
-            projectmgmt::python_distribution_extract(dist_path, dest_path)
+            projectmgmt::python_distribution_extract(dist_path, dest_path, &filters, list)
         }
 
         ("python-distribution-info", Some(args)) => {
             let dist_path = args.value_of("path").unwrap();
+            let sizes = args.is_present("sizes");
+            let json = args.is_present("json") || args.value_of("format") == Some("json");
 
-            projectmgmt::python_distribution_info(dist_path)
+            projectmgmt::python_distribution_info(dist_path, sizes, json)
         }
 
         ("python-distribution-licenses", Some(args)) => {
@@ -383,7 +1626,25 @@ pub fn run_cli() -> Result<()> {
             let target = args.value_of("target");
             let release = args.is_present("release");
             let path = args.value_of("path").unwrap();
-            let extra: Vec<&str> = args.values_of("extra").unwrap_or_default().collect();
+            let mut extra: Vec<&str> = args.values_of("extra").unwrap_or_default().collect();
+            extra.extend(app_args.iter().map(|arg| arg.as_str()));
+            let (force_artifacts, force_cargo) = force(args);
+
+            // Files are applied first, so --env overrides them.
+            let mut options = RunOptions::default();
+            for path in args.values_of("env-file").unwrap_or_default() {
+                options.env.extend(
+                    run_env::read_env_file(Path::new(path)).category(ErrorCategory::Usage)?,
+                );
+            }
+            for definition in args.values_of("env").unwrap_or_default() {
+                options
+                    .env
+                    .push(run_env::parse_env_var(definition).map_err(|e| {
+                        categorize(anyhow!("invalid --env: {}", e), ErrorCategory::Usage)
+                    })?);
+            }
+            options.cwd = args.value_of("cwd").map(PathBuf::from);
 
             projectmgmt::run(
                 &logger_context.logger,
@@ -391,10 +1652,40 @@ pub fn run_cli() -> Result<()> {
                 target,
                 release,
                 &extra,
-                verbose,
+                &options,
+                verbosity,
+                locked(args),
+                &offline_options(&eval_options, args),
+                force_artifacts,
+                force_cargo,
+                args.is_present("skip-space-check"),
+                &interrupt_token()?,
             )
         }
 
+        ("upgrade-project", Some(args)) => {
+            let path = args.value_of("path").unwrap();
+
+            project_layout::upgrade_project(Path::new(path))?;
+
+            Ok(())
+        }
+
+        ("verify", Some(args)) => {
+            if let Some(lockfile) = args.value_of("lockfile") {
+                return projectmgmt::verify_lockfile_path(
+                    &logger_context.logger,
+                    lockfile,
+                    &eval_options,
+                );
+            }
+
+            let manifest = args.value_of("manifest").unwrap();
+            let artifacts: Vec<&str> = args.values_of("artifact").unwrap_or_default().collect();
+
+            projectmgmt::verify(manifest, &artifacts)
+        }
+
         _ => Err(anyhow!("invalid sub-command")),
     }
 }
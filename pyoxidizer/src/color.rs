@@ -0,0 +1,251 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/*!
+When output uses colors.
+
+`--color` chooses `auto` (the default), `always` or `never`. With `auto`,
+colors are disabled if `NO_COLOR` is set to a non-empty value, forced if
+`CLICOLOR_FORCE` is set to anything but `0`, and otherwise used when
+writing to a terminal. An explicit `always` or `never` wins over the
+environment.
+
+Child tools are told explicitly whether to use colors instead of guessing
+themselves, as their output often reaches the terminal through us. Tools
+whose output we capture are told `never`, so captured output is free of
+escape sequences.
+*/
+
+use anyhow::{anyhow, Result};
+use lazy_static::lazy_static;
+use std::sync::Mutex;
+
+/// Disables colors when set to a non-empty value.
+pub const NO_COLOR_ENV: &str = "NO_COLOR";
+
+/// Forces colors when set to a value other than `0`.
+pub const CLICOLOR_FORCE_ENV: &str = "CLICOLOR_FORCE";
+
+/// Whether to use colors.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ColorChoice {
+    /// Depending on the environment and whether output goes to a terminal.
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorChoice {
+    pub fn parse(value: &str) -> Result<ColorChoice> {
+        match value {
+            "auto" => Ok(ColorChoice::Auto),
+            "always" => Ok(ColorChoice::Always),
+            "never" => Ok(ColorChoice::Never),
+            _ => Err(anyhow!(
+                "{} is not a color choice; use auto, always or never",
+                value
+            )),
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ColorChoice::Auto => "auto",
+            ColorChoice::Always => "always",
+            ColorChoice::Never => "never",
+        }
+    }
+
+    /// Whether to use colors for output going to a terminal or not.
+    ///
+    /// `env` looks up environment variables.
+    pub fn resolve(self, env: impl Fn(&str) -> Option<String>, is_terminal: bool) -> bool {
+        match self {
+            ColorChoice::Always => true,
+            ColorChoice::Never => false,
+            ColorChoice::Auto => {
+                if env(NO_COLOR_ENV).map_or(false, |value| !value.is_empty()) {
+                    false
+                } else if env(CLICOLOR_FORCE_ENV).map_or(false, |value| value != "0") {
+                    true
+                } else {
+                    is_terminal
+                }
+            }
+        }
+    }
+
+    /// What to tell a child tool.
+    ///
+    /// `captured` is whether we capture the tool's output rather than let it
+    /// reach a terminal or our output. Never returns `Auto`.
+    pub fn for_child(
+        self,
+        env: impl Fn(&str) -> Option<String>,
+        captured: bool,
+        is_terminal: bool,
+    ) -> ColorChoice {
+        if !captured && self.resolve(env, is_terminal) {
+            ColorChoice::Always
+        } else {
+            ColorChoice::Never
+        }
+    }
+
+    /// Environment variables telling a child tool and its children.
+    pub fn child_envs(self) -> Vec<(&'static str, &'static str)> {
+        match self {
+            ColorChoice::Auto => vec![],
+            ColorChoice::Always => vec![(NO_COLOR_ENV, ""), (CLICOLOR_FORCE_ENV, "1")],
+            ColorChoice::Never => vec![(NO_COLOR_ENV, "1"), (CLICOLOR_FORCE_ENV, "0")],
+        }
+    }
+}
+
+lazy_static! {
+    static ref CHOICE: Mutex<ColorChoice> = Mutex::new(ColorChoice::Auto);
+}
+
+/// Use colors as chosen with `--color` for the remainder of the process.
+pub fn set_color_choice(choice: ColorChoice) {
+    *CHOICE.lock().unwrap() = choice;
+}
+
+/// The color choice of the process.
+pub fn color_choice() -> ColorChoice {
+    *CHOICE.lock().unwrap()
+}
+
+/// What to tell a child tool, given the process's choice and environment.
+///
+/// `is_terminal` is whether the tool's output would reach a terminal.
+pub fn child_color(captured: bool, is_terminal: bool) -> ColorChoice {
+    color_choice().for_child(|key| std::env::var(key).ok(), captured, is_terminal)
+}
+
+#[cfg(unix)]
+pub fn stdout_is_terminal() -> bool {
+    unsafe { libc::isatty(libc::STDOUT_FILENO) == 1 }
+}
+
+#[cfg(unix)]
+pub fn stderr_is_terminal() -> bool {
+    unsafe { libc::isatty(libc::STDERR_FILENO) == 1 }
+}
+
+#[cfg(windows)]
+fn handle_is_console(handle: winapi::shared::minwindef::DWORD) -> bool {
+    use winapi::um::consoleapi::GetConsoleMode;
+    use winapi::um::processenv::GetStdHandle;
+
+    let mut mode = 0;
+    unsafe { GetConsoleMode(GetStdHandle(handle), &mut mode) != 0 }
+}
+
+#[cfg(windows)]
+pub fn stdout_is_terminal() -> bool {
+    handle_is_console(winapi::um::winbase::STD_OUTPUT_HANDLE)
+}
+
+#[cfg(windows)]
+pub fn stderr_is_terminal() -> bool {
+    handle_is_console(winapi::um::winbase::STD_ERROR_HANDLE)
+}
+
+#[cfg(not(any(unix, windows)))]
+pub fn stdout_is_terminal() -> bool {
+    false
+}
+
+#[cfg(not(any(unix, windows)))]
+pub fn stderr_is_terminal() -> bool {
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn env<'a>(vars: &'a [(&'a str, &'a str)]) -> impl Fn(&str) -> Option<String> + 'a {
+        move |key| {
+            vars.iter()
+                .find(|(k, _)| *k == key)
+                .map(|(_, v)| v.to_string())
+        }
+    }
+
+    #[test]
+    fn test_parse() -> Result<()> {
+        for choice in &[ColorChoice::Auto, ColorChoice::Always, ColorChoice::Never] {
+            assert_eq!(ColorChoice::parse(choice.as_str())?, *choice);
+        }
+        assert!(ColorChoice::parse("yes").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_resolve() {
+        let cases: &[(ColorChoice, &[(&str, &str)], bool, bool)] = &[
+            (ColorChoice::Auto, &[], true, true),
+            (ColorChoice::Auto, &[], false, false),
+            (ColorChoice::Auto, &[("NO_COLOR", "1")], true, false),
+            // An empty NO_COLOR counts as unset.
+            (ColorChoice::Auto, &[("NO_COLOR", "")], true, true),
+            (ColorChoice::Auto, &[("CLICOLOR_FORCE", "1")], false, true),
+            (ColorChoice::Auto, &[("CLICOLOR_FORCE", "0")], false, false),
+            (
+                ColorChoice::Auto,
+                &[("NO_COLOR", "1"), ("CLICOLOR_FORCE", "1")],
+                true,
+                false,
+            ),
+            // Explicit choices win over the environment.
+            (ColorChoice::Always, &[("NO_COLOR", "1")], false, true),
+            (ColorChoice::Never, &[("CLICOLOR_FORCE", "1")], true, false),
+        ];
+
+        for (choice, vars, is_terminal, expected) in cases {
+            assert_eq!(
+                choice.resolve(env(vars), *is_terminal),
+                *expected,
+                "{:?} {:?} terminal={}",
+                choice,
+                vars,
+                is_terminal
+            );
+        }
+    }
+
+    #[test]
+    fn test_for_child() {
+        for is_terminal in &[false, true] {
+            assert_eq!(
+                ColorChoice::Auto.for_child(env(&[]), false, *is_terminal),
+                if *is_terminal {
+                    ColorChoice::Always
+                } else {
+                    ColorChoice::Never
+                }
+            );
+
+            // Captured output is never colored.
+            for choice in &[ColorChoice::Auto, ColorChoice::Always, ColorChoice::Never] {
+                assert_eq!(
+                    choice.for_child(env(&[("CLICOLOR_FORCE", "1")]), true, *is_terminal),
+                    ColorChoice::Never
+                );
+            }
+        }
+
+        assert_eq!(
+            ColorChoice::Always.for_child(env(&[]), false, false),
+            ColorChoice::Always
+        );
+        assert_eq!(
+            ColorChoice::Never.for_child(env(&[]), false, true),
+            ColorChoice::Never
+        );
+    }
+}
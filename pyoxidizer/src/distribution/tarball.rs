@@ -4,10 +4,12 @@
 
 use anyhow::{anyhow, Result};
 use slog::warn;
+use std::collections::BTreeMap;
 use std::path::PathBuf;
 use tar;
 
 use crate::app_packaging::config::DistributionTarball;
+use crate::app_packaging::resource::{target_case_insensitive, CaseCollisionPolicy};
 use crate::app_packaging::state::BuildContext;
 
 pub fn produce_tarball(
@@ -30,13 +32,22 @@ pub fn produce_tarball(
 
     let mut builder = tar::Builder::new(fh);
     builder.mode(tar::HeaderMode::Deterministic);
+    // Preserve symlinks, such as those for multicall run modes.
+    builder.follow_symlinks(false);
 
     // The tar crate isn't deterministic when iterating directories. So we
     // do the iteration ourselves.
-    let walk =
-        walkdir::WalkDir::new(&context.app_path).sort_by(|a, b| a.file_name().cmp(b.file_name()));
+    let mut walk = walkdir::WalkDir::new(&context.app_path)
+        .sort_by(|a, b| a.file_name().cmp(b.file_name()))
+        .into_iter();
 
-    for entry in walk {
+    // Archive paths only differing in case collide when extracted on targets
+    // with case-insensitive filesystems.
+    let check_case = target_case_insensitive(&context.target_triple);
+    let mut archive_paths: BTreeMap<String, PathBuf> = BTreeMap::new();
+    let mut collisions = Vec::new();
+
+    while let Some(entry) = walk.next() {
         let entry = entry?;
 
         let path = entry.path();
@@ -53,6 +64,33 @@ pub fn produce_tarball(
             PathBuf::from(rel_path)
         };
 
+        if check_case {
+            let key = archive_path.to_string_lossy().to_lowercase();
+
+            if let Some(existing) = archive_paths.get(&key) {
+                if config.case_collision_policy == CaseCollisionPolicy::FirstWins {
+                    warn!(
+                        logger,
+                        "not adding {} due to a case collision with {}",
+                        archive_path.display(),
+                        existing.display()
+                    );
+                    if entry.file_type().is_dir() {
+                        walk.skip_current_dir();
+                    }
+                    continue;
+                }
+
+                collisions.push(format!(
+                    "{} and {}",
+                    existing.display(),
+                    archive_path.display()
+                ));
+            } else {
+                archive_paths.insert(key, archive_path.clone());
+            }
+        }
+
         warn!(
             logger,
             "adding {} as {}",
@@ -62,6 +100,17 @@ pub fn produce_tarball(
         builder.append_path_with_name(path, &archive_path)?;
     }
 
+    if !collisions.is_empty() {
+        drop(builder);
+        std::fs::remove_file(&filename)?;
+
+        return Err(anyhow!(
+            "tarball paths collide on the case-insensitive filesystems of {}:\n  {}\n\nuse case_collision_policy=\"first-wins\" to keep the path added first",
+            context.target_triple,
+            collisions.join("\n  ")
+        ));
+    }
+
     builder.finish()?;
 
     Ok(())
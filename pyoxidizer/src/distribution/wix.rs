@@ -12,7 +12,9 @@ use std::io::{BufRead, BufReader, Read, Write};
 use std::path::{Path, PathBuf};
 
 use crate::app_packaging::config::DistributionWixInstaller;
+use crate::app_packaging::signing::sign_configured;
 use crate::app_packaging::state::BuildContext;
+use crate::py_packaging::download::get_http_client;
 
 const TOOLSET_URL: &str =
     "https://github.com/wixtoolset/wix3/releases/download/wix3111rtm/wix311-binaries.zip";
@@ -48,7 +50,7 @@ lazy_static! {
 
 fn download_and_verify(logger: &slog::Logger, url: &str, hash: &str) -> Result<Vec<u8>> {
     warn!(logger, "downloading {}", url);
-    let mut response = reqwest::get(url)?;
+    let mut response = get_http_client()?.get(url).send()?.error_for_status()?;
 
     let mut data: Vec<u8> = Vec::new();
     response.read_to_end(&mut data)?;
@@ -364,6 +366,8 @@ pub fn build_wix_app_installer(
         &app_installer_path(context),
     )?;
 
+    sign_configured(logger, context, &app_installer_path(context))?;
+
     Ok(())
 }
 
@@ -384,7 +388,10 @@ pub fn build_wix_installer(
             Some("i686-pc-windows-msvc"),
             true,
             None,
+            context.verbosity,
+            // Lockfiles are specific to a target.
             false,
+            &context.eval_options(),
         )?
     } else if context.target_triple == "i686-pc-windows-msvc" {
         warn!(logger, "building application for x64");
@@ -395,7 +402,10 @@ pub fn build_wix_installer(
             Some("x86_64-pc-windows-msvc"),
             true,
             None,
+            context.verbosity,
+            // Lockfiles are specific to a target.
             false,
+            &context.eval_options(),
         )?
     } else {
         return Err(anyhow!(
@@ -490,5 +500,7 @@ pub fn build_wix_installer(
         &bundle_installer_path,
     )?;
 
+    sign_configured(logger, context, &bundle_installer_path)?;
+
     Ok(())
 }
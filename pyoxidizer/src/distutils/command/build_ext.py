@@ -30,6 +30,34 @@ def show_compilers ():
     show_compilers()
 
 
+def refuse_cross_compile(distribution, extensions):
+    """Refuse to compile extensions when PyOxidizer builds for another target.
+
+    Extensions would be compiled for the machine running the build. What was
+    refused is recorded so PyOxidizer can name the package and its build
+    backend.
+    """
+    import json
+
+    name = distribution.get_name()
+    data = {
+        'package': name,
+        # pip sets this for PEP 517 builds. Otherwise setup.py is run directly.
+        'build_backend': os.environ.get('PEP517_BUILD_BACKEND',
+                                        'setuptools.build_meta:__legacy__'),
+        'extensions': [ext.name if isinstance(ext, Extension) else ext[0]
+                       for ext in extensions],
+    }
+
+    state_dir = os.environ['PYOXIDIZER_DISTUTILS_STATE_DIR']
+    path = os.path.join(state_dir, 'refused.%s.json' % name)
+    with open(path, 'w', encoding='utf-8') as fh:
+        json.dump(data, fh, indent=4, sort_keys=True)
+
+    raise DistutilsPlatformError(
+        'PyOxidizer cannot cross-compile extension modules of %s' % name)
+
+
 class build_ext(Command):
 
     description = "build C/C++ extensions (compile/link to build directory)"
@@ -293,6 +321,9 @@ class build_ext(Command):
         if not self.extensions:
             return
 
+        if os.environ.get('PYOXIDIZER_CROSS_COMPILING'):
+            refuse_cross_compile(self.distribution, self.extensions)
+
         # If we were asked to build any C/C++ libraries, make sure that the
         # directory where we put them is in the library search path for
         # linking extensions.
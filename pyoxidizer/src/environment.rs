@@ -8,7 +8,7 @@ use anyhow::{anyhow, Result};
 use git2::{Commit, Repository};
 use lazy_static::lazy_static;
 use std::env;
-use std::path::{Path, PathBuf};
+use std::path::{Component, Path, PathBuf};
 
 pub const PYOXIDIZER_VERSION: &str = env!("CARGO_PKG_VERSION");
 
@@ -29,11 +29,53 @@ pub const BUILD_SEMVER: &str = env!("VERGEN_SEMVER");
 /// <tag>-<count>-<short sha>.
 pub const BUILD_SEMVER_LIGHTWEIGHT: &str = env!("VERGEN_SEMVER_LIGHTWEIGHT");
 
+/// Environment variable forbidding network access when set to `1`.
+///
+/// It is an environment variable so it propagates to PyOxidizer running
+/// from a build script.
+pub const FROZEN_ENV: &str = "PYOXIDIZER_FROZEN";
+
+/// Environment variable enabling offline mode when set to `1`.
+///
+/// Offline builds don't access the network and take Python distributions
+/// from the distribution cache.
+pub const OFFLINE_ENV: &str = "PYOXIDIZER_OFFLINE";
+
+/// Environment variable making project paths canonical when set to `1`.
+///
+/// By default, symlinks in paths given by the user are preserved.
+pub const CANONICAL_PATHS_ENV: &str = "PYOXIDIZER_CANONICAL_PATHS";
+
 lazy_static! {
     /// Minimum version of Rust required to build PyOxidizer applications.
     pub static ref MINIMUM_RUST_VERSION: semver::Version = semver::Version::new(1, 36, 0);
 }
 
+/// Forbid network access for the remainder of the process and its children.
+pub fn forbid_network() {
+    env::set_var(FROZEN_ENV, "1");
+}
+
+fn env_flag(name: &str) -> bool {
+    match env::var(name) {
+        Ok(value) => value == "1",
+        Err(_) => false,
+    }
+}
+
+/// Whether `--frozen` was given.
+pub fn frozen() -> bool {
+    env_flag(FROZEN_ENV)
+}
+
+/// Whether the environment asks to work offline.
+///
+/// `--offline` doesn't change the environment. Builds define the variable
+/// for cargo, so PyOxidizer running from the build script works offline too.
+pub fn offline() -> bool {
+    env_flag(OFFLINE_ENV)
+}
+
 /// Find the root Git commit given a starting Git commit.
 ///
 /// This just walks parents until it gets to a commit without any.
@@ -63,6 +105,88 @@ pub fn canonicalize_path(path: &Path) -> Result<PathBuf, std::io::Error> {
     Ok(p)
 }
 
+/// Resolve project paths to their canonical form for the remainder of the
+/// process and its children.
+pub fn use_canonical_paths() {
+    env::set_var(CANONICAL_PATHS_ENV, "1");
+}
+
+/// Whether project paths are resolved to their canonical form.
+pub fn canonical_paths() -> bool {
+    env_flag(CANONICAL_PATHS_ENV)
+}
+
+/// Remove `.` and `..` components of a path without accessing the filesystem.
+fn normalize_lexically(path: &Path) -> PathBuf {
+    let mut res = PathBuf::new();
+
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                res.pop();
+            }
+            c => res.push(c.as_os_str()),
+        }
+    }
+
+    res
+}
+
+/// The current directory as the user knows it.
+///
+/// `getcwd()` resolves symlinks on UNIX. Shells record the path the user
+/// changed to in `PWD`, which is used if it names the same directory.
+fn logical_current_dir() -> Result<PathBuf, std::io::Error> {
+    let cwd = env::current_dir()?;
+
+    if let Some(pwd) = env::var_os("PWD").map(PathBuf::from) {
+        if pwd.is_absolute() && canonicalize_path(&pwd).ok() == canonicalize_path(&cwd).ok() {
+            return Ok(pwd);
+        }
+    }
+
+    Ok(cwd)
+}
+
+/// Resolve a path given by the user to an absolute path.
+///
+/// Symlinks and Windows directory junctions in the path are preserved, so
+/// paths displayed and registered with cargo are those the user knows,
+/// unless `use_canonical_paths()` was called. If removing `..` components
+/// would change the directory the path names, because they follow a
+/// symlink, the canonical path is returned instead. Compare the result
+/// with `canonicalize_path()` to check whether paths name the same file.
+pub fn resolve_logical_path(path: &Path) -> Result<PathBuf, std::io::Error> {
+    let canonical = canonicalize_path(path)?;
+
+    if canonical_paths() {
+        return Ok(canonical);
+    }
+
+    let logical = normalize_lexically(&logical_current_dir()?.join(path));
+
+    if canonicalize_path(&logical).ok() != Some(canonical.clone()) {
+        return Ok(canonical);
+    }
+
+    if cfg!(windows) {
+        Ok(PathBuf::from(
+            logical.display().to_string().replace("\\", "/"),
+        ))
+    } else {
+        Ok(logical)
+    }
+}
+
+/// A key identifying the file a path names.
+///
+/// Paths reaching a file through different symlinks have the same key.
+/// Paths that don't exist are their own key.
+pub fn path_identity(path: &Path) -> PathBuf {
+    canonicalize_path(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
 /// Describes the location of the PyOxidizer source files.
 pub enum PyOxidizerSource {
     /// A local filesystem path.
@@ -158,3 +282,44 @@ pub fn resolve_environment() -> Result<Environment> {
         pyoxidizer_semver,
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize_lexically() {
+        assert_eq!(
+            normalize_lexically(Path::new("/a/./b/../c")),
+            PathBuf::from("/a/c")
+        );
+        assert_eq!(
+            normalize_lexically(Path::new("/a/b/..")),
+            PathBuf::from("/a")
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_resolve_logical_path() -> Result<()> {
+        let temp_dir = tempdir::TempDir::new("pyoxidizer-test")?;
+        let root = canonicalize_path(temp_dir.path())?;
+        let store = root.join("store");
+        std::fs::create_dir_all(store.join("project").join("src"))?;
+        let link = root.join("link");
+        std::os::unix::fs::symlink(store.join("project"), &link)?;
+
+        assert_eq!(resolve_logical_path(&link)?, link);
+        assert_eq!(resolve_logical_path(&link.join("src").join(".."))?, link);
+        assert_eq!(path_identity(&link), store.join("project"));
+        assert_eq!(path_identity(&root.join("missing")), root.join("missing"));
+
+        // `..` after a symlink leaves the directory it points to.
+        assert_eq!(
+            resolve_logical_path(&link.join(".."))?,
+            canonicalize_path(&store)?
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,360 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/*!
+Exit codes and the final status line of the command line interface.
+
+Wrapper scripts need to tell configuration problems from build failures
+without parsing log output. Errors are tagged with the `ErrorCategory` of
+the phase producing them and each category maps to a stable exit code:
+
+* 0: success
+* 1: errors without a category
+* 2 (`usage`): invalid command line arguments
+* 10 (`config`): evaluating the config file
+* 11 (`distribution`): resolving or downloading the Python distribution
+* 12 (`packaging`): collecting and packaging resources, including pip
+* 13 (`compile`): building with cargo
+* 14 (`run`): launching the application with `pyoxidizer run`
+* 130: the build was cancelled
+
+An application run with `pyoxidizer run` that fails exits us with its own
+exit code, or with 128 plus the signal number if a signal terminated it,
+as a shell would. Its errors are in the `run` category.
+
+After a command finishes, a single line like
+`pyoxidizer: status=error category=packaging exit_code=12` is printed to
+stderr.
+
+`--legacy-exit-codes` restores exiting with 1 for all errors and doesn't
+print the status line.
+*/
+
+use std::fmt;
+use std::process::ExitStatus;
+
+use crate::cancel::is_cancelled_error;
+
+/// Exit code for errors without a category.
+pub const EXIT_OTHER: i32 = 1;
+
+/// Exit code for cancelled builds, as if interrupted by SIGINT.
+pub const EXIT_CANCELLED: i32 = 130;
+
+/// The kind of failure an error represents.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ErrorCategory {
+    /// Invalid command line arguments.
+    Usage,
+    /// Evaluating the config file failed.
+    Config,
+    /// Resolving or downloading the Python distribution failed.
+    Distribution,
+    /// Collecting or packaging Python resources failed, including pip.
+    Packaging,
+    /// Building with cargo failed.
+    Compile,
+    /// Launching the built application with `pyoxidizer run` failed.
+    Run,
+}
+
+impl ErrorCategory {
+    pub fn exit_code(self) -> i32 {
+        match self {
+            ErrorCategory::Usage => 2,
+            ErrorCategory::Config => 10,
+            ErrorCategory::Distribution => 11,
+            ErrorCategory::Packaging => 12,
+            ErrorCategory::Compile => 13,
+            ErrorCategory::Run => 14,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ErrorCategory::Usage => "usage",
+            ErrorCategory::Config => "config",
+            ErrorCategory::Distribution => "distribution",
+            ErrorCategory::Packaging => "packaging",
+            ErrorCategory::Compile => "compile",
+            ErrorCategory::Run => "run",
+        }
+    }
+}
+
+/// An error tagged with its category.
+///
+/// It displays as the wrapped error.
+#[derive(Debug)]
+pub struct CategorizedError {
+    pub category: ErrorCategory,
+    error: anyhow::Error,
+}
+
+impl fmt::Display for CategorizedError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if f.alternate() {
+            write!(f, "{:#}", self.error)
+        } else {
+            write!(f, "{}", self.error)
+        }
+    }
+}
+
+impl std::error::Error for CategorizedError {}
+
+/// An application run by us failed.
+#[derive(Debug)]
+pub struct AppExitError {
+    /// The exit code we exit with.
+    pub code: i32,
+    description: String,
+}
+
+impl AppExitError {
+    /// Describe the exit status of an application, unless it succeeded.
+    pub fn from_status(name: &str, status: ExitStatus) -> Option<Self> {
+        if status.success() {
+            return None;
+        }
+
+        if let Some(code) = status.code() {
+            return Some(AppExitError {
+                code,
+                description: format!("{} exited with code {}", name, code),
+            });
+        }
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::process::ExitStatusExt;
+
+            if let Some(signal) = status.signal() {
+                return Some(AppExitError {
+                    code: 128 + signal,
+                    description: format!("{} terminated by {}", name, signal_name(signal)),
+                });
+            }
+        }
+
+        Some(AppExitError {
+            code: EXIT_OTHER,
+            description: format!("{} failed with {}", name, status),
+        })
+    }
+}
+
+impl fmt::Display for AppExitError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.description)
+    }
+}
+
+impl std::error::Error for AppExitError {}
+
+/// The name of a signal, e.g. `SIGSEGV`.
+#[cfg(unix)]
+fn signal_name(signal: i32) -> String {
+    let name = match signal {
+        libc::SIGHUP => "SIGHUP",
+        libc::SIGINT => "SIGINT",
+        libc::SIGQUIT => "SIGQUIT",
+        libc::SIGILL => "SIGILL",
+        libc::SIGTRAP => "SIGTRAP",
+        libc::SIGABRT => "SIGABRT",
+        libc::SIGBUS => "SIGBUS",
+        libc::SIGFPE => "SIGFPE",
+        libc::SIGKILL => "SIGKILL",
+        libc::SIGUSR1 => "SIGUSR1",
+        libc::SIGSEGV => "SIGSEGV",
+        libc::SIGUSR2 => "SIGUSR2",
+        libc::SIGPIPE => "SIGPIPE",
+        libc::SIGALRM => "SIGALRM",
+        libc::SIGTERM => "SIGTERM",
+        _ => return format!("signal {}", signal),
+    };
+
+    name.to_string()
+}
+
+/// Tag an error with a category.
+///
+/// Errors already having a category keep it, so the phase closest to the
+/// failure wins. Cancellation errors aren't tagged so they can still be
+/// recognized.
+pub fn categorize(error: anyhow::Error, category: ErrorCategory) -> anyhow::Error {
+    if error_category(&error).is_some() || is_cancelled_error(&error) {
+        error
+    } else {
+        anyhow::Error::new(CategorizedError { category, error })
+    }
+}
+
+/// Tag the error of a result with a category.
+pub trait Categorize<T> {
+    fn category(self, category: ErrorCategory) -> anyhow::Result<T>;
+}
+
+impl<T> Categorize<T> for anyhow::Result<T> {
+    fn category(self, category: ErrorCategory) -> anyhow::Result<T> {
+        self.map_err(|e| categorize(e, category))
+    }
+}
+
+/// Obtain the category of an error.
+pub fn error_category(error: &anyhow::Error) -> Option<ErrorCategory> {
+    if error.downcast_ref::<AppExitError>().is_some() {
+        return Some(ErrorCategory::Run);
+    }
+
+    error.downcast_ref::<CategorizedError>().map(|e| e.category)
+}
+
+/// The exit code for the result of a command.
+pub fn exit_code(result: &anyhow::Result<()>, legacy: bool) -> i32 {
+    match result {
+        Ok(()) => 0,
+        Err(e) if is_cancelled_error(e) => EXIT_CANCELLED,
+        Err(_) if legacy => EXIT_OTHER,
+        Err(e) if e.downcast_ref::<AppExitError>().is_some() => {
+            e.downcast_ref::<AppExitError>().unwrap().code
+        }
+        Err(e) => error_category(e)
+            .map(ErrorCategory::exit_code)
+            .unwrap_or(EXIT_OTHER),
+    }
+}
+
+/// The status line printed after a command finishes.
+pub fn status_line(result: &anyhow::Result<()>, exit_code: i32) -> String {
+    match result {
+        Ok(()) => format!("pyoxidizer: status=ok exit_code={}", exit_code),
+        Err(e) if is_cancelled_error(e) => {
+            format!("pyoxidizer: status=cancelled exit_code={}", exit_code)
+        }
+        Err(e) => format!(
+            "pyoxidizer: status=error category={} exit_code={}",
+            error_category(e)
+                .map(ErrorCategory::as_str)
+                .unwrap_or("other"),
+            exit_code
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cancel::BuildError;
+    use anyhow::{anyhow, Context};
+
+    #[test]
+    fn test_exit_codes() {
+        let categories = [
+            ErrorCategory::Usage,
+            ErrorCategory::Config,
+            ErrorCategory::Distribution,
+            ErrorCategory::Packaging,
+            ErrorCategory::Compile,
+            ErrorCategory::Run,
+        ];
+
+        assert_eq!(
+            categories
+                .iter()
+                .map(|c| (c.as_str(), c.exit_code()))
+                .collect::<Vec<_>>(),
+            vec![
+                ("usage", 2),
+                ("config", 10),
+                ("distribution", 11),
+                ("packaging", 12),
+                ("compile", 13),
+                ("run", 14),
+            ]
+        );
+
+        for category in &categories {
+            let result = Err(categorize(anyhow!("failure"), *category));
+            assert_eq!(exit_code(&result, false), category.exit_code());
+            assert_eq!(exit_code(&result, true), EXIT_OTHER);
+        }
+
+        assert_eq!(exit_code(&Ok(()), false), 0);
+        assert_eq!(exit_code(&Err(anyhow!("failure")), false), EXIT_OTHER);
+        assert_eq!(
+            exit_code(&Err(anyhow::Error::new(BuildError::Cancelled)), true),
+            EXIT_CANCELLED
+        );
+    }
+
+    #[test]
+    fn test_categorize() {
+        let err = categorize(
+            categorize(anyhow!("sha256 mismatch"), ErrorCategory::Distribution),
+            ErrorCategory::Packaging,
+        );
+        assert_eq!(error_category(&err), Some(ErrorCategory::Distribution));
+        assert_eq!(err.to_string(), "sha256 mismatch");
+
+        let err: anyhow::Result<()> = Err(anyhow!("inner")).context("outer");
+        let err = err.category(ErrorCategory::Compile).unwrap_err();
+        assert_eq!(err.to_string(), "outer");
+        assert_eq!(format!("{:#}", err), "outer: inner");
+
+        let err = categorize(
+            anyhow::Error::new(BuildError::Cancelled),
+            ErrorCategory::Packaging,
+        );
+        assert!(is_cancelled_error(&err));
+        assert_eq!(error_category(&err), None);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_app_exit_error() {
+        use std::os::unix::process::ExitStatusExt;
+
+        assert!(AppExitError::from_status("myapp", ExitStatus::from_raw(0)).is_none());
+
+        let exit = AppExitError::from_status("myapp", ExitStatus::from_raw(3 << 8)).unwrap();
+        assert_eq!(exit.code, 3);
+        assert_eq!(exit.to_string(), "myapp exited with code 3");
+
+        let exit = AppExitError::from_status("myapp", ExitStatus::from_raw(libc::SIGSEGV)).unwrap();
+        assert_eq!(exit.code, 128 + libc::SIGSEGV);
+        assert_eq!(exit.to_string(), "myapp terminated by SIGSEGV");
+
+        let result: anyhow::Result<()> = Err(anyhow::Error::new(exit)).context("running myapp");
+        assert_eq!(exit_code(&result, false), 128 + libc::SIGSEGV);
+        assert_eq!(exit_code(&result, true), EXIT_OTHER);
+        assert_eq!(
+            status_line(&result, 139),
+            "pyoxidizer: status=error category=run exit_code=139"
+        );
+
+        let err = categorize(result.unwrap_err(), ErrorCategory::Packaging);
+        assert_eq!(error_category(&err), Some(ErrorCategory::Run));
+    }
+
+    #[test]
+    fn test_status_line() {
+        assert_eq!(status_line(&Ok(()), 0), "pyoxidizer: status=ok exit_code=0");
+        assert_eq!(
+            status_line(
+                &Err(categorize(anyhow!("failure"), ErrorCategory::Packaging)),
+                12
+            ),
+            "pyoxidizer: status=error category=packaging exit_code=12"
+        );
+        assert_eq!(
+            status_line(&Err(anyhow!("failure")), 1),
+            "pyoxidizer: status=error category=other exit_code=1"
+        );
+        assert_eq!(
+            status_line(&Err(anyhow::Error::new(BuildError::Cancelled)), 130),
+            "pyoxidizer: status=cancelled exit_code=130"
+        );
+    }
+}
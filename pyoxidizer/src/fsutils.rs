@@ -0,0 +1,678 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Filesystem operations resilient to transient file locking.
+//!
+//! On Windows, anti-virus software (including Windows Defender) commonly
+//! opens freshly written executables and libraries to scan them. While the
+//! scan is in progress, other processes attempting to write, copy, or delete
+//! the file fail with `ERROR_ACCESS_DENIED` or `ERROR_SHARING_VIOLATION`.
+//!
+//! Functions in this module retry operations failing with these errors and
+//! produce a targeted diagnostic if the file remains locked.
+
+use anyhow::{anyhow, Context, Result};
+use sha2::Digest;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+const ERROR_ACCESS_DENIED: i32 = 5;
+const ERROR_SHARING_VIOLATION: i32 = 32;
+const ERROR_LOCK_VIOLATION: i32 = 33;
+
+/// Number of times to retry an operation failing due to a locked file.
+const DEFAULT_RETRY_ATTEMPTS: u32 = 5;
+
+/// Delay before the first retry. Doubled on every subsequent retry.
+const DEFAULT_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// Interval between attempts when waiting for a file to become unlocked.
+const WAIT_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Milliseconds to wait for locked files to become unlocked.
+static WAIT_FOR_UNLOCK_MS: AtomicU64 = AtomicU64::new(0);
+
+/// Set how long to keep retrying operations on locked files.
+///
+/// By default, operations are only retried briefly.
+pub fn set_wait_for_unlock(duration: Duration) {
+    WAIT_FOR_UNLOCK_MS.store(duration.as_millis() as u64, Ordering::SeqCst);
+}
+
+fn wait_for_unlock() -> Duration {
+    Duration::from_millis(WAIT_FOR_UNLOCK_MS.load(Ordering::SeqCst))
+}
+
+/// Whether a Windows error code indicates a file held open by another process.
+fn is_windows_lock_error_code(code: i32) -> bool {
+    match code {
+        ERROR_ACCESS_DENIED | ERROR_SHARING_VIOLATION | ERROR_LOCK_VIOLATION => true,
+        _ => false,
+    }
+}
+
+/// Whether an I/O error is likely caused by another process locking a file.
+///
+/// Only Windows exhibits this behavior. This always returns false on other
+/// platforms.
+pub fn is_lock_error(err: &io::Error) -> bool {
+    cfg!(windows) && err.raw_os_error().map_or(false, is_windows_lock_error_code)
+}
+
+/// Controls how operations are retried.
+struct RetryPolicy {
+    attempts: u32,
+    delay: Duration,
+    wait: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            attempts: DEFAULT_RETRY_ATTEMPTS,
+            delay: DEFAULT_RETRY_DELAY,
+            wait: wait_for_unlock(),
+        }
+    }
+}
+
+/// Messages of tools failing on a file locked by another process.
+const LOCKED_FILE_PATTERNS: &[&str] = &[
+    "(os error 5)",
+    "(os error 32)",
+    "(os error 33)",
+    "access is denied",
+    "being used by another process",
+    // Linker errors for output files that can't be opened.
+    "lnk1104",
+    "lnk1168",
+];
+
+/// Whether a message of an external tool such as Cargo reports a locked file.
+pub fn mentions_locked_file(message: &str) -> bool {
+    let message = message.to_lowercase();
+
+    LOCKED_FILE_PATTERNS
+        .iter()
+        .any(|pattern| message.contains(pattern))
+}
+
+/// Hint for build failures that may be caused by locked files.
+///
+/// Used when an external tool such as Cargo fails with a message for which
+/// `mentions_locked_file()` is true.
+pub fn locked_build_hint(build_dir: &Path) -> String {
+    format!(
+        "If the failure mentions `Access is denied (os error 5)` or a file \
+         being used by another process, anti-virus software (such as Windows \
+         Defender) scanning newly written files is the likely cause. Consider \
+         excluding {} from real-time scanning.",
+        build_dir.display()
+    )
+}
+
+/// Produce the diagnostic for a file that remained locked.
+fn locked_file_error(
+    path: &Path,
+    action: &str,
+    err: io::Error,
+    elapsed: Duration,
+) -> anyhow::Error {
+    let exclude_dir = path.parent().unwrap_or(path);
+
+    anyhow!(
+        "{} {} failed after retrying for {}ms: {}\n\n\
+         The file appears to be locked by another process. This is commonly \
+         caused by anti-virus software (such as Windows Defender) scanning \
+         newly written files.\n\n\
+         Consider excluding {} (or your entire build directory) from \
+         real-time scanning or re-run with --wait-for-unlock <seconds> to \
+         wait longer for the file to become unlocked.",
+        action,
+        path.display(),
+        elapsed.as_millis(),
+        err,
+        exclude_dir.display()
+    )
+}
+
+fn retry_with<T, F, P>(
+    path: &Path,
+    action: &str,
+    policy: &RetryPolicy,
+    is_lock: P,
+    mut op: F,
+) -> Result<T>
+where
+    F: FnMut() -> io::Result<T>,
+    P: Fn(&io::Error) -> bool,
+{
+    let start = Instant::now();
+    let mut attempt = 0;
+    let mut delay = policy.delay;
+
+    loop {
+        let err = match op() {
+            Ok(v) => return Ok(v),
+            Err(e) => e,
+        };
+
+        if !is_lock(&err) {
+            return Err(err).with_context(|| format!("{} {}", action, path.display()));
+        }
+
+        attempt += 1;
+
+        let elapsed = start.elapsed();
+        if attempt >= policy.attempts && elapsed >= policy.wait {
+            return Err(locked_file_error(path, action, err, elapsed));
+        }
+
+        if attempt >= policy.attempts {
+            std::thread::sleep(std::cmp::min(WAIT_POLL_INTERVAL, policy.wait - elapsed));
+        } else {
+            std::thread::sleep(delay);
+            delay *= 2;
+        }
+    }
+}
+
+/// Perform an operation on a path, retrying if the path is locked.
+pub fn with_unlock_retry<T, F>(path: &Path, action: &str, op: F) -> Result<T>
+where
+    F: FnMut() -> io::Result<T>,
+{
+    retry_with(path, action, &RetryPolicy::default(), is_lock_error, op)
+}
+
+/// Create a file, retrying if it is locked.
+pub fn create_file(path: &Path) -> Result<File> {
+    with_unlock_retry(path, "creating", || File::create(path))
+}
+
+/// Write data to a file, retrying if it is locked.
+pub fn write_file<C: AsRef<[u8]>>(path: &Path, data: C) -> Result<()> {
+    with_unlock_retry(path, "writing", || std::fs::write(path, data.as_ref()))
+}
+
+/// Copy a file, retrying if either file is locked.
+pub fn copy_file(from: &Path, to: &Path) -> Result<u64> {
+    with_unlock_retry(to, "copying to", || std::fs::copy(from, to))
+}
+
+/// Remove a directory and its content, retrying if files are locked.
+pub fn remove_dir_all(path: &Path) -> Result<()> {
+    with_unlock_retry(path, "removing", || std::fs::remove_dir_all(path))
+}
+
+/// Whether an error is due to renaming across filesystems or drives.
+pub fn is_cross_device_error(err: &io::Error) -> bool {
+    match err.raw_os_error() {
+        #[cfg(unix)]
+        Some(code) => code == libc::EXDEV,
+        // ERROR_NOT_SAME_DEVICE
+        #[cfg(windows)]
+        Some(code) => code == 17,
+        _ => false,
+    }
+}
+
+/// Copy a file or directory tree, preserving permissions and mtimes.
+///
+/// Written files are synced to disk before returning.
+fn copy_preserving(from: &Path, to: &Path) -> Result<()> {
+    let metadata = std::fs::symlink_metadata(from)
+        .with_context(|| format!("reading metadata of {}", from.display()))?;
+
+    #[cfg(unix)]
+    {
+        if metadata.file_type().is_symlink() {
+            let target = std::fs::read_link(from)?;
+            std::os::unix::fs::symlink(&target, to)
+                .with_context(|| format!("creating symlink {}", to.display()))?;
+            return Ok(());
+        }
+    }
+
+    if metadata.is_dir() {
+        std::fs::create_dir(to).with_context(|| format!("creating {}", to.display()))?;
+
+        for entry in std::fs::read_dir(from)? {
+            let entry = entry?;
+            copy_preserving(&entry.path(), &to.join(entry.file_name()))?;
+        }
+    } else {
+        copy_file(from, to)?;
+        File::open(to)
+            .and_then(|fh| fh.sync_all())
+            .with_context(|| format!("syncing {}", to.display()))?;
+    }
+
+    // Copying content updates the mtime of directories. So times are set
+    // last.
+    std::fs::set_permissions(to, metadata.permissions())
+        .with_context(|| format!("setting permissions of {}", to.display()))?;
+    filetime::set_file_times(
+        to,
+        filetime::FileTime::from_last_access_time(&metadata),
+        filetime::FileTime::from_last_modification_time(&metadata),
+    )
+    .with_context(|| format!("setting times of {}", to.display()))?;
+
+    Ok(())
+}
+
+fn move_with<R>(from: &Path, to: &Path, rename: R) -> Result<()>
+where
+    R: Fn(&Path, &Path) -> io::Result<()>,
+{
+    let renamed = with_unlock_retry(to, "renaming to", || match rename(from, to) {
+        Ok(()) => Ok(true),
+        Err(ref e) if is_cross_device_error(e) => Ok(false),
+        Err(e) => Err(e),
+    })?;
+
+    if renamed {
+        return Ok(());
+    }
+
+    // Copy next to the destination then rename, so the destination never
+    // holds partial content.
+    let mut temp_name = std::ffi::OsString::from(".");
+    temp_name.push(to.file_name().unwrap_or_default());
+    temp_name.push(format!(".moving-{}", std::process::id()));
+    let temp_path = to.with_file_name(temp_name);
+
+    if let Err(e) = copy_preserving(from, &temp_path)
+        .and_then(|_| with_unlock_retry(to, "renaming to", || rename(&temp_path, to)))
+    {
+        if temp_path.is_dir() {
+            let _ = std::fs::remove_dir_all(&temp_path);
+        } else {
+            let _ = std::fs::remove_file(&temp_path);
+        }
+
+        return Err(e);
+    }
+
+    if from.is_dir() {
+        remove_dir_all(from)
+    } else {
+        with_unlock_retry(from, "removing", || std::fs::remove_file(from))
+    }
+}
+
+/// Move a file or directory, like `std::fs::rename()`.
+///
+/// Moves between filesystems or drives, which can't be renamed, fall back
+/// to copying then deleting the source.
+pub fn move_path(from: &Path, to: &Path) -> Result<()> {
+    move_with(from, to, |from, to| std::fs::rename(from, to))
+}
+
+/// A directory populated in a staging location then moved into place.
+///
+/// If dropped before `commit()` is called, e.g. because the operation
+/// populating it failed or was cancelled, the staging directory is removed.
+/// This ensures interrupted operations don't leave partially written
+/// directories behind.
+pub struct StagedDir {
+    staging: PathBuf,
+    dest: PathBuf,
+    committed: bool,
+}
+
+impl StagedDir {
+    pub fn new(dest: &Path) -> Result<Self> {
+        let name = dest
+            .file_name()
+            .ok_or_else(|| anyhow!("{} has no file name", dest.display()))?;
+
+        let mut staging_name = std::ffi::OsString::from(".");
+        staging_name.push(name);
+        staging_name.push(format!(".staging-{}", std::process::id()));
+        let staging = dest.with_file_name(staging_name);
+
+        if staging.exists() {
+            remove_dir_all(&staging)?;
+        }
+        std::fs::create_dir_all(&staging)
+            .with_context(|| format!("creating {}", staging.display()))?;
+
+        Ok(StagedDir {
+            staging,
+            dest: dest.to_path_buf(),
+            committed: false,
+        })
+    }
+
+    /// The directory to populate.
+    pub fn path(&self) -> &Path {
+        &self.staging
+    }
+
+    /// Replace the destination directory with the staged content.
+    pub fn commit(mut self) -> Result<()> {
+        if self.dest.exists() {
+            remove_dir_all(&self.dest)?;
+        }
+
+        move_path(&self.staging, &self.dest)?;
+        self.committed = true;
+
+        Ok(())
+    }
+}
+
+impl Drop for StagedDir {
+    fn drop(&mut self) {
+        if !self.committed {
+            let _ = std::fs::remove_dir_all(&self.staging);
+        }
+    }
+}
+
+/// Compute the hex SHA-256 of a file without reading it into memory.
+pub fn sha256_file(path: &Path) -> Result<String> {
+    let mut hasher = sha2::Sha256::new();
+    let mut fh =
+        std::fs::File::open(path).with_context(|| format!("opening {}", path.display()))?;
+
+    let mut buffer = [0; 32768];
+    loop {
+        let count = fh
+            .read(&mut buffer)
+            .with_context(|| format!("reading {}", path.display()))?;
+        if count == 0 {
+            break;
+        }
+        hasher.input(&buffer[..count]);
+    }
+
+    Ok(hex::encode(hasher.result()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn is_simulated_lock(err: &io::Error) -> bool {
+        err.raw_os_error().map_or(false, is_windows_lock_error_code)
+    }
+
+    fn test_policy(wait: Duration) -> RetryPolicy {
+        RetryPolicy {
+            attempts: 3,
+            delay: Duration::from_millis(1),
+            wait,
+        }
+    }
+
+    #[test]
+    fn test_lock_error_codes() {
+        assert!(is_windows_lock_error_code(ERROR_ACCESS_DENIED));
+        assert!(is_windows_lock_error_code(ERROR_SHARING_VIOLATION));
+        assert!(is_windows_lock_error_code(ERROR_LOCK_VIOLATION));
+        assert!(!is_windows_lock_error_code(2));
+
+        let err = io::Error::new(io::ErrorKind::Other, "not an OS error");
+        assert!(!is_lock_error(&err));
+    }
+
+    #[test]
+    fn test_mentions_locked_file() {
+        for message in &[
+            "error: failed to remove file `C:\\app\\target\\debug\\myapp.exe`\n\nCaused by:\n  Access is denied. (os error 5)",
+            "error: The process cannot access the file because it is being used by another process. (os error 32)",
+            "LINK : fatal error LNK1104: cannot open file 'C:\\app\\myapp.exe'",
+        ] {
+            assert!(mentions_locked_file(message), "{}", message);
+        }
+
+        for message in &[
+            "error[E0308]: mismatched types",
+            "error: linking with `cc` failed: exit code: 1",
+            "error: could not compile `myapp`",
+        ] {
+            assert!(!mentions_locked_file(message), "{}", message);
+        }
+    }
+
+    #[test]
+    fn test_retry_transient_lock() -> Result<()> {
+        for code in &[ERROR_ACCESS_DENIED, ERROR_SHARING_VIOLATION] {
+            let mut calls = 0;
+
+            let res = retry_with(
+                Path::new("build/myapp.exe"),
+                "writing",
+                &test_policy(Duration::from_millis(0)),
+                is_simulated_lock,
+                || {
+                    calls += 1;
+                    if calls < 3 {
+                        Err(io::Error::from_raw_os_error(*code))
+                    } else {
+                        Ok(42)
+                    }
+                },
+            )?;
+
+            assert_eq!(res, 42);
+            assert_eq!(calls, 3);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_retry_persistent_lock() {
+        let path = Path::new("build").join("myapp.exe");
+        let mut calls = 0;
+
+        let res: Result<()> = retry_with(
+            &path,
+            "writing",
+            &test_policy(Duration::from_millis(0)),
+            is_simulated_lock,
+            || {
+                calls += 1;
+                Err(io::Error::from_raw_os_error(ERROR_SHARING_VIOLATION))
+            },
+        );
+
+        assert_eq!(calls, 3);
+        let message = res.unwrap_err().to_string();
+        assert!(message.contains(&format!("writing {} failed", path.display())));
+        assert!(message.contains("anti-virus"));
+        assert!(message.contains(&format!(
+            "Consider excluding {} ",
+            Path::new("build").display()
+        )));
+    }
+
+    #[test]
+    fn test_retry_wait_for_unlock() -> Result<()> {
+        let mut calls = 0;
+
+        let res = retry_with(
+            Path::new("build/myapp.exe"),
+            "writing",
+            &test_policy(Duration::from_millis(2000)),
+            is_simulated_lock,
+            || {
+                calls += 1;
+                if calls < 5 {
+                    Err(io::Error::from_raw_os_error(ERROR_ACCESS_DENIED))
+                } else {
+                    Ok(())
+                }
+            },
+        );
+
+        assert!(res.is_ok());
+        assert_eq!(calls, 5);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_staged_dir() -> Result<()> {
+        let temp_dir = tempdir::TempDir::new("pyoxidizer-test")?;
+        let dest = temp_dir.path().join("out");
+
+        {
+            let staged = StagedDir::new(&dest)?;
+            std::fs::write(staged.path().join("partial"), b"data")?;
+        }
+        assert!(!dest.exists());
+        assert_eq!(std::fs::read_dir(temp_dir.path())?.count(), 0);
+
+        std::fs::create_dir(&dest)?;
+        std::fs::write(dest.join("old"), b"old")?;
+
+        let staged = StagedDir::new(&dest)?;
+        std::fs::write(staged.path().join("new"), b"new")?;
+        staged.commit()?;
+
+        assert!(!dest.join("old").exists());
+        assert_eq!(std::fs::read(dest.join("new"))?, b"new");
+        assert_eq!(std::fs::read_dir(temp_dir.path())?.count(), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_other_errors_not_retried() {
+        let mut calls = 0;
+
+        let res: Result<()> = retry_with(
+            Path::new("build/myapp.exe"),
+            "writing",
+            &test_policy(Duration::from_millis(0)),
+            is_simulated_lock,
+            || {
+                calls += 1;
+                Err(io::Error::from_raw_os_error(2))
+            },
+        );
+
+        assert_eq!(calls, 1);
+        assert!(!res.unwrap_err().to_string().contains("anti-virus"));
+    }
+
+    fn cross_device_error() -> io::Error {
+        #[cfg(unix)]
+        let code = libc::EXDEV;
+        #[cfg(windows)]
+        let code = 17;
+        #[cfg(not(any(unix, windows)))]
+        let code = 0;
+
+        io::Error::from_raw_os_error(code)
+    }
+
+    /// A rename failing like it does across filesystems.
+    ///
+    /// Only renames out of `source` fail, as the fallback renames within
+    /// the destination directory.
+    fn rename_cross_device(source: &Path) -> impl Fn(&Path, &Path) -> io::Result<()> + '_ {
+        move |from, to| {
+            if from.starts_with(source) {
+                Err(cross_device_error())
+            } else {
+                std::fs::rename(from, to)
+            }
+        }
+    }
+
+    #[test]
+    #[cfg(any(unix, windows))]
+    fn test_move_file_cross_device() -> Result<()> {
+        let temp_dir = tempdir::TempDir::new("pyoxidizer-test")?;
+        let source = temp_dir.path().join("source");
+        let dest = temp_dir.path().join("dest");
+        std::fs::create_dir(&source)?;
+        std::fs::create_dir(&dest)?;
+
+        let from = source.join("file");
+        std::fs::write(&from, b"data")?;
+        let mtime = filetime::FileTime::from_unix_time(1_000_000_000, 0);
+        filetime::set_file_mtime(&from, mtime)?;
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(&from, std::fs::Permissions::from_mode(0o751))?;
+        }
+
+        let to = dest.join("file");
+        move_with(&from, &to, rename_cross_device(&source))?;
+
+        assert!(!from.exists());
+        assert_eq!(std::fs::read(&to)?, b"data");
+        assert_eq!(
+            filetime::FileTime::from_last_modification_time(&to.metadata()?),
+            mtime
+        );
+        assert_eq!(std::fs::read_dir(&dest)?.count(), 1);
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(to.metadata()?.permissions().mode() & 0o777, 0o751);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(any(unix, windows))]
+    fn test_move_dir_cross_device() -> Result<()> {
+        let temp_dir = tempdir::TempDir::new("pyoxidizer-test")?;
+        let source = temp_dir.path().join("source");
+        let dest = temp_dir.path().join("dest");
+        std::fs::create_dir_all(source.join("tree").join("sub"))?;
+        std::fs::create_dir(&dest)?;
+
+        std::fs::write(source.join("tree").join("a"), b"a")?;
+        std::fs::write(source.join("tree").join("sub").join("b"), b"b")?;
+        let mtime = filetime::FileTime::from_unix_time(1_000_000_000, 0);
+        filetime::set_file_mtime(source.join("tree").join("sub"), mtime)?;
+
+        let to = dest.join("tree");
+        move_with(&source.join("tree"), &to, rename_cross_device(&source))?;
+
+        assert!(!source.join("tree").exists());
+        assert_eq!(std::fs::read(to.join("a"))?, b"a");
+        assert_eq!(std::fs::read(to.join("sub").join("b"))?, b"b");
+        assert_eq!(
+            filetime::FileTime::from_last_modification_time(&to.join("sub").metadata()?),
+            mtime
+        );
+        assert_eq!(std::fs::read_dir(&dest)?.count(), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_move_other_errors() -> Result<()> {
+        let temp_dir = tempdir::TempDir::new("pyoxidizer-test")?;
+        let from = temp_dir.path().join("file");
+        std::fs::write(&from, b"data")?;
+
+        let res = move_with(&from, &temp_dir.path().join("dest"), |_, _| {
+            Err(io::Error::from_raw_os_error(2))
+        });
+
+        assert!(res.is_err());
+        assert!(from.exists());
+        assert_eq!(std::fs::read_dir(temp_dir.path())?.count(), 1);
+
+        Ok(())
+    }
+}
@@ -13,8 +13,15 @@ This library exposes that functionality to other tools.
 
 pub mod analyze;
 pub mod app_packaging;
+pub mod build_events;
+pub mod build_stats;
+pub mod cache;
+pub mod cancel;
+pub mod color;
 pub mod distribution;
 pub mod environment;
+pub mod exit_status;
+pub mod fsutils;
 mod licensing;
 pub mod logging;
 pub mod project_building;
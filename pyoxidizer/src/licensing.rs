@@ -2,6 +2,14 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use slog::warn;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+use crate::py_packaging::distribution::{choose_variant, ExtensionModule};
+
 /// SPDX licenses in Python distributions that are not GPL.
 ///
 /// We store an allow list of licenses rather than trying to deny GPL licenses
@@ -15,3 +23,613 @@ pub const NON_GPL_LICENSES: &[&str] = &[
     "X11",
     "Zlib",
 ];
+
+/// SPDX identifier for licenses that are unknown.
+const UNKNOWN_LICENSE: &str = "NOASSERTION";
+
+/// What to do when the default variant of an extension module has licenses
+/// that aren't allowed.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub enum LicenseConflictAction {
+    /// Use another variant whose licenses are allowed, omitting the module
+    /// if there is none.
+    PreferCompatibleVariant,
+    /// Omit the module.
+    Omit,
+    /// Fail.
+    Error,
+}
+
+impl LicenseConflictAction {
+    pub fn from_str(s: &str) -> Result<LicenseConflictAction> {
+        match s {
+            "prefer-compatible-variant" => Ok(LicenseConflictAction::PreferCompatibleVariant),
+            "omit" => Ok(LicenseConflictAction::Omit),
+            "error" => Ok(LicenseConflictAction::Error),
+            t => Err(anyhow!(
+                "{} is not a valid license conflict action; expected prefer-compatible-variant, omit or error",
+                t
+            )),
+        }
+    }
+}
+
+/// Restricts extension modules of a Python distribution to allowed licenses.
+///
+/// Defined by `extension_module_license_filter()` in the config.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct ExtensionModuleLicensePolicy {
+    /// SPDX license shortnames that are allowed.
+    pub allow: Vec<String>,
+    pub on_conflict: LicenseConflictAction,
+}
+
+/// A variant of an extension module removed by an `ExtensionModuleLicensePolicy`.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct LicenseFilterDecision {
+    pub module: String,
+    pub variant: String,
+    /// Licenses of the variant that aren't allowed.
+    pub licenses: Vec<String>,
+    /// The variant used instead. `None` if the module was omitted.
+    pub substitute: Option<String>,
+    /// Licenses of the substitute.
+    pub substitute_licenses: Vec<String>,
+}
+
+impl fmt::Display for LicenseFilterDecision {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.substitute {
+            Some(substitute) => write!(
+                f,
+                "{}: replaced variant {} ({}) with {} ({})",
+                self.module,
+                self.variant,
+                self.licenses.join(", "),
+                substitute,
+                if self.substitute_licenses.is_empty() {
+                    "no additional licenses".to_string()
+                } else {
+                    self.substitute_licenses.join(", ")
+                }
+            ),
+            None => write!(
+                f,
+                "{}: omitted variant {} ({})",
+                self.module,
+                self.variant,
+                self.licenses.join(", ")
+            ),
+        }
+    }
+}
+
+/// Licenses covering the libraries an extension module variant links.
+///
+/// Variants linking nothing are only covered by Python's license. Unknown
+/// licenses are `NOASSERTION`.
+fn variant_licenses(em: &ExtensionModule) -> Vec<String> {
+    if em.links.is_empty() || em.license_public_domain == Some(true) {
+        Vec::new()
+    } else {
+        match &em.licenses {
+            Some(licenses) => licenses.clone(),
+            None => vec![UNKNOWN_LICENSE.to_string()],
+        }
+    }
+}
+
+fn describe_variants(variants: &[ExtensionModule]) -> String {
+    variants
+        .iter()
+        .map(|em| format!("{} ({})", em.variant, variant_licenses(em).join(", ")))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+impl ExtensionModuleLicensePolicy {
+    /// Licenses of an extension module variant that aren't allowed.
+    pub fn disallowed_licenses(&self, em: &ExtensionModule) -> Vec<String> {
+        variant_licenses(em)
+            .into_iter()
+            .filter(|license| !self.allow.contains(license))
+            .collect()
+    }
+
+    /// Remove extension module variants whose licenses aren't allowed.
+    ///
+    /// `extension_modules` are the variants of each extension module of a
+    /// distribution. Disallowed variants other than the default are always
+    /// removed so they can't be preferred later. What happens when the
+    /// default variant is disallowed depends on `on_conflict`. Required
+    /// extension modules without an allowed variant are an error.
+    ///
+    /// Returns the removed variants, which are also logged.
+    pub fn apply(
+        &self,
+        logger: &slog::Logger,
+        extension_modules: &mut BTreeMap<String, Vec<ExtensionModule>>,
+    ) -> Result<Vec<LicenseFilterDecision>> {
+        let mut decisions = Vec::new();
+        let mut omitted = Vec::new();
+
+        for (name, variants) in extension_modules.iter_mut() {
+            let (allowed, disallowed): (Vec<ExtensionModule>, Vec<ExtensionModule>) = variants
+                .iter()
+                .cloned()
+                .partition(|em| self.disallowed_licenses(em).is_empty());
+
+            if disallowed.is_empty() {
+                continue;
+            }
+
+            let required = variants.iter().any(|em| em.required);
+            let default = choose_variant(variants, &None);
+            let default_allowed = allowed.iter().any(|em| em.variant == default.variant);
+
+            if allowed.is_empty() && required {
+                return Err(anyhow!(
+                    "extension module {} is required but none of its variants have allowed licenses: {}",
+                    name,
+                    describe_variants(variants)
+                ));
+            }
+
+            if !default_allowed && self.on_conflict == LicenseConflictAction::Error {
+                return Err(anyhow!(
+                    "variant {} of extension module {} has licenses that aren't allowed: {}",
+                    default.variant,
+                    name,
+                    self.disallowed_licenses(&default).join(", ")
+                ));
+            }
+
+            let omit = allowed.is_empty()
+                || (!default_allowed
+                    && !required
+                    && self.on_conflict == LicenseConflictAction::Omit);
+
+            let substitute = if omit {
+                None
+            } else {
+                Some(choose_variant(&allowed, &None))
+            };
+
+            for em in &disallowed {
+                decisions.push(LicenseFilterDecision {
+                    module: name.clone(),
+                    variant: em.variant.clone(),
+                    licenses: self.disallowed_licenses(em),
+                    substitute: substitute.as_ref().map(|s| s.variant.clone()),
+                    substitute_licenses: substitute
+                        .as_ref()
+                        .map(variant_licenses)
+                        .unwrap_or_else(Vec::new),
+                });
+            }
+
+            if omit {
+                omitted.push(name.clone());
+            } else {
+                *variants = allowed;
+            }
+        }
+
+        for name in omitted {
+            extension_modules.remove(&name);
+        }
+
+        for decision in &decisions {
+            warn!(logger, "extension module license filter: {}", decision);
+        }
+
+        Ok(decisions)
+    }
+}
+
+/// Licenses extension modules embedded in an application may carry.
+///
+/// Defined by `license_denylist`, `license_allowlist` and
+/// `license_exempt_extensions` in `Config()`. Unlike an
+/// `ExtensionModuleLicensePolicy`, nothing is substituted: violations fail
+/// the build.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct LicenseCheckPolicy {
+    /// SPDX license shortnames that are not allowed.
+    pub denylist: Vec<String>,
+    /// If set, the only SPDX license shortnames allowed.
+    pub allowlist: Option<Vec<String>>,
+    /// Extension modules which aren't checked.
+    pub exempt_extensions: BTreeSet<String>,
+}
+
+/// An embedded extension module carrying a license that isn't allowed.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LicenseViolation {
+    pub module: String,
+    pub variant: String,
+    pub license: String,
+}
+
+impl fmt::Display for LicenseViolation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} (variant {}): {}",
+            self.module, self.variant, self.license
+        )
+    }
+}
+
+impl LicenseCheckPolicy {
+    pub fn is_empty(&self) -> bool {
+        self.denylist.is_empty() && self.allowlist.is_none()
+    }
+
+    fn is_allowed(&self, license: &str) -> bool {
+        !self.denylist.iter().any(|l| l == license)
+            && self
+                .allowlist
+                .as_ref()
+                .map_or(true, |allow| allow.iter().any(|l| l == license))
+    }
+
+    /// Find licenses of embedded extension modules that aren't allowed.
+    ///
+    /// Unknown licenses are `NOASSERTION`, which an allowlist rejects
+    /// unless it lists it.
+    pub fn violations(
+        &self,
+        extension_modules: &BTreeMap<String, ExtensionModule>,
+    ) -> Vec<LicenseViolation> {
+        let mut res = Vec::new();
+
+        for (name, em) in extension_modules {
+            if self.exempt_extensions.contains(name) {
+                continue;
+            }
+
+            for license in variant_licenses(em) {
+                if !self.is_allowed(&license) {
+                    res.push(LicenseViolation {
+                        module: name.clone(),
+                        variant: em.variant.clone(),
+                        license,
+                    });
+                }
+            }
+        }
+
+        res
+    }
+
+    /// Fail if embedded extension modules carry licenses that aren't allowed.
+    pub fn check(&self, extension_modules: &BTreeMap<String, ExtensionModule>) -> Result<()> {
+        let violations = self.violations(extension_modules);
+
+        if violations.is_empty() {
+            return Ok(());
+        }
+
+        Err(anyhow!(
+            "{} embedded extension modules have disallowed licenses:\n{}\nchange the extension modules or variants embedded, or add them to license_exempt_extensions",
+            violations.len(),
+            violations
+                .iter()
+                .map(|v| format!("  {}", v))
+                .collect::<Vec<_>>()
+                .join("\n")
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::py_packaging::distribution::LibraryDepends;
+    use crate::testutil::*;
+
+    fn variant(
+        module: &str,
+        variant: &str,
+        links: &[&str],
+        licenses: Option<&[&str]>,
+        required: bool,
+    ) -> ExtensionModule {
+        ExtensionModule {
+            module: module.to_string(),
+            init_fn: Some(format!("PyInit_{}", module)),
+            builtin_default: false,
+            disableable: !required,
+            object_paths: Vec::new(),
+            static_library: None,
+            shared_library: None,
+            links: links
+                .iter()
+                .map(|name| LibraryDepends {
+                    name: name.to_string(),
+                    static_path: None,
+                    dynamic_path: None,
+                    framework: false,
+                    system: false,
+                })
+                .collect(),
+            required,
+            variant: variant.to_string(),
+            licenses: licenses.map(|l| l.iter().map(|s| s.to_string()).collect()),
+            license_paths: None,
+            license_public_domain: None,
+        }
+    }
+
+    /// Extension modules like those of a distribution, with conflicting variants.
+    fn fixture() -> BTreeMap<String, Vec<ExtensionModule>> {
+        let mut res = BTreeMap::new();
+
+        res.insert(
+            "_json".to_string(),
+            vec![variant("_json", "default", &[], None, false)],
+        );
+        res.insert(
+            "_gdbm".to_string(),
+            vec![
+                variant("_gdbm", "default", &["gdbm"], Some(&["GPL-3.0"]), false),
+                variant("_gdbm", "bsd", &["gdbm_compat"], Some(&["MIT"]), false),
+            ],
+        );
+        // readline prefers libedit by default.
+        res.insert(
+            "readline".to_string(),
+            vec![
+                variant(
+                    "readline",
+                    "default",
+                    &["readline"],
+                    Some(&["GPL-3.0"]),
+                    false,
+                ),
+                variant(
+                    "readline",
+                    "libedit",
+                    &["edit"],
+                    Some(&["BSD-3-Clause"]),
+                    false,
+                ),
+            ],
+        );
+        res.insert(
+            "_ssl".to_string(),
+            vec![variant(
+                "_ssl",
+                "default",
+                &["ssl"],
+                Some(&["OpenSSL"]),
+                false,
+            )],
+        );
+        res.insert(
+            "_unknown".to_string(),
+            vec![variant("_unknown", "default", &["unknown"], None, false)],
+        );
+        res.insert(
+            "_required".to_string(),
+            vec![variant(
+                "_required",
+                "default",
+                &["gpl"],
+                Some(&["GPL-2.0"]),
+                true,
+            )],
+        );
+
+        res
+    }
+
+    fn policy(allow: &[&str], on_conflict: LicenseConflictAction) -> ExtensionModuleLicensePolicy {
+        ExtensionModuleLicensePolicy {
+            allow: allow.iter().map(|s| s.to_string()).collect(),
+            on_conflict,
+        }
+    }
+
+    fn variants(modules: &BTreeMap<String, Vec<ExtensionModule>>, name: &str) -> Vec<String> {
+        modules[name].iter().map(|em| em.variant.clone()).collect()
+    }
+
+    #[test]
+    fn test_prefer_compatible_variant() -> Result<()> {
+        let logger = get_logger()?;
+        let mut modules = fixture();
+        modules.remove("_required");
+
+        let decisions = policy(
+            &["BSD-3-Clause", "MIT"],
+            LicenseConflictAction::PreferCompatibleVariant,
+        )
+        .apply(&logger, &mut modules)?;
+
+        assert_eq!(variants(&modules, "_json"), vec!["default"]);
+        assert_eq!(variants(&modules, "_gdbm"), vec!["bsd"]);
+        assert_eq!(variants(&modules, "readline"), vec!["libedit"]);
+        assert!(!modules.contains_key("_ssl"));
+        assert!(!modules.contains_key("_unknown"));
+
+        assert_eq!(
+            decisions.iter().map(|d| d.to_string()).collect::<Vec<_>>(),
+            vec![
+                "_gdbm: replaced variant default (GPL-3.0) with bsd (MIT)",
+                "_ssl: omitted variant default (OpenSSL)",
+                "_unknown: omitted variant default (NOASSERTION)",
+                "readline: replaced variant default (GPL-3.0) with libedit (BSD-3-Clause)",
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_omit() -> Result<()> {
+        let logger = get_logger()?;
+        let mut modules = fixture();
+        modules.remove("_required");
+
+        let decisions = policy(
+            &["BSD-3-Clause", "MIT", "OpenSSL"],
+            LicenseConflictAction::Omit,
+        )
+        .apply(&logger, &mut modules)?;
+
+        // The default variant of readline is allowed, so only the other is removed.
+        assert_eq!(variants(&modules, "readline"), vec!["libedit"]);
+        assert!(!modules.contains_key("_gdbm"));
+        assert_eq!(variants(&modules, "_ssl"), vec!["default"]);
+
+        assert_eq!(decisions.len(), 3);
+        assert_eq!(decisions[0].module, "_gdbm");
+        assert_eq!(decisions[0].substitute, None);
+        assert_eq!(decisions[1].module, "_unknown");
+        assert_eq!(decisions[2].module, "readline");
+        assert_eq!(decisions[2].substitute, Some("libedit".to_string()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_error() -> Result<()> {
+        let logger = get_logger()?;
+        let mut modules = fixture();
+        modules.remove("_required");
+        modules.remove("_unknown");
+
+        let err = policy(&["BSD-3-Clause", "MIT"], LicenseConflictAction::Error)
+            .apply(&logger, &mut modules)
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "variant default of extension module _gdbm has licenses that aren't allowed: GPL-3.0"
+        );
+
+        // Disallowed variants that aren't the default are only removed.
+        let mut modules = fixture();
+        modules.remove("_required");
+        modules.remove("_gdbm");
+        modules.remove("_unknown");
+        let decisions = policy(&["BSD-3-Clause", "OpenSSL"], LicenseConflictAction::Error)
+            .apply(&logger, &mut modules)?;
+        assert_eq!(variants(&modules, "readline"), vec!["libedit"]);
+        assert_eq!(decisions.len(), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_required_without_compatible_variant() -> Result<()> {
+        let logger = get_logger()?;
+
+        for action in &[
+            LicenseConflictAction::PreferCompatibleVariant,
+            LicenseConflictAction::Omit,
+            LicenseConflictAction::Error,
+        ] {
+            let mut modules = fixture();
+            let err = policy(&["BSD-3-Clause", "MIT", "OpenSSL", "GPL-3.0"], *action)
+                .apply(&logger, &mut modules)
+                .unwrap_err();
+
+            assert_eq!(
+                err.to_string(),
+                "extension module _required is required but none of its variants have allowed licenses: default (GPL-2.0)"
+            );
+        }
+
+        Ok(())
+    }
+
+    fn strings(values: &[&str]) -> Vec<String> {
+        values.iter().map(|s| s.to_string()).collect()
+    }
+
+    /// The default variant of every extension module in the fixture.
+    fn embedded() -> BTreeMap<String, ExtensionModule> {
+        fixture()
+            .into_iter()
+            .map(|(name, variants)| (name, variants[0].clone()))
+            .collect()
+    }
+
+    #[test]
+    fn test_license_denylist() -> Result<()> {
+        let mut policy = LicenseCheckPolicy {
+            denylist: strings(&["GPL-2.0", "GPL-3.0"]),
+            ..LicenseCheckPolicy::default()
+        };
+
+        assert_eq!(
+            policy.violations(&embedded()),
+            vec![
+                LicenseViolation {
+                    module: "_gdbm".to_string(),
+                    variant: "default".to_string(),
+                    license: "GPL-3.0".to_string(),
+                },
+                LicenseViolation {
+                    module: "_required".to_string(),
+                    variant: "default".to_string(),
+                    license: "GPL-2.0".to_string(),
+                },
+                LicenseViolation {
+                    module: "readline".to_string(),
+                    variant: "default".to_string(),
+                    license: "GPL-3.0".to_string(),
+                },
+            ]
+        );
+
+        policy.exempt_extensions.insert("_required".to_string());
+        policy.exempt_extensions.insert("readline".to_string());
+        let err = policy.check(&embedded()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "1 embedded extension modules have disallowed licenses:\n  _gdbm (variant default): GPL-3.0\nchange the extension modules or variants embedded, or add them to license_exempt_extensions"
+        );
+
+        policy.exempt_extensions.insert("_gdbm".to_string());
+        policy.check(&embedded())?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_license_allowlist() -> Result<()> {
+        let mut policy = LicenseCheckPolicy {
+            allowlist: Some(strings(&["OpenSSL", "GPL-2.0", "GPL-3.0"])),
+            ..LicenseCheckPolicy::default()
+        };
+
+        // Modules linking nothing are covered by Python's license and
+        // unknown licenses must be allowed explicitly.
+        assert_eq!(
+            policy.violations(&embedded()),
+            vec![LicenseViolation {
+                module: "_unknown".to_string(),
+                variant: "default".to_string(),
+                license: "NOASSERTION".to_string(),
+            }]
+        );
+
+        policy.denylist = strings(&["GPL-3.0"]);
+        assert_eq!(
+            policy
+                .violations(&embedded())
+                .iter()
+                .map(|v| v.module.as_str())
+                .collect::<Vec<_>>(),
+            vec!["_gdbm", "_unknown", "readline"]
+        );
+
+        assert!(LicenseCheckPolicy::default().is_empty());
+        LicenseCheckPolicy::default().check(&embedded())?;
+
+        Ok(())
+    }
+}
@@ -4,7 +4,11 @@
 
 use slog::Drain;
 
+use crate::build_events::print_human;
+
 /// A slog Drain that uses println!.
+///
+/// Messages go to stderr instead if stdout is reserved for JSON events.
 pub struct PrintlnDrain {
     /// Minimum logging level that we're emitting.
     pub min_level: slog::Level,
@@ -21,7 +25,7 @@ impl slog::Drain for PrintlnDrain {
         _values: &slog::OwnedKVList,
     ) -> Result<Self::Ok, Self::Err> {
         if record.level().is_at_least(self.min_level) {
-            println!("{}", record.msg());
+            print_human(&record.msg().to_string());
         }
 
         Ok(())
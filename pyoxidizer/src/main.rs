@@ -30,9 +30,16 @@ a rather effective and powerful tool.
 mod analyze;
 #[allow(unused)]
 pub mod app_packaging;
+mod build_events;
+mod build_stats;
+mod cache;
+mod cancel;
 mod cli;
+mod color;
 mod distribution;
 mod environment;
+mod exit_status;
+mod fsutils;
 mod licensing;
 mod logging;
 mod project_building;
@@ -46,11 +53,25 @@ pub mod starlark;
 mod testutil;
 
 fn main() {
-    std::process::exit(match cli::run_cli() {
-        Ok(_) => 0,
-        Err(e) => {
-            println!("error: {}", e);
-            1
-        }
-    });
+    let legacy = cli::legacy_exit_codes();
+    if let Some(path) = cli::stats_file() {
+        build_stats::start(&path);
+    }
+    let result = cli::run_cli();
+
+    if let Err(e) = &result {
+        build_events::print_human(&format!("error: {}", e));
+    }
+
+    let code = exit_status::exit_code(&result, legacy);
+
+    if let Err(e) = build_stats::finish(&result, code) {
+        eprintln!("unable to write build statistics: {:#}", e);
+    }
+
+    if !legacy {
+        eprintln!("{}", exit_status::status_line(&result, code));
+    }
+
+    std::process::exit(code);
 }
@@ -7,19 +7,39 @@ use slog::warn;
 use std::fs::create_dir_all;
 use std::path::{Path, PathBuf};
 
+use crate::app_packaging::config::rustflags_env;
+use crate::app_packaging::library::shared_library_filename;
+use crate::app_packaging::python_sys::{
+    python_sys_executable_for_build, PYTHON_SYS_EXECUTABLE_ENV,
+};
+use crate::app_packaging::rustc_bootstrap::rustc_bootstrap_for_build;
+use crate::app_packaging::toolchain::resolve_toolchain;
 use crate::environment::MINIMUM_RUST_VERSION;
-use crate::project_layout::initialize_project;
+use crate::project_layout::{initialize_project, ProjectTemplate};
 use crate::py_packaging::binary::{EmbeddedPythonBinaryData, PreBuiltPythonExecutable};
-use crate::py_packaging::config::RawAllocator;
 
-/// Build an executable embedding Python using an existing Rust project.
+/// The rustc flag exporting the symbols of an executable for a target.
+fn export_symbols_rustflag(target: &str) -> Option<&'static str> {
+    if target.contains("apple-darwin") {
+        Some("-Clink-arg=-Wl,-export_dynamic")
+    } else if target.contains("-linux-") {
+        Some("-Clink-arg=-Wl,--export-dynamic")
+    } else {
+        None
+    }
+}
+
+/// Build an existing Rust project embedding Python.
 ///
-/// The path to the produced executable is returned.
+/// `product_args` select what Cargo builds, e.g. `--bin <name>`. `rustflags`
+/// are the effective rustc flags, as resolved by `BuildConfig::resolve_rustflags()`.
+///
+/// The directory Cargo writes the target's artifacts to is returned.
 #[allow(clippy::too_many_arguments)]
-pub fn build_executable_with_rust_project(
+fn build_rust_project(
     logger: &slog::Logger,
     project_path: &Path,
-    bin_name: &str,
+    product_args: &[&str],
     exe: &PreBuiltPythonExecutable,
     build_path: &Path,
     artifacts_path: &Path,
@@ -27,6 +47,7 @@ pub fn build_executable_with_rust_project(
     target: &str,
     opt_level: &str,
     release: bool,
+    rustflags: &[String],
 ) -> Result<PathBuf> {
     create_dir_all(&artifacts_path)
         .with_context(|| "creating directory for PyOxidizer build artifacts")?;
@@ -37,7 +58,10 @@ pub fn build_executable_with_rust_project(
     )?;
     embedded_data.write_files(&artifacts_path)?;
 
-    let rust_version = rustc_version::version()?;
+    // Configs can't be consulted here, so only --toolchain selects one.
+    let toolchain = resolve_toolchain(None);
+    let rust_meta = toolchain.version_meta(project_path)?;
+    let rust_version = &rust_meta.semver;
     if rust_version.lt(&MINIMUM_RUST_VERSION) {
         return Err(anyhow!(
             "PyOxidizer requires Rust {}; version {} found",
@@ -53,7 +77,11 @@ pub fn build_executable_with_rust_project(
             .join(target)
             .join(if release { "release" } else { "debug" });
 
-    let mut args = Vec::new();
+    let toolchain_args = toolchain.cargo_args();
+    let mut args = toolchain_args
+        .iter()
+        .map(String::as_str)
+        .collect::<Vec<_>>();
     args.push("build");
     args.push("--target");
     args.push(target);
@@ -62,16 +90,15 @@ pub fn build_executable_with_rust_project(
     args.push("--target-dir");
     args.push(&target_dir);
 
-    args.push("--bin");
-    args.push(bin_name);
+    args.extend(product_args);
 
     if release {
         args.push("--release");
     }
 
-    if exe.config.raw_allocator == RawAllocator::Jemalloc {
+    if let Some(feature) = exe.config.raw_allocator.cargo_feature() {
         args.push("--features");
-        args.push("jemalloc");
+        args.push(feature);
     }
 
     let mut envs = Vec::new();
@@ -83,19 +110,36 @@ pub fn build_executable_with_rust_project(
 
     // Set PYTHON_SYS_EXECUTABLE so python3-sys uses our distribution's Python to configure
     // itself.
-    let python_exe_path = &exe.distribution.python_exe;
+    let python_exe_path = python_sys_executable_for_build(logger, &exe.distribution.python_exe)?;
     envs.push((
-        "PYTHON_SYS_EXECUTABLE",
+        PYTHON_SYS_EXECUTABLE_ENV,
         python_exe_path.display().to_string(),
     ));
 
-    // static-nobundle link kind requires nightly Rust compiler until
-    // https://github.com/rust-lang/rust/issues/37403 is resolved.
-    if cfg!(windows) {
+    // Extension modules loaded from the filesystem resolve Python symbols
+    // from the executable.
+    let mut rustflags = rustflags.to_vec();
+    if !exe.resources.filesystem_extension_modules().is_empty() {
+        if let Some(flag) = export_symbols_rustflag(target) {
+            if !rustflags.iter().any(|f| f == flag) {
+                rustflags.push(flag.to_string());
+            }
+        }
+    }
+
+    if let Some(value) = rustflags_env(&rustflags, std::env::var_os("RUSTFLAGS").is_some()) {
+        envs.push(("RUSTFLAGS", value));
+    }
+
+    // Configs can't be consulted here, so only --no-rustc-bootstrap
+    // forbids it.
+    if rustc_bootstrap_for_build(logger, &rust_meta, &exe.distribution.flavor, target, true)? {
         envs.push(("RUSTC_BOOTSTRAP", "1".to_string()));
     }
 
-    let status = std::process::Command::new("cargo")
+    envs.extend(toolchain.cargo_envs());
+
+    let status = std::process::Command::new(toolchain.cargo_program())
         .args(args)
         .current_dir(&project_path)
         .envs(envs)
@@ -105,6 +149,40 @@ pub fn build_executable_with_rust_project(
         return Err(anyhow!("cargo build failed"));
     }
 
+    Ok(target_triple_base_path)
+}
+
+/// Build an executable embedding Python using an existing Rust project.
+///
+/// The path to the produced executable is returned.
+#[allow(clippy::too_many_arguments)]
+pub fn build_executable_with_rust_project(
+    logger: &slog::Logger,
+    project_path: &Path,
+    bin_name: &str,
+    exe: &PreBuiltPythonExecutable,
+    build_path: &Path,
+    artifacts_path: &Path,
+    host: &str,
+    target: &str,
+    opt_level: &str,
+    release: bool,
+    rustflags: &[String],
+) -> Result<PathBuf> {
+    let target_triple_base_path = build_rust_project(
+        logger,
+        project_path,
+        &["--bin", bin_name],
+        exe,
+        build_path,
+        artifacts_path,
+        host,
+        target,
+        opt_level,
+        release,
+        rustflags,
+    )?;
+
     let exe_name = if target.contains("pc-windows") {
         format!("{}.exe", bin_name)
     } else {
@@ -123,6 +201,7 @@ pub fn build_executable_with_rust_project(
 /// Build a Python executable using a temporary Rust project.
 ///
 /// Returns the binary data constituting the built executable.
+#[allow(clippy::too_many_arguments)]
 pub fn build_python_executable(
     logger: &slog::Logger,
     bin_name: &str,
@@ -131,6 +210,7 @@ pub fn build_python_executable(
     target: &str,
     opt_level: &str,
     release: bool,
+    rustflags: &[String],
 ) -> Result<(String, Vec<u8>)> {
     let temp_dir = tempdir::TempDir::new("pyoxidizer")?;
 
@@ -139,7 +219,7 @@ pub fn build_python_executable(
     let build_path = temp_dir.path().join("build");
     let artifacts_path = temp_dir.path().join("artifacts");
 
-    initialize_project(&project_path, None, &[])?;
+    initialize_project(&project_path, None, &[], false, ProjectTemplate::Repl)?;
 
     let exe_path = build_executable_with_rust_project(
         logger,
@@ -152,6 +232,7 @@ pub fn build_python_executable(
         target,
         opt_level,
         release,
+        rustflags,
     )?;
 
     let data = std::fs::read(&exe_path)?;
@@ -160,11 +241,187 @@ pub fn build_python_executable(
     Ok((filename, data))
 }
 
+/// Build a shared library embedding Python using a temporary Rust project.
+///
+/// The library exports the C ABI of projects created by `pyoxidizer init --lib`.
+///
+/// Returns the filename and binary data of the built library.
+#[allow(clippy::too_many_arguments)]
+pub fn build_python_library(
+    logger: &slog::Logger,
+    lib_name: &str,
+    exe: &PreBuiltPythonExecutable,
+    host: &str,
+    target: &str,
+    opt_level: &str,
+    release: bool,
+    rustflags: &[String],
+) -> Result<(String, Vec<u8>)> {
+    let temp_dir = tempdir::TempDir::new("pyoxidizer")?;
+
+    // Directory needs to have name of project.
+    let project_path = temp_dir.path().join(lib_name);
+    let build_path = temp_dir.path().join("build");
+    let artifacts_path = temp_dir.path().join("artifacts");
+
+    initialize_project(&project_path, None, &[], true, ProjectTemplate::Repl)?;
+
+    let target_triple_base_path = build_rust_project(
+        logger,
+        &project_path,
+        &["--lib"],
+        exe,
+        &build_path,
+        &artifacts_path,
+        host,
+        target,
+        opt_level,
+        release,
+        rustflags,
+    )?;
+
+    let filename = shared_library_filename(target, lib_name);
+    let lib_path = target_triple_base_path.join(&filename);
+
+    if !lib_path.exists() {
+        return Err(anyhow!("{} does not exist", lib_path.display()));
+    }
+
+    let data = std::fs::read(&lib_path)?;
+
+    Ok((filename, data))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::app_packaging::library::{header_filename, library_header};
     use crate::py_packaging::binary::tests::get_prebuilt;
+    use crate::py_packaging::binary::SharedPythonResources;
+    use crate::py_packaging::config::{DetachedStdio, ImporterPrecedence, RunMode, SysExecutable};
+    use crate::py_packaging::distribution::{is_stdlib_test_package, ExtensionModuleFilter};
+    use crate::py_packaging::resource::{BytecodeOptimizationLevel, ResourceData, SourceModule};
     use crate::testutil::*;
+    use indoc::indoc;
+
+    /// Add the stdlib, without tests, to an executable.
+    fn add_stdlib(logger: &slog::Logger, exe: &mut PreBuiltPythonExecutable) -> Result<()> {
+        for ext in
+            exe.distribution
+                .filter_extension_modules(logger, &ExtensionModuleFilter::All, None)
+        {
+            exe.resources.add_extension_module(&ext);
+        }
+
+        for module in exe.distribution.source_modules()? {
+            if !is_stdlib_test_package(&module.package()) {
+                exe.resources.add_source_module(&module);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Build an executable with the stdlib that evaluates `code`.
+    ///
+    /// Returns the directory holding the executable and its path.
+    fn build_stdlib_executable(
+        logger: &slog::Logger,
+        code: &str,
+        configure: impl FnOnce(&mut PreBuiltPythonExecutable),
+    ) -> Result<(tempdir::TempDir, PathBuf)> {
+        let mut pre_built = get_prebuilt(logger)?;
+        pre_built.run_mode = RunMode::Eval {
+            code: code.to_string(),
+        };
+        add_stdlib(logger, &mut pre_built)?;
+
+        configure(&mut pre_built);
+
+        let (filename, data) = build_python_executable(
+            logger,
+            "myapp",
+            &pre_built,
+            env!("HOST"),
+            env!("HOST"),
+            "0",
+            false,
+            &[],
+        )?;
+
+        let temp_dir = tempdir::TempDir::new("pyoxidizer-test")?;
+        let exe_path = temp_dir.path().join(filename);
+        std::fs::write(&exe_path, &data)?;
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(&exe_path, std::fs::Permissions::from_mode(0o755))?;
+        }
+
+        if let Some(shared) = pre_built.shared_resources_file()? {
+            let path = temp_dir.path().join(&shared.path);
+            create_dir_all(path.parent().unwrap())?;
+            std::fs::write(&path, &shared.data)?;
+        }
+
+        Ok((temp_dir, exe_path))
+    }
+
+    /// Build an executable with the stdlib whose `main.rs` is `main_rs`.
+    ///
+    /// Returns the directory holding the executable and its path.
+    fn build_custom_main_executable(
+        logger: &slog::Logger,
+        main_rs: &str,
+    ) -> Result<(tempdir::TempDir, PathBuf)> {
+        let mut pre_built = get_prebuilt(logger)?;
+        pre_built.run_mode = RunMode::Noop;
+        add_stdlib(logger, &mut pre_built)?;
+
+        let temp_dir = tempdir::TempDir::new("pyoxidizer-test")?;
+        let project_path = temp_dir.path().join("myapp");
+        initialize_project(&project_path, None, &[], false, ProjectTemplate::Repl)?;
+        std::fs::write(project_path.join("src").join("main.rs"), main_rs)?;
+
+        let exe_path = build_executable_with_rust_project(
+            logger,
+            &project_path,
+            "myapp",
+            &pre_built,
+            &temp_dir.path().join("build"),
+            &temp_dir.path().join("artifacts"),
+            env!("HOST"),
+            env!("HOST"),
+            "0",
+            false,
+            &[],
+        )?;
+
+        Ok((temp_dir, exe_path))
+    }
+
+    /// Build and run an executable with the stdlib that evaluates `code`.
+    fn run_stdlib_executable(
+        logger: &slog::Logger,
+        sys_executable: SysExecutable,
+        code: &str,
+    ) -> Result<String> {
+        let (_temp_dir, exe_path) = build_stdlib_executable(logger, code, |exe| {
+            exe.config.sys_executable = sys_executable;
+            exe.config.run_python_marker = true;
+        })?;
+
+        let output = std::process::Command::new(&exe_path).output()?;
+        if !output.status.success() {
+            return Err(anyhow!(
+                "executable failed: {}",
+                String::from_utf8_lossy(&output.stderr)
+            ));
+        }
+
+        Ok(String::from_utf8_lossy(&output.stdout).to_string())
+    }
 
     #[test]
     fn test_empty_project() -> Result<()> {
@@ -179,8 +436,921 @@ mod tests {
             env!("HOST"),
             "0",
             false,
+            &[],
         )?;
 
         Ok(())
     }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_library_c_harness() -> Result<()> {
+        let logger = get_logger()?;
+        let mut pre_built = get_prebuilt(&logger)?;
+        pre_built.run_mode = RunMode::Embedding;
+
+        for module in pre_built.distribution.source_modules()? {
+            if !is_stdlib_test_package(&module.package()) {
+                pre_built.resources.add_source_module(&module);
+            }
+        }
+
+        let (filename, data) = build_python_library(
+            &logger,
+            "testlib",
+            &pre_built,
+            env!("HOST"),
+            env!("HOST"),
+            "0",
+            false,
+            &[],
+        )?;
+
+        let temp_dir = tempdir::TempDir::new("pyoxidizer-test")?;
+        std::fs::write(temp_dir.path().join(filename), &data)?;
+        std::fs::write(
+            temp_dir.path().join(header_filename("testlib")),
+            library_header("testlib"),
+        )?;
+
+        let harness_path = temp_dir.path().join("harness.c");
+        std::fs::write(
+            &harness_path,
+            indoc!(
+                r#"
+                #include "testlib.h"
+
+                int main(void) {
+                    if (testlib_init() != 0) return 10;
+                    if (testlib_eval("import sys\nprint('argv', sys.argv)") != 0) return 11;
+                    if (testlib_eval("raise ValueError('expected')") != 1) return 12;
+                    if (testlib_eval("print('still alive')") != 0) return 13;
+                    /* Initializing again while initialized does nothing. */
+                    if (testlib_init() != 0) return 14;
+                    if (testlib_eval("print('same interpreter', 'sys' in dir())") != 0) return 15;
+                    testlib_shutdown();
+                    /* The interpreter can't be used or initialized again. */
+                    if (testlib_eval("print('finalized')") != -1) return 16;
+                    if (testlib_init() != 1) return 17;
+                    if (testlib_eval("print('finalized')") != -1) return 18;
+                    return 0;
+                }
+                "#
+            ),
+        )?;
+
+        let exe_path = temp_dir.path().join("harness");
+        let compiler = cc::Build::new()
+            .host(env!("HOST"))
+            .target(env!("HOST"))
+            .opt_level(0)
+            .cargo_metadata(false)
+            .get_compiler();
+        let status = compiler
+            .to_command()
+            .arg(&harness_path)
+            .arg("-I")
+            .arg(temp_dir.path())
+            .arg("-L")
+            .arg(temp_dir.path())
+            .arg("-ltestlib")
+            .arg("-o")
+            .arg(&exe_path)
+            .status()?;
+        if !status.success() {
+            return Err(anyhow!("compiling C harness failed"));
+        }
+
+        let output = std::process::Command::new(&exe_path)
+            .env("LD_LIBRARY_PATH", temp_dir.path())
+            .env("DYLD_LIBRARY_PATH", temp_dir.path())
+            .output()?;
+        if !output.status.success() {
+            return Err(anyhow!(
+                "harness failed ({}): {}",
+                output.status,
+                String::from_utf8_lossy(&output.stderr)
+            ));
+        }
+
+        assert_eq!(
+            String::from_utf8_lossy(&output.stdout),
+            "argv ['']\nstill alive\nsame interpreter True\n"
+        );
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(stderr.contains("ValueError: expected"));
+        assert!(stderr.contains(
+            "a Python interpreter cannot be initialized after the interpreter was finalized"
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_subprocess_sys_executable() -> Result<()> {
+        let logger = get_logger()?;
+
+        let stdout = run_stdlib_executable(
+            &logger,
+            SysExecutable::Exe,
+            indoc!(
+                r#"
+                import os, subprocess, sys
+                assert sys.prefix == os.path.dirname(sys.executable)
+                res = subprocess.run(
+                    [sys.executable, "--pyoxidizer-run-python", "-c", "import sys; print(sys.argv)"],
+                    stdout=subprocess.PIPE,
+                    check=True,
+                )
+                print(res.stdout.decode("utf-8").strip())
+                "#
+            ),
+        )?;
+
+        assert_eq!(stdout.trim(), "['-c']");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_subprocess_empty_sys_executable() -> Result<()> {
+        let logger = get_logger()?;
+
+        let stdout = run_stdlib_executable(
+            &logger,
+            SysExecutable::Empty,
+            indoc!(
+                r#"
+                import subprocess, sys
+                try:
+                    subprocess.run([sys.executable, "-c", "pass"])
+                except OSError:
+                    print("no interpreter")
+                "#
+            ),
+        )?;
+
+        assert_eq!(stdout.trim(), "no interpreter");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_console_detection() -> Result<()> {
+        let logger = get_logger()?;
+
+        let stdout = run_stdlib_executable(
+            &logger,
+            SysExecutable::Exe,
+            "import oxidized_app; print(oxidized_app.has_console, oxidized_app.stdout_is_console, oxidized_app.stdio_buffer())",
+        )?;
+
+        // stdout is a pipe.
+        assert_eq!(stdout.trim(), "False False None");
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_detached_stdio_null() -> Result<()> {
+        let logger = get_logger()?;
+
+        let (_temp_dir, exe_path) = build_stdlib_executable(
+            &logger,
+            indoc!(
+                r#"
+                import oxidized_app, sys
+                if oxidized_app.has_console or sys.stdout is None or sys.stdin.read() != "":
+                    sys.exit(3)
+                for i in range(100000):
+                    print("line", i)
+                sys.stdout.write("direct\n")
+                sys.stderr.write("error\n")
+                sys.stdout.flush()
+                "#
+            ),
+            |_| {},
+        )?;
+
+        assert!(run_detached(&exe_path, &[])?.success());
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_detached_stdio_logfile() -> Result<()> {
+        let logger = get_logger()?;
+
+        let (temp_dir, exe_path) = build_stdlib_executable(
+            &logger,
+            indoc!(
+                r#"
+                import sys
+                for i in range(100000):
+                    print("line", i)
+                sys.stderr.write("done\n")
+                "#
+            ),
+            |exe| {
+                exe.config.detached_stdio = DetachedStdio::LogFile("logs/app.log".to_string());
+            },
+        )?;
+
+        assert!(run_detached(&exe_path, &[])?.success());
+
+        let log_dir = temp_dir.path().join("logs");
+        let current = std::fs::read_to_string(log_dir.join("app.log"))?;
+        let rotated = std::fs::read_to_string(log_dir.join("app.log.1"))?;
+
+        assert!(current.ends_with("line 99999\ndone\n"));
+        assert!(rotated.starts_with("line "));
+        assert!(rotated.len() <= 1024 * 1024);
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_detached_stdio_buffer() -> Result<()> {
+        let logger = get_logger()?;
+
+        let (_temp_dir, exe_path) = build_stdlib_executable(
+            &logger,
+            indoc!(
+                r#"
+                import oxidized_app, sys
+                for i in range(200000):
+                    print("line", i)
+                buffered = oxidized_app.stdio_buffer()
+                if len(buffered) != oxidized_app.BUFFER_SIZE or not buffered.endswith("line 199999\n"):
+                    sys.exit(3)
+                "#
+            ),
+            |exe| {
+                exe.config.detached_stdio = DetachedStdio::Buffer;
+            },
+        )?;
+
+        assert!(run_detached(&exe_path, &[])?.success());
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_rust_extension_module() -> Result<()> {
+        use crate::py_packaging::rust_extension::{
+            build_rust_extension_module, RustExtensionModule,
+        };
+
+        let logger = get_logger()?;
+        let temp_dir = tempdir::TempDir::new("pyoxidizer-test")?;
+
+        let crate_path = temp_dir.path().join("fast");
+        std::fs::create_dir_all(crate_path.join("src"))?;
+        let cpython_path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("..")
+            .join("third_party")
+            .join("rust-cpython");
+        std::fs::write(
+            crate_path.join("Cargo.toml"),
+            indoc!(
+                r#"
+                [package]
+                name = "fast"
+                version = "0.1.0"
+                edition = "2018"
+
+                [lib]
+                crate-type = ["cdylib"]
+
+                [dependencies.cpython]
+                path = "CPYTHON_PATH"
+                features = ["extension-module"]
+
+                [workspace]
+                "#
+            )
+            .replace("CPYTHON_PATH", &cpython_path.display().to_string()),
+        )?;
+        std::fs::write(
+            crate_path.join("src").join("lib.rs"),
+            indoc!(
+                r#"
+                #[macro_use]
+                extern crate cpython;
+
+                use cpython::{PyResult, Python};
+
+                py_module_initializer!(_fast, init_fast, PyInit__fast, |py, m| {
+                    m.add(py, "add", py_fn!(py, add(a: i64, b: i64)))?;
+                    Ok(())
+                });
+
+                fn add(_py: Python, a: i64, b: i64) -> PyResult<i64> {
+                    Ok(a + b)
+                }
+                "#
+            ),
+        )?;
+
+        let mut pre_built = get_prebuilt(&logger)?;
+
+        let em = build_rust_extension_module(
+            &logger,
+            &RustExtensionModule {
+                name: "_fast".to_string(),
+                manifest_path: crate_path.join("Cargo.toml"),
+                features: vec![],
+            },
+            &pre_built.distribution,
+            env!("HOST"),
+            env!("HOST"),
+            &temp_dir.path().join("target"),
+            false,
+            false,
+            &temp_dir.path().join("modules"),
+        )?;
+
+        for module in pre_built.distribution.source_modules()? {
+            if !is_stdlib_test_package(&module.package()) {
+                pre_built.resources.add_source_module(&module);
+            }
+        }
+        pre_built.resources.add_extension_module(&em);
+        pre_built
+            .resources
+            .filesystem_packages
+            .insert("_fast".to_string());
+        pre_built.config.filesystem_importer = true;
+        pre_built.config.sys_paths = vec!["$ORIGIN/lib".to_string()];
+        pre_built.run_mode = RunMode::Eval {
+            code: "import _fast; print(_fast.add(2, 3))".to_string(),
+        };
+
+        let (filename, data) = build_python_executable(
+            &logger,
+            "myapp",
+            &pre_built,
+            env!("HOST"),
+            env!("HOST"),
+            "0",
+            false,
+            &[],
+        )?;
+
+        let app_path = temp_dir.path().join("app");
+        let library_path = em.shared_library.unwrap();
+        std::fs::create_dir_all(app_path.join("lib"))?;
+        std::fs::copy(
+            &library_path,
+            app_path.join("lib").join(library_path.file_name().unwrap()),
+        )?;
+
+        let exe_path = app_path.join(filename);
+        std::fs::write(&exe_path, &data)?;
+        {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(&exe_path, std::fs::Permissions::from_mode(0o755))?;
+        }
+
+        let output = std::process::Command::new(&exe_path).output()?;
+        if !output.status.success() {
+            return Err(anyhow!(
+                "executable failed: {}",
+                String::from_utf8_lossy(&output.stderr)
+            ));
+        }
+
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "5\n");
+
+        Ok(())
+    }
+
+    /// Add a module as both source and bytecode.
+    fn add_module(exe: &mut PreBuiltPythonExecutable, name: &str, source: &str, is_package: bool) {
+        let module = SourceModule {
+            name: name.to_string(),
+            source: source.as_bytes().to_vec(),
+            is_package,
+        };
+
+        exe.resources.add_source_module(&module);
+        exe.resources
+            .add_bytecode_module(&module.as_bytecode_module(BytecodeOptimizationLevel::Zero));
+    }
+
+    #[test]
+    fn test_lazy_index() -> Result<()> {
+        let logger = get_logger()?;
+
+        let code = indoc!(
+            r#"
+            import importlib.resources, sys
+            import app.sub
+            assert "ns" not in sys.modules
+            from ns import a, b
+            import ns
+            print(app.sub.VALUE, a.VALUE + b.VALUE, ns.__path__ is not None)
+            print(importlib.resources.read_text("app.data", "config.txt"))
+            print(sorted(importlib.resources.contents("app.data")))
+            "#
+        );
+
+        let mut outputs = Vec::new();
+
+        for lazy_index in &[false, true] {
+            let (_temp_dir, exe_path) = build_stdlib_executable(&logger, code, |exe| {
+                exe.resources.lazy_index = *lazy_index;
+
+                add_module(exe, "app", "", true);
+                add_module(exe, "app.sub", "from app import data\nVALUE = 42\n", false);
+                add_module(exe, "app.data", "", true);
+                // A pkgutil-style namespace package.
+                add_module(
+                    exe,
+                    "ns",
+                    "__path__ = __import__('pkgutil').extend_path(__path__, __name__)\n",
+                    true,
+                );
+                add_module(exe, "ns.a", "VALUE = 1\n", false);
+                add_module(exe, "ns.b", "VALUE = 2\n", false);
+                exe.resources.add_resource(&ResourceData {
+                    package: "app.data".to_string(),
+                    name: "config.txt".to_string(),
+                    data: b"key = value".to_vec(),
+                });
+            })?;
+
+            let output = std::process::Command::new(&exe_path).output()?;
+            if !output.status.success() {
+                return Err(anyhow!(
+                    "executable failed: {}",
+                    String::from_utf8_lossy(&output.stderr)
+                ));
+            }
+
+            outputs.push(String::from_utf8_lossy(&output.stdout).to_string());
+        }
+
+        assert_eq!(outputs[0], "42 3 True\nkey = value\n['config.txt']\n");
+        assert_eq!(outputs[0], outputs[1]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_runtime_code_override() -> Result<()> {
+        let logger = get_logger()?;
+
+        let code = "import sys; print('configured', sys.argv[1:])";
+        let args = [
+            "--pyoxidizer-c",
+            "import sys; print('override', sys.argv)",
+            "a",
+        ];
+
+        for allow in &[false, true] {
+            let (_temp_dir, exe_path) = build_stdlib_executable(&logger, code, |exe| {
+                exe.config.allow_runtime_code_override = *allow;
+            })?;
+
+            let output = std::process::Command::new(&exe_path).args(&args).output()?;
+            if !output.status.success() {
+                return Err(anyhow!(
+                    "executable failed: {}",
+                    String::from_utf8_lossy(&output.stderr)
+                ));
+            }
+
+            // Without the config allowing it, support is compiled out and
+            // reserved arguments reach the configured code untouched.
+            assert_eq!(
+                String::from_utf8_lossy(&output.stdout),
+                if *allow {
+                    "override ['-c', 'a']\n"
+                } else {
+                    "configured ['--pyoxidizer-c', \"import sys; print('override', sys.argv)\", 'a']\n"
+                }
+            );
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_shared_resources() -> Result<()> {
+        let logger = get_logger()?;
+
+        let code = indoc!(
+            r#"
+            import importlib.resources, json
+            import app.a, app.b
+            print(json.dumps([app.a.VALUE, app.b.VALUE]))
+            print(importlib.resources.read_text("app", "a.txt"))
+            print(importlib.resources.read_text("app", "b.txt"))
+            "#
+        );
+
+        let add_resource = |exe: &mut PreBuiltPythonExecutable, name: &str, data: &str| {
+            exe.resources.add_resource(&ResourceData {
+                package: "app".to_string(),
+                name: name.to_string(),
+                data: data.as_bytes().to_vec(),
+            });
+        };
+
+        for (shared_lazy, own_lazy) in &[(false, false), (true, false), (false, true), (true, true)]
+        {
+            let (temp_dir, exe_path) = build_stdlib_executable(&logger, code, |exe| {
+                add_module(exe, "app", "", true);
+                add_module(exe, "app.a", "VALUE = 'shared'\n", false);
+                add_module(exe, "app.b", "VALUE = 'shared'\n", false);
+                add_resource(exe, "a.txt", "shared");
+                add_resource(exe, "b.txt", "shared");
+
+                let mut shared = exe.resources.clone();
+                shared.lazy_index = *shared_lazy;
+                exe.shared_resources = Some(SharedPythonResources {
+                    path: "lib/shared-python-resources".to_string(),
+                    resources: shared,
+                });
+
+                // Modules and resources which differ are embedded and win.
+                exe.resources.lazy_index = *own_lazy;
+                add_module(exe, "app.b", "VALUE = 'own'\n", false);
+                add_resource(exe, "b.txt", "own");
+            })?;
+
+            let output = std::process::Command::new(&exe_path).output()?;
+            if !output.status.success() {
+                return Err(anyhow!(
+                    "executable failed: {}",
+                    String::from_utf8_lossy(&output.stderr)
+                ));
+            }
+
+            assert_eq!(
+                String::from_utf8_lossy(&output.stdout),
+                "[\"shared\", \"own\"]\nshared\nown\n"
+            );
+
+            // Executables refuse shared resources they weren't built with.
+            let shared_path = temp_dir.path().join("lib").join("shared-python-resources");
+            let mut data = std::fs::read(&shared_path)?;
+            data.push(0);
+            std::fs::write(&shared_path, &data)?;
+
+            let output = std::process::Command::new(&exe_path).output()?;
+            assert!(!output.status.success());
+            assert!(
+                String::from_utf8_lossy(&output.stderr).contains("do not match this executable")
+            );
+
+            std::fs::remove_file(&shared_path)?;
+            let output = std::process::Command::new(&exe_path).output()?;
+            assert!(!output.status.success());
+            assert!(
+                String::from_utf8_lossy(&output.stderr).contains("unable to read shared resources")
+            );
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_importer_precedence() -> Result<()> {
+        let logger = get_logger()?;
+
+        let code = indoc!(
+            r#"
+            import sys
+            import app, _symtable
+            print(app.WHERE)
+            print(_symtable.__spec__.origin)
+            print(" ".join(getattr(f, "__name__", type(f).__name__) for f in sys.meta_path))
+            "#
+        );
+
+        for (precedence, expected) in &[
+            (
+                ImporterPrecedence::MemoryFirst,
+                "memory\nbuilt-in\nPyOxidizerFinder PathFinder\n",
+            ),
+            (
+                ImporterPrecedence::FilesystemFirst,
+                "filesystem\nbuilt-in\nBuiltinImporter FrozenImporter PathFinder PyOxidizerFinder\n",
+            ),
+        ] {
+            let (temp_dir, exe_path) = build_stdlib_executable(&logger, code, |exe| {
+                exe.config.filesystem_importer = true;
+                exe.config.importer_precedence = precedence.clone();
+                exe.config.sys_paths = vec!["$ORIGIN/lib".to_string()];
+                add_module(exe, "app", "WHERE = 'memory'\n", false);
+            })?;
+
+            let lib = temp_dir.path().join("lib");
+            std::fs::create_dir(&lib)?;
+            std::fs::write(lib.join("app.py"), "WHERE = 'filesystem'\n")?;
+            // Built-in modules aren't shadowed by the filesystem either way.
+            std::fs::write(
+                lib.join("_symtable.py"),
+                "raise ImportError('built-in module shadowed')\n",
+            )?;
+
+            let output = std::process::Command::new(&exe_path).output()?;
+            assert!(
+                output.status.success(),
+                "{:?}: {}",
+                precedence,
+                String::from_utf8_lossy(&output.stderr)
+            );
+            assert_eq!(
+                String::from_utf8_lossy(&output.stdout),
+                *expected,
+                "{:?}",
+                precedence
+            );
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_import_profile_filesystem() -> Result<()> {
+        let logger = get_logger()?;
+
+        let (temp_dir, exe_path) = build_stdlib_executable(&logger, "import slowmod\n", |exe| {
+            exe.config.filesystem_importer = true;
+            exe.config.sys_paths = vec!["$ORIGIN/lib".to_string()];
+            exe.config.import_profile_env = Some("APP_IMPORT_PROFILE".to_string());
+        })?;
+
+        let lib = temp_dir.path().join("lib");
+        std::fs::create_dir(&lib)?;
+        std::fs::write(lib.join("slowmod.py"), "import time\ntime.sleep(0.05)\n")?;
+
+        let profile_path = temp_dir.path().join("profile.json");
+        let output = std::process::Command::new(&exe_path)
+            .env("APP_IMPORT_PROFILE", &profile_path)
+            .output()?;
+        assert!(
+            output.status.success(),
+            "{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+
+        let profile: serde_json::Value = serde_json::from_slice(&std::fs::read(&profile_path)?)?;
+        let slowmod = profile["imports"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|i| i["name"] == "slowmod")
+            .unwrap();
+        assert_eq!(slowmod["source"], "filesystem");
+        assert!(slowmod["cumulative_us"].as_u64().unwrap() >= 50_000);
+        assert!(slowmod["self_us"].as_u64().unwrap() >= 50_000);
+        assert!(slowmod["bytes"].as_u64().unwrap() > 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_startup_hook_order() -> Result<()> {
+        let logger = get_logger()?;
+
+        let record = |what: &str| {
+            format!(
+                "import os\nwith open(os.environ['ORDER_PATH'], 'a') as fh:\n    fh.write('{}\\n')\n",
+                what
+            )
+        };
+
+        let (temp_dir, exe_path) = build_stdlib_executable(&logger, &record("run mode"), |exe| {
+            add_module(exe, "app_startup", &record("startup module"), false);
+            exe.config.startup_module = Some("app_startup".to_string());
+            exe.config.startup_code = Some(record("startup code"));
+        })?;
+
+        let order_path = temp_dir.path().join("order.txt");
+        let output = std::process::Command::new(&exe_path)
+            .env("ORDER_PATH", &order_path)
+            .output()?;
+        assert!(
+            output.status.success(),
+            "{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        assert_eq!(
+            std::fs::read_to_string(&order_path)?,
+            "startup module\nstartup code\nrun mode\n"
+        );
+
+        // A failing hook prevents the run mode from running.
+        let (temp_dir, exe_path) = build_stdlib_executable(&logger, &record("run mode"), |exe| {
+            exe.config.startup_code = Some(format!(
+                "{}raise ValueError('hook failed')\n",
+                record("startup code")
+            ));
+        })?;
+
+        let order_path = temp_dir.path().join("order.txt");
+        let output = std::process::Command::new(&exe_path)
+            .env("ORDER_PATH", &order_path)
+            .output()?;
+        assert!(!output.status.success());
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(stderr.contains("ValueError: hook failed"), "{}", stderr);
+        assert!(
+            stderr.contains("error running Python startup hook"),
+            "{}",
+            stderr
+        );
+        assert_eq!(std::fs::read_to_string(&order_path)?, "startup code\n");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_interpreter_lifecycle() -> Result<()> {
+        let logger = get_logger()?;
+
+        let (_temp_dir, exe_path) = build_custom_main_executable(
+            &logger,
+            indoc!(
+                r#"
+                use pyembed::{default_python_config, MainPythonInterpreter};
+
+                fn main() {
+                    let mut lazy = MainPythonInterpreter::lazy(default_python_config());
+                    println!("initialized: {}", lazy.is_initialized());
+
+                    let interp = lazy.acquire().expect("initializing interpreter");
+                    interp.run_code("print('first', flush=True)").expect("running code");
+                    println!("initialized: {}", lazy.is_initialized());
+
+                    match MainPythonInterpreter::new(default_python_config()) {
+                        Ok(_) => println!("second interpreter initialized"),
+                        Err(msg) => println!("second: {}", msg),
+                    }
+
+                    // The first interpreter is unaffected and acquired again.
+                    let interp = lazy.acquire().expect("acquiring interpreter");
+                    interp.run_code("print('still first', flush=True)").expect("running code");
+
+                    println!("exit code: {}", lazy.finalize(3));
+
+                    let mut after = MainPythonInterpreter::lazy(default_python_config());
+                    for _ in 0..2 {
+                        match after.acquire() {
+                            Ok(_) => println!("initialized after finalize"),
+                            Err(msg) => println!("after finalize: {}", msg),
+                        }
+                    }
+                    println!("initialized: {}", after.is_initialized());
+                }
+                "#
+            ),
+        )?;
+
+        let output = std::process::Command::new(&exe_path).output()?;
+        assert!(
+            output.status.success(),
+            "{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        assert_eq!(
+            String::from_utf8_lossy(&output.stdout),
+            indoc!(
+                r#"
+                initialized: false
+                first
+                initialized: true
+                second: a Python interpreter is already initialized in this process
+                still first
+                exit code: 3
+                after finalize: a Python interpreter cannot be initialized after the interpreter was finalized
+                after finalize: a Python interpreter cannot be initialized after the interpreter was finalized
+                initialized: false
+                "#
+            )
+        );
+
+        Ok(())
+    }
+
+    /// Compares startup time of an executable initializing its interpreter
+    /// with one whose lazily constructed interpreter is never acquired, like
+    /// when handling `--help`.
+    ///
+    /// Run with `cargo test --release -- --ignored --nocapture bench_`.
+    #[test]
+    #[ignore]
+    fn bench_lazy_interpreter_startup() -> Result<()> {
+        use std::time::{Duration, Instant};
+
+        const RUNS: usize = 20;
+
+        let logger = get_logger()?;
+
+        let (_temp_dir, exe_path) = build_custom_main_executable(
+            &logger,
+            indoc!(
+                r#"
+                use pyembed::{default_python_config, MainPythonInterpreter};
+
+                fn main() {
+                    let mut lazy = MainPythonInterpreter::lazy(default_python_config());
+
+                    if std::env::args().any(|arg| arg == "--help") {
+                        println!("usage: myapp");
+                        return;
+                    }
+
+                    let code = lazy.acquire().expect("initializing interpreter").run_as_main();
+                    std::process::exit(lazy.finalize(code));
+                }
+                "#
+            ),
+        )?;
+
+        for args in &[&[][..], &["--help"][..]] {
+            let mut timings = Vec::with_capacity(RUNS);
+            for _ in 0..RUNS {
+                let start = Instant::now();
+                let status = std::process::Command::new(&exe_path).args(*args).status()?;
+                timings.push(start.elapsed());
+                assert!(status.success());
+            }
+            timings.sort();
+
+            eprintln!(
+                "args {:?}: median startup {:?}; fastest {:?}",
+                args,
+                timings[RUNS / 2],
+                timings.first().cloned().unwrap_or_else(Duration::default)
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Compares startup time of executables embedding a large number of
+    /// modules with and without a lazily hydrated index.
+    ///
+    /// Run with `cargo test --release -- --ignored --nocapture bench_`.
+    #[test]
+    #[ignore]
+    fn bench_lazy_index_startup() -> Result<()> {
+        use std::time::{Duration, Instant};
+
+        const PACKAGES: usize = 500;
+        const MODULES: usize = 60;
+        const RUNS: usize = 20;
+
+        let logger = get_logger()?;
+
+        for lazy_index in &[false, true] {
+            let (_temp_dir, exe_path) =
+                build_stdlib_executable(&logger, "import pkg0.mod0", |exe| {
+                    exe.resources.lazy_index = *lazy_index;
+
+                    for package in 0..PACKAGES {
+                        let name = format!("pkg{}", package);
+                        add_module(exe, &name, "", true);
+
+                        for module in 0..MODULES {
+                            add_module(
+                                exe,
+                                &format!("{}.mod{}", name, module),
+                                &format!("VALUE = {}\n", module),
+                                false,
+                            );
+                        }
+                    }
+                })?;
+
+            let mut timings = Vec::with_capacity(RUNS);
+            for _ in 0..RUNS {
+                let start = Instant::now();
+                let status = std::process::Command::new(&exe_path).status()?;
+                timings.push(start.elapsed());
+                assert!(status.success());
+            }
+            timings.sort();
+
+            eprintln!(
+                "{} modules, lazy_index={}: median startup {:?}; fastest {:?}; executable {} bytes",
+                PACKAGES * (MODULES + 1),
+                lazy_index,
+                timings[RUNS / 2],
+                timings.first().cloned().unwrap_or_else(Duration::default),
+                std::fs::metadata(&exe_path)?.len()
+            );
+        }
+
+        Ok(())
+    }
 }
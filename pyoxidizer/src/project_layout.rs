@@ -13,6 +13,7 @@ use std::fs::create_dir_all;
 use std::io::Write;
 use std::path::{Path, PathBuf};
 
+use crate::app_packaging::library;
 use crate::environment::{
     canonicalize_path, PyOxidizerSource, BUILD_GIT_COMMIT, PYOXIDIZER_VERSION,
 };
@@ -27,9 +28,13 @@ lazy_static! {
         res.insert("data.rs", include_bytes!("pyembed/data.rs"));
         res.insert("importer.rs", include_bytes!("pyembed/importer.rs"));
         res.insert("osutils.rs", include_bytes!("pyembed/osutils.rs"));
+        res.insert("profile.rs", include_bytes!("pyembed/profile.rs"));
         res.insert("pyalloc.rs", include_bytes!("pyembed/pyalloc.rs"));
         res.insert("pyinterp.rs", include_bytes!("pyembed/pyinterp.rs"));
         res.insert("pystr.rs", include_bytes!("pyembed/pystr.rs"));
+        res.insert("shared.rs", include_bytes!("pyembed/shared.rs"));
+        res.insert("repl.py", include_bytes!("pyembed/repl.py"));
+        res.insert("oxidized_app.py", include_bytes!("pyembed/oxidized_app.py"));
 
         res
     };
@@ -39,12 +44,27 @@ lazy_static! {
         handlebars
             .register_template_string("new-main.rs", include_str!("templates/new-main.rs"))
             .unwrap();
+        handlebars
+            .register_template_string("new-lib.rs", include_str!("templates/new-lib.rs"))
+            .unwrap();
         handlebars
             .register_template_string(
                 "new-pyoxidizer.bzl",
                 include_str!("templates/new-pyoxidizer.bzl"),
             )
             .unwrap();
+        handlebars
+            .register_template_string("app-main.py", include_str!("templates/app-main.py"))
+            .unwrap();
+        handlebars
+            .register_template_string("app-cli.py", include_str!("templates/app-cli.py"))
+            .unwrap();
+        handlebars
+            .register_template_string("app-service.py", include_str!("templates/app-service.py"))
+            .unwrap();
+        handlebars
+            .register_template_string("app-gui.py", include_str!("templates/app-gui.py"))
+            .unwrap();
         handlebars
             .register_template_string(
                 "pyembed-build.rs",
@@ -62,6 +82,55 @@ lazy_static! {
     };
 }
 
+/// The kind of application created by `pyoxidizer init`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ProjectTemplate {
+    /// An interactive Python REPL.
+    Repl,
+    /// A command line tool with an argparse entry point.
+    Cli,
+    /// A long-running service stopping cleanly on SIGTERM.
+    Service,
+    /// A GUI application without a console.
+    Gui,
+}
+
+impl ProjectTemplate {
+    /// Names of templates, as accepted by `from_name()`.
+    pub const NAMES: &'static [&'static str] = &["repl", "cli", "service", "gui"];
+
+    pub fn from_name(name: &str) -> Result<ProjectTemplate> {
+        match name {
+            "repl" => Ok(ProjectTemplate::Repl),
+            "cli" => Ok(ProjectTemplate::Cli),
+            "service" => Ok(ProjectTemplate::Service),
+            "gui" => Ok(ProjectTemplate::Gui),
+            _ => Err(anyhow!(
+                "unknown project template {}; expected one of {}",
+                name,
+                ProjectTemplate::NAMES.join(", ")
+            )),
+        }
+    }
+
+    /// The template of the `__init__.py` of the example Python package.
+    ///
+    /// The REPL has no package.
+    fn package_template(self) -> Option<&'static str> {
+        match self {
+            ProjectTemplate::Repl => None,
+            ProjectTemplate::Cli => Some("app-cli.py"),
+            ProjectTemplate::Service => Some("app-service.py"),
+            ProjectTemplate::Gui => Some("app-gui.py"),
+        }
+    }
+}
+
+/// Name of the example Python package of a project.
+pub fn python_package_name(project_name: &str) -> String {
+    project_name.replace('-', "_")
+}
+
 #[derive(Serialize)]
 struct PythonDistribution {
     build_target: String,
@@ -82,6 +151,10 @@ struct TemplateData {
     program_name: Option<String>,
     code: Option<String>,
     pip_install_simple: Vec<String>,
+    lib: bool,
+    python_package: Option<String>,
+    service: bool,
+    gui: bool,
 }
 
 impl TemplateData {
@@ -97,6 +170,10 @@ impl TemplateData {
             program_name: None,
             code: None,
             pip_install_simple: Vec::new(),
+            lib: false,
+            python_package: None,
+            service: false,
+            gui: false,
         }
     }
 }
@@ -140,10 +217,59 @@ pub fn find_pyoxidizer_files(root: &Path) -> Vec<PathBuf> {
     res
 }
 
+/// Render the main.rs file of new projects.
+fn render_main_rs(template: ProjectTemplate) -> Result<String> {
+    let mut data: BTreeMap<String, bool> = BTreeMap::new();
+    data.insert("gui".to_string(), template == ProjectTemplate::Gui);
+
+    Ok(HANDLEBARS.render("new-main.rs", &data)?)
+}
+
+/// Render the lib.rs file of new library projects.
+fn render_lib_rs(lib_name: &str) -> Result<String> {
+    let mut data: BTreeMap<String, String> = BTreeMap::new();
+    data.insert("lib_name".to_string(), lib_name.to_string());
+
+    Ok(HANDLEBARS.render("new-lib.rs", &data)?)
+}
+
+/// Render the build.rs file of the pyembed crate.
+fn render_pyembed_build_rs() -> Result<String> {
+    let mut data: BTreeMap<String, String> = BTreeMap::new();
+    data.insert(
+        "pyoxidizer_exe".to_string(),
+        canonicalize_path(&std::env::current_exe()?)?
+            .display()
+            .to_string(),
+    );
+
+    Ok(HANDLEBARS.render("pyembed-build.rs", &data)?)
+}
+
+/// Render the Cargo.toml file of the pyembed crate.
+fn render_pyembed_cargo_toml() -> Result<String> {
+    let mut data = TemplateData::new();
+    populate_template_data(&mut data);
+
+    Ok(HANDLEBARS.render("pyembed-cargo.toml", &data)?)
+}
+
 /// Write a new main.rs file that runs the embedded Python interpreter.
-pub fn write_new_main_rs(path: &Path) -> Result<()> {
-    let data: BTreeMap<String, String> = BTreeMap::new();
-    let t = HANDLEBARS.render("new-main.rs", &data)?;
+pub fn write_new_main_rs(path: &Path, template: ProjectTemplate) -> Result<()> {
+    let t = render_main_rs(template)?;
+
+    println!("writing {}", path.to_str().unwrap());
+    let mut fh = std::fs::File::create(path)?;
+    fh.write_all(t.as_bytes())?;
+
+    Ok(())
+}
+
+/// Write a new lib.rs file exporting a C ABI backed by the embedded Python interpreter.
+///
+/// Exported functions are prefixed with `lib_name`.
+pub fn write_new_lib_rs(path: &Path, lib_name: &str) -> Result<()> {
+    let t = render_lib_rs(lib_name)?;
 
     println!("writing {}", path.to_str().unwrap());
     let mut fh = std::fs::File::create(path)?;
@@ -153,17 +279,29 @@ pub fn write_new_main_rs(path: &Path) -> Result<()> {
 }
 
 /// Writes default PyOxidizer config files into a project directory.
+///
+/// `lib` indicates the project is a library embedding Python in a host
+/// application. Otherwise `template` selects the kind of application.
 pub fn write_new_pyoxidizer_config_file(
     project_dir: &Path,
     name: &str,
     code: Option<&str>,
     pip_install: &[&str],
+    lib: bool,
+    template: ProjectTemplate,
 ) -> Result<()> {
     let path = project_dir.to_path_buf().join("pyoxidizer.bzl");
 
     let mut data = TemplateData::new();
     populate_template_data(&mut data);
     data.program_name = Some(name.to_string());
+    data.lib = lib;
+
+    if template.package_template().is_some() {
+        data.python_package = Some(python_package_name(name));
+    }
+    data.service = template == ProjectTemplate::Service;
+    data.gui = template == ProjectTemplate::Gui;
 
     if let Some(code) = code {
         // Replace " with \" to work around
@@ -182,17 +320,44 @@ pub fn write_new_pyoxidizer_config_file(
     Ok(())
 }
 
+/// Write the example Python package of a project template.
+///
+/// The package is written to `app/<package>` in the project directory.
+/// Nothing is written for templates without a package.
+pub fn write_template_package(
+    project_dir: &Path,
+    name: &str,
+    template: ProjectTemplate,
+) -> Result<()> {
+    let init_template = match template.package_template() {
+        Some(t) => t,
+        None => return Ok(()),
+    };
+
+    let package = python_package_name(name);
+    let package_dir = project_dir.join("app").join(&package);
+    println!("creating {}", package_dir.display());
+    create_dir_all(&package_dir)?;
+
+    let mut data = TemplateData::new();
+    data.program_name = Some(name.to_string());
+    data.python_package = Some(package);
+
+    for (filename, t) in &[
+        ("__init__.py", init_template),
+        ("__main__.py", "app-main.py"),
+    ] {
+        let path = package_dir.join(filename);
+        println!("writing {}", path.display());
+        std::fs::write(&path, HANDLEBARS.render(t, &data)?)?;
+    }
+
+    Ok(())
+}
+
 /// Write a new build.rs file supporting PyOxidizer.
 pub fn write_pyembed_build_rs(project_dir: &Path) -> Result<()> {
-    let mut data: BTreeMap<String, String> = BTreeMap::new();
-    data.insert(
-        "pyoxidizer_exe".to_string(),
-        canonicalize_path(&std::env::current_exe()?)?
-            .display()
-            .to_string(),
-    );
-
-    let t = HANDLEBARS.render("pyembed-build.rs", &data)?;
+    let t = render_pyembed_build_rs()?;
 
     let path = project_dir.to_path_buf().join("build.rs");
 
@@ -219,10 +384,7 @@ pub fn write_pyembed_crate_files(dest_dir: &Path) -> Result<()> {
         fh.write_all(&data)?;
     }
 
-    let mut data = TemplateData::new();
-    populate_template_data(&mut data);
-
-    let t = HANDLEBARS.render("pyembed-cargo.toml", &data)?;
+    let t = render_pyembed_cargo_toml()?;
 
     let path = dest_dir.to_path_buf().join("Cargo.toml");
     println!("writing {}", path.to_str().unwrap());
@@ -286,6 +448,18 @@ pub fn update_new_cargo_toml(path: &Path) -> Result<()> {
     fh.write_all(b"[features]\n")?;
     fh.write_all(b"default = []\n")?;
     fh.write_all(b"jemalloc = [\"jemallocator-global\", \"pyembed/jemalloc\"]\n")?;
+    fh.write_all(b"mimalloc = [\"pyembed/mimalloc\"]\n")?;
+
+    Ok(())
+}
+
+/// Make a new Cargo.toml build a shared library loadable from C.
+pub fn update_new_cargo_toml_cdylib(path: &Path) -> Result<()> {
+    let mut fh = std::fs::OpenOptions::new().append(true).open(path)?;
+
+    fh.write_all(b"\n")?;
+    fh.write_all(b"[lib]\n")?;
+    fh.write_all(b"crate-type = [\"cdylib\"]\n")?;
 
     Ok(())
 }
@@ -294,14 +468,26 @@ pub fn update_new_cargo_toml(path: &Path) -> Result<()> {
 ///
 /// The created binary application will have the name of the final
 /// path component.
+///
+/// If `lib` is set, the project is instead a `cdylib` exporting a C ABI
+/// for embedding Python in a host application. Otherwise `template`
+/// selects the kind of application.
 pub fn initialize_project(
     project_path: &Path,
     code: Option<&str>,
     pip_install: &[&str],
+    lib: bool,
+    template: ProjectTemplate,
 ) -> Result<()> {
+    if lib && template != ProjectTemplate::Repl {
+        return Err(anyhow!(
+            "libraries can't be created from application templates"
+        ));
+    }
+
     let status = std::process::Command::new("cargo")
         .arg("init")
-        .arg("--bin")
+        .arg(if lib { "--lib" } else { "--bin" })
         .arg(project_path)
         .status()?;
 
@@ -313,8 +499,637 @@ pub fn initialize_project(
     let name = path.iter().last().unwrap().to_str().unwrap();
     add_pyoxidizer(&path, true)?;
     update_new_cargo_toml(&path.join("Cargo.toml"))?;
-    write_new_main_rs(&path.join("src").join("main.rs"))?;
-    write_new_pyoxidizer_config_file(&path, &name, code, pip_install)?;
+
+    if lib {
+        update_new_cargo_toml_cdylib(&path.join("Cargo.toml"))?;
+        write_new_lib_rs(&path.join("src").join("lib.rs"), &name.replace('-', "_"))?;
+    } else {
+        write_new_main_rs(&path.join("src").join("main.rs"), template)?;
+    }
+
+    write_new_pyoxidizer_config_file(&path, &name, code, pip_install, lib, template)?;
+    write_template_package(&path, &name, template)?;
 
     Ok(())
 }
+
+/// Marker preceding the name of a generated region.
+const GENERATED_BEGIN: &str = "// BEGIN PYOXIDIZER GENERATED CODE: ";
+
+/// Marker preceding the name of the region a generated region ends.
+const GENERATED_END: &str = "// END PYOXIDIZER GENERATED CODE: ";
+
+/// A line of a file containing generated regions.
+enum RegionLine<'a> {
+    Begin(&'a str),
+    End(&'a str),
+    Code,
+}
+
+fn parse_region_line(line: &str) -> RegionLine {
+    let trimmed = line.trim();
+
+    if trimmed.starts_with(GENERATED_BEGIN) {
+        RegionLine::Begin(trimmed[GENERATED_BEGIN.len()..].trim())
+    } else if trimmed.starts_with(GENERATED_END) {
+        RegionLine::End(trimmed[GENERATED_END.len()..].trim())
+    } else {
+        RegionLine::Code
+    }
+}
+
+/// Obtain the lines of generated regions in a file, keyed by region name.
+///
+/// Marker lines are not part of the returned lines.
+fn generated_regions(data: &str) -> Result<BTreeMap<String, Vec<String>>> {
+    let mut res = BTreeMap::new();
+    let mut current: Option<(String, Vec<String>)> = None;
+
+    for (i, line) in data.lines().enumerate() {
+        match (parse_region_line(line), current.take()) {
+            (RegionLine::Begin(name), None) => {
+                if res.contains_key(name) {
+                    return Err(anyhow!(
+                        "line {}: generated region {} appears more than once",
+                        i + 1,
+                        name
+                    ));
+                }
+
+                current = Some((name.to_string(), Vec::new()));
+            }
+            (RegionLine::Begin(name), Some((outer, _))) => {
+                return Err(anyhow!(
+                    "line {}: generated region {} begins inside region {}",
+                    i + 1,
+                    name,
+                    outer
+                ));
+            }
+            (RegionLine::End(name), Some((outer, lines))) => {
+                if name != outer {
+                    return Err(anyhow!(
+                        "line {}: end of generated region {} inside region {}",
+                        i + 1,
+                        name,
+                        outer
+                    ));
+                }
+
+                res.insert(outer, lines);
+            }
+            (RegionLine::End(name), None) => {
+                return Err(anyhow!(
+                    "line {}: end of generated region {} without beginning",
+                    i + 1,
+                    name
+                ));
+            }
+            (RegionLine::Code, Some((name, mut lines))) => {
+                lines.push(line.to_string());
+                current = Some((name, lines));
+            }
+            (RegionLine::Code, None) => {}
+        }
+    }
+
+    if let Some((name, _)) = current {
+        return Err(anyhow!("generated region {} is not terminated", name));
+    }
+
+    Ok(res)
+}
+
+/// Result of replacing generated regions in a file.
+#[derive(Debug, PartialEq)]
+pub struct RegionsUpdate {
+    /// New content of the file.
+    pub content: String,
+    /// Regions whose content changed.
+    pub changed: Vec<String>,
+    /// Generated regions the file doesn't have.
+    pub missing: Vec<String>,
+}
+
+/// Replace generated regions in `existing` with those from `generated`.
+///
+/// Code outside generated regions is preserved. Regions of `existing`
+/// unknown to `generated` are left alone.
+pub fn replace_generated_regions(existing: &str, generated: &str) -> Result<RegionsUpdate> {
+    // Validates the markers of the existing file.
+    let existing_regions = generated_regions(existing)?;
+    let generated_regions = generated_regions(generated)?;
+
+    let mut lines: Vec<String> = Vec::new();
+    let mut changed = Vec::new();
+    let mut skipping = false;
+
+    for line in existing.lines() {
+        match parse_region_line(line) {
+            RegionLine::Begin(name) => {
+                lines.push(line.to_string());
+
+                if let Some(new_lines) = generated_regions.get(name) {
+                    if existing_regions.get(name) != Some(new_lines) {
+                        changed.push(name.to_string());
+                    }
+
+                    lines.extend(new_lines.iter().cloned());
+                    skipping = true;
+                }
+            }
+            RegionLine::End(_) => {
+                lines.push(line.to_string());
+                skipping = false;
+            }
+            RegionLine::Code => {
+                if !skipping {
+                    lines.push(line.to_string());
+                }
+            }
+        }
+    }
+
+    let mut content = lines.join("\n");
+    if existing.ends_with('\n') {
+        content.push('\n');
+    }
+
+    let missing = generated_regions
+        .keys()
+        .filter(|name| !existing_regions.contains_key(*name))
+        .cloned()
+        .collect();
+
+    Ok(RegionsUpdate {
+        content,
+        changed,
+        missing,
+    })
+}
+
+/// Compute a line diff between two texts.
+///
+/// Returns removed lines prefixed with `-` and added lines prefixed with `+`.
+fn diff_lines(old: &str, new: &str) -> Vec<String> {
+    let old: Vec<&str> = old.lines().collect();
+    let new: Vec<&str> = new.lines().collect();
+
+    // Length of the longest common subsequence of old[i..] and new[j..].
+    let mut lcs = vec![vec![0usize; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            lcs[i][j] = if old[i] == new[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+
+    let mut res = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            i += 1;
+            j += 1;
+        } else if j < new.len() && (i == old.len() || lcs[i][j + 1] >= lcs[i + 1][j]) {
+            res.push(format!("+{}", new[j]));
+            j += 1;
+        } else {
+            res.push(format!("-{}", old[i]));
+            i += 1;
+        }
+    }
+
+    res
+}
+
+fn print_update(path: &Path, old: &str, new: &str) {
+    for line in diff_lines(old, new) {
+        println!("    {}", line);
+    }
+    println!("updated {}", path.display());
+}
+
+/// Replace a file owned by PyOxidizer if its content differs.
+fn upgrade_generated_file(path: &Path, generated: &str) -> Result<bool> {
+    let existing = if path.exists() {
+        std::fs::read_to_string(path)?
+    } else {
+        String::new()
+    };
+
+    if existing == generated {
+        return Ok(false);
+    }
+
+    std::fs::write(path, generated)?;
+    print_update(path, &existing, generated);
+
+    Ok(true)
+}
+
+/// Replace the generated regions of a file shared with the user.
+///
+/// Files without generated regions, e.g. ones created before regions were
+/// introduced, are not modified.
+fn upgrade_regions_file(path: &Path, generated: &str) -> Result<bool> {
+    if !path.exists() {
+        println!("{} does not exist; not upgrading", path.display());
+        return Ok(false);
+    }
+
+    let existing = std::fs::read_to_string(path)?;
+    let update = replace_generated_regions(&existing, generated)
+        .map_err(|e| anyhow!("{}: {}", path.display(), e))?;
+
+    if update.missing.len() == generated_regions(generated)?.len() {
+        println!(
+            "{} has no PyOxidizer generated regions; not upgrading (compare it against a new project created with `pyoxidizer init`)",
+            path.display()
+        );
+        return Ok(false);
+    }
+
+    for name in &update.missing {
+        println!(
+            "{} lacks generated region {}; copy it from a new project",
+            path.display(),
+            name
+        );
+    }
+
+    if update.changed.is_empty() {
+        return Ok(false);
+    }
+
+    std::fs::write(path, &update.content)?;
+    println!(
+        "regions of {}: {}",
+        path.display(),
+        update.changed.join(", ")
+    );
+    print_update(path, &existing, &update.content);
+
+    Ok(true)
+}
+
+/// Upgrade the PyOxidizer files of a project to this version of PyOxidizer.
+///
+/// The pyembed crate's sources and Cargo.toml are replaced. Only the
+/// generated regions of `pyembed/build.rs` and `src/main.rs` (or
+/// `src/lib.rs` for library projects) are replaced, preserving code
+/// outside them. Returns the paths of updated files.
+pub fn upgrade_project(project_dir: &Path) -> Result<Vec<PathBuf>> {
+    let cargo_toml = project_dir.join("Cargo.toml");
+    let pyembed_dir = project_dir.join("pyembed");
+
+    if !cargo_toml.exists() || !pyembed_dir.join("Cargo.toml").exists() {
+        return Err(anyhow!(
+            "{} is not a PyOxidizer project",
+            project_dir.display()
+        ));
+    }
+
+    let manifest = cargo_toml::Manifest::from_slice(&std::fs::read(&cargo_toml)?)?;
+
+    let mut res = Vec::new();
+
+    for (rs, data) in PYEMBED_RS_FILES.iter() {
+        let path = pyembed_dir.join("src").join(rs);
+
+        if upgrade_generated_file(&path, &String::from_utf8_lossy(data))? {
+            res.push(path);
+        }
+    }
+
+    let path = pyembed_dir.join("Cargo.toml");
+    if upgrade_generated_file(&path, &render_pyembed_cargo_toml()?)? {
+        res.push(path);
+    }
+
+    let path = pyembed_dir.join("build.rs");
+    if upgrade_regions_file(&path, &render_pyembed_build_rs()?)? {
+        res.push(path);
+    }
+
+    let (path, generated) = if library::is_cdylib(&manifest) {
+        (
+            project_dir.join("src").join("lib.rs"),
+            render_lib_rs(&library::library_name(&manifest)?)?,
+        )
+    } else {
+        // Templates only differ outside of generated regions.
+        (
+            project_dir.join("src").join("main.rs"),
+            render_main_rs(ProjectTemplate::Repl)?,
+        )
+    };
+
+    if upgrade_regions_file(&path, &generated)? {
+        res.push(path);
+    }
+
+    if res.is_empty() {
+        println!("{} is up to date", project_dir.display());
+    }
+
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_replace_generated_regions() -> Result<()> {
+        let generated = "// BEGIN PYOXIDIZER GENERATED CODE: a\nnew a\n// END PYOXIDIZER GENERATED CODE: a\n\
+                         // BEGIN PYOXIDIZER GENERATED CODE: b\nnew b\n// END PYOXIDIZER GENERATED CODE: b\n";
+        let existing = "user 1\n// BEGIN PYOXIDIZER GENERATED CODE: a\nold a\nmore a\n\
+                        // END PYOXIDIZER GENERATED CODE: a\nuser 2\n\
+                        // BEGIN PYOXIDIZER GENERATED CODE: c\nc\n// END PYOXIDIZER GENERATED CODE: c\n";
+
+        assert_eq!(
+            replace_generated_regions(existing, generated)?,
+            RegionsUpdate {
+                content: "user 1\n// BEGIN PYOXIDIZER GENERATED CODE: a\nnew a\n\
+                          // END PYOXIDIZER GENERATED CODE: a\nuser 2\n\
+                          // BEGIN PYOXIDIZER GENERATED CODE: c\nc\n// END PYOXIDIZER GENERATED CODE: c\n"
+                    .to_string(),
+                changed: vec!["a".to_string()],
+                missing: vec!["b".to_string()],
+            }
+        );
+
+        assert!(replace_generated_regions(
+            "// BEGIN PYOXIDIZER GENERATED CODE: a\nfoo\n",
+            generated
+        )
+        .is_err());
+        assert!(replace_generated_regions(
+            "// BEGIN PYOXIDIZER GENERATED CODE: a\n// END PYOXIDIZER GENERATED CODE: b\n",
+            generated
+        )
+        .is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_diff_lines() {
+        assert_eq!(
+            diff_lines("a\nb\nc\n", "a\nx\nc\nd\n"),
+            vec!["+x", "-b", "+d"]
+        );
+    }
+
+    #[test]
+    fn test_upgrade_project() -> Result<()> {
+        let temp_dir = tempdir::TempDir::new("pyoxidizer-test")?;
+        let project_dir = temp_dir.path();
+
+        // What `pyoxidizer init` writes, minus `cargo init`.
+        fs::create_dir_all(project_dir.join("src"))?;
+        fs::write(
+            project_dir.join("Cargo.toml"),
+            "[package]\nname = \"myapp\"\nversion = \"0.1.0\"\n",
+        )?;
+        write_pyembed_crate_files(&project_dir.join("pyembed"))?;
+        let main_rs = project_dir.join("src").join("main.rs");
+        write_new_main_rs(&main_rs, ProjectTemplate::Repl)?;
+
+        assert!(upgrade_project(project_dir)?.is_empty());
+
+        // Users customize code outside the generated regions. Generated
+        // regions get out of date.
+        let build_rs = project_dir.join("pyembed").join("build.rs");
+        let original_build_rs = fs::read_to_string(&build_rs)?;
+        fs::write(
+            &build_rs,
+            original_build_rs
+                .replace(
+                    "// Your build logic goes here.",
+                    "println!(\"cargo:rustc-link-lib=custom\");",
+                )
+                .replace("PYOXIDIZER_REUSE_ARTIFACTS", "OLD_REUSE_ARTIFACTS"),
+        )?;
+
+        let original_main_rs = fs::read_to_string(&main_rs)?;
+        fs::write(
+            &main_rs,
+            format!(
+                "mod cli;\n\n{}",
+                original_main_rs
+                    .replace(
+                        "let code = run_python();",
+                        "cli::parse();\n    let code = run_python();"
+                    )
+                    .replace("pyembed::run_pre_init_hooks();\n", "")
+            ),
+        )?;
+
+        let data_rs = project_dir.join("pyembed").join("src").join("data.rs");
+        fs::write(&data_rs, "stale")?;
+
+        let updated = upgrade_project(project_dir)?;
+        assert_eq!(
+            updated,
+            vec![data_rs.clone(), build_rs.clone(), main_rs.clone()]
+        );
+
+        let build_rs_data = fs::read_to_string(&build_rs)?;
+        assert!(build_rs_data.contains("println!(\"cargo:rustc-link-lib=custom\");"));
+        assert!(!build_rs_data.contains("OLD_REUSE_ARTIFACTS"));
+        assert_eq!(
+            generated_regions(&build_rs_data)?,
+            generated_regions(&original_build_rs)?
+        );
+
+        let main_rs_data = fs::read_to_string(&main_rs)?;
+        assert!(main_rs_data.starts_with("mod cli;\n\n"));
+        assert!(main_rs_data.contains("cli::parse();\n    let code = run_python();"));
+        assert!(main_rs_data.contains("pyembed::run_pre_init_hooks();\n"));
+
+        assert_eq!(
+            fs::read(&data_rs)?,
+            PYEMBED_RS_FILES.get("data.rs").unwrap().to_vec()
+        );
+
+        // Files without regions are left alone.
+        fs::write(&main_rs, "fn main() {}\n")?;
+        assert!(upgrade_project(project_dir)?.is_empty());
+        assert_eq!(fs::read_to_string(&main_rs)?, "fn main() {}\n");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_project_templates() -> Result<()> {
+        for name in ProjectTemplate::NAMES {
+            ProjectTemplate::from_name(name)?;
+        }
+        assert!(ProjectTemplate::from_name("web").is_err());
+
+        assert!(render_main_rs(ProjectTemplate::Gui)?
+            .contains("#![windows_subsystem = \"windows\"]\n\n// Code between"));
+        assert!(render_main_rs(ProjectTemplate::Cli)?.starts_with("// Code between"));
+
+        // Upgrading doesn't depend on the template.
+        assert_eq!(
+            generated_regions(&render_main_rs(ProjectTemplate::Gui)?)?,
+            generated_regions(&render_main_rs(ProjectTemplate::Repl)?)?
+        );
+
+        let temp_dir = tempdir::TempDir::new("pyoxidizer-test")?;
+        let project_dir = temp_dir.path();
+
+        write_new_pyoxidizer_config_file(
+            project_dir,
+            "my-app",
+            None,
+            &[],
+            false,
+            ProjectTemplate::Service,
+        )?;
+        write_template_package(project_dir, "my-app", ProjectTemplate::Service)?;
+
+        let config = fs::read_to_string(project_dir.join("pyoxidizer.bzl"))?;
+        assert!(config.contains("packages=[\"my_app\"]"));
+        assert!(config.contains("python_run_mode_module(\"my_app.__main__\")"));
+        assert!(config.contains("\n    unbuffered_stdio=True,\n"));
+        assert!(!config.contains("\n    repl_history_file="));
+        assert_eq!(config.matches("repl_history_file=").count(), 1);
+        assert!(!config.contains("\n    detached_stdio="));
+
+        let package_dir = project_dir.join("app").join("my_app");
+        assert!(fs::read_to_string(package_dir.join("__init__.py"))?.contains("SIGTERM"));
+        assert_eq!(
+            fs::read_to_string(package_dir.join("__main__.py"))?,
+            "import sys\n\nfrom my_app import main\n\nsys.exit(main())\n"
+        );
+
+        // The REPL has no package.
+        write_new_pyoxidizer_config_file(
+            project_dir,
+            "my-app",
+            None,
+            &[],
+            false,
+            ProjectTemplate::Repl,
+        )?;
+        let config = fs::read_to_string(project_dir.join("pyoxidizer.bzl"))?;
+        assert!(
+            config.contains("\n    repl_history_file=\"~/.my-app_history\",\n#     bytes_warning")
+        );
+        assert_eq!(config.matches("repl_history_file=").count(), 1);
+        assert!(!config.contains("packages=[\"my_app\"]"));
+        assert!(!config.contains("python_run_mode_module(\"my"));
+
+        Ok(())
+    }
+
+    /// Drives `repl.LineEditor.readline()` with scripted keys.
+    const REPL_DRIVER: &str = r#"
+import io
+import os
+import sys
+
+sys.path.insert(0, sys.argv[1])
+import repl
+
+
+class TtyStdin(object):
+    def isatty(self):
+        return True
+
+
+class NoRawMode(object):
+    def __enter__(self):
+        return self
+
+    def __exit__(self, *args):
+        return False
+
+
+repl._RawMode = NoRawMode
+
+
+def readline(editor, keys):
+    repl._read_keys = lambda: iter(keys)
+    stdin, stdout = sys.stdin, sys.stdout
+    sys.stdin, sys.stdout = TtyStdin(), io.StringIO()
+    try:
+        return editor.readline(">>> ")
+    except EOFError:
+        return "<EOF>"
+    finally:
+        sys.stdin, sys.stdout = stdin, stdout
+
+
+history_path = os.path.join(sys.argv[1], "history")
+editor = repl.LineEditor(history_path)
+
+# In-line editing.
+keys = list("prnt(1)") + ["home", "right", "right", "i", "end", "\x7f"]
+keys += list("2)") + ["left", "delete", ")", "\r"]
+print(readline(editor, keys))
+print(readline(editor, list("= 1") + ["\x01", "x", " ", "\x05", "\r"]))
+# Empty lines aren't remembered.
+print(repr(readline(editor, ["\r"])))
+
+# History.
+print(readline(editor, ["up", "\r"]))
+print(readline(editor, ["up", "up", "up", "\r"]))
+print(readline(editor, list("ab") + ["up", "up", "down", "down", "\r"]))
+print(readline(editor, ["down", "\r"]) or "<empty>")
+
+# Ctrl-D ends input only on an empty line.
+print(readline(editor, ["c", "\x04", "\r"]))
+print(readline(editor, ["\x04"]))
+
+print(editor.history)
+print(repl.LineEditor(history_path).history)
+"#;
+
+    #[test]
+    fn test_repl_line_editor() -> Result<()> {
+        let dist = crate::testutil::get_default_distribution()?;
+
+        let temp_dir = tempdir::TempDir::new("pyoxidizer-test")?;
+        fs::write(temp_dir.path().join("repl.py"), PYEMBED_RS_FILES["repl.py"])?;
+        fs::write(temp_dir.path().join("driver.py"), REPL_DRIVER)?;
+
+        let output = std::process::Command::new(&dist.python_exe)
+            .arg(temp_dir.path().join("driver.py"))
+            .arg(temp_dir.path())
+            .output()?;
+        assert!(
+            output.status.success(),
+            "{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+
+        assert_eq!(
+            String::from_utf8_lossy(&output.stdout)
+                .lines()
+                .collect::<Vec<_>>(),
+            vec![
+                "print(12)",
+                "x = 1",
+                "''",
+                "x = 1",
+                "print(12)",
+                "ab",
+                "<empty>",
+                "c",
+                "<EOF>",
+                "['print(12)', 'x = 1', 'print(12)', 'ab', 'c']",
+                "['print(12)', 'x = 1', 'print(12)', 'ab', 'c']",
+            ]
+        );
+
+        Ok(())
+    }
+}
@@ -4,226 +4,840 @@
 
 //! Manage PyOxidizer projects.
 
-use anyhow::{anyhow, Result};
-use slog::warn;
+use anyhow::{anyhow, Context, Result};
+use slog::{info, warn};
+use std::collections::BTreeSet;
 use std::fs::create_dir_all;
-use std::io::{Cursor, Read};
 use std::path::{Path, PathBuf};
 use std::process;
 
-use super::environment::{canonicalize_path, MINIMUM_RUST_VERSION};
-use crate::app_packaging::config::{eval_starlark_config_file, find_pyoxidizer_config_file_env};
-use crate::app_packaging::repackage::{package_project, process_config, run_from_build};
-use crate::app_packaging::state::BuildContext;
-use crate::project_layout::{find_pyoxidizer_files, initialize_project};
-use crate::py_packaging::config::RawAllocator;
-use crate::py_packaging::distribution::{analyze_python_distribution_tar_zst, python_exe_path};
+use super::cache::{cached_distributions, distribution_cache_dir, remove_cached_distribution};
+use super::cancel::{self, CancellationToken};
+use super::environment::{canonicalize_path, frozen, resolve_logical_path, MINIMUM_RUST_VERSION};
+use super::exit_status::{categorize, error_category, AppExitError, Categorize, ErrorCategory};
+use super::fsutils::{locked_build_hint, remove_dir_all};
+use crate::app_packaging::artifacts_owner::{
+    artifacts_version, claim_artifacts_path, is_ownership_entry, lock_artifacts_path, version_dirs,
+    versioned_artifacts_path,
+};
+use crate::app_packaging::build_environment::BuildEnvironment;
+use crate::app_packaging::cargo_diagnostics::{
+    process_cargo_output, relay_cargo_stderr, DiagnosticsSummary,
+};
+use crate::app_packaging::config::{
+    eval_starlark_config_file, eval_starlark_config_file_with_cache,
+    find_pyoxidizer_config_file_env, rustflags_env, Config,
+};
+use crate::app_packaging::config_diff::{diff_config_values, diff_configs};
+use crate::app_packaging::disk_space::{
+    artifacts_requirements, cargo_requirements, check_space, margin_from_env, SpaceRequirement,
+    SystemSpaceProbe,
+};
+use crate::app_packaging::environment::{EvalOptions, PYTHON_DISTRIBUTIONS_DIR};
+use crate::app_packaging::lockfile::{input_digests_reusing, Lockfile};
+use crate::app_packaging::manifest::{verify_build_manifest, VerifyResult};
+use crate::app_packaging::output_layout::OutputDirectories;
+use crate::app_packaging::plan::{
+    app_files, artifact_files, BuildPlan, CargoInvocation, PlanMode, ResourcesPlan,
+};
+use crate::app_packaging::prepared::{
+    materialize_prepared, write_prepared, PreparedManifest, PREPARED_ARTIFACTS_DIR,
+};
+use crate::app_packaging::python_sys::{
+    python_sys_executable_for_build, PYTHON_SYS_EXECUTABLE_ENV,
+};
+use crate::app_packaging::repackage::{
+    artifacts_cargo_metadata, artifacts_packaging_state, package_project, process_config,
+    resolve_config, run_from_build, ArtifactPaths, HOST,
+};
+use crate::app_packaging::run_env::RunOptions;
+use crate::app_packaging::rustc_bootstrap::rustc_bootstrap_for_build;
+use crate::app_packaging::staleness::{
+    InputsManifest, StaleReason, StalenessReport, INPUTS_MANIFEST_FILENAME,
+};
+use crate::app_packaging::state::{
+    BuildContext, BuildOptions, ExtraCargoArgs, PackagingState, Verbosity,
+};
+use crate::build_events::{self, print_human, BuildEvent};
+use crate::build_stats;
+use crate::project_layout::{
+    find_pyoxidizer_files, initialize_project, python_package_name, ProjectTemplate,
+};
+use crate::py_packaging::archive::read_tar_zst;
+use crate::py_packaging::capabilities::DistributionCapabilities;
+use crate::py_packaging::distribution::{
+    analyze_python_distribution_archive, analyze_python_distribution_metadata,
+    default_distribution, distribution_flavor, distribution_python_version, python_exe_path,
+    python_exe_runs, DistributionInfo, DistributionSizes,
+};
+use crate::py_packaging::libpython::libpython_cargo_metadata;
+use crate::py_packaging::members::{extract_members, read_members, MemberSelection};
+use crate::py_packaging::requirement::{
+    format_constraints, normalize_name, requirements_from_pip_args,
+};
+use crate::starlark::eval::EvaluationCache;
+
+/// Extract the host triple from the output of `rustc -vV`.
+pub fn parse_rustc_host(output: &str) -> Result<String> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let mut parts = line.splitn(2, ':');
+            match (parts.next(), parts.next()) {
+                (Some("host"), Some(value)) => Some(value.trim()),
+                _ => None,
+            }
+        })
+        .find(|value| !value.is_empty())
+        .map(|value| value.to_string())
+        .ok_or_else(|| anyhow!("no host line in rustc -vV output"))
+}
 
-/// Attempt to resolve the default Rust target for a build.
-pub fn default_target() -> Result<String> {
-    // TODO derive these more intelligently.
-    if cfg!(target_os = "linux") {
-        Ok("x86_64-unknown-linux-gnu".to_string())
-    } else if cfg!(target_os = "windows") {
-        Ok("x86_64-pc-windows-msvc".to_string())
-    } else if cfg!(target_os = "macos") {
-        Ok("x86_64-apple-darwin".to_string())
-    } else {
-        Err(anyhow!("unable to resolve target"))
-    }
+/// Resolve the host triple of the Rust toolchain.
+///
+/// Asks `rustc -vV` (`$RUSTC` if set) so the triple matches what Cargo
+/// builds for. Falls back to the triple pyoxidizer was built for.
+pub fn host_triple() -> String {
+    let rustc = std::env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string());
+
+    process::Command::new(rustc)
+        .arg("-vV")
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| parse_rustc_host(&String::from_utf8_lossy(&output.stdout)).ok())
+        .unwrap_or_else(|| HOST.to_string())
 }
 
-fn dependency_current(
-    logger: &slog::Logger,
-    path: &Path,
-    built_time: std::time::SystemTime,
-) -> bool {
-    match path.metadata() {
-        Ok(md) => match md.modified() {
-            Ok(t) => {
-                if t > built_time {
-                    warn!(
-                        logger,
-                        "building artifacts because {} changed",
-                        path.display()
-                    );
-                    false
-                } else {
-                    true
-                }
-            }
-            Err(_) => {
-                warn!(logger, "error resolving mtime of {}", path.display());
-                false
-            }
-        },
-        Err(_) => {
-            warn!(logger, "error resolving metadata of {}", path.display());
-            false
-        }
-    }
+/// Attempt to resolve the default Rust target for a build.
+///
+/// This is the host triple. Whether a default Python distribution exists for
+/// it is checked when the config asks for one.
+pub fn default_target() -> Result<String> {
+    Ok(host_triple())
 }
 
 /// Determines whether PyOxidizer artifacts are current.
-fn artifacts_current(logger: &slog::Logger, config_path: &Path, artifacts_path: &Path) -> bool {
+///
+/// The content of every input recorded when the artifacts were generated
+/// is compared, and every changed input is reported, not just the first.
+/// Files referenced by the config as evaluated now are only hashed if
+/// nothing else made the artifacts stale, and those that are also recorded
+/// inputs aren't hashed again.
+fn artifacts_staleness(
+    config: &Config,
+    artifacts_path: &Path,
+    rustflags: &[String],
+) -> StalenessReport {
+    let mut report = StalenessReport::new(artifacts_path);
     let metadata_path = artifacts_path.join("cargo_metadata.txt");
 
     if !metadata_path.exists() {
-        warn!(logger, "no existing PyOxidizer artifacts found");
-        return false;
-    }
-
-    // We assume the mtime of the metadata file is the built time. If we
-    // encounter any modified times newer than that file, we're not up to date.
-    let built_time = match metadata_path.metadata() {
-        Ok(md) => match md.modified() {
-            Ok(t) => t,
-            Err(_) => {
-                warn!(
-                    logger,
-                    "error determining mtime of {}",
-                    metadata_path.display()
-                );
-                return false;
-            }
-        },
-        Err(_) => {
-            warn!(
-                logger,
-                "error resolving metadata of {}",
-                metadata_path.display()
-            );
-            return false;
+        report.add(StaleReason::NoArtifacts);
+        return report;
+    }
+
+    let manifest_path = artifacts_path.join(INPUTS_MANIFEST_FILENAME);
+    let known_digests = match InputsManifest::from_path(&manifest_path) {
+        Ok(manifest) => manifest.compare(&mut report),
+        Err(e) => {
+            report.add(StaleReason::Unreadable {
+                path: manifest_path,
+                error: format!("{:#}", e),
+            });
+            return report;
         }
     };
 
-    let metadata_data = match std::fs::read_to_string(&metadata_path) {
-        Ok(data) => data,
-        Err(_) => {
-            warn!(logger, "error reading {}", metadata_path.display());
-            return false;
+    let state_path = artifacts_path.join("packaging_state.cbor");
+    let state: PackagingState = match std::fs::File::open(&state_path) {
+        Ok(fh) => match serde_cbor::from_reader(std::io::BufReader::new(fh)) {
+            Ok(state) => state,
+            Err(e) => {
+                report.add(StaleReason::Unreadable {
+                    path: state_path,
+                    error: e.to_string(),
+                });
+                return report;
+            }
+        },
+        Err(e) => {
+            report.add(StaleReason::Unreadable {
+                path: state_path,
+                error: e.to_string(),
+            });
+            return report;
         }
     };
 
-    for line in metadata_data.split('\n') {
-        if line.starts_with("cargo:rerun-if-changed=") {
-            let path = PathBuf::from(&line[23..line.len()]);
+    if state.rustflags != rustflags {
+        report.add(StaleReason::RustflagsChanged {
+            old: state.rustflags.clone(),
+            new: rustflags.to_vec(),
+        });
+    }
+
+    if state.config_digest != config.digest() {
+        report.add(StaleReason::ConfigChanged);
+    }
+
+    if report.is_stale() {
+        return report;
+    }
+
+    // The recorded inputs are those of the config as evaluated when
+    // generating artifacts. Globs may now match a different set of files.
+    // So compare the content of everything the config references now.
+    match input_digests_reusing(config, &known_digests) {
+        Ok(digests) => {
+            for (path, digest) in &digests {
+                if state.input_digests.get(path) != Some(digest) {
+                    report.add(StaleReason::ContentChanged(path.clone()));
+                }
+            }
 
-            if !dependency_current(logger, &path, built_time) {
-                return false;
+            for path in state.input_digests.keys() {
+                if !digests.contains_key(path) {
+                    report.add(StaleReason::ContentChanged(path.clone()));
+                }
             }
         }
+        Err(e) => {
+            report.add(StaleReason::Unreadable {
+                path: config.config_path.clone(),
+                error: format!("hashing files referenced by config: {}", e),
+            });
+        }
     }
 
-    let current_exe = std::env::current_exe().expect("unable to determine current exe");
-    if !dependency_current(logger, &current_exe, built_time) {
-        return false;
-    }
+    // build-environment.json is deliberately not considered: it is rewritten
+    // on every build and describes the build rather than its inputs.
+
+    report
+}
+
+/// Determines whether PyOxidizer artifacts need to be generated.
+///
+/// The decision is logged either way so users can tell whether forcing
+/// regeneration is needed.
+fn artifacts_need_building(
+    logger: &slog::Logger,
+    context: &BuildContext,
+    artifacts_path: &Path,
+) -> StalenessReport {
+    let _phase = build_stats::phase("check artifacts");
+
+    let report = if context.force_artifacts {
+        let mut report = StalenessReport::new(artifacts_path);
+        report.add(StaleReason::Forced);
+        report
+    } else {
+        artifacts_staleness(&context.config, artifacts_path, &context.rustflags)
+    };
+
+    report.log(logger);
+    build_stats::record_staleness(&report);
+
+    report
+}
+
+/// Remove artifacts written by an interrupted `process_config()`.
+///
+/// Entries not in `existing` are removed, except for the Python distribution
+/// archive, its download metadata and its extracted copy, which are only
+/// ever written atomically.
+/// The files marking artifacts as complete are also removed so the next
+/// build regenerates everything.
+fn remove_partial_artifacts(
+    artifacts_path: &Path,
+    existing: &BTreeSet<PathBuf>,
+    distribution_path: &Path,
+) -> Result<()> {
+    for entry in std::fs::read_dir(artifacts_path)? {
+        let path = entry?.path();
+        let name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+
+        // Other versions' artifacts and the record of who owns the
+        // directory are never partial artifacts.
+        if is_ownership_entry(&name) {
+            continue;
+        }
+
+        let is_marker = name == "cargo_metadata.txt"
+            || name == "packaging_state.cbor"
+            || name == INPUTS_MANIFEST_FILENAME;
+        let is_distribution = path.file_name() == distribution_path.file_name()
+            || name.ends_with(".tar.zst")
+            || name.ends_with(".tar.zst.json");
 
-    if !dependency_current(logger, config_path, built_time) {
-        return false;
+        if is_marker || !(existing.contains(&path) || is_distribution) {
+            if path.is_dir() {
+                remove_dir_all(&path)?;
+            } else {
+                std::fs::remove_file(&path)
+                    .with_context(|| format!("removing {}", path.display()))?;
+            }
+        }
     }
 
-    // TODO detect config file change.
-    true
+    Ok(())
 }
 
 /// Build PyOxidizer artifacts for a project.
 fn build_pyoxidizer_artifacts(logger: &slog::Logger, context: &mut BuildContext) -> Result<()> {
-    let pyoxidizer_artifacts_path = &context.pyoxidizer_artifacts_path;
-
-    create_dir_all(&pyoxidizer_artifacts_path)?;
+    let root_path = context.pyoxidizer_artifacts_root_path.clone();
+
+    // Held while artifacts are written so concurrent builds don't mix them.
+    let _lock = lock_artifacts_path(logger, &root_path)?;
+    let claimed_path = claim_artifacts_path(logger, &root_path, artifacts_version())?;
+    context.set_pyoxidizer_artifacts_path(&claimed_path);
+
+    let pyoxidizer_artifacts_path = resolve_logical_path(&context.pyoxidizer_artifacts_path)?;
+
+    build_events::emit(BuildEvent::ArtifactsStarted {
+        target: context.target_triple.clone(),
+        artifacts_path: pyoxidizer_artifacts_path.clone(),
+    });
+
+    let regenerated =
+        artifacts_need_building(logger, context, &pyoxidizer_artifacts_path).is_stale();
+
+    if regenerated {
+        check_phase_space(
+            logger,
+            context,
+            "generate PyOxidizer artifacts",
+            &artifacts_requirements(context),
+        )?;
+
+        if context.force_artifacts {
+            // Start from scratch. The Python distribution is kept since it
+            // is verified against its SHA-256 when resolved.
+            remove_partial_artifacts(
+                &pyoxidizer_artifacts_path,
+                &BTreeSet::new(),
+                &context.python_distribution_path,
+            )?;
+        }
 
-    let pyoxidizer_artifacts_path = canonicalize_path(pyoxidizer_artifacts_path)?;
+        // Snapshot existing artifacts so we can remove anything a cancelled
+        // run leaves behind.
+        let existing = std::fs::read_dir(&pyoxidizer_artifacts_path)?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<std::io::Result<BTreeSet<PathBuf>>>()?;
+
+        let phase = build_stats::phase("generate artifacts");
+        let res = process_config(logger, context, "0");
+        drop(phase);
+
+        if let Err(e) = res {
+            if cancel::is_cancelled_error(&e) {
+                remove_partial_artifacts(
+                    &pyoxidizer_artifacts_path,
+                    &existing,
+                    &context.python_distribution_path,
+                )?;
+            }
 
-    if !artifacts_current(logger, &context.config_path, &pyoxidizer_artifacts_path) {
-        process_config(logger, context, "0");
+            return Err(categorize(e, ErrorCategory::Packaging));
+        }
     }
 
+    build_events::emit(BuildEvent::ArtifactsFinished {
+        target: context.target_triple.clone(),
+        artifacts_path: pyoxidizer_artifacts_path,
+        regenerated,
+    });
+
     Ok(())
 }
 
-/// Build an oxidized Rust application at the specified project path.
-pub fn build_project(logger: &slog::Logger, context: &mut BuildContext) -> Result<()> {
-    if let Ok(rust_version) = rustc_version::version() {
-        if rust_version.lt(&MINIMUM_RUST_VERSION) {
-            return Err(anyhow!(
-                "PyOxidizer requires Rust {}; version {} found",
-                *MINIMUM_RUST_VERSION,
-                rust_version,
-            ));
-        }
-    } else {
-        return Err(anyhow!(
-            "unable to determine Rust version; is Rust installed?"
-        ));
+/// Fail early if there isn't enough free disk space for a build phase.
+fn check_phase_space(
+    logger: &slog::Logger,
+    context: &BuildContext,
+    phase: &str,
+    requirements: &[SpaceRequirement],
+) -> Result<()> {
+    if context.skip_space_check {
+        return Ok(());
     }
 
-    // Our build process is to first generate artifacts from the PyOxidizer
-    // configuration within this process then call out to `cargo build`. We do
-    // this because it is easier to emit output from this process than to have
-    // it proxied via cargo.
-    build_pyoxidizer_artifacts(logger, context)?;
+    check_space(
+        logger,
+        &SystemSpaceProbe,
+        phase,
+        requirements,
+        margin_from_env()?,
+    )
+}
 
-    let mut args = Vec::new();
-    args.push("build");
+/// Resolve the effective RUSTFLAGS of a build.
+fn resolve_rustflags(logger: &slog::Logger, context: &mut BuildContext) {
+    let env_rustflags = std::env::var("RUSTFLAGS").ok();
+    let (rustflags, overridden) = context
+        .config
+        .build_config
+        .resolve_rustflags(env_rustflags.as_ref().map(String::as_str), context.release);
+
+    if let Some(value) = &env_rustflags {
+        info!(
+            logger,
+            "merging RUSTFLAGS from environment ({}) with config; config flags take precedence",
+            value
+        );
+    }
+    for flag in &overridden {
+        warn!(logger, "RUSTFLAGS {} overridden by config", flag);
+    }
+    if !rustflags.is_empty() {
+        info!(logger, "effective RUSTFLAGS: {}", rustflags.join(" "));
+    }
+    context.rustflags = rustflags;
+}
 
-    args.push("--target");
-    args.push(&context.target_triple);
+/// Obtain the arguments and environment variables `cargo build` is invoked with.
+///
+/// `python_exe_path` is the Python executable of the embedded distribution.
+fn cargo_build_invocation(
+    context: &BuildContext,
+    python_exe_path: &Path,
+) -> (Vec<String>, Vec<(String, String)>) {
+    let mut args = context.toolchain.cargo_args();
+    args.push("build".to_string());
+
+    // Cargo resolves symlinks in its working directory. Naming the manifest
+    // keeps the project path it passes to build scripts as we resolved it.
+    args.push("--manifest-path".to_string());
+    args.push(
+        context
+            .project_path
+            .join("Cargo.toml")
+            .display()
+            .to_string(),
+    );
+
+    args.push("--target".to_string());
+    args.push(context.target_triple.clone());
 
     // We use an explicit target directory so we can be sure we write our
     // artifacts to the same directory that cargo is using (unless the config
     // file overwrites the artifacts directory, of course).
-    let target_dir = context.target_base_path.display().to_string();
-    args.push("--target-dir");
-    args.push(&target_dir);
+    args.push("--target-dir".to_string());
+    args.push(context.target_base_path.display().to_string());
 
-    args.push("--bin");
-    args.push(&context.config.build_config.application_name);
+    if context.library {
+        args.push("--lib".to_string());
+    } else {
+        args.push("--bin".to_string());
+        args.push(context.config.build_config.application_name.clone());
+    }
 
     if context.release {
-        args.push("--release");
+        args.push("--release".to_string());
+    }
+
+    // A single --features keeps the user's features from conflicting with ours.
+    let mut features = Vec::new();
+    if let Some(feature) = context
+        .config
+        .embedded_python_config
+        .raw_allocator
+        .cargo_feature()
+    {
+        features.push(feature.to_string());
+    }
+    for feature in &context.extra_cargo_args.features {
+        if !features.contains(feature) {
+            features.push(feature.clone());
+        }
+    }
+    if !features.is_empty() {
+        args.push("--features".to_string());
+        args.push(features.join(","));
     }
 
-    if context.config.embedded_python_config.raw_allocator == RawAllocator::Jemalloc {
-        args.push("--features");
-        args.push("jemalloc");
+    args.extend(
+        context
+            .verbosity
+            .cargo_args()
+            .iter()
+            .map(|arg| arg.to_string()),
+    );
+
+    args.push("--color".to_string());
+    args.push(context.cargo_color.as_str().to_string());
+
+    args.extend(context.extra_cargo_args.args.iter().cloned());
+
+    // Cargo must not fetch crates either. Cargo rejects repeated flags, so
+    // don't add one the user already passed.
+    let network_arg = if frozen() {
+        Some("--frozen")
+    } else if context.offline {
+        Some("--offline")
+    } else {
+        None
+    };
+    if let Some(arg) = network_arg {
+        if !args.iter().any(|a| a == arg) {
+            args.push(arg.to_string());
+        }
     }
 
     let mut envs = Vec::new();
     envs.push((
-        "PYOXIDIZER_ARTIFACT_DIR",
+        "PYOXIDIZER_ARTIFACT_DIR".to_string(),
         context.pyoxidizer_artifacts_path.display().to_string(),
     ));
-    envs.push(("PYOXIDIZER_REUSE_ARTIFACTS", "1".to_string()));
+    envs.push(("PYOXIDIZER_REUSE_ARTIFACTS".to_string(), "1".to_string()));
+
+    if let Some(value) = rustflags_env(&context.rustflags, std::env::var_os("RUSTFLAGS").is_some())
+    {
+        envs.push(("RUSTFLAGS".to_string(), value));
+    }
 
     // Set PYTHON_SYS_EXECUTABLE so python3-sys uses our distribution's Python to
     // configure itself.
-    let python_exe_path = python_exe_path(&context.python_distribution_path)?;
     envs.push((
-        "PYTHON_SYS_EXECUTABLE",
+        PYTHON_SYS_EXECUTABLE_ENV.to_string(),
         python_exe_path.display().to_string(),
     ));
 
-    // static-nobundle link kind requires nightly Rust compiler until
-    // https://github.com/rust-lang/rust/issues/37403 is resolved.
-    if cfg!(windows) {
-        envs.push(("RUSTC_BOOTSTRAP", "1".to_string()));
+    if context.rustc_bootstrap {
+        envs.push(("RUSTC_BOOTSTRAP".to_string(), "1".to_string()));
+    }
+
+    envs.extend(
+        context
+            .toolchain
+            .cargo_envs()
+            .into_iter()
+            .map(|(key, value)| (key.to_string(), value)),
+    );
+
+    // The build script evaluates the config again, offline and with the
+    // same build variables.
+    envs.extend(context.eval_options().child_envs());
+
+    // Diagnostics are parsed so warnings in generated code can be told
+    // apart from the application's.
+    args.push("--message-format".to_string());
+    args.push("json".to_string());
+
+    (args, envs)
+}
+
+/// Make cargo rebuild a project even if nothing changed.
+///
+/// The `pyembed` crate's build script is touched. Cargo reruns changed
+/// build scripts and rebuilds everything depending on their crate.
+fn force_cargo_rebuild(logger: &slog::Logger, project_path: &Path) -> Result<()> {
+    let path = project_path.join("pyembed").join("build.rs");
+
+    if !path.exists() {
+        warn!(
+            logger,
+            "{} not found; cargo may not rebuild the project",
+            path.display()
+        );
+        return Ok(());
+    }
+
+    warn!(
+        logger,
+        "touching {} so cargo rebuilds the project because forcing was requested",
+        path.display()
+    );
+    filetime::set_file_mtime(&path, filetime::FileTime::now())
+        .with_context(|| format!("touching {}", path.display()))
+}
+
+/// Check that the Rust toolchain building the project is new enough.
+fn check_rust_version(context: &BuildContext) -> Result<()> {
+    let rust_version = context
+        .toolchain
+        .version_meta(&context.project_path)?
+        .semver;
+
+    if rust_version.lt(&MINIMUM_RUST_VERSION) {
+        return Err(anyhow!(
+            "PyOxidizer requires Rust {}; version {} found in toolchain {}",
+            *MINIMUM_RUST_VERSION,
+            rust_version,
+            context.toolchain,
+        ));
+    }
+
+    Ok(())
+}
+
+/// Build an oxidized Rust application at the specified project path.
+pub fn build_project(logger: &slog::Logger, context: &mut BuildContext) -> Result<()> {
+    // Our build process is to first generate artifacts from the PyOxidizer
+    // configuration within this process then call out to `cargo build`. We do
+    // this because it is easier to emit output from this process than to have
+    // it proxied via cargo.
+    generate_artifacts(logger, context)?;
+
+    cargo_build_project(logger, context)
+}
+
+/// Generate the PyOxidizer artifacts of a project before running cargo.
+fn generate_artifacts(logger: &slog::Logger, context: &mut BuildContext) -> Result<()> {
+    check_rust_version(context).category(ErrorCategory::Compile)?;
+    resolve_rustflags(logger, context);
+
+    build_pyoxidizer_artifacts(logger, context)
+}
+
+/// Compile a project whose artifacts were generated and package it.
+///
+/// This is the part of a build `compile` runs for a prepared build. The
+/// path of the produced executable or library is returned.
+fn compile_and_package(logger: &slog::Logger, context: &mut BuildContext) -> Result<PathBuf> {
+    cargo_build_project(logger, context)?;
+    package_project(logger, context).category(ErrorCategory::Packaging)?;
+    build_stats::record_artifact(&context.app_exe_path);
+
+    // Printed regardless of verbosity because scripts look for it.
+    print_human(&format!(
+        "{} path: {}",
+        if context.library {
+            "library"
+        } else {
+            "executable"
+        },
+        context.app_exe_path.display()
+    ));
+
+    // Also printed regardless of verbosity, so reviewing the build output
+    // shows everything that came from outside the project.
+    for external in &context.config.external_paths {
+        print_human(&format!(
+            "external path allowed: {} (rule {})",
+            external.target.display(),
+            external.rule
+        ));
+    }
+
+    build_events::emit(BuildEvent::BuildFinished {
+        target: context.target_triple.clone(),
+        release: context.release,
+        artifacts_path: context.pyoxidizer_artifacts_path.clone(),
+        app_path: Some(context.app_path.clone()),
+        app_exe_path: Some(context.app_exe_path.clone()),
+    });
+
+    Ok(context.app_exe_path.clone())
+}
+
+/// Run cargo for a project whose PyOxidizer artifacts were generated.
+fn cargo_build_project(logger: &slog::Logger, context: &mut BuildContext) -> Result<()> {
+    if context.force_cargo {
+        force_cargo_rebuild(logger, &context.project_path)?;
+    }
+
+    check_phase_space(
+        logger,
+        context,
+        "build with cargo",
+        &cargo_requirements(context),
+    )?;
+
+    let python_exe_path =
+        python_exe_path(&context.python_distribution_path).category(ErrorCategory::Distribution)?;
+    let python_exe_path =
+        host_python_exe(logger, context, &python_exe_path).category(ErrorCategory::Distribution)?;
+    let python_exe_path =
+        python_sys_executable_for_build(logger, &python_exe_path).category(ErrorCategory::Usage)?;
+
+    resolve_rustc_bootstrap(logger, context).category(ErrorCategory::Compile)?;
+
+    cargo_build(logger, context, &python_exe_path)
+}
+
+/// Resolve whether cargo needs to be invoked with `RUSTC_BOOTSTRAP=1`.
+fn resolve_rustc_bootstrap(logger: &slog::Logger, context: &mut BuildContext) -> Result<()> {
+    let flavor = distribution_flavor(&context.python_distribution_path)
+        .unwrap_or_else(|| "unknown".to_string());
+
+    let meta = context.toolchain.version_meta(&context.project_path)?;
+
+    context.rustc_bootstrap = rustc_bootstrap_for_build(
+        logger,
+        &meta,
+        &flavor,
+        &context.target_triple,
+        context.config.build_config.allow_rustc_bootstrap,
+    )?;
+
+    Ok(())
+}
+
+/// Resolve a Python executable Rust crates can run to configure themselves.
+///
+/// This is the executable of the distribution being embedded unless it
+/// can't run on this machine, e.g. when building for `aarch64-apple-darwin`
+/// on `x86_64-apple-darwin`. Then the executable of the host's default
+/// distribution is used, which must have the same Python version.
+fn host_python_exe(
+    logger: &slog::Logger,
+    context: &BuildContext,
+    python_exe: &Path,
+) -> Result<PathBuf> {
+    if context.host_triple == context.target_triple || python_exe_runs(python_exe) {
+        return Ok(python_exe.to_path_buf());
+    }
+
+    warn!(
+        logger,
+        "Python of the {} distribution can't run on this machine; using the default distribution for {} to configure Rust crates",
+        context.target_triple,
+        context.host_triple
+    );
+
+    let host_dist = default_distribution(
+        logger,
+        &context.host_triple,
+        &context.distributions_path,
+        context.offline,
+    )
+    .with_context(|| {
+        format!(
+            "resolving a Python distribution running on {} to cross build for {}",
+            context.host_triple, context.target_triple
+        )
+    })?;
+
+    let major_minor = |version: &str| version.split('.').take(2).collect::<Vec<_>>().join(".");
+
+    if let Some(target_version) = distribution_python_version(&context.python_distribution_path) {
+        if major_minor(&target_version) != major_minor(&host_dist.version) {
+            return Err(anyhow!(
+                "the Python distribution for {} can't run on this machine and the default distribution for {} is Python {}, not {}; build on a {} machine or use a {} distribution of Python {}",
+                context.target_triple,
+                context.host_triple,
+                host_dist.version,
+                target_version,
+                context.target_triple,
+                context.host_triple,
+                target_version
+            ));
+        }
     }
 
-    let status = process::Command::new("cargo")
+    Ok(host_dist.python_exe)
+}
+
+/// Run `cargo build` for a project whose artifacts were generated.
+///
+/// All failures are compile errors.
+fn cargo_build(
+    logger: &slog::Logger,
+    context: &BuildContext,
+    python_exe_path: &Path,
+) -> Result<()> {
+    let _phase = build_stats::phase("cargo build");
+
+    invoke_cargo_build(logger, context, python_exe_path).category(ErrorCategory::Compile)
+}
+
+fn invoke_cargo_build(
+    logger: &slog::Logger,
+    context: &BuildContext,
+    python_exe_path: &Path,
+) -> Result<()> {
+    let (args, envs) = cargo_build_invocation(context, python_exe_path);
+
+    // Record how we invoke cargo so build problems can be reproduced.
+    let build_environment_path = BuildEnvironment::path(&context.pyoxidizer_artifacts_path);
+    BuildEnvironment::capture(
+        context,
+        python_exe_path,
+        &args.iter().map(String::as_str).collect::<Vec<_>>(),
+        &envs,
+    )
+    .write_path(&build_environment_path)?;
+
+    build_events::emit(BuildEvent::CargoInvocation {
+        target: context.target_triple.clone(),
+        args: args.clone(),
+        env: envs.iter().cloned().collect(),
+        cwd: context.project_path.clone(),
+    });
+
+    let mut command = process::Command::new(context.toolchain.cargo_program());
+    command
         .args(args)
         .current_dir(&context.project_path)
         .envs(envs)
-        .status()?;
+        .stdout(process::Stdio::piped());
+
+    // On Windows, cargo's own errors are inspected for files locked by
+    // anti-virus software.
+    if cfg!(windows) {
+        command.stderr(process::Stdio::piped());
+    }
+
+    let mut child = cancel::spawn(&mut command)?;
+
+    let stderr_relay = child.child_mut().stderr.take().map(|stderr| {
+        cancel::spawn_thread(move || {
+            relay_cargo_stderr(std::io::BufReader::new(stderr), &mut std::io::stderr())
+        })
+    });
+
+    let summary = match child.child_mut().stdout.take() {
+        Some(stdout) => process_cargo_output(
+            std::io::BufReader::new(stdout),
+            &mut std::io::stderr(),
+            &context.project_path,
+            &context.pyoxidizer_artifacts_path,
+        )?,
+        None => DiagnosticsSummary::default(),
+    };
+
+    let status = child.wait()?;
+
+    let stderr_locked_file = match stderr_relay {
+        Some(relay) => relay
+            .join()
+            .map_err(|_| anyhow!("relaying the output of cargo failed"))??,
+        None => false,
+    };
+
+    summary.report(logger);
+
+    let environment_hint = format!(
+        "build environment recorded in {}; please attach it to bug reports",
+        build_environment_path.display()
+    );
 
     if status.success() {
+        if context.deny_generated_warnings && !summary.generated_warnings.is_empty() {
+            return Err(anyhow!(
+                "{} warning(s) in code generated by PyOxidizer and --deny-generated-warnings is set",
+                summary.generated_warnings.len()
+            ));
+        }
+
         Ok(())
+    } else if cfg!(windows) && (summary.locked_file || stderr_locked_file) {
+        Err(anyhow!(
+            "cargo build failed\n\n{}\n\n{}",
+            locked_build_hint(&context.build_path),
+            environment_hint
+        ))
     } else {
-        Err(anyhow!("cargo build failed"))
+        Err(anyhow!("cargo build failed\n\n{}", environment_hint))
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 pub fn resolve_build_context(
     logger: &slog::Logger,
     project_path: &str,
@@ -231,12 +845,45 @@ pub fn resolve_build_context(
     target: Option<&str>,
     release: bool,
     force_artifacts_path: Option<&Path>,
-    verbose: bool,
+    verbosity: Verbosity,
+    locked: bool,
+    options: &EvalOptions,
+) -> Result<BuildContext> {
+    resolve_build_context_with_cache(
+        logger,
+        project_path,
+        config_path,
+        target,
+        release,
+        force_artifacts_path,
+        verbosity,
+        locked,
+        options,
+        &EvaluationCache::default(),
+    )
+}
+
+/// Like `resolve_build_context()`, evaluating the config with `eval_cache`.
+#[allow(clippy::too_many_arguments)]
+pub fn resolve_build_context_with_cache(
+    logger: &slog::Logger,
+    project_path: &str,
+    config_path: Option<&str>,
+    target: Option<&str>,
+    release: bool,
+    force_artifacts_path: Option<&Path>,
+    verbosity: Verbosity,
+    locked: bool,
+    options: &EvalOptions,
+    eval_cache: &EvaluationCache,
 ) -> Result<BuildContext> {
-    let path = canonicalize_path(&PathBuf::from(project_path))?;
+    let path = resolve_logical_path(&PathBuf::from(project_path))?;
 
     if find_pyoxidizer_files(&path).is_empty() {
-        return Err(anyhow!("no PyOxidizer files in specified path"));
+        return Err(categorize(
+            anyhow!("no PyOxidizer files in specified path"),
+            ErrorCategory::Config,
+        ));
     }
 
     let target = match target {
@@ -248,43 +895,126 @@ pub fn resolve_build_context(
         Some(p) => PathBuf::from(p),
         None => match find_pyoxidizer_config_file_env(logger, &path) {
             Some(p) => p,
-            None => return Err(anyhow!("unable to find PyOxidizer config file")),
+            None => {
+                return Err(categorize(
+                    anyhow!("unable to find PyOxidizer config file"),
+                    ErrorCategory::Config,
+                ))
+            }
         },
     };
 
-    let res = eval_starlark_config_file(logger, &config_path, &target)?;
+    let res = eval_starlark_config_file_with_cache(
+        logger,
+        &config_path,
+        &target,
+        release,
+        options,
+        eval_cache,
+    )?;
+
+    if locked {
+        verify_lockfile(&res.config, &target).category(ErrorCategory::Config)?;
+    }
 
-    BuildContext::new(
+    let mut context = BuildContext::new(
         &path,
         res.config,
         None,
         &target,
         release,
         force_artifacts_path,
-        verbose,
-    )
+        verbosity,
+    )?;
+    context.offline = options.offline;
+    context.build_vars = options.build_vars.clone();
+
+    // Another PyOxidizer version may own the artifacts directory. Building
+    // claims a directory, so this only matters for reading artifacts.
+    let artifacts_path =
+        versioned_artifacts_path(&context.pyoxidizer_artifacts_root_path, artifacts_version())
+            .category(ErrorCategory::Config)?;
+    context.set_pyoxidizer_artifacts_path(&artifacts_path);
+
+    Ok(context)
 }
 
 fn run_project(
     logger: &slog::Logger,
     context: &mut BuildContext,
     extra_args: &[&str],
+    options: &RunOptions,
+    cancel: &CancellationToken,
 ) -> Result<()> {
+    if context.library {
+        return Err(anyhow!(
+            "{} builds a library, which can't be run",
+            context.project_path.display()
+        ));
+    }
+
     // We call our build wrapper and invoke the binary directly. This allows
     // build output to be printed.
-    build_project(logger, context)?;
+    {
+        let _active = cancel.activate();
+        build_project(logger, context)?;
+        package_project(logger, context).category(ErrorCategory::Packaging)?;
+        build_stats::record_artifact(&context.app_exe_path);
+    }
 
-    package_project(logger, context)?;
+    // The application owns the terminal once it is running. So it isn't
+    // subject to build cancellation.
 
-    let status = process::Command::new(&context.app_exe_path)
-        .current_dir(&context.project_path)
-        .args(extra_args)
-        .status()?;
+    let cwd = options.cwd.as_ref().unwrap_or(&context.project_path);
 
-    if status.success() {
-        Ok(())
-    } else {
-        Err(anyhow!("cargo run failed"))
+    run_app(&context.app_exe_path, cwd, extra_args, &options.env)
+}
+
+/// Run a built application until it exits.
+///
+/// `env` is set in addition to our environment. All failures are run
+/// errors. A failing application results in an `AppExitError`, so we exit
+/// with its exit code. Signals asking us to stop are forwarded to it while
+/// it runs.
+fn run_app(exe_path: &Path, cwd: &Path, args: &[&str], env: &[(String, String)]) -> Result<()> {
+    // A relative path would be resolved against cwd.
+    let exe_path = std::env::current_dir()
+        .map(|dir| dir.join(exe_path))
+        .context("resolving the current directory")
+        .category(ErrorCategory::Run)?;
+
+    let mut child = process::Command::new(&exe_path)
+        .current_dir(cwd)
+        .args(args)
+        .envs(env.iter().map(|(k, v)| (k, v)))
+        .spawn()
+        .with_context(|| format!("launching {}", exe_path.display()))
+        .category(ErrorCategory::Run)?;
+
+    let status = {
+        let _forwarder = match cancel::forward_signals(&child) {
+            Ok(forwarder) => forwarder,
+            Err(e) => {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(categorize(e, ErrorCategory::Run));
+            }
+        };
+
+        child
+            .wait()
+            .with_context(|| format!("waiting for {}", exe_path.display()))
+            .category(ErrorCategory::Run)?
+    };
+
+    let name = exe_path
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_else(|| exe_path.display().to_string());
+
+    match AppExitError::from_status(&name, status) {
+        Some(exit) => Err(anyhow::Error::new(exit)),
+        None => Ok(()),
     }
 }
 
@@ -292,35 +1022,297 @@ fn run_project(
 ///
 /// This is a glorified wrapper around `cargo build`. Our goal is to get the
 /// output from repackaging to give the user something for debugging.
+///
+/// The project is built for each of `targets`, or for the default target if
+/// there are none. Each target is built into its own directories. When
+/// building for multiple targets, a failing target doesn't stop the
+/// remaining ones unless `options.fail_fast` is set, and a summary of what
+/// was produced for each target is printed at the end.
+///
+/// If `cancel` is cancelled, the build stops and returns
+/// `BuildError::Cancelled`.
 pub fn build(
+    logger: &slog::Logger,
+    project_path: &str,
+    targets: &[&str],
+    options: &BuildOptions,
+    cancel: &CancellationToken,
+) -> Result<()> {
+    let _active = cancel.activate();
+
+    // Targets are built from one config. Their evaluations share work that
+    // doesn't depend on the target.
+    let eval_cache = EvaluationCache::default();
+
+    let build_target =
+        |target: Option<&str>| build_one_target(logger, project_path, target, options, &eval_cache);
+
+    let mut unique_targets = Vec::new();
+    for target in targets {
+        if !unique_targets.contains(target) {
+            unique_targets.push(*target);
+        }
+    }
+
+    if unique_targets.len() < 2 {
+        build_target(unique_targets.first().cloned())?;
+        return Ok(());
+    }
+
+    let target_count = unique_targets.len();
+    let mut outcomes = Vec::new();
+
+    for target in unique_targets {
+        warn!(logger, "building for {}", target);
+
+        match build_target(Some(target)) {
+            Ok(path) => outcomes.push(TargetOutcome {
+                target: target.to_string(),
+                result: Ok(path),
+            }),
+            Err(e) if cancel::is_cancelled_error(&e) => return Err(e),
+            Err(e) => {
+                warn!(logger, "building for {} failed: {:#}", target, e);
+                outcomes.push(TargetOutcome {
+                    target: target.to_string(),
+                    result: Err(e),
+                });
+                if options.fail_fast {
+                    break;
+                }
+            }
+        }
+    }
+
+    print_human(format_target_outcomes(&outcomes).trim_end());
+
+    let failed = outcomes
+        .iter()
+        .filter_map(|o| match &o.result {
+            Ok(_) => None,
+            Err(e) => Some((o.target.as_str(), e)),
+        })
+        .collect::<Vec<_>>();
+
+    match failed.first() {
+        None => Ok(()),
+        Some((_, first)) => {
+            // The exit code is that of the first failure.
+            let category = error_category(first).unwrap_or(ErrorCategory::Compile);
+
+            let mut message = format!(
+                "building failed for {} of {} targets: {}",
+                failed.len(),
+                target_count,
+                failed
+                    .iter()
+                    .map(|(target, _)| *target)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+            if outcomes.len() < target_count {
+                message.push_str(&format!(
+                    "; {} not built because of --fail-fast",
+                    target_count - outcomes.len()
+                ));
+            }
+
+            Err(categorize(anyhow!(message), category))
+        }
+    }
+}
+
+/// Build and package a project for a single target.
+///
+/// The path of the produced executable or library is returned.
+fn build_one_target(
+    logger: &slog::Logger,
+    project_path: &str,
+    target: Option<&str>,
+    options: &BuildOptions,
+    eval_cache: &EvaluationCache,
+) -> Result<PathBuf> {
+    let mut context = resolve_build_context_with_cache(
+        logger,
+        project_path,
+        None,
+        target,
+        options.release,
+        None,
+        options.verbosity,
+        options.locked,
+        &options.eval_options,
+        eval_cache,
+    )?;
+    context.deny_generated_warnings = options.deny_generated_warnings;
+    context.force_artifacts = options.force_artifacts;
+    context.force_cargo = options.force_cargo;
+    context.skip_space_check = options.skip_space_check;
+    context.third_party_notices |= options.third_party_notices;
+    context.extra_cargo_args = options.extra_cargo_args.clone();
+    generate_artifacts(logger, &mut context)?;
+
+    compile_and_package(logger, &mut context)
+}
+
+/// The result of building for one target of a multi-target build.
+struct TargetOutcome {
+    target: String,
+    /// The produced executable or library, or why building failed.
+    result: Result<PathBuf>,
+}
+
+/// Format the summary table of a multi-target build.
+fn format_target_outcomes(outcomes: &[TargetOutcome]) -> String {
+    let width = outcomes
+        .iter()
+        .map(|o| o.target.len())
+        .chain(std::iter::once("TARGET".len()))
+        .max()
+        .unwrap_or(0);
+
+    let mut res = format!(
+        "{:<width$}  {:<6}  {}\n",
+        "TARGET",
+        "STATUS",
+        "PATH",
+        width = width
+    );
+
+    for outcome in outcomes {
+        let (status, detail) = match &outcome.result {
+            Ok(path) => ("ok", path.display().to_string()),
+            Err(e) => (
+                "failed",
+                format!(
+                    "({})",
+                    error_category(e)
+                        .map(ErrorCategory::as_str)
+                        .unwrap_or("other")
+                ),
+            ),
+        };
+
+        res.push_str(&format!(
+            "{:<width$}  {:<6}  {}\n",
+            outcome.target,
+            status,
+            detail,
+            width = width
+        ));
+    }
+
+    res
+}
+
+/// Describe what building a PyOxidizer enabled project would do.
+///
+/// The config is evaluated and, unless the plan is shallow, the Python
+/// distribution and resources are resolved. Nothing is built and no
+/// artifacts are written, although the Python distribution is fetched and
+/// extracted if needed.
+#[allow(clippy::too_many_arguments)]
+pub fn plan(
     logger: &slog::Logger,
     project_path: &str,
     target: Option<&str>,
     release: bool,
-    verbose: bool,
+    verbosity: Verbosity,
+    locked: bool,
+    options: &EvalOptions,
+    mode: PlanMode,
+    json: bool,
+    cancel: &CancellationToken,
 ) -> Result<()> {
-    let mut context =
-        resolve_build_context(logger, project_path, None, target, release, None, verbose)?;
-    build_project(logger, &mut context)?;
-    package_project(logger, &mut context)?;
+    let _active = cancel.activate();
 
-    warn!(
+    let mut context = resolve_build_context(
         logger,
-        "executable path: {}",
-        context.app_exe_path.display()
-    );
+        project_path,
+        None,
+        target,
+        release,
+        None,
+        verbosity,
+        locked,
+        options,
+    )?;
+    resolve_rustflags(logger, &mut context);
+
+    let mut plan = BuildPlan {
+        mode,
+        config_path: context.config_path.clone(),
+        target_triple: context.target_triple.clone(),
+        release,
+        artifacts_path: context.pyoxidizer_artifacts_path.clone(),
+        app_path: context.app_path.clone(),
+        resources: None,
+        files: artifact_files(&context),
+        cargo_metadata: None,
+        cargo: None,
+    };
+
+    if mode == PlanMode::Full {
+        let resolved = resolve_config(logger, &context)?;
+        let embedded = &resolved.resources.embedded;
+
+        // The lines process_config() writes to cargo_metadata.txt.
+        let libpython_metadata = libpython_cargo_metadata(
+            logger,
+            &resolved.dist,
+            embedded,
+            &context.pyoxidizer_artifacts_path,
+            &context.target_triple,
+        );
+        let cargo_metadata = artifacts_cargo_metadata(
+            &resolved,
+            &libpython_metadata,
+            &ArtifactPaths::new(&context),
+        );
+
+        let state = artifacts_packaging_state(&context, &resolved)?;
+
+        let python_exe_path = python_exe_path(&context.python_distribution_path)?;
+        resolve_rustc_bootstrap(logger, &mut context)?;
+        let (args, envs) = cargo_build_invocation(&context, &python_exe_path);
+
+        plan.resources = Some(ResourcesPlan::from_resources(embedded));
+        plan.files.extend(app_files(&context, Some(&state))?);
+        plan.cargo_metadata = Some(cargo_metadata);
+        plan.cargo = Some(CargoInvocation::new(
+            args,
+            &envs,
+            context.project_path.clone(),
+        ));
+    } else {
+        plan.files.extend(app_files(&context, None)?);
+    }
+
+    if json {
+        println!("{}", plan.to_json()?);
+    } else {
+        print!("{}", plan.to_text());
+    }
 
     Ok(())
 }
 
+#[allow(clippy::too_many_arguments)]
 pub fn build_artifacts(
     logger: &slog::Logger,
     project_path: &Path,
     dest_path: &Path,
     target: Option<&str>,
     release: bool,
-    verbose: bool,
+    verbosity: Verbosity,
+    locked: bool,
+    options: &EvalOptions,
+    force_artifacts: bool,
+    skip_space_check: bool,
+    cancel: &CancellationToken,
 ) -> Result<()> {
+    let _active = cancel.activate();
+
     let mut context = resolve_build_context(
         logger,
         project_path.to_str().unwrap(),
@@ -328,26 +1320,194 @@ pub fn build_artifacts(
         target,
         release,
         Some(dest_path),
-        verbose,
+        verbosity,
+        locked,
+        options,
+    )?;
+    context.force_artifacts = force_artifacts;
+    context.skip_space_check = skip_space_check;
+
+    build_pyoxidizer_artifacts(logger, &mut context)?;
+
+    build_events::emit(BuildEvent::BuildFinished {
+        target: context.target_triple.clone(),
+        release: context.release,
+        artifacts_path: context.pyoxidizer_artifacts_path.clone(),
+        app_path: None,
+        app_exe_path: None,
+    });
+
+    Ok(())
+}
+
+/// Prepare a build to be compiled elsewhere.
+///
+/// The config is evaluated and PyOxidizer artifacts are generated into
+/// `out_path`, along with everything `compile()` needs. See
+/// `app_packaging::prepared`.
+#[allow(clippy::too_many_arguments)]
+pub fn prepare(
+    logger: &slog::Logger,
+    project_path: &str,
+    out_path: &Path,
+    target: Option<&str>,
+    release: bool,
+    verbosity: Verbosity,
+    locked: bool,
+    options: &EvalOptions,
+    skip_space_check: bool,
+    cancel: &CancellationToken,
+) -> Result<()> {
+    let _active = cancel.activate();
+
+    create_dir_all(out_path).with_context(|| format!("creating {}", out_path.display()))?;
+    let out_path = resolve_logical_path(out_path)?;
+    if std::fs::read_dir(&out_path)?.next().is_some() {
+        return Err(categorize(
+            anyhow!("{} is not empty", out_path.display()),
+            ErrorCategory::Usage,
+        ));
+    }
+
+    let mut context = resolve_build_context(
+        logger,
+        project_path,
+        None,
+        target,
+        release,
+        Some(&out_path.join(PREPARED_ARTIFACTS_DIR)),
+        verbosity,
+        locked,
+        options,
     )?;
+    context.skip_space_check = skip_space_check;
 
+    // Artifacts record the effective RUSTFLAGS.
+    resolve_rustflags(logger, &mut context);
     build_pyoxidizer_artifacts(logger, &mut context)?;
 
+    let manifest =
+        write_prepared(logger, &context, &out_path).category(ErrorCategory::Packaging)?;
+
+    // Printed regardless of verbosity because scripts look for it.
+    print_human(&format!(
+        "prepared {} for {}: {}",
+        manifest.app_name,
+        manifest.target_triple,
+        out_path.display()
+    ));
+
     Ok(())
 }
 
+/// Compile and package a build prepared by `prepare()`.
+///
+/// The config isn't evaluated, so neither pip nor the network is needed
+/// to obtain Python resources. The path of the produced executable or
+/// library is returned.
+#[allow(clippy::too_many_arguments)]
+pub fn compile(
+    logger: &slog::Logger,
+    prepared_path: &Path,
+    target_dir: &Path,
+    target: Option<&str>,
+    verbosity: Verbosity,
+    options: &EvalOptions,
+    deny_generated_warnings: bool,
+    skip_space_check: bool,
+    third_party_notices: bool,
+    extra_cargo_args: &ExtraCargoArgs,
+    cancel: &CancellationToken,
+) -> Result<PathBuf> {
+    let _active = cancel.activate();
+
+    let prepared_path = resolve_logical_path(prepared_path)
+        .with_context(|| format!("resolving {}", prepared_path.display()))
+        .category(ErrorCategory::Usage)?;
+    let manifest =
+        PreparedManifest::from_prepared_path(&prepared_path).category(ErrorCategory::Usage)?;
+
+    let target = match target {
+        Some(v) => v.to_string(),
+        None => default_target()?,
+    };
+    manifest
+        .check_compatible(&target, artifacts_version())
+        .category(ErrorCategory::Usage)?;
+
+    create_dir_all(target_dir).with_context(|| format!("creating {}", target_dir.display()))?;
+    let target_dir = resolve_logical_path(target_dir)?;
+
+    let build = materialize_prepared(logger, &prepared_path, &manifest, &target_dir)
+        .category(ErrorCategory::Config)?;
+
+    let mut context = BuildContext::new(
+        &build.project_path,
+        build.config,
+        None,
+        &target,
+        manifest.release,
+        Some(&build.artifacts_path),
+        verbosity,
+    )?;
+    context.python_distribution_path = build.python_distribution_path;
+    context.offline = options.offline;
+    context.build_vars = options.build_vars.clone();
+    context.deny_generated_warnings = deny_generated_warnings;
+    context.skip_space_check = skip_space_check;
+    context.third_party_notices |= third_party_notices;
+    context.extra_cargo_args = extra_cargo_args.clone();
+
+    check_rust_version(&context).category(ErrorCategory::Compile)?;
+    resolve_rustflags(logger, &mut context);
+
+    compile_and_package(logger, &mut context)
+}
+
+#[allow(clippy::too_many_arguments)]
 pub fn run(
     logger: &slog::Logger,
     project_path: &str,
     target: Option<&str>,
     release: bool,
     extra_args: &[&str],
-    verbose: bool,
+    options: &RunOptions,
+    verbosity: Verbosity,
+    locked: bool,
+    eval_options: &EvalOptions,
+    force_artifacts: bool,
+    force_cargo: bool,
+    skip_space_check: bool,
+    cancel: &CancellationToken,
 ) -> Result<()> {
-    let mut context =
-        resolve_build_context(logger, project_path, None, target, release, None, verbose)?;
+    if let Some(cwd) = &options.cwd {
+        if !cwd.is_dir() {
+            return Err(categorize(
+                anyhow!("--cwd {} is not a directory", cwd.display()),
+                ErrorCategory::Usage,
+            ));
+        }
+    }
+
+    let mut context = {
+        let _active = cancel.activate();
+        resolve_build_context(
+            logger,
+            project_path,
+            None,
+            target,
+            release,
+            None,
+            verbosity,
+            locked,
+            eval_options,
+        )?
+    };
+    context.force_artifacts = force_artifacts;
+    context.force_cargo = force_cargo;
+    context.skip_space_check = skip_space_check;
 
-    run_project(logger, &mut context, extra_args)
+    run_project(logger, &mut context, extra_args, options, cancel)
 }
 
 /// Initialize a new Rust project with PyOxidizer support.
@@ -356,11 +1516,54 @@ pub fn run(
 /// application.
 ///
 /// `pip_install` can specify Python packages to `pip install` for the application.
-pub fn init(project_path: &str, code: Option<&str>, pip_install: &[&str]) -> Result<()> {
+///
+/// `lib` creates a shared library embedding Python in a host application
+/// instead of an executable. Otherwise `template` selects the kind of
+/// application.
+pub fn init(
+    project_path: &str,
+    code: Option<&str>,
+    pip_install: &[&str],
+    lib: bool,
+    template: ProjectTemplate,
+) -> Result<()> {
     let path = Path::new(project_path);
 
-    initialize_project(&path, code, pip_install)?;
+    initialize_project(&path, code, pip_install, lib, template)?;
     println!();
+
+    if lib {
+        let name = path
+            .iter()
+            .last()
+            .unwrap()
+            .to_string_lossy()
+            .replace('-', "_");
+
+        println!(
+            "A new Rust shared library embedding Python has been created in {}",
+            path.display()
+        );
+        println!();
+        println!("This library can be built by doing the following:");
+        println!();
+        println!("  $ cd {}", path.display());
+        println!("  $ pyoxidizer build");
+        println!();
+        println!(
+            "The library and a {}.h header declaring the functions it",
+            name
+        );
+        println!(
+            "exports ({0}_init(), {0}_eval() and {0}_shutdown()) are written",
+            name
+        );
+        println!("to the build's application directory. Edit src/lib.rs to change");
+        println!("the exported functions.");
+
+        return Ok(());
+    }
+
     println!(
         "A new Rust binary application has been created in {}",
         path.display()
@@ -372,38 +1575,213 @@ pub fn init(project_path: &str, code: Option<&str>, pip_install: &[&str]) -> Res
     println!("  $ pyoxidizer build");
     println!("  $ pyoxidizer run");
     println!();
-    println!("The default configuration is to invoke a Python REPL. You can");
-    println!("edit the various pyoxidizer.*.bzl config files or the main.rs ");
-    println!("file to change behavior. The application will need to be rebuilt ");
-    println!("for configuration changes to take effect.");
+
+    if template == ProjectTemplate::Repl {
+        println!("The default configuration is to invoke a Python REPL. You can");
+        println!("edit the various pyoxidizer.*.bzl config files or the main.rs ");
+        println!("file to change behavior. The application will need to be rebuilt ");
+        println!("for configuration changes to take effect.");
+
+        return Ok(());
+    }
+
+    let package = python_package_name(&path.iter().last().unwrap().to_string_lossy());
+
+    println!(
+        "The application runs the {} Python package in {}.",
+        package,
+        path.join("app").join(&package).display()
+    );
+    println!("Edit it, the pyoxidizer.bzl config file or the main.rs file to");
+    println!("change behavior. The application will need to be rebuilt for");
+    println!("changes to take effect.");
 
     Ok(())
 }
 
-pub fn python_distribution_extract(dist_path: &str, dest_path: &str) -> Result<()> {
-    let mut fh = std::fs::File::open(Path::new(dist_path))?;
-    let mut data = Vec::new();
-    fh.read_to_end(&mut data)?;
-    let cursor = Cursor::new(data);
-    let dctx = zstd::stream::Decoder::new(cursor)?;
-    let mut tf = tar::Archive::new(dctx);
+/// Extract a Python distribution archive, or list its entries.
+///
+/// `filters` are paths or glob patterns matching tar entry paths. If any
+/// are given, only matching entries are extracted or listed and it is an
+/// error for none to match.
+pub fn python_distribution_extract(
+    dist_path: &str,
+    dest_path: Option<&str>,
+    filters: &[&str],
+    list: bool,
+) -> Result<()> {
+    let mut selection = MemberSelection::default();
+    for filter in filters {
+        selection
+            .include_filter(filter)
+            .categorize(ErrorCategory::Usage)?;
+    }
+
+    let no_match = || anyhow!("no entries of {} match {}", dist_path, filters.join(", "));
+
+    if list {
+        let members = read_tar_zst(Path::new(dist_path), false, |reader| {
+            read_members(reader, &MemberSelection::default())
+        })?;
+
+        let entries = members
+            .files
+            .iter()
+            .filter(|(path, _)| selection.is_empty() || selection.contains(path))
+            .collect::<Vec<_>>();
+
+        if entries.is_empty() && !selection.is_empty() {
+            return Err(no_match());
+        }
+
+        for (path, size) in entries {
+            println!("{:>12}  {}", size, path.display());
+        }
+
+        return Ok(());
+    }
+
+    let dest_path = dest_path.ok_or_else(|| {
+        categorize(
+            anyhow!("a destination path is required unless --list is given"),
+            ErrorCategory::Usage,
+        )
+    })?;
+
+    if selection.is_empty() {
+        println!("extracting archive to {}", dest_path);
+
+        return read_tar_zst(Path::new(dist_path), false, |reader| {
+            tar::Archive::new(reader).unpack(dest_path)?;
 
-    println!("extracting archive to {}", dest_path);
-    tf.unpack(dest_path)?;
+            Ok(())
+        });
+    }
+
+    println!("extracting matching entries to {}", dest_path);
+
+    let extracted = read_tar_zst(Path::new(dist_path), false, |reader| {
+        extract_members(reader, &selection, Path::new(dest_path))
+    })?;
+
+    if extracted.is_empty() {
+        return Err(no_match());
+    }
+
+    println!("extracted {} entries", extracted.len());
 
     Ok(())
 }
 
-pub fn python_distribution_info(dist_path: &str) -> Result<()> {
-    let mut fh = std::fs::File::open(Path::new(dist_path))?;
-    let mut data = Vec::new();
-    fh.read_to_end(&mut data)?;
+/// Number of entries to print per category in size reports.
+const SIZE_REPORT_TOP_ENTRIES: usize = 20;
+
+/// Print the largest components of a distribution by category.
+fn print_distribution_sizes(sizes: &DistributionSizes) {
+    let total = sizes.total();
+
+    for (category, values) in sizes.categories() {
+        let category_total: u64 = values.values().sum();
+
+        let mut entries: Vec<(&String, &u64)> = values.iter().collect();
+        entries.sort_by(|a, b| b.1.cmp(a.1).then_with(|| a.0.cmp(b.0)));
+
+        println!("{}", category);
+        println!("{}", "=".repeat(category.len()));
+        println!();
+
+        for (name, size) in entries.iter().take(SIZE_REPORT_TOP_ENTRIES) {
+            println!("{:>12}  {:>5.1}%  {}", size, percent(**size, total), name);
+        }
+
+        if entries.len() > SIZE_REPORT_TOP_ENTRIES {
+            println!("... {} more", entries.len() - SIZE_REPORT_TOP_ENTRIES);
+        }
+
+        println!(
+            "{:>12}  {:>5.1}%  total ({} entries)",
+            category_total,
+            percent(category_total, total),
+            entries.len()
+        );
+        println!();
+    }
+
+    println!("{:>12}  total", total);
+}
+
+fn percent(value: u64, total: u64) -> f64 {
+    if total == 0 {
+        0.0
+    } else {
+        value as f64 * 100.0 / total as f64
+    }
+}
+
+/// Represent distribution sizes as JSON.
+///
+/// Entries in each category are sorted by descending size.
+fn distribution_sizes_json(sizes: &DistributionSizes) -> serde_json::Value {
+    let mut categories = serde_json::Map::new();
+
+    for (category, values) in sizes.categories() {
+        let mut entries: Vec<(&String, &u64)> = values.iter().collect();
+        entries.sort_by(|a, b| b.1.cmp(a.1).then_with(|| a.0.cmp(b.0)));
+
+        categories.insert(
+            category.to_string(),
+            serde_json::json!({
+                "total": values.values().sum::<u64>(),
+                "entries": entries
+                    .iter()
+                    .map(|(name, size)| serde_json::json!({"name": name, "size": size}))
+                    .collect::<Vec<_>>(),
+            }),
+        );
+    }
+
+    serde_json::json!({
+        "total": sizes.total(),
+        "categories": categories,
+    })
+}
 
+/// Print information about a Python distribution archive.
+///
+/// With `json`, a `DistributionInfo` or the sizes are printed as JSON.
+pub fn python_distribution_info(dist_path: &str, sizes: bool, json: bool) -> Result<()> {
     let temp_dir = tempdir::TempDir::new("python-distribution")?;
     let temp_dir_path = temp_dir.path();
 
-    let cursor = Cursor::new(data);
-    let dist = analyze_python_distribution_tar_zst(cursor, temp_dir_path)?;
+    // Sizes are those of extracted files. Everything else is described by
+    // the archive's metadata.
+    let dist = if sizes {
+        analyze_python_distribution_archive(Path::new(dist_path), temp_dir_path)?
+    } else {
+        analyze_python_distribution_metadata(Path::new(dist_path), temp_dir_path)?
+    };
+
+    if sizes {
+        if json {
+            println!(
+                "{}",
+                serde_json::to_string_pretty(&distribution_sizes_json(&dist.sizes))?
+            );
+        } else {
+            print_distribution_sizes(&dist.sizes);
+        }
+
+        return Ok(());
+    }
+
+    if json {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&DistributionInfo::from_distribution(&dist))?
+        );
+
+        return Ok(());
+    }
 
     println!("High-Level Metadata");
     println!("===================");
@@ -414,6 +1792,33 @@ pub fn python_distribution_info(dist_path: &str) -> Result<()> {
     println!("Architecture: {}", dist.arch);
     println!();
 
+    let missing = dist.missing_embedding_requirements();
+    if !missing.is_empty() {
+        println!("This distribution cannot be embedded. It lacks:");
+        for requirement in missing {
+            println!("  {}", requirement);
+        }
+        println!();
+    }
+
+    let capabilities = DistributionCapabilities::from_distribution(&dist);
+    println!("Capabilities");
+    println!("============");
+    println!();
+    println!(
+        "static_libpython:              {}",
+        capabilities.static_libpython
+    );
+    println!(
+        "supports_in_memory_extensions: {}",
+        capabilities.supports_in_memory_extensions
+    );
+    println!(
+        "supports_jemalloc:             {}",
+        capabilities.supports_jemalloc
+    );
+    println!();
+
     println!("Extension Modules");
     println!("=================");
     for (name, ems) in dist.extension_modules {
@@ -427,6 +1832,9 @@ pub fn python_distribution_info(dist_path: &str) -> Result<()> {
             println!();
             println!("Required: {}", em.required);
             println!("Built-in Default: {}", em.builtin_default);
+            if let Some(path) = &em.shared_library {
+                println!("Shared Library: {}", path.display());
+            }
             if let Some(licenses) = em.licenses {
                 println!("Licenses: {}", licenses.join(", "));
             }
@@ -445,6 +1853,19 @@ pub fn python_distribution_info(dist_path: &str) -> Result<()> {
         }
     }
 
+    println!("SQLite Compile Options");
+    println!("======================");
+    println!();
+    match &dist.sqlite_compile_options {
+        Some(options) => {
+            for option in options {
+                println!("{}", option);
+            }
+        }
+        None => println!("unknown"),
+    }
+    println!();
+
     println!("Python Modules");
     println!("==============");
     println!();
@@ -467,15 +1888,10 @@ pub fn python_distribution_info(dist_path: &str) -> Result<()> {
 }
 
 pub fn python_distribution_licenses(path: &str) -> Result<()> {
-    let mut fh = std::fs::File::open(Path::new(path))?;
-    let mut data = Vec::new();
-    fh.read_to_end(&mut data)?;
-
     let temp_dir = tempdir::TempDir::new("python-distribution")?;
     let temp_dir_path = temp_dir.path();
 
-    let cursor = Cursor::new(data);
-    let dist = analyze_python_distribution_tar_zst(cursor, temp_dir_path)?;
+    let dist = analyze_python_distribution_metadata(Path::new(path), temp_dir_path)?;
 
     println!(
         "Python Distribution Licenses: {}",
@@ -544,3 +1960,1974 @@ pub fn run_build_script(logger: &slog::Logger, build_script: &str) -> Result<()>
 
     Ok(())
 }
+
+/// Print verification results and error if any failed.
+///
+/// `noun` describes the verified items and `reference` what they were
+/// verified against. Successful results are only printed if `print_ok` is
+/// set.
+fn report_verify_results<T: std::fmt::Display>(
+    results: &[(T, VerifyResult)],
+    noun: &str,
+    reference: &str,
+    print_ok: bool,
+) -> Result<()> {
+    let mut failed = 0;
+
+    for (item, result) in results {
+        if !result.is_ok() {
+            failed += 1;
+        } else if !print_ok {
+            continue;
+        }
+
+        println!("{}: {}", item, result);
+    }
+
+    if failed > 0 {
+        Err(anyhow!(
+            "verification failed: {} of {} {} do not match the {}",
+            failed,
+            results.len(),
+            noun,
+            reference
+        ))
+    } else {
+        println!("verified {} {}", results.len(), noun);
+        Ok(())
+    }
+}
+
+/// Verify files against a build manifest.
+pub fn verify(manifest_path: &str, artifacts: &[&str]) -> Result<()> {
+    let artifacts: Vec<PathBuf> = artifacts.iter().map(PathBuf::from).collect();
+
+    let results = verify_build_manifest(Path::new(manifest_path), &artifacts)?;
+    let results = results
+        .into_iter()
+        .map(|(path, result)| (path.display().to_string(), result))
+        .collect::<Vec<_>>();
+
+    report_verify_results(&results, "files", "build manifest", true)
+}
+
+/// Verify the inputs of an evaluated config against its lockfile.
+fn verify_lockfile(config: &Config, target: &str) -> Result<()> {
+    let path = Lockfile::path_for_config(&config.config_path);
+
+    if !path.exists() {
+        return Err(anyhow!(
+            "{} does not exist; run `pyoxidizer lock` to create it",
+            path.display()
+        ));
+    }
+
+    let locked = Lockfile::from_path(&path)?;
+    let current = Lockfile::from_config(config, target)?;
+
+    report_verify_results(
+        &locked.verify(&current),
+        "inputs",
+        &format!("lockfile {}", path.display()),
+        false,
+    )
+    .map_err(|e| anyhow!("{}; run `pyoxidizer lock` to update it", e))
+}
+
+/// Check that a config file found for a lockfile is the one it belongs to.
+///
+/// `config_path` is the config file found from the lockfile's directory,
+/// possibly through `PYOXIDIZER_CONFIG`. Lockfiles are written next to their
+/// config file.
+fn lockfile_config_path(lockfile_path: &Path, config_path: Option<PathBuf>) -> Result<PathBuf> {
+    let config_path = config_path.ok_or_else(|| {
+        anyhow!(
+            "unable to find the PyOxidizer config file of lockfile {}",
+            lockfile_path.display()
+        )
+    })?;
+
+    let expected = resolve_logical_path(&Lockfile::path_for_config(&config_path))?;
+    if expected != lockfile_path {
+        return Err(anyhow!(
+            "lockfile {} doesn't belong to config file {}, whose lockfile is {}",
+            lockfile_path.display(),
+            config_path.display(),
+            expected.display()
+        ));
+    }
+
+    Ok(config_path)
+}
+
+/// Verify a project against a lockfile.
+///
+/// The config file of the lockfile is evaluated for the lockfile's target.
+pub fn verify_lockfile_path(
+    logger: &slog::Logger,
+    lockfile_path: &str,
+    options: &EvalOptions,
+) -> Result<()> {
+    let lockfile_path = resolve_logical_path(Path::new(lockfile_path))
+        .with_context(|| format!("resolving {}", lockfile_path))?;
+    let locked = Lockfile::from_path(&lockfile_path)?;
+
+    let lockfile_dir = lockfile_path
+        .parent()
+        .ok_or_else(|| anyhow!("unable to resolve parent directory of lockfile"))?;
+    let config_path = lockfile_config_path(
+        &lockfile_path,
+        find_pyoxidizer_config_file_env(logger, lockfile_dir),
+    )?;
+
+    let res =
+        eval_starlark_config_file(logger, &config_path, &locked.target_triple, false, options)?;
+    let current = Lockfile::from_config(&res.config, &locked.target_triple)?;
+
+    report_verify_results(
+        &locked.verify(&current),
+        "inputs",
+        &format!("lockfile {}", lockfile_path.display()),
+        true,
+    )
+}
+
+/// Resolve the inputs of a project and write its lockfile.
+pub fn lock(
+    logger: &slog::Logger,
+    project_path: &str,
+    target: Option<&str>,
+    options: &EvalOptions,
+) -> Result<()> {
+    let path = resolve_logical_path(&PathBuf::from(project_path))?;
+
+    let target = match target {
+        Some(v) => v.to_string(),
+        None => default_target()?,
+    };
+
+    let config_path = match find_pyoxidizer_config_file_env(logger, &path) {
+        Some(p) => p,
+        None => return Err(anyhow!("unable to find PyOxidizer config file")),
+    };
+
+    let res = eval_starlark_config_file(logger, &config_path, &target, false, options)?;
+    let lockfile = Lockfile::from_config(&res.config, &target)?;
+
+    let lockfile_path = Lockfile::path_for_config(&config_path);
+    lockfile.write_path(&lockfile_path)?;
+
+    println!(
+        "locked {} Python packages and {} files in {}",
+        lockfile.python_packages.len(),
+        lockfile.files.len(),
+        lockfile_path.display()
+    );
+
+    Ok(())
+}
+
+/// Remove artifacts PyOxidizer versions not owning an artifacts directory wrote into it.
+///
+/// Artifacts directories of all targets and profiles are cleaned. Each
+/// removed directory is printed.
+fn clean_other_versions(
+    logger: &slog::Logger,
+    context: &BuildContext,
+    dry_run: bool,
+) -> Result<()> {
+    // Artifacts are in <target>/<triple>/<profile>/pyoxidizer.
+    let mut artifacts_paths = Vec::new();
+    if context.target_base_path.is_dir() {
+        for entry in std::fs::read_dir(&context.target_base_path)? {
+            let triple_path = entry?.path();
+
+            for profile in &["debug", "release"] {
+                let path = triple_path.join(profile).join("pyoxidizer");
+                if path.is_dir() {
+                    artifacts_paths.push(path);
+                }
+            }
+        }
+    }
+    artifacts_paths.sort();
+
+    let mut removed = 0;
+    for artifacts_path in artifacts_paths {
+        if dry_run {
+            for path in version_dirs(&artifacts_path)? {
+                println!("would remove {}", path.display());
+                removed += 1;
+            }
+            continue;
+        }
+
+        let _lock = lock_artifacts_path(logger, &artifacts_path)?;
+
+        for path in version_dirs(&artifacts_path)? {
+            println!("removing {}", path.display());
+            remove_dir_all(&path)?;
+            removed += 1;
+        }
+    }
+
+    if removed == 0 {
+        println!("no artifacts of other PyOxidizer versions found");
+    }
+
+    Ok(())
+}
+
+/// Find the directories holding state generated for a project.
+///
+/// These are the Rust target directory, the PyOxidizer artifacts directories
+/// and the directories applications were packaged into, including those of
+/// other targets and previous output layouts. With `dist_cache`, the
+/// directory distributions are downloaded to without a distribution cache
+/// is included. Paths are canonical, exist and aren't inside each other.
+fn generated_state_paths(contexts: &[BuildContext], dist_cache: bool) -> Result<Vec<PathBuf>> {
+    let mut candidates = Vec::new();
+
+    for context in contexts {
+        candidates.push(context.target_base_path.clone());
+        candidates.push(context.pyoxidizer_artifacts_root_path.clone());
+        candidates.push(context.app_path.clone());
+
+        let recorded = OutputDirectories::from_build_path(&context.build_path)?;
+        candidates.extend(
+            recorded
+                .directories
+                .values()
+                .map(|path| context.build_path.join(path)),
+        );
+
+        if dist_cache {
+            candidates.push(context.build_path.join(PYTHON_DISTRIBUTIONS_DIR));
+        }
+    }
+
+    let paths = candidates
+        .iter()
+        .filter(|path| path.exists())
+        .map(|path| {
+            canonicalize_path(path).with_context(|| format!("resolving {}", path.display()))
+        })
+        .collect::<Result<BTreeSet<_>>>()?;
+
+    Ok(paths
+        .iter()
+        .filter(|path| {
+            !paths
+                .iter()
+                .any(|other| other != *path && path.starts_with(other))
+        })
+        .cloned()
+        .collect())
+}
+
+/// Fail unless `clean` may remove a path.
+///
+/// Whatever the config says, only paths inside the project directory not
+/// containing any of the `protected` paths may be removed. All paths must
+/// be canonical.
+fn check_clean_path(path: &Path, project_path: &Path, protected: &[PathBuf]) -> Result<()> {
+    if path == project_path {
+        return Err(anyhow!(
+            "refusing to remove {}: it is the project directory",
+            path.display()
+        ));
+    }
+
+    if !path.starts_with(project_path) {
+        return Err(anyhow!(
+            "refusing to remove {}: it is outside the project directory {}",
+            path.display(),
+            project_path.display()
+        ));
+    }
+
+    if let Some(protected) = protected.iter().find(|p| p.starts_with(path)) {
+        return Err(anyhow!(
+            "refusing to remove {}: it contains {}",
+            path.display(),
+            protected.display()
+        ));
+    }
+
+    Ok(())
+}
+
+/// Remove state generated for a project.
+///
+/// The Rust target directory, PyOxidizer artifacts and packaged applications
+/// of both profiles are removed. With `dist_cache`, the project's Python
+/// distributions are also removed from the distribution cache, unless a
+/// build is using them. Nothing is removed if any path is outside the
+/// project directory. With `dry_run`, what would be removed is printed
+/// instead. With `other_versions`, only artifacts of other PyOxidizer
+/// versions are removed.
+pub fn clean(
+    logger: &slog::Logger,
+    project_path: &str,
+    target: Option<&str>,
+    dry_run: bool,
+    dist_cache: bool,
+    other_versions: bool,
+    options: &EvalOptions,
+) -> Result<()> {
+    let contexts = [false, true]
+        .iter()
+        .map(|release| {
+            resolve_build_context(
+                logger,
+                project_path,
+                None,
+                target,
+                *release,
+                None,
+                Verbosity::Normal,
+                false,
+                options,
+            )
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    if other_versions {
+        return clean_other_versions(logger, &contexts[0], dry_run);
+    }
+
+    let project_path = canonicalize_path(&contexts[0].project_path)?;
+    let protected = [
+        contexts[0].config_path.clone(),
+        contexts[0].project_path.join("Cargo.toml"),
+    ]
+    .iter()
+    .map(|path| canonicalize_path(path).with_context(|| format!("resolving {}", path.display())))
+    .collect::<Result<Vec<_>>>()?;
+
+    let paths = generated_state_paths(&contexts, dist_cache)?;
+    for path in &paths {
+        check_clean_path(path, &project_path, &protected).category(ErrorCategory::Config)?;
+    }
+
+    let mut dists = Vec::new();
+    if dist_cache {
+        if let Some(dir) = distribution_cache_dir() {
+            let hashes = contexts
+                .iter()
+                .map(|context| context.config.python_distribution.sha256().to_lowercase())
+                .collect::<BTreeSet<_>>();
+
+            dists = cached_distributions(&dir)?
+                .into_iter()
+                .filter(|dist| hashes.contains(&dist.sha256))
+                .collect();
+        }
+    }
+
+    if paths.is_empty() && dists.is_empty() {
+        println!("nothing to clean");
+        return Ok(());
+    }
+
+    for path in &paths {
+        if dry_run {
+            println!("would remove {}", path.display());
+        } else {
+            println!("removing {}", path.display());
+            remove_dir_all(path)?;
+        }
+    }
+
+    for dist in &dists {
+        if dry_run {
+            println!("would remove Python distribution {}", dist.path.display());
+        } else if remove_cached_distribution(dist)? {
+            println!("removed Python distribution {}", dist.path.display());
+        } else {
+            warn!(
+                logger,
+                "keeping Python distribution {}: a build is using it",
+                dist.path.display()
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Write the versions of packages installed by `pip_install()` to a constraints file.
+///
+/// Changes to the pins of an existing constraints file are printed.
+pub fn freeze_deps(
+    logger: &slog::Logger,
+    project_path: &str,
+    target: Option<&str>,
+    output: Option<&str>,
+    options: &EvalOptions,
+) -> Result<()> {
+    let path = resolve_logical_path(&PathBuf::from(project_path))?;
+
+    let target = match target {
+        Some(v) => v.to_string(),
+        None => default_target()?,
+    };
+
+    let config_path = match find_pyoxidizer_config_file_env(logger, &path) {
+        Some(p) => p,
+        None => return Err(anyhow!("unable to find PyOxidizer config file")),
+    };
+
+    let res = eval_starlark_config_file(logger, &config_path, &target, false, options)?;
+    let versions = &res.config.python_package_versions;
+
+    let constraints_path = match output {
+        Some(p) => PathBuf::from(p),
+        None => config_path
+            .parent()
+            .ok_or_else(|| anyhow!("unable to resolve directory of config file"))?
+            .join("constraints.txt"),
+    };
+
+    if constraints_path.exists() {
+        let previous = requirements_from_pip_args(&[
+            "-c".to_string(),
+            constraints_path.display().to_string(),
+        ])?
+        .iter()
+        .map(|r| (normalize_name(&r.name), r.specifiers_string()))
+        .collect();
+        let current = versions
+            .iter()
+            .map(|(name, version)| (normalize_name(name), format!("=={}", version)))
+            .collect();
+
+        for difference in diff_config_values(&previous, &current) {
+            println!("{}", difference);
+        }
+    }
+
+    std::fs::write(&constraints_path, format_constraints(versions))
+        .with_context(|| format!("writing {}", constraints_path.display()))?;
+
+    println!(
+        "froze {} Python packages in {}",
+        versions.len(),
+        constraints_path.display()
+    );
+
+    Ok(())
+}
+
+/// Print the behavioral differences between two configs.
+///
+/// Errors if differences exist.
+pub fn diff_config(
+    logger: &slog::Logger,
+    config_a: &str,
+    config_b: &str,
+    target: Option<&str>,
+    other_target: Option<&str>,
+    options: &EvalOptions,
+) -> Result<()> {
+    let target = match target {
+        Some(v) => v.to_string(),
+        None => default_target()?,
+    };
+    let other_target = match other_target {
+        Some(v) => v.to_string(),
+        None => target.clone(),
+    };
+
+    let config_a = resolve_logical_path(Path::new(config_a))
+        .with_context(|| format!("resolving {}", config_a))?;
+    let config_b = resolve_logical_path(Path::new(config_b))
+        .with_context(|| format!("resolving {}", config_b))?;
+
+    let a = eval_starlark_config_file(logger, &config_a, &target, false, options)?;
+    let b = eval_starlark_config_file(logger, &config_b, &other_target, false, options)?;
+
+    let differences = diff_configs(&a.config, &b.config)?;
+
+    if differences.is_empty() {
+        println!(
+            "{} ({}) and {} ({}) are equivalent",
+            config_a.display(),
+            target,
+            config_b.display(),
+            other_target
+        );
+        return Ok(());
+    }
+
+    for difference in &differences {
+        println!("{}", difference);
+    }
+
+    Err(anyhow!(
+        "{} values differ between {} ({}) and {} ({})",
+        differences.len(),
+        config_a.display(),
+        target,
+        config_b.display(),
+        other_target
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app_packaging::config::SplitDebugInfo;
+    use crate::app_packaging::lockfile::{
+        input_digests, sha256_files, sha256_paths, HASH_THREADS, LOCKFILE_FILENAME,
+    };
+    use crate::app_packaging::staleness::artifact_inputs;
+    use crate::app_packaging::toolchain::Toolchain;
+    use crate::color::ColorChoice;
+    use crate::environment::OFFLINE_ENV;
+    use crate::py_packaging::config::RawAllocator;
+    use crate::python_distributions::no_default_distribution_message;
+    use crate::testutil::*;
+    use std::fs;
+
+    #[test]
+    fn test_parse_rustc_host() -> Result<()> {
+        let output = |host: &str| {
+            format!(
+                "rustc 1.40.0 (73528e339 2019-12-16)\n\
+                 binary: rustc\n\
+                 commit-hash: 73528e339aae0f17a15ffa49a8ac608f50c6cf14\n\
+                 commit-date: 2019-12-16\n\
+                 host: {}\n\
+                 release: 1.40.0\n\
+                 LLVM version: 9.0\n",
+                host
+            )
+        };
+
+        for host in &[
+            "x86_64-unknown-linux-gnu",
+            "aarch64-unknown-linux-gnu",
+            "x86_64-unknown-linux-musl",
+            "aarch64-apple-darwin",
+            "i686-pc-windows-msvc",
+        ] {
+            assert_eq!(parse_rustc_host(&output(host))?, *host);
+        }
+
+        assert_eq!(
+            parse_rustc_host("host:   x86_64-pc-windows-msvc\r\n")?,
+            "x86_64-pc-windows-msvc"
+        );
+        assert!(parse_rustc_host("rustc 1.40.0\nhost:\n").is_err());
+        assert!(parse_rustc_host("").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_default_target() -> Result<()> {
+        // The test suite is run with the host's toolchain.
+        assert_eq!(default_target()?, HOST);
+
+        let err = no_default_distribution_message("aarch64-unknown-linux-gnu");
+        assert!(err.starts_with(
+            "no default Python distribution is available for aarch64-unknown-linux-gnu; default distributions exist for "
+        ));
+        assert!(err.contains("x86_64-unknown-linux-gnu"));
+
+        // Supported targets lacking a default say so rather than suggesting
+        // another target.
+        let err = no_default_distribution_message("aarch64-apple-darwin");
+        assert!(err.starts_with(
+            "aarch64-apple-darwin is supported but no default Python distribution exists for it yet"
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_force_artifacts() -> Result<()> {
+        let logger = get_logger()?;
+        let temp_dir = tempdir::TempDir::new("pyoxidizer-test")?;
+        let project_path = temp_dir.path();
+
+        fs::write(
+            project_path.join("Cargo.toml"),
+            "[package]\nname = \"myapp\"\nversion = \"0.1.0\"\n",
+        )?;
+        create_dir_all(project_path.join("src"))?;
+        fs::write(project_path.join("src").join("main.rs"), "fn main() {}\n")?;
+        fs::write(project_path.join("dist.tar.zst"), b"distribution")?;
+        fs::write(
+            project_path.join("pyoxidizer.bzl"),
+            "dist = PythonDistribution(sha256='0123456789abcdef', local_path='dist.tar.zst')\n\
+             Config(\n\
+             \x20   application_name='myapp',\n\
+             \x20   embedded_python_config=EmbeddedPythonConfig(),\n\
+             \x20   python_distribution=dist,\n\
+             \x20   python_run_mode=python_run_mode_noop(),\n\
+             )\n",
+        )?;
+
+        let mut context = resolve_build_context(
+            &logger,
+            &project_path.display().to_string(),
+            None,
+            Some("x86_64-unknown-linux-gnu"),
+            false,
+            Some(&project_path.join("artifacts")),
+            Verbosity::Normal,
+            false,
+            &EvalOptions::default(),
+        )?;
+
+        // Write the artifacts a previous build would have left behind.
+        let artifacts_path = &context.pyoxidizer_artifacts_path;
+        create_dir_all(artifacts_path)?;
+        let state = PackagingState {
+            license_files_path: None,
+            license_infos: Default::default(),
+            rustflags: context.rustflags.clone(),
+            input_digests: input_digests(&context.config)?,
+            config_digest: context.config.digest(),
+            license_filter_decisions: Vec::new(),
+            notice_components: Vec::new(),
+        };
+        serde_cbor::to_writer(
+            fs::File::create(artifacts_path.join("packaging_state.cbor"))?,
+            &state,
+        )?;
+        let cargo_metadata = format!(
+            "cargo:rerun-if-changed={}\ncargo:rerun-if-changed={}\n",
+            project_path.join("src/main.rs").display(),
+            project_path.join("Cargo.toml").display()
+        );
+        fs::write(artifacts_path.join("cargo_metadata.txt"), &cargo_metadata)?;
+
+        // Without an inputs manifest, artifacts are stale.
+        let report = artifacts_need_building(&logger, &context, artifacts_path);
+        assert!(report.is_stale());
+        assert_eq!(report.changed_files(), Vec::<&Path>::new());
+
+        InputsManifest::from_paths(&artifact_inputs(
+            &cargo_metadata,
+            &context.config_path,
+            &std::env::current_exe()?,
+        ))?
+        .write(&artifacts_path.join(INPUTS_MANIFEST_FILENAME))?;
+
+        assert!(!artifacts_need_building(&logger, &context, artifacts_path).is_stale());
+
+        // Modification times don't matter.
+        let future =
+            filetime::FileTime::from_unix_time(filetime::FileTime::now().unix_seconds() + 3600, 0);
+        for name in &["src/main.rs", "Cargo.toml", "pyoxidizer.bzl"] {
+            filetime::set_file_mtime(project_path.join(name), future)?;
+        }
+        assert!(!artifacts_need_building(&logger, &context, artifacts_path).is_stale());
+
+        // All changed files are reported.
+        fs::write(project_path.join("src/main.rs"), "fn main() {}\n")?;
+        let mut cargo_toml = fs::read_to_string(project_path.join("Cargo.toml"))?;
+        cargo_toml.push_str("\n[dependencies]\n");
+        fs::write(project_path.join("Cargo.toml"), cargo_toml)?;
+        let report = artifacts_need_building(&logger, &context, artifacts_path);
+        assert_eq!(
+            report.changed_files(),
+            vec![
+                project_path.join("Cargo.toml").as_path(),
+                project_path.join("src/main.rs").as_path()
+            ]
+        );
+        assert!(report
+            .summary()
+            .starts_with("artifacts stale: 2 changed files (first: "));
+
+        // Changes only affecting the result of evaluating the config are
+        // noticed as well.
+        let mut config = context.config.clone();
+        config.build_config.application_name = "otherapp".to_string();
+        assert!(
+            artifacts_staleness(&config, artifacts_path, &context.rustflags)
+                .reasons
+                .contains(&StaleReason::ConfigChanged)
+        );
+
+        context.force_artifacts = true;
+        assert_eq!(
+            artifacts_need_building(&logger, &context, artifacts_path).reasons,
+            vec![StaleReason::Forced]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_forced_build_rewrites() -> Result<()> {
+        use filetime::FileTime;
+
+        let logger = get_logger()?;
+        let (temp_dir, _) = build_template_project(&logger, ProjectTemplate::Cli)?;
+        let project_path = temp_dir.path().join("myapp");
+
+        let context = resolve_build_context(
+            &logger,
+            &project_path.display().to_string(),
+            None,
+            None,
+            false,
+            None,
+            Verbosity::Normal,
+            false,
+            &EvalOptions::default(),
+        )?;
+        let paths = ArtifactPaths::new(&context);
+        let build_rs = project_path.join("pyembed").join("build.rs");
+        let watched = vec![
+            paths.data_rs.clone(),
+            paths.cargo_metadata.clone(),
+            paths.py_modules.clone(),
+            paths.packaging_state.clone(),
+            build_rs,
+        ];
+
+        // Dated back so rewrites are noticed without waiting.
+        let past = FileTime::from_unix_time(FileTime::now().unix_seconds() - 3600, 0);
+        for path in &watched {
+            filetime::set_file_mtime(path, past)?;
+        }
+        let data_rs = fs::read(&paths.data_rs)?;
+        let cargo_metadata = fs::read(&paths.cargo_metadata)?;
+
+        let build = |force: bool| {
+            build_one_target(
+                &logger,
+                &project_path.display().to_string(),
+                None,
+                false,
+                Verbosity::Normal,
+                false,
+                false,
+                force,
+                force,
+                true,
+                false,
+                &ExtraCargoArgs::default(),
+                &EvaluationCache::default(),
+            )
+        };
+        let mtimes = || -> Result<Vec<FileTime>> {
+            watched
+                .iter()
+                .map(|path| Ok(FileTime::from_last_modification_time(&fs::metadata(path)?)))
+                .collect()
+        };
+
+        // Inputs are compared by content, so old modification times alone
+        // don't cause rewrites.
+        build(false)?;
+        assert_eq!(mtimes()?, vec![past; watched.len()]);
+
+        // Forcing rewrites the artifacts and touches the build script
+        // although nothing changed.
+        build(true)?;
+        for (path, mtime) in watched.iter().zip(mtimes()?) {
+            assert!(mtime > past, "{} wasn't rewritten", path.display());
+        }
+        assert_eq!(fs::read(&paths.data_rs)?, data_rs);
+        assert_eq!(fs::read(&paths.cargo_metadata)?, cargo_metadata);
+
+        Ok(())
+    }
+
+    /// Compares the no-op path of `artifacts_staleness()` for a large tree
+    /// against hashing every `cargo:rerun-if-changed` line on its own and
+    /// the inputs referenced by the config separately, as was done before
+    /// inputs were collapsed.
+    ///
+    /// Run with `cargo test --release -- --ignored --nocapture bench_`.
+    #[test]
+    #[ignore]
+    fn bench_artifacts_staleness() -> Result<()> {
+        use std::time::Instant;
+
+        let logger = get_logger()?;
+        let temp_dir = tempdir::TempDir::new("pyoxidizer-test")?;
+        let project_path = temp_dir.path();
+
+        fs::write(
+            project_path.join("Cargo.toml"),
+            "[package]\nname = \"myapp\"\nversion = \"0.1.0\"\n",
+        )?;
+        fs::write(project_path.join("dist.tar.zst"), b"distribution")?;
+        fs::write(
+            project_path.join("pyoxidizer.bzl"),
+            "dist = PythonDistribution(sha256='0123456789abcdef', local_path='dist.tar.zst')\n\
+             Config(\n\
+             \x20   application_name='myapp',\n\
+             \x20   embedded_python_config=EmbeddedPythonConfig(),\n\
+             \x20   python_distribution=dist,\n\
+             \x20   python_run_mode=python_run_mode_noop(),\n\
+             )\n",
+        )?;
+
+        let context = resolve_build_context(
+            &logger,
+            &project_path.display().to_string(),
+            None,
+            Some("x86_64-unknown-linux-gnu"),
+            false,
+            Some(&project_path.join("artifacts")),
+            Verbosity::Normal,
+            false,
+            &EvalOptions::default(),
+        )?;
+
+        // The config references the tree, as when it adds its modules.
+        let tree = project_path.join("app");
+        let files = generate_tree(&tree, 100_000)?;
+        let mut config = context.config.clone();
+        config.input_paths.insert(tree.clone());
+
+        let artifacts_path = &context.pyoxidizer_artifacts_path;
+        create_dir_all(artifacts_path)?;
+        let cargo_metadata = tree_metadata(&tree, &files);
+        fs::write(artifacts_path.join("cargo_metadata.txt"), &cargo_metadata)?;
+        InputsManifest::from_paths(&artifact_inputs(
+            &cargo_metadata,
+            &config.config_path,
+            &std::env::current_exe()?,
+        ))?
+        .write(&artifacts_path.join(INPUTS_MANIFEST_FILENAME))?;
+        let state = PackagingState {
+            license_files_path: None,
+            license_infos: Default::default(),
+            rustflags: context.rustflags.clone(),
+            input_digests: input_digests(&config)?,
+            config_digest: config.digest(),
+            license_filter_decisions: Vec::new(),
+            notice_components: Vec::new(),
+        };
+        serde_cbor::to_writer(
+            fs::File::create(artifacts_path.join("packaging_state.cbor"))?,
+            &state,
+        )?;
+
+        let start = Instant::now();
+        for line in cargo_metadata.lines() {
+            let mut paths = BTreeSet::new();
+            paths.insert(PathBuf::from(&line["cargo:rerun-if-changed=".len()..]));
+            sha256_paths(&paths, &[])?;
+        }
+        input_digests(&config)?;
+        let before = start.elapsed();
+
+        let start = Instant::now();
+        let report = artifacts_staleness(&config, artifacts_path, &context.rustflags);
+        let after = start.elapsed();
+        assert!(!report.is_stale(), "{}", report.summary());
+
+        let start = Instant::now();
+        sha256_files(&files, 1);
+        let serial = start.elapsed();
+        let start = Instant::now();
+        sha256_files(&files, HASH_THREADS);
+        let parallel = start.elapsed();
+
+        eprintln!(
+            "{} files: {} inputs hashed individually in {:?}; artifacts found current in {:?}",
+            files.len(),
+            cargo_metadata.lines().count(),
+            before,
+            after
+        );
+        eprintln!(
+            "hashing files: {:?} on 1 thread, {:?} on {} threads",
+            serial, parallel, HASH_THREADS
+        );
+
+        Ok(())
+    }
+
+    /// Create a project whose config uses a bogus Python distribution and
+    /// whose Rust code doesn't compile.
+    fn failing_project(config_prefix: &str) -> Result<tempdir::TempDir> {
+        let temp_dir = tempdir::TempDir::new("pyoxidizer-test")?;
+        let project_path = temp_dir.path();
+
+        fs::write(
+            project_path.join("Cargo.toml"),
+            "[package]\nname = \"myapp\"\nversion = \"0.1.0\"\n",
+        )?;
+        create_dir_all(project_path.join("src"))?;
+        fs::write(project_path.join("src").join("main.rs"), "fn main() {\n")?;
+        fs::write(project_path.join("dist.tar.zst"), b"distribution")?;
+        fs::write(
+            project_path.join("pyoxidizer.bzl"),
+            format!(
+                "{}\n\
+                 dist = PythonDistribution(sha256='0123456789abcdef', local_path='dist.tar.zst')\n\
+                 Config(\n\
+                 \x20   application_name='myapp',\n\
+                 \x20   embedded_python_config=EmbeddedPythonConfig(),\n\
+                 \x20   python_distribution=dist,\n\
+                 \x20   python_run_mode=python_run_mode_noop(),\n\
+                 )\n",
+                config_prefix
+            ),
+        )?;
+
+        Ok(temp_dir)
+    }
+
+    fn failing_context(logger: &slog::Logger, project_path: &Path) -> Result<BuildContext> {
+        let mut context = resolve_build_context(
+            logger,
+            &project_path.display().to_string(),
+            None,
+            Some(env!("HOST")),
+            false,
+            Some(&project_path.join("artifacts")),
+            Verbosity::Normal,
+            false,
+            &EvalOptions::default(),
+        )?;
+        context.skip_space_check = true;
+
+        Ok(context)
+    }
+
+    fn assert_exit_code<T>(result: Result<T>, code: i32) {
+        let result = result.map(|_| ());
+        assert!(result.is_err());
+        assert_eq!(crate::exit_status::exit_code(&result, false), code);
+        assert_eq!(crate::exit_status::exit_code(&result, true), 1);
+    }
+
+    #[test]
+    fn test_exit_code_config() -> Result<()> {
+        let logger = get_logger()?;
+        let project = failing_project("Config(")?;
+
+        assert_exit_code(failing_context(&logger, project.path()), 10);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_exit_code_distribution() -> Result<()> {
+        let logger = get_logger()?;
+        let project = failing_project("")?;
+        let mut context = failing_context(&logger, project.path())?;
+
+        assert_exit_code(build_pyoxidizer_artifacts(&logger, &mut context), 11);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_exit_code_packaging() -> Result<()> {
+        let logger = get_logger()?;
+        let project = failing_project("FileManifest().install('out')")?;
+
+        // Installing replaces a directory, which fails on a file.
+        create_dir_all(project.path().join("build"))?;
+        fs::write(project.path().join("build").join("out"), b"")?;
+
+        assert_exit_code(failing_context(&logger, project.path()), 12);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_exit_code_compile() -> Result<()> {
+        let logger = get_logger()?;
+        let project = failing_project("")?;
+        let context = failing_context(&logger, project.path())?;
+        create_dir_all(&context.pyoxidizer_artifacts_path)?;
+
+        assert_exit_code(cargo_build(&logger, &context, Path::new("python3")), 13);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_exit_code_run() -> Result<()> {
+        let project = failing_project("")?;
+
+        assert_exit_code(
+            run_app(
+                &project.path().join("missing-app"),
+                project.path(),
+                &[],
+                &[],
+            ),
+            14,
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_exit_code_run_app_failed() -> Result<()> {
+        let project = failing_project("")?;
+        let sh = Path::new("/bin/sh");
+
+        let err = run_app(sh, project.path(), &["-c", "exit 3"], &[]).unwrap_err();
+        assert_eq!(err.to_string(), "sh exited with code 3");
+        assert_exit_code(Err::<(), _>(err), 3);
+
+        let err = run_app(sh, project.path(), &["-c", "kill -SEGV $$"], &[]).unwrap_err();
+        assert_eq!(err.to_string(), "sh terminated by SIGSEGV");
+        assert_exit_code(Err::<(), _>(err), 139);
+
+        run_app(sh, project.path(), &["-c", "exit 0"], &[])?;
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_run_app_env_cwd() -> Result<()> {
+        let project = failing_project("")?;
+        let cwd = project.path().join("data");
+        std::fs::create_dir(&cwd)?;
+
+        let env = crate::app_packaging::run_env::parse_env_file("MYAPP_CONFIG=a=b\nEMPTY=\n")?;
+        run_app(
+            Path::new("/bin/sh"),
+            &cwd,
+            &[
+                "-c",
+                "[ \"$MYAPP_CONFIG\" = a=b ] && [ \"${EMPTY-unset}\" = \"\" ] && [ \"$(basename \"$PWD\")\" = data ]",
+            ],
+            &env,
+        )?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_clean() -> Result<()> {
+        let logger = get_logger()?;
+        let project = failing_project("")?;
+        let path = project.path().display().to_string();
+        let target = "x86_64-unknown-linux-gnu";
+        let build_path = project.path().join("build");
+
+        let generated = [
+            build_path
+                .join("target")
+                .join(target)
+                .join("debug")
+                .join("pyoxidizer"),
+            build_path
+                .join("apps")
+                .join("myapp")
+                .join(target)
+                .join("debug"),
+            build_path
+                .join("apps")
+                .join("myapp")
+                .join(target)
+                .join("release"),
+        ];
+        let kept = [
+            build_path.join("distribution"),
+            build_path.join(PYTHON_DISTRIBUTIONS_DIR),
+            project.path().join("src"),
+        ];
+        for dir in generated.iter().chain(kept.iter()) {
+            create_dir_all(dir)?;
+            fs::write(dir.join("file"), b"data")?;
+        }
+
+        clean(
+            &logger,
+            &path,
+            Some(target),
+            true,
+            true,
+            false,
+            &EvalOptions::default(),
+        )?;
+        assert!(generated.iter().all(|dir| dir.join("file").exists()));
+
+        clean(
+            &logger,
+            &path,
+            Some(target),
+            false,
+            false,
+            false,
+            &EvalOptions::default(),
+        )?;
+        assert!(!build_path.join("target").exists());
+        assert!(!build_path.join("apps").join("myapp").join(target).exists());
+        assert!(kept.iter().all(|dir| dir.join("file").exists()));
+        assert!(project.path().join("Cargo.toml").exists());
+
+        clean(
+            &logger,
+            &path,
+            Some(target),
+            false,
+            true,
+            false,
+            &EvalOptions::default(),
+        )?;
+        assert!(!build_path.join(PYTHON_DISTRIBUTIONS_DIR).exists());
+        assert!(build_path.join("distribution").exists());
+
+        // Nothing left to clean.
+        clean(
+            &logger,
+            &path,
+            Some(target),
+            false,
+            true,
+            false,
+            &EvalOptions::default(),
+        )?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_clean_refuses_outside_project() -> Result<()> {
+        let logger = get_logger()?;
+        let elsewhere = tempdir::TempDir::new("pyoxidizer-test")?;
+        let target = "x86_64-unknown-linux-gnu";
+        let target_path = elsewhere.path().join("target").join(target);
+        create_dir_all(&target_path)?;
+
+        let project = failing_project(&format!(
+            "set_build_path('{}')",
+            elsewhere.path().display().to_string().replace("\\", "/")
+        ))?;
+        let path = project.path().display().to_string();
+
+        let err = clean(
+            &logger,
+            &path,
+            Some(target),
+            false,
+            false,
+            false,
+            &EvalOptions::default(),
+        )
+        .unwrap_err();
+        assert!(err.to_string().starts_with(&format!(
+            "refusing to remove {}: it is outside the project directory",
+            canonicalize_path(&elsewhere.path().join("target"))?.display()
+        )));
+        assert_exit_code::<()>(Err(err), 10);
+        assert!(target_path.exists());
+
+        // Recorded output directories can't point at the project either.
+        let project = failing_project("")?;
+        let build_path = project.path().join("build");
+        create_dir_all(build_path.join("target"))?;
+        fs::write(
+            build_path.join("output-directories.json"),
+            "{\"directories\": {\"myapp/x86_64-unknown-linux-gnu/debug\": \"..\"}}",
+        )?;
+        let path = project.path().display().to_string();
+
+        let err = clean(
+            &logger,
+            &path,
+            Some(target),
+            false,
+            false,
+            false,
+            &EvalOptions::default(),
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "refusing to remove {}: it is the project directory",
+                canonicalize_path(project.path())?.display()
+            )
+        );
+        assert!(project.path().join("Cargo.toml").exists());
+        assert!(build_path.join("target").exists());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_run_app_args() -> Result<()> {
+        let dist = get_default_distribution()?;
+        let project = failing_project("")?;
+        let out = project.path().join("argv.json");
+
+        let app_args = [
+            "--help",
+            "--config=/etc/app.toml",
+            "",
+            "two words",
+            "--",
+            "-",
+            "-x",
+            "--opt=a=b",
+            "=",
+            "ünïcødé ✓",
+            "--env",
+            "K=V",
+        ];
+
+        let mut argv = vec!["pyoxidizer", "run", "--release", ".", "--"];
+        argv.extend(&app_args);
+        let argv = argv
+            .iter()
+            .map(std::ffi::OsString::from)
+            .collect::<Vec<_>>();
+        let (ours, theirs) = crate::app_packaging::run_env::split_app_args(&argv)?;
+        assert_eq!(ours.len(), 4);
+        let theirs = theirs.unwrap();
+
+        let mut args = vec![
+            "-c",
+            "import json, os, sys; json.dump(sys.argv[1:], open(os.environ['ARGV_OUT'], 'w'))",
+        ];
+        args.extend(theirs.iter().map(|arg| arg.as_str()));
+
+        run_app(
+            &dist.python_exe,
+            project.path(),
+            &args,
+            &[("ARGV_OUT".to_string(), out.display().to_string())],
+        )?;
+
+        let observed: Vec<String> = serde_json::from_slice(&fs::read(&out)?)?;
+        assert_eq!(observed, app_args);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_build_multiple_targets() -> Result<()> {
+        let logger = get_logger()?;
+        let project = failing_project("")?;
+        let path = project.path().display().to_string();
+        let targets = [env!("HOST"), "x86_64-pc-windows-msvc", env!("HOST")];
+
+        let build_targets = |fail_fast| {
+            let options = BuildOptions {
+                fail_fast,
+                skip_space_check: true,
+                ..BuildOptions::default()
+            };
+
+            build(
+                &logger,
+                &path,
+                &targets,
+                &options,
+                &CancellationToken::new(),
+            )
+        };
+
+        // Failing targets don't stop the others and duplicates are ignored.
+        let result = build_targets(false);
+        assert_eq!(
+            format!("{}", result.as_ref().unwrap_err()),
+            format!(
+                "building failed for 2 of 2 targets: {}, x86_64-pc-windows-msvc",
+                env!("HOST")
+            )
+        );
+        assert_exit_code(result, 11);
+
+        let result = build_targets(true);
+        assert_eq!(
+            format!("{}", result.as_ref().unwrap_err()),
+            format!(
+                "building failed for 1 of 2 targets: {}; 1 not built because of --fail-fast",
+                env!("HOST")
+            )
+        );
+        assert_exit_code(result, 11);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_format_target_outcomes() {
+        let outcomes = vec![
+            TargetOutcome {
+                target: "x86_64-unknown-linux-gnu".to_string(),
+                result: Ok(PathBuf::from(
+                    "build/apps/myapp/x86_64-unknown-linux-gnu/debug/myapp",
+                )),
+            },
+            TargetOutcome {
+                target: "x86_64-pc-windows-msvc".to_string(),
+                result: Err(categorize(anyhow!("cargo failed"), ErrorCategory::Compile)),
+            },
+        ];
+
+        assert_eq!(
+            format_target_outcomes(&outcomes),
+            "TARGET                    STATUS  PATH\n\
+             x86_64-unknown-linux-gnu  ok      build/apps/myapp/x86_64-unknown-linux-gnu/debug/myapp\n\
+             x86_64-pc-windows-msvc    failed  (compile)\n"
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_symlinked_project() -> Result<()> {
+        let logger = get_logger()?;
+        let project = failing_project("")?;
+        let links = tempdir::TempDir::new("pyoxidizer-test")?;
+        let link = canonicalize_path(links.path())?.join("project");
+        std::os::unix::fs::symlink(project.path(), &link)?;
+
+        let context = failing_context(&logger, &link)?;
+        assert_eq!(context.project_path, link);
+        assert_eq!(context.config_path, link.join("pyoxidizer.bzl"));
+        assert!(context.pyoxidizer_artifacts_path.starts_with(&link));
+
+        // Cargo passes build scripts the project path it was given.
+        let (args, _) = cargo_build_invocation(&context, Path::new("python"));
+        let manifest_path = link.join("Cargo.toml").display().to_string();
+        assert!(args
+            .windows(2)
+            .any(|w| w[0] == "--manifest-path" && w[1] == manifest_path));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_offline_cargo_invocation() -> Result<()> {
+        let logger = get_logger()?;
+        let project = failing_project("")?;
+        let mut context = failing_context(&logger, project.path())?;
+        context.offline = true;
+        context
+            .build_vars
+            .insert("customer".to_string(), "acme".to_string());
+
+        let (args, envs) = cargo_build_invocation(&context, Path::new("python"));
+        assert!(args.contains(&"--offline".to_string()));
+        assert!(envs.contains(&(OFFLINE_ENV.to_string(), "1".to_string())));
+        assert!(envs.contains(&("PYOXIDIZER_VAR_customer".to_string(), "acme".to_string())));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_verbosity_cargo_invocation() -> Result<()> {
+        let logger = get_logger()?;
+        let project = failing_project("")?;
+        let mut context = failing_context(&logger, project.path())?;
+
+        let cargo_args = |context: &BuildContext| {
+            let (args, _) = cargo_build_invocation(context, Path::new("python"));
+            args.into_iter()
+                .filter(|arg| arg == "-q" || arg == "-vv")
+                .collect::<Vec<_>>()
+        };
+
+        assert!(cargo_args(&context).is_empty());
+        context.verbosity = Verbosity::Quiet;
+        assert_eq!(cargo_args(&context), vec!["-q"]);
+        context.verbosity = Verbosity::Verbose;
+        assert_eq!(cargo_args(&context), vec!["-vv"]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_extra_cargo_args_parse() -> Result<()> {
+        let parsed =
+            ExtraCargoArgs::parse(&["--locked", "--features", "a,b", "-j", "4", "--features=b c"])?;
+        assert_eq!(parsed.args, vec!["--locked", "-j", "4"]);
+        assert_eq!(parsed.features, vec!["a", "b", "c"]);
+
+        for args in &[
+            vec!["--target", "x86_64-pc-windows-msvc"],
+            vec!["--target-dir=elsewhere"],
+            vec!["--color", "always"],
+        ] {
+            let err = ExtraCargoArgs::parse(args).err().unwrap();
+            assert!(err.to_string().contains("conflicts with PyOxidizer"));
+        }
+
+        assert!(ExtraCargoArgs::parse(&["--features"]).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_extra_cargo_args_invocation() -> Result<()> {
+        let logger = get_logger()?;
+        let project = failing_project("")?;
+        let mut context = failing_context(&logger, project.path())?;
+        context.config.embedded_python_config.raw_allocator = RawAllocator::Jemalloc;
+        context.offline = true;
+        context.extra_cargo_args =
+            ExtraCargoArgs::parse(&["--offline", "--features", "jemalloc,my-feature", "-j", "4"])?;
+
+        let (args, _) = cargo_build_invocation(&context, Path::new("python"));
+        assert_eq!(args.iter().filter(|a| *a == "--features").count(), 1);
+        assert!(args
+            .windows(2)
+            .any(|w| w == ["--features", "jemalloc,my-feature"]));
+        assert!(args.windows(2).any(|w| w == ["-j", "4"]));
+        assert_eq!(args.iter().filter(|a| *a == "--offline").count(), 1);
+
+        context.config.embedded_python_config.raw_allocator = RawAllocator::Mimalloc;
+        let (args, _) = cargo_build_invocation(&context, Path::new("python"));
+        assert!(args
+            .windows(2)
+            .any(|w| w == ["--features", "mimalloc,jemalloc,my-feature"]));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_split_debug_info_rustflags() -> Result<()> {
+        let logger = get_logger()?;
+        let project = failing_project("")?;
+        let mut context = failing_context(&logger, project.path())?;
+        context.release = true;
+        context.config.build_config.split_debug_info = SplitDebugInfo::Adjacent;
+
+        // What builds and compile do before invoking cargo.
+        resolve_rustflags(&logger, &mut context);
+
+        let (_, envs) = cargo_build_invocation(&context, Path::new("python"));
+        let rustflags = envs
+            .iter()
+            .find(|(key, _)| *key == "RUSTFLAGS")
+            .map(|(_, value)| value.clone());
+        assert!(rustflags
+            .unwrap_or_default()
+            .split(' ')
+            .any(|flag| flag.starts_with("-Cdebuginfo=")));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_color_cargo_invocation() -> Result<()> {
+        let logger = get_logger()?;
+        let project = failing_project("")?;
+        let mut context = failing_context(&logger, project.path())?;
+
+        let cases = &[
+            (ColorChoice::Auto, false, "never"),
+            (ColorChoice::Auto, true, "always"),
+            (ColorChoice::Always, false, "always"),
+            (ColorChoice::Always, true, "always"),
+            (ColorChoice::Never, false, "never"),
+            (ColorChoice::Never, true, "never"),
+        ];
+
+        for (choice, is_terminal, expected) in cases {
+            context.cargo_color = choice.for_child(|_| None, false, *is_terminal);
+
+            let (args, _) = cargo_build_invocation(&context, Path::new("python"));
+            assert_eq!(args.iter().filter(|a| *a == "--color").count(), 1);
+            assert!(
+                args.windows(2).any(|w| w == ["--color", *expected]),
+                "{:?} terminal={}: {:?}",
+                choice,
+                is_terminal,
+                args
+            );
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_toolchain_invocation() -> Result<()> {
+        let logger = get_logger()?;
+        let project = failing_project("")?;
+        let mut context = failing_context(&logger, project.path())?;
+        context.toolchain = Toolchain::Rustup("nightly".to_string());
+
+        let (args, _) = cargo_build_invocation(&context, Path::new("python"));
+        assert_eq!(&args[0..2], ["+nightly", "build"]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_application_name_mismatch() -> Result<()> {
+        let logger = get_logger()?;
+        let project = failing_project("")?;
+        let config_path = project.path().join("pyoxidizer.bzl");
+        let config = fs::read_to_string(&config_path)?;
+
+        fs::write(&config_path, config.replace("'myapp'", "'renamed'"))?;
+        let err = failing_context(&logger, project.path()).err().unwrap();
+        assert!(err.to_string().contains("application_name \"renamed\" in"));
+        assert_exit_code::<()>(Err(err), 10);
+
+        fs::write(&config_path, config.replace("'myapp'", "'auto'"))?;
+        let context = failing_context(&logger, project.path())?;
+        assert_eq!(context.app_name, "myapp");
+        let (args, _) = cargo_build_invocation(&context, Path::new("python"));
+        assert!(args.windows(2).any(|w| w == ["--bin", "myapp"]));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_lockfile_config_path() -> Result<()> {
+        let temp_dir = tempdir::TempDir::new("pyoxidizer-test")?;
+        let project = canonicalize_path(temp_dir.path())?.join("project");
+        let other = canonicalize_path(temp_dir.path())?.join("other");
+        fs::create_dir_all(&project)?;
+        fs::create_dir_all(&other)?;
+
+        // Config files named otherwise, e.g. given by PYOXIDIZER_CONFIG.
+        let config_path = project.join("custom.bzl");
+        fs::write(&config_path, "")?;
+        let lockfile_path = project.join(LOCKFILE_FILENAME);
+        fs::write(&lockfile_path, "{}")?;
+
+        assert_eq!(
+            lockfile_config_path(&lockfile_path, Some(config_path.clone()))?,
+            config_path
+        );
+
+        let other_config = other.join("pyoxidizer.bzl");
+        fs::write(&other_config, "")?;
+        fs::write(other.join(LOCKFILE_FILENAME), "{}")?;
+        let err = lockfile_config_path(&lockfile_path, Some(other_config)).unwrap_err();
+        assert!(err.to_string().contains("doesn't belong to config file"));
+
+        assert!(lockfile_config_path(&lockfile_path, None).is_err());
+
+        Ok(())
+    }
+
+    /// Create a project from a template and build it.
+    ///
+    /// Returns the directory holding the project and the path of the built
+    /// executable.
+    fn build_template_project(
+        logger: &slog::Logger,
+        template: ProjectTemplate,
+    ) -> Result<(tempdir::TempDir, PathBuf)> {
+        let temp_dir = tempdir::TempDir::new("pyoxidizer-test")?;
+        let project_path = temp_dir.path().join("myapp");
+
+        initialize_project(&project_path, None, &[], false, template)?;
+
+        let exe_path = build_one_target(
+            logger,
+            &project_path.display().to_string(),
+            None,
+            false,
+            Verbosity::Normal,
+            false,
+            false,
+            false,
+            false,
+            true,
+            false,
+            &ExtraCargoArgs::default(),
+            &EvaluationCache::default(),
+        )?;
+
+        Ok((temp_dir, exe_path))
+    }
+
+    #[test]
+    fn test_plan_matches_build() -> Result<()> {
+        let logger = get_logger()?;
+        let (temp_dir, _) = build_template_project(&logger, ProjectTemplate::Cli)?;
+        let project_path = temp_dir.path().join("myapp");
+
+        let mut context = resolve_build_context(
+            &logger,
+            &project_path.display().to_string(),
+            None,
+            None,
+            false,
+            None,
+            Verbosity::Normal,
+            false,
+            &EvalOptions::default(),
+        )?;
+        let state = context.get_packaging_state()?;
+
+        // Every file the plan lists was written.
+        for file in artifact_files(&context)
+            .into_iter()
+            .chain(app_files(&context, Some(&state))?)
+        {
+            assert!(
+                fs::symlink_metadata(&file.path).is_ok(),
+                "{} ({}) wasn't written",
+                file.path.display(),
+                file.description
+            );
+        }
+
+        // The planned cargo lines are those written.
+        let resolved = resolve_config(&logger, &context)?;
+        let libpython_metadata = libpython_cargo_metadata(
+            &logger,
+            &resolved.dist,
+            &resolved.resources.embedded,
+            &context.pyoxidizer_artifacts_path,
+            &context.target_triple,
+        );
+        let paths = ArtifactPaths::new(&context);
+        assert_eq!(
+            artifacts_cargo_metadata(&resolved, &libpython_metadata, &paths).join("\n"),
+            fs::read_to_string(&paths.cargo_metadata)?
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_rebuild_globbed_modules() -> Result<()> {
+        let logger = get_logger()?;
+        let temp_dir = tempdir::TempDir::new("pyoxidizer-test")?;
+        let project_path = temp_dir.path().join("myapp");
+        let package_path = project_path.join("app").join("myapp");
+        let filters_path = project_path.join("filters");
+
+        initialize_project(&project_path, None, &[], false, ProjectTemplate::Cli)?;
+
+        // Modules of the package are only kept when a filter file under the
+        // globbed directory lists them.
+        let config_path = project_path.join("pyoxidizer.bzl");
+        let config = fs::read_to_string(&config_path)?
+            .replace(
+                "#embedded.filter_from_files(files=[\"/path/to/filter-file\"]))",
+                "embedded.filter_from_files(glob_files=[\"filters/*.txt\"])",
+            )
+            .replace(
+                "python_run_mode = python_run_mode_module(\"myapp.__main__\")",
+                "python_run_mode = python_run_mode_eval(\"import importlib.util; \
+                 print(*[importlib.util.find_spec('myapp.' + m) is not None \
+                 for m in ('extra', 'more')])\")",
+            );
+        fs::write(&config_path, config)?;
+        create_dir_all(&filters_path)?;
+        fs::write(
+            filters_path.join("base.txt"),
+            "[!m]*\nm[!y]*\nmyapp\nmyapp.__main__\n",
+        )?;
+
+        let build_and_run = || -> Result<String> {
+            let exe_path = build_one_target(
+                &logger,
+                &project_path.display().to_string(),
+                None,
+                false,
+                Verbosity::Normal,
+                false,
+                false,
+                false,
+                false,
+                true,
+                false,
+                &ExtraCargoArgs::default(),
+                &EvaluationCache::default(),
+            )?;
+            let output = process::Command::new(&exe_path).output()?;
+            assert!(output.status.success());
+
+            Ok(String::from_utf8_lossy(&output.stdout).to_string())
+        };
+
+        assert_eq!(build_and_run()?, "False False\n");
+
+        // A new module and the filter file listing it.
+        fs::write(package_path.join("extra.py"), "")?;
+        fs::write(package_path.join("more.py"), "")?;
+        fs::write(filters_path.join("extra.txt"), "myapp.extra\n")?;
+        assert_eq!(build_and_run()?, "True False\n");
+
+        // A new filter file alone.
+        fs::write(filters_path.join("more.txt"), "myapp.more\n")?;
+        assert_eq!(build_and_run()?, "True True\n");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_run_multicall_links() -> Result<()> {
+        let logger = get_logger()?;
+        let temp_dir = tempdir::TempDir::new("pyoxidizer-test")?;
+        let project_path = temp_dir.path().join("myapp");
+
+        initialize_project(&project_path, None, &[], false, ProjectTemplate::Cli)?;
+
+        let config_path = project_path.join("pyoxidizer.bzl");
+        let config = fs::read_to_string(&config_path)?
+            .replace(
+                "python_run_mode = python_run_mode_module(\"myapp.__main__\")",
+                "python_run_mode = python_run_mode_eval(\"print('main')\")",
+            )
+            .replace(
+                "    # multicall_run_modes={\n    \
+                 #     \"myapp-worker\": python_run_mode_module(\"mypackage.worker\"),\n    \
+                 # },\n",
+                "    multicall_run_modes={\n        \
+                 \"myapp-worker\": python_run_mode_eval(\"print('worker')\"),\n        \
+                 \"myapp-admin\": python_run_mode_eval(\"print('admin')\"),\n    \
+                 },\n",
+            );
+        assert!(config.contains("\"myapp-admin\""));
+        fs::write(&config_path, config)?;
+
+        let exe_path = build_one_target(
+            &logger,
+            &project_path.display().to_string(),
+            None,
+            false,
+            Verbosity::Normal,
+            false,
+            false,
+            false,
+            false,
+            true,
+            false,
+            &ExtraCargoArgs::default(),
+            &EvaluationCache::default(),
+        )?;
+
+        // Each link reaches the entry point of its name, from any directory.
+        let suffix = if cfg!(windows) { ".exe" } else { "" };
+        let app_path = exe_path.parent().unwrap();
+        for (name, expected) in &[
+            ("myapp", "main\n"),
+            ("myapp-worker", "worker\n"),
+            ("myapp-admin", "admin\n"),
+        ] {
+            let output = process::Command::new(app_path.join(format!("{}{}", name, suffix)))
+                .current_dir(temp_dir.path())
+                .output()?;
+            assert!(
+                output.status.success(),
+                "{} failed: {}",
+                name,
+                String::from_utf8_lossy(&output.stderr)
+            );
+            assert_eq!(
+                String::from_utf8_lossy(&output.stdout),
+                *expected,
+                "{}",
+                name
+            );
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_prepare_compile_relocated() -> Result<()> {
+        let logger = get_logger()?;
+        let temp_dir = tempdir::TempDir::new("pyoxidizer-test")?;
+        let project_path = temp_dir.path().join("myapp");
+        let prepared_path = temp_dir.path().join("prepared");
+
+        initialize_project(&project_path, None, &[], false, ProjectTemplate::Cli)?;
+
+        prepare(
+            &logger,
+            &project_path.display().to_string(),
+            &prepared_path,
+            None,
+            false,
+            Verbosity::Normal,
+            false,
+            &EvalOptions::default(),
+            true,
+            &CancellationToken::new(),
+        )?;
+
+        let manifest = PreparedManifest::from_prepared_path(&prepared_path)?;
+        assert_eq!(manifest.target_triple, default_target()?);
+        assert_eq!(manifest.config_path, "project/pyoxidizer.bzl");
+
+        // Nothing outside the prepared directory is needed once it's moved.
+        remove_dir_all(&project_path)?;
+        let other_dir = tempdir::TempDir::new("pyoxidizer-test")?;
+        let moved_path = other_dir.path().join("moved");
+        crate::fsutils::move_path(&prepared_path, &moved_path)?;
+        let target_dir = canonicalize_path(other_dir.path())?.join("build");
+
+        let err = compile(
+            &logger,
+            &moved_path,
+            &target_dir,
+            Some("wasm32-unknown-unknown"),
+            Verbosity::Normal,
+            &EvalOptions::default(),
+            false,
+            true,
+            false,
+            &ExtraCargoArgs::default(),
+            &CancellationToken::new(),
+        )
+        .unwrap_err();
+        assert!(err.to_string().starts_with(&format!(
+            "build was prepared for {}, not wasm32-unknown-unknown",
+            manifest.target_triple
+        )));
+        assert_exit_code::<()>(Err(err), 2);
+
+        // Options of build are honored as well.
+        let exe_path = compile(
+            &logger,
+            &moved_path,
+            &target_dir,
+            None,
+            Verbosity::Normal,
+            &EvalOptions::default(),
+            false,
+            true,
+            true,
+            &ExtraCargoArgs::parse(&["--jobs", "1"])?,
+            &CancellationToken::new(),
+        )?;
+        assert!(exe_path.starts_with(&target_dir));
+        assert!(exe_path
+            .parent()
+            .unwrap()
+            .join("THIRD-PARTY-NOTICES.txt")
+            .exists());
+
+        let output = process::Command::new(&exe_path)
+            .args(&["--shout", "world"])
+            .output()?;
+        assert!(output.status.success());
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "HELLO, WORLD!\n");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_template_cli() -> Result<()> {
+        let logger = get_logger()?;
+        let (_temp_dir, exe_path) = build_template_project(&logger, ProjectTemplate::Cli)?;
+
+        let output = process::Command::new(&exe_path)
+            .args(&["--shout", "world"])
+            .output()?;
+        assert!(output.status.success());
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "HELLO, WORLD!\n");
+
+        // argparse rejects missing arguments with exit code 2.
+        let output = process::Command::new(&exe_path).output()?;
+        assert_eq!(output.status.code(), Some(2));
+        assert!(String::from_utf8_lossy(&output.stderr).starts_with("usage: myapp"));
+
+        Ok(())
+    }
+
+    /// Start a service and wait until it is ready.
+    fn start_service(
+        command: &mut process::Command,
+    ) -> Result<(process::Child, std::io::BufReader<process::ChildStderr>)> {
+        use std::io::BufRead;
+
+        let mut child = command.stderr(process::Stdio::piped()).spawn()?;
+        let mut stderr = std::io::BufReader::new(child.stderr.take().unwrap());
+
+        let mut line = String::new();
+        while !line.contains("ready") {
+            line.clear();
+            if stderr.read_line(&mut line)? == 0 {
+                break;
+            }
+        }
+        assert!(line.contains("myapp INFO ready"));
+
+        Ok((child, stderr))
+    }
+
+    /// Wait for a service to stop and check it stopped cleanly on a signal.
+    fn check_service_stopped(
+        mut child: process::Child,
+        mut stderr: std::io::BufReader<process::ChildStderr>,
+        signum: i32,
+    ) -> Result<()> {
+        use std::io::Read;
+
+        let mut rest = String::new();
+        stderr.read_to_string(&mut rest)?;
+        assert!(child.wait()?.success());
+        assert!(rest.contains(&format!("received signal {}, stopping", signum)));
+        assert!(rest.contains("myapp INFO stopped"));
+
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_template_service() -> Result<()> {
+        let logger = get_logger()?;
+        let (_temp_dir, exe_path) = build_template_project(&logger, ProjectTemplate::Service)?;
+
+        // The asyncio loop handles the signals instead of Python raising
+        // KeyboardInterrupt on SIGINT.
+        for signum in &[libc::SIGTERM, libc::SIGINT] {
+            let (child, stderr) = start_service(&mut process::Command::new(&exe_path))?;
+
+            unsafe {
+                libc::kill(child.id() as libc::pid_t, *signum);
+            }
+
+            check_service_stopped(child, stderr, *signum)?;
+        }
+
+        Ok(())
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn test_template_service() -> Result<()> {
+        use std::os::windows::process::CommandExt;
+        use winapi::um::wincon::{GenerateConsoleCtrlEvent, CTRL_BREAK_EVENT};
+
+        let logger = get_logger()?;
+        let (_temp_dir, exe_path) = build_template_project(&logger, ProjectTemplate::Service)?;
+
+        // A process group of its own keeps the event from reaching us.
+        let (child, stderr) = start_service(
+            process::Command::new(&exe_path)
+                .creation_flags(winapi::um::winbase::CREATE_NEW_PROCESS_GROUP),
+        )?;
+
+        assert_ne!(
+            unsafe { GenerateConsoleCtrlEvent(CTRL_BREAK_EVENT, child.id()) },
+            0
+        );
+
+        // SIGBREAK
+        check_service_stopped(child, stderr, 21)
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_template_gui() -> Result<()> {
+        let logger = get_logger()?;
+        let (_temp_dir, exe_path) = build_template_project(&logger, ProjectTemplate::Gui)?;
+
+        // Without a console, output goes to a log file next to the executable.
+        assert!(run_detached(&exe_path, &["--help"])?.success());
+
+        let log = fs::read_to_string(exe_path.with_file_name("myapp.log"))?;
+        assert!(log.starts_with("usage: myapp [-h] [--title TITLE]"));
+
+        Ok(())
+    }
+}
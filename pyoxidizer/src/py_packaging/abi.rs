@@ -0,0 +1,444 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/*!
+Checking collected artifacts are compatible with a Python distribution.
+
+Wheels and compiled extension modules target a specific Python version.
+Packaging ones built for another version succeeds, but the application
+fails at run-time (or crashes). This module finds them at collection time
+using wheel tags, extension module file names and, where detectable, the
+`libpython` an extension links against.
+*/
+
+use anyhow::{anyhow, Result};
+use lazy_static::lazy_static;
+use std::path::{Path, PathBuf};
+
+use super::fsscan::{find_python_resources, PythonFileResource};
+
+lazy_static! {
+    /// Matches the ABI tag in extension module file names.
+    ///
+    /// e.g. `.cpython-39-x86_64-linux-gnu.so`, `.cpython-37m-darwin.so`,
+    /// `.cp39-win_amd64.pyd` and `.abi3.so`.
+    static ref RE_EXTENSION_ABI: regex::Regex = regex::Regex::new(
+        r"\.(?:(?P<abi>(?:cpython-|cp)(?P<major>\d)(?P<minor>\d+)[a-z]*)|abi3)(?:-[^.]*)?\.(?:so|pyd)$"
+    )
+    .unwrap();
+    /// Matches libpython names linked by extension modules.
+    ///
+    /// e.g. `libpython3.9.so.1.0`, `python39.dll` and
+    /// `Python.framework/Versions/3.9/Python`.
+    static ref RE_LIBPYTHON: regex::Regex = regex::Regex::new(
+        r"(?i)(?:^libpython(\d)\.(\d+)|^python(\d)(\d+)\.dll$|/Versions/(\d)\.(\d+)/Python$)"
+    )
+    .unwrap();
+}
+
+/// Parse the major and minor version from a Python version string.
+pub fn major_minor(version: &str) -> Result<(u32, u32)> {
+    let mut parts = version.split('.');
+
+    let mut next = || -> Result<u32> {
+        parts
+            .next()
+            .and_then(|v| v.parse::<u32>().ok())
+            .ok_or_else(|| anyhow!("unable to parse Python version {}", version))
+    };
+
+    Ok((next()?, next()?))
+}
+
+/// Describes an artifact incompatible with a Python distribution.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Incompatibility {
+    /// Path of the artifact.
+    pub path: PathBuf,
+    /// Full name of the extension module, if the artifact is one.
+    pub module: Option<String>,
+    /// Describes the artifact's incompatible tag.
+    pub found: String,
+    /// Describes what would be compatible.
+    pub expected: String,
+}
+
+impl std::fmt::Display for Incompatibility {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{}: {} (expected {})",
+            self.path.display(),
+            self.found,
+            self.expected
+        )
+    }
+}
+
+/// Whether a single wheel tag triple is compatible with a Python version.
+///
+/// `tag` is `<python>-<abi>-<platform>` and may use compressed tag sets,
+/// e.g. `py2.py3-none-any`. The platform isn't checked.
+pub fn wheel_tag_compatible(tag: &str, major: u32, minor: u32) -> bool {
+    let parts = tag.split('-').collect::<Vec<&str>>();
+    if parts.len() != 3 {
+        return false;
+    }
+
+    // Returns the minor version targeted by a python tag, or None if the
+    // tag targets another major version or implementation.
+    let python_minor = |python: &str, prefix: &str| -> Option<Option<u32>> {
+        if !python.starts_with(prefix) {
+            return None;
+        }
+
+        let version = &python[prefix.len()..];
+        let tag_major = version.get(0..1)?.parse::<u32>().ok()?;
+        if tag_major != major {
+            return None;
+        }
+
+        match &version[1..] {
+            "" => Some(None),
+            v => v.parse::<u32>().ok().map(Some),
+        }
+    };
+
+    parts[0].split('.').any(|python| {
+        parts[1].split('.').any(|abi| match abi {
+            // Pure Python or otherwise ABI independent.
+            "none" => match python_minor(python, "py").or_else(|| python_minor(python, "cp")) {
+                Some(None) => true,
+                Some(Some(m)) => m <= minor,
+                None => false,
+            },
+            // The stable ABI is forwards compatible.
+            "abi3" => match python_minor(python, "cp") {
+                Some(Some(m)) => m <= minor,
+                _ => false,
+            },
+            abi => {
+                let expected = format!("cp{}{}", major, minor);
+                python == expected && abi.starts_with(&expected)
+            }
+        })
+    })
+}
+
+/// Describe wheel tags compatible with a Python version.
+fn expected_wheel_tags(major: u32, minor: u32) -> String {
+    format!(
+        "cp{0}{1}-cp{0}{1}{2}-*, cp{0}X-abi3-* or py{0}-none-*",
+        major,
+        minor,
+        if major == 3 && minor < 8 { "m" } else { "" }
+    )
+}
+
+/// Obtain the `Tag` values from the content of a wheel's `WHEEL` file.
+pub fn parse_wheel_tags(data: &str) -> Vec<String> {
+    data.lines()
+        .filter_map(|line| {
+            let mut parts = line.splitn(2, ':');
+            match (parts.next(), parts.next()) {
+                (Some(key), Some(value)) if key.trim() == "Tag" => Some(value.trim().to_string()),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+/// Obtain the Python version targeted by an extension module file name.
+///
+/// Returns the ABI tag and, unless it is the stable ABI, the major and
+/// minor version. Returns None if the file name has no ABI tag.
+pub fn extension_filename_abi(filename: &str) -> Option<(String, Option<(u32, u32)>)> {
+    let caps = RE_EXTENSION_ABI.captures(filename)?;
+
+    match (caps.name("abi"), caps.name("major"), caps.name("minor")) {
+        (Some(abi), Some(major), Some(minor)) => Some((
+            abi.as_str().to_string(),
+            Some((major.as_str().parse().ok()?, minor.as_str().parse().ok()?)),
+        )),
+        _ => Some(("abi3".to_string(), None)),
+    }
+}
+
+/// Describe the extension module ABI tag for a Python version.
+///
+/// The expected tag uses the same convention as the incompatible tag
+/// `found`.
+fn expected_extension_abi(found: &str, major: u32, minor: u32) -> String {
+    if found.starts_with("cpython-") {
+        format!(
+            "cpython-{}{}{}",
+            major,
+            minor,
+            if major == 3 && minor < 8 { "m" } else { "" }
+        )
+    } else {
+        format!("cp{}{}", major, minor)
+    }
+}
+
+/// Obtain the Python version of a libpython a binary links against.
+///
+/// Returns None if the binary can't be parsed or doesn't link libpython.
+pub fn linked_python_version(data: &[u8]) -> Option<(u32, u32)> {
+    let libraries = match goblin::Object::parse(data).ok()? {
+        goblin::Object::Elf(elf) => elf.libraries,
+        goblin::Object::PE(pe) => pe.libraries,
+        goblin::Object::Mach(goblin::mach::Mach::Binary(macho)) => macho.libs,
+        _ => return None,
+    };
+
+    libraries.iter().find_map(|lib| {
+        let caps = RE_LIBPYTHON.captures(lib)?;
+        let version = (1..7)
+            .filter_map(|i| caps.get(i))
+            .map(|m| m.as_str().parse::<u32>().ok())
+            .collect::<Option<Vec<u32>>>()?;
+
+        match version.as_slice() {
+            [major, minor] => Some((*major, *minor)),
+            _ => None,
+        }
+    })
+}
+
+/// Find artifacts under a directory incompatible with a Python version.
+///
+/// Checks the tags of installed wheels (from `*.dist-info/WHEEL`) and the
+/// ABI tag and linked libpython of extension modules.
+pub fn find_incompatible_artifacts(root: &Path, version: &str) -> Result<Vec<Incompatibility>> {
+    let (major, minor) = major_minor(version)?;
+    let mut res = Vec::new();
+
+    for entry in walkdir::WalkDir::new(root)
+        .sort_by(|a, b| a.file_name().cmp(b.file_name()))
+        .into_iter()
+        .filter_map(|e| e.ok())
+    {
+        let path = entry.path();
+        let is_wheel_metadata = path.file_name().map_or(false, |n| n == "WHEEL")
+            && path
+                .parent()
+                .and_then(|p| p.file_name())
+                .map_or(false, |n| n.to_string_lossy().ends_with(".dist-info"));
+
+        if !is_wheel_metadata {
+            continue;
+        }
+
+        let tags = parse_wheel_tags(&std::fs::read_to_string(path)?);
+        if tags.is_empty() || tags.iter().any(|t| wheel_tag_compatible(t, major, minor)) {
+            continue;
+        }
+
+        res.push(Incompatibility {
+            path: path.parent().unwrap().to_path_buf(),
+            module: None,
+            found: format!("wheel tag {}", tags.join(", ")),
+            expected: expected_wheel_tags(major, minor),
+        });
+    }
+
+    for resource in find_python_resources(root) {
+        let (full_name, path) = match resource {
+            PythonFileResource::ExtensionModule {
+                full_name, path, ..
+            } => (full_name, path),
+            _ => continue,
+        };
+
+        let filename = path
+            .file_name()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or_default();
+
+        if let Some((abi, Some(tag_version))) = extension_filename_abi(&filename) {
+            if tag_version != (major, minor) {
+                res.push(Incompatibility {
+                    path: path.clone(),
+                    module: Some(full_name),
+                    found: format!("extension module ABI {}", abi),
+                    expected: expected_extension_abi(&abi, major, minor),
+                });
+                continue;
+            }
+        }
+
+        if let Some(linked) = std::fs::read(&path)
+            .ok()
+            .and_then(|data| linked_python_version(&data))
+        {
+            if linked != (major, minor) {
+                res.push(Incompatibility {
+                    path: path.clone(),
+                    module: Some(full_name),
+                    found: format!(
+                        "extension module linked against Python {}.{}",
+                        linked.0, linked.1
+                    ),
+                    expected: format!("Python {}.{}", major, minor),
+                });
+            }
+        }
+    }
+
+    Ok(res)
+}
+
+/// Produce an error listing incompatible artifacts.
+///
+/// Returns `Ok(())` if there are none.
+pub fn incompatibility_error(incompatible: &[Incompatibility], version: &str) -> Result<()> {
+    if incompatible.is_empty() {
+        return Ok(());
+    }
+
+    Err(anyhow!(
+        "artifacts incompatible with the Python {} distribution:\n  {}",
+        version,
+        incompatible
+            .iter()
+            .map(|i| i.to_string())
+            .collect::<Vec<String>>()
+            .join("\n  ")
+    ))
+}
+
+/// Verify artifacts under a directory are compatible with a Python version.
+pub fn check_artifacts_compatible(root: &Path, version: &str) -> Result<()> {
+    incompatibility_error(&find_incompatible_artifacts(root, version)?, version)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wheel_tag_compatible() {
+        assert!(wheel_tag_compatible("py3-none-any", 3, 7));
+        assert!(wheel_tag_compatible("py2.py3-none-any", 3, 7));
+        assert!(wheel_tag_compatible("py36-none-any", 3, 7));
+        assert!(!wheel_tag_compatible("py38-none-any", 3, 7));
+        assert!(!wheel_tag_compatible("py2-none-any", 3, 7));
+        assert!(wheel_tag_compatible("cp37-cp37m-manylinux1_x86_64", 3, 7));
+        assert!(wheel_tag_compatible("cp310-cp310-win_amd64", 3, 10));
+        assert!(!wheel_tag_compatible("cp39-cp39-manylinux1_x86_64", 3, 7));
+        assert!(!wheel_tag_compatible("cp31-cp31-win_amd64", 3, 10));
+        assert!(wheel_tag_compatible("cp36-abi3-manylinux1_x86_64", 3, 7));
+        assert!(!wheel_tag_compatible("cp38-abi3-manylinux1_x86_64", 3, 7));
+        assert!(!wheel_tag_compatible(
+            "pp37-pypy37_pp73-manylinux1_x86_64",
+            3,
+            7
+        ));
+        assert!(!wheel_tag_compatible("garbage", 3, 7));
+    }
+
+    #[test]
+    fn test_parse_wheel_tags() {
+        assert_eq!(
+            parse_wheel_tags(
+                "Wheel-Version: 1.0\nGenerator: bdist_wheel (0.33.6)\nRoot-Is-Purelib: false\nTag: cp39-cp39-manylinux1_x86_64\nTag: cp39-cp39-manylinux2010_x86_64\n"
+            ),
+            vec![
+                "cp39-cp39-manylinux1_x86_64".to_string(),
+                "cp39-cp39-manylinux2010_x86_64".to_string()
+            ]
+        );
+    }
+
+    #[test]
+    fn test_extension_filename_abi() {
+        assert_eq!(
+            extension_filename_abi("_speedups.cpython-39-x86_64-linux-gnu.so"),
+            Some(("cpython-39".to_string(), Some((3, 9))))
+        );
+        assert_eq!(
+            extension_filename_abi("_speedups.cpython-37m-darwin.so"),
+            Some(("cpython-37m".to_string(), Some((3, 7))))
+        );
+        assert_eq!(
+            extension_filename_abi("_speedups.cp310-win_amd64.pyd"),
+            Some(("cp310".to_string(), Some((3, 10))))
+        );
+        assert_eq!(
+            extension_filename_abi("_speedups.abi3.so"),
+            Some(("abi3".to_string(), None))
+        );
+        assert_eq!(extension_filename_abi("_speedups.so"), None);
+        assert_eq!(extension_filename_abi("_speedups.pyd"), None);
+    }
+
+    fn write(root: &Path, path: &str, data: &[u8]) {
+        let path = root.join(path);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, data).unwrap();
+    }
+
+    #[test]
+    fn test_wheel_tag_mismatch() -> Result<()> {
+        let temp_dir = tempdir::TempDir::new("pyoxidizer-test")?;
+        let root = temp_dir.path();
+
+        write(root, "mypkg/__init__.py", b"");
+        write(
+            root,
+            "mypkg-1.0.dist-info/WHEEL",
+            b"Wheel-Version: 1.0\nTag: cp39-cp39-manylinux1_x86_64\n",
+        );
+        write(root, "other-1.0.dist-info/WHEEL", b"Tag: py3-none-any\n");
+
+        let incompatible = find_incompatible_artifacts(root, "3.7.5")?;
+        assert_eq!(
+            incompatible,
+            vec![Incompatibility {
+                path: root.join("mypkg-1.0.dist-info"),
+                module: None,
+                found: "wheel tag cp39-cp39-manylinux1_x86_64".to_string(),
+                expected: "cp37-cp37m-*, cp3X-abi3-* or py3-none-*".to_string(),
+            }]
+        );
+
+        let err = check_artifacts_compatible(root, "3.7.5").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "artifacts incompatible with the Python 3.7.5 distribution:\n  {}: wheel tag cp39-cp39-manylinux1_x86_64 (expected cp37-cp37m-*, cp3X-abi3-* or py3-none-*)",
+                root.join("mypkg-1.0.dist-info").display()
+            )
+        );
+
+        assert!(check_artifacts_compatible(root, "3.9.1").is_ok());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_extension_abi_mismatch() -> Result<()> {
+        let temp_dir = tempdir::TempDir::new("pyoxidizer-test")?;
+        let root = temp_dir.path();
+
+        write(root, "mypkg/__init__.py", b"");
+        write(root, "mypkg/_speedups.cpython-39-x86_64-linux-gnu.so", b"");
+        write(root, "mypkg/_compat.cpython-37m-x86_64-linux-gnu.so", b"");
+        write(root, "mypkg/_stable.abi3.so", b"");
+
+        let incompatible = find_incompatible_artifacts(root, "3.7.5")?;
+        assert_eq!(
+            incompatible,
+            vec![Incompatibility {
+                path: root.join("mypkg/_speedups.cpython-39-x86_64-linux-gnu.so"),
+                module: Some("mypkg._speedups.cpython-39-x86_64-linux-gnu".to_string()),
+                found: "extension module ABI cpython-39".to_string(),
+                expected: "cpython-37m".to_string(),
+            }]
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,358 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/*!
+Diagnostics for reading zstandard compressed tar archives.
+
+Errors from the decompression and tar layers (e.g. `Unknown frame
+descriptor`) don't say which file is bad or what to do about it. Readers
+here track how far into an archive they got and turn failures into an
+`ArchiveError` describing the archive and how to recover.
+*/
+
+use anyhow::{Context, Result};
+use std::cell::Cell;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use crate::cancel;
+use crate::fsutils::sha256_file;
+
+/// Magic number starting every zstandard frame.
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+/// A reader counting the bytes read through it.
+pub struct CountingReader<R: Read> {
+    inner: R,
+    count: Rc<Cell<u64>>,
+}
+
+impl<R: Read> CountingReader<R> {
+    /// Wrap a reader, returning it and a handle to its byte count.
+    pub fn new(inner: R) -> (Self, Rc<Cell<u64>>) {
+        let count = Rc::new(Cell::new(0));
+
+        (
+            CountingReader {
+                inner,
+                count: count.clone(),
+            },
+            count,
+        )
+    }
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let size = self.inner.read(buf)?;
+        self.count.set(self.count.get() + size as u64);
+
+        Ok(size)
+    }
+}
+
+/// Describes a failure to read an archive.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ArchiveError {
+    pub path: PathBuf,
+    /// Size of the archive file.
+    pub size: u64,
+    /// SHA-256 of the archive file.
+    pub sha256: String,
+    /// Compressed bytes read before the failure.
+    ///
+    /// Readers buffer, so the failure is near rather than at this offset.
+    pub compressed_offset: u64,
+    /// Decompressed bytes read before the failure.
+    pub decompressed_offset: u64,
+    /// Whether the archive is in a cache PyOxidizer manages.
+    pub cached: bool,
+    /// Description of the underlying error.
+    pub cause: String,
+}
+
+impl std::fmt::Display for ArchiveError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        writeln!(
+            f,
+            "error reading archive {}: {}",
+            self.path.display(),
+            self.cause
+        )?;
+        writeln!(f, "  size: {} bytes", self.size)?;
+        writeln!(f, "  sha256: {}", self.sha256)?;
+        writeln!(
+            f,
+            "  failed near compressed byte {} of {} (after {} decompressed bytes)",
+            self.compressed_offset, self.size, self.decompressed_offset
+        )?;
+
+        if self.cached {
+            write!(
+                f,
+                "the cached archive is likely corrupt; delete {} to download it again",
+                self.path.display()
+            )
+        } else {
+            write!(
+                f,
+                "the archive is likely corrupt or truncated; obtain a fresh copy and verify its sha256"
+            )
+        }
+    }
+}
+
+impl std::error::Error for ArchiveError {}
+
+/// Read the tar stream of a zstandard compressed tar archive.
+///
+/// The archive is decompressed while it is read from disk, so it is never
+/// held in memory as a whole.
+///
+/// `f` receives the decompressed stream. Errors it returns, including
+/// errors from decompression, are turned into an `ArchiveError`.
+/// Cancellation errors are passed through.
+///
+/// `cached` indicates the archive is in a cache PyOxidizer manages,
+/// which changes the suggested remedy.
+pub fn read_tar_zst<T, F>(path: &Path, cached: bool, f: F) -> Result<T>
+where
+    F: FnOnce(&mut dyn Read) -> Result<T>,
+{
+    let fh = std::fs::File::open(path).with_context(|| format!("opening {}", path.display()))?;
+    let size = fh.metadata()?.len();
+
+    let (mut compressed, compressed_count) = CountingReader::new(std::io::BufReader::new(fh));
+
+    let archive_error = |cause: String| -> anyhow::Error {
+        ArchiveError {
+            path: path.to_path_buf(),
+            size,
+            sha256: sha256_file(path).unwrap_or_else(|e| format!("<unknown: {}>", e)),
+            compressed_offset: compressed_count.get(),
+            decompressed_offset: 0,
+            cached,
+            cause,
+        }
+        .into()
+    };
+
+    // Catch files that aren't zstandard (e.g. an HTML error page saved in
+    // place of a download) before the decoder reports something cryptic.
+    let mut magic = [0; 4];
+    if let Err(e) = compressed.read_exact(&mut magic) {
+        return Err(archive_error(format!("unable to read header: {}", e)));
+    }
+    if magic != ZSTD_MAGIC {
+        return Err(archive_error(format!(
+            "not a zstandard archive (starts with {})",
+            hex::encode(magic)
+        )));
+    }
+
+    let decoder = zstd::stream::Decoder::new(std::io::Cursor::new(magic).chain(compressed))
+        .map_err(|e| archive_error(e.to_string()))?;
+    let (mut decompressed, decompressed_count) = CountingReader::new(decoder);
+
+    f(&mut decompressed).or_else(|e| {
+        if cancel::is_cancelled_error(&e) {
+            return Err(e);
+        }
+
+        let mut err = archive_error(format!("{:#}", e));
+        if let Some(archive) = err.downcast_mut::<ArchiveError>() {
+            archive.decompressed_offset = decompressed_count.get();
+        }
+
+        Err(err)
+    })
+}
+
+/// Quarantine a corrupt archive by renaming it.
+///
+/// Returns the new path of the archive.
+pub fn quarantine_archive(path: &Path) -> Result<PathBuf> {
+    let mut dest = path.as_os_str().to_os_string();
+    dest.push(format!(".corrupt-{}", uuid::Uuid::new_v4()));
+    let dest = PathBuf::from(dest);
+
+    std::fs::rename(path, &dest)
+        .with_context(|| format!("quarantining {} to {}", path.display(), dest.display()))?;
+
+    Ok(dest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Create a zstandard compressed tar archive holding a few files.
+    fn fixture_archive() -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::new());
+
+        // Pseudo-random content compresses poorly, so the archive spans
+        // multiple zstandard blocks.
+        let mut state: u32 = 0x1234_5678;
+
+        for (name, size) in &[("python/PYTHON.json", 512), ("python/lib/os.py", 400_000)] {
+            let data = (0..*size)
+                .map(|_| {
+                    state ^= state << 13;
+                    state ^= state >> 17;
+                    state ^= state << 5;
+                    state as u8
+                })
+                .collect::<Vec<u8>>();
+
+            let mut header = tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder.append_data(&mut header, name, &data[..]).unwrap();
+        }
+
+        zstd::stream::encode_all(&builder.into_inner().unwrap()[..], 3).unwrap()
+    }
+
+    /// Read every entry of a tar stream.
+    fn read_entries(reader: &mut dyn Read) -> Result<usize> {
+        let mut archive = tar::Archive::new(reader);
+        let mut count = 0;
+
+        for entry in archive.entries()? {
+            let mut data = Vec::new();
+            entry?.read_to_end(&mut data)?;
+            count += 1;
+        }
+
+        Ok(count)
+    }
+
+    fn write_fixture(data: &[u8]) -> (tempdir::TempDir, PathBuf) {
+        let temp_dir = tempdir::TempDir::new("pyoxidizer-test").unwrap();
+        let path = temp_dir.path().join("cpython.tar.zst");
+        std::fs::write(&path, data).unwrap();
+
+        (temp_dir, path)
+    }
+
+    /// Map the files under a directory to their content.
+    fn tree_content(root: &Path) -> Result<std::collections::BTreeMap<PathBuf, Vec<u8>>> {
+        let mut res = std::collections::BTreeMap::new();
+
+        for entry in walkdir::WalkDir::new(root) {
+            let entry = entry?;
+
+            if entry.file_type().is_file() {
+                res.insert(
+                    entry.path().strip_prefix(root)?.to_path_buf(),
+                    std::fs::read(entry.path())?,
+                );
+            }
+        }
+
+        Ok(res)
+    }
+
+    #[test]
+    fn test_streaming_matches_in_memory() -> Result<()> {
+        let (temp_dir, path) = write_fixture(&fixture_archive());
+
+        let streamed = temp_dir.path().join("streamed");
+        read_tar_zst(&path, false, |reader| {
+            tar::Archive::new(reader).unpack(&streamed)?;
+            Ok(())
+        })?;
+
+        // Decompressing the whole archive read into memory.
+        let buffered = temp_dir.path().join("buffered");
+        let data = std::fs::read(&path)?;
+        tar::Archive::new(zstd::stream::Decoder::new(std::io::Cursor::new(data))?)
+            .unpack(&buffered)?;
+
+        let content = tree_content(&streamed)?;
+        assert_eq!(
+            content.keys().collect::<Vec<_>>(),
+            vec![
+                Path::new("python/PYTHON.json"),
+                Path::new("python/lib/os.py")
+            ]
+        );
+        assert_eq!(content, tree_content(&buffered)?);
+
+        Ok(())
+    }
+
+    fn archive_error(err: anyhow::Error) -> ArchiveError {
+        err.downcast_ref::<ArchiveError>()
+            .expect("error should be an ArchiveError")
+            .clone()
+    }
+
+    #[test]
+    fn test_valid() -> Result<()> {
+        let (_temp_dir, path) = write_fixture(&fixture_archive());
+
+        assert_eq!(read_tar_zst(&path, false, read_entries)?, 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_truncated() {
+        let data = fixture_archive();
+        let (_temp_dir, path) = write_fixture(&data[0..data.len() / 2]);
+
+        let err = archive_error(read_tar_zst(&path, false, read_entries).unwrap_err());
+        assert_eq!(err.path, path);
+        assert_eq!(err.size, (data.len() / 2) as u64);
+        assert_eq!(err.sha256, sha256_file(&path).unwrap());
+        assert!(err.compressed_offset > 0);
+        assert!(err.decompressed_offset > 0);
+        assert!(err
+            .to_string()
+            .contains("the archive is likely corrupt or truncated"));
+    }
+
+    #[test]
+    fn test_bit_flipped_frame_header() {
+        let mut data = fixture_archive();
+        // Set the reserved bit of the frame header descriptor.
+        data[4] |= 0x08;
+        let (_temp_dir, path) = write_fixture(&data);
+
+        let err = archive_error(read_tar_zst(&path, true, read_entries).unwrap_err());
+        assert_eq!(err.decompressed_offset, 0);
+        assert!(err.to_string().contains(&format!(
+            "the cached archive is likely corrupt; delete {} to download it again",
+            path.display()
+        )));
+    }
+
+    #[test]
+    fn test_not_zstd() {
+        let (_temp_dir, path) = write_fixture(b"<html>Not Found</html>");
+
+        let err = archive_error(read_tar_zst(&path, false, read_entries).unwrap_err());
+        assert_eq!(err.cause, "not a zstandard archive (starts with 3c68746d)");
+        assert_eq!(err.size, 22);
+    }
+
+    #[test]
+    fn test_quarantine() -> Result<()> {
+        let (temp_dir, path) = write_fixture(b"corrupt");
+
+        let dest = quarantine_archive(&path)?;
+        assert!(!path.exists());
+        assert_eq!(dest.parent(), Some(temp_dir.path()));
+        assert!(dest
+            .file_name()
+            .unwrap()
+            .to_string_lossy()
+            .starts_with("cpython.tar.zst.corrupt-"));
+
+        Ok(())
+    }
+}
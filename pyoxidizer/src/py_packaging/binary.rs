@@ -4,7 +4,7 @@
 
 use anyhow::Result;
 use slog::warn;
-use std::fs::File;
+use std::collections::BTreeMap;
 use std::io::Write;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
@@ -12,9 +12,21 @@ use tempdir::TempDir;
 
 use super::config::{EmbeddedPythonConfig, RunMode};
 use super::distribution::ParsedPythonDistribution;
-use super::embedded_resource::EmbeddedPythonResourcesPrePackaged;
+use super::embedded_resource::{EmbeddedPythonResources, EmbeddedPythonResourcesPrePackaged};
 use super::libpython::{derive_importlib, link_libpython, ImportlibData};
-use super::pyembed::{derive_python_config, write_data_rs};
+use super::pyembed::{derive_python_config, write_data_rs, AppIdentifiers, SharedResourcesFile};
+use crate::fsutils::create_file;
+
+/// Modules and resources shared by several executables.
+///
+/// They are written to a file installed next to the executables instead of
+/// being embedded in each of them.
+#[derive(Clone, Debug)]
+pub struct SharedPythonResources {
+    /// Path of the file, relative to the executables.
+    pub path: String,
+    pub resources: EmbeddedPythonResourcesPrePackaged,
+}
 
 /// A self-contained Python executable before it is compiled.
 #[derive(Debug)]
@@ -22,11 +34,57 @@ pub struct PreBuiltPythonExecutable {
     pub name: String,
     pub distribution: Arc<ParsedPythonDistribution>,
     pub resources: EmbeddedPythonResourcesPrePackaged,
+    /// Modules and resources loaded from a file shared with other executables.
+    ///
+    /// Only modules and resources which differ from the shared ones are
+    /// embedded.
+    pub shared_resources: Option<SharedPythonResources>,
     pub config: EmbeddedPythonConfig,
     pub run_mode: RunMode,
 }
 
 impl PreBuiltPythonExecutable {
+    /// Package the shared resources, if any.
+    fn package_shared_resources(
+        &self,
+    ) -> Result<Option<(EmbeddedPythonResources, SharedResourcesFile)>> {
+        match &self.shared_resources {
+            Some(shared) => {
+                let packaged = shared.resources.package(
+                    &self.distribution.python_exe,
+                    &self.distribution.archive_sha256,
+                )?;
+                let file = SharedResourcesFile {
+                    path: shared.path.clone(),
+                    data: packaged.write_shared_resources(),
+                };
+
+                Ok(Some((packaged, file)))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Package the resources to embed and the shared resources file, if any.
+    pub fn package_resources(
+        &self,
+    ) -> Result<(EmbeddedPythonResources, Option<SharedResourcesFile>)> {
+        let resources = self.resources.package(
+            &self.distribution.python_exe,
+            &self.distribution.archive_sha256,
+        )?;
+
+        Ok(match self.package_shared_resources()? {
+            Some((shared, file)) => (resources.without_shared(&shared), Some(file)),
+            None => (resources, None),
+        })
+    }
+
+    /// The shared resources file to install next to the executable, if any.
+    pub fn shared_resources_file(&self) -> Result<Option<SharedResourcesFile>> {
+        Ok(self.package_shared_resources()?.map(|(_, file)| file))
+    }
+
     /// Build a Python library suitable for linking.
     ///
     /// This will take the underlying distribution, resources, and
@@ -38,7 +96,7 @@ impl PreBuiltPythonExecutable {
         target: &str,
         opt_level: &str,
     ) -> Result<PythonLibrary> {
-        let resources = self.resources.package(&self.distribution.python_exe)?;
+        let (resources, _) = self.package_resources()?;
 
         let temp_dir = TempDir::new("pyoxidizer-build-exe")?;
         let temp_dir_path = temp_dir.path();
@@ -76,7 +134,7 @@ impl PreBuiltPythonExecutable {
 
     /// Generate data embedded in binaries representing Python resource data.
     pub fn build_embedded_blobs(&self) -> Result<EmbeddedResourcesBlobs> {
-        let embedded_resources = self.resources.package(&self.distribution.python_exe)?;
+        let (embedded_resources, shared) = self.package_resources()?;
 
         let mut module_names = Vec::new();
         let mut modules = Vec::new();
@@ -88,6 +146,7 @@ impl PreBuiltPythonExecutable {
             module_names,
             modules,
             resources,
+            shared,
         })
     }
 }
@@ -106,6 +165,8 @@ pub struct EmbeddedResourcesBlobs {
     pub module_names: Vec<u8>,
     pub modules: Vec<u8>,
     pub resources: Vec<u8>,
+    /// The file holding modules and resources not embedded, if any.
+    pub shared: Option<SharedResourcesFile>,
 }
 
 /// Holds filesystem paths to resources required to build a binary embedding Python.
@@ -128,6 +189,7 @@ pub struct EmbeddedPythonBinaryData {
     pub library: PythonLibrary,
     pub importlib: ImportlibData,
     pub resources: EmbeddedResourcesBlobs,
+    pub identifiers: AppIdentifiers,
     pub host: String,
     pub target: String,
 }
@@ -147,6 +209,20 @@ impl EmbeddedPythonBinaryData {
             "deriving custom importlib modules to support in-memory importing"
         );
         let importlib = derive_importlib(&exe.distribution)?;
+        let identifiers = AppIdentifiers::derive(
+            &resources.module_names,
+            &resources.modules,
+            &resources.resources,
+            &exe.config,
+            &exe.run_mode,
+            &BTreeMap::new(),
+            &exe.distribution.archive_sha256,
+            target,
+        );
+        let identifiers = match &resources.shared {
+            Some(shared) => identifiers.with_shared_resources(shared),
+            None => identifiers,
+        };
 
         Ok(EmbeddedPythonBinaryData {
             config: exe.config.clone(),
@@ -154,6 +230,7 @@ impl EmbeddedPythonBinaryData {
             library,
             importlib,
             resources,
+            identifiers,
             host: host.to_string(),
             target: target.to_string(),
         })
@@ -162,46 +239,53 @@ impl EmbeddedPythonBinaryData {
     /// Write out files needed to link a binary.
     pub fn write_files(&self, dest_dir: &Path) -> Result<EmbeddedPythonBinaryPaths> {
         let importlib_bootstrap = dest_dir.join("importlib_bootstrap");
-        let mut fh = File::create(&importlib_bootstrap)?;
+        let mut fh = create_file(&importlib_bootstrap)?;
         fh.write_all(&self.importlib.bootstrap_bytecode)?;
 
         let importlib_bootstrap_external = dest_dir.join("importlib_bootstrap_external");
-        let mut fh = File::create(&importlib_bootstrap_external)?;
+        let mut fh = create_file(&importlib_bootstrap_external)?;
         fh.write_all(&self.importlib.bootstrap_external_bytecode)?;
 
         let module_names = dest_dir.join("py-module-names");
-        let mut fh = File::create(&module_names)?;
+        let mut fh = create_file(&module_names)?;
         fh.write_all(&self.resources.module_names)?;
 
         let py_modules = dest_dir.join("py-modules");
-        let mut fh = File::create(&py_modules)?;
+        let mut fh = create_file(&py_modules)?;
         fh.write_all(&self.resources.modules)?;
 
         let resources = dest_dir.join("python-resources");
-        let mut fh = File::create(&resources)?;
+        let mut fh = create_file(&resources)?;
         fh.write_all(&self.resources.resources)?;
 
         let libpython = dest_dir.join(&self.library.libpython_filename);
-        let mut fh = File::create(&libpython)?;
+        let mut fh = create_file(&libpython)?;
         fh.write_all(&self.library.libpython_data)?;
 
         let libpyembeddedconfig = dest_dir.join(&self.library.libpyembeddedconfig_filename);
-        let mut fh = File::create(&libpyembeddedconfig)?;
+        let mut fh = create_file(&libpyembeddedconfig)?;
         fh.write_all(&self.library.libpyembeddedconfig_data)?;
 
         let config_rs_data = derive_python_config(
             &self.config,
             &self.run_mode,
+            &BTreeMap::new(),
             &importlib_bootstrap,
             &importlib_bootstrap_external,
             &py_modules,
             &resources,
+            self.resources.shared.as_ref(),
+            &self.identifiers,
         );
         let config_rs = dest_dir.join("data.rs");
-        write_data_rs(&config_rs, &config_rs_data)?;
+        write_data_rs(&config_rs, &config_rs_data, &[], &[])?;
+
+        self.config
+            .write_resolved_json(&dest_dir.join("resolved-config.json"), &self.target)?;
 
         let mut cargo_metadata_lines = Vec::new();
         cargo_metadata_lines.extend(self.library.cargo_metadata.clone());
+        cargo_metadata_lines.extend(self.config.cargo_metadata());
 
         // Tell Cargo where libpythonXY is located.
         cargo_metadata_lines.push(format!(
@@ -216,7 +300,7 @@ impl EmbeddedPythonBinaryData {
         ));
 
         let cargo_metadata = dest_dir.join("cargo_metadata.txt");
-        let mut fh = File::create(&cargo_metadata)?;
+        let mut fh = create_file(&cargo_metadata)?;
         fh.write_all(cargo_metadata_lines.join("\n").as_bytes())?;
 
         Ok(EmbeddedPythonBinaryPaths {
@@ -237,6 +321,7 @@ impl EmbeddedPythonBinaryData {
 pub mod tests {
     use super::*;
     use crate::py_packaging::distribution::ExtensionModuleFilter;
+    use crate::py_packaging::resource::SourceModule;
     use crate::testutil::*;
 
     pub fn get_prebuilt(logger: &slog::Logger) -> Result<PreBuiltPythonExecutable> {
@@ -258,6 +343,7 @@ pub mod tests {
             name: "testapp".to_string(),
             distribution,
             resources,
+            shared_resources: None,
             config,
             run_mode,
         })
@@ -283,4 +369,54 @@ pub mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_shared_resources_blobs() -> Result<()> {
+        let logger = get_logger()?;
+        let mut exe = get_prebuilt(&logger)?;
+
+        let module = |name: &str, source: &str| SourceModule {
+            name: name.to_string(),
+            source: source.as_bytes().to_vec(),
+            is_package: false,
+        };
+
+        let mut shared = EmbeddedPythonResourcesPrePackaged::default();
+        shared.add_source_module(&module("json", "x = 1\n"));
+        shared.add_source_module(&module("six", "y = 2\n"));
+
+        exe.resources.add_source_module(&module("json", "x = 1\n"));
+        exe.resources.add_source_module(&module("six", "y = 3\n"));
+        exe.resources.add_source_module(&module("myapp", "z = 4\n"));
+
+        let own = exe.build_embedded_blobs()?;
+        assert_eq!(own.module_names, b"json\nmyapp\nsix\n".to_vec());
+        assert!(own.shared.is_none());
+
+        exe.shared_resources = Some(SharedPythonResources {
+            path: "shared-python-resources".to_string(),
+            resources: shared,
+        });
+
+        let blobs = exe.build_embedded_blobs()?;
+        assert_eq!(blobs.module_names, b"myapp\nsix\n".to_vec());
+
+        let file = blobs.shared.unwrap();
+        assert_eq!(file.path, "shared-python-resources");
+        assert_eq!(Some(file), exe.shared_resources_file()?);
+
+        let embedded = EmbeddedPythonBinaryData::from_pre_built_python_executable(
+            &exe,
+            &logger,
+            env!("HOST"),
+            env!("HOST"),
+            "0",
+        )?;
+        let temp_dir = tempdir::TempDir::new("pyoxidizer-test")?;
+        let paths = embedded.write_files(temp_dir.path())?;
+        assert!(std::fs::read_to_string(&paths.config_rs)?
+            .contains("shared_resources: Some(SharedResources {"));
+
+        Ok(())
+    }
 }
@@ -0,0 +1,379 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/*!
+Detecting Python packages that compile extension modules when installed.
+
+Packages installed from source, e.g. ones shipping Cython `.pyx` sources,
+compile extension modules with the machine's compiler while being installed.
+The result targets the machine running PyOxidizer. That is fine if it is
+also the machine the application is built for, and wrong otherwise.
+
+Local source trees are inspected before installing using their
+`[build-system]` metadata (PEP 518) and sources. Packages only discovered
+while pip builds them are refused by our modified distutils, which records
+what it refused in its state directory.
+*/
+
+use anyhow::{anyhow, Context, Result};
+use serde::Deserialize;
+use slog::warn;
+use std::collections::HashMap;
+use std::path::Path;
+
+use super::distribution::ParsedPythonDistribution;
+use super::requirement::normalize_name;
+
+/// Build backend of source trees having a `setup.py` but no `[build-system]`.
+pub const LEGACY_BUILD_BACKEND: &str = "setuptools.build_meta:__legacy__";
+
+/// Build requirements implying extension modules are compiled.
+const COMPILED_BUILD_REQUIREMENTS: &[&str] = &[
+    "cython",
+    "cffi",
+    "pybind11",
+    "scikit-build",
+    "setuptools-rust",
+];
+
+/// A package compiling extension modules when installed from source.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CompiledBuild {
+    pub package: String,
+    pub build_backend: String,
+    /// Why the package is believed to compile extension modules.
+    pub reason: String,
+}
+
+impl std::fmt::Display for CompiledBuild {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{} (build backend {}): {}",
+            self.package, self.build_backend, self.reason
+        )
+    }
+}
+
+/// Obtain the name of the package a build requirement is on.
+fn requirement_name(requirement: &str) -> String {
+    let end = requirement
+        .find(|c: char| !(c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.'))
+        .unwrap_or_else(|| requirement.len());
+
+    normalize_name(&requirement[..end])
+}
+
+/// Obtain the name from the `[metadata]` section of a `setup.cfg`.
+fn setup_cfg_name(data: &str) -> Option<String> {
+    let mut in_metadata = false;
+
+    for line in data.lines().map(|l| l.trim()) {
+        if line.starts_with('[') {
+            in_metadata = line == "[metadata]";
+        } else if in_metadata {
+            let mut parts = line.splitn(2, '=');
+            let key = parts.next()?.trim();
+
+            if key == "name" {
+                return parts.next().map(|v| v.trim().to_string());
+            }
+        }
+    }
+
+    None
+}
+
+/// Find a Cython source in a source tree.
+fn find_cython_source(source_path: &Path) -> Option<String> {
+    walkdir::WalkDir::new(source_path)
+        .into_iter()
+        .filter_entry(|entry| !entry.file_name().to_string_lossy().starts_with('.'))
+        .filter_map(|entry| entry.ok())
+        .find(|entry| entry.path().extension().map_or(false, |ext| ext == "pyx"))
+        .and_then(|entry| {
+            entry
+                .path()
+                .strip_prefix(source_path)
+                .ok()
+                .map(|p| p.display().to_string())
+        })
+}
+
+/// Determine whether a source tree compiles extension modules when installed.
+///
+/// Returns `None` for paths not containing a Python source tree, such as
+/// wheel files, and for source trees only containing Python code.
+pub fn find_compiled_build(source_path: &Path) -> Result<Option<CompiledBuild>> {
+    if !source_path.is_dir() {
+        return Ok(None);
+    }
+
+    let pyproject_path = source_path.join("pyproject.toml");
+    let setup_py_path = source_path.join("setup.py");
+
+    let pyproject = if pyproject_path.exists() {
+        let data = std::fs::read_to_string(&pyproject_path)
+            .with_context(|| format!("reading {}", pyproject_path.display()))?;
+        Some(
+            data.parse::<toml::Value>()
+                .with_context(|| format!("parsing {}", pyproject_path.display()))?,
+        )
+    } else {
+        None
+    };
+
+    let build_system = pyproject.as_ref().and_then(|v| v.get("build-system"));
+
+    let build_backend = match build_system
+        .and_then(|v| v.get("build-backend"))
+        .and_then(|v| v.as_str())
+    {
+        Some(backend) => backend.to_string(),
+        None if pyproject.is_some() || setup_py_path.exists() => LEGACY_BUILD_BACKEND.to_string(),
+        None => return Ok(None),
+    };
+
+    let requires = build_system
+        .and_then(|v| v.get("requires"))
+        .and_then(|v| v.as_array())
+        .map(|values| {
+            values
+                .iter()
+                .filter_map(|v| v.as_str())
+                .map(String::from)
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+
+    let reason = if let Some(requirement) = requires
+        .iter()
+        .find(|r| COMPILED_BUILD_REQUIREMENTS.contains(&requirement_name(r).as_str()))
+    {
+        format!("build requires {}", requirement)
+    } else if let Some(path) = find_cython_source(source_path) {
+        format!("contains Cython source {}", path)
+    } else {
+        let setup_py = std::fs::read_to_string(&setup_py_path).unwrap_or_default();
+
+        if setup_py.contains("ext_modules") {
+            "setup.py declares extension modules".to_string()
+        } else {
+            return Ok(None);
+        }
+    };
+
+    let package = pyproject
+        .as_ref()
+        .and_then(|v| v.get("project"))
+        .and_then(|v| v.get("name"))
+        .and_then(|v| v.as_str())
+        .map(String::from)
+        .or_else(|| {
+            std::fs::read_to_string(source_path.join("setup.cfg"))
+                .ok()
+                .and_then(|data| setup_cfg_name(&data))
+        })
+        .unwrap_or_else(|| {
+            source_path
+                .file_name()
+                .map(|name| name.to_string_lossy().to_string())
+                .unwrap_or_else(|| source_path.display().to_string())
+        });
+
+    Ok(Some(CompiledBuild {
+        package,
+        build_backend,
+        reason,
+    }))
+}
+
+/// Whether the machine running PyOxidizer builds for another target.
+pub fn is_cross_compiling(host: &str, target: &str) -> bool {
+    host != target
+}
+
+/// Construct the error for packages that can't be compiled for a target.
+pub fn cross_compile_error(builds: &[CompiledBuild], host: &str, target: &str) -> anyhow::Error {
+    anyhow!(
+        "cannot build for {} on {}: packages compile extension modules when installed from source:\n  {}\n\
+         extension modules would be compiled for {} and PyOxidizer can't cross-compile them; \
+         install pre-built wheels for {} or build on a {} machine",
+        target,
+        host,
+        builds
+            .iter()
+            .map(|b| b.to_string())
+            .collect::<Vec<_>>()
+            .join("\n  "),
+        host,
+        target,
+        target
+    )
+}
+
+/// Report packages compiling extension modules and reject them when cross-compiling.
+pub fn check_compiled_builds(
+    logger: &slog::Logger,
+    builds: &[CompiledBuild],
+    host: &str,
+    target: &str,
+) -> Result<()> {
+    for build in builds {
+        warn!(logger, "compiling extension modules of {}", build);
+    }
+
+    if !builds.is_empty() && is_cross_compiling(host, target) {
+        Err(cross_compile_error(builds, host, target))
+    } else {
+        Ok(())
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct RefusedBuildState {
+    package: String,
+    build_backend: String,
+    extensions: Vec<String>,
+}
+
+/// Read packages our modified distutils refused to compile extension modules for.
+pub fn read_refused_builds(state_dir: &Path) -> Result<Vec<CompiledBuild>> {
+    let mut res = Vec::new();
+
+    for entry in std::fs::read_dir(state_dir)? {
+        let path = entry?.path();
+        let file_name = path.file_name().unwrap().to_string_lossy().to_string();
+
+        if !file_name.starts_with("refused.") || !file_name.ends_with(".json") {
+            continue;
+        }
+
+        let state: RefusedBuildState = serde_json::from_str(&std::fs::read_to_string(&path)?)?;
+
+        res.push(CompiledBuild {
+            package: state.package,
+            build_backend: state.build_backend,
+            reason: format!("builds extension modules {}", state.extensions.join(", ")),
+        });
+    }
+
+    res.sort_by(|a, b| a.package.cmp(&b.package));
+
+    Ok(res)
+}
+
+/// Environment variables pointing compilers at a Python distribution.
+///
+/// Include and library paths of the distribution are prepended to `CFLAGS`
+/// and `LDFLAGS` so extensions are compiled against its CPython version
+/// rather than whatever the machine has installed. `PYTHON_SYS_EXECUTABLE`
+/// and `PYO3_PYTHON` do the same for Rust extensions.
+pub fn compiled_build_envs(dist: &ParsedPythonDistribution) -> HashMap<String, String> {
+    let mut res = HashMap::new();
+
+    let prepend = |name: &str, value: String| match std::env::var(name) {
+        Ok(existing) if !existing.is_empty() => format!("{} {}", value, existing),
+        _ => value,
+    };
+
+    if let Some(include_path) = dist.includes.get("Python.h").and_then(|p| p.parent()) {
+        res.insert(
+            "CFLAGS".to_string(),
+            prepend("CFLAGS", format!("-I{}", include_path.display())),
+        );
+    }
+
+    if let Some(library_path) = dist
+        .libraries
+        .iter()
+        .find(|(name, _)| name.starts_with("python"))
+        .and_then(|(_, path)| path.parent())
+    {
+        res.insert(
+            "LDFLAGS".to_string(),
+            prepend("LDFLAGS", format!("-L{}", library_path.display())),
+        );
+    }
+
+    let python_exe = dist.python_exe.display().to_string();
+    res.insert("PYTHON_SYS_EXECUTABLE".to_string(), python_exe.clone());
+    res.insert("PYO3_PYTHON".to_string(), python_exe);
+
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_find_compiled_build() -> Result<()> {
+        let temp_dir = tempdir::TempDir::new("pyoxidizer-test")?;
+        let root = temp_dir.path();
+
+        let package = root.join("pure");
+        fs::create_dir_all(&package)?;
+        assert_eq!(find_compiled_build(&package)?, None);
+        fs::write(package.join("setup.py"), "setup(name='pure')\n")?;
+        assert_eq!(find_compiled_build(&package)?, None);
+
+        let package = root.join("fastthing");
+        fs::create_dir_all(package.join("src"))?;
+        fs::write(
+            package.join("pyproject.toml"),
+            "[build-system]\nrequires = [\"setuptools\", \"Cython>=0.29\"]\n\
+             build-backend = \"setuptools.build_meta\"\n",
+        )?;
+        fs::write(package.join("setup.cfg"), "[metadata]\nname = fast-thing\n")?;
+        assert_eq!(
+            find_compiled_build(&package)?,
+            Some(CompiledBuild {
+                package: "fast-thing".to_string(),
+                build_backend: "setuptools.build_meta".to_string(),
+                reason: "build requires Cython>=0.29".to_string(),
+            })
+        );
+
+        let package = root.join("legacy");
+        fs::create_dir_all(package.join("legacy"))?;
+        fs::write(package.join("setup.py"), "setup(name='legacy')\n")?;
+        fs::write(package.join("legacy").join("speedups.pyx"), "")?;
+        let build = find_compiled_build(&package)?.unwrap();
+        assert_eq!(build.package, "legacy");
+        assert_eq!(build.build_backend, LEGACY_BUILD_BACKEND);
+        assert!(build.reason.starts_with("contains Cython source legacy"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_refused_builds() -> Result<()> {
+        let temp_dir = tempdir::TempDir::new("pyoxidizer-test")?;
+
+        fs::write(
+            temp_dir.path().join("refused.fastthing.json"),
+            r#"{"package": "fastthing", "build_backend": "setuptools.build_meta", "extensions": ["fastthing._speedups"]}"#,
+        )?;
+        fs::write(temp_dir.path().join("extension.other.json"), "{}")?;
+
+        let builds = read_refused_builds(temp_dir.path())?;
+        assert_eq!(builds.len(), 1);
+
+        let message = cross_compile_error(
+            &builds,
+            "x86_64-unknown-linux-gnu",
+            "x86_64-unknown-linux-musl",
+        )
+        .to_string();
+        assert!(message.contains(
+            "fastthing (build backend setuptools.build_meta): builds extension modules fastthing._speedups"
+        ));
+        assert!(message
+            .contains("cannot build for x86_64-unknown-linux-musl on x86_64-unknown-linux-gnu"));
+
+        Ok(())
+    }
+}
@@ -2,7 +2,7 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use lazy_static::lazy_static;
 use std::fs::File;
 use std::io::{BufRead, BufReader, Read, Write};
@@ -10,42 +10,148 @@ use std::path::{Path, PathBuf};
 use std::process;
 
 use super::resource::BytecodeOptimizationLevel;
+use crate::cancel::{self, CancellableChild};
 
 pub const BYTECODE_COMPILER: &[u8] = include_bytes!("bytecodecompiler.py");
 
+/// UTF-8 byte order mark.
+const UTF8_BOM: &[u8] = b"\xef\xbb\xbf";
+
 lazy_static! {
     static ref RE_CODING: regex::bytes::Regex =
         { regex::bytes::Regex::new(r"^[ \t\f]*#.*?coding[:=][ \t]*([-_.a-zA-Z0-9]+)").unwrap() };
 }
 
+/// Find the PEP 263 coding cookie in Python source code.
+fn coding_cookie(source: &[u8]) -> Option<String> {
+    // The cookie must be on the first or second line.
+    for line in source.split(|v| v == &b'\n').take(2) {
+        if let Some(caps) = RE_CODING.captures(line) {
+            return Some(String::from_utf8_lossy(&caps[1]).to_string());
+        }
+    }
+
+    None
+}
+
+/// Normalize an encoding name the way CPython's tokenizer does.
+fn normalize_encoding(name: &str) -> String {
+    let name = name.to_lowercase().replace('_', "-");
+
+    if name == "utf-8" || name.starts_with("utf-8-") {
+        "utf-8".to_string()
+    } else if ["latin-1", "iso-8859-1", "iso-latin-1"]
+        .iter()
+        .any(|n| name == *n || name.starts_with(&format!("{}-", n)))
+    {
+        "iso-8859-1".to_string()
+    } else {
+        name
+    }
+}
+
 /// Derive the source encoding from Python source code.
 pub fn python_source_encoding(source: &[u8]) -> Vec<u8> {
-    // Default source encoding is UTF-8. But per PEP 263, the first or second
-    // line of source can match a regular expression to define a custom
-    // encoding.
-    let lines = source.split(|v| v == &b'\n');
-
-    for (i, line) in lines.enumerate() {
-        if i > 1 {
-            break;
-        }
+    // Per PEP 3120 the default source encoding is UTF-8. Per PEP 263, a
+    // UTF-8 BOM or a coding cookie on the first or second line overrides it.
+    if source.starts_with(UTF8_BOM) {
+        return b"utf-8".to_vec();
+    }
+
+    match coding_cookie(source) {
+        Some(cookie) => normalize_encoding(&cookie).into_bytes(),
+        None => b"utf-8".to_vec(),
+    }
+}
+
+/// Decode data, returning the offset of the first invalid byte on failure.
+fn decode_with_offset(
+    encoding: &'static encoding_rs::Encoding,
+    data: &[u8],
+) -> std::result::Result<String, usize> {
+    let mut decoder = encoding.new_decoder_without_bom_handling();
+    let mut decoded = String::new();
+    let mut offset = 0;
+
+    loop {
+        decoded.reserve(
+            decoder
+                .max_utf8_buffer_length_without_replacement(data.len() - offset)
+                .unwrap_or(4096),
+        );
+
+        let (result, read) =
+            decoder.decode_to_string_without_replacement(&data[offset..], &mut decoded, true);
+        offset += read;
 
-        if let Some(m) = RE_CODING.find(line) {
-            return m.as_bytes().to_vec();
+        match result {
+            encoding_rs::DecoderResult::InputEmpty => return Ok(decoded),
+            encoding_rs::DecoderResult::OutputFull => {}
+            encoding_rs::DecoderResult::Malformed(bad, extra) => {
+                return Err(offset - bad as usize - extra as usize)
+            }
         }
     }
+}
+
+/// Decode Python source code according to PEP 263 and PEP 3120.
+///
+/// Source is decoded for inspection only: packaged source and the input
+/// to bytecode compilation remain the original bytes.
+///
+/// Errors name `filename`, the encoding and the offset of the first byte
+/// that fails to decode. Encodings only known to Python can't be decoded
+/// here; `None` is returned for them.
+pub fn decode_python_source(source: &[u8], filename: &str) -> Result<Option<String>> {
+    let (data, base_offset) = if source.starts_with(UTF8_BOM) {
+        if let Some(cookie) = coding_cookie(source) {
+            if normalize_encoding(&cookie) != "utf-8" {
+                return Err(anyhow!(
+                    "{}: encoding problem: {} with BOM",
+                    filename,
+                    cookie
+                ));
+            }
+        }
+
+        (&source[UTF8_BOM.len()..], UTF8_BOM.len())
+    } else {
+        (source, 0)
+    };
+
+    let encoding = String::from_utf8_lossy(&python_source_encoding(source)).to_string();
 
-    b"utf-8".to_vec()
+    let decoded = if encoding == "ascii" || encoding == "us-ascii" {
+        match data.iter().position(|b| *b >= 0x80) {
+            Some(offset) => Err(offset),
+            None => Ok(String::from_utf8_lossy(data).to_string()),
+        }
+    } else if let Some(decoder) = encoding_rs::Encoding::for_label(encoding.as_bytes()) {
+        decode_with_offset(decoder, data)
+    } else {
+        return Ok(None);
+    };
+
+    match decoded {
+        Ok(decoded) => Ok(Some(decoded)),
+        Err(offset) => Err(anyhow!(
+            "{}: unable to decode source as {}: invalid data at byte offset {}",
+            filename,
+            encoding,
+            base_offset + offset
+        )),
+    }
 }
 
 /// An entity to perform Python bytecode compilation.
 #[derive(Debug)]
 pub struct BytecodeCompiler {
     _temp_dir: tempdir::TempDir,
-    command: process::Child,
+    command: Option<CancellableChild>,
 }
 
 /// Output mode for BytecodeCompiler.
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum CompileMode {
     /// Emit just Python bytecode.
     Bytecode,
@@ -55,6 +161,16 @@ pub enum CompileMode {
     PycUncheckedHash,
 }
 
+impl CompileMode {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            CompileMode::Bytecode => "bytecode",
+            CompileMode::PycCheckedHash => "pyc-checked-hash",
+            CompileMode::PycUncheckedHash => "pyc-unchecked-hash",
+        }
+    }
+}
+
 impl BytecodeCompiler {
     pub fn new(python: &Path) -> Result<BytecodeCompiler> {
         let temp_dir = tempdir::TempDir::new("bytecode-compiler")?;
@@ -66,15 +182,16 @@ impl BytecodeCompiler {
             fh.write_all(BYTECODE_COMPILER)?;
         }
 
-        let command = process::Command::new(python)
-            .arg(script_path.clone())
-            .stdin(process::Stdio::piped())
-            .stdout(process::Stdio::piped())
-            .spawn()?;
+        let command = cancel::spawn(
+            process::Command::new(python)
+                .arg(script_path.clone())
+                .stdin(process::Stdio::piped())
+                .stdout(process::Stdio::piped()),
+        )?;
 
         Ok(BytecodeCompiler {
             _temp_dir: temp_dir,
-            command,
+            command: Some(command),
         })
     }
 
@@ -86,8 +203,56 @@ impl BytecodeCompiler {
         optimize: BytecodeOptimizationLevel,
         output_mode: CompileMode,
     ) -> Result<Vec<u8>> {
-        let stdin = self.command.stdin.as_mut().expect("failed to get stdin");
-        let stdout = self.command.stdout.as_mut().expect("failed to get stdout");
+        cancel::check_cancelled()?;
+
+        // Surface undecodable source with a precise location before Python
+        // reports it with less context.
+        decode_python_source(source, filename)?;
+
+        let res = self.send_compile(source, filename, optimize, output_mode);
+
+        // The compiler process is killed if the build is cancelled, which
+        // manifests as an I/O error.
+        if res.is_err() {
+            cancel::check_cancelled()?;
+        }
+
+        res
+    }
+
+    /// Obtain the magic number and version of the compiling interpreter.
+    ///
+    /// Bytecode produced by interpreters with different values may differ.
+    pub fn magic_number(&mut self) -> Result<Vec<u8>> {
+        let child = self.command.as_mut().unwrap().child_mut();
+        let stdin = child.stdin.as_mut().expect("failed to get stdin");
+        let stdout = child.stdout.as_mut().expect("failed to get stdout");
+
+        let mut reader = BufReader::new(stdout);
+
+        stdin.write_all(b"magic\n")?;
+        stdin.flush()?;
+
+        let mut len_s = String::new();
+        reader.read_line(&mut len_s)?;
+        let len = len_s.trim_end().parse::<u64>()?;
+
+        let mut magic = Vec::new();
+        reader.take(len).read_to_end(&mut magic)?;
+
+        Ok(magic)
+    }
+
+    fn send_compile(
+        &mut self,
+        source: &[u8],
+        filename: &str,
+        optimize: BytecodeOptimizationLevel,
+        output_mode: CompileMode,
+    ) -> Result<Vec<u8>> {
+        let child = self.command.as_mut().unwrap().child_mut();
+        let stdin = child.stdin.as_mut().expect("failed to get stdin");
+        let stdout = child.stdout.as_mut().expect("failed to get stdout");
 
         let mut reader = BufReader::new(stdout);
 
@@ -98,11 +263,7 @@ impl BytecodeCompiler {
         stdin.write_all(b"\n")?;
         stdin.write_all(i32::from(optimize).to_string().as_bytes())?;
         stdin.write_all(b"\n")?;
-        stdin.write_all(match output_mode {
-            CompileMode::Bytecode => b"bytecode",
-            CompileMode::PycCheckedHash => b"pyc-checked-hash",
-            CompileMode::PycUncheckedHash => b"pyc-unchecked-hash",
-        })?;
+        stdin.write_all(output_mode.as_str().as_bytes())?;
         stdin.write_all(b"\n")?;
         stdin.write_all(filename.as_bytes())?;
         stdin.write_all(source)?;
@@ -112,7 +273,19 @@ impl BytecodeCompiler {
         reader.read_line(&mut len_s)?;
 
         let len_s = len_s.trim_end();
-        let bytecode_len = len_s.parse::<u64>().unwrap();
+
+        if len_s == "error" {
+            let mut len_s = String::new();
+            reader.read_line(&mut len_s)?;
+            let message_len = len_s.trim_end().parse::<u64>()?;
+
+            let mut message = Vec::new();
+            reader.take(message_len).read_to_end(&mut message)?;
+
+            return Err(anyhow!("{}", String::from_utf8_lossy(&message)));
+        }
+
+        let bytecode_len = len_s.parse::<u64>()?;
 
         let mut bytecode: Vec<u8> = Vec::new();
         reader.take(bytecode_len).read_to_end(&mut bytecode)?;
@@ -123,10 +296,89 @@ impl BytecodeCompiler {
 
 impl Drop for BytecodeCompiler {
     fn drop(&mut self) {
-        let stdin = self.command.stdin.as_mut().expect("failed to get stdin");
-        stdin.write_all(b"exit\n").expect("write failed");
-        stdin.flush().expect("flush failed");
+        if let Some(mut command) = self.command.take() {
+            // The process may already be gone if the build was cancelled.
+            if let Some(stdin) = command.child_mut().stdin.as_mut() {
+                let _ = stdin.write_all(b"exit\n");
+                let _ = stdin.flush();
+            }
+
+            let _ = command.wait();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BOM_CRLF: &[u8] = b"\xef\xbb\xbfx = '\xc3\xa9'\r\nprint(x)\r\n";
+    const LATIN1_COOKIE: &[u8] =
+        b"#!/usr/bin/env python\r\n# -*- coding: latin-1 -*-\r\nx = '\xe9'\r\n";
+    const INVALID_UTF8: &[u8] = b"x = 1\ny = '\xff'\n";
+    const BOM_LATIN1_COOKIE: &[u8] = b"\xef\xbb\xbf# coding: latin-1\nx = 1\n";
+
+    #[test]
+    fn test_python_source_encoding() {
+        assert_eq!(python_source_encoding(b"x = 1\n"), b"utf-8");
+        assert_eq!(python_source_encoding(BOM_CRLF), b"utf-8");
+        assert_eq!(python_source_encoding(LATIN1_COOKIE), b"iso-8859-1");
+        assert_eq!(
+            python_source_encoding(b"# vim: set fileencoding=UTF_8_SIG :\n"),
+            b"utf-8"
+        );
+        assert_eq!(python_source_encoding(b"# coding=cp1252\n"), b"cp1252");
+        // Cookies after the second line are ignored.
+        assert_eq!(python_source_encoding(b"\n\n# coding: latin-1\n"), b"utf-8");
+    }
+
+    #[test]
+    fn test_decode_python_source() -> Result<()> {
+        assert_eq!(
+            decode_python_source(BOM_CRLF, "bom.py")?,
+            Some("x = 'é'\r\nprint(x)\r\n".to_string())
+        );
+        assert_eq!(
+            decode_python_source(LATIN1_COOKIE, "latin1.py")?,
+            Some("#!/usr/bin/env python\r\n# -*- coding: latin-1 -*-\r\nx = 'é'\r\n".to_string())
+        );
+        assert_eq!(
+            decode_python_source(b"# coding: x-unknown\n", "unknown.py")?,
+            None
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_decode_python_source_errors() {
+        assert_eq!(
+            decode_python_source(INVALID_UTF8, "invalid.py")
+                .unwrap_err()
+                .to_string(),
+            "invalid.py: unable to decode source as utf-8: invalid data at byte offset 11"
+        );
+
+        // Offsets are relative to the start of the file, including the BOM.
+        let mut source = BOM_CRLF.to_vec();
+        source.push(0xff);
+        assert!(decode_python_source(&source, "bom.py")
+            .unwrap_err()
+            .to_string()
+            .ends_with(&format!("byte offset {}", BOM_CRLF.len())));
+
+        assert_eq!(
+            decode_python_source(b"# coding: ascii\nx = '\xe9'\n", "ascii.py")
+                .unwrap_err()
+                .to_string(),
+            "ascii.py: unable to decode source as ascii: invalid data at byte offset 21"
+        );
 
-        self.command.wait().expect("compiler process did not exit");
+        assert_eq!(
+            decode_python_source(BOM_LATIN1_COOKIE, "bom.py")
+                .unwrap_err()
+                .to_string(),
+            "bom.py: encoding problem: latin-1 with BOM"
+        );
     }
 }
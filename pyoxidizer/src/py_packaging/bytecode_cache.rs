@@ -0,0 +1,553 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/*!
+Caching compiled bytecode across projects.
+
+Projects built with the same Python distribution compile the same standard
+library. Compiled bytecode is cached in the `bytecode` directory of the
+machine-global cache (see `crate::cache`), so only the first project pays
+for compiling it.
+
+Entries are keyed by a hash of everything affecting the compiler's output:
+the distribution's SHA-256, the compiling interpreter's magic number and
+version, the optimization level, the output mode, the module name (which
+becomes the code's filename) and the SHA-256 of the source.
+
+Reading an entry updates its modification time. Pruning deletes the least
+recently used entries first.
+*/
+
+use anyhow::{Context, Result};
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use super::bytecode::{BytecodeCompiler, CompileMode};
+use super::resource::BytecodeOptimizationLevel;
+use crate::cache::cache_dir;
+use crate::cancel;
+
+/// Name of the directory in the machine-global cache holding bytecode.
+pub const BYTECODE_CACHE_DIR: &str = "bytecode";
+
+/// Size in bytes the bytecode cache is pruned to after builds.
+pub const DEFAULT_MAX_CACHE_SIZE: u64 = 512 * 1024 * 1024;
+
+/// Derive the cache key of compiled bytecode.
+pub fn cache_key(
+    distribution_sha256: &str,
+    magic: &[u8],
+    optimize: BytecodeOptimizationLevel,
+    mode: CompileMode,
+    name: &str,
+    source: &[u8],
+) -> String {
+    let source_sha256 = Sha256::digest(source);
+    let optimize = i32::from(optimize).to_string();
+    let distribution_sha256 = distribution_sha256.to_lowercase();
+
+    let mut hasher = Sha256::new();
+
+    // Fields are length prefixed so they can't run into each other.
+    for field in &[
+        distribution_sha256.as_bytes(),
+        magic,
+        optimize.as_bytes(),
+        mode.as_str().as_bytes(),
+        name.as_bytes(),
+        source_sha256.as_slice(),
+    ] {
+        hasher.input(&(field.len() as u64).to_le_bytes());
+        hasher.input(field);
+    }
+
+    hex::encode(hasher.result())
+}
+
+/// Outcome of pruning a cache.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PruneStats {
+    pub removed_entries: usize,
+    pub removed_bytes: u64,
+    pub remaining_bytes: u64,
+}
+
+/// Compiled bytecode stored in the machine-global cache.
+#[derive(Clone, Debug)]
+pub struct BytecodeCache {
+    dir: PathBuf,
+}
+
+impl BytecodeCache {
+    /// Obtain the bytecode cache of a cache directory.
+    pub fn new(cache_dir: &Path) -> BytecodeCache {
+        BytecodeCache {
+            dir: cache_dir.join(BYTECODE_CACHE_DIR),
+        }
+    }
+
+    /// Obtain the bytecode cache of the machine-global cache directory.
+    pub fn default_cache() -> Option<BytecodeCache> {
+        cache_dir().map(|dir| BytecodeCache::new(&dir))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.dir
+    }
+
+    fn entry_path(&self, key: &str) -> PathBuf {
+        self.dir.join(&key[0..2]).join(key)
+    }
+
+    /// Read a cached entry, marking it as recently used.
+    ///
+    /// Unreadable entries are treated as missing.
+    pub fn get(&self, key: &str) -> Option<Vec<u8>> {
+        let path = self.entry_path(key);
+        let data = std::fs::read(&path).ok()?;
+
+        let _ = filetime::set_file_mtime(&path, filetime::FileTime::now());
+
+        Some(data)
+    }
+
+    /// Store an entry.
+    ///
+    /// The entry is written to a temporary file and renamed into place, so
+    /// concurrent readers never see partial entries.
+    pub fn put(&self, key: &str, data: &[u8]) -> Result<()> {
+        let path = self.entry_path(key);
+        let parent = path.parent().unwrap();
+        std::fs::create_dir_all(parent)?;
+
+        let temp_path = parent.join(format!("{}.{}.tmp", key, uuid::Uuid::new_v4()));
+        std::fs::write(&temp_path, data)
+            .with_context(|| format!("writing {}", temp_path.display()))?;
+
+        std::fs::rename(&temp_path, &path).or_else(|e| {
+            let _ = std::fs::remove_file(&temp_path);
+            Err(e).with_context(|| format!("renaming {}", temp_path.display()))
+        })
+    }
+
+    /// Files in the cache with their size and modification time.
+    fn entries(&self) -> Result<Vec<(PathBuf, u64, SystemTime)>> {
+        if !self.dir.exists() {
+            return Ok(Vec::new());
+        }
+
+        let mut entries = Vec::new();
+
+        for entry in walkdir::WalkDir::new(&self.dir) {
+            let entry = entry?;
+
+            if !entry.file_type().is_file() {
+                continue;
+            }
+
+            let metadata = entry.metadata()?;
+            entries.push((
+                entry.path().to_path_buf(),
+                metadata.len(),
+                metadata.modified()?,
+            ));
+        }
+
+        Ok(entries)
+    }
+
+    /// Total size in bytes of cached entries.
+    pub fn size(&self) -> Result<u64> {
+        Ok(self.entries()?.iter().map(|(_, size, _)| size).sum())
+    }
+
+    /// Delete the least recently used entries until the cache is at most
+    /// `max_size` bytes.
+    pub fn prune(&self, max_size: u64) -> Result<PruneStats> {
+        self.prune_while(|stats, _| stats.remaining_bytes > max_size)
+    }
+
+    /// Delete entries not used since `cutoff`.
+    pub fn prune_unused_since(&self, cutoff: SystemTime) -> Result<PruneStats> {
+        self.prune_while(|_, modified| modified < cutoff)
+    }
+
+    /// Delete entries, least recently used first, while `condition` holds.
+    fn prune_while<F>(&self, condition: F) -> Result<PruneStats>
+    where
+        F: Fn(&PruneStats, SystemTime) -> bool,
+    {
+        let mut entries = self.entries()?;
+        entries.sort_by(|a, b| a.2.cmp(&b.2).then_with(|| a.0.cmp(&b.0)));
+
+        let mut stats = PruneStats {
+            remaining_bytes: entries.iter().map(|(_, size, _)| size).sum(),
+            ..PruneStats::default()
+        };
+
+        for (path, size, modified) in entries {
+            if !condition(&stats, modified) {
+                break;
+            }
+
+            // Another process may have removed it already.
+            match std::fs::remove_file(&path) {
+                Ok(()) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e).with_context(|| format!("removing {}", path.display())),
+            }
+
+            stats.removed_entries += 1;
+            stats.removed_bytes += size;
+            stats.remaining_bytes -= size;
+        }
+
+        Ok(stats)
+    }
+}
+
+/// A bytecode compiler consulting a `BytecodeCache` before compiling.
+pub struct CachingBytecodeCompiler {
+    compiler: BytecodeCompiler,
+    cache: Option<BytecodeCache>,
+    distribution_sha256: String,
+    magic: Option<Vec<u8>>,
+    /// Number of compilations served from the cache.
+    pub hits: usize,
+    /// Number of compilations performed by the compiler.
+    pub misses: usize,
+}
+
+impl CachingBytecodeCompiler {
+    /// Create a compiler using the Python interpreter of a distribution.
+    ///
+    /// If `cache` is `None` or the distribution's SHA-256 is unknown,
+    /// everything is compiled.
+    pub fn new(
+        python: &Path,
+        cache: Option<BytecodeCache>,
+        distribution_sha256: &str,
+    ) -> Result<CachingBytecodeCompiler> {
+        Ok(CachingBytecodeCompiler {
+            compiler: BytecodeCompiler::new(python)?,
+            cache: if distribution_sha256.is_empty() {
+                None
+            } else {
+                cache
+            },
+            distribution_sha256: distribution_sha256.to_string(),
+            magic: None,
+            hits: 0,
+            misses: 0,
+        })
+    }
+
+    /// Compile Python source into bytecode, reusing cached bytecode.
+    ///
+    /// Failing to store bytecode in the cache doesn't fail compilation.
+    pub fn compile(
+        &mut self,
+        source: &[u8],
+        filename: &str,
+        optimize: BytecodeOptimizationLevel,
+        output_mode: CompileMode,
+    ) -> Result<Vec<u8>> {
+        let cache = match &self.cache {
+            Some(cache) => cache,
+            None => {
+                self.misses += 1;
+                return self
+                    .compiler
+                    .compile(source, filename, optimize, output_mode);
+            }
+        };
+
+        if self.magic.is_none() {
+            self.magic = Some(self.compiler.magic_number()?);
+        }
+
+        let key = cache_key(
+            &self.distribution_sha256,
+            self.magic.as_ref().unwrap(),
+            optimize,
+            output_mode,
+            filename,
+            source,
+        );
+
+        if let Some(bytecode) = cache.get(&key) {
+            cancel::check_cancelled()?;
+            self.hits += 1;
+
+            return Ok(bytecode);
+        }
+
+        let bytecode = self
+            .compiler
+            .compile(source, filename, optimize, output_mode)?;
+        self.misses += 1;
+
+        let _ = cache.put(&key, &bytecode);
+
+        Ok(bytecode)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::*;
+
+    #[test]
+    fn test_cache_key() {
+        let key = |distribution: &str, magic: &[u8], level, mode, name: &str, source: &[u8]| {
+            cache_key(distribution, magic, level, mode, name, source)
+        };
+
+        let base = key(
+            "ABCD",
+            b"\x42\x0d\x0d\x0a",
+            BytecodeOptimizationLevel::Zero,
+            CompileMode::Bytecode,
+            "json",
+            b"x = 1\n",
+        );
+        assert_eq!(base.len(), 64);
+
+        // The distribution hash isn't case sensitive.
+        assert_eq!(
+            base,
+            key(
+                "abcd",
+                b"\x42\x0d\x0d\x0a",
+                BytecodeOptimizationLevel::Zero,
+                CompileMode::Bytecode,
+                "json",
+                b"x = 1\n",
+            )
+        );
+
+        let others = vec![
+            key(
+                "abce",
+                b"\x42\x0d\x0d\x0a",
+                BytecodeOptimizationLevel::Zero,
+                CompileMode::Bytecode,
+                "json",
+                b"x = 1\n",
+            ),
+            key(
+                "abcd",
+                b"\x55\x0d\x0d\x0a",
+                BytecodeOptimizationLevel::Zero,
+                CompileMode::Bytecode,
+                "json",
+                b"x = 1\n",
+            ),
+            key(
+                "abcd",
+                b"\x42\x0d\x0d\x0a",
+                BytecodeOptimizationLevel::Two,
+                CompileMode::Bytecode,
+                "json",
+                b"x = 1\n",
+            ),
+            key(
+                "abcd",
+                b"\x42\x0d\x0d\x0a",
+                BytecodeOptimizationLevel::Zero,
+                CompileMode::PycUncheckedHash,
+                "json",
+                b"x = 1\n",
+            ),
+            key(
+                "abcd",
+                b"\x42\x0d\x0d\x0a",
+                BytecodeOptimizationLevel::Zero,
+                CompileMode::Bytecode,
+                "json.decoder",
+                b"x = 1\n",
+            ),
+            key(
+                "abcd",
+                b"\x42\x0d\x0d\x0a",
+                BytecodeOptimizationLevel::Zero,
+                CompileMode::Bytecode,
+                "json",
+                b"x = 2\n",
+            ),
+        ];
+
+        for other in &others {
+            assert_ne!(&base, other);
+        }
+    }
+
+    #[test]
+    fn test_prune() -> Result<()> {
+        let temp_dir = tempdir::TempDir::new("pyoxidizer-test")?;
+        let cache = BytecodeCache::new(temp_dir.path());
+
+        assert_eq!(cache.prune(0)?, PruneStats::default());
+
+        let keys = ["aa01", "bb02", "cc03"];
+        for (i, key) in keys.iter().enumerate() {
+            cache.put(key, &[0; 10])?;
+            filetime::set_file_mtime(
+                cache.entry_path(key),
+                filetime::FileTime::from_unix_time(1_000_000 + i as i64, 0),
+            )?;
+        }
+        assert_eq!(cache.size()?, 30);
+
+        // Reading an entry makes it the most recently used.
+        assert_eq!(cache.get("aa01"), Some(vec![0; 10]));
+
+        assert_eq!(
+            cache.prune(20)?,
+            PruneStats {
+                removed_entries: 1,
+                removed_bytes: 10,
+                remaining_bytes: 20,
+            }
+        );
+        assert!(cache.get("bb02").is_none());
+        assert!(cache.get("aa01").is_some());
+        assert!(cache.get("cc03").is_some());
+
+        assert_eq!(cache.prune(0)?.removed_entries, 2);
+        assert_eq!(cache.size()?, 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_prune_unused_since() -> Result<()> {
+        let temp_dir = tempdir::TempDir::new("pyoxidizer-test")?;
+        let cache = BytecodeCache::new(temp_dir.path());
+
+        for (i, key) in ["aa01", "bb02", "cc03"].iter().enumerate() {
+            cache.put(key, &[0; 10])?;
+            filetime::set_file_mtime(
+                cache.entry_path(key),
+                filetime::FileTime::from_unix_time(1_000_000 + i as i64, 0),
+            )?;
+        }
+
+        let cutoff = std::time::UNIX_EPOCH + std::time::Duration::from_secs(1_000_002);
+        assert_eq!(
+            cache.prune_unused_since(cutoff)?,
+            PruneStats {
+                removed_entries: 2,
+                removed_bytes: 20,
+                remaining_bytes: 10,
+            }
+        );
+        assert!(cache.get("cc03").is_some());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_caching_compiler() -> Result<()> {
+        let dist = get_default_distribution()?;
+        let temp_dir = tempdir::TempDir::new("pyoxidizer-test")?;
+        let cache = BytecodeCache::new(temp_dir.path());
+
+        let compile = |compiler: &mut CachingBytecodeCompiler, source: &[u8]| {
+            compiler.compile(
+                source,
+                "json",
+                BytecodeOptimizationLevel::Zero,
+                CompileMode::Bytecode,
+            )
+        };
+
+        let mut compiler =
+            CachingBytecodeCompiler::new(&dist.python_exe, Some(cache.clone()), "abcd")?;
+        let first = compile(&mut compiler, b"x = 1\n")?;
+        assert_eq!((compiler.hits, compiler.misses), (0, 1));
+
+        // A second project reuses the cached bytecode.
+        let mut compiler =
+            CachingBytecodeCompiler::new(&dist.python_exe, Some(cache.clone()), "abcd")?;
+        assert_eq!(compile(&mut compiler, b"x = 1\n")?, first);
+        assert_eq!((compiler.hits, compiler.misses), (1, 0));
+
+        // Changed source isn't served from the cache.
+        compile(&mut compiler, b"x = 2\n")?;
+        assert_eq!((compiler.hits, compiler.misses), (1, 1));
+
+        // Neither is bytecode of another distribution.
+        let mut compiler = CachingBytecodeCompiler::new(&dist.python_exe, Some(cache), "abce")?;
+        assert_eq!(compile(&mut compiler, b"x = 1\n")?, first);
+        assert_eq!((compiler.hits, compiler.misses), (0, 1));
+
+        // Errors aren't cached.
+        assert!(compile(&mut compiler, b"x = \n").is_err());
+        assert!(compile(&mut compiler, b"x = \n").is_err());
+
+        Ok(())
+    }
+
+    /// Compares compiling the standard library with an empty cache and
+    /// compiling it again from the filled cache.
+    ///
+    /// Run with `cargo test --release -- --ignored --nocapture bench_`.
+    #[test]
+    #[ignore]
+    fn bench_stdlib_compile() -> Result<()> {
+        use crate::py_packaging::distribution::is_stdlib_test_package;
+        use std::time::Instant;
+
+        let dist = get_default_distribution()?;
+        let temp_dir = tempdir::TempDir::new("pyoxidizer-test")?;
+        let cache = BytecodeCache::new(temp_dir.path());
+
+        let modules = dist
+            .source_modules()?
+            .into_iter()
+            .filter(|module| !is_stdlib_test_package(&module.package()))
+            .collect::<Vec<_>>();
+
+        let compile_all = |compiler: &mut CachingBytecodeCompiler| -> Result<Vec<Vec<u8>>> {
+            modules
+                .iter()
+                .map(|module| {
+                    compiler.compile(
+                        &module.source,
+                        &module.name,
+                        BytecodeOptimizationLevel::Zero,
+                        CompileMode::Bytecode,
+                    )
+                })
+                .collect()
+        };
+
+        let mut compiler =
+            CachingBytecodeCompiler::new(&dist.python_exe, Some(cache.clone()), "abcd")?;
+        let start = Instant::now();
+        let cold_bytecode = compile_all(&mut compiler)?;
+        let cold = start.elapsed();
+        assert_eq!(compiler.hits, 0);
+
+        let mut compiler = CachingBytecodeCompiler::new(&dist.python_exe, Some(cache), "abcd")?;
+        let start = Instant::now();
+        let warm_bytecode = compile_all(&mut compiler)?;
+        let warm = start.elapsed();
+        assert_eq!((compiler.hits, compiler.misses), (modules.len(), 0));
+
+        eprintln!(
+            "{} modules; cold cache: {:?}; warm cache: {:?}",
+            modules.len(),
+            cold,
+            warm
+        );
+
+        assert_eq!(cold_bytecode, warm_bytecode);
+
+        Ok(())
+    }
+}
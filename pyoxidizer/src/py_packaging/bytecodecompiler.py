@@ -11,13 +11,9 @@ import importlib._bootstrap_external
 import importlib.util
 import marshal
 import os
-import re
 import sys
 
 
-RE_CODING = re.compile(b'^[ \t\f]*#.*?coding[:=][ \t]*([-_.a-zA-Z0-9]+)')
-
-
 if marshal.version != 4:
     raise Exception('unexpected marshal version: %d' % marshal.version)
 
@@ -30,6 +26,12 @@ while True:
 
     if command == b'exit':
         sys.exit(0)
+    elif command == b'magic':
+        # Bytecode is only valid for interpreters having this magic number.
+        out = importlib.util.MAGIC_NUMBER + sys.version.encode('utf-8')
+        stdout.write(b'%d\n' % len(out))
+        stdout.write(out)
+        stdout.flush()
     elif command == b'compile':
         name_len = stdin.readline().rstrip()
         source_len = stdin.readline().rstrip()
@@ -45,41 +47,31 @@ while True:
 
         name = os.fsdecode(name)
 
-        # Default source encoding is UTF-8. But per PEP 263, the first or second
-        # line of source can match a regular expression to define a custom
-        # encoding. We need to detect custom encodings and use it to decode
-        # the passed bytes to str.
-        encoding = 'utf-8'
-
-        for line in source.splitlines()[0:2]:
-            m = RE_CODING.match(line)
-            if m:
-                encoding = m.group(1).decode('ascii')
-                break
-
-        # Someone has set us up the BOM! According to PEP 263 the file should
-        # be interpreted as UTF-8.
-        if source.startswith(b'\xef\xbb\xbf'):
-            encoding = 'utf-8'
-            source = source[3:]
-
-        source_bytes = source
-        source = source.decode(encoding)
+        # compile() decodes bytes per PEP 263 and PEP 3120, honoring BOMs
+        # and coding cookies and normalizing newlines. The original bytes are
+        # hashed so .pyc files match the source they are installed with.
+        try:
+            code = compile(source, name, 'exec', optimize=optimize_level)
+        except (SyntaxError, ValueError) as e:
+            message = ('%s: %s' % (type(e).__name__, e)).encode('utf-8')
+            stdout.write(b'error\n%d\n' % len(message))
+            stdout.write(message)
+            stdout.flush()
+            continue
 
-        code = compile(source, name, 'exec', optimize=optimize_level)
         bytecode = marshal.dumps(code)
 
         if output_mode == b'bytecode':
             out = bytecode
         elif output_mode == b'pyc-checked-hash':
-            source_hash = importlib.util.source_hash(source_bytes)
+            source_hash = importlib.util.source_hash(source)
             out = importlib._bootstrap_external._code_to_hash_pyc(
                 code,
                 source_hash,
                 checked=True,
             )
         elif output_mode == b'pyc-unchecked-hash':
-            source_hash = importlib.util.source_hash(source_bytes)
+            source_hash = importlib.util.source_hash(source)
             out = importlib._bootstrap_external._code_to_hash_pyc(
                 code,
                 source_hash,
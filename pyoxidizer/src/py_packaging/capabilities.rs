@@ -0,0 +1,310 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/*!
+What a Python distribution supports and whether a config fits it.
+
+Some features requested by a config, e.g. `raw_allocator="jemalloc"`,
+only work with some distributions or targets. Without checking, they fail
+late with linker or run-time errors. Once a config is resolved, every
+requested feature is checked against the `DistributionCapabilities` of the
+distribution and all mismatches are reported in a single error.
+*/
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+
+use super::config::{EmbeddedPythonConfig, RawAllocator};
+use super::distribution::{ExtensionModule, ParsedPythonDistribution};
+use crate::python_distributions::CPYTHON_BY_TRIPLE;
+
+/// Features of a Python distribution configs may depend on.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct DistributionCapabilities {
+    /// Python can be linked statically into an executable.
+    pub static_libpython: bool,
+    /// Extension modules can be embedded in an executable and loaded from
+    /// it rather than from shared libraries on the filesystem.
+    pub supports_in_memory_extensions: bool,
+    /// jemalloc can be used as the raw allocator.
+    pub supports_jemalloc: bool,
+}
+
+/// Whether an extension module can be linked into an executable.
+fn is_linkable(em: &ExtensionModule) -> bool {
+    !em.object_paths.is_empty() || em.static_library.is_some()
+}
+
+impl DistributionCapabilities {
+    pub fn from_distribution(dist: &ParsedPythonDistribution) -> Self {
+        let static_libpython = !dist.objs_core.is_empty() && dist.includes.contains_key("Python.h");
+
+        DistributionCapabilities {
+            static_libpython,
+            supports_in_memory_extensions: static_libpython
+                && dist
+                    .extension_modules
+                    .values()
+                    .flatten()
+                    .any(|em| !em.builtin_default && is_linkable(em)),
+            // jemalloc doesn't work on Windows.
+            supports_jemalloc: dist.os != "windows",
+        }
+    }
+}
+
+/// A capability a config may require.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Capability {
+    StaticLibpython,
+    InMemoryExtensions,
+    Jemalloc,
+}
+
+impl Capability {
+    /// Name of the capability, as in `DistributionCapabilities`.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Capability::StaticLibpython => "static_libpython",
+            Capability::InMemoryExtensions => "supports_in_memory_extensions",
+            Capability::Jemalloc => "supports_jemalloc",
+        }
+    }
+
+    pub fn is_supported(self, capabilities: &DistributionCapabilities) -> bool {
+        match self {
+            Capability::StaticLibpython => capabilities.static_libpython,
+            Capability::InMemoryExtensions => capabilities.supports_in_memory_extensions,
+            Capability::Jemalloc => capabilities.supports_jemalloc,
+        }
+    }
+
+    /// Targets whose default distribution has this capability.
+    pub fn supporting_targets(self) -> Vec<&'static str> {
+        CPYTHON_BY_TRIPLE
+            .keys()
+            .map(|target| target.as_str())
+            .filter(|target| match self {
+                Capability::StaticLibpython | Capability::InMemoryExtensions => true,
+                Capability::Jemalloc => !target.contains("-windows-"),
+            })
+            .collect()
+    }
+}
+
+/// A feature requested by a config and the capability it needs.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CapabilityRequirement {
+    /// Describes what requests the feature.
+    pub feature: String,
+    pub capability: Capability,
+}
+
+/// Collect the capabilities a config needs.
+///
+/// `extension_modules` are the extension modules embedded in the
+/// executable.
+pub fn required_capabilities<'a>(
+    config: &EmbeddedPythonConfig,
+    extension_modules: impl Iterator<Item = &'a ExtensionModule>,
+) -> Vec<CapabilityRequirement> {
+    let mut requirements = vec![CapabilityRequirement {
+        feature: "embedding Python in an executable".to_string(),
+        capability: Capability::StaticLibpython,
+    }];
+
+    if config.raw_allocator == RawAllocator::Jemalloc {
+        requirements.push(CapabilityRequirement {
+            feature: "raw_allocator=\"jemalloc\"".to_string(),
+            capability: Capability::Jemalloc,
+        });
+    }
+
+    let embedded = extension_modules
+        .filter(|em| !em.builtin_default)
+        .map(|em| em.module.as_str())
+        .collect::<Vec<_>>();
+    if !embedded.is_empty() {
+        requirements.push(CapabilityRequirement {
+            feature: format!(
+                "embedding extension modules in the executable ({})",
+                embedded.join(", ")
+            ),
+            capability: Capability::InMemoryExtensions,
+        });
+    }
+
+    requirements
+}
+
+/// Check requirements against the capabilities of a distribution.
+///
+/// All unsupported requirements are reported in one error. `description`
+/// names the distribution.
+pub fn check_capabilities(
+    requirements: &[CapabilityRequirement],
+    capabilities: &DistributionCapabilities,
+    description: &str,
+) -> Result<()> {
+    let unsupported = requirements
+        .iter()
+        .filter(|r| !r.capability.is_supported(capabilities))
+        .map(|r| {
+            let targets = r.capability.supporting_targets();
+
+            format!(
+                "{} requires {}; default distributions having it: {}",
+                r.feature,
+                r.capability.as_str(),
+                if targets.is_empty() {
+                    "none".to_string()
+                } else {
+                    targets.join(", ")
+                }
+            )
+        })
+        .collect::<Vec<_>>();
+
+    if unsupported.is_empty() {
+        Ok(())
+    } else {
+        Err(anyhow!(
+            "the config requests features the Python distribution {} lacks:\n  {}",
+            description,
+            unsupported.join("\n  ")
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::*;
+
+    fn extension_module(module: &str, builtin_default: bool) -> ExtensionModule {
+        ExtensionModule {
+            module: module.to_string(),
+            init_fn: Some(format!("PyInit_{}", module)),
+            builtin_default,
+            disableable: true,
+            object_paths: Vec::new(),
+            static_library: None,
+            shared_library: None,
+            links: Vec::new(),
+            required: false,
+            variant: "default".to_string(),
+            licenses: None,
+            license_paths: None,
+            license_public_domain: None,
+        }
+    }
+
+    fn config(raw_allocator: RawAllocator) -> EmbeddedPythonConfig {
+        let mut config = EmbeddedPythonConfig::default();
+        config.raw_allocator = raw_allocator;
+        config
+    }
+
+    #[test]
+    fn test_default_distribution_capabilities() -> Result<()> {
+        let dist = get_default_distribution()?;
+        let capabilities = DistributionCapabilities::from_distribution(&dist);
+
+        assert!(capabilities.static_libpython);
+        assert!(capabilities.supports_in_memory_extensions);
+        assert_eq!(capabilities.supports_jemalloc, dist.os != "windows");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_required_capabilities() {
+        let ems = vec![
+            extension_module("_io", true),
+            extension_module("_ssl", false),
+            extension_module("select", false),
+        ];
+
+        let requirements = required_capabilities(&config(RawAllocator::Jemalloc), ems.iter());
+        assert_eq!(
+            requirements
+                .iter()
+                .map(|r| (r.feature.as_str(), r.capability))
+                .collect::<Vec<_>>(),
+            vec![
+                (
+                    "embedding Python in an executable",
+                    Capability::StaticLibpython
+                ),
+                ("raw_allocator=\"jemalloc\"", Capability::Jemalloc),
+                (
+                    "embedding extension modules in the executable (_ssl, select)",
+                    Capability::InMemoryExtensions
+                ),
+            ]
+        );
+
+        let requirements = required_capabilities(&config(RawAllocator::System), ems[..1].iter());
+        assert_eq!(requirements.len(), 1);
+    }
+
+    #[test]
+    fn test_check_capabilities() {
+        let ems = vec![extension_module("_ssl", false)];
+        let all = DistributionCapabilities {
+            static_libpython: true,
+            supports_in_memory_extensions: true,
+            supports_jemalloc: true,
+        };
+
+        let jemalloc = required_capabilities(&config(RawAllocator::Jemalloc), ems.iter());
+        let system = required_capabilities(&config(RawAllocator::System), ems.iter());
+        let mimalloc = required_capabilities(&config(RawAllocator::Mimalloc), std::iter::empty());
+
+        assert!(check_capabilities(&jemalloc, &all, "dist").is_ok());
+
+        // jemalloc on Windows.
+        let windows = DistributionCapabilities {
+            supports_jemalloc: false,
+            ..all.clone()
+        };
+        let err = check_capabilities(&jemalloc, &windows, "standalone 3.7.5 (windows x86_64)")
+            .unwrap_err()
+            .to_string();
+        assert_eq!(
+            err.lines().collect::<Vec<_>>(),
+            vec![
+                "the config requests features the Python distribution standalone 3.7.5 (windows x86_64) lacks:",
+                "  raw_allocator=\"jemalloc\" requires supports_jemalloc; default distributions having it: x86_64-apple-darwin, x86_64-unknown-linux-gnu, x86_64-unknown-linux-musl",
+            ]
+        );
+        assert!(check_capabilities(&system, &windows, "dist").is_ok());
+
+        // A distribution only shipping shared libraries, e.g. the embeddable zip.
+        let embeddable = DistributionCapabilities::default();
+        let err = check_capabilities(&jemalloc, &embeddable, "embeddable 3.7.5 (windows x86)")
+            .unwrap_err()
+            .to_string();
+        let lines = err.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 4);
+        assert!(lines[1].starts_with(
+            "  embedding Python in an executable requires static_libpython; default distributions having it: "
+        ));
+        assert!(lines[2].starts_with("  raw_allocator=\"jemalloc\" requires supports_jemalloc"));
+        assert!(lines[3].starts_with(
+            "  embedding extension modules in the executable (_ssl) requires supports_in_memory_extensions"
+        ));
+
+        // Extension modules only available as shared libraries.
+        let shared_extensions = DistributionCapabilities {
+            supports_in_memory_extensions: false,
+            ..all.clone()
+        };
+        let err = check_capabilities(&system, &shared_extensions, "dist")
+            .unwrap_err()
+            .to_string();
+        assert_eq!(err.lines().count(), 2);
+        assert!(check_capabilities(&mimalloc, &shared_extensions, "dist").is_ok());
+    }
+}
@@ -0,0 +1,273 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/*!
+Packages known not to work when imported from memory.
+
+Some packages locate files relative to `__file__`, which the in-memory
+importer doesn't set, or rely on `pkg_resources` finding them on the
+filesystem. Packages in `KNOWN_INCOMPATIBLE_PACKAGES` collected by
+`pip_install()` are packaged relative to the executable instead, unless
+the config says otherwise.
+*/
+
+use anyhow::{anyhow, Result};
+use std::collections::{BTreeMap, BTreeSet};
+
+use super::requirement::{normalize_name, Version};
+
+/// Directory next to the executable packages are installed into when
+/// they can't be imported from memory.
+pub const FILESYSTEM_RELATIVE_DIR: &str = "lib";
+
+/// A package known not to work when imported from memory.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct KnownIncompatibility {
+    /// Top-level package name.
+    pub name: &'static str,
+    pub reason: &'static str,
+    /// First version working from memory, if any.
+    pub fixed_version: Option<&'static str>,
+}
+
+/// Packages which break when imported from memory.
+///
+/// Keep sorted by name.
+pub const KNOWN_INCOMPATIBLE_PACKAGES: &[KnownIncompatibility] = &[
+    KnownIncompatibility {
+        name: "certifi",
+        reason: "certifi.where() returns a path derived from __file__",
+        fixed_version: None,
+    },
+    KnownIncompatibility {
+        name: "matplotlib",
+        reason: "mpl-data is located relative to __file__",
+        fixed_version: None,
+    },
+    KnownIncompatibility {
+        name: "numpy",
+        reason: "shared libraries are loaded relative to __file__",
+        fixed_version: None,
+    },
+    KnownIncompatibility {
+        name: "pandas",
+        reason: "data files are read relative to __file__",
+        fixed_version: None,
+    },
+    KnownIncompatibility {
+        name: "pkg_resources",
+        reason: "distributions are discovered by scanning sys.path on the filesystem",
+        fixed_version: None,
+    },
+    KnownIncompatibility {
+        name: "pytz",
+        reason: "zoneinfo files are opened relative to __file__",
+        fixed_version: None,
+    },
+    KnownIncompatibility {
+        name: "scipy",
+        reason: "shared libraries are loaded relative to __file__",
+        fixed_version: None,
+    },
+];
+
+/// Where a package is packaged.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PackageLocation {
+    /// Embedded in the executable and imported from memory.
+    InMemory,
+    /// Installed in `FILESYSTEM_RELATIVE_DIR` next to the executable.
+    FilesystemRelative,
+}
+
+impl PackageLocation {
+    pub fn from_str(s: &str) -> Result<PackageLocation> {
+        match s {
+            "in-memory" => Ok(PackageLocation::InMemory),
+            "filesystem-relative" => Ok(PackageLocation::FilesystemRelative),
+            _ => Err(anyhow!(
+                "package location must be 'in-memory' or 'filesystem-relative'; got {}",
+                s
+            )),
+        }
+    }
+}
+
+/// How the location of packages collected from pip is chosen.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PackageLocationPolicy {
+    /// Packages whose location is chosen by the config.
+    pub overrides: BTreeMap<String, PackageLocation>,
+    /// Packages to import from memory even if known not to work.
+    pub force_in_memory: BTreeSet<String>,
+}
+
+/// A package not imported from memory and why.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LocationDecision {
+    pub package: String,
+    pub reason: String,
+}
+
+impl PackageLocationPolicy {
+    /// Decide whether a top-level package must be packaged on the filesystem.
+    ///
+    /// `versions` are installed distributions and their versions. Packages
+    /// at or above the fixed version of their table entry stay in memory.
+    /// Returns `None` for packages imported from memory.
+    pub fn resolve(
+        &self,
+        package: &str,
+        versions: &BTreeMap<String, String>,
+    ) -> Option<LocationDecision> {
+        self.resolve_with_table(KNOWN_INCOMPATIBLE_PACKAGES, package, versions)
+    }
+
+    fn resolve_with_table(
+        &self,
+        table: &[KnownIncompatibility],
+        package: &str,
+        versions: &BTreeMap<String, String>,
+    ) -> Option<LocationDecision> {
+        if self.force_in_memory.contains(package) {
+            return None;
+        }
+
+        match self.overrides.get(package) {
+            Some(PackageLocation::InMemory) => return None,
+            Some(PackageLocation::FilesystemRelative) => {
+                return Some(LocationDecision {
+                    package: package.to_string(),
+                    reason: "requested by package_location_overrides".to_string(),
+                })
+            }
+            None => {}
+        }
+
+        let entry = table.iter().find(|entry| entry.name == package)?;
+
+        let version = versions
+            .iter()
+            .find(|(name, _)| normalize_name(name) == normalize_name(package))
+            .and_then(|(_, version)| Version::parse(version));
+
+        let reason = match (entry.fixed_version, version) {
+            (Some(fixed), Some(version)) => {
+                if version >= Version::parse(fixed)? {
+                    return None;
+                }
+
+                format!("{} (fixed in {})", entry.reason, fixed)
+            }
+            (Some(fixed), None) => format!("{} (fixed in {})", entry.reason, fixed),
+            (None, _) => entry.reason.to_string(),
+        };
+
+        Some(LocationDecision {
+            package: package.to_string(),
+            reason,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn versions(values: &[(&str, &str)]) -> BTreeMap<String, String> {
+        values
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_table_sorted() {
+        let names = KNOWN_INCOMPATIBLE_PACKAGES
+            .iter()
+            .map(|entry| entry.name)
+            .collect::<Vec<_>>();
+        let mut sorted = names.clone();
+        sorted.sort();
+        sorted.dedup();
+
+        assert_eq!(names, sorted);
+
+        for entry in KNOWN_INCOMPATIBLE_PACKAGES {
+            if let Some(fixed) = entry.fixed_version {
+                assert!(Version::parse(fixed).is_some(), "{}", entry.name);
+            }
+        }
+    }
+
+    #[test]
+    fn test_automatic_demotion() {
+        let policy = PackageLocationPolicy::default();
+        let versions = versions(&[("pandas", "0.25.3"), ("six", "1.13.0")]);
+
+        assert_eq!(
+            policy.resolve("pandas", &versions),
+            Some(LocationDecision {
+                package: "pandas".to_string(),
+                reason: "data files are read relative to __file__".to_string(),
+            })
+        );
+        assert_eq!(policy.resolve("six", &versions), None);
+        // Packages without a fixed version are affected whatever is installed.
+        assert!(policy.resolve("pytz", &versions).is_some());
+    }
+
+    #[test]
+    fn test_fixed_version() {
+        let policy = PackageLocationPolicy::default();
+        let table = [KnownIncompatibility {
+            name: "pandas",
+            reason: "data files are read relative to __file__",
+            fixed_version: Some("1.0"),
+        }];
+        let resolve = |installed: &[(&str, &str)]| {
+            policy.resolve_with_table(&table, "pandas", &versions(installed))
+        };
+
+        assert_eq!(
+            resolve(&[("Pandas", "0.25.3")]),
+            Some(LocationDecision {
+                package: "pandas".to_string(),
+                reason: "data files are read relative to __file__ (fixed in 1.0)".to_string(),
+            })
+        );
+        assert_eq!(resolve(&[("pandas", "1.0.0")]), None);
+        assert_eq!(resolve(&[("pandas", "1.1")]), None);
+        assert!(resolve(&[]).is_some());
+    }
+
+    #[test]
+    fn test_overrides() -> Result<()> {
+        let mut policy = PackageLocationPolicy::default();
+        policy.force_in_memory.insert("pandas".to_string());
+        policy.overrides.insert(
+            "certifi".to_string(),
+            PackageLocation::from_str("in-memory")?,
+        );
+        policy.overrides.insert(
+            "mypkg".to_string(),
+            PackageLocation::from_str("filesystem-relative")?,
+        );
+        let versions = versions(&[]);
+
+        assert_eq!(policy.resolve("pandas", &versions), None);
+        assert_eq!(policy.resolve("certifi", &versions), None);
+        assert_eq!(
+            policy.resolve("mypkg", &versions),
+            Some(LocationDecision {
+                package: "mypkg".to_string(),
+                reason: "requested by package_location_overrides".to_string(),
+            })
+        );
+        assert!(policy.resolve("numpy", &versions).is_some());
+        assert!(PackageLocation::from_str("app-relative").is_err());
+
+        Ok(())
+    }
+}
@@ -2,30 +2,73 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
-#[derive(Clone, Debug, PartialEq)]
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use crate::fsutils::write_file;
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
 pub enum RawAllocator {
     Jemalloc,
+    Mimalloc,
     Rust,
     System,
 }
 
-#[derive(Clone, Debug, PartialEq)]
+impl RawAllocator {
+    /// The cargo feature of generated projects providing the allocator.
+    pub fn cargo_feature(&self) -> Option<&'static str> {
+        match self {
+            RawAllocator::Jemalloc => Some("jemalloc"),
+            RawAllocator::Mimalloc => Some("mimalloc"),
+            RawAllocator::Rust | RawAllocator::System => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
 pub enum RunMode {
     Noop,
     Repl,
     Module { module: String },
     Eval { code: String },
+    Embedding,
 }
 
 /// How the `terminfo` database is resolved at run-time.
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
 pub enum TerminfoResolution {
     Dynamic,
     None,
     Static(String),
 }
 
-#[derive(Clone, Debug, PartialEq)]
+/// What `sys.executable` is set to at run-time.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub enum SysExecutable {
+    Exe,
+    Empty,
+}
+
+/// Which importer wins when a module is both embedded and on the filesystem.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub enum ImporterPrecedence {
+    MemoryFirst,
+    FilesystemFirst,
+}
+
+/// What replaces standard streams that aren't available at run-time.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub enum DetachedStdio {
+    Null,
+    /// Path template of a log file.
+    LogFile(String),
+    Buffer,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
 pub struct EmbeddedPythonConfig {
     pub bytes_warning: i32,
     pub dont_write_bytecode: bool,
@@ -43,15 +86,38 @@ pub struct EmbeddedPythonConfig {
     pub stdio_encoding_name: Option<String>,
     pub stdio_encoding_errors: Option<String>,
     pub unbuffered_stdio: bool,
+    pub detached_stdio: DetachedStdio,
     pub use_hash_seed: bool,
     pub verbose: i32,
     pub filesystem_importer: bool,
+    pub importer_precedence: ImporterPrecedence,
     pub sys_frozen: bool,
     pub sys_meipass: bool,
     pub sys_paths: Vec<String>,
+    pub sys_executable: SysExecutable,
+    pub sys_prefix: String,
+    pub run_python_marker: bool,
+    pub allow_runtime_code_override: bool,
     pub raw_allocator: RawAllocator,
     pub terminfo_resolution: TerminfoResolution,
     pub write_modules_directory_env: Option<String>,
+    /// Environment variable holding the path to write an import profile to.
+    pub import_profile_env: Option<String>,
+    pub repl_history_file: Option<String>,
+    /// Module imported after interpreter initialization, before the run mode.
+    pub startup_module: Option<String>,
+    /// Code executed after interpreter initialization, before the run mode.
+    pub startup_code: Option<String>,
+    /// Whether `atexit` functions are called when the interpreter is finalized.
+    pub run_atexit: bool,
+    /// Whether finalization waits for non-daemon threads to finish.
+    pub wait_for_non_daemon_threads: bool,
+    /// Whether Python installs its signal handlers, e.g. for `KeyboardInterrupt`.
+    pub install_signal_handlers: bool,
+    /// Whether `pkg_resources` can access modules imported from memory.
+    pub pkg_resources_compat: bool,
+    /// Fields whose value was chosen by `select()`, mapped to the matching arm.
+    pub target_selections: BTreeMap<String, String>,
 }
 
 impl Default for EmbeddedPythonConfig {
@@ -73,15 +139,62 @@ impl Default for EmbeddedPythonConfig {
             stdio_encoding_name: None,
             stdio_encoding_errors: None,
             unbuffered_stdio: false,
+            detached_stdio: DetachedStdio::Null,
             use_hash_seed: false,
             verbose: 0,
             filesystem_importer: false,
+            importer_precedence: ImporterPrecedence::MemoryFirst,
             sys_frozen: false,
             sys_meipass: false,
             sys_paths: Vec::new(),
+            sys_executable: SysExecutable::Exe,
+            sys_prefix: "$ORIGIN".to_string(),
+            run_python_marker: false,
+            allow_runtime_code_override: false,
             raw_allocator: RawAllocator::System,
             terminfo_resolution: TerminfoResolution::None,
             write_modules_directory_env: None,
+            import_profile_env: None,
+            repl_history_file: None,
+            startup_module: None,
+            startup_code: None,
+            run_atexit: true,
+            wait_for_non_daemon_threads: true,
+            install_signal_handlers: true,
+            pkg_resources_compat: false,
+            target_selections: BTreeMap::new(),
         }
     }
 }
+
+/// `cfg` compiling support for overriding the run mode into `pyembed`.
+pub const RUNTIME_CODE_OVERRIDE_CFG: &str = "pyembed_runtime_code_override";
+
+impl EmbeddedPythonConfig {
+    /// Cargo metadata lines for building the `pyembed` crate.
+    ///
+    /// Support for overriding the run mode with reserved arguments is only
+    /// compiled when the config allows it.
+    pub fn cargo_metadata(&self) -> Vec<String> {
+        let mut res = Vec::new();
+
+        if self.allow_runtime_code_override {
+            res.push(format!("cargo:rustc-cfg={}", RUNTIME_CODE_OVERRIDE_CFG));
+        }
+
+        res
+    }
+
+    /// Write the resolved config for a target to a JSON file.
+    ///
+    /// This allows auditing the values used for a build, notably values
+    /// chosen by `select()`.
+    pub fn write_resolved_json(&self, path: &Path, target_triple: &str) -> Result<()> {
+        let value = serde_json::json!({
+            "target_triple": target_triple,
+            "embedded_python_config": self,
+        });
+
+        write_file(path, serde_json::to_vec_pretty(&value)?)
+    }
+}
@@ -8,22 +8,36 @@ use fs2::FileExt;
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 use slog::{info, warn};
-use std::collections::{BTreeMap, HashMap};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::fs;
 use std::fs::{create_dir_all, File};
-use std::io::{BufRead, BufReader, Cursor, Read};
+use std::io::{BufRead, BufReader, Read, Write};
 use std::path::{Path, PathBuf};
+use std::time::Duration;
 use url::Url;
 use uuid::Uuid;
 
+use super::archive::{quarantine_archive, read_tar_zst, ArchiveError};
+use super::capabilities::DistributionCapabilities;
 use super::distutils::prepare_hacked_distutils;
+use super::download::{
+    http_fetch, interrupted, is_transient, truncated, DownloadProgress, FetchResponse, RetryPolicy,
+};
+use super::embeddable::{analyze_embeddable_distribution_zip, is_zip_archive};
 use super::fsscan::{
-    find_python_resources, is_package_from_path, walk_tree_files, PythonFileResource,
+    find_python_resources_in_paths, is_package_from_path, walk_tree_files, PythonFileResource,
 };
+use super::members::{read_members, ArchiveMembers, MemberSelection};
 use super::resource::{ResourceData, SourceModule};
+use super::sqlite::library_compile_options;
 
+use crate::build_events::print_human;
+use crate::build_stats::{self, Cache};
+use crate::cache::{distribution_cache_dir, DISTRIBUTION_CACHE_ENV};
+use crate::cancel;
+use crate::fsutils::{move_path, StagedDir};
 use crate::licensing::NON_GPL_LICENSES;
-use crate::python_distributions::CPYTHON_BY_TRIPLE;
+use crate::python_distributions::{no_default_distribution_message, CPYTHON_BY_TRIPLE};
 
 #[cfg(windows)]
 const PYTHON_EXE_BASENAME: &str = "python.exe";
@@ -40,6 +54,24 @@ const PIP_EXE_BASENAME: &str = "pip3";
 // This needs to be kept in sync with *compiler.py
 const PYOXIDIZER_STATE_DIR: &str = "state/pyoxidizer";
 
+/// Version of the analysis persisted for extracted distributions.
+///
+/// Bump this when `ParsedPythonDistribution` or how it is derived changes
+/// so stale analyses aren't reused.
+const ANALYSIS_FORMAT_VERSION: u32 = 3;
+
+/// File in an extracted distribution holding its persisted analysis.
+const ANALYSIS_CACHE_FILENAME: &str = "pyoxidizer-analysis.cbor";
+
+/// Directory a distribution in the distribution cache is extracted to.
+pub(crate) const EXTRACTED_DIR: &str = "extracted";
+
+/// File whose modification time records when a cached distribution was used.
+pub(crate) const LAST_USED_FILENAME: &str = "last-used";
+
+/// Lock file serializing changes to the extraction of a distribution.
+pub(crate) const EXTRACT_LOCK_FILENAME: &str = "distribution-extract-lock";
+
 const STDLIB_TEST_PACKAGES: &[&str] = &[
     "bsddb.test",
     "ctypes.test",
@@ -67,12 +99,22 @@ pub fn is_stdlib_test_package(name: &str) -> bool {
     false
 }
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
 pub enum PythonDistributionLocation {
     Local { local_path: String, sha256: String },
     Url { url: String, sha256: String },
 }
 
+impl PythonDistributionLocation {
+    /// The SHA-256 of the distribution archive.
+    pub fn sha256(&self) -> &str {
+        match self {
+            PythonDistributionLocation::Local { sha256, .. } => sha256,
+            PythonDistributionLocation::Url { sha256, .. } => sha256,
+        }
+    }
+}
+
 #[derive(Debug, Deserialize)]
 struct LinkEntry {
     name: String,
@@ -124,18 +166,6 @@ struct PythonJsonMain {
     tcl_library_path: Option<String>,
 }
 
-fn parse_python_json(path: &Path) -> Result<PythonJsonMain> {
-    if !path.exists() {
-        panic!("PYTHON.json does not exist; are you using an up-to-date Python distribution that conforms with our requirements?");
-    }
-
-    let buf = fs::read(path)?;
-
-    let v: PythonJsonMain = serde_json::from_slice(&buf)?;
-
-    Ok(v)
-}
-
 /// Represents contents of the config.c/config.c.in file.
 #[derive(Debug)]
 #[allow(unused)]
@@ -149,7 +179,7 @@ pub struct ConfigC {
 /// If the license fields are Some value, then license metadata was
 /// present in the distribution. If the values are None, then license
 /// metadata is not known.
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct LibraryDepends {
     /// Name of the library we depend on.
     pub name: String,
@@ -168,7 +198,7 @@ pub struct LibraryDepends {
 }
 
 /// Describes an extension module in a Python distribution.
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct ExtensionModule {
     /// Name of the Python module this extension module provides.
     pub module: String,
@@ -197,6 +227,12 @@ pub struct ExtensionModule {
     /// Path to static library providing this extension module.
     pub static_library: Option<PathBuf>,
 
+    /// Path to shared library providing this extension module.
+    ///
+    /// Distributions shipping only compiled extension modules (e.g. `.pyd`
+    /// files) have this instead of object files or a static library.
+    pub shared_library: Option<PathBuf>,
+
     /// Library linking metadata.
     pub links: Vec<LibraryDepends>,
 
@@ -249,12 +285,180 @@ pub struct LicenseInfo {
     pub license_text: String,
 }
 
+/// On-disk sizes of components of a Python distribution.
+///
+/// Each category maps component names to sizes in bytes.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct DistributionSizes {
+    /// Object files providing the core Python implementation.
+    pub core: BTreeMap<String, u64>,
+
+    /// Compiled extension modules, including their static library.
+    ///
+    /// Keys are module names. Modules with multiple variants are keyed
+    /// as ``<module>[<variant>]``.
+    pub extension_modules: BTreeMap<String, u64>,
+
+    /// Static libraries extension modules link against.
+    pub libraries: BTreeMap<String, u64>,
+
+    /// Python source modules, grouped by top-level package.
+    pub stdlib_packages: BTreeMap<String, u64>,
+
+    /// Non-module resource files, grouped by top-level package.
+    pub resources: BTreeMap<String, u64>,
+}
+
+impl DistributionSizes {
+    /// Obtain all categories of sizes with a descriptive name.
+    pub fn categories(&self) -> Vec<(&'static str, &BTreeMap<String, u64>)> {
+        vec![
+            ("core", &self.core),
+            ("extension_modules", &self.extension_modules),
+            ("libraries", &self.libraries),
+            ("stdlib_packages", &self.stdlib_packages),
+            ("resources", &self.resources),
+        ]
+    }
+
+    /// Total size of all components.
+    pub fn total(&self) -> u64 {
+        self.categories()
+            .iter()
+            .map(|(_, sizes)| sizes.values().sum::<u64>())
+            .sum()
+    }
+}
+
+/// Version of the `DistributionInfo` format.
+pub const DISTRIBUTION_INFO_FORMAT_VERSION: u32 = 1;
+
+/// A library an extension module links against.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct LinkInfo {
+    pub name: String,
+    /// Whether this is a system framework.
+    pub framework: bool,
+    /// Whether this is a system library.
+    pub system: bool,
+}
+
+/// A variant of an extension module.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct ExtensionModuleInfo {
+    pub variant: String,
+    /// Whether the extension must be loaded to initialize Python.
+    pub required: bool,
+    /// Whether the extension is compiled into libpython by default.
+    pub builtin_default: bool,
+    /// Path of the shared library providing the module, relative to the
+    /// distribution, using `/` as the separator.
+    pub shared_library: Option<String>,
+    /// SPDX license identifiers. `None` if unknown.
+    pub licenses: Option<Vec<String>>,
+    pub links: Vec<LinkInfo>,
+}
+
+/// Machine-readable description of a Python distribution.
+///
+/// Printed by `pyoxidizer python-distribution-info --format json`. As long
+/// as `format_version` doesn't change, fields are only ever added, never
+/// removed or changed in meaning.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct DistributionInfo {
+    /// `DISTRIBUTION_INFO_FORMAT_VERSION`.
+    pub format_version: u32,
+    pub flavor: String,
+    pub version: String,
+    pub os: String,
+    pub arch: String,
+    /// What the distribution lacks to be embedded. Empty if it can be.
+    pub missing_embedding_requirements: Vec<String>,
+    /// Features configs may depend on.
+    #[serde(default)]
+    pub capabilities: DistributionCapabilities,
+    /// Extension modules by module name, with an entry for every variant.
+    pub extension_modules: BTreeMap<String, Vec<ExtensionModuleInfo>>,
+    /// Names of Python source modules, sorted.
+    pub py_modules: Vec<String>,
+    /// Names of non-module resource files by package, sorted.
+    pub resources: BTreeMap<String, Vec<String>>,
+    /// Options the SQLite library used by `_sqlite3` was compiled with.
+    /// `None` if unknown.
+    pub sqlite_compile_options: Option<Vec<String>>,
+}
+
+impl DistributionInfo {
+    pub fn from_distribution(dist: &ParsedPythonDistribution) -> Self {
+        let relative = |path: &Path| {
+            path.strip_prefix(&dist.base_dir)
+                .unwrap_or(path)
+                .components()
+                .map(|c| c.as_os_str().to_string_lossy().to_string())
+                .collect::<Vec<_>>()
+                .join("/")
+        };
+
+        DistributionInfo {
+            format_version: DISTRIBUTION_INFO_FORMAT_VERSION,
+            flavor: dist.flavor.clone(),
+            version: dist.version.clone(),
+            os: dist.os.clone(),
+            arch: dist.arch.clone(),
+            missing_embedding_requirements: dist.missing_embedding_requirements(),
+            capabilities: DistributionCapabilities::from_distribution(dist),
+            extension_modules: dist
+                .extension_modules
+                .iter()
+                .map(|(name, variants)| {
+                    let variants = variants
+                        .iter()
+                        .map(|em| ExtensionModuleInfo {
+                            variant: em.variant.clone(),
+                            required: em.required,
+                            builtin_default: em.builtin_default,
+                            shared_library: em.shared_library.as_ref().map(|p| relative(p)),
+                            licenses: em.licenses.clone(),
+                            links: em
+                                .links
+                                .iter()
+                                .map(|link| LinkInfo {
+                                    name: link.name.clone(),
+                                    framework: link.framework,
+                                    system: link.system,
+                                })
+                                .collect(),
+                        })
+                        .collect();
+
+                    (name.clone(), variants)
+                })
+                .collect(),
+            py_modules: dist.py_modules.keys().cloned().collect(),
+            resources: dist
+                .resources
+                .iter()
+                .map(|(package, resources)| (package.clone(), resources.keys().cloned().collect()))
+                .collect(),
+            sqlite_compile_options: dist.sqlite_compile_options.clone(),
+        }
+    }
+}
+
+pub(crate) fn file_size(path: &Path) -> u64 {
+    fs::metadata(path).map(|m| m.len()).unwrap_or(0)
+}
+
+pub(crate) fn top_level_package(name: &str) -> String {
+    name.split('.').next().unwrap_or(name).to_string()
+}
+
 /// Represents a parsed Python distribution.
 ///
 /// Distribution info is typically derived from a tarball containing a
 /// Python install and its build artifacts.
 #[allow(unused)]
-#[derive(Debug)]
+#[derive(Debug, Deserialize, Serialize)]
 pub struct ParsedPythonDistribution {
     /// Directory where distribution lives in the filesystem.
     pub base_dir: PathBuf,
@@ -327,6 +531,21 @@ pub struct ParsedPythonDistribution {
 
     /// Path to copy of hacked dist to use for packaging rules venvs
     pub venv_base: PathBuf,
+
+    /// On-disk sizes of components in this distribution.
+    pub sizes: DistributionSizes,
+
+    /// Lowercase SHA-256 of the archive the distribution was extracted from.
+    ///
+    /// Empty if unknown.
+    #[serde(default)]
+    pub archive_sha256: String,
+
+    /// Options the SQLite library used by `_sqlite3` was compiled with.
+    ///
+    /// `None` if unknown.
+    #[serde(default)]
+    pub sqlite_compile_options: Option<Vec<String>>,
 }
 
 #[derive(Debug)]
@@ -452,28 +671,38 @@ pub fn invoke_python(python_paths: &PythonPaths, logger: &slog::Logger, args: &[
     }
 }
 
+/// Extension module variants to prefer when no explicit preference is given.
+///
+/// The `libedit` variant of `readline` gives the REPL line editing and history
+/// without the GPL licensing implications of GNU readline.
+const DEFAULT_PREFERRED_VARIANTS: &[(&str, &str)] = &[("readline", "libedit")];
+
 pub fn choose_variant(
     extensions: &[ExtensionModule],
     variants: &Option<HashMap<String, String>>,
 ) -> ExtensionModule {
-    if let Some(variants) = variants {
-        if let Some(preferred) = variants.get(&extensions[0].module) {
-            let mut desired = extensions[0].clone();
-
-            for em in extensions {
-                if &em.variant == preferred {
-                    desired = em.clone();
-                    break;
-                }
-            }
+    let module = &extensions[0].module;
 
-            desired
-        } else {
-            extensions[0].clone()
+    let preferred = match variants {
+        Some(variants) => variants.get(module).map(|v| v.as_str()),
+        None => None,
+    }
+    .or_else(|| {
+        DEFAULT_PREFERRED_VARIANTS
+            .iter()
+            .find(|(name, _)| *name == module.as_str())
+            .map(|(_, variant)| *variant)
+    });
+
+    if let Some(preferred) = preferred {
+        for em in extensions {
+            if em.variant == preferred {
+                return em.clone();
+            }
         }
-    } else {
-        extensions[0].clone()
     }
+
+    extensions[0].clone()
 }
 
 impl ParsedPythonDistribution {
@@ -482,14 +711,103 @@ impl ParsedPythonDistribution {
         path: &Path,
         extract_dir: &Path,
     ) -> Result<ParsedPythonDistribution> {
-        let mut fh =
-            fs::File::open(path).with_context(|| format!("unable to open {}", path.display()))?;
-
-        let mut python_distribution_data = Vec::new();
-        fh.read_to_end(&mut python_distribution_data)?;
-        let dist_cursor = Cursor::new(python_distribution_data);
         warn!(logger, "reading data from Python distribution...");
-        analyze_python_distribution_tar_zst(dist_cursor, &extract_dir)
+        let dist = analyze_python_distribution_archive(path, extract_dir)?;
+
+        let missing = dist.missing_embedding_requirements();
+        if !missing.is_empty() {
+            return Err(anyhow!(
+                "Python distribution {} cannot be embedded; it lacks:\n  {}",
+                path.display(),
+                missing.join("\n  ")
+            ));
+        }
+
+        Ok(dist)
+    }
+
+    /// Obtain a parsed distribution, reusing a persisted analysis if possible.
+    ///
+    /// `archive_sha256` is the verified SHA-256 of the archive at `path`. The
+    /// analysis of the extracted archive is persisted next to the extracted
+    /// files and reused while it matches the archive and analyzer version.
+    pub fn from_path_cached(
+        logger: &slog::Logger,
+        path: &Path,
+        extract_dir: &Path,
+        archive_sha256: &str,
+    ) -> Result<ParsedPythonDistribution> {
+        let cache_path = extract_dir.join(ANALYSIS_CACHE_FILENAME);
+
+        if cache_path.exists() {
+            match read_cached_analysis(&cache_path, extract_dir, archive_sha256) {
+                Ok(mut dist) => {
+                    info!(
+                        logger,
+                        "using cached analysis of Python distribution from {}",
+                        cache_path.display()
+                    );
+                    dist.archive_sha256 = archive_sha256.to_lowercase();
+                    return Ok(dist);
+                }
+                Err(e) => warn!(logger, "ignoring cached distribution analysis: {}", e),
+            }
+        }
+
+        let mut dist = ParsedPythonDistribution::from_path(logger, path, extract_dir)?;
+        dist.archive_sha256 = archive_sha256.to_lowercase();
+
+        if let Err(e) = write_cached_analysis(&cache_path, archive_sha256, &dist) {
+            warn!(
+                logger,
+                "unable to cache analysis of Python distribution: {}", e
+            );
+        }
+
+        Ok(dist)
+    }
+
+    /// Describe pieces needed to embed this distribution that it lacks.
+    ///
+    /// An empty result means the distribution can be linked into a binary.
+    pub fn missing_embedding_requirements(&self) -> Vec<String> {
+        let mut missing = Vec::new();
+
+        if !self.includes.contains_key("Python.h") {
+            missing.push("Python headers (include/Python.h)".to_string());
+        }
+
+        if self.objs_core.is_empty() {
+            missing.push("object files for the core Python implementation".to_string());
+
+            if self.os == "windows" {
+                missing.push(format!(
+                    "import library for the Python DLL (libs/python{}.lib)",
+                    self.version.split('.').take(2).collect::<Vec<_>>().join("")
+                ));
+            }
+        }
+
+        let shared_only = self
+            .extension_modules
+            .values()
+            .flatten()
+            .filter(|em| {
+                em.shared_library.is_some()
+                    && em.object_paths.is_empty()
+                    && em.static_library.is_none()
+            })
+            .map(|em| em.module.clone())
+            .collect::<BTreeSet<_>>();
+
+        if !shared_only.is_empty() {
+            missing.push(format!(
+                "object files or static libraries for extension modules: {}",
+                itertools::join(shared_only, ", ")
+            ));
+        }
+
+        missing
     }
 
     pub fn as_minimal_info(&self) -> PythonDistributionMinimalInfo {
@@ -509,10 +827,16 @@ impl ParsedPythonDistribution {
 
         let pip_path = python_paths.bin_dir.join(PIP_EXE_BASENAME);
 
-        if !pip_path.exists() {
-            warn!(logger, "{} doesnt exist", pip_path.display().to_string());
-            invoke_python(&python_paths, &logger, &["-m", "ensurepip"]);
-        }
+        // Other builds may share the extracted distribution.
+        with_extraction_lock(&self.base_dir, || {
+            if !pip_path.exists() {
+                warn!(logger, "{} doesnt exist", pip_path.display().to_string());
+                invoke_python(&python_paths, &logger, &["-m", "ensurepip"]);
+            }
+
+            Ok(())
+        })
+        .unwrap();
 
         pip_path
     }
@@ -523,23 +847,28 @@ impl ParsedPythonDistribution {
 
         let venv_dir_s = self.venv_base.display().to_string();
 
-        if !venv_base.exists() {
-            let dist_prefix = self.base_dir.join("python").join("install");
+        let python_paths = resolve_python_paths(&venv_base, &self.version);
 
-            copy_dir(&dist_prefix, &venv_base).unwrap();
+        // The hacked base lives next to the extracted distribution, which
+        // other builds may share.
+        with_extraction_lock(&self.base_dir, || {
+            if !venv_base.exists() {
+                let dist_prefix = self.base_dir.join("python").join("install");
 
-            let dist_prefix_s = dist_prefix.display().to_string();
-            warn!(
-                logger,
-                "copied {} to create hacked base {}", dist_prefix_s, venv_dir_s
-            );
-        }
+                copy_dir(&dist_prefix, &venv_base).unwrap();
 
-        let python_paths = resolve_python_paths(&venv_base, &self.version);
+                let dist_prefix_s = dist_prefix.display().to_string();
+                warn!(
+                    logger,
+                    "copied {} to create hacked base {}", dist_prefix_s, venv_dir_s
+                );
+            }
 
-        invoke_python(&python_paths, &logger, &["-m", "ensurepip"]);
+            invoke_python(&python_paths, &logger, &["-m", "ensurepip"]);
 
-        prepare_hacked_distutils(logger, self, &venv_base, &[]).unwrap();
+            prepare_hacked_distutils(logger, self, &venv_base, &[])
+        })
+        .unwrap();
 
         python_paths
     }
@@ -730,16 +1059,317 @@ impl ParsedPythonDistribution {
     }
 }
 
-fn parse_python_json_from_distribution(dist_dir: &Path) -> Result<PythonJsonMain> {
-    let python_json_path = dist_dir.join("python").join("PYTHON.json");
-    parse_python_json(&python_json_path)
+/// Locations of the `python` executable in distribution layouts we know of.
+///
+/// Paths are relative to the `python` directory of an extracted
+/// distribution. More preferred locations come first.
+const PYTHON_EXE_CANDIDATES: &[&str] = &[
+    "install/bin/python3",
+    "install/bin/python",
+    "install/python.exe",
+    "install/bin/python.exe",
+    "bin/python3",
+    "python.exe",
+];
+
+/// Read a string field of an extracted distribution's `PYTHON.json`.
+///
+/// This is lenient: distributions with a malformed or partial `PYTHON.json`
+/// may still have a usable interpreter.
+fn python_json_field(dist_dir: &Path, field: &str) -> Option<String> {
+    let data = fs::read(dist_dir.join("python").join("PYTHON.json")).ok()?;
+    let value = serde_json::from_slice::<serde_json::Value>(&data).ok()?;
+
+    value.get(field)?.as_str().map(|s| s.to_string())
+}
+
+/// The Python version of an extracted distribution according to `PYTHON.json`.
+pub fn distribution_python_version(dist_dir: &Path) -> Option<String> {
+    python_json_field(dist_dir, "python_version")
+}
+
+/// The flavor of an extracted distribution according to `PYTHON.json`.
+pub fn distribution_flavor(dist_dir: &Path) -> Option<String> {
+    python_json_field(dist_dir, "python_flavor")
+}
+
+/// Find where the `python` executable of an extracted distribution could be.
+///
+/// The `python_exe` field of `PYTHON.json` comes first when present,
+/// followed by `PYTHON_EXE_CANDIDATES`.
+fn python_exe_candidates(dist_dir: &Path) -> Vec<PathBuf> {
+    let python_dir = dist_dir.join("python");
+    let mut res = Vec::new();
+
+    if let Some(path) = python_json_field(dist_dir, "python_exe") {
+        res.push(python_dir.join(path));
+    }
+
+    for candidate in PYTHON_EXE_CANDIDATES {
+        let path = python_dir.join(candidate);
+        if !res.contains(&path) {
+            res.push(path);
+        }
+    }
+
+    res
+}
+
+/// Execute a `python` executable and obtain its `sys.version`.
+fn probe_python_version(python_exe: &Path) -> Result<String> {
+    let output = std::process::Command::new(python_exe)
+        .args(&["-c", "import sys; print(sys.version)"])
+        .output()?;
+
+    if !output.status.success() {
+        return Err(anyhow!(
+            "exited with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+
+    let version = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    if version.is_empty() {
+        return Err(anyhow!("printed no version"));
+    }
+
+    Ok(version)
+}
+
+/// Whether a `python` executable can run on this machine.
+///
+/// Executables built for another architecture or operating system can't.
+pub fn python_exe_runs(python_exe: &Path) -> bool {
+    probe_python_version(python_exe).is_ok()
+}
+
+/// Find a working `python` executable in an extracted distribution.
+///
+/// Candidates are tried in order. A candidate is used if it exists, isn't
+/// the interpreter of a virtualenv redirecting to another installation and
+/// `probe` succeeds. Errors list every candidate tried and why it was
+/// rejected.
+fn find_python_exe<F>(dist_dir: &Path, probe: F) -> Result<PathBuf>
+where
+    F: Fn(&Path) -> Result<String>,
+{
+    let mut rejected = Vec::new();
+
+    for candidate in python_exe_candidates(dist_dir) {
+        if !candidate.is_file() {
+            rejected.push(format!("{}: does not exist", candidate.display()));
+            continue;
+        }
+
+        // A virtualenv's interpreter runs the installation named by
+        // pyvenv.cfg, not the distribution.
+        let venv_cfg = candidate
+            .ancestors()
+            .skip(1)
+            .take(2)
+            .map(|dir| dir.join("pyvenv.cfg"))
+            .find(|p| p.exists());
+        if let Some(cfg) = venv_cfg {
+            rejected.push(format!(
+                "{}: is a virtualenv interpreter (found {})",
+                candidate.display(),
+                cfg.display()
+            ));
+            continue;
+        }
+
+        match probe(&candidate) {
+            Ok(_) => return Ok(candidate),
+            Err(e) => rejected.push(format!("{}: unable to run: {}", candidate.display(), e)),
+        }
+    }
+
+    Err(anyhow!(
+        "unable to find a working Python executable in {}; tried:\n  {}",
+        dist_dir.display(),
+        rejected.join("\n  ")
+    ))
+}
+
+/// Read the `python` executable from the persisted analysis of a distribution.
+fn cached_python_exe(dist_dir: &Path) -> Option<PathBuf> {
+    let fh = File::open(dist_dir.join(ANALYSIS_CACHE_FILENAME)).ok()?;
+    let cached: CachedAnalysis<ParsedPythonDistribution> =
+        serde_cbor::from_reader(BufReader::new(fh)).ok()?;
+
+    if cached.format_version == ANALYSIS_FORMAT_VERSION
+        && cached.distribution.base_dir == dist_dir
+        && cached.distribution.python_exe.is_file()
+    {
+        Some(cached.distribution.python_exe)
+    } else {
+        None
+    }
 }
 
 /// Resolve the path to a `python` executable in a Python distribution.
+///
+/// The path recorded by the distribution's persisted analysis is used if
+/// available. Otherwise known layouts are probed. See `find_python_exe()`.
+/// Executables of distributions for another operating system or
+/// architecture can't be run, so they are only required to exist.
 pub fn python_exe_path(dist_dir: &Path) -> Result<PathBuf> {
-    let pi = parse_python_json_from_distribution(dist_dir)?;
+    if let Some(path) = cached_python_exe(dist_dir) {
+        return Ok(path);
+    }
+
+    let foreign = |field: &str, host: &str| {
+        python_json_field(dist_dir, field).map_or(false, |value| value != host)
+    };
+
+    if foreign("os", std::env::consts::OS) || foreign("arch", std::env::consts::ARCH) {
+        find_python_exe(dist_dir, |_| Ok(String::new()))
+    } else {
+        find_python_exe(dist_dir, probe_python_version)
+    }
+}
+
+/// Files of a Python distribution being analyzed.
+///
+/// Paths are under the directory of the distribution.
+trait DistributionFiles {
+    /// Names of the entries of a directory.
+    fn dir_names(&self, dir: &Path) -> Result<Vec<String>>;
+
+    fn exists(&self, path: &Path) -> bool;
+
+    fn read(&self, path: &Path) -> Result<Vec<u8>>;
+
+    /// Size of a file, or 0 if unknown.
+    fn size(&self, path: &Path) -> u64;
+
+    /// Files inside a directory, recursively, sorted.
+    fn files_under(&self, dir: &Path) -> Vec<PathBuf>;
+
+    /// Path to the `python` executable.
+    fn python_exe(&self, dist_dir: &Path) -> Result<PathBuf>;
+}
+
+/// A distribution extracted to the filesystem.
+struct ExtractedFiles;
+
+impl DistributionFiles for ExtractedFiles {
+    fn dir_names(&self, dir: &Path) -> Result<Vec<String>> {
+        let mut res = Vec::new();
+
+        for entry in fs::read_dir(dir)? {
+            res.push(entry?.file_name().to_string_lossy().to_string());
+        }
+
+        Ok(res)
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        path.exists()
+    }
+
+    fn read(&self, path: &Path) -> Result<Vec<u8>> {
+        fs::read(path).with_context(|| format!("unable to read {}", path.display()))
+    }
+
+    fn size(&self, path: &Path) -> u64 {
+        files.size(path)
+    }
+
+    fn files_under(&self, dir: &Path) -> Vec<PathBuf> {
+        walk_tree_files(dir)
+            .map(|entry| entry.path().to_path_buf())
+            .collect()
+    }
+
+    fn python_exe(&self, dist_dir: &Path) -> Result<PathBuf> {
+        python_exe_path(dist_dir)
+    }
+}
+
+/// A distribution read from an archive without extracting it.
+///
+/// `base_dir` stands for the directory the archive would be extracted to.
+struct ArchiveFiles {
+    base_dir: PathBuf,
+    members: ArchiveMembers,
+}
+
+impl ArchiveFiles {
+    fn member<'a>(&self, path: &'a Path) -> &'a Path {
+        path.strip_prefix(&self.base_dir).unwrap_or(path)
+    }
+}
+
+impl DistributionFiles for ArchiveFiles {
+    fn dir_names(&self, dir: &Path) -> Result<Vec<String>> {
+        let dir = self.member(dir);
+
+        Ok(self
+            .members
+            .files
+            .keys()
+            .filter_map(|path| path.strip_prefix(dir).ok())
+            .filter_map(|rel| rel.iter().next())
+            .map(|name| name.to_string_lossy().to_string())
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .collect())
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        self.members.files.contains_key(self.member(path))
+    }
+
+    fn read(&self, path: &Path) -> Result<Vec<u8>> {
+        self.members
+            .contents
+            .get(self.member(path))
+            .cloned()
+            .ok_or_else(|| anyhow!("{} was not read from the archive", path.display()))
+    }
+
+    fn size(&self, path: &Path) -> u64 {
+        self.members
+            .files
+            .get(self.member(path))
+            .cloned()
+            .unwrap_or(0)
+    }
+
+    fn files_under(&self, dir: &Path) -> Vec<PathBuf> {
+        let dir = self.member(dir);
+
+        self.members
+            .files
+            .keys()
+            .filter(|path| path.starts_with(dir) && path.as_path() != dir)
+            .map(|path| self.base_dir.join(path))
+            .collect()
+    }
+
+    /// The executable isn't run, so it only needs to be in the archive.
+    fn python_exe(&self, dist_dir: &Path) -> Result<PathBuf> {
+        let python_exe = self
+            .read(&dist_dir.join("python").join("PYTHON.json"))
+            .ok()
+            .and_then(|data| serde_json::from_slice::<serde_json::Value>(&data).ok())
+            .and_then(|value| {
+                value
+                    .get("python_exe")
+                    .and_then(|v| v.as_str())
+                    .map(|s| s.to_string())
+            });
 
-    Ok(dist_dir.join("python").join(&pi.python_exe))
+        python_exe
+            .iter()
+            .map(|s| s.as_str())
+            .chain(PYTHON_EXE_CANDIDATES.iter().cloned())
+            .map(|candidate| dist_dir.join("python").join(candidate))
+            .find(|path| self.exists(path))
+            .ok_or_else(|| anyhow!("unable to find a Python executable in the archive"))
+    }
 }
 
 /// Extract useful information from the files constituting a Python distribution.
@@ -748,6 +1378,13 @@ pub fn python_exe_path(dist_dir: &Path) -> Result<PathBuf> {
 /// it makes things easier to implement and allows us to do things like consume
 /// tarballs without filesystem I/O.
 pub fn analyze_python_distribution_data(dist_dir: &Path) -> Result<ParsedPythonDistribution> {
+    analyze_distribution_files(dist_dir, &ExtractedFiles)
+}
+
+fn analyze_distribution_files<F: DistributionFiles>(
+    dist_dir: &Path,
+    files: &F,
+) -> Result<ParsedPythonDistribution> {
     let mut objs_core: BTreeMap<PathBuf, PathBuf> = BTreeMap::new();
     let mut links_core: Vec<LibraryDepends> = Vec::new();
     let mut extension_modules: BTreeMap<String, Vec<ExtensionModule>> = BTreeMap::new();
@@ -757,12 +1394,12 @@ pub fn analyze_python_distribution_data(dist_dir: &Path) -> Result<ParsedPythonD
     let mut py_modules: BTreeMap<String, PathBuf> = BTreeMap::new();
     let mut resources: BTreeMap<String, BTreeMap<String, PathBuf>> = BTreeMap::new();
     let mut license_infos: BTreeMap<String, Vec<LicenseInfo>> = BTreeMap::new();
+    let mut sizes = DistributionSizes::default();
 
-    for entry in fs::read_dir(dist_dir)? {
-        let entry = entry?;
-
-        match entry.file_name().to_str() {
+    for name in files.dir_names(dist_dir)? {
+        match Some(name.as_str()) {
             Some("python") => continue,
+            Some(ANALYSIS_CACHE_FILENAME) => continue,
             Some(value) => panic!("unexpected entry in distribution root directory: {}", value),
             _ => panic!("error listing root directory of Python distribution"),
         };
@@ -770,10 +1407,8 @@ pub fn analyze_python_distribution_data(dist_dir: &Path) -> Result<ParsedPythonD
 
     let python_path = dist_dir.join("python");
 
-    for entry in fs::read_dir(&python_path)? {
-        let entry = entry?;
-
-        match entry.file_name().to_str() {
+    for name in files.dir_names(&python_path)? {
+        match Some(name.as_str()) {
             Some("build") => continue,
             Some("install") => continue,
             Some("lib") => continue,
@@ -785,11 +1420,17 @@ pub fn analyze_python_distribution_data(dist_dir: &Path) -> Result<ParsedPythonD
         };
     }
 
-    let pi = parse_python_json_from_distribution(dist_dir)?;
+    let python_json_path = python_path.join("PYTHON.json");
+    if !files.exists(&python_json_path) {
+        panic!("PYTHON.json does not exist; are you using an up-to-date Python distribution that conforms with our requirements?");
+    }
+    let pi: PythonJsonMain = serde_json::from_slice(&files.read(&python_json_path)?)?;
 
     if let Some(ref python_license_path) = pi.license_path {
         let license_path = python_path.join(python_license_path);
-        let license_text = fs::read_to_string(&license_path)
+        let license_text = files
+            .read(&license_path)
+            .and_then(|data| Ok(String::from_utf8(data)?))
             .with_context(|| format!("unable to read Python license {}", license_path.display()))?;
 
         let mut licenses = Vec::new();
@@ -807,6 +1448,7 @@ pub fn analyze_python_distribution_data(dist_dir: &Path) -> Result<ParsedPythonD
         let rel_path = PathBuf::from(obj);
         let full_path = python_path.join(obj);
 
+        sizes.core.insert(obj.clone(), files.size(&full_path));
         objs_core.insert(rel_path, full_path);
     }
 
@@ -825,7 +1467,12 @@ pub fn analyze_python_distribution_data(dist_dir: &Path) -> Result<ParsedPythonD
         let mut ems: Vec<ExtensionModule> = Vec::new();
 
         for entry in variants.iter() {
-            let object_paths = entry.objs.iter().map(|p| python_path.join(p)).collect();
+            let object_paths: Vec<PathBuf> =
+                entry.objs.iter().map(|p| python_path.join(p)).collect();
+            let static_library = match &entry.static_lib {
+                Some(p) => Some(python_path.join(p)),
+                None => None,
+            };
             let mut links = Vec::new();
 
             for link in &entry.links {
@@ -843,7 +1490,9 @@ pub fn analyze_python_distribution_data(dist_dir: &Path) -> Result<ParsedPythonD
 
                 for license_path in license_paths {
                     let license_path = python_path.join(license_path);
-                    let license_text = fs::read_to_string(&license_path)
+                    let license_text = files
+                        .read(&license_path)
+                        .and_then(|data| Ok(String::from_utf8(data)?))
                         .with_context(|| "unable to read license file")?;
 
                     licenses.push(LicenseInfo {
@@ -861,6 +1510,18 @@ pub fn analyze_python_distribution_data(dist_dir: &Path) -> Result<ParsedPythonD
                 license_infos.insert(module.clone(), licenses);
             }
 
+            let size_key = if variants.len() > 1 {
+                format!("{}[{}]", module, entry.variant)
+            } else {
+                module.clone()
+            };
+            let size = object_paths
+                .iter()
+                .chain(static_library.iter())
+                .map(|p: &PathBuf| files.size(p))
+                .sum();
+            sizes.extension_modules.insert(size_key, size);
+
             ems.push(ExtensionModule {
                 module: module.clone(),
                 init_fn: Some(entry.init_fn.clone()),
@@ -874,10 +1535,8 @@ pub fn analyze_python_distribution_data(dist_dir: &Path) -> Result<ParsedPythonD
                 licenses: entry.licenses.clone(),
                 object_paths,
                 required: entry.required,
-                static_library: match &entry.static_lib {
-                    Some(p) => Some(python_path.join(p)),
-                    None => None,
-                },
+                static_library,
+                shared_library: None,
                 links,
                 variant: entry.variant.clone(),
             });
@@ -886,10 +1545,13 @@ pub fn analyze_python_distribution_data(dist_dir: &Path) -> Result<ParsedPythonD
         extension_modules.insert(module.clone(), ems);
     }
 
+    for (name, path) in &libraries {
+        sizes.libraries.insert(name.clone(), files.size(path));
+    }
+
     let include_path = python_path.join(pi.python_include);
 
-    for entry in walk_tree_files(&include_path) {
-        let full_path = entry.path();
+    for full_path in files.files_under(&include_path) {
         let rel_path = full_path
             .strip_prefix(&include_path)
             .expect("unable to strip prefix");
@@ -901,13 +1563,18 @@ pub fn analyze_python_distribution_data(dist_dir: &Path) -> Result<ParsedPythonD
 
     let stdlib_path = python_path.join(pi.python_stdlib);
 
-    for entry in find_python_resources(&stdlib_path) {
+    for entry in find_python_resources_in_paths(&stdlib_path, files.files_under(&stdlib_path)) {
         match entry {
             PythonFileResource::Resource(resource) => {
                 if !resources.contains_key(&resource.package) {
                     resources.insert(resource.package.clone(), BTreeMap::new());
                 }
 
+                *sizes
+                    .resources
+                    .entry(top_level_package(&resource.package))
+                    .or_insert(0) += files.size(&resource.path);
+
                 resources
                     .get_mut(&resource.package)
                     .unwrap()
@@ -916,6 +1583,11 @@ pub fn analyze_python_distribution_data(dist_dir: &Path) -> Result<ParsedPythonD
             PythonFileResource::Source {
                 full_name, path, ..
             } => {
+                *sizes
+                    .stdlib_packages
+                    .entry(top_level_package(&full_name))
+                    .or_insert(0) += files.size(&path);
+
                 py_modules.insert(full_name.clone(), path);
             }
             _ => {}
@@ -924,12 +1596,18 @@ pub fn analyze_python_distribution_data(dist_dir: &Path) -> Result<ParsedPythonD
 
     let venv_base = dist_dir.parent().unwrap().join("hacked_base");
 
+    // Only known if the library was read, which analyzing metadata may skip.
+    let sqlite_compile_options = libraries
+        .get("sqlite3")
+        .and_then(|path| files.read(path).ok())
+        .and_then(|data| library_compile_options(&data));
+
     Ok(ParsedPythonDistribution {
         flavor: pi.python_flavor.clone(),
         version: pi.python_version.clone(),
         os: pi.os.clone(),
         arch: pi.arch.clone(),
-        python_exe: python_exe_path(dist_dir)?,
+        python_exe: files.python_exe(dist_dir)?,
         stdlib_path,
         licenses: pi.licenses.clone(),
         license_path: match pi.license_path {
@@ -951,24 +1629,24 @@ pub fn analyze_python_distribution_data(dist_dir: &Path) -> Result<ParsedPythonD
         resources,
         license_infos,
         venv_base,
+        sizes,
+        archive_sha256: String::new(),
+        sqlite_compile_options,
     })
 }
 
-/// Extract Python distribution data from a tar archive.
-pub fn analyze_python_distribution_tar<R: Read>(
-    source: R,
-    extract_dir: &Path,
-) -> Result<ParsedPythonDistribution> {
-    let mut tf = tar::Archive::new(source);
-
+/// Run a function while holding the lock serializing distribution extraction.
+pub(crate) fn with_extraction_lock<T, F>(extract_dir: &Path, f: F) -> Result<T>
+where
+    F: FnOnce() -> Result<T>,
+{
     // Multiple threads or processes could race to extract the archive.
-    // So we use a lock file to ensure exclusive access.
-    // TODO use more granular lock based on the output directory (possibly
-    // by putting lock in output directory itself).
-    let lock_path = extract_dir
-        .parent()
-        .unwrap()
-        .join("distribution-extract-lock");
+    // So we use a lock file to ensure exclusive access. Distributions in the
+    // distribution cache each have a directory, so the lock only covers
+    // one distribution there.
+    let lock_dir = extract_dir.parent().unwrap();
+    create_dir_all(lock_dir)?;
+    let lock_path = lock_dir.join(EXTRACT_LOCK_FILENAME);
 
     let file = File::create(&lock_path)
         .with_context(|| format!("could not create {}", lock_path.display()))?;
@@ -976,14 +1654,43 @@ pub fn analyze_python_distribution_tar<R: Read>(
     file.lock_exclusive()
         .with_context(|| format!("failed to obtain lock for {}", lock_path.display()))?;
 
-    // The content of the distribution could change between runs. But caching
-    // the extraction does keep things fast.
-    let test_path = extract_dir.join("python").join("PYTHON.json");
-    if !test_path.exists() {
-        std::fs::create_dir_all(extract_dir)?;
-        let absolute_path = std::fs::canonicalize(extract_dir)?;
-        tf.unpack(&absolute_path)
-            .with_context(|| "unable to extract tar archive")?;
+    let res = f()?;
+
+    file.unlock()
+        .with_context(|| format!("releasing lock on {}", lock_path.display()))?;
+
+    Ok(res)
+}
+
+/// Extract a Python distribution tar archive to a directory.
+///
+/// Extraction is skipped if the directory already holds the distribution.
+fn extract_python_distribution_tar<R: Read>(source: R, extract_dir: &Path) -> Result<()> {
+    let mut tf = tar::Archive::new(source);
+
+    with_extraction_lock(extract_dir, || {
+        // The content of the distribution could change between runs. But caching
+        // the extraction does keep things fast.
+        let test_path = extract_dir.join("python").join("PYTHON.json");
+        if test_path.exists() {
+            return Ok(());
+        }
+
+        // Extract into a staging directory so an interrupted extraction
+        // doesn't leave a partial distribution behind.
+        let staged = StagedDir::new(extract_dir)?;
+        let absolute_path = std::fs::canonicalize(staged.path())?;
+
+        for entry in tf
+            .entries()
+            .with_context(|| "unable to extract tar archive")?
+        {
+            cancel::check_cancelled()?;
+
+            entry
+                .and_then(|mut entry| entry.unpack_in(&absolute_path))
+                .with_context(|| "unable to extract tar archive")?;
+        }
 
         // Ensure unpacked files are writable. We've had issues where we
         // consume archives with read-only file permissions. When we later
@@ -1003,10 +1710,17 @@ pub fn analyze_python_distribution_tar<R: Read>(
                 })?;
             }
         }
-    }
 
-    file.unlock()
-        .with_context(|| format!("releasing lock on {}", lock_path.display()))?;
+        staged.commit()
+    })
+}
+
+/// Extract Python distribution data from a tar archive.
+pub fn analyze_python_distribution_tar<R: Read>(
+    source: R,
+    extract_dir: &Path,
+) -> Result<ParsedPythonDistribution> {
+    extract_python_distribution_tar(source, extract_dir)?;
 
     analyze_python_distribution_data(extract_dir)
 }
@@ -1021,6 +1735,180 @@ pub fn analyze_python_distribution_tar_zst<R: Read>(
     analyze_python_distribution_tar(dctx, extract_dir)
 }
 
+/// Extract Python distribution data from a distribution archive file.
+///
+/// Zstandard compressed tar archives are expected to hold a `PYTHON.json`
+/// describing the distribution. Failures reading them are reported as an
+/// `ArchiveError`. Zip archives are analyzed as embeddable distributions.
+pub fn analyze_python_distribution_archive(
+    path: &Path,
+    extract_dir: &Path,
+) -> Result<ParsedPythonDistribution> {
+    if is_zip_archive(path)? {
+        return analyze_embeddable_distribution_zip(path, extract_dir);
+    }
+
+    read_tar_zst(path, false, |reader| {
+        extract_python_distribution_tar(reader, extract_dir)
+    })?;
+
+    analyze_python_distribution_data(extract_dir)
+}
+
+/// Analyze a distribution archive without extracting it.
+///
+/// Only `PYTHON.json` and license files are read into memory. Everything
+/// else in the result is derived from the names of archive members, so
+/// paths in it are under `path` and don't exist, the `python` executable
+/// isn't run and sizes of hard links are 0. Use
+/// `analyze_python_distribution_archive()` when files or their sizes are
+/// needed. Embeddable zip archives are small, so they are extracted to
+/// `extract_dir` and analyzed as usual.
+pub fn analyze_python_distribution_metadata(
+    path: &Path,
+    extract_dir: &Path,
+) -> Result<ParsedPythonDistribution> {
+    if is_zip_archive(path)? {
+        return analyze_embeddable_distribution_zip(path, extract_dir);
+    }
+
+    let mut selection = MemberSelection::metadata();
+    let mut members = read_tar_zst(path, false, |reader| read_members(reader, &selection))?;
+
+    // Licenses are usually in the selected directories. Read the archive
+    // again for any elsewhere and for the SQLite library, whose compile
+    // options are part of the metadata.
+    let python_json = members
+        .contents
+        .get(Path::new("python/PYTHON.json"))
+        .and_then(|data| serde_json::from_slice::<PythonJsonMain>(data).ok());
+    if let Some(pi) = python_json {
+        let paths = pi
+            .license_path
+            .iter()
+            .chain(
+                pi.build_info
+                    .extensions
+                    .values()
+                    .flatten()
+                    .filter_map(|entry| entry.license_paths.as_ref())
+                    .flatten(),
+            )
+            .chain(
+                pi.build_info
+                    .extensions
+                    .values()
+                    .flatten()
+                    .flat_map(|entry| entry.links.iter())
+                    .filter(|link| link.name == "sqlite3")
+                    .filter_map(|link| link.path_static.as_ref()),
+            )
+            .map(|p| Path::new("python").join(p))
+            .filter(|p| !members.contents.contains_key(p))
+            .collect::<Vec<_>>();
+
+        if !paths.is_empty() {
+            for p in paths {
+                selection.include(p);
+            }
+            members = read_tar_zst(path, false, |reader| read_members(reader, &selection))?;
+        }
+    }
+
+    analyze_distribution_files(
+        path,
+        &ArchiveFiles {
+            base_dir: path.to_path_buf(),
+            members,
+        },
+    )
+}
+
+/// Persisted analysis of an extracted Python distribution.
+#[derive(Deserialize, Serialize)]
+struct CachedAnalysis<D> {
+    format_version: u32,
+    archive_sha256: String,
+    distribution: D,
+}
+
+/// Read a persisted distribution analysis.
+///
+/// Errors if the file can't be parsed, was written by another analyzer
+/// version, for another archive or for another extraction directory.
+fn read_cached_analysis(
+    cache_path: &Path,
+    extract_dir: &Path,
+    archive_sha256: &str,
+) -> Result<ParsedPythonDistribution> {
+    let fh = File::open(cache_path)?;
+    let cached: CachedAnalysis<ParsedPythonDistribution> =
+        serde_cbor::from_reader(BufReader::new(fh))
+            .with_context(|| format!("unable to parse {}", cache_path.display()))?;
+
+    if cached.format_version != ANALYSIS_FORMAT_VERSION {
+        return Err(anyhow!(
+            "analysis format version {} does not match {}",
+            cached.format_version,
+            ANALYSIS_FORMAT_VERSION
+        ));
+    }
+
+    if !cached.archive_sha256.eq_ignore_ascii_case(archive_sha256) {
+        return Err(anyhow!(
+            "analysis is for archive {}, not {}",
+            cached.archive_sha256,
+            archive_sha256
+        ));
+    }
+
+    // Paths in the analysis point into the extraction directory.
+    if cached.distribution.base_dir != extract_dir {
+        return Err(anyhow!(
+            "analysis is for {}, not {}",
+            cached.distribution.base_dir.display(),
+            extract_dir.display()
+        ));
+    }
+
+    if !cached.distribution.python_exe.exists() {
+        return Err(anyhow!(
+            "{} no longer exists",
+            cached.distribution.python_exe.display()
+        ));
+    }
+
+    Ok(cached.distribution)
+}
+
+/// Persist the analysis of an extracted Python distribution.
+fn write_cached_analysis(
+    cache_path: &Path,
+    archive_sha256: &str,
+    dist: &ParsedPythonDistribution,
+) -> Result<()> {
+    let cached = CachedAnalysis {
+        format_version: ANALYSIS_FORMAT_VERSION,
+        archive_sha256: archive_sha256.to_string(),
+        distribution: dist,
+    };
+
+    // Write to a temporary file so concurrent readers never see a partial file.
+    let mut temp_path = cache_path.to_path_buf();
+    temp_path.set_file_name(format!("{}.tmp", Uuid::new_v4()));
+
+    let data = serde_cbor::to_vec(&cached)?;
+    fs::write(&temp_path, data)
+        .with_context(|| format!("unable to write {}", temp_path.display()))?;
+
+    if let Err(e) = move_path(&temp_path, cache_path) {
+        let _ = fs::remove_file(&temp_path);
+        return Err(e);
+    }
+
+    Ok(())
+}
+
 fn sha256_path(path: &PathBuf) -> Vec<u8> {
     let mut hasher = Sha256::new();
     let fh = File::open(&path).unwrap();
@@ -1039,142 +1927,534 @@ fn sha256_path(path: &PathBuf) -> Vec<u8> {
     hasher.result().to_vec()
 }
 
-pub fn get_http_client() -> reqwest::Result<reqwest::Client> {
-    let mut builder = reqwest::ClientBuilder::new();
+/// Suffix of files distributions are downloaded to before being verified.
+const PARTIAL_SUFFIX: &str = ".partial";
 
-    for (key, value) in std::env::vars() {
-        let key = key.to_lowercase();
-        if key.ends_with("_proxy") {
-            let end = key.len() - "_proxy".len();
-            let schema = &key[..end];
+/// Age after which partial downloads are considered abandoned.
+///
+/// Downloads by concurrent processes are younger than this.
+const PARTIAL_DOWNLOAD_MAX_AGE: Duration = Duration::from_secs(3600);
+
+/// Metadata recorded next to a downloaded distribution in the cache.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+struct CacheEntryMetadata {
+    url: String,
+    size: u64,
+    sha256: String,
+    /// When the download completed, in seconds since the UNIX epoch.
+    completed: u64,
+}
 
-            if let Ok(url) = Url::parse(&value) {
-                if let Some(proxy) = match schema {
-                    "http" => Some(reqwest::Proxy::http(url.as_str())),
-                    "https" => Some(reqwest::Proxy::https(url.as_str())),
-                    _ => None,
-                } {
-                    if let Ok(proxy) = proxy {
-                        builder = builder.proxy(proxy);
-                    }
+impl CacheEntryMetadata {
+    fn path(cache_path: &Path) -> PathBuf {
+        let mut name = cache_path.as_os_str().to_os_string();
+        name.push(".json");
+        PathBuf::from(name)
+    }
+
+    fn read(cache_path: &Path) -> Option<Self> {
+        let data = fs::read(Self::path(cache_path)).ok()?;
+        serde_json::from_slice(&data).ok()
+    }
+
+    fn write(&self, cache_path: &Path) -> Result<()> {
+        let path = Self::path(cache_path);
+        fs::write(&path, serde_json::to_vec_pretty(self)?)
+            .with_context(|| format!("unable to write {}", path.display()))
+    }
+}
+
+/// Path of a distribution archive in the cache.
+///
+/// Archives are keyed by their SHA-256, so distributions sharing a file name
+/// don't collide and an entry always holds the content its key names.
+fn cache_entry_path(cache_dir: &Path, sha256: &str, basename: &str) -> PathBuf {
+    cache_dir.join(sha256.to_lowercase()).join(basename)
+}
+
+/// Error for a distribution archive whose SHA-256 isn't the configured one.
+///
+/// `path` is where the offending file can be inspected.
+fn sha256_mismatch_error(expected: &str, actual: &[u8], path: &Path) -> anyhow::Error {
+    anyhow!(
+        "sha256 of Python distribution does not validate: expected {}, got {}; the file is at {}",
+        expected.to_lowercase(),
+        hex::encode(actual),
+        path.display()
+    )
+}
+
+/// A unique path to write a file in the cache to before moving it into place.
+fn partial_path(cache_dir: &Path, basename: &str) -> PathBuf {
+    cache_dir.join(format!("{}.{}{}", basename, Uuid::new_v4(), PARTIAL_SUFFIX))
+}
+
+/// Remove partial downloads abandoned by interrupted processes.
+///
+/// Errors are ignored: the files are only wasting space.
+fn remove_abandoned_partial_downloads(cache_dir: &Path, max_age: Duration) {
+    let entries = match fs::read_dir(cache_dir) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+
+    for entry in entries.filter_map(|entry| entry.ok()) {
+        if !entry
+            .file_name()
+            .to_string_lossy()
+            .ends_with(PARTIAL_SUFFIX)
+        {
+            continue;
+        }
+
+        let age = entry
+            .metadata()
+            .and_then(|m| m.modified())
+            .ok()
+            .and_then(|modified| modified.elapsed().ok());
+
+        if let Some(age) = age {
+            if age > max_age {
+                print_human(&format!(
+                    "removing abandoned download {}",
+                    entry.path().display()
+                ));
+                let _ = fs::remove_file(entry.path());
+            }
+        }
+    }
+}
+
+/// Whether a cached distribution is complete.
+///
+/// The size recorded when it was verified is checked first, so truncated
+/// files are noticed without hashing them. Entries whose metadata records
+/// the expected hash were verified before being moved into place and aren't
+/// hashed again. Corruption after that is caught when the archive is read.
+fn cached_archive_valid(cache_path: &Path, expected_hash: &[u8]) -> bool {
+    let size = match fs::metadata(cache_path) {
+        Ok(metadata) => metadata.len(),
+        Err(_) => return false,
+    };
+
+    if let Some(metadata) = CacheEntryMetadata::read(cache_path) {
+        if metadata.size != size {
+            return false;
+        }
+
+        if metadata
+            .sha256
+            .eq_ignore_ascii_case(&hex::encode(expected_hash))
+        {
+            return true;
+        }
+    }
+
+    // We don't care about timing side-channels from the string compare.
+    sha256_path(&cache_path.to_path_buf()) == expected_hash
+}
+
+/// Make one attempt at downloading a URL to `path`.
+///
+/// If `path` holds the start of an earlier attempt, the rest is requested
+/// and appended if the server supports range requests. Otherwise the file
+/// is overwritten. Returns the size of the file.
+fn download_attempt<F>(u: &Url, path: &Path, fetch: &F) -> Result<u64>
+where
+    F: Fn(&Url, u64) -> Result<FetchResponse>,
+{
+    let offset = fs::metadata(path).map(|m| m.len()).unwrap_or(0);
+    let response = fetch(u, offset)?;
+
+    let (mut fh, start) = if response.resumed {
+        print_human(&format!("resuming download of {} at byte {}", u, offset));
+        let fh = fs::OpenOptions::new()
+            .append(true)
+            .open(path)
+            .with_context(|| format!("unable to open {}", path.display()))?;
+        (fh, offset)
+    } else {
+        let fh =
+            File::create(path).with_context(|| format!("unable to create {}", path.display()))?;
+        (fh, 0)
+    };
+
+    let total = response.content_length.map(|length| length + start);
+    let mut progress = DownloadProgress::new(total);
+    let mut reader = response.reader;
+    let mut size = start;
+
+    // Read incrementally so cancellation is noticed mid-download.
+    let mut buffer = [0; 32768];
+    let res = loop {
+        if let Err(e) = cancel::check_cancelled() {
+            break Err(e);
+        }
+
+        let count = match reader.read(&mut buffer) {
+            Ok(0) => break Ok(()),
+            Ok(count) => count,
+            Err(e) => break Err(interrupted(u, e)),
+        };
+
+        if let Err(e) = fh.write_all(&buffer[..count]) {
+            break Err(anyhow!(e).context(format!("unable to write {}", path.display())));
+        }
+        size += count as u64;
+        progress.update(size);
+    };
+    progress.finish(size);
+    res?;
+
+    fh.sync_all()?;
+
+    // Catch connections dropping mid-transfer before the hash check,
+    // which would only report a mismatch.
+    if let Some(expected) = total {
+        if size != expected {
+            return Err(truncated(u, size, expected));
+        }
+    }
+
+    Ok(size)
+}
+
+/// Download a URL to a file, verifying its length and hash.
+///
+/// Transient failures are retried according to `retry`, resuming where the
+/// previous attempt stopped. If every attempt fails, the file is kept so
+/// the next build resumes it; other failures remove it. If its hash doesn't
+/// validate, it is moved to `rejected_path` for inspection.
+fn download_to_partial<F>(
+    u: &Url,
+    expected_hash: &[u8],
+    partial_path: &Path,
+    rejected_path: &Path,
+    retry: &RetryPolicy,
+    fetch: &F,
+) -> Result<u64>
+where
+    F: Fn(&Url, u64) -> Result<FetchResponse>,
+{
+    let mut attempt = 1;
+
+    let size = loop {
+        match download_attempt(u, partial_path, fetch) {
+            Ok(size) => break size,
+            Err(e) if is_transient(&e) && attempt < retry.attempts => {
+                let delay = retry.backoff(attempt);
+                print_human(&format!(
+                    "{}; retrying in {}s (attempt {} of {})",
+                    e,
+                    delay.as_secs(),
+                    attempt + 1,
+                    retry.attempts
+                ));
+                std::thread::sleep(delay);
+                cancel::check_cancelled()?;
+                attempt += 1;
+            }
+            Err(e) if is_transient(&e) => {
+                return Err(anyhow!(
+                    "{}; giving up after {} attempts; run the build again to resume",
+                    e,
+                    attempt
+                ));
+            }
+            Err(e) => {
+                if !cancel::is_cancelled_error(&e) {
+                    let _ = fs::remove_file(partial_path);
                 }
+                return Err(e);
             }
         }
+    };
+
+    let actual_hash = sha256_path(&partial_path.to_path_buf());
+    if actual_hash != expected_hash {
+        let _ = fs::remove_file(rejected_path);
+        move_path(partial_path, rejected_path)?;
+
+        return Err(sha256_mismatch_error(
+            &hex::encode(expected_hash),
+            &actual_hash,
+            rejected_path,
+        ));
     }
 
-    builder.build()
+    Ok(size)
 }
 
 /// Ensure a Python distribution at a URL is available in a local directory.
 ///
 /// The path to the downloaded and validated file is returned.
-pub fn download_distribution(url: &str, sha256: &str, cache_dir: &Path) -> PathBuf {
-    let expected_hash = hex::decode(sha256).expect("could not parse SHA256 hash");
-    let u = Url::parse(url).expect("failed to parse URL");
+pub fn download_distribution(url: &str, sha256: &str, cache_dir: &Path) -> Result<PathBuf> {
+    download_distribution_with(url, sha256, cache_dir, &RetryPolicy::default(), &http_fetch)
+}
+
+/// Download a distribution into a cache directory using a fetch function.
+///
+/// Distributions are downloaded to a `.download` file, verified against
+/// their expected length and hash and only then renamed into place, so an
+/// interrupted download never appears complete. The `.download` file is
+/// kept when a download fails part way, and the next attempt resumes it.
+/// A `.lock` file next to it keeps concurrent builds from downloading the
+/// same archive. Cached archives failing verification, e.g. because an
+/// older version of PyOxidizer was killed while writing them, are deleted
+/// and downloaded again. Downloads with the wrong hash are kept as
+/// `<name>.rejected` next to where the archive would be cached.
+fn download_distribution_with<F>(
+    url: &str,
+    sha256: &str,
+    cache_dir: &Path,
+    retry: &RetryPolicy,
+    fetch: &F,
+) -> Result<PathBuf>
+where
+    F: Fn(&Url, u64) -> Result<FetchResponse>,
+{
+    let expected_hash = hex::decode(sha256).context("could not parse SHA256 hash")?;
+    let u = Url::parse(url).context("failed to parse URL")?;
 
     let basename = u
         .path_segments()
-        .expect("cannot be base path")
+        .ok_or_else(|| anyhow!("cannot be base path"))?
         .last()
-        .expect("could not get final URL path element")
+        .ok_or_else(|| anyhow!("could not get final URL path element"))?
         .to_string();
 
-    let cache_path = cache_dir.join(basename);
+    let cache_path = cache_entry_path(cache_dir, sha256, &basename);
+    let entry_dir = cache_path.parent().unwrap();
+    create_dir_all(entry_dir)?;
 
-    if cache_path.exists() {
-        let file_hash = sha256_path(&cache_path);
+    remove_abandoned_partial_downloads(entry_dir, PARTIAL_DOWNLOAD_MAX_AGE);
 
-        // We don't care about timing side-channels from the string compare.
-        if file_hash == expected_hash {
-            return cache_path;
+    let lock_path = entry_dir.join(format!("{}.lock", basename));
+    let lock = File::create(&lock_path)
+        .with_context(|| format!("could not create {}", lock_path.display()))?;
+    if lock.try_lock_exclusive().is_err() {
+        print_human(&format!(
+            "waiting for another process downloading {}",
+            basename
+        ));
+        lock.lock_exclusive()
+            .with_context(|| format!("failed to obtain lock for {}", lock_path.display()))?;
+    }
+
+    // The lock is released when `lock` is dropped.
+    if cache_path.exists() {
+        if cached_archive_valid(&cache_path, &expected_hash) {
+            build_stats::record_cache(Cache::Distribution, 1, 0);
+            return Ok(cache_path);
         }
+
+        print_human(&format!(
+            "{} is incomplete or corrupt; downloading it again",
+            cache_path.display()
+        ));
+        fs::remove_file(&cache_path)
+            .with_context(|| format!("unable to remove {}", cache_path.display()))?;
+        let _ = fs::remove_file(CacheEntryMetadata::path(&cache_path));
     }
 
-    let mut data: Vec<u8> = Vec::new();
+    if crate::environment::frozen() {
+        return Err(anyhow!(
+            "{} is not in the download cache and network access is forbidden (--frozen)",
+            url
+        ));
+    }
 
-    println!("downloading {}", u);
-    let client = get_http_client().expect("unable to get HTTP client");
-    let mut response = client
-        .get(u.as_str())
-        .send()
-        .expect("unable to perform HTTP request");
-    response
-        .read_to_end(&mut data)
-        .expect("unable to download URL");
+    let download_path = entry_dir.join(format!("{}.download", basename));
+    let rejected_path = entry_dir.join(format!("{}.rejected", basename));
+
+    build_stats::record_cache(Cache::Distribution, 0, 1);
+    print_human(&format!("downloading {}", u));
+    let size = download_to_partial(
+        &u,
+        &expected_hash,
+        &download_path,
+        &rejected_path,
+        retry,
+        fetch,
+    )?;
+
+    move_path(&download_path, &cache_path).context("unable to rename downloaded file")?;
+
+    let completed = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+
+    CacheEntryMetadata {
+        url: url.to_string(),
+        size,
+        sha256: sha256.to_lowercase(),
+        completed,
+    }
+    .write(&cache_path)?;
 
-    let mut hasher = Sha256::new();
-    hasher.input(&data);
+    Ok(cache_path)
+}
+
+pub fn copy_local_distribution(path: &PathBuf, sha256: &str, cache_dir: &Path) -> Result<PathBuf> {
+    let expected_hash = hex::decode(sha256).context("could not parse SHA256 hash")?;
+    let basename = path
+        .file_name()
+        .ok_or_else(|| anyhow!("{} has no file name", path.display()))?
+        .to_string_lossy()
+        .to_string();
+    let cache_path = cache_entry_path(cache_dir, sha256, &basename);
+    let entry_dir = cache_path.parent().unwrap();
+    create_dir_all(entry_dir)?;
+
+    remove_abandoned_partial_downloads(entry_dir, PARTIAL_DOWNLOAD_MAX_AGE);
 
-    let url_hash = hasher.result().to_vec();
-    if url_hash != expected_hash {
-        panic!("sha256 of Python distribution does not validate");
+    if cached_archive_valid(&cache_path, &expected_hash) {
+        build_stats::record_cache(Cache::Distribution, 1, 0);
+        return Ok(cache_path);
     }
 
-    let mut temp_cache_path = cache_path.clone();
-    temp_cache_path.set_file_name(format!("{}.tmp", Uuid::new_v4()));
+    let source_hash = sha256_path(&path);
 
-    fs::write(&temp_cache_path, data).expect("unable to write file");
+    if source_hash != expected_hash {
+        return Err(sha256_mismatch_error(sha256, &source_hash, path));
+    }
 
-    fs::rename(&temp_cache_path, &cache_path)
-        .or_else(|e| {
-            fs::remove_file(&temp_cache_path).expect("unable to remove temp file");
+    cancel::check_cancelled()?;
 
-            if cache_path.exists() {
-                download_distribution(url, sha256, cache_dir);
-                return Ok(());
-            }
+    build_stats::record_cache(Cache::Distribution, 0, 1);
+    print_human(&format!("copying {}", path.display()));
+    let partial_path = partial_path(entry_dir, &basename);
+    let size = std::fs::copy(path, &partial_path)
+        .with_context(|| format!("copying {}", path.display()))?;
 
-            Err(e)
-        })
-        .expect("unable to rename downloaded file");
+    if let Err(e) = move_path(&partial_path, &cache_path) {
+        let _ = fs::remove_file(&partial_path);
+        return Err(e.context("unable to rename copied file"));
+    }
 
-    cache_path
+    let completed = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+
+    CacheEntryMetadata {
+        url: path.display().to_string(),
+        size,
+        sha256: sha256.to_lowercase(),
+        completed,
+    }
+    .write(&cache_path)?;
+
+    Ok(cache_path)
 }
 
-pub fn copy_local_distribution(path: &PathBuf, sha256: &str, cache_dir: &Path) -> PathBuf {
-    let expected_hash = hex::decode(sha256).expect("could not parse SHA256 hash");
-    let basename = path.file_name().unwrap().to_str().unwrap().to_string();
-    let cache_path = cache_dir.join(basename);
+/// Add a local distribution archive to a cache directory.
+///
+/// The archive is keyed by its SHA-256, which is returned with the path
+/// of the cached copy.
+pub fn cache_local_distribution(path: &Path, cache_dir: &Path) -> Result<(String, PathBuf)> {
+    if !path.is_file() {
+        return Err(anyhow!("{} is not a file", path.display()));
+    }
 
-    if cache_path.exists() {
-        let file_hash = sha256_path(&cache_path);
+    let path = path.to_path_buf();
+    let sha256 = hex::encode(sha256_path(&path));
+    let cache_path = copy_local_distribution(&path, &sha256, cache_dir)?;
 
-        if file_hash == expected_hash {
-            println!(
-                "existing {} passes SHA-256 integrity check",
-                cache_path.display()
-            );
-            return cache_path;
-        }
+    Ok((sha256, cache_path))
+}
+
+/// Find a complete archive with the given SHA-256 in a cache directory.
+///
+/// An archive named `basename` is preferred. Archives are keyed by their
+/// digest, so one added to the cache under another name is found too.
+fn find_cached_archive(cache_dir: &Path, sha256: &str, basename: &str) -> Option<PathBuf> {
+    let expected_hash = hex::decode(sha256).ok()?;
+    let preferred = cache_entry_path(cache_dir, sha256, basename);
+    if CacheEntryMetadata::path(&preferred).exists()
+        && cached_archive_valid(&preferred, &expected_hash)
+    {
+        return Some(preferred);
     }
 
-    let source_hash = sha256_path(&path);
+    let mut candidates = fs::read_dir(preferred.parent()?)
+        .ok()?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|p| p.is_file() && CacheEntryMetadata::path(p).exists())
+        .collect::<Vec<_>>();
+    candidates.sort();
+
+    candidates
+        .into_iter()
+        .find(|p| cached_archive_valid(p, &expected_hash))
+}
 
-    if source_hash != expected_hash {
-        panic!("sha256 of Python distribution does not validate");
+/// Obtain a distribution without accessing the network.
+///
+/// The archive in `cache_dir` is used if present. Otherwise it is copied
+/// from `offline_dir`, the distribution cache seeded by
+/// `pyoxidizer cache add`. The error for a missing archive names it and
+/// where it would be downloaded from.
+fn offline_distribution(
+    url: &str,
+    sha256: &str,
+    cache_dir: &Path,
+    offline_dir: Option<&Path>,
+) -> Result<PathBuf> {
+    let u = Url::parse(url).context("failed to parse URL")?;
+    let basename = u
+        .path_segments()
+        .and_then(|segments| segments.last())
+        .unwrap_or("")
+        .to_string();
+
+    if let Some(path) = find_cached_archive(cache_dir, sha256, &basename) {
+        build_stats::record_cache(Cache::Distribution, 1, 0);
+        return Ok(path);
     }
 
-    println!("copying {}", path.display());
-    std::fs::copy(path, &cache_path).unwrap();
+    if let Some(path) = offline_dir.and_then(|dir| find_cached_archive(dir, sha256, &basename)) {
+        return copy_local_distribution(&path, sha256, cache_dir);
+    }
+
+    let location = match offline_dir {
+        Some(dir) => dir.display().to_string(),
+        None => format!("no cache directory; set {}", DISTRIBUTION_CACHE_ENV),
+    };
 
-    cache_path
+    Err(anyhow!(
+        "Python distribution {} is not cached and --offline forbids downloading it\n\n  \
+         url: {}\n  sha256: {}\n  cache: {}\n\n\
+         Download it on a machine with network access and add it with \
+         `pyoxidizer cache add <path>`",
+        basename,
+        url,
+        sha256.to_lowercase(),
+        location
+    ))
 }
 
 /// Obtain a local Path for a Python distribution tar archive.
 ///
 /// Takes a parsed config and a cache directory as input. Usually the cache
-/// directory is the OUT_DIR for the invocation of a Cargo build script.
+/// directory is the distribution cache shared by all projects.
 /// A Python distribution will be fetched according to the configuration and a
 /// copy of the archive placed in ``cache_dir``. If the archive already exists
 /// in ``cache_dir``, it will be verified and returned.
 ///
 /// Local filesystem paths are preferred over remote URLs if both are defined.
+///
+/// If `offline`, remote distributions must be in ``cache_dir`` or the
+/// distribution cache.
 pub fn resolve_python_distribution_archive(
     dist: &PythonDistributionLocation,
     cache_dir: &Path,
-) -> PathBuf {
+    offline: bool,
+) -> Result<PathBuf> {
     if !cache_dir.exists() {
-        create_dir_all(cache_dir).unwrap();
+        create_dir_all(cache_dir)?;
     }
 
     match dist {
@@ -1183,38 +2463,121 @@ pub fn resolve_python_distribution_archive(
             copy_local_distribution(&p, sha256, cache_dir)
         }
         PythonDistributionLocation::Url { url, sha256 } => {
-            download_distribution(url, sha256, cache_dir)
+            if offline {
+                let offline_dir = distribution_cache_dir();
+                offline_distribution(
+                    url,
+                    sha256,
+                    cache_dir,
+                    offline_dir.as_ref().map(PathBuf::as_path),
+                )
+            } else {
+                download_distribution(url, sha256, cache_dir)
+            }
+        }
+    }
+}
+
+/// Directories of a distribution in the distribution cache.
+///
+/// Returns the directory archives are cached in and the directory the
+/// distribution is extracted to, which is shared by all projects. `None`
+/// if there is no distribution cache.
+pub fn shared_distribution_dirs(
+    location: &PythonDistributionLocation,
+) -> Option<(PathBuf, PathBuf)> {
+    let cache_dir = distribution_cache_dir()?;
+    let extract_dir = cache_dir
+        .join(location.sha256().to_lowercase())
+        .join(EXTRACTED_DIR);
+
+    Some((cache_dir, extract_dir))
+}
+
+/// Record that a distribution extracted in the distribution cache was used.
+///
+/// Errors are ignored: the record only decides what
+/// `pyoxidizer cache purge --older-than` deletes.
+pub fn mark_distribution_used(extract_dir: &Path) {
+    if let Some(entry_dir) = extract_dir.parent() {
+        let path = entry_dir.join(LAST_USED_FILENAME);
+        if File::create(&path).is_ok() {
+            let _ = filetime::set_file_mtime(&path, filetime::FileTime::now());
         }
     }
 }
 
 /// Resolve a parsed distribution from a location and local filesystem path.
 ///
-/// The distribution will be copied and extracted into the destination
-/// directory. It will be parsed from the extracted location.
+/// The distribution is fetched into and extracted in the distribution cache,
+/// so projects share it. Without a distribution cache it is copied and
+/// extracted into the destination directory. It will be parsed from the
+/// extracted location.
 ///
 /// The created files outlive the returned object.
 pub fn resolve_parsed_distribution(
     logger: &slog::Logger,
     location: &PythonDistributionLocation,
     dest_dir: &Path,
+    offline: bool,
 ) -> Result<ParsedPythonDistribution> {
     warn!(logger, "resolving Python distribution {:?}", location);
-    let path = resolve_python_distribution_archive(location, dest_dir);
+
+    if let Some((cache_dir, extract_dir)) = shared_distribution_dirs(location) {
+        let dist = parse_cached_distribution(logger, location, &cache_dir, &extract_dir, offline)?;
+        mark_distribution_used(&extract_dir);
+
+        return Ok(dist);
+    }
+
+    let distribution_path = dest_dir.join(format!("python.{}", location.sha256()));
+
+    parse_cached_distribution(logger, location, dest_dir, &distribution_path, offline)
+}
+
+/// Fetch a Python distribution into a cache directory and parse it.
+///
+/// If the cached archive can't be read, it is quarantined and fetched
+/// again once.
+pub fn parse_cached_distribution(
+    logger: &slog::Logger,
+    location: &PythonDistributionLocation,
+    cache_dir: &Path,
+    extract_dir: &Path,
+    offline: bool,
+) -> Result<ParsedPythonDistribution> {
+    let path = resolve_python_distribution_archive(location, cache_dir, offline)?;
     warn!(
         logger,
         "Python distribution available at {}",
         path.display()
     );
 
-    let distribution_hash = match location {
+    let archive_sha256 = match location {
         PythonDistributionLocation::Local { sha256, .. } => sha256,
         PythonDistributionLocation::Url { sha256, .. } => sha256,
     };
 
-    let distribution_path = dest_dir.join(format!("python.{}", distribution_hash));
+    match ParsedPythonDistribution::from_path_cached(logger, &path, extract_dir, archive_sha256) {
+        Err(e) if e.downcast_ref::<ArchiveError>().is_some() => {
+            let mut archive_error = e.downcast::<ArchiveError>().unwrap();
+            archive_error.cached = true;
+            warn!(logger, "{}", archive_error);
+
+            let quarantined = quarantine_archive(&path).map_err(|_| archive_error.clone())?;
+            warn!(
+                logger,
+                "moved corrupt archive to {}; fetching it again",
+                quarantined.display()
+            );
+
+            let path = resolve_python_distribution_archive(location, cache_dir, offline)
+                .map_err(|e| anyhow!("{}\n\nre-fetching failed: {}", archive_error, e))?;
 
-    ParsedPythonDistribution::from_path(logger, &path, &distribution_path)
+            ParsedPythonDistribution::from_path_cached(logger, &path, extract_dir, archive_sha256)
+        }
+        res => res,
+    }
 }
 
 /// Resolve the default Python distribution for a build target.
@@ -1222,18 +2585,18 @@ pub fn default_distribution(
     logger: &slog::Logger,
     target: &str,
     dest_dir: &Path,
+    offline: bool,
 ) -> Result<ParsedPythonDistribution> {
-    let dist = CPYTHON_BY_TRIPLE.get(target).ok_or(anyhow!(
-        "could not find default Python distribution for {}",
-        target
-    ))?;
+    let dist = CPYTHON_BY_TRIPLE
+        .get(target)
+        .ok_or_else(|| anyhow!(no_default_distribution_message(target)))?;
 
     let location = PythonDistributionLocation::Url {
         url: dist.url.clone(),
         sha256: dist.sha256.clone(),
     };
 
-    resolve_parsed_distribution(logger, &location, dest_dir)
+    resolve_parsed_distribution(logger, &location, dest_dir, offline)
 }
 
 #[cfg(test)]
@@ -1248,7 +2611,763 @@ mod tests {
 
         let temp_dir = tempdir::TempDir::new("pyoxidizer-test")?;
 
-        default_distribution(&logger, target, temp_dir.path())?;
+        default_distribution(&logger, target, temp_dir.path(), false)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_distribution_sizes() -> Result<()> {
+        let dist = get_default_distribution()?;
+        let sizes = &dist.sizes;
+
+        assert!(!sizes.core.is_empty());
+        assert!(sizes.stdlib_packages["json"] > 0);
+        assert!(sizes.extension_modules.contains_key("_ssl"));
+        assert!(sizes.libraries.values().all(|size| *size > 0));
+
+        let category_total: u64 = sizes
+            .categories()
+            .iter()
+            .map(|(_, values)| values.values().sum::<u64>())
+            .sum();
+        assert_eq!(sizes.total(), category_total);
+        assert!(sizes.stdlib_packages.len() < dist.py_modules.len());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_readline_prefers_libedit() -> Result<()> {
+        let logger = get_logger()?;
+        let dist = get_default_distribution()?;
+
+        // Not every distribution has a libedit variant.
+        let has_libedit = match dist.extension_modules.get("readline") {
+            Some(variants) => variants.iter().any(|em| em.variant == "libedit"),
+            None => false,
+        };
+        if !has_libedit {
+            return Ok(());
+        }
+
+        let readline = |variants| -> Vec<ExtensionModule> {
+            dist.filter_extension_modules(&logger, &ExtensionModuleFilter::All, variants)
+                .into_iter()
+                .filter(|em| em.module == "readline")
+                .collect()
+        };
+
+        assert_eq!(readline(None)[0].variant, "libedit");
+
+        // An explicit preference wins.
+        let mut variants = HashMap::new();
+        variants.insert("readline".to_string(), "default".to_string());
+        assert_eq!(readline(Some(variants))[0].variant, "default");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_distribution_info() -> Result<()> {
+        let dist = get_default_distribution()?;
+        let info = DistributionInfo::from_distribution(&dist);
+
+        assert_eq!(info.format_version, DISTRIBUTION_INFO_FORMAT_VERSION);
+        assert_eq!(info.version, dist.version);
+        assert!(info.py_modules.iter().any(|name| name == "json"));
+        assert_eq!(info.extension_modules.len(), dist.extension_modules.len());
+        assert!(info.extension_modules.contains_key("_ssl"));
+        assert!(info
+            .extension_modules
+            .values()
+            .flatten()
+            .filter_map(|em| em.shared_library.as_ref())
+            .all(|path| !Path::new(path).is_absolute()));
+
+        let json = serde_json::to_value(&info)?;
+        for key in &[
+            "format_version",
+            "flavor",
+            "version",
+            "os",
+            "arch",
+            "missing_embedding_requirements",
+            "capabilities",
+            "extension_modules",
+            "py_modules",
+            "resources",
+        ] {
+            assert!(json.get(key).is_some(), "{} missing", key);
+        }
+        assert_eq!(serde_json::from_value::<DistributionInfo>(json)?, info);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_cached_analysis() -> Result<()> {
+        let dist = get_default_distribution()?;
+        let temp_dir = tempdir::TempDir::new("pyoxidizer-test")?;
+        let cache_path = temp_dir.path().join(ANALYSIS_CACHE_FILENAME);
+
+        let analyzed = analyze_python_distribution_data(&dist.base_dir)?;
+        write_cached_analysis(&cache_path, "deadbeef", &analyzed)?;
+        let cached = read_cached_analysis(&cache_path, &dist.base_dir, "deadbeef")?;
+
+        assert_eq!(cached.py_modules, analyzed.py_modules);
+        assert_eq!(cached.resources, analyzed.resources);
+        assert_eq!(cached.license_infos.len(), analyzed.license_infos.len());
+        assert_eq!(
+            cached.extension_modules.keys().collect::<Vec<_>>(),
+            analyzed.extension_modules.keys().collect::<Vec<_>>()
+        );
+        assert_eq!(cached.sizes.total(), analyzed.sizes.total());
+
+        Ok(())
+    }
+
+    /// Compares analyzing an extracted distribution with reading the cached
+    /// analysis.
+    ///
+    /// Run with `cargo test --release -- --ignored --nocapture bench_`.
+    #[test]
+    #[ignore]
+    fn bench_cached_analysis() -> Result<()> {
+        use std::time::Instant;
+
+        let dist = get_default_distribution()?;
+        let temp_dir = tempdir::TempDir::new("pyoxidizer-test")?;
+        let cache_path = temp_dir.path().join(ANALYSIS_CACHE_FILENAME);
+
+        let start = Instant::now();
+        let analyzed = analyze_python_distribution_data(&dist.base_dir)?;
+        let cold = start.elapsed();
+
+        write_cached_analysis(&cache_path, "deadbeef", &analyzed)?;
+
+        let start = Instant::now();
+        read_cached_analysis(&cache_path, &dist.base_dir, "deadbeef")?;
+        let warm = start.elapsed();
+
+        eprintln!("analysis: {:?}; cached analysis: {:?}", cold, warm);
+
+        assert!(
+            warm * 4 < cold,
+            "cached analysis took {:?}; analysis took {:?}",
+            warm,
+            cold
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_cached_analysis_invalid() -> Result<()> {
+        let dist = get_default_distribution()?;
+        let temp_dir = tempdir::TempDir::new("pyoxidizer-test")?;
+        let cache_path = temp_dir.path().join(ANALYSIS_CACHE_FILENAME);
+
+        write_cached_analysis(&cache_path, "deadbeef", &dist)?;
+        assert!(read_cached_analysis(&cache_path, &dist.base_dir, "DEADBEEF").is_ok());
+
+        // Another archive or extraction directory.
+        assert!(read_cached_analysis(&cache_path, &dist.base_dir, "cafe").is_err());
+        assert!(read_cached_analysis(&cache_path, temp_dir.path(), "deadbeef").is_err());
+
+        // Another analyzer version.
+        let mut cached: CachedAnalysis<ParsedPythonDistribution> =
+            serde_cbor::from_slice(&fs::read(&cache_path)?)?;
+        cached.format_version = ANALYSIS_FORMAT_VERSION + 1;
+        fs::write(&cache_path, serde_cbor::to_vec(&cached)?)?;
+        let err = read_cached_analysis(&cache_path, &dist.base_dir, "deadbeef").unwrap_err();
+        assert!(err.to_string().contains("format version"));
+
+        // Truncated file.
+        write_cached_analysis(&cache_path, "deadbeef", &dist)?;
+        let data = fs::read(&cache_path)?;
+        fs::write(&cache_path, &data[0..data.len() / 2])?;
+        assert!(read_cached_analysis(&cache_path, &dist.base_dir, "deadbeef").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_corrupt_cached_analysis_reanalyzed() -> Result<()> {
+        let logger = get_logger()?;
+        let dist = get_default_distribution()?;
+
+        // Work on a copy of the archive so the shared extraction is untouched.
+        let temp_dir = tempdir::TempDir::new("pyoxidizer-test")?;
+        let entry = CPYTHON_BY_TRIPLE.get(env!("HOST")).unwrap();
+        let basename = entry.url.rsplit('/').next().unwrap();
+        let location = PythonDistributionLocation::Local {
+            local_path: dist
+                .base_dir
+                .parent()
+                .unwrap()
+                .join(basename)
+                .display()
+                .to_string(),
+            sha256: entry.sha256.clone(),
+        };
+        let extract_dir = temp_dir.path().join("python.extracted");
+        let cache_path = extract_dir.join(ANALYSIS_CACHE_FILENAME);
+
+        let first =
+            parse_cached_distribution(&logger, &location, temp_dir.path(), &extract_dir, false)?;
+        assert!(cache_path.exists());
+
+        fs::write(&cache_path, b"not cbor")?;
+
+        let second =
+            parse_cached_distribution(&logger, &location, temp_dir.path(), &extract_dir, false)?;
+        assert_eq!(second.py_modules, first.py_modules);
+
+        // The analysis was persisted again.
+        read_cached_analysis(&cache_path, &extract_dir, &entry.sha256)?;
+
+        Ok(())
+    }
+
+    fn python_exe_fixture(files: &[&str], python_json: Option<&str>) -> Result<tempdir::TempDir> {
+        let temp_dir = tempdir::TempDir::new("pyoxidizer-test")?;
+        let python_dir = temp_dir.path().join("python");
+
+        for file in files {
+            let path = python_dir.join(file);
+            create_dir_all(path.parent().unwrap())?;
+            fs::write(&path, b"")?;
+        }
+
+        if let Some(data) = python_json {
+            create_dir_all(&python_dir)?;
+            fs::write(python_dir.join("PYTHON.json"), data)?;
+        }
+
+        Ok(temp_dir)
+    }
+
+    const FIXTURE_PYTHON_JSON: &str = r#"{
+        "arch": "fakearch",
+        "os": "fakeos",
+        "python_exe": "install/bin/python3",
+        "python_flavor": "standalone",
+        "python_include": "install/include/python3.7m",
+        "python_stdlib": "install/lib/python3.7",
+        "python_version": "3.7.5",
+        "version": "4",
+        "licenses": ["Python-2.0"],
+        "license_path": "LICENSE.rst",
+        "build_info": {
+            "core": {"objs": ["build/core/main.o"], "links": []},
+            "extensions": {
+                "zlib": [{
+                    "in_core": false,
+                    "init_fn": "PyInit_zlib",
+                    "licenses": ["Zlib"],
+                    "license_paths": ["licenses/LICENSE.zlib.txt", "build/zlib/README"],
+                    "links": [{"name": "z", "path_static": "build/lib/libz.a"}],
+                    "objs": ["build/extensions/zlib.o"],
+                    "required": false,
+                    "variant": "default"
+                }]
+            }
+        }
+    }"#;
+
+    #[test]
+    fn test_analyze_metadata() -> Result<()> {
+        let temp_dir = tempdir::TempDir::new("pyoxidizer-test")?;
+        let archive_path = temp_dir.path().join("cpython.tar.zst");
+        let files: &[(&str, &[u8])] = &[
+            ("python/PYTHON.json", FIXTURE_PYTHON_JSON.as_bytes()),
+            ("python/LICENSE.rst", b"PSF"),
+            ("python/licenses/LICENSE.zlib.txt", b"zlib license"),
+            ("python/build/zlib/README", b"zlib readme"),
+            ("python/build/core/main.o", b"main"),
+            ("python/build/extensions/zlib.o", b"zlib"),
+            ("python/build/lib/libz.a", b"libz"),
+            ("python/install/bin/python3", b""),
+            ("python/install/include/python3.7m/Python.h", b"header"),
+            ("python/install/lib/python3.7/os.py", b"import abc\n"),
+            ("python/install/lib/python3.7/json/__init__.py", b""),
+            ("python/install/lib/python3.7/json/data.txt", b"data"),
+        ];
+        fs::write(
+            &archive_path,
+            zstd::stream::encode_all(&tar_fixture(files, &[])?[..], 3)?,
+        )?;
+
+        let metadata =
+            analyze_python_distribution_metadata(&archive_path, &temp_dir.path().join("unused"))?;
+        // Nothing was extracted.
+        assert!(!temp_dir.path().join("unused").exists());
+
+        let extracted =
+            analyze_python_distribution_archive(&archive_path, &temp_dir.path().join("extracted"))?;
+
+        // Reports are the same either way.
+        assert_eq!(
+            serde_json::to_value(DistributionInfo::from_distribution(&metadata))?,
+            serde_json::to_value(DistributionInfo::from_distribution(&extracted))?
+        );
+        assert_eq!(metadata.licenses, extracted.licenses);
+        let license_texts = |dist: &ParsedPythonDistribution| {
+            dist.license_infos
+                .iter()
+                .map(|(name, infos)| {
+                    (
+                        name.clone(),
+                        infos
+                            .iter()
+                            .map(|info| (info.license_filename.clone(), info.license_text.clone()))
+                            .collect::<Vec<_>>(),
+                    )
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(license_texts(&metadata), license_texts(&extracted));
+        assert_eq!(
+            license_texts(&metadata)[1].1[1],
+            ("README".to_string(), "zlib readme".to_string())
+        );
+        assert_eq!(
+            metadata.py_modules.keys().collect::<Vec<_>>(),
+            vec!["json", "os"]
+        );
+        assert_eq!(
+            metadata.python_exe,
+            archive_path.join("python/install/bin/python3")
+        );
+        assert!(metadata.missing_embedding_requirements().is_empty());
+
+        Ok(())
+    }
+
+    fn probe_ok(_: &Path) -> Result<String> {
+        Ok("3.7.5".to_string())
+    }
+
+    #[test]
+    fn test_find_python_exe_layouts() -> Result<()> {
+        for layout in PYTHON_EXE_CANDIDATES {
+            let temp_dir = python_exe_fixture(&[layout], None)?;
+
+            assert_eq!(
+                find_python_exe(temp_dir.path(), probe_ok)?,
+                temp_dir.path().join("python").join(layout)
+            );
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_find_python_exe_python_json() -> Result<()> {
+        // PYTHON.json takes priority over known layouts.
+        let temp_dir = python_exe_fixture(
+            &["install/bin/python3", "install/bin/python3.7m"],
+            Some(r#"{"python_exe": "install/bin/python3.7m"}"#),
+        )?;
+        assert_eq!(
+            find_python_exe(temp_dir.path(), probe_ok)?,
+            temp_dir.path().join("python/install/bin/python3.7m")
+        );
+
+        // A stale PYTHON.json falls back to known layouts.
+        let temp_dir = python_exe_fixture(
+            &["install/python.exe"],
+            Some(r#"{"python_exe": "install/bin/python3"}"#),
+        )?;
+        assert_eq!(
+            find_python_exe(temp_dir.path(), probe_ok)?,
+            temp_dir.path().join("python/install/python.exe")
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_find_python_exe_rejected() -> Result<()> {
+        let temp_dir = python_exe_fixture(
+            &["install/bin/python3", "install/pyvenv.cfg", "python.exe"],
+            None,
+        )?;
+        let broken = temp_dir.path().join("python/python.exe");
+
+        let err = find_python_exe(temp_dir.path(), |p| {
+            if p == broken {
+                Err(anyhow!("exec format error"))
+            } else {
+                probe_ok(p)
+            }
+        })
+        .unwrap_err()
+        .to_string();
+
+        assert!(err.starts_with("unable to find a working Python executable in"));
+        for candidate in PYTHON_EXE_CANDIDATES {
+            assert!(err.contains(
+                &temp_dir
+                    .path()
+                    .join("python")
+                    .join(candidate)
+                    .display()
+                    .to_string()
+            ));
+        }
+        assert!(err.contains("install/bin/python3: is a virtualenv interpreter"));
+        assert!(err.contains("python.exe: unable to run: exec format error"));
+        assert!(err.contains("install/bin/python: does not exist"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_python_exe_path_foreign_arch() -> Result<()> {
+        let arch = if std::env::consts::ARCH == "aarch64" {
+            "x86_64"
+        } else {
+            "aarch64"
+        };
+
+        // The empty file can't run, but isn't expected to.
+        let temp_dir = python_exe_fixture(
+            &["install/bin/python3"],
+            Some(&format!(
+                r#"{{"os": "{}", "arch": "{}", "python_version": "3.7.5"}}"#,
+                std::env::consts::OS,
+                arch
+            )),
+        )?;
+        let python_exe = python_exe_path(temp_dir.path())?;
+        assert_eq!(
+            python_exe,
+            temp_dir.path().join("python/install/bin/python3")
+        );
+        assert!(!python_exe_runs(&python_exe));
+        assert_eq!(
+            distribution_python_version(temp_dir.path()),
+            Some("3.7.5".to_string())
+        );
+
+        Ok(())
+    }
+
+    const FIXTURE_URL: &str = "https://example.com/dist/cpython.tar.zst";
+
+    fn fixture_fetch<'a>(
+        data: &'a [u8],
+        content_length: u64,
+        calls: &'a std::cell::Cell<usize>,
+    ) -> impl Fn(&Url, u64) -> Result<FetchResponse> + 'a {
+        move |_: &Url, _| {
+            calls.set(calls.get() + 1);
+            Ok(FetchResponse {
+                reader: Box::new(std::io::Cursor::new(data.to_vec())),
+                content_length: Some(content_length),
+                resumed: false,
+            })
+        }
+    }
+
+    fn no_backoff(attempts: u32) -> RetryPolicy {
+        RetryPolicy {
+            attempts,
+            initial_backoff: Duration::from_secs(0),
+            max_backoff: Duration::from_secs(0),
+        }
+    }
+
+    fn fixture_sha256(data: &[u8]) -> String {
+        let mut hasher = Sha256::new();
+        hasher.input(data);
+        hex::encode(hasher.result())
+    }
+
+    fn dir_names(path: &Path) -> Result<Vec<String>> {
+        let mut names = fs::read_dir(path)?
+            .map(|entry| Ok(entry?.file_name().to_string_lossy().to_string()))
+            .collect::<Result<Vec<_>>>()?;
+        names.sort();
+
+        Ok(names)
+    }
+
+    #[test]
+    fn test_download_distribution_heals_truncated_cache() -> Result<()> {
+        let temp_dir = tempdir::TempDir::new("pyoxidizer-test")?;
+        let cache_dir = temp_dir.path();
+        let data = vec![42u8; 100_000];
+        let sha256 = fixture_sha256(&data);
+        let calls = std::cell::Cell::new(0);
+        let fetch = fixture_fetch(&data, data.len() as u64, &calls);
+        let retry = no_backoff(1);
+
+        let path = download_distribution_with(FIXTURE_URL, &sha256, cache_dir, &retry, &fetch)?;
+        assert_eq!(calls.get(), 1);
+        assert_eq!(fs::read(&path)?, data);
+        assert_eq!(path, cache_dir.join(&sha256).join("cpython.tar.zst"));
+        assert_eq!(dir_names(cache_dir)?, vec![sha256.clone()]);
+        assert_eq!(
+            dir_names(&cache_dir.join(&sha256))?,
+            vec![
+                "cpython.tar.zst",
+                "cpython.tar.zst.json",
+                "cpython.tar.zst.lock"
+            ]
+        );
+
+        let metadata = CacheEntryMetadata::read(&path).unwrap();
+        assert_eq!(metadata.url, FIXTURE_URL);
+        assert_eq!(metadata.size, data.len() as u64);
+        assert_eq!(metadata.sha256, sha256);
+        assert!(metadata.completed > 0);
+
+        download_distribution_with(FIXTURE_URL, &sha256, cache_dir, &retry, &fetch)?;
+        assert_eq!(calls.get(), 1);
+
+        // Verified entries aren't hashed again.
+        fs::write(&path, vec![1u8; data.len()])?;
+        download_distribution_with(
+            FIXTURE_URL,
+            &sha256.to_uppercase(),
+            cache_dir,
+            &retry,
+            &fetch,
+        )?;
+        assert_eq!(calls.get(), 1);
+
+        // A download killed by an older version left a truncated file.
+        fs::write(&path, &data[..1000])?;
+        download_distribution_with(FIXTURE_URL, &sha256, cache_dir, &retry, &fetch)?;
+        assert_eq!(calls.get(), 2);
+        assert_eq!(fs::read(&path)?, data);
+
+        // Without metadata, the hash is checked.
+        fs::remove_file(CacheEntryMetadata::path(&path))?;
+        fs::write(&path, vec![0u8; data.len()])?;
+        download_distribution_with(FIXTURE_URL, &sha256, cache_dir, &retry, &fetch)?;
+        assert_eq!(calls.get(), 3);
+        assert_eq!(fs::read(&path)?, data);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_download_distribution_truncated_download() -> Result<()> {
+        let temp_dir = tempdir::TempDir::new("pyoxidizer-test")?;
+        let cache_dir = temp_dir.path();
+        let data = vec![42u8; 100_000];
+        let sha256 = fixture_sha256(&data);
+        let calls = std::cell::Cell::new(0);
+        let fetch = fixture_fetch(&data[..1000], data.len() as u64, &calls);
+        let retry = no_backoff(3);
+
+        let err = download_distribution_with(FIXTURE_URL, &sha256, cache_dir, &retry, &fetch)
+            .unwrap_err()
+            .to_string();
+        assert_eq!(calls.get(), 3);
+        assert!(err.contains("received 1000 of 100000 bytes"));
+        assert!(err.ends_with("giving up after 3 attempts; run the build again to resume"));
+        // The partial download is kept for the next build to resume.
+        let entry_dir = cache_dir.join(&sha256);
+        assert_eq!(
+            dir_names(&entry_dir)?,
+            vec!["cpython.tar.zst.download", "cpython.tar.zst.lock"]
+        );
+
+        // A server ignoring the range request sends everything again.
+        let fetch = fixture_fetch(&data, data.len() as u64, &calls);
+        let path = download_distribution_with(FIXTURE_URL, &sha256, cache_dir, &retry, &fetch)?;
+        assert_eq!(fs::read(&path)?, data);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_download_distribution_resumes() -> Result<()> {
+        let temp_dir = tempdir::TempDir::new("pyoxidizer-test")?;
+        let cache_dir = temp_dir.path();
+        let data = (0..100_000).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        let sha256 = fixture_sha256(&data);
+        let offsets = std::cell::RefCell::new(Vec::new());
+
+        // The first response drops after 1000 bytes.
+        let fetch = |_: &Url, offset: u64| -> Result<FetchResponse> {
+            offsets.borrow_mut().push(offset);
+            let start = offset as usize;
+            let end = if start == 0 { 1000 } else { data.len() };
+
+            Ok(FetchResponse {
+                reader: Box::new(std::io::Cursor::new(data[start..end].to_vec())),
+                content_length: Some((data.len() - start) as u64),
+                resumed: start > 0,
+            })
+        };
+
+        let path =
+            download_distribution_with(FIXTURE_URL, &sha256, cache_dir, &no_backoff(2), &fetch)?;
+        assert_eq!(*offsets.borrow(), vec![0, 1000]);
+        assert_eq!(fs::read(&path)?, data);
+        assert_eq!(CacheEntryMetadata::read(&path).unwrap().size, 100_000);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_download_distribution_permanent_error() -> Result<()> {
+        let temp_dir = tempdir::TempDir::new("pyoxidizer-test")?;
+        let cache_dir = temp_dir.path();
+        let sha256 = fixture_sha256(b"data");
+        let calls = std::cell::Cell::new(0);
+
+        let fetch = |u: &Url, _: u64| -> Result<FetchResponse> {
+            calls.set(calls.get() + 1);
+            Err(anyhow!("HTTP 404 Not Found fetching {}", u))
+        };
+
+        let err =
+            download_distribution_with(FIXTURE_URL, &sha256, cache_dir, &no_backoff(5), &fetch)
+                .unwrap_err()
+                .to_string();
+        assert_eq!(calls.get(), 1);
+        assert_eq!(err, format!("HTTP 404 Not Found fetching {}", FIXTURE_URL));
+        assert_eq!(
+            dir_names(&cache_dir.join(&sha256))?,
+            vec!["cpython.tar.zst.lock"]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_download_distribution_redownload_fails() -> Result<()> {
+        let temp_dir = tempdir::TempDir::new("pyoxidizer-test")?;
+        let cache_dir = temp_dir.path();
+        let data = vec![42u8; 100_000];
+        let sha256 = fixture_sha256(&data);
+        let entry_dir = cache_dir.join(&sha256);
+        create_dir_all(&entry_dir)?;
+        fs::write(entry_dir.join("cpython.tar.zst"), &data[..1000])?;
+
+        let bad = vec![0u8; data.len()];
+        let calls = std::cell::Cell::new(0);
+        let fetch = fixture_fetch(&bad, bad.len() as u64, &calls);
+        let retry = no_backoff(1);
+
+        let err = download_distribution_with(FIXTURE_URL, &sha256, cache_dir, &retry, &fetch)
+            .unwrap_err()
+            .to_string();
+        assert_eq!(calls.get(), 1);
+        let rejected = entry_dir.join("cpython.tar.zst.rejected");
+        assert_eq!(
+            err,
+            format!(
+                "sha256 of Python distribution does not validate: expected {}, got {}; the file is at {}",
+                sha256,
+                fixture_sha256(&bad),
+                rejected.display()
+            )
+        );
+        // The download is kept for inspection, but not where it's cached.
+        assert_eq!(
+            dir_names(&entry_dir)?,
+            vec!["cpython.tar.zst.lock", "cpython.tar.zst.rejected"]
+        );
+        assert_eq!(fs::read(&rejected)?, bad);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_copy_local_distribution() -> Result<()> {
+        let temp_dir = tempdir::TempDir::new("pyoxidizer-test")?;
+        let cache_dir = temp_dir.path().join("cache");
+        let source = temp_dir.path().join("cpython.tar.zst");
+        let data = vec![42u8; 1000];
+        let sha256 = fixture_sha256(&data);
+        fs::write(&source, &data)?;
+
+        let path = copy_local_distribution(&source, &sha256, &cache_dir)?;
+        assert_eq!(path, cache_dir.join(&sha256).join("cpython.tar.zst"));
+        assert_eq!(fs::read(&path)?, data);
+        assert_eq!(CacheEntryMetadata::read(&path).unwrap().size, 1000);
+
+        // The verified copy is used without reading the source.
+        fs::remove_file(&source)?;
+        assert_eq!(copy_local_distribution(&source, &sha256, &cache_dir)?, path);
+
+        fs::write(&source, b"tampered")?;
+        let wanted = fixture_sha256(b"other");
+        let err = copy_local_distribution(&source, &wanted, &cache_dir)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains(&format!(
+            "expected {}, got {}",
+            wanted,
+            fixture_sha256(b"tampered")
+        )));
+        assert!(err.ends_with(&format!("the file is at {}", source.display())));
+        assert!(dir_names(&cache_dir.join(&wanted))?.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_offline_distribution() -> Result<()> {
+        let temp_dir = tempdir::TempDir::new("pyoxidizer-test")?;
+        let cache_dir = temp_dir.path().join("cache");
+        let offline_dir = temp_dir.path().join("offline");
+        let source = temp_dir.path().join("renamed.tar.zst");
+        let data = vec![42u8; 1000];
+        let sha256 = fixture_sha256(&data);
+        let url = "https://example.com/cpython.tar.zst";
+        fs::write(&source, &data)?;
+
+        let err = offline_distribution(url, &sha256, &cache_dir, Some(&offline_dir))
+            .unwrap_err()
+            .to_string();
+        assert!(err.starts_with("Python distribution cpython.tar.zst is not cached"));
+        assert!(err.contains(&format!("url: {}", url)));
+        assert!(err.contains(&format!("sha256: {}", sha256)));
+        assert!(err.contains(&format!("cache: {}", offline_dir.display())));
+
+        // Archives are found by digest whatever they were named when added.
+        let (added_sha256, _) = cache_local_distribution(&source, &offline_dir)?;
+        assert_eq!(added_sha256, sha256);
+        let path = offline_distribution(url, &sha256, &cache_dir, Some(&offline_dir))?;
+        assert_eq!(path, cache_dir.join(&sha256).join("renamed.tar.zst"));
+        assert_eq!(fs::read(&path)?, data);
+
+        // The build's copy is used once it has one.
+        fs::remove_dir_all(&offline_dir)?;
+        assert_eq!(offline_distribution(url, &sha256, &cache_dir, None)?, path);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_remove_abandoned_partial_downloads() -> Result<()> {
+        let temp_dir = tempdir::TempDir::new("pyoxidizer-test")?;
+        let cache_dir = temp_dir.path();
+
+        let old = filetime::FileTime::from_system_time(
+            std::time::SystemTime::now() - Duration::from_secs(7200),
+        );
+        for name in &["a.tar.zst.1.partial", "b.tar.zst.2.partial", "c.tar.zst"] {
+            fs::write(cache_dir.join(name), b"data")?;
+        }
+        filetime::set_file_mtime(cache_dir.join("a.tar.zst.1.partial"), old)?;
+        filetime::set_file_mtime(cache_dir.join("c.tar.zst"), old)?;
+
+        remove_abandoned_partial_downloads(cache_dir, PARTIAL_DOWNLOAD_MAX_AGE);
+
+        assert_eq!(
+            dir_names(cache_dir)?,
+            vec!["b.tar.zst.2.partial", "c.tar.zst"]
+        );
 
         Ok(())
     }
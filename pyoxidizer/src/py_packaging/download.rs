@@ -0,0 +1,390 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/*!
+HTTP downloads of Python distributions.
+
+Requests honor the `HTTP_PROXY`, `HTTPS_PROXY`, `ALL_PROXY` and `NO_PROXY`
+environment variables (in either case). Failures which may go away on their
+own, such as dropped connections and server errors, are reported as
+`TransientError` so callers can retry them.
+*/
+
+use anyhow::{anyhow, Context, Result};
+use std::fmt;
+use std::io::{Read, Write};
+use std::time::{Duration, Instant};
+use url::Url;
+
+use crate::build_events::json_messages;
+use crate::color::stdout_is_terminal;
+
+/// A download failure worth retrying.
+#[derive(Clone, Debug)]
+pub struct TransientError(pub String);
+
+impl fmt::Display for TransientError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for TransientError {}
+
+/// Whether an error is a `TransientError`.
+pub fn is_transient(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<TransientError>().is_some()
+}
+
+fn transient(message: String) -> anyhow::Error {
+    anyhow::Error::new(TransientError(message))
+}
+
+/// How often and how patiently failed downloads are retried.
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    /// Total number of attempts, including the first.
+    pub attempts: u32,
+    /// Delay before the first retry. It doubles for each further retry.
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            attempts: 5,
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(30),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Delay before retrying after the given failed attempt, counting from 1.
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let factor = 1u32 << (attempt.max(1) - 1).min(16);
+
+        std::cmp::min(self.initial_backoff * factor, self.max_backoff)
+    }
+}
+
+/// Proxies configured by environment variables.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ProxyConfig {
+    pub http: Option<Url>,
+    pub https: Option<Url>,
+    /// Host names (and their subdomains) not to use a proxy for. `*`
+    /// disables proxies.
+    pub no_proxy: Vec<String>,
+}
+
+/// Parse a proxy URL, which may omit the `http://` scheme.
+fn parse_proxy_url(value: &str) -> Option<Url> {
+    if value.is_empty() {
+        return None;
+    }
+
+    if value.contains("://") {
+        Url::parse(value).ok()
+    } else {
+        Url::parse(&format!("http://{}", value)).ok()
+    }
+}
+
+impl ProxyConfig {
+    /// Resolve proxies from environment variables.
+    ///
+    /// Lowercase variables win over uppercase ones, like curl. Scheme specific
+    /// variables win over `ALL_PROXY`.
+    pub fn from_vars<I>(vars: I) -> Self
+    where
+        I: IntoIterator<Item = (String, String)>,
+    {
+        let mut values = std::collections::BTreeMap::new();
+
+        for (key, value) in vars {
+            let lower = key.to_lowercase();
+            match lower.as_ref() {
+                "http_proxy" | "https_proxy" | "all_proxy" | "no_proxy" => {}
+                _ => continue,
+            }
+
+            if key == lower || !values.contains_key(&lower) {
+                values.insert(lower, value);
+            }
+        }
+
+        let get = |key: &str| values.get(key).and_then(|v| parse_proxy_url(v));
+        let all = get("all_proxy");
+
+        ProxyConfig {
+            http: get("http_proxy").or_else(|| all.clone()),
+            https: get("https_proxy").or(all),
+            no_proxy: values
+                .get("no_proxy")
+                .map(|v| {
+                    v.split(',')
+                        .map(|host| host.trim().trim_start_matches('.').to_lowercase())
+                        .filter(|host| !host.is_empty())
+                        .collect()
+                })
+                .unwrap_or_else(Vec::new),
+        }
+    }
+
+    /// The proxy to fetch a URL through, if any.
+    pub fn proxy_for(&self, url: &Url) -> Option<Url> {
+        let host = url.host_str()?.to_lowercase();
+
+        let bypassed = self
+            .no_proxy
+            .iter()
+            .any(|entry| entry == "*" || host == *entry || host.ends_with(&format!(".{}", entry)));
+        if bypassed {
+            return None;
+        }
+
+        match url.scheme() {
+            "http" => self.http.clone(),
+            "https" => self.https.clone(),
+            _ => None,
+        }
+    }
+}
+
+pub fn get_http_client() -> reqwest::Result<reqwest::Client> {
+    let proxies = ProxyConfig::from_vars(std::env::vars());
+
+    let mut builder = reqwest::ClientBuilder::new();
+
+    // reqwest uses its own version of the url crate, so URLs are passed as strings.
+    if proxies.http.is_some() || proxies.https.is_some() {
+        builder = builder.proxy(reqwest::Proxy::custom(move |url: &reqwest::Url| {
+            let url = Url::parse(url.as_str()).ok()?;
+            proxies.proxy_for(&url).map(|proxy| proxy.to_string())
+        }));
+    }
+
+    builder.build()
+}
+
+/// A response to a request for a URL, possibly from an offset.
+pub struct FetchResponse {
+    pub reader: Box<dyn Read>,
+    /// Length of the response body, if known.
+    pub content_length: Option<u64>,
+    /// Whether the body starts at the requested offset instead of the start.
+    pub resumed: bool,
+}
+
+/// Fetch a URL over HTTP, from `offset` if the server supports range requests.
+pub fn http_fetch(url: &Url, offset: u64) -> Result<FetchResponse> {
+    let client = get_http_client().context("unable to get HTTP client")?;
+
+    let mut request = client.get(url.as_str());
+    if offset > 0 {
+        request = request.header(reqwest::header::RANGE, format!("bytes={}-", offset));
+    }
+
+    let response = request
+        .send()
+        .map_err(|e| transient(format!("unable to perform HTTP request: {}", e)))?;
+    let status = response.status();
+
+    // The partial download is already complete or the file changed.
+    if offset > 0 && status == reqwest::StatusCode::RANGE_NOT_SATISFIABLE {
+        return http_fetch(url, 0);
+    }
+
+    if status.is_server_error() || status == reqwest::StatusCode::TOO_MANY_REQUESTS {
+        return Err(transient(format!("HTTP {} fetching {}", status, url)));
+    }
+
+    if !status.is_success() {
+        return Err(anyhow!("HTTP {} fetching {}", status, url));
+    }
+
+    Ok(FetchResponse {
+        content_length: response.content_length(),
+        resumed: status == reqwest::StatusCode::PARTIAL_CONTENT,
+        reader: Box::new(response),
+    })
+}
+
+/// Wrap a read error during a download as transient.
+pub fn interrupted(url: &Url, e: std::io::Error) -> anyhow::Error {
+    transient(format!("download of {} interrupted: {}", url, e))
+}
+
+/// Wrap a download delivering fewer bytes than announced as transient.
+pub fn truncated(url: &Url, received: u64, expected: u64) -> anyhow::Error {
+    transient(format!(
+        "download of {} truncated: received {} of {} bytes",
+        url, received, expected
+    ))
+}
+
+const PROGRESS_WIDTH: usize = 30;
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Render a progress line for `done` of `total` bytes.
+fn render_progress(done: u64, total: Option<u64>) -> String {
+    let mb = |bytes: u64| bytes as f64 / 1_000_000.0;
+
+    match total {
+        Some(total) if total > 0 => {
+            let fraction = (done as f64 / total as f64).min(1.0);
+            let filled = (fraction * PROGRESS_WIDTH as f64) as usize;
+
+            format!(
+                "[{}{}] {:>3}% {:.1}/{:.1} MB",
+                "=".repeat(filled),
+                " ".repeat(PROGRESS_WIDTH - filled),
+                (fraction * 100.0) as u64,
+                mb(done),
+                mb(total)
+            )
+        }
+        _ => format!("{:.1} MB", mb(done)),
+    }
+}
+
+/// A progress bar for a download, rendered on stdout.
+///
+/// Nothing is rendered if stdout isn't a terminal or is reserved for JSON
+/// events.
+pub struct DownloadProgress {
+    enabled: bool,
+    total: Option<u64>,
+    last_render: Option<Instant>,
+}
+
+impl DownloadProgress {
+    pub fn new(total: Option<u64>) -> Self {
+        DownloadProgress {
+            enabled: stdout_is_terminal() && !json_messages(),
+            total,
+            last_render: None,
+        }
+    }
+
+    /// Record that `done` bytes were downloaded.
+    pub fn update(&mut self, done: u64) {
+        if !self.enabled {
+            return;
+        }
+
+        if let Some(last) = self.last_render {
+            if last.elapsed() < PROGRESS_INTERVAL {
+                return;
+            }
+        }
+
+        print!("\r{}", render_progress(done, self.total));
+        let _ = std::io::stdout().flush();
+        self.last_render = Some(Instant::now());
+    }
+
+    /// Render the final state and end the progress line.
+    pub fn finish(&mut self, done: u64) {
+        if self.enabled && self.last_render.is_some() {
+            println!("\r{}", render_progress(done, self.total));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars(values: &[(&str, &str)]) -> Vec<(String, String)> {
+        values
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    fn url(s: &str) -> Url {
+        Url::parse(s).unwrap()
+    }
+
+    #[test]
+    fn test_retry_backoff() {
+        let policy = RetryPolicy::default();
+
+        assert_eq!(policy.backoff(1), Duration::from_secs(1));
+        assert_eq!(policy.backoff(2), Duration::from_secs(2));
+        assert_eq!(policy.backoff(4), Duration::from_secs(8));
+        assert_eq!(policy.backoff(6), Duration::from_secs(30));
+        assert_eq!(policy.backoff(100), Duration::from_secs(30));
+    }
+
+    #[test]
+    fn test_proxy_config() {
+        let config = ProxyConfig::from_vars(vars(&[
+            ("HTTPS_PROXY", "http://upper:3128"),
+            ("https_proxy", "proxy.corp:3128"),
+            ("ALL_PROXY", "socks5://all:1080"),
+            ("NO_PROXY", "localhost, .internal.corp"),
+            ("PATH", "/usr/bin"),
+        ]));
+
+        assert_eq!(config.https, Some(url("http://proxy.corp:3128")));
+        assert_eq!(config.http, Some(url("socks5://all:1080")));
+        assert_eq!(config.no_proxy, vec!["localhost", "internal.corp"]);
+
+        assert_eq!(
+            config.proxy_for(&url("https://github.com/dist.tar.zst")),
+            Some(url("http://proxy.corp:3128"))
+        );
+        assert_eq!(
+            config.proxy_for(&url("http://example.com/")),
+            Some(url("socks5://all:1080"))
+        );
+        assert_eq!(
+            config.proxy_for(&url("https://mirror.internal.corp/dist")),
+            None
+        );
+        assert_eq!(config.proxy_for(&url("http://localhost:8000/")), None);
+        assert_eq!(config.proxy_for(&url("ftp://example.com/")), None);
+
+        let config = ProxyConfig::from_vars(vars(&[
+            ("HTTP_PROXY", "http://proxy:3128"),
+            ("no_proxy", "*"),
+        ]));
+        assert_eq!(config.proxy_for(&url("http://example.com/")), None);
+
+        assert_eq!(ProxyConfig::from_vars(vec![]), ProxyConfig::default());
+    }
+
+    #[test]
+    fn test_transient() {
+        let u = url("https://example.com/dist.tar.zst");
+
+        assert!(is_transient(&truncated(&u, 10, 20)));
+        assert!(is_transient(&interrupted(
+            &u,
+            std::io::Error::new(std::io::ErrorKind::ConnectionReset, "reset")
+        )));
+        assert!(!is_transient(&anyhow!("HTTP 404 Not Found fetching {}", u)));
+        assert_eq!(
+            truncated(&u, 10, 20).to_string(),
+            "download of https://example.com/dist.tar.zst truncated: received 10 of 20 bytes"
+        );
+    }
+
+    #[test]
+    fn test_render_progress() {
+        assert_eq!(
+            render_progress(25_000_000, Some(50_000_000)),
+            format!("[{}{}]  50% 25.0/50.0 MB", "=".repeat(15), " ".repeat(15))
+        );
+        assert_eq!(
+            render_progress(50_000_000, Some(50_000_000)),
+            format!("[{}] 100% 50.0/50.0 MB", "=".repeat(30))
+        );
+        assert_eq!(render_progress(1_500_000, None), "1.5 MB");
+    }
+}
@@ -0,0 +1,560 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/*!
+Analysis of Python distributions laid out like the Windows embeddable zip.
+
+These distributions hold `python.exe`, the Python DLL, compiled extension
+modules (`.pyd` files) and a `pythonXY.zip` holding the standard library in
+the root of a zip archive. They don't ship headers, an import library or
+object files, so they can be analyzed but not embedded.
+*/
+
+use anyhow::{anyhow, Context, Result};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use super::distribution::{
+    file_size, top_level_package, with_extraction_lock, DistributionSizes, ExtensionModule,
+    LicenseInfo, ParsedPythonDistribution,
+};
+use super::fsscan::walk_tree_files;
+use super::sqlite::library_compile_options;
+
+use crate::cancel;
+use crate::fsutils::StagedDir;
+
+/// Flavor of distributions analyzed from an embeddable layout.
+pub const EMBEDDABLE_FLAVOR: &str = "embeddable";
+
+/// Directory the standard library zip is extracted to.
+const STDLIB_DIR: &str = "stdlib";
+
+/// Whether a file is a zip archive.
+pub fn is_zip_archive(path: &Path) -> Result<bool> {
+    let mut fh =
+        fs::File::open(path).with_context(|| format!("unable to open {}", path.display()))?;
+
+    let mut magic = [0; 4];
+    match fh.read_exact(&mut magic) {
+        Ok(()) => Ok(&magic == b"PK\x03\x04"),
+        Err(ref e) if e.kind() == std::io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Extract all members of a zip archive into a directory.
+fn extract_zip(path: &Path, dest_dir: &Path) -> Result<()> {
+    let fh = fs::File::open(path).with_context(|| format!("unable to open {}", path.display()))?;
+    let mut za = zip::ZipArchive::new(fh)
+        .with_context(|| format!("unable to read zip archive {}", path.display()))?;
+
+    for i in 0..za.len() {
+        cancel::check_cancelled()?;
+
+        let mut file = za
+            .by_index(i)
+            .with_context(|| format!("unable to read zip archive {}", path.display()))?;
+
+        if file.name().ends_with('/') {
+            continue;
+        }
+
+        let dest_path = dest_dir.join(file.sanitized_name());
+        if let Some(parent) = dest_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let mut fh = fs::File::create(&dest_path)
+            .with_context(|| format!("unable to create {}", dest_path.display()))?;
+        std::io::copy(&mut file, &mut fh).with_context(|| {
+            format!("unable to extract {} from {}", file.name(), path.display())
+        })?;
+    }
+
+    Ok(())
+}
+
+/// Parse the `X` and `Y` of a `pythonXY.<extension>` file name.
+fn python_xy(file_name: &str, extension: &str) -> Option<(String, String)> {
+    if !file_name.starts_with("python") || !file_name.ends_with(extension) {
+        return None;
+    }
+
+    let digits = &file_name["python".len()..file_name.len() - extension.len()];
+    if digits.len() < 2 || !digits.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+
+    Some((digits[0..1].to_string(), digits[1..].to_string()))
+}
+
+/// Resolve the architecture a Windows executable is built for.
+fn pe_machine_arch(data: &[u8]) -> Option<&'static str> {
+    if data.len() < 0x40 || &data[0..2] != b"MZ" {
+        return None;
+    }
+
+    let offset = u32::from_le_bytes([data[0x3c], data[0x3d], data[0x3e], data[0x3f]]) as usize;
+    if data.len() < offset + 6 || &data[offset..offset + 4] != b"PE\0\0" {
+        return None;
+    }
+
+    match u16::from_le_bytes([data[offset + 4], data[offset + 5]]) {
+        0x014c => Some("x86"),
+        0x8664 => Some("x86_64"),
+        0xaa64 => Some("aarch64"),
+        _ => None,
+    }
+}
+
+/// Extract an embeddable distribution zip archive to a directory.
+///
+/// The archive is extracted to `python/` and its standard library zip to
+/// `python/stdlib/`. Extraction is skipped if the directory already holds
+/// the distribution.
+fn extract_embeddable_distribution_zip(path: &Path, extract_dir: &Path) -> Result<()> {
+    with_extraction_lock(extract_dir, || {
+        if extract_dir.join("python").join(STDLIB_DIR).exists() {
+            return Ok(());
+        }
+
+        let staged = StagedDir::new(extract_dir)?;
+        let python_path = staged.path().join("python");
+
+        extract_zip(path, &python_path)?;
+
+        let stdlib_zip = fs::read_dir(&python_path)?
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .find(|p| match p.file_name().and_then(|n| n.to_str()) {
+                Some(name) => python_xy(name, ".zip").is_some(),
+                None => false,
+            })
+            .ok_or_else(|| {
+                anyhow!(
+                    "{} is not a Python distribution: it has no PYTHON.json and no pythonXY.zip holding the standard library",
+                    path.display()
+                )
+            })?;
+
+        extract_zip(&stdlib_zip, &python_path.join(STDLIB_DIR))?;
+
+        staged.commit()
+    })
+}
+
+/// Extract and analyze an embeddable distribution zip archive.
+pub fn analyze_embeddable_distribution_zip(
+    path: &Path,
+    extract_dir: &Path,
+) -> Result<ParsedPythonDistribution> {
+    extract_embeddable_distribution_zip(path, extract_dir)?;
+
+    analyze_embeddable_distribution_data(extract_dir)
+        .with_context(|| format!("analyzing {}", path.display()))
+}
+
+/// Analyze an extracted embeddable distribution.
+pub fn analyze_embeddable_distribution_data(dist_dir: &Path) -> Result<ParsedPythonDistribution> {
+    let python_path = dist_dir.join("python");
+
+    let mut version = None;
+    let mut extension_modules: BTreeMap<String, Vec<ExtensionModule>> = BTreeMap::new();
+    let mut sizes = DistributionSizes::default();
+
+    let mut root_files = fs::read_dir(&python_path)?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|p| p.is_file())
+        .collect::<Vec<_>>();
+    root_files.sort();
+
+    for path in &root_files {
+        let file_name = path.file_name().unwrap().to_string_lossy();
+
+        if let Some((major, minor)) = python_xy(&file_name, ".zip") {
+            version = Some(format!("{}.{}", major, minor));
+        } else if python_xy(&file_name, ".dll").is_some() {
+            sizes.core.insert(file_name.to_string(), file_size(path));
+        } else if file_name.ends_with(".pyd") {
+            let module = file_name.trim_end_matches(".pyd").to_string();
+
+            sizes
+                .extension_modules
+                .insert(module.clone(), file_size(path));
+
+            extension_modules.insert(
+                module.clone(),
+                vec![ExtensionModule {
+                    module: module.clone(),
+                    init_fn: Some(format!("PyInit_{}", module)),
+                    builtin_default: false,
+                    disableable: true,
+                    object_paths: Vec::new(),
+                    static_library: None,
+                    shared_library: Some(path.clone()),
+                    links: Vec::new(),
+                    required: false,
+                    variant: "default".to_string(),
+                    licenses: None,
+                    license_paths: None,
+                    license_public_domain: None,
+                }],
+            );
+        }
+    }
+
+    let python_exe = python_path.join("python.exe");
+    let mut missing = Vec::new();
+    if version.is_none() {
+        missing.push("pythonXY.zip");
+    }
+    if !python_exe.exists() {
+        missing.push("python.exe");
+    }
+    if sizes.core.is_empty() {
+        missing.push("pythonXY.dll");
+    }
+    if !missing.is_empty() {
+        return Err(anyhow!(
+            "{} is not an embeddable Python distribution; it lacks {}",
+            dist_dir.display(),
+            missing.join(", ")
+        ));
+    }
+    let version = version.unwrap();
+
+    let arch = pe_machine_arch(&fs::read(&python_exe)?).ok_or_else(|| {
+        anyhow!(
+            "unable to determine the architecture of {}",
+            python_exe.display()
+        )
+    })?;
+
+    // _sqlite3.pyd uses the SQLite DLL shipped next to it.
+    let sqlite_compile_options = fs::read(python_path.join("sqlite3.dll"))
+        .ok()
+        .and_then(|data| library_compile_options(&data));
+
+    let stdlib_path = python_path.join(STDLIB_DIR);
+    let (py_modules, resources) = stdlib_resources(&stdlib_path, &mut sizes)?;
+
+    let license_path = python_path.join("LICENSE.txt");
+    let (licenses, license_infos, license_path) = if license_path.exists() {
+        let licenses = vec!["Python-2.0".to_string()];
+        let mut license_infos = BTreeMap::new();
+        license_infos.insert(
+            "python".to_string(),
+            vec![LicenseInfo {
+                licenses: licenses.clone(),
+                license_filename: "LICENSE.python.txt".to_string(),
+                license_text: fs::read_to_string(&license_path)?,
+            }],
+        );
+
+        (
+            Some(licenses),
+            license_infos,
+            Some(PathBuf::from("LICENSE.txt")),
+        )
+    } else {
+        (None, BTreeMap::new(), None)
+    };
+
+    Ok(ParsedPythonDistribution {
+        base_dir: dist_dir.to_path_buf(),
+        flavor: EMBEDDABLE_FLAVOR.to_string(),
+        version,
+        os: "windows".to_string(),
+        arch: arch.to_string(),
+        python_exe,
+        stdlib_path,
+        licenses,
+        license_path,
+        tcl_library_path: None,
+        objs_core: BTreeMap::new(),
+        links_core: Vec::new(),
+        extension_modules,
+        frozen_c: Vec::new(),
+        includes: BTreeMap::new(),
+        libraries: BTreeMap::new(),
+        py_modules,
+        resources,
+        license_infos,
+        venv_base: dist_dir.parent().unwrap().join("hacked_base"),
+        sizes,
+        archive_sha256: String::new(),
+        sqlite_compile_options,
+    })
+}
+
+/// Python modules and resources in an extracted standard library zip.
+///
+/// The standard library zip holds `.pyc` files next to where their source
+/// would be, so modules are derived from both `.py` and `.pyc` files.
+#[allow(clippy::type_complexity)]
+fn stdlib_resources(
+    stdlib_path: &Path,
+    sizes: &mut DistributionSizes,
+) -> Result<(
+    BTreeMap<String, PathBuf>,
+    BTreeMap<String, BTreeMap<String, PathBuf>>,
+)> {
+    let mut py_modules = BTreeMap::new();
+    let mut packages = BTreeSet::new();
+    let mut other_files = Vec::new();
+
+    for entry in walk_tree_files(stdlib_path) {
+        let path = entry.path().to_path_buf();
+        let rel_path = path.strip_prefix(stdlib_path)?.to_path_buf();
+
+        let mut components = rel_path
+            .iter()
+            .map(|c| c.to_string_lossy().to_string())
+            .collect::<Vec<_>>();
+
+        let file_name = components.pop().unwrap();
+        let stem = if file_name.ends_with(".pyc") {
+            file_name.trim_end_matches(".pyc")
+        } else if file_name.ends_with(".py") {
+            file_name.trim_end_matches(".py")
+        } else {
+            other_files.push((components, file_name, path));
+            continue;
+        };
+
+        if stem == "__init__" {
+            packages.insert(components.join("."));
+        } else {
+            components.push(stem.to_string());
+        }
+
+        let name = components.join(".");
+        *sizes
+            .stdlib_packages
+            .entry(top_level_package(&name))
+            .or_insert(0) += file_size(&path);
+
+        // Prefer source when a module has both source and bytecode.
+        if file_name.ends_with(".py") || !py_modules.contains_key(&name) {
+            py_modules.insert(name, path);
+        }
+    }
+
+    let mut resources: BTreeMap<String, BTreeMap<String, PathBuf>> = BTreeMap::new();
+
+    for (mut components, file_name, path) in other_files {
+        // Resources belong to the nearest enclosing package.
+        let mut stem_parts = vec![file_name];
+        while !components.is_empty() && !packages.contains(&components.join(".")) {
+            stem_parts.insert(0, components.pop().unwrap());
+        }
+
+        if components.is_empty() {
+            continue;
+        }
+
+        let package = components.join(".");
+        *sizes
+            .resources
+            .entry(top_level_package(&package))
+            .or_insert(0) += file_size(&path);
+
+        resources
+            .entry(package)
+            .or_insert_with(BTreeMap::new)
+            .insert(stem_parts.join("/"), path);
+    }
+
+    Ok((py_modules, resources))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    /// Write a zip archive with the given members.
+    fn write_zip(path: &Path, members: &[(&str, &[u8])]) -> Result<()> {
+        let mut zf = zip::ZipWriter::new(fs::File::create(path)?);
+
+        for (name, data) in members {
+            zf.start_file(*name, zip::write::FileOptions::default())?;
+            zf.write_all(data)?;
+        }
+
+        zf.finish()?;
+
+        Ok(())
+    }
+
+    /// A minimal PE image for the given machine type.
+    fn pe_image(machine: u16) -> Vec<u8> {
+        let mut data = vec![0; 0x80];
+        data[0..2].copy_from_slice(b"MZ");
+        data[0x3c..0x40].copy_from_slice(&0x40u32.to_le_bytes());
+        data[0x40..0x44].copy_from_slice(b"PE\0\0");
+        data[0x44..0x46].copy_from_slice(&machine.to_le_bytes());
+        data
+    }
+
+    /// Write a trimmed copy of the layout of python-3.7.x-embed-amd64.zip.
+    fn write_embeddable_fixture(path: &Path) -> Result<()> {
+        let stdlib_zip = path.with_file_name("python37.zip");
+        write_zip(
+            &stdlib_zip,
+            &[
+                ("os.pyc", b"os"),
+                ("json/__init__.pyc", b"json"),
+                ("json/decoder.pyc", b"decoder"),
+                ("email/__init__.pyc", b"email"),
+                ("email/architecture.rst", b"docs"),
+                ("lib2to3/__init__.pyc", b"lib2to3"),
+                ("lib2to3/pgen2/grammar.pyc", b"grammar"),
+                ("lib2to3/pgen2/Grammar.txt", b"grammar"),
+            ],
+        )?;
+        let stdlib_data = fs::read(&stdlib_zip)?;
+
+        write_zip(
+            path,
+            &[
+                ("python.exe", &pe_image(0x8664)),
+                ("python37.dll", b"dll"),
+                ("python3.dll", b"dll"),
+                ("python37.zip", &stdlib_data),
+                ("python37._pth", b"python37.zip\n.\n"),
+                ("_ssl.pyd", b"pyd"),
+                ("select.pyd", b"pyd"),
+                ("libcrypto-1_1-x64.dll", b"dll"),
+                ("LICENSE.txt", b"license"),
+            ],
+        )
+    }
+
+    #[test]
+    fn test_python_xy() {
+        assert_eq!(
+            python_xy("python37.zip", ".zip"),
+            Some(("3".to_string(), "7".to_string()))
+        );
+        assert_eq!(
+            python_xy("python310.dll", ".dll"),
+            Some(("3".to_string(), "10".to_string()))
+        );
+        assert_eq!(python_xy("python3.dll", ".dll"), None);
+        assert_eq!(python_xy("pythonw.exe", ".exe"), None);
+    }
+
+    #[test]
+    fn test_pe_machine_arch() {
+        assert_eq!(pe_machine_arch(&pe_image(0x8664)), Some("x86_64"));
+        assert_eq!(pe_machine_arch(&pe_image(0x014c)), Some("x86"));
+        assert_eq!(pe_machine_arch(&pe_image(0x1234)), None);
+        assert_eq!(pe_machine_arch(b"\x7fELF"), None);
+    }
+
+    #[test]
+    fn test_analyze_embeddable() -> Result<()> {
+        let temp_dir = tempdir::TempDir::new("pyoxidizer-test")?;
+        let archive_path = temp_dir.path().join("python-3.7.5-embed-amd64.zip");
+        write_embeddable_fixture(&archive_path)?;
+        assert!(is_zip_archive(&archive_path)?);
+
+        let extract_dir = temp_dir.path().join("python.extracted");
+        let dist = analyze_embeddable_distribution_zip(&archive_path, &extract_dir)?;
+
+        assert_eq!(dist.flavor, EMBEDDABLE_FLAVOR);
+        assert_eq!(dist.version, "3.7");
+        assert_eq!(dist.os, "windows");
+        assert_eq!(dist.arch, "x86_64");
+        assert_eq!(
+            dist.py_modules.keys().collect::<Vec<_>>(),
+            vec![
+                "email",
+                "json",
+                "json.decoder",
+                "lib2to3",
+                "lib2to3.pgen2.grammar",
+                "os"
+            ]
+        );
+        assert_eq!(
+            dist.resources["email"].keys().collect::<Vec<_>>(),
+            vec!["architecture.rst"]
+        );
+        assert_eq!(
+            dist.resources["lib2to3"].keys().collect::<Vec<_>>(),
+            vec!["pgen2/Grammar.txt"]
+        );
+        assert_eq!(
+            dist.extension_modules.keys().collect::<Vec<_>>(),
+            vec!["_ssl", "select"]
+        );
+        assert!(dist.extension_modules["_ssl"][0]
+            .shared_library
+            .as_ref()
+            .unwrap()
+            .exists());
+        assert_eq!(
+            dist.sizes.core.keys().collect::<Vec<_>>(),
+            vec!["python37.dll"]
+        );
+        assert_eq!(dist.licenses, Some(vec!["Python-2.0".to_string()]));
+
+        assert_eq!(
+            dist.missing_embedding_requirements(),
+            vec![
+                "Python headers (include/Python.h)".to_string(),
+                "object files for the core Python implementation".to_string(),
+                "import library for the Python DLL (libs/python37.lib)".to_string(),
+                "object files or static libraries for extension modules: _ssl, select".to_string(),
+            ]
+        );
+
+        // A second analysis reuses the extraction.
+        fs::remove_file(&archive_path)?;
+        write_zip(&archive_path, &[])?;
+        analyze_embeddable_distribution_zip(&archive_path, &extract_dir)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_analyze_unexpected_zip() -> Result<()> {
+        let temp_dir = tempdir::TempDir::new("pyoxidizer-test")?;
+        let archive_path = temp_dir.path().join("dist.zip");
+        write_zip(&archive_path, &[("python.exe", &pe_image(0x8664))])?;
+
+        let err = analyze_embeddable_distribution_zip(
+            &archive_path,
+            &temp_dir.path().join("python.extracted"),
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("no pythonXY.zip"));
+
+        let archive_path = temp_dir.path().join("nodll.zip");
+        let stdlib_path = temp_dir.path().join("python37.zip");
+        write_zip(&stdlib_path, &[("os.pyc", b"os")])?;
+        write_zip(
+            &archive_path,
+            &[
+                ("python.exe", &pe_image(0x8664)),
+                ("python37.zip", &fs::read(&stdlib_path)?),
+            ],
+        )?;
+
+        let err = analyze_embeddable_distribution_zip(
+            &archive_path,
+            &temp_dir.path().join("nodll.extracted"),
+        )
+        .unwrap_err();
+        assert!(format!("{:#}", err).contains("it lacks pythonXY.dll"));
+
+        Ok(())
+    }
+}
@@ -2,22 +2,26 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
-use anyhow::Result;
-use byteorder::{LittleEndian, WriteBytesExt};
+use anyhow::{anyhow, Result};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use lazy_static::lazy_static;
 use slog::warn;
 use std::collections::{BTreeMap, BTreeSet};
-use std::io::Write;
+use std::io::{Cursor, Write};
 use std::iter::FromIterator;
 use std::path::Path;
 
 use super::bytecode::{BytecodeCompiler, CompileMode};
+use super::bytecode_cache::{BytecodeCache, CachingBytecodeCompiler};
 use super::distribution::ExtensionModule;
-use super::filtering::{filter_btreemap, resolve_resource_names_from_files};
+use super::filtering::{
+    filter_btreemap, resolve_resource_names_from_files, unmatched_patterns, CORE_MODULE_NAMES,
+};
 use super::resource::{
-    BuiltExtensionModule, BytecodeModule, PackagedModuleBytecode, PackagedModuleSource,
-    ResourceData, SourceModule,
+    BuiltExtensionModule, BytecodeModule, BytecodeOptimizationLevel, PackagedModuleBytecode,
+    PackagedModuleSource, ResourceData, SourceModule,
 };
+use super::retention::{resolve_policy, RetentionBucket, SourcePolicy};
 
 lazy_static! {
     /// Python extension modules that should never be included.
@@ -56,6 +60,18 @@ pub struct EmbeddedPythonResourcesPrePackaged {
     pub bytecode_modules: BTreeMap<String, BytecodeModule>,
     pub resources: BTreeMap<String, BTreeMap<String, Vec<u8>>>,
     pub extension_modules: BTreeMap<String, ExtensionModule>,
+    /// Module name patterns and which representations of matching modules to keep.
+    pub source_policies: Vec<(String, SourcePolicy)>,
+    /// Module name patterns and the bytecode optimization level of matching modules.
+    pub optimize_policies: Vec<(String, BytecodeOptimizationLevel)>,
+    /// Top-level packages installed next to the executable instead of embedded.
+    pub filesystem_packages: BTreeSet<String>,
+    /// Whether to write modules and resources data with a lazily hydrated index.
+    pub lazy_index: bool,
+}
+
+fn top_level_package(name: &str) -> &str {
+    name.split('.').next().unwrap()
 }
 
 impl EmbeddedPythonResourcesPrePackaged {
@@ -90,15 +106,105 @@ impl EmbeddedPythonResourcesPrePackaged {
             .insert(module.module.clone(), module.clone());
     }
 
+    /// Obtain the names of all modules and resource packages in this instance.
+    pub fn resource_names(&self) -> BTreeSet<String> {
+        let mut names = BTreeSet::new();
+
+        names.extend(self.source_modules.keys().cloned());
+        names.extend(self.bytecode_modules.keys().cloned());
+        names.extend(self.extension_modules.keys().cloned());
+        names.extend(self.resources.keys().cloned());
+
+        names
+    }
+
+    fn is_filesystem_relative(&self, name: &str) -> bool {
+        self.filesystem_packages.contains(top_level_package(name))
+    }
+
+    /// Obtain the modules and resource data installed next to the executable.
+    ///
+    /// Modules added only as bytecode are installed as source.
+    pub fn filesystem_resources(&self) -> (Vec<SourceModule>, Vec<ResourceData>) {
+        let mut modules = self
+            .source_modules
+            .values()
+            .filter(|m| self.is_filesystem_relative(&m.name))
+            .cloned()
+            .collect::<Vec<_>>();
+
+        for (name, module) in &self.bytecode_modules {
+            if self.is_filesystem_relative(name) && !self.source_modules.contains_key(name) {
+                modules.push(SourceModule {
+                    name: name.clone(),
+                    source: module.source.clone(),
+                    is_package: module.is_package,
+                });
+            }
+        }
+
+        let mut resources = Vec::new();
+        for (package, entries) in &self.resources {
+            if self.is_filesystem_relative(package) {
+                for (name, data) in entries {
+                    resources.push(ResourceData {
+                        package: package.clone(),
+                        name: name.clone(),
+                        data: data.clone(),
+                    });
+                }
+            }
+        }
+
+        (modules, resources)
+    }
+
+    /// Obtain the extension modules installed next to the executable.
+    ///
+    /// Only extension modules built as shared libraries can be installed.
+    pub fn filesystem_extension_modules(&self) -> Vec<ExtensionModule> {
+        self.extension_modules
+            .values()
+            .filter(|em| self.is_filesystem_relative(&em.module) && em.shared_library.is_some())
+            .cloned()
+            .collect()
+    }
+
     /// Filter the entities in this instance against names in files.
+    ///
+    /// Names and patterns in the files which match nothing are logged if
+    /// `allow_no_match` is set, which is the default in configs, and are an
+    /// error otherwise.
     pub fn filter_from_files(
         &mut self,
         logger: &slog::Logger,
         files: &[&Path],
         glob_patterns: &[&str],
+        allow_no_match: bool,
     ) -> Result<()> {
         let resource_names = resolve_resource_names_from_files(files, glob_patterns)?;
 
+        let mut available = self.resource_names();
+        available.extend(CORE_MODULE_NAMES.iter().map(|s| s.to_string()));
+
+        let unmatched = unmatched_patterns(&resource_names, &available);
+        if !unmatched.is_empty() {
+            if allow_no_match {
+                for u in &unmatched {
+                    warn!(logger, "filter {}", u);
+                }
+            } else {
+                return Err(anyhow!(
+                    "{}\n(remove allow_no_match=False if this is expected)",
+                    unmatched
+                        .iter()
+                        .map(|u| u.to_string())
+                        .collect::<Vec<String>>()
+                        .join("\n")
+                ));
+            }
+        }
+
         warn!(logger, "filtering embedded extension modules");
         filter_btreemap(logger, &mut self.extension_modules, &resource_names);
         warn!(logger, "filtering embedded module sources");
@@ -111,30 +217,198 @@ impl EmbeddedPythonResourcesPrePackaged {
         Ok(())
     }
 
-    pub fn package(&self, python_exe: &Path) -> Result<EmbeddedPythonResources> {
+    /// Apply source and optimize policies to modules.
+    ///
+    /// `stdlib` holds the names of the distribution's standard library
+    /// modules. Returns the modules each policy applied to, keyed by a
+    /// description of the policy. Modules matching no pattern are left alone.
+    pub fn apply_retention_policies(
+        &mut self,
+        stdlib: &BTreeSet<String>,
+    ) -> BTreeMap<String, RetentionBucket> {
+        let mut buckets: BTreeMap<String, RetentionBucket> = BTreeMap::new();
+
+        let mut names = BTreeSet::new();
+        names.extend(self.source_modules.keys().cloned());
+        names.extend(self.bytecode_modules.keys().cloned());
+
+        for name in &names {
+            let is_stdlib = stdlib.contains(name);
+
+            if let Some((pattern, policy)) =
+                resolve_policy(&self.source_policies, name, is_stdlib).cloned()
+            {
+                let source = self.source_modules.get(name).cloned();
+                let bytecode = self.bytecode_modules.get(name).cloned();
+
+                // Each representation can be derived from the other, as
+                // bytecode requests carry the module source.
+                if policy != SourcePolicy::SourceOnly && bytecode.is_none() {
+                    if let Some(m) = &source {
+                        self.add_bytecode_module(
+                            &m.as_bytecode_module(BytecodeOptimizationLevel::Zero),
+                        );
+                    }
+                }
+                if policy != SourcePolicy::BytecodeOnly && source.is_none() {
+                    if let Some(m) = &bytecode {
+                        self.add_source_module(&SourceModule {
+                            name: m.name.clone(),
+                            source: m.source.clone(),
+                            is_package: m.is_package,
+                        });
+                    }
+                }
+
+                match policy {
+                    SourcePolicy::BytecodeOnly => {
+                        self.source_modules.remove(name);
+                    }
+                    SourcePolicy::SourceOnly => {
+                        self.bytecode_modules.remove(name);
+                    }
+                    SourcePolicy::SourceAndBytecode => {}
+                }
+
+                buckets
+                    .entry(format!("source policy {} = {}", pattern, policy.as_str()))
+                    .or_default()
+                    .modules
+                    .insert(name.clone());
+            }
+
+            if let Some(module) = self.bytecode_modules.get_mut(name) {
+                if let Some((pattern, level)) =
+                    resolve_policy(&self.optimize_policies, name, is_stdlib)
+                {
+                    module.optimize_level = *level;
+
+                    buckets
+                        .entry(format!(
+                            "optimize policy {} = {}",
+                            pattern,
+                            i32::from(*level)
+                        ))
+                        .or_default()
+                        .modules
+                        .insert(name.clone());
+                }
+            }
+        }
+
+        buckets
+    }
+
+    /// Compute how the packaged size of the modules in each bucket changed.
+    ///
+    /// `before` is this collection before `apply_retention_policies()`.
+    /// Bytecode is only compiled for modules whose bytecode changed. A module
+    /// matching both a source and an optimize policy counts toward both.
+    pub fn measure_retention_buckets(
+        &self,
+        before: &EmbeddedPythonResourcesPrePackaged,
+        buckets: &mut BTreeMap<String, RetentionBucket>,
+        python_exe: &Path,
+    ) -> Result<()> {
+        let mut compiler = None;
+        let mut bytecode_size = |module: Option<&BytecodeModule>| -> Result<i64> {
+            let module = match module {
+                Some(module) => module,
+                None => return Ok(0),
+            };
+
+            if compiler.is_none() {
+                compiler = Some(BytecodeCompiler::new(python_exe)?);
+            }
+
+            Ok(compiler
+                .as_mut()
+                .unwrap()
+                .compile(
+                    &module.source,
+                    &module.name,
+                    module.optimize_level,
+                    CompileMode::Bytecode,
+                )?
+                .len() as i64)
+        };
+        let source_size = |resources: &EmbeddedPythonResourcesPrePackaged, name: &str| {
+            resources
+                .source_modules
+                .get(name)
+                .map_or(0, |m| m.source.len() as i64)
+        };
+
+        let mut deltas = BTreeMap::new();
+
+        for bucket in buckets.values_mut() {
+            bucket.size_delta = 0;
+
+            for name in &bucket.modules {
+                if !deltas.contains_key(name) {
+                    let mut delta = source_size(self, name) - source_size(before, name);
+
+                    let old = before.bytecode_modules.get(name);
+                    let new = self.bytecode_modules.get(name);
+                    if old != new {
+                        delta += bytecode_size(new)? - bytecode_size(old)?;
+                    }
+
+                    deltas.insert(name.clone(), delta);
+                }
+
+                bucket.size_delta += deltas[name];
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Compile bytecode and collect resources to embed.
+    ///
+    /// Compiled bytecode is cached if `distribution_sha256` is the SHA-256
+    /// of the distribution providing `python_exe`.
+    pub fn package(
+        &self,
+        python_exe: &Path,
+        distribution_sha256: &str,
+    ) -> Result<EmbeddedPythonResources> {
         let mut all_modules = BTreeSet::new();
         let mut all_packages = BTreeSet::new();
 
-        let module_sources = BTreeMap::from_iter(self.source_modules.iter().map(|(k, v)| {
-            all_modules.insert(k.clone());
-            if v.is_package {
-                all_packages.insert(k.clone());
-            }
+        let module_sources = BTreeMap::from_iter(
+            self.source_modules
+                .iter()
+                .filter(|(k, _)| !self.is_filesystem_relative(k))
+                .map(|(k, v)| {
+                    all_modules.insert(k.clone());
+                    if v.is_package {
+                        all_packages.insert(k.clone());
+                    }
 
-            (
-                k.clone(),
-                PackagedModuleSource {
-                    source: v.source.clone(),
-                    is_package: v.is_package,
-                },
-            )
-        }));
+                    (
+                        k.clone(),
+                        PackagedModuleSource {
+                            source: v.source.clone(),
+                            is_package: v.is_package,
+                        },
+                    )
+                }),
+        );
 
         let mut module_bytecodes = BTreeMap::new();
         {
-            let mut compiler = BytecodeCompiler::new(&python_exe)?;
+            let mut compiler = CachingBytecodeCompiler::new(
+                &python_exe,
+                BytecodeCache::default_cache(),
+                distribution_sha256,
+            )?;
 
             for (name, request) in &self.bytecode_modules {
+                if self.is_filesystem_relative(name) {
+                    continue;
+                }
+
                 let bytecode = compiler.compile(
                     &request.source,
                     &request.name,
@@ -157,7 +431,12 @@ impl EmbeddedPythonResourcesPrePackaged {
             }
         }
 
-        let resources = self.resources.clone();
+        let resources = self
+            .resources
+            .iter()
+            .filter(|(package, _)| !self.is_filesystem_relative(package))
+            .map(|(package, entries)| (package.clone(), entries.clone()))
+            .collect::<BTreeMap<_, _>>();
         all_packages.extend(resources.keys().cloned());
 
         let ignored = OS_IGNORE_EXTENSIONS
@@ -167,7 +446,7 @@ impl EmbeddedPythonResourcesPrePackaged {
 
         let extension_modules =
             BTreeMap::from_iter(self.extension_modules.iter().filter_map(|(k, v)| {
-                if ignored.contains(k) {
+                if ignored.contains(k) || self.is_filesystem_relative(k) {
                     None
                 } else {
                     Some((k.clone(), v.clone()))
@@ -182,6 +461,7 @@ impl EmbeddedPythonResourcesPrePackaged {
             resources,
             extension_modules,
             built_extension_modules: Default::default(),
+            lazy_index: self.lazy_index,
         })
     }
 }
@@ -196,6 +476,8 @@ pub struct EmbeddedPythonResources {
     pub resources: BTreeMap<String, BTreeMap<String, Vec<u8>>>,
     pub extension_modules: BTreeMap<String, ExtensionModule>,
     pub built_extension_modules: BTreeMap<String, BuiltExtensionModule>,
+    /// Whether to write modules and resources data with a lazily hydrated index.
+    pub lazy_index: bool,
 }
 
 /// Represents a single module's data record.
@@ -243,9 +525,106 @@ impl EmbeddedPythonResources {
             module_names.write_all(b"\n").expect("failed to write");
         }
 
-        write_modules_entries(modules, &self.modules_records()).unwrap();
+        if self.lazy_index {
+            write_lazy_modules_entries(modules, &self.modules_records()).unwrap();
+            write_lazy_resources_entries(resources, &self.resources).unwrap();
+        } else {
+            write_modules_entries(modules, &self.modules_records()).unwrap();
+            write_resources_entries(resources, &self.resources).unwrap();
+        }
+    }
+
+    /// Serialize modules and resources as a shared resources file.
+    ///
+    /// See the `pyembed` crate for the file format.
+    pub fn write_shared_resources(&self) -> Vec<u8> {
+        let mut module_names = Vec::new();
+        let mut modules = Vec::new();
+        let mut resources = Vec::new();
+
+        self.write_blobs(&mut module_names, &mut modules, &mut resources);
+
+        let mut data = SHARED_RESOURCES_MAGIC.to_vec();
+        data.write_u64::<LittleEndian>(modules.len() as u64)
+            .unwrap();
+        data.write_u64::<LittleEndian>(resources.len() as u64)
+            .unwrap();
+        data.extend(modules);
+        data.extend(resources);
+
+        data
+    }
+
+    /// The source, bytecode and whether a module is a package.
+    fn module_record(&self, name: &str) -> (Option<&Vec<u8>>, Option<&Vec<u8>>, bool) {
+        (
+            self.module_sources.get(name).map(|m| &m.source),
+            self.module_bytecodes.get(name).map(|m| &m.bytecode),
+            self.all_packages.contains(name),
+        )
+    }
+
+    /// Remove modules and resources identical to those of a shared group.
+    ///
+    /// Modules and resources which differ are kept, so they take precedence
+    /// over the shared ones at run-time. Extension modules of the shared group
+    /// are added, as they can't be loaded from a file and must be linked in.
+    pub fn without_shared(&self, shared: &EmbeddedPythonResources) -> EmbeddedPythonResources {
+        let all_modules = self
+            .all_modules
+            .iter()
+            .filter(|name| {
+                !shared.all_modules.contains(*name)
+                    || self.module_record(name) != shared.module_record(name)
+            })
+            .cloned()
+            .collect::<BTreeSet<_>>();
+
+        let mut resources = BTreeMap::new();
+        for (package, entries) in &self.resources {
+            let shared_entries = shared.resources.get(package);
+
+            let kept = entries
+                .iter()
+                .filter(|(name, data)| shared_entries.and_then(|e| e.get(*name)) != Some(data))
+                .map(|(name, data)| (name.clone(), data.clone()))
+                .collect::<BTreeMap<_, _>>();
+
+            if !kept.is_empty() {
+                resources.insert(package.clone(), kept);
+            }
+        }
 
-        write_resources_entries(resources, &self.resources).unwrap();
+        let all_packages = self
+            .all_packages
+            .iter()
+            .filter(|name| all_modules.contains(*name) || resources.contains_key(*name))
+            .cloned()
+            .collect();
+
+        let mut extension_modules = shared.extension_modules.clone();
+        extension_modules.extend(self.extension_modules.clone());
+
+        EmbeddedPythonResources {
+            module_sources: self
+                .module_sources
+                .iter()
+                .filter(|(name, _)| all_modules.contains(*name))
+                .map(|(name, module)| (name.clone(), module.clone()))
+                .collect(),
+            module_bytecodes: self
+                .module_bytecodes
+                .iter()
+                .filter(|(name, _)| all_modules.contains(*name))
+                .map(|(name, module)| (name.clone(), module.clone()))
+                .collect(),
+            all_modules,
+            all_packages,
+            resources,
+            extension_modules,
+            built_extension_modules: self.built_extension_modules.clone(),
+            lazy_index: self.lazy_index,
+        }
     }
 
     pub fn embedded_extension_module_names(&self) -> BTreeSet<String> {
@@ -262,13 +641,29 @@ impl EmbeddedPythonResources {
     }
 }
 
+/// Magic bytes at the start of lazily indexed modules and resources data.
+///
+/// Data not starting with these bytes is in the original format, which
+/// begins with an entry count.
+pub const LAZY_INDEX_MAGIC: &[u8] = b"pyoxlz01";
+
+/// Magic bytes at the start of a shared resources file.
+pub const SHARED_RESOURCES_MAGIC: &[u8] = b"pyoxsr01";
+
 /// Serialize a ModulesEntries to a writer.
 ///
 /// See the documentation in the `pyembed` crate for the data format.
 pub fn write_modules_entries<W: Write>(mut dest: W, entries: &[ModuleEntry]) -> Result<()> {
-    dest.write_u32::<LittleEndian>(entries.len() as u32)?;
+    write_modules(&mut dest, entries.iter())
+}
+
+fn write_modules<'a, W: Write, I>(dest: &mut W, entries: I) -> Result<()>
+where
+    I: Iterator<Item = &'a ModuleEntry> + Clone,
+{
+    dest.write_u32::<LittleEndian>(entries.clone().count() as u32)?;
 
-    for entry in entries.iter() {
+    for entry in entries.clone() {
         let name_bytes = entry.name.as_bytes();
         dest.write_u32::<LittleEndian>(name_bytes.len() as u32)?;
         dest.write_u32::<LittleEndian>(if let Some(ref v) = entry.source {
@@ -290,18 +685,18 @@ pub fn write_modules_entries<W: Write>(mut dest: W, entries: &[ModuleEntry]) ->
         dest.write_u32::<LittleEndian>(flags)?;
     }
 
-    for entry in entries.iter() {
+    for entry in entries.clone() {
         let name_bytes = entry.name.as_bytes();
         dest.write_all(name_bytes)?;
     }
 
-    for entry in entries.iter() {
+    for entry in entries.clone() {
         if let Some(ref v) = entry.source {
             dest.write_all(v.as_slice())?;
         }
     }
 
-    for entry in entries.iter() {
+    for entry in entries {
         if let Some(ref v) = entry.bytecode {
             dest.write_all(v.as_slice())?;
         }
@@ -317,10 +712,17 @@ pub fn write_resources_entries<W: Write>(
     dest: &mut W,
     entries: &BTreeMap<String, BTreeMap<String, Vec<u8>>>,
 ) -> Result<()> {
-    dest.write_u32::<LittleEndian>(entries.len() as u32)?;
+    write_resources(dest, entries.iter())
+}
+
+fn write_resources<'a, W: Write, I>(dest: &mut W, entries: I) -> Result<()>
+where
+    I: Iterator<Item = (&'a String, &'a BTreeMap<String, Vec<u8>>)> + Clone,
+{
+    dest.write_u32::<LittleEndian>(entries.clone().count() as u32)?;
 
     // All the numeric index data is written in pass 1.
-    for (package, resources) in entries {
+    for (package, resources) in entries.clone() {
         let package_bytes = package.as_bytes();
 
         dest.write_u32::<LittleEndian>(package_bytes.len() as u32)?;
@@ -335,7 +737,7 @@ pub fn write_resources_entries<W: Write>(
     }
 
     // All the name strings are written in pass 2.
-    for (package, resources) in entries {
+    for (package, resources) in entries.clone() {
         dest.write_all(package.as_bytes())?;
 
         for name in resources.keys() {
@@ -344,7 +746,7 @@ pub fn write_resources_entries<W: Write>(
     }
 
     // All the resource data is written in pass 3.
-    for resources in entries.values() {
+    for (_, resources) in entries {
         for value in resources.values() {
             dest.write_all(value.as_slice())?;
         }
@@ -352,3 +754,707 @@ pub fn write_resources_entries<W: Write>(
 
     Ok(())
 }
+
+/// Write groups of serialized data behind a lazily hydrated index.
+fn write_lazy_index<W: Write>(dest: &mut W, groups: &BTreeMap<&str, Vec<u8>>) -> Result<()> {
+    dest.write_all(LAZY_INDEX_MAGIC)?;
+    dest.write_u32::<LittleEndian>(groups.len() as u32)?;
+
+    for (name, data) in groups {
+        dest.write_u32::<LittleEndian>(name.len() as u32)?;
+        dest.write_u32::<LittleEndian>(data.len() as u32)?;
+    }
+
+    for name in groups.keys() {
+        dest.write_all(name.as_bytes())?;
+    }
+
+    for data in groups.values() {
+        dest.write_all(data)?;
+    }
+
+    Ok(())
+}
+
+/// Serialize a ModulesEntries to a writer with a lazily hydrated index.
+///
+/// Entries are grouped by top-level package and each group is serialized
+/// like `write_modules_entries()` does, so the importer only needs to parse
+/// a group once a module in its package is requested.
+pub fn write_lazy_modules_entries<W: Write>(mut dest: W, entries: &[ModuleEntry]) -> Result<()> {
+    let mut grouped: BTreeMap<&str, Vec<&ModuleEntry>> = BTreeMap::new();
+    for entry in entries {
+        grouped
+            .entry(top_level_package(&entry.name))
+            .or_default()
+            .push(entry);
+    }
+
+    let mut groups = BTreeMap::new();
+    for (name, group) in grouped {
+        let mut data = Vec::new();
+        write_modules(&mut data, group.iter().cloned())?;
+        groups.insert(name, data);
+    }
+
+    write_lazy_index(&mut dest, &groups)
+}
+
+/// Serializes resource data to a writer with a lazily hydrated index.
+///
+/// This is the equivalent of `write_lazy_modules_entries()` for resources.
+/// Resources are grouped by the top-level package of the package they belong
+/// to.
+pub fn write_lazy_resources_entries<W: Write>(
+    dest: &mut W,
+    entries: &BTreeMap<String, BTreeMap<String, Vec<u8>>>,
+) -> Result<()> {
+    let mut grouped: BTreeMap<&str, Vec<(&String, &BTreeMap<String, Vec<u8>>)>> = BTreeMap::new();
+    for (package, resources) in entries {
+        grouped
+            .entry(top_level_package(package))
+            .or_default()
+            .push((package, resources));
+    }
+
+    let mut groups = BTreeMap::new();
+    for (name, group) in grouped {
+        let mut data = Vec::new();
+        write_resources(&mut data, group.iter().cloned())?;
+        groups.insert(name, data);
+    }
+
+    write_lazy_index(dest, &groups)
+}
+
+/// Parse the header of lazily indexed data.
+///
+/// Returns `None` if `data` doesn't begin with `LAZY_INDEX_MAGIC`. Otherwise
+/// returns the total data length and the name and data of every group.
+fn parse_lazy_index(data: &[u8]) -> Result<Option<(usize, Vec<(&str, &[u8])>)>> {
+    if !data.starts_with(LAZY_INDEX_MAGIC) {
+        return Ok(None);
+    }
+
+    let mut reader = Cursor::new(&data[LAZY_INDEX_MAGIC.len()..]);
+    let count = reader.read_u32::<LittleEndian>()? as usize;
+
+    let mut lengths = Vec::with_capacity(count);
+    for _ in 0..count {
+        let name_length = reader.read_u32::<LittleEndian>()? as usize;
+        let data_length = reader.read_u32::<LittleEndian>()? as usize;
+
+        if name_length == 0 {
+            return Err(anyhow!("lazy index group has empty name"));
+        }
+
+        lengths.push((name_length, data_length));
+    }
+
+    let mut name_offset = LAZY_INDEX_MAGIC.len() + reader.position() as usize;
+    let mut data_offset = name_offset + lengths.iter().map(|(l, _)| l).sum::<usize>();
+    let total = data_offset + lengths.iter().map(|(_, l)| l).sum::<usize>();
+
+    if total > data.len() {
+        return Err(anyhow!(
+            "lazy index data truncated: index describes {} bytes; {} available",
+            total,
+            data.len()
+        ));
+    }
+
+    let mut groups = Vec::with_capacity(count);
+    for (name_length, data_length) in lengths {
+        let name = std::str::from_utf8(&data[name_offset..name_offset + name_length])?;
+        name_offset += name_length;
+
+        groups.push((name, &data[data_offset..data_offset + data_length]));
+        data_offset += data_length;
+    }
+
+    Ok(Some((total, groups)))
+}
+
+/// Validate every group of lazily indexed data with a parser for the original format.
+fn parse_lazy_data_length(data: &[u8], parse: fn(&[u8]) -> Result<usize>) -> Result<Option<usize>> {
+    match parse_lazy_index(data)? {
+        Some((total, groups)) => {
+            for (name, group) in groups {
+                let length = parse(group).map_err(|e| anyhow!("group {}: {}", name, e))?;
+
+                if length != group.len() {
+                    return Err(anyhow!(
+                        "group {}: index describes {} bytes; group has {}",
+                        name,
+                        length,
+                        group.len()
+                    ));
+                }
+            }
+
+            Ok(Some(total))
+        }
+        None => Ok(None),
+    }
+}
+
+/// Parse the index of serialized modules data and return the total data length.
+///
+/// This validates that data produced by `write_modules_entries()` or
+/// `write_lazy_modules_entries()` is well-formed without materializing its
+/// content. `data` may extend past the end of the serialized modules data.
+pub fn parse_modules_data_length(data: &[u8]) -> Result<usize> {
+    match parse_lazy_data_length(data, parse_modules_group_length)? {
+        Some(total) => Ok(total),
+        None => parse_modules_group_length(data),
+    }
+}
+
+fn parse_modules_group_length(data: &[u8]) -> Result<usize> {
+    let mut reader = Cursor::new(data);
+
+    let count = reader.read_u32::<LittleEndian>()? as usize;
+    let mut total = 4 + count * 16;
+
+    for _ in 0..count {
+        let name_length = reader.read_u32::<LittleEndian>()? as usize;
+        let source_length = reader.read_u32::<LittleEndian>()? as usize;
+        let bytecode_length = reader.read_u32::<LittleEndian>()? as usize;
+        let flags = reader.read_u32::<LittleEndian>()?;
+
+        if name_length == 0 {
+            return Err(anyhow!("module entry has empty name"));
+        }
+        if flags & !1 != 0 {
+            return Err(anyhow!("module entry has unknown flags {}", flags));
+        }
+
+        total += name_length + source_length + bytecode_length;
+    }
+
+    if total > data.len() {
+        return Err(anyhow!(
+            "modules data truncated: index describes {} bytes; {} available",
+            total,
+            data.len()
+        ));
+    }
+
+    Ok(total)
+}
+
+/// An entry in the index of serialized modules data.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ModuleIndexEntry {
+    pub name: String,
+    pub is_package: bool,
+    pub source_length: usize,
+    pub bytecode_length: usize,
+}
+
+/// Parse the index of serialized modules data.
+///
+/// Module data itself isn't read. Both the original and the lazily indexed
+/// formats are supported.
+pub fn parse_modules_index(data: &[u8]) -> Result<Vec<ModuleIndexEntry>> {
+    parse_modules_data_length(data)?;
+
+    match parse_lazy_index(data)? {
+        Some((_, groups)) => {
+            let mut res = Vec::new();
+            for (_, group) in groups {
+                res.extend(parse_modules_group_index(group)?);
+            }
+
+            Ok(res)
+        }
+        None => parse_modules_group_index(data),
+    }
+}
+
+fn parse_modules_group_index(data: &[u8]) -> Result<Vec<ModuleIndexEntry>> {
+    let mut reader = Cursor::new(data);
+    let count = reader.read_u32::<LittleEndian>()? as usize;
+
+    let mut lengths = Vec::with_capacity(count);
+    for _ in 0..count {
+        let name_length = reader.read_u32::<LittleEndian>()? as usize;
+        let source_length = reader.read_u32::<LittleEndian>()? as usize;
+        let bytecode_length = reader.read_u32::<LittleEndian>()? as usize;
+        let flags = reader.read_u32::<LittleEndian>()?;
+
+        lengths.push((name_length, source_length, bytecode_length, flags & 1 != 0));
+    }
+
+    let mut offset = reader.position() as usize;
+    let mut res = Vec::with_capacity(count);
+
+    for (name_length, source_length, bytecode_length, is_package) in lengths {
+        let name = std::str::from_utf8(&data[offset..offset + name_length])?;
+        offset += name_length;
+
+        res.push(ModuleIndexEntry {
+            name: name.to_string(),
+            is_package,
+            source_length,
+            bytecode_length,
+        });
+    }
+
+    Ok(res)
+}
+
+/// Parse the index of serialized resources data and return the total data length.
+///
+/// This is the equivalent of `parse_modules_data_length()` for data produced
+/// by `write_resources_entries()` or `write_lazy_resources_entries()`.
+pub fn parse_resources_data_length(data: &[u8]) -> Result<usize> {
+    match parse_lazy_data_length(data, parse_resources_group_length)? {
+        Some(total) => Ok(total),
+        None => parse_resources_group_length(data),
+    }
+}
+
+fn parse_resources_group_length(data: &[u8]) -> Result<usize> {
+    let mut reader = Cursor::new(data);
+
+    let package_count = reader.read_u32::<LittleEndian>()? as usize;
+    let mut total = 4;
+
+    for _ in 0..package_count {
+        let package_length = reader.read_u32::<LittleEndian>()? as usize;
+        let resource_count = reader.read_u32::<LittleEndian>()? as usize;
+
+        if package_length == 0 {
+            return Err(anyhow!("resources entry has empty package name"));
+        }
+
+        total += 8 + package_length;
+
+        for _ in 0..resource_count {
+            let name_length = reader.read_u32::<LittleEndian>()? as usize;
+            let data_length = reader.read_u32::<LittleEndian>()? as usize;
+
+            total += 8 + name_length + data_length;
+        }
+    }
+
+    if total > data.len() {
+        return Err(anyhow!(
+            "resources data truncated: index describes {} bytes; {} available",
+            total,
+            data.len()
+        ));
+    }
+
+    Ok(total)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_parse_modules_data_length() -> Result<()> {
+        let entries = vec![
+            ModuleEntry {
+                name: "foo".to_string(),
+                is_package: true,
+                source: Some(b"import bar".to_vec()),
+                bytecode: None,
+            },
+            ModuleEntry {
+                name: "foo.bar".to_string(),
+                is_package: false,
+                source: None,
+                bytecode: Some(b"bytecode".to_vec()),
+            },
+        ];
+
+        let mut data = Vec::new();
+        write_modules_entries(&mut data, &entries)?;
+
+        assert_eq!(parse_modules_data_length(&data)?, data.len());
+
+        let mut extended = data.clone();
+        extended.extend(b"trailing data");
+        assert_eq!(parse_modules_data_length(&extended)?, data.len());
+
+        assert!(parse_modules_data_length(&data[0..data.len() - 1]).is_err());
+
+        assert_eq!(
+            parse_modules_index(&data)?,
+            vec![
+                ModuleIndexEntry {
+                    name: "foo".to_string(),
+                    is_package: true,
+                    source_length: 10,
+                    bytecode_length: 0,
+                },
+                ModuleIndexEntry {
+                    name: "foo.bar".to_string(),
+                    is_package: false,
+                    source_length: 0,
+                    bytecode_length: 8,
+                },
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_resources_data_length() -> Result<()> {
+        let mut entries = BTreeMap::new();
+        let mut resources = BTreeMap::new();
+        resources.insert("data.txt".to_string(), b"content".to_vec());
+        resources.insert("other.txt".to_string(), b"".to_vec());
+        entries.insert("foo".to_string(), resources);
+
+        let mut data = Vec::new();
+        write_resources_entries(&mut data, &entries)?;
+
+        assert_eq!(parse_resources_data_length(&data)?, data.len());
+        assert!(parse_resources_data_length(&data[0..data.len() - 1]).is_err());
+
+        Ok(())
+    }
+
+    fn packaged_resources(
+        modules: &[(&str, &str)],
+        resources: &[(&str, &str, &str)],
+    ) -> EmbeddedPythonResources {
+        let mut packaged = EmbeddedPythonResources::default();
+
+        for (name, source) in modules {
+            packaged.all_modules.insert(name.to_string());
+            packaged.module_sources.insert(
+                name.to_string(),
+                PackagedModuleSource {
+                    source: source.as_bytes().to_vec(),
+                    is_package: false,
+                },
+            );
+        }
+
+        for (package, name, data) in resources {
+            packaged.all_packages.insert(package.to_string());
+            packaged
+                .resources
+                .entry(package.to_string())
+                .or_insert_with(BTreeMap::new)
+                .insert(name.to_string(), data.as_bytes().to_vec());
+        }
+
+        packaged
+    }
+
+    #[test]
+    fn test_without_shared() {
+        let shared = packaged_resources(
+            &[("json", "x = 1\n"), ("six", "y = 2\n")],
+            &[
+                ("certifi", "cacert.pem", "certs"),
+                ("certifi", "py.typed", ""),
+            ],
+        );
+
+        let exe = packaged_resources(
+            &[
+                ("json", "x = 1\n"),
+                ("six", "y = 3\n"),
+                ("myapp", "z = 4\n"),
+            ],
+            &[
+                ("certifi", "cacert.pem", "certs"),
+                ("certifi", "py.typed", "typed"),
+                ("myapp", "data.txt", "data"),
+            ],
+        );
+
+        let own = exe.without_shared(&shared);
+
+        // Identical modules are loaded from the shared file, others replace them.
+        assert_eq!(
+            own.all_modules.iter().collect::<Vec<_>>(),
+            vec!["myapp", "six"]
+        );
+        assert_eq!(
+            own.module_sources.keys().collect::<Vec<_>>(),
+            vec!["myapp", "six"]
+        );
+        assert_eq!(
+            own.resources
+                .iter()
+                .flat_map(|(package, entries)| entries
+                    .keys()
+                    .map(move |name| format!("{}/{}", package, name)))
+                .collect::<Vec<_>>(),
+            vec!["certifi/py.typed", "myapp/data.txt"]
+        );
+
+        // Nothing is left if everything is shared.
+        let own = shared.without_shared(&shared);
+        assert!(own.all_modules.is_empty());
+        assert!(own.all_packages.is_empty());
+        assert!(own.resources.is_empty());
+    }
+
+    #[test]
+    fn test_write_shared_resources() -> Result<()> {
+        let shared = packaged_resources(
+            &[("json", "x = 1\n")],
+            &[("certifi", "cacert.pem", "certs")],
+        );
+
+        let mut module_names = Vec::new();
+        let mut modules = Vec::new();
+        let mut resources = Vec::new();
+        shared.write_blobs(&mut module_names, &mut modules, &mut resources);
+
+        let data = shared.write_shared_resources();
+        assert!(data.starts_with(SHARED_RESOURCES_MAGIC));
+
+        let mut header = &data[SHARED_RESOURCES_MAGIC.len()..];
+        assert_eq!(header.read_u64::<LittleEndian>()?, modules.len() as u64);
+        assert_eq!(header.read_u64::<LittleEndian>()?, resources.len() as u64);
+        assert_eq!(&header[..modules.len()], modules.as_slice());
+        assert_eq!(&header[modules.len()..], resources.as_slice());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_lazy_index() -> Result<()> {
+        let entries = ["foo", "foo.bar", "ns.a", "ns.b", "six"]
+            .iter()
+            .map(|name| ModuleEntry {
+                name: name.to_string(),
+                is_package: *name == "foo",
+                source: None,
+                bytecode: Some(name.as_bytes().to_vec()),
+            })
+            .collect::<Vec<_>>();
+
+        let mut legacy = Vec::new();
+        write_modules_entries(&mut legacy, &entries)?;
+        let mut data = Vec::new();
+        write_lazy_modules_entries(&mut data, &entries)?;
+
+        assert!(data.starts_with(LAZY_INDEX_MAGIC));
+        assert_eq!(parse_modules_data_length(&data)?, data.len());
+        assert!(parse_modules_data_length(&data[0..data.len() - 1]).is_err());
+        assert_eq!(parse_modules_index(&data)?, parse_modules_index(&legacy)?);
+
+        let (_, groups) = parse_lazy_index(&data)?.unwrap();
+        assert_eq!(
+            groups.iter().map(|(name, _)| *name).collect::<Vec<_>>(),
+            vec!["foo", "ns", "six"]
+        );
+        assert_eq!(
+            parse_modules_index(groups[1].1)?
+                .iter()
+                .map(|entry| entry.name.as_str())
+                .collect::<Vec<_>>(),
+            vec!["ns.a", "ns.b"]
+        );
+
+        let mut resources = BTreeMap::new();
+        for package in &["foo", "foo.data", "six"] {
+            let mut entries = BTreeMap::new();
+            entries.insert("file.txt".to_string(), package.as_bytes().to_vec());
+            resources.insert(package.to_string(), entries);
+        }
+
+        let mut data = Vec::new();
+        write_lazy_resources_entries(&mut data, &resources)?;
+        assert_eq!(parse_resources_data_length(&data)?, data.len());
+
+        let (_, groups) = parse_lazy_index(&data)?.unwrap();
+        assert_eq!(
+            groups.iter().map(|(name, _)| *name).collect::<Vec<_>>(),
+            vec!["foo", "six"]
+        );
+        let mut expected = Vec::new();
+        write_resources_entries(
+            &mut expected,
+            &resources
+                .iter()
+                .filter(|(package, _)| package.starts_with("foo"))
+                .map(|(k, v)| (k.clone(), v.clone()))
+                .collect(),
+        )?;
+        assert_eq!(groups[0].1, expected.as_slice());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_filter_from_files_unmatched() -> Result<()> {
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let temp_dir = tempdir::TempDir::new("pyoxidizer-test")?;
+        let path = temp_dir.path().join("filter");
+        std::fs::write(&path, "sys\nmypkg\nmypkg.new*\nmypkg.oldmodule\n")?;
+
+        let mut embedded = EmbeddedPythonResourcesPrePackaged::default();
+        for name in &["mypkg", "mypkg.newmodule", "other"] {
+            embedded.add_source_module(&SourceModule {
+                name: name.to_string(),
+                source: vec![],
+                is_package: false,
+            });
+        }
+
+        let err = embedded
+            .clone()
+            .filter_from_files(&logger, &[&path], &[], false)
+            .unwrap_err();
+        assert!(err.to_string().contains(
+            "pattern 'mypkg.oldmodule' matched no packaged resources; did you mean 'mypkg.newmodule'?"
+        ));
+
+        embedded.filter_from_files(&logger, &[&path], &[], true)?;
+        assert_eq!(
+            embedded
+                .source_modules
+                .keys()
+                .cloned()
+                .collect::<Vec<String>>(),
+            vec!["mypkg".to_string(), "mypkg.newmodule".to_string()]
+        );
+
+        Ok(())
+    }
+
+    fn source_module(name: &str, source: &str) -> SourceModule {
+        SourceModule {
+            name: name.to_string(),
+            source: source.as_bytes().to_vec(),
+            is_package: false,
+        }
+    }
+
+    #[test]
+    fn test_filesystem_packages() -> Result<()> {
+        let dist = crate::testutil::get_default_distribution()?;
+
+        let mut resources = EmbeddedPythonResourcesPrePackaged::default();
+        resources.add_source_module(&source_module("pandas", "x = 1\n"));
+        resources.add_source_module(&source_module("six", "y = 2\n"));
+        resources.add_bytecode_module(
+            &source_module("pandas.io", "z = 3\n")
+                .as_bytecode_module(BytecodeOptimizationLevel::Zero),
+        );
+        resources.add_resource(&ResourceData {
+            package: "pandas".to_string(),
+            name: "data.csv".to_string(),
+            data: b"a,b\n".to_vec(),
+        });
+        resources.add_extension_module(&ExtensionModule {
+            module: "pandas._libs".to_string(),
+            init_fn: Some("PyInit__libs".to_string()),
+            builtin_default: false,
+            disableable: true,
+            object_paths: vec![],
+            static_library: None,
+            shared_library: Some(PathBuf::from("_libs.cpython-37m-x86_64-linux-gnu.so")),
+            links: vec![],
+            required: false,
+            variant: "default".to_string(),
+            licenses: None,
+            license_paths: None,
+            license_public_domain: None,
+        });
+        resources.filesystem_packages.insert("pandas".to_string());
+
+        let (modules, data) = resources.filesystem_resources();
+        assert_eq!(
+            modules.iter().map(|m| m.name.as_str()).collect::<Vec<_>>(),
+            vec!["pandas", "pandas.io"]
+        );
+        assert_eq!(modules[1].source, b"z = 3\n".to_vec());
+        assert_eq!(data.len(), 1);
+        assert_eq!(
+            resources
+                .filesystem_extension_modules()
+                .iter()
+                .map(|em| em.module.as_str())
+                .collect::<Vec<_>>(),
+            vec!["pandas._libs"]
+        );
+
+        let packaged = resources.package(&dist.python_exe, &dist.archive_sha256)?;
+        assert_eq!(packaged.all_modules.iter().collect::<Vec<_>>(), vec!["six"]);
+        assert!(packaged.resources.is_empty());
+        assert!(packaged.extension_modules.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_apply_retention_policies() {
+        let mut resources = EmbeddedPythonResourcesPrePackaged::default();
+        for name in &["myapp", "myapp.cli", "black", "json"] {
+            let module = source_module(name, "x = 1\n");
+            resources.add_source_module(&module);
+            resources
+                .add_bytecode_module(&module.as_bytecode_module(BytecodeOptimizationLevel::Zero));
+        }
+        resources.add_source_module(&source_module("toml", "y = 2\n"));
+
+        resources.source_policies = vec![
+            ("myapp.*".to_string(), SourcePolicy::SourceAndBytecode),
+            ("*".to_string(), SourcePolicy::BytecodeOnly),
+        ];
+        resources.optimize_policies =
+            vec![("stdlib:*".to_string(), BytecodeOptimizationLevel::Two)];
+
+        let before = resources.clone();
+        let stdlib = vec!["json".to_string()].into_iter().collect();
+        let mut buckets = resources.apply_retention_policies(&stdlib);
+
+        assert_eq!(
+            resources.source_modules.keys().collect::<Vec<_>>(),
+            vec!["myapp", "myapp.cli"]
+        );
+        assert_eq!(
+            resources.bytecode_modules.keys().collect::<Vec<_>>(),
+            vec!["black", "json", "myapp", "myapp.cli", "toml"]
+        );
+        assert_eq!(
+            resources.bytecode_modules["json"].optimize_level,
+            BytecodeOptimizationLevel::Two
+        );
+        assert_eq!(
+            resources.bytecode_modules["toml"].optimize_level,
+            BytecodeOptimizationLevel::Zero
+        );
+
+        assert_eq!(
+            buckets
+                .iter()
+                .map(|(label, bucket)| (label.as_str(), bucket.modules.len()))
+                .collect::<Vec<_>>(),
+            vec![
+                ("optimize policy stdlib:* = 2", 1),
+                ("source policy * = bytecode-only", 3),
+                ("source policy myapp.* = source+bytecode", 2),
+            ]
+        );
+
+        // Only sources of the remaining modules changed, so nothing is compiled.
+        buckets.remove("optimize policy stdlib:* = 2");
+        let bucket = buckets.get_mut("source policy * = bytecode-only").unwrap();
+        bucket.modules.remove("json");
+        bucket.modules.remove("toml");
+        resources
+            .measure_retention_buckets(&before, &mut buckets, Path::new("/nonexistent/python"))
+            .unwrap();
+        assert_eq!(buckets["source policy * = bytecode-only"].size_delta, -6);
+        assert_eq!(
+            buckets["source policy myapp.* = source+bytecode"].size_delta,
+            0
+        );
+    }
+}
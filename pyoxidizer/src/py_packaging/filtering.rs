@@ -2,13 +2,155 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, Context, Result};
 use slog::warn;
 use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
 use std::fs::File;
 use std::io::{BufRead, BufReader};
 use std::path::Path;
 
+/// Maximum number of suggestions offered for a pattern matching nothing.
+const MAX_SUGGESTIONS: usize = 3;
+
+/// Modules provided by the interpreter itself rather than packaged resources.
+///
+/// These show up in `sys.modules` and import statements but never in the
+/// set of packaged resources.
+pub const CORE_MODULE_NAMES: &[&str] = &[
+    "__main__",
+    "_frozen_importlib",
+    "_frozen_importlib_external",
+    "_imp",
+    "_io",
+    "_pyoxidizer_importer",
+    "_thread",
+    "_warnings",
+    "_weakref",
+    "builtins",
+    "marshal",
+    "sys",
+];
+
+/// Whether a resource name pattern uses glob syntax.
+pub fn is_glob_pattern(pattern: &str) -> bool {
+    pattern.contains(|c| c == '*' || c == '?' || c == '[')
+}
+
+/// Whether a resource name matches a pattern.
+///
+/// Patterns without glob syntax must equal the name. Glob patterns must
+/// match the entire name. `*` matches any characters, including `.`.
+pub fn name_matches(pattern: &str, name: &str) -> bool {
+    if is_glob_pattern(pattern) {
+        match glob::Pattern::new(pattern) {
+            Ok(p) => p.matches(name),
+            Err(_) => false,
+        }
+    } else {
+        pattern == name
+    }
+}
+
+/// Compute the Levenshtein distance between two strings.
+pub fn edit_distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<char>>();
+    let mut row = (0..=b.len()).collect::<Vec<usize>>();
+
+    for (i, ca) in a.chars().enumerate() {
+        let mut previous = row[0];
+        row[0] = i + 1;
+
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = if ca == *cb { previous } else { previous + 1 };
+            previous = row[j + 1];
+            row[j + 1] = substitution.min(row[j] + 1).min(previous + 1);
+        }
+    }
+
+    row[b.len()]
+}
+
+/// Find names similar to a pattern which matched nothing.
+pub fn suggest_names(pattern: &str, names: &BTreeSet<String>) -> Vec<String> {
+    // Compare against the literal portion of glob patterns.
+    let literal = pattern
+        .chars()
+        .filter(|c| !"*?[]".contains(*c))
+        .collect::<String>();
+    let max_distance = std::cmp::max(2, literal.len() / 3);
+
+    let mut candidates = names
+        .iter()
+        .map(|name| (edit_distance(&literal, name), name))
+        .filter(|(distance, _)| *distance <= max_distance)
+        .collect::<Vec<_>>();
+    candidates.sort();
+
+    candidates
+        .into_iter()
+        .take(MAX_SUGGESTIONS)
+        .map(|(_, name)| name.clone())
+        .collect()
+}
+
+/// A name pattern which matched no packaged resources.
+#[derive(Clone, Debug, PartialEq)]
+pub struct UnmatchedPattern {
+    pub pattern: String,
+    /// Similar resource names, most similar first.
+    pub suggestions: Vec<String>,
+}
+
+impl fmt::Display for UnmatchedPattern {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "pattern '{}' matched no packaged resources",
+            self.pattern
+        )?;
+
+        if !self.suggestions.is_empty() {
+            write!(
+                f,
+                "; did you mean {}?",
+                self.suggestions
+                    .iter()
+                    .map(|s| format!("'{}'", s))
+                    .collect::<Vec<String>>()
+                    .join(" or ")
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Find patterns that match none of the given names.
+pub fn unmatched_patterns<'a, I>(patterns: I, names: &BTreeSet<String>) -> Vec<UnmatchedPattern>
+where
+    I: IntoIterator<Item = &'a String>,
+{
+    patterns
+        .into_iter()
+        .filter(|pattern| {
+            if is_glob_pattern(pattern) {
+                !names.iter().any(|name| name_matches(pattern, name))
+            } else {
+                !names.contains(*pattern)
+            }
+        })
+        .map(|pattern| UnmatchedPattern {
+            pattern: pattern.clone(),
+            suggestions: suggest_names(pattern, names),
+        })
+        .collect()
+}
+
+/// Read resource names and patterns from a file.
+///
+/// Lines containing glob syntax (`*`, `?`, `[`) are patterns. Other lines
+/// are exact resource names.
 pub fn read_resource_names_file(path: &Path) -> Result<BTreeSet<String>> {
     let fh = File::open(path)?;
 
@@ -21,6 +163,11 @@ pub fn read_resource_names_file(path: &Path) -> Result<BTreeSet<String>> {
             continue;
         }
 
+        if is_glob_pattern(&line) {
+            glob::Pattern::new(&line)
+                .with_context(|| format!("invalid pattern {} in {}", line, path.display()))?;
+        }
+
         res.insert(line);
     }
 
@@ -63,11 +210,106 @@ pub fn filter_btreemap<V>(
     f: &BTreeSet<String>,
 ) {
     let keys: Vec<String> = m.keys().cloned().collect();
+    let globs = f
+        .iter()
+        .filter(|p| is_glob_pattern(p))
+        .filter_map(|p| glob::Pattern::new(p).ok())
+        .collect::<Vec<glob::Pattern>>();
 
     for key in keys {
-        if !f.contains(&key) {
+        if !f.contains(&key) && !globs.iter().any(|p| p.matches(&key)) {
             warn!(logger, "removing {}", key);
             m.remove(&key);
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(values: &[&str]) -> BTreeSet<String> {
+        values.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn test_name_matches() {
+        // Exact names don't match children or prefixes.
+        assert!(name_matches("mypkg.mod", "mypkg.mod"));
+        assert!(!name_matches("mypkg.mod", "mypkg.mod.sub"));
+        assert!(!name_matches("mypkg", "mypkg.mod"));
+
+        // Globs must match the whole name and * spans dots.
+        assert!(name_matches("mypkg.*", "mypkg.mod"));
+        assert!(name_matches("mypkg.*", "mypkg.mod.sub"));
+        assert!(!name_matches("mypkg.*", "mypkg"));
+        assert!(name_matches("mypkg*", "mypkg"));
+        assert!(name_matches("mypkg.mod?", "mypkg.mod2"));
+        assert!(name_matches("mypkg.[ab]", "mypkg.a"));
+        assert!(!name_matches("mypkg.[ab]", "mypkg.c"));
+    }
+
+    #[test]
+    fn test_edit_distance() {
+        assert_eq!(edit_distance("", ""), 0);
+        assert_eq!(edit_distance("abc", ""), 3);
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+        assert_eq!(edit_distance("mypkg.oldmodule", "mypkg.newmodule"), 3);
+    }
+
+    #[test]
+    fn test_unmatched_patterns() {
+        let available = names(&["mypkg", "mypkg.newmodule", "mypkg.util", "zlib"]);
+        let patterns = vec![
+            "mypkg".to_string(),
+            "mypkg.util*".to_string(),
+            "mypkg.oldmodule".to_string(),
+            "mypkg.oldmodule*".to_string(),
+            "unrelated.thing".to_string(),
+        ];
+
+        let unmatched = unmatched_patterns(&patterns, &available);
+        assert_eq!(
+            unmatched,
+            vec![
+                UnmatchedPattern {
+                    pattern: "mypkg.oldmodule".to_string(),
+                    suggestions: vec!["mypkg.newmodule".to_string()],
+                },
+                UnmatchedPattern {
+                    pattern: "mypkg.oldmodule*".to_string(),
+                    suggestions: vec!["mypkg.newmodule".to_string()],
+                },
+                UnmatchedPattern {
+                    pattern: "unrelated.thing".to_string(),
+                    suggestions: vec![],
+                },
+            ]
+        );
+
+        assert_eq!(
+            unmatched[1].to_string(),
+            "pattern 'mypkg.oldmodule*' matched no packaged resources; did you mean 'mypkg.newmodule'?"
+        );
+        assert_eq!(
+            unmatched[2].to_string(),
+            "pattern 'unrelated.thing' matched no packaged resources"
+        );
+    }
+
+    #[test]
+    fn test_filter_btreemap_globs() {
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+
+        let mut m = BTreeMap::new();
+        for name in &["a", "a.b", "a.c", "b"] {
+            m.insert(name.to_string(), ());
+        }
+
+        filter_btreemap(&logger, &mut m, &names(&["a.*", "b"]));
+        assert_eq!(
+            m.keys().cloned().collect::<Vec<String>>(),
+            vec!["a.b".to_string(), "a.c".to_string(), "b".to_string()]
+        );
+    }
+}
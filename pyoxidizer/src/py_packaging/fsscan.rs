@@ -124,38 +124,28 @@ pub enum PythonFileResource {
 
 pub struct PythonResourceIterator {
     root_path: PathBuf,
-    walkdir_result: Box<dyn Iterator<Item = walkdir::DirEntry>>,
+    paths: Box<dyn Iterator<Item = PathBuf>>,
     seen_packages: HashSet<String>,
     resources: Vec<FileBasedResource>,
 }
 
 impl PythonResourceIterator {
     fn new(path: &Path) -> PythonResourceIterator {
-        let res = walkdir::WalkDir::new(path).sort_by(|a, b| a.file_name().cmp(b.file_name()));
+        let paths = walk_tree_files(path).map(|entry| entry.path().to_path_buf());
 
-        let filtered = res.into_iter().filter_map(|entry| {
-            let entry = entry.expect("unable to get directory entry");
-
-            let path = entry.path();
-
-            if path.is_dir() {
-                None
-            } else {
-                Some(entry)
-            }
-        });
+        PythonResourceIterator::from_paths(path, Box::new(paths))
+    }
 
+    fn from_paths(root_path: &Path, paths: Box<dyn Iterator<Item = PathBuf>>) -> Self {
         PythonResourceIterator {
-            root_path: path.to_path_buf(),
-            walkdir_result: Box::new(filtered),
+            root_path: root_path.to_path_buf(),
+            paths,
             seen_packages: HashSet::new(),
             resources: Vec::new(),
         }
     }
 
-    fn resolve_dir_entry(&mut self, entry: walkdir::DirEntry) -> Option<PythonFileResource> {
-        let path = entry.path();
-
+    fn resolve_path(&mut self, path: &Path) -> Option<PythonFileResource> {
         let mut rel_path = path
             .strip_prefix(&self.root_path)
             .expect("unable to strip path prefix");
@@ -410,15 +400,15 @@ impl Iterator for PythonResourceIterator {
         // We then emit those at the end, perhaps doing some post-processing along the
         // way.
         loop {
-            let res = self.walkdir_result.next();
+            let res = self.paths.next();
 
             // We're out of directory entries;
             if res.is_none() {
                 break;
             }
 
-            let entry = res.unwrap();
-            let python_resource = self.resolve_dir_entry(entry);
+            let path = res.unwrap();
+            let python_resource = self.resolve_path(&path);
 
             // Try the next directory entry.
             if python_resource.is_none() {
@@ -508,6 +498,17 @@ pub fn find_python_resources(root_path: &Path) -> PythonResourceIterator {
     PythonResourceIterator::new(root_path)
 }
 
+/// Find Python resources in a list of files under a root directory.
+///
+/// Like `find_python_resources()`, but the files are given instead of
+/// found by walking the directory, which needn't exist.
+pub fn find_python_resources_in_paths(
+    root_path: &Path,
+    paths: Vec<PathBuf>,
+) -> PythonResourceIterator {
+    PythonResourceIterator::from_paths(root_path, Box::new(paths.into_iter()))
+}
+
 pub fn find_python_modules(root_path: &Path) -> Result<BTreeMap<String, Vec<u8>>> {
     let mut mods = BTreeMap::new();
 
@@ -0,0 +1,415 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/*!
+Analyzing import profiles written by embedded interpreters.
+
+Applications whose config sets `import_profile_env` write a JSON report of
+the modules they imported, where from and how long executing each took. This
+module summarizes such a report and, joined with the index of the embedded
+modules data, suggests changes to packaging and to application code.
+*/
+
+use anyhow::{anyhow, Context, Result};
+use serde::Deserialize;
+use std::collections::BTreeSet;
+use std::path::Path;
+
+use super::embedded_resource::{parse_modules_index, ModuleIndexEntry};
+
+/// Version of the report format understood.
+const IMPORT_PROFILE_VERSION: u32 = 1;
+
+/// Modules taking at least this long to import are lazy-load candidates.
+const LAZY_LOAD_THRESHOLD_US: u64 = 10_000;
+
+/// Number of entries shown in each list.
+const LIST_LIMIT: usize = 15;
+
+/// A module import recorded in a profile.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct ProfiledImport {
+    pub name: String,
+    pub source: String,
+    pub start_us: Option<u64>,
+    pub cumulative_us: Option<u64>,
+    pub self_us: Option<u64>,
+    pub bytes: u64,
+}
+
+/// Reads of a resource recorded in a profile.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct ProfiledResource {
+    pub package: String,
+    pub name: String,
+    pub reads: u64,
+    pub bytes: u64,
+}
+
+/// An import profile written by an embedded interpreter.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct ImportProfile {
+    pub version: u32,
+    pub module_bytes: u64,
+    pub resource_bytes: u64,
+    pub imports: Vec<ProfiledImport>,
+    pub resources: Vec<ProfiledResource>,
+}
+
+impl ImportProfile {
+    pub fn from_path(path: &Path) -> Result<Self> {
+        let data = std::fs::read(path).with_context(|| format!("reading {}", path.display()))?;
+        let profile: ImportProfile = serde_json::from_slice(&data)
+            .with_context(|| format!("parsing import profile {}", path.display()))?;
+
+        if profile.version != IMPORT_PROFILE_VERSION {
+            return Err(anyhow!(
+                "{} has import profile version {}; only version {} is supported",
+                path.display(),
+                profile.version,
+                IMPORT_PROFILE_VERSION
+            ));
+        }
+
+        Ok(profile)
+    }
+}
+
+/// Summary of an import profile with suggestions.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ImportProfileAnalysis {
+    /// Number of imports by source.
+    pub sources: Vec<(String, usize)>,
+    /// Total time executing modules, excluding nested imports.
+    pub total_us: u64,
+    pub module_bytes: u64,
+    pub resource_bytes: u64,
+    pub resource_reads: u64,
+    /// Imports taking the most time, excluding nested imports.
+    pub slowest: Vec<ProfiledImport>,
+    /// Modules imported from the filesystem, which could be embedded.
+    pub freeze_candidates: Vec<ProfiledImport>,
+    /// Slow modules whose import could be deferred until needed.
+    pub lazy_load_candidates: Vec<ProfiledImport>,
+    /// Embedded modules never imported, largest first.
+    ///
+    /// `None` if no modules index was available.
+    pub unused_modules: Option<Vec<ModuleIndexEntry>>,
+}
+
+/// Analyze an import profile, optionally joined with the embedded modules index.
+pub fn analyze_import_profile(
+    profile: &ImportProfile,
+    index: Option<&[ModuleIndexEntry]>,
+) -> ImportProfileAnalysis {
+    let mut sources: Vec<(String, usize)> = Vec::new();
+    for import in &profile.imports {
+        match sources
+            .iter_mut()
+            .find(|(source, _)| source == &import.source)
+        {
+            Some(entry) => entry.1 += 1,
+            None => sources.push((import.source.clone(), 1)),
+        }
+    }
+    sources.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+
+    let mut slowest = profile
+        .imports
+        .iter()
+        .filter(|i| i.self_us.is_some())
+        .cloned()
+        .collect::<Vec<_>>();
+    slowest.sort_by(|a, b| b.self_us.cmp(&a.self_us).then_with(|| a.name.cmp(&b.name)));
+    slowest.truncate(LIST_LIMIT);
+
+    let mut freeze_candidates = profile
+        .imports
+        .iter()
+        .filter(|i| i.source == "filesystem")
+        .cloned()
+        .collect::<Vec<_>>();
+    freeze_candidates.sort_by(|a, b| a.name.cmp(&b.name));
+
+    // Only the outermost slow module is suggested: deferring it defers the
+    // modules it imports.
+    let slow = profile
+        .imports
+        .iter()
+        .filter(|i| i.cumulative_us.unwrap_or(0) >= LAZY_LOAD_THRESHOLD_US)
+        .collect::<Vec<_>>();
+    let slow_names = slow
+        .iter()
+        .map(|i| i.name.as_str())
+        .collect::<BTreeSet<_>>();
+    let mut lazy_load_candidates = slow
+        .iter()
+        .filter(|i| {
+            !slow_names
+                .iter()
+                .any(|name| i.name.starts_with(&format!("{}.", name)))
+        })
+        .map(|i| (*i).clone())
+        .collect::<Vec<_>>();
+    lazy_load_candidates.sort_by(|a, b| {
+        b.cumulative_us
+            .cmp(&a.cumulative_us)
+            .then_with(|| a.name.cmp(&b.name))
+    });
+    lazy_load_candidates.truncate(LIST_LIMIT);
+
+    let unused_modules = index.map(|index| {
+        let imported = profile
+            .imports
+            .iter()
+            .map(|i| i.name.as_str())
+            .collect::<BTreeSet<_>>();
+
+        let mut unused = index
+            .iter()
+            .filter(|entry| !imported.contains(entry.name.as_str()))
+            .cloned()
+            .collect::<Vec<_>>();
+        unused.sort_by(|a, b| {
+            (b.source_length + b.bytecode_length)
+                .cmp(&(a.source_length + a.bytecode_length))
+                .then_with(|| a.name.cmp(&b.name))
+        });
+
+        unused
+    });
+
+    ImportProfileAnalysis {
+        sources,
+        total_us: profile.imports.iter().filter_map(|i| i.self_us).sum(),
+        module_bytes: profile.module_bytes,
+        resource_bytes: profile.resource_bytes,
+        resource_reads: profile.resources.iter().map(|r| r.reads).sum(),
+        slowest,
+        freeze_candidates,
+        lazy_load_candidates,
+        unused_modules,
+    }
+}
+
+fn format_ms(us: Option<u64>) -> String {
+    match us {
+        Some(us) => format!("{:.1} ms", us as f64 / 1000.0),
+        None => "-".to_string(),
+    }
+}
+
+impl std::fmt::Display for ImportProfileAnalysis {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        writeln!(
+            f,
+            "{} modules imported ({})",
+            self.sources.iter().map(|(_, count)| count).sum::<usize>(),
+            self.sources
+                .iter()
+                .map(|(source, count)| format!("{} {}", count, source))
+                .collect::<Vec<_>>()
+                .join(", ")
+        )?;
+        writeln!(
+            f,
+            "time executing modules: {}",
+            format_ms(Some(self.total_us))
+        )?;
+        writeln!(
+            f,
+            "module data read: {} bytes; resource data read: {} bytes in {} reads",
+            self.module_bytes, self.resource_bytes, self.resource_reads
+        )?;
+
+        if !self.slowest.is_empty() {
+            writeln!(f, "\nslowest imports (excluding nested imports):")?;
+            for i in &self.slowest {
+                writeln!(
+                    f,
+                    "  {:>10}  {} ({})",
+                    format_ms(i.self_us),
+                    i.name,
+                    i.source
+                )?;
+            }
+        }
+
+        if !self.freeze_candidates.is_empty() {
+            writeln!(
+                f,
+                "\nfreeze candidates (imported from the filesystem; embed them to import from memory):"
+            )?;
+            for i in &self.freeze_candidates {
+                writeln!(f, "  {} ({} bytes)", i.name, i.bytes)?;
+            }
+        }
+
+        if !self.lazy_load_candidates.is_empty() {
+            writeln!(
+                f,
+                "\nlazy-load candidates (slow to import; consider importing them when first needed):"
+            )?;
+            for i in &self.lazy_load_candidates {
+                writeln!(
+                    f,
+                    "  {:>10}  {} (started at {})",
+                    format_ms(i.cumulative_us),
+                    i.name,
+                    format_ms(i.start_us)
+                )?;
+            }
+        }
+
+        match &self.unused_modules {
+            Some(unused) if !unused.is_empty() => {
+                writeln!(
+                    f,
+                    "\n{} embedded modules ({} bytes) were never imported; consider excluding the largest:",
+                    unused.len(),
+                    unused
+                        .iter()
+                        .map(|e| e.source_length + e.bytecode_length)
+                        .sum::<usize>()
+                )?;
+                for entry in unused.iter().take(LIST_LIMIT) {
+                    writeln!(
+                        f,
+                        "  {:>10}  {}",
+                        entry.source_length + entry.bytecode_length,
+                        entry.name
+                    )?;
+                }
+            }
+            Some(_) => writeln!(f, "\nevery embedded module was imported")?,
+            None => writeln!(
+                f,
+                "\npass --modules with the py-modules file of the build artifacts to find unused embedded modules"
+            )?,
+        }
+
+        Ok(())
+    }
+}
+
+/// Print an analysis of an import profile file.
+///
+/// `modules_path` is the `py-modules` file of the build artifacts.
+pub fn analyze_import_profile_path(profile_path: &Path, modules_path: Option<&Path>) -> Result<()> {
+    let profile = ImportProfile::from_path(profile_path)?;
+
+    let index = match modules_path {
+        Some(path) => {
+            let data =
+                std::fs::read(path).with_context(|| format!("reading {}", path.display()))?;
+            Some(
+                parse_modules_index(&data)
+                    .with_context(|| format!("parsing modules index {}", path.display()))?,
+            )
+        }
+        None => None,
+    };
+
+    print!(
+        "{}",
+        analyze_import_profile(&profile, index.as_ref().map(|v| v.as_slice()))
+    );
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn import(
+        name: &str,
+        source: &str,
+        cumulative_us: Option<u64>,
+        self_us: Option<u64>,
+    ) -> ProfiledImport {
+        ProfiledImport {
+            name: name.to_string(),
+            source: source.to_string(),
+            start_us: cumulative_us.map(|_| 0),
+            cumulative_us,
+            self_us,
+            bytes: 100,
+        }
+    }
+
+    fn entry(name: &str, bytecode_length: usize) -> ModuleIndexEntry {
+        ModuleIndexEntry {
+            name: name.to_string(),
+            is_package: false,
+            source_length: 0,
+            bytecode_length,
+        }
+    }
+
+    #[test]
+    fn test_analyze_import_profile() -> Result<()> {
+        let profile: ImportProfile = serde_json::from_str(
+            r#"{
+              "version": 1,
+              "module_bytes": 300,
+              "resource_bytes": 10,
+              "imports": [
+                {"name": "heavy.sub", "source": "memory", "start_us": 5, "cumulative_us": 30000, "self_us": 30000, "bytes": 100},
+                {"name": "heavy", "source": "memory", "start_us": 0, "cumulative_us": 50000, "self_us": 20000, "bytes": 100},
+                {"name": "sys", "source": "builtin", "start_us": 0, "cumulative_us": 10, "self_us": 10, "bytes": 0},
+                {"name": "plugin", "source": "filesystem", "start_us": null, "cumulative_us": null, "self_us": null, "bytes": 100}
+              ],
+              "resources": [{"package": "heavy", "name": "data.bin", "reads": 2, "bytes": 10}]
+            }"#,
+        )?;
+
+        let index = vec![
+            entry("heavy", 10),
+            entry("heavy.sub", 10),
+            entry("unused.small", 5),
+            entry("unused.big", 50),
+        ];
+
+        let analysis = analyze_import_profile(&profile, Some(&index));
+
+        assert_eq!(
+            analysis.sources,
+            vec![
+                ("memory".to_string(), 2),
+                ("builtin".to_string(), 1),
+                ("filesystem".to_string(), 1)
+            ]
+        );
+        assert_eq!(analysis.total_us, 50010);
+        assert_eq!(analysis.resource_reads, 2);
+        assert_eq!(analysis.slowest[0].name, "heavy.sub");
+        assert_eq!(
+            analysis.freeze_candidates,
+            vec![import("plugin", "filesystem", None, None)]
+        );
+        assert_eq!(
+            analysis
+                .lazy_load_candidates
+                .iter()
+                .map(|i| i.name.as_str())
+                .collect::<Vec<_>>(),
+            vec!["heavy"]
+        );
+        assert_eq!(
+            analysis.unused_modules,
+            Some(vec![entry("unused.big", 50), entry("unused.small", 5)])
+        );
+
+        let report = analysis.to_string();
+        assert!(report.starts_with("4 modules imported (2 memory, 1 builtin, 1 filesystem)\n"));
+        assert!(report.contains("2 embedded modules (55 bytes) were never imported"));
+
+        assert!(analyze_import_profile(&profile, None)
+            .to_string()
+            .contains("pass --modules"));
+
+        Ok(())
+    }
+}
@@ -141,10 +141,189 @@ pub struct LibpythonInfo {
     pub license_infos: BTreeMap<String, Vec<LicenseInfo>>,
 }
 
+/// Whether a target triple is a Windows target, which uses `.lib` libraries.
+fn is_windows_target(target: &str) -> bool {
+    match target {
+        "i686-pc-windows-msvc" => true,
+        "x86_64-pc-windows-msvc" => true,
+        _ => false,
+    }
+}
+
+/// Obtain the filename of a static library produced by `cc` for a target.
+pub fn static_library_filename(target: &str, name: &str) -> String {
+    if is_windows_target(target) {
+        format!("{}.lib", name)
+    } else {
+        format!("lib{}.a", name)
+    }
+}
+
+/// Paths of the files `link_libpython()` writes to its output directory.
+#[derive(Clone, Debug)]
+pub struct LibpythonPaths {
+    /// Derived `config.c` defining the built-in extension modules.
+    pub config_c: PathBuf,
+    /// Static library compiled from `config.c`.
+    pub libpyembeddedconfig: PathBuf,
+    /// Static library containing Python.
+    pub libpython: PathBuf,
+}
+
+impl LibpythonPaths {
+    pub fn new(out_dir: &Path, target: &str) -> LibpythonPaths {
+        LibpythonPaths {
+            config_c: out_dir.join("config.c"),
+            libpyembeddedconfig: out_dir.join(static_library_filename(target, "pyembeddedconfig")),
+            libpython: out_dir.join(static_library_filename(target, "pythonXY")),
+        }
+    }
+}
+
+/// Obtain the cargo: lines for linking against the libraries `link_libpython()`
+/// writes to `out_dir`.
+///
+/// This doesn't build anything. So it can be used to describe a build.
+pub fn libpython_cargo_metadata(
+    logger: &slog::Logger,
+    dist: &ParsedPythonDistribution,
+    resources: &EmbeddedPythonResources,
+    out_dir: &Path,
+    target: &str,
+) -> Vec<String> {
+    let mut cargo_metadata: Vec<String> = Vec::new();
+
+    cargo_metadata.push("cargo:rustc-link-lib=static=pyembeddedconfig".to_string());
+
+    // Collect the set of libraries that we need to link against.
+    let mut needed_libraries: BTreeSet<&str> = BTreeSet::new();
+    let mut needed_frameworks: BTreeSet<&str> = BTreeSet::new();
+    let mut needed_system_libraries: BTreeSet<&str> = BTreeSet::new();
+    let mut needed_libraries_external: BTreeSet<&str> = BTreeSet::new();
+
+    warn!(
+        logger,
+        "resolving libraries required by core distribution..."
+    );
+    for entry in &dist.links_core {
+        if entry.framework {
+            warn!(logger, "framework {} required by core", entry.name);
+            needed_frameworks.insert(&entry.name);
+        } else if entry.system {
+            warn!(logger, "system library {} required by core", entry.name);
+            needed_system_libraries.insert(&entry.name);
+        }
+        // TODO handle static/dynamic libraries.
+    }
+
+    for (name, em) in &resources.extension_modules {
+        if em.builtin_default {
+            continue;
+        }
+
+        for entry in &em.links {
+            if entry.framework {
+                needed_frameworks.insert(&entry.name);
+                warn!(logger, "framework {} required by {}", entry.name, name);
+            } else if entry.system {
+                warn!(logger, "system library {} required by {}", entry.name, name);
+                needed_system_libraries.insert(&entry.name);
+            } else if let Some(_lib) = &entry.static_path {
+                needed_libraries.insert(&entry.name);
+                warn!(logger, "static library {} required by {}", entry.name, name);
+            } else if let Some(_lib) = &entry.dynamic_path {
+                needed_libraries.insert(&entry.name);
+                warn!(
+                    logger,
+                    "dynamic library {} required by {}", entry.name, name
+                );
+            }
+        }
+    }
+
+    for (name, em) in &resources.built_extension_modules {
+        for library in &em.libraries {
+            warn!(logger, "library {} required by {}", library, name);
+            needed_libraries_external.insert(&library);
+        }
+
+        // TODO do something with library_dirs.
+    }
+
+    // Windows requires dynamic linking against msvcrt. Ensure that happens.
+    // TODO this workaround feels like a bug in the Python distribution not
+    // advertising a dependency on the CRT linkage type. Consider adding this
+    // to the distribution metadata.
+    if is_windows_target(target) {
+        needed_system_libraries.insert("msvcrt");
+    }
+
+    let mut extra_library_paths = BTreeSet::new();
+
+    for library in needed_libraries.iter() {
+        if OS_IGNORE_LIBRARIES.contains(&library) {
+            continue;
+        }
+
+        // Find the library in the distribution and statically link against it.
+        let fs_path = dist
+            .libraries
+            .get(*library)
+            .unwrap_or_else(|| panic!("unable to find library {}", library));
+
+        extra_library_paths.insert(fs_path.parent().unwrap().to_path_buf());
+
+        cargo_metadata.push(format!("cargo:rustc-link-lib=static={}", library))
+    }
+
+    for framework in needed_frameworks {
+        cargo_metadata.push(format!("cargo:rustc-link-lib=framework={}", framework));
+    }
+
+    for lib in needed_system_libraries {
+        cargo_metadata.push(format!("cargo:rustc-link-lib={}", lib));
+    }
+
+    for lib in needed_libraries_external {
+        cargo_metadata.push(format!("cargo:rustc-link-lib={}", lib));
+    }
+
+    cargo_metadata.push("cargo:rustc-link-lib=static=pythonXY".to_string());
+    cargo_metadata.push(format!(
+        "cargo:rustc-link-search=native={}",
+        out_dir.display()
+    ));
+
+    for path in extra_library_paths {
+        cargo_metadata.push(format!("cargo:rustc-link-search=native={}", path.display()));
+    }
+
+    cargo_metadata
+}
+
+/// Obtain licenses of the Python distribution components being linked.
+pub fn libpython_license_infos(
+    dist: &ParsedPythonDistribution,
+    resources: &EmbeddedPythonResources,
+) -> BTreeMap<String, Vec<LicenseInfo>> {
+    let mut license_infos = BTreeMap::new();
+
+    if let Some(li) = dist.license_infos.get("python") {
+        license_infos.insert("python".to_string(), li.clone());
+    }
+
+    for name in resources.extension_modules.keys() {
+        if let Some(li) = dist.license_infos.get(name) {
+            license_infos.insert(name.clone(), li.clone());
+        }
+    }
+
+    license_infos
+}
+
 /// Create a static libpython from a Python distribution.
 ///
 /// Returns a vector of cargo: lines that can be printed in build scripts.
-#[allow(clippy::cognitive_complexity)]
 pub fn link_libpython(
     logger: &slog::Logger,
     dist: &ParsedPythonDistribution,
@@ -154,20 +333,13 @@ pub fn link_libpython(
     target: &str,
     opt_level: &str,
 ) -> Result<LibpythonInfo> {
-    let mut cargo_metadata: Vec<String> = Vec::new();
-
     let temp_dir = tempdir::TempDir::new("libpython")?;
     let temp_dir_path = temp_dir.path();
+    let paths = LibpythonPaths::new(out_dir, target);
 
     let extension_modules = &resources.extension_modules;
     let built_extension_modules = &resources.built_extension_modules;
 
-    let windows = match target {
-        "i686-pc-windows-msvc" => true,
-        "x86_64-pc-windows-msvc" => true,
-        _ => false,
-    };
-
     // Sometimes we have canonicalized paths. These can break cc/cl.exe when they
     // are \\?\ paths on Windows for some reason. We hack around this by doing
     // operations in the temp directory and copying files to their final resting
@@ -183,10 +355,9 @@ pub fn link_libpython(
         extension_modules.len() + built_extension_modules.len()
     );
     let config_c_source = make_config_c(&extension_modules, &built_extension_modules);
-    let config_c_path = out_dir.join("config.c");
     let config_c_temp_path = temp_dir_path.join("config.c");
 
-    fs::write(&config_c_path, config_c_source.as_bytes())?;
+    fs::write(&paths.config_c, config_c_source.as_bytes())?;
     fs::write(&config_c_temp_path, config_c_source.as_bytes())?;
 
     // We need to make all .h includes accessible.
@@ -211,15 +382,6 @@ pub fn link_libpython(
         .cargo_metadata(false)
         .compile("pyembeddedconfig");
 
-    let libpyembeddedconfig_path = out_dir.join(if windows {
-        "pyembeddedconfig.lib"
-    } else {
-        "libpyembeddedconfig.a"
-    });
-
-    // Since we disabled cargo metadata lines above.
-    cargo_metadata.push("cargo:rustc-link-lib=static=pyembeddedconfig".to_string());
-
     warn!(logger, "resolving inputs for custom Python library...");
     let mut build = cc::Build::new();
     build.out_dir(out_dir);
@@ -256,29 +418,7 @@ pub fn link_libpython(
         build.object(&full);
     }
 
-    // For each extension module, extract and use its object file. We also
-    // use this pass to collect the set of libraries that we need to link
-    // against.
-    let mut needed_libraries: BTreeSet<&str> = BTreeSet::new();
-    let mut needed_frameworks: BTreeSet<&str> = BTreeSet::new();
-    let mut needed_system_libraries: BTreeSet<&str> = BTreeSet::new();
-    let mut needed_libraries_external: BTreeSet<&str> = BTreeSet::new();
-
-    warn!(
-        logger,
-        "resolving libraries required by core distribution..."
-    );
-    for entry in &dist.links_core {
-        if entry.framework {
-            warn!(logger, "framework {} required by core", entry.name);
-            needed_frameworks.insert(&entry.name);
-        } else if entry.system {
-            warn!(logger, "system library {} required by core", entry.name);
-            needed_system_libraries.insert(&entry.name);
-        }
-        // TODO handle static/dynamic libraries.
-    }
-
+    // For each extension module, extract and use its object file.
     warn!(
         logger,
         "resolving inputs for {} extension modules...",
@@ -299,25 +439,6 @@ pub fn link_libpython(
         for path in &em.object_paths {
             build.object(path);
         }
-
-        for entry in &em.links {
-            if entry.framework {
-                needed_frameworks.insert(&entry.name);
-                warn!(logger, "framework {} required by {}", entry.name, name);
-            } else if entry.system {
-                warn!(logger, "system library {} required by {}", entry.name, name);
-                needed_system_libraries.insert(&entry.name);
-            } else if let Some(_lib) = &entry.static_path {
-                needed_libraries.insert(&entry.name);
-                warn!(logger, "static library {} required by {}", entry.name, name);
-            } else if let Some(_lib) = &entry.dynamic_path {
-                needed_libraries.insert(&entry.name);
-                warn!(
-                    logger,
-                    "dynamic library {} required by {}", entry.name, name
-                );
-            }
-        }
     }
 
     warn!(
@@ -339,52 +460,10 @@ pub fn link_libpython(
             fs::write(&out_path, object_data)?;
             build.object(&out_path);
         }
-
-        for library in &em.libraries {
-            warn!(logger, "library {} required by {}", library, name);
-            needed_libraries_external.insert(&library);
-        }
-
-        // TODO do something with library_dirs.
     }
 
-    // Windows requires dynamic linking against msvcrt. Ensure that happens.
-    // TODO this workaround feels like a bug in the Python distribution not
-    // advertising a dependency on the CRT linkage type. Consider adding this
-    // to the distribution metadata.
-    if windows {
-        needed_system_libraries.insert("msvcrt");
-    }
-
-    let mut extra_library_paths = BTreeSet::new();
-
-    for library in needed_libraries.iter() {
-        if OS_IGNORE_LIBRARIES.contains(&library) {
-            continue;
-        }
-
-        // Find the library in the distribution and statically link against it.
-        let fs_path = dist
-            .libraries
-            .get(*library)
-            .unwrap_or_else(|| panic!("unable to find library {}", library));
-
-        extra_library_paths.insert(fs_path.parent().unwrap().to_path_buf());
-
-        cargo_metadata.push(format!("cargo:rustc-link-lib=static={}", library))
-    }
-
-    for framework in needed_frameworks {
-        cargo_metadata.push(format!("cargo:rustc-link-lib=framework={}", framework));
-    }
-
-    for lib in needed_system_libraries {
-        cargo_metadata.push(format!("cargo:rustc-link-lib={}", lib));
-    }
-
-    for lib in needed_libraries_external {
-        cargo_metadata.push(format!("cargo:rustc-link-lib={}", lib));
-    }
+    // Since we disabled cargo metadata lines above.
+    let cargo_metadata = libpython_cargo_metadata(logger, dist, resources, out_dir, target);
 
     // python3-sys uses #[link(name="pythonXY")] attributes heavily on Windows. Its
     // build.rs then remaps ``pythonXY`` to e.g. ``python37``. This causes Cargo to
@@ -406,38 +485,10 @@ pub fn link_libpython(
     build.compile("pythonXY");
     warn!(logger, "libpythonXY created");
 
-    let libpython_path = out_dir.join(if windows {
-        "pythonXY.lib"
-    } else {
-        "libpythonXY.a"
-    });
-
-    cargo_metadata.push("cargo:rustc-link-lib=static=pythonXY".to_string());
-    cargo_metadata.push(format!(
-        "cargo:rustc-link-search=native={}",
-        out_dir.display()
-    ));
-
-    for path in extra_library_paths {
-        cargo_metadata.push(format!("cargo:rustc-link-search=native={}", path.display()));
-    }
-
-    let mut license_infos = BTreeMap::new();
-
-    if let Some(li) = dist.license_infos.get("python") {
-        license_infos.insert("python".to_string(), li.clone());
-    }
-
-    for name in extension_modules.keys() {
-        if let Some(li) = dist.license_infos.get(name) {
-            license_infos.insert(name.clone(), li.clone());
-        }
-    }
-
     Ok(LibpythonInfo {
-        libpython_path,
-        libpyembeddedconfig_path,
+        libpython_path: paths.libpython,
+        libpyembeddedconfig_path: paths.libpyembeddedconfig,
         cargo_metadata,
-        license_infos,
+        license_infos: libpython_license_infos(dist, resources),
     })
 }
@@ -0,0 +1,283 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/*!
+Selecting members of Python distribution archives.
+
+Some operations only need a few members of a distribution archive. Reports
+about a distribution read `PYTHON.json` and license files and otherwise
+only need the names of members. A `MemberSelection` describes the members
+whose content is needed and `read_members()` streams a tar archive,
+holding the content of selected members in memory and recording the name
+and size of every other file, without writing anything to disk.
+
+Selections can also be built from filters given on the command line, which
+are either paths or glob patterns. `extract_members()` unpacks only the
+selected members of an archive.
+*/
+
+use anyhow::{anyhow, Context, Result};
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::Read;
+use std::path::{Component, Path, PathBuf};
+
+use crate::cancel;
+
+/// Members of a distribution archive, by path relative to its root.
+///
+/// A member is selected if its path is or is inside a selected path, or if
+/// it or one of its parent directories matches a selected pattern.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MemberSelection {
+    paths: BTreeSet<PathBuf>,
+    patterns: Vec<glob::Pattern>,
+}
+
+/// Glob matching options. `*` doesn't cross directories.
+const PATTERN_OPTIONS: glob::MatchOptions = glob::MatchOptions {
+    case_sensitive: true,
+    require_literal_separator: true,
+    require_literal_leading_dot: false,
+};
+
+impl MemberSelection {
+    /// Members describing a distribution: `PYTHON.json` and licenses.
+    pub fn metadata() -> Self {
+        let mut selection = MemberSelection::default();
+        selection.include("python/PYTHON.json");
+        selection.include("python/LICENSE.rst");
+        selection.include("python/licenses");
+
+        selection
+    }
+
+    pub fn include<P: Into<PathBuf>>(&mut self, path: P) {
+        self.paths.insert(path.into());
+    }
+
+    /// Include members matching a filter.
+    ///
+    /// Filters containing `*`, `?` or `[` are glob patterns. Other filters
+    /// are paths.
+    pub fn include_filter(&mut self, filter: &str) -> Result<()> {
+        let path = member_path(Path::new(filter));
+
+        if filter.contains(|c: char| c == '*' || c == '?' || c == '[') {
+            let pattern = glob::Pattern::new(&path.display().to_string())
+                .map_err(|e| anyhow!("invalid pattern {}: {}", filter, e))?;
+            self.patterns.push(pattern);
+        } else {
+            self.include(path);
+        }
+
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.paths.is_empty() && self.patterns.is_empty()
+    }
+
+    pub fn contains(&self, path: &Path) -> bool {
+        self.paths.iter().any(|p| path.starts_with(p))
+            || self.patterns.iter().any(|pattern| {
+                path.ancestors()
+                    .any(|p| pattern.matches_path_with(p, PATTERN_OPTIONS))
+            })
+    }
+}
+
+/// Members read from a distribution archive.
+#[derive(Clone, Debug, Default)]
+pub struct ArchiveMembers {
+    /// Content of selected files.
+    pub contents: BTreeMap<PathBuf, Vec<u8>>,
+    /// Every file in the archive and its size.
+    ///
+    /// Links are recorded with size 0.
+    pub files: BTreeMap<PathBuf, u64>,
+}
+
+/// Normalize the path of an archive member, dropping `.` components.
+fn member_path(path: &Path) -> PathBuf {
+    path.components()
+        .filter(|c| *c != Component::CurDir)
+        .collect()
+}
+
+/// Read the members of a tar archive.
+///
+/// Files selected by `selection` are read into memory. Only the names and
+/// sizes of other files are recorded.
+pub fn read_members<R: Read>(source: R, selection: &MemberSelection) -> Result<ArchiveMembers> {
+    let mut tf = tar::Archive::new(source);
+    let mut members = ArchiveMembers::default();
+
+    for entry in tf.entries().context("unable to read tar archive")? {
+        cancel::check_cancelled()?;
+
+        let mut entry = entry.context("unable to read tar archive")?;
+        let entry_type = entry.header().entry_type();
+        if entry_type.is_dir() {
+            continue;
+        }
+
+        let path = member_path(&entry.path().context("unable to read tar archive")?);
+
+        let size = if entry_type.is_file() {
+            entry.header().size()?
+        } else {
+            0
+        };
+
+        if entry_type.is_file() && selection.contains(&path) {
+            let mut data = Vec::with_capacity(size as usize);
+            entry
+                .read_to_end(&mut data)
+                .with_context(|| format!("unable to read {}", path.display()))?;
+            members.contents.insert(path.clone(), data);
+        }
+
+        members.files.insert(path, size);
+    }
+
+    Ok(members)
+}
+
+/// Unpack the selected members of a tar archive into a directory.
+///
+/// Returns the paths of unpacked members. Directories are only unpacked if
+/// selected; parents of unpacked members are created as needed.
+pub fn extract_members<R: Read>(
+    source: R,
+    selection: &MemberSelection,
+    dest_dir: &Path,
+) -> Result<Vec<PathBuf>> {
+    let mut tf = tar::Archive::new(source);
+    let mut extracted = Vec::new();
+
+    std::fs::create_dir_all(dest_dir)
+        .with_context(|| format!("creating {}", dest_dir.display()))?;
+
+    for entry in tf.entries().context("unable to read tar archive")? {
+        cancel::check_cancelled()?;
+
+        let mut entry = entry.context("unable to read tar archive")?;
+        let path = member_path(&entry.path().context("unable to read tar archive")?);
+
+        if !selection.contains(&path) {
+            continue;
+        }
+
+        entry
+            .unpack_in(dest_dir)
+            .with_context(|| format!("unable to extract {}", path.display()))?;
+        extracted.push(path);
+    }
+
+    Ok(extracted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::tar_fixture;
+
+    #[test]
+    fn test_selection() {
+        let selection = MemberSelection::metadata();
+
+        assert!(selection.contains(Path::new("python/PYTHON.json")));
+        assert!(selection.contains(Path::new("python/licenses/LICENSE.bzip2.txt")));
+        assert!(!selection.contains(Path::new("python/licenses.txt")));
+        assert!(!selection.contains(Path::new("python/install/lib/libpython3.7m.a")));
+    }
+
+    #[test]
+    fn test_read_members() -> Result<()> {
+        let data = tar_fixture(
+            &[
+                ("python/PYTHON.json", b"{}"),
+                ("./python/licenses/LICENSE.zlib.txt", b"zlib"),
+                ("python/install/lib/python3.7/os.py", b"import abc\n"),
+            ],
+            &[("python/install/bin/python3", "python3.7")],
+        )?;
+
+        let members = read_members(std::io::Cursor::new(data), &MemberSelection::metadata())?;
+
+        assert_eq!(
+            members.contents.keys().collect::<Vec<_>>(),
+            vec![
+                Path::new("python/PYTHON.json"),
+                Path::new("python/licenses/LICENSE.zlib.txt")
+            ]
+        );
+        assert_eq!(
+            members.contents[Path::new("python/licenses/LICENSE.zlib.txt")],
+            b"zlib"
+        );
+        assert_eq!(
+            members.files.into_iter().collect::<Vec<_>>(),
+            vec![
+                (PathBuf::from("python/PYTHON.json"), 2),
+                (PathBuf::from("python/install/bin/python3"), 0),
+                (PathBuf::from("python/install/lib/python3.7/os.py"), 11),
+                (PathBuf::from("python/licenses/LICENSE.zlib.txt"), 4),
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_filters() -> Result<()> {
+        let mut selection = MemberSelection::default();
+        assert!(selection.is_empty());
+
+        selection.include_filter("./python/PYTHON.json")?;
+        selection.include_filter("python/licenses/*.txt")?;
+        selection.include_filter("python/install/lib/python3.?")?;
+        assert!(!selection.is_empty());
+
+        assert!(selection.contains(Path::new("python/PYTHON.json")));
+        assert!(selection.contains(Path::new("python/licenses/LICENSE.zlib.txt")));
+        assert!(!selection.contains(Path::new("python/licenses/LICENSE.rst")));
+        assert!(selection.contains(Path::new("python/install/lib/python3.7/os.py")));
+        assert!(!selection.contains(Path::new("python/install/lib/libpython3.7m.a")));
+
+        assert!(selection.include_filter("python/[licenses").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_extract_members() -> Result<()> {
+        let data = tar_fixture(
+            &[
+                ("python/PYTHON.json", b"{}"),
+                ("python/licenses/LICENSE.zlib.txt", b"zlib"),
+                ("python/install/lib/python3.7/os.py", b"import abc\n"),
+            ],
+            &[],
+        )?;
+
+        let temp_dir = tempdir::TempDir::new("pyoxidizer-test")?;
+        let mut selection = MemberSelection::default();
+        selection.include_filter("python/licenses/*")?;
+
+        let extracted = extract_members(std::io::Cursor::new(data), &selection, temp_dir.path())?;
+
+        assert_eq!(
+            extracted,
+            vec![PathBuf::from("python/licenses/LICENSE.zlib.txt")]
+        );
+        assert_eq!(
+            std::fs::read(temp_dir.path().join("python/licenses/LICENSE.zlib.txt"))?,
+            b"zlib"
+        );
+        assert!(!temp_dir.path().join("python/PYTHON.json").exists());
+
+        Ok(())
+    }
+}
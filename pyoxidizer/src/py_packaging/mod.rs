@@ -2,15 +2,32 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
+pub mod abi;
+pub mod archive;
 pub mod binary;
+pub mod build_system;
 pub mod bytecode;
+pub mod bytecode_cache;
+pub mod capabilities;
+pub mod compatibility;
 pub mod config;
 pub mod distribution;
 pub mod distutils;
+pub mod download;
+pub mod embeddable;
 pub mod embedded_resource;
 pub mod filtering;
 pub mod fsscan;
+pub mod import_profile;
 pub mod libpython;
+pub mod members;
 pub mod pip;
+pub mod pkg_resources_compat;
 pub mod pyembed;
+pub mod references;
+pub mod requirement;
 pub mod resource;
+pub mod retention;
+pub mod rust_extension;
+pub mod sqlite;
+pub mod transform;
@@ -3,48 +3,286 @@
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
 use anyhow::{anyhow, Result};
+use sha2::Digest;
 use slog::warn;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::convert::TryFrom;
 use std::io::{BufRead, BufReader};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
+use super::abi::{check_artifacts_compatible, major_minor};
+use super::build_system::{
+    check_compiled_builds, compiled_build_envs, cross_compile_error, find_compiled_build,
+    is_cross_compiling, read_refused_builds, CompiledBuild,
+};
 use super::distribution::ParsedPythonDistribution;
 use super::distutils::{prepare_hacked_distutils, read_built_extensions};
 use super::fsscan::{find_python_resources, PythonFileResource};
+use super::pkg_resources_compat::dist_info_resources;
+use super::requirement::{
+    find_installed_distributions, find_unconstrained_distributions, find_version_mismatches,
+    normalize_name, requirements_from_pip_args,
+};
 use super::resource::PythonResource;
+use crate::build_stats::{self, Cache};
+use crate::cancel;
+use crate::color::{self, ColorChoice};
+
+/// How to react to pip installing versions not matching requirements.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum VersionMismatchPolicy {
+    Error,
+    Warn,
+}
+
+/// Result of running `pip install`.
+#[derive(Debug)]
+pub struct PipInstallResult {
+    /// Python resources installed.
+    pub resources: Vec<PythonResource>,
+    /// Installed packages and their versions.
+    pub versions: BTreeMap<String, String>,
+    /// Installed packages and the SHA-256 of their `RECORD` file.
+    ///
+    /// `RECORD` lists the digest of every installed file, so this identifies
+    /// the installed content of a package.
+    pub hashes: BTreeMap<String, String>,
+}
+
+/// Compute the SHA-256 of the `RECORD` file of distributions installed in a directory.
+///
+/// `installed` is the result of `find_installed_distributions()`. Keys of the
+/// returned map are package names as reported by the package metadata.
+/// Distributions without a `RECORD` file are omitted.
+fn installed_record_hashes(
+    path: &Path,
+    installed: &BTreeMap<String, (String, String)>,
+) -> Result<BTreeMap<String, String>> {
+    let mut res = BTreeMap::new();
+
+    for entry in std::fs::read_dir(path)? {
+        let entry = entry?;
+        let dir_name = entry.file_name().to_string_lossy().to_string();
+
+        if !dir_name.ends_with(".dist-info") {
+            continue;
+        }
+
+        let stem = &dir_name[..dir_name.len() - ".dist-info".len()];
+        let name = normalize_name(stem.splitn(2, '-').next().unwrap());
+
+        let display_name = match installed.get(&name) {
+            Some((display_name, _)) => display_name,
+            None => continue,
+        };
+
+        if let Ok(data) = std::fs::read(entry.path().join("RECORD")) {
+            let mut hasher = sha2::Sha256::new();
+            hasher.input(&data);
+            res.insert(display_name.clone(), hex::encode(hasher.result()));
+        }
+    }
+
+    Ok(res)
+}
+
+/// Arguments running pip, before its command.
+///
+/// `color` is `Never` or `Always`.
+fn pip_global_args(verbose: bool, color: ColorChoice) -> Vec<String> {
+    let mut args = vec![
+        "-m".to_string(),
+        "pip".to_string(),
+        "--disable-pip-version-check".to_string(),
+        // pip's output is captured, so nobody can answer a prompt.
+        "--no-input".to_string(),
+    ];
+
+    // pip colors when writing to a terminal and has no way to force it.
+    if color == ColorChoice::Never {
+        args.push("--no-color".to_string());
+    }
+
+    if verbose {
+        args.push("--verbose".to_string());
+    }
+
+    args
+}
+
+/// Execute a `python` executable and obtain its implementation and version.
+///
+/// e.g. `cpython 3.7`.
+fn probe_interpreter(python_exe: &Path) -> Result<String> {
+    let output = std::process::Command::new(python_exe)
+        .args(&[
+            "-c",
+            "import sys; print(sys.implementation.name, '%d.%d' % sys.version_info[:2])",
+        ])
+        .output()
+        .map_err(|e| anyhow!("unable to run {}: {}", python_exe.display(), e))?;
+
+    if !output.status.success() {
+        return Err(anyhow!(
+            "{} exited with {}: {}",
+            python_exe.display(),
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+/// Arguments pinning the Python pip resolves packages for to a distribution.
+///
+/// pip only accepts pinning arguments when it won't build from source or
+/// won't install dependencies, so they are only returned if `install_args`
+/// request either. Otherwise pip resolves packages for the interpreter
+/// running it. `interpreter` probes that interpreter, which must then be
+/// the distribution's Python, so resolution can't silently target another.
+fn pip_target_args<F>(install_args: &[String], version: &str, interpreter: F) -> Result<Vec<String>>
+where
+    F: FnOnce() -> Result<String>,
+{
+    let binary_only = install_args.iter().enumerate().any(|(i, arg)| {
+        arg == "--no-deps"
+            || arg == "--only-binary=:all:"
+            || (arg == "--only-binary"
+                && install_args.get(i + 1).map(|s| s.as_str()) == Some(":all:"))
+    });
+
+    let (major, minor) = major_minor(version)?;
+
+    if !binary_only {
+        let expected = format!("cpython {}.{}", major, minor);
+        let guidance = format!(
+            "pass --no-deps or --only-binary=:all: to pip_install() so packages are resolved for Python {}.{} regardless",
+            major, minor
+        );
+
+        return match interpreter() {
+            Ok(ref found) if *found == expected => Ok(Vec::new()),
+            Ok(found) => Err(anyhow!(
+                "pip would resolve packages for {} rather than the {} distribution; {}",
+                found,
+                expected,
+                guidance
+            )),
+            Err(e) => Err(anyhow!(
+                "unable to verify pip resolves packages for the {} distribution: {}; {}",
+                expected,
+                e,
+                guidance
+            )),
+        };
+    }
+
+    Ok(vec![
+        "--python-version".to_string(),
+        format!("{}.{}", major, minor),
+        "--implementation".to_string(),
+        "cp".to_string(),
+    ])
+}
+
+/// Find local source trees in `pip install` arguments compiling extension modules.
+fn find_compiled_builds(install_args: &[String]) -> Result<Vec<CompiledBuild>> {
+    let mut res = Vec::new();
+
+    for arg in install_args {
+        let path = if arg.starts_with("--editable=") {
+            &arg["--editable=".len()..]
+        } else if arg.starts_with('-') {
+            continue;
+        } else {
+            arg.as_str()
+        };
+
+        if let Some(build) = find_compiled_build(Path::new(path))? {
+            res.push(build);
+        }
+    }
+
+    Ok(res)
+}
 
 /// Run `pip install` and return found resources.
+///
+/// The versions of installed packages are compared against the version
+/// specifiers of requirements in `install_args`, including requirements and
+/// constraints files.
+///
+/// `constraints` is a constraints file passed to pip with `-c`. Installed
+/// packages it doesn't pin are reported, as they may resolve differently in
+/// future builds.
+///
+/// Packages compiling extension modules are compiled against `dist`. They
+/// are rejected if `target` isn't the machine we run on.
+///
+/// If `offline`, pip doesn't use package indexes.
+#[allow(clippy::too_many_arguments)]
 pub fn pip_install(
     logger: &slog::Logger,
     dist: &ParsedPythonDistribution,
+    target: &str,
     verbose: bool,
+    offline: bool,
     install_args: &[String],
+    constraints: Option<&Path>,
     extra_envs: &HashMap<String, String>,
-) -> Result<Vec<PythonResource>> {
+    verify_versions: VersionMismatchPolicy,
+) -> Result<PipInstallResult> {
+    let constraints_args = match constraints {
+        Some(path) => vec!["-c".to_string(), path.display().to_string()],
+        None => Vec::new(),
+    };
+    let install_args = install_args
+        .iter()
+        .chain(constraints_args.iter())
+        .cloned()
+        .collect::<Vec<_>>();
+    let install_args = install_args.as_slice();
+
+    let requirements = requirements_from_pip_args(install_args)?;
+
+    let host = env!("HOST");
+    check_compiled_builds(logger, &find_compiled_builds(install_args)?, host, target)?;
+
     let temp_dir = tempdir::TempDir::new("pyoxidizer-pip-install")?;
 
     dist.ensure_pip(logger);
 
     let mut env = prepare_hacked_distutils(logger, dist, temp_dir.path(), &[])?;
+    let state_dir = PathBuf::from(env.get("PYOXIDIZER_DISTUTILS_STATE_DIR").unwrap());
+
+    if is_cross_compiling(host, target) {
+        env.insert("PYOXIDIZER_CROSS_COMPILING".to_string(), "1".to_string());
+    } else {
+        env.extend(compiled_build_envs(dist));
+    }
 
     for (key, value) in extra_envs.iter() {
         env.insert(key.clone(), value.clone());
     }
 
+    // Packages must then come from local paths or `--find-links`.
+    if offline || crate::environment::frozen() {
+        env.insert("PIP_NO_INDEX".to_string(), "1".to_string());
+    }
+
+    // pip's output is logged, so keep escape sequences out of it. Build
+    // backends are told too.
+    let pip_color = color::child_color(true, false);
+    for (key, value) in pip_color.child_envs() {
+        env.insert(key.to_string(), value.to_string());
+    }
+
     let target_dir = temp_dir.path().join("install");
 
     warn!(logger, "pip installing to {}", target_dir.display());
 
-    let mut pip_args: Vec<String> = vec![
-        "-m".to_string(),
-        "pip".to_string(),
-        "--disable-pip-version-check".to_string(),
-    ];
-
-    if verbose {
-        pip_args.push("--verbose".to_string());
-    }
+    let mut pip_args = pip_global_args(verbose, pip_color);
 
     pip_args.extend(vec![
         "install".to_string(),
@@ -52,28 +290,94 @@ pub fn pip_install(
         format!("{}", target_dir.display()),
     ]);
 
+    pip_args.extend(pip_target_args(install_args, &dist.version, || {
+        probe_interpreter(&dist.python_exe)
+    })?);
     pip_args.extend(install_args.iter().map(|x| x.clone()));
 
     // TODO send stderr to stdout
-    let mut cmd = std::process::Command::new(&dist.python_exe)
-        .args(&pip_args)
-        .envs(&env)
-        .stdout(std::process::Stdio::piped())
-        .spawn()?;
+    let mut cmd = cancel::spawn(
+        std::process::Command::new(&dist.python_exe)
+            .args(&pip_args)
+            .envs(&env)
+            .stdout(std::process::Stdio::piped()),
+    )?;
     {
-        let stdout = cmd.stdout.as_mut().ok_or(anyhow!("unable to get stdout"))?;
+        let stdout = cmd
+            .child_mut()
+            .stdout
+            .as_mut()
+            .ok_or(anyhow!("unable to get stdout"))?;
         let reader = BufReader::new(stdout);
 
+        let (mut cached, mut downloaded) = (0, 0);
+
         for line in reader.lines() {
-            warn!(logger, "{}", line?);
+            let line = line?;
+
+            // pip doesn't report the use of its cache otherwise.
+            let action = line.trim_start();
+            if action.starts_with("Using cached ") {
+                cached += 1;
+            } else if action.starts_with("Downloading ") {
+                downloaded += 1;
+            }
+
+            warn!(logger, "{}", line);
         }
+
+        build_stats::record_cache(Cache::Pip, cached, downloaded);
     }
 
-    let status = cmd.wait().unwrap();
+    let status = cmd.wait()?;
     if !status.success() {
+        let refused = read_refused_builds(&state_dir)?;
+
+        if !refused.is_empty() {
+            return Err(cross_compile_error(&refused, host, target));
+        }
+
         return Err(anyhow!("error running pip"));
     }
 
+    check_artifacts_compatible(&target_dir, &dist.version)?;
+
+    let installed = find_installed_distributions(&target_dir)?;
+    let hashes = installed_record_hashes(&target_dir, &installed)?;
+    let mismatches = find_version_mismatches(&requirements, &installed);
+
+    if !mismatches.is_empty() {
+        let message = format!(
+            "pip installed versions not matching requirements:\n  {}",
+            mismatches
+                .iter()
+                .map(|m| m.to_string())
+                .collect::<Vec<String>>()
+                .join("\n  ")
+        );
+
+        match verify_versions {
+            VersionMismatchPolicy::Error => return Err(anyhow!(message)),
+            VersionMismatchPolicy::Warn => warn!(logger, "{}", message),
+        }
+    }
+
+    if constraints.is_some() {
+        let unconstrained = find_unconstrained_distributions(
+            &requirements_from_pip_args(&constraints_args)?,
+            &installed,
+        );
+
+        if !unconstrained.is_empty() {
+            warn!(
+                logger,
+                "pip installed packages not pinned by constraints file {}: {}; run `pyoxidizer freeze-deps` to update it",
+                constraints_args[1],
+                unconstrained.join(", ")
+            );
+        }
+    }
+
     let mut res = Vec::new();
 
     for r in find_python_resources(&target_dir) {
@@ -90,10 +394,136 @@ pub fn pip_install(
         }
     }
 
-    let state_dir = PathBuf::from(env.get("PYOXIDIZER_DISTUTILS_STATE_DIR").unwrap());
     for ext in read_built_extensions(&state_dir)? {
         res.push(PythonResource::BuiltExtensionModule(ext));
     }
 
-    Ok(res)
+    // Only embedded if the executable opts into pkg_resources support.
+    for resource in dist_info_resources(&target_dir)? {
+        res.push(resource.as_python_resource());
+    }
+
+    Ok(PipInstallResult {
+        resources: res,
+        versions: installed.into_iter().map(|(_, v)| v).collect(),
+        hashes,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::*;
+
+    fn args(values: &[&str]) -> Vec<String> {
+        values.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn test_pip_target_args() -> Result<()> {
+        let pinned = args(&["--python-version", "3.7", "--implementation", "cp"]);
+        let matching = || Ok("cpython 3.7".to_string());
+        let unprobed = || -> Result<String> { panic!("interpreter probed") };
+
+        assert!(pip_target_args(&args(&["black==19.10b0"]), "3.7.5", matching)?.is_empty());
+        assert_eq!(
+            pip_target_args(&args(&["--no-deps", "black==19.10b0"]), "3.7.5", unprobed)?,
+            pinned
+        );
+        assert_eq!(
+            pip_target_args(&args(&["--only-binary=:all:", "black"]), "3.7.5", unprobed)?,
+            pinned
+        );
+        assert_eq!(
+            pip_target_args(
+                &args(&["--only-binary", ":all:", "black"]),
+                "3.7.5",
+                unprobed
+            )?,
+            pinned
+        );
+        assert!(pip_target_args(
+            &args(&["--only-binary", "black", "black"]),
+            "3.7.5",
+            matching
+        )?
+        .is_empty());
+
+        // Without pinning arguments, pip's interpreter must be the
+        // distribution's Python.
+        let err = pip_target_args(&args(&["black"]), "3.7.5", || Ok("cpython 3.9".to_string()))
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "pip would resolve packages for cpython 3.9 rather than the cpython 3.7 distribution; pass --no-deps or --only-binary=:all: to pip_install() so packages are resolved for Python 3.7 regardless"
+        );
+
+        let err = pip_target_args(&args(&["black"]), "3.7.5", || {
+            Err(anyhow!("exec format error"))
+        })
+        .unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("unable to verify pip resolves packages for the cpython 3.7 distribution: exec format error; pass --no-deps"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_probe_interpreter() -> Result<()> {
+        let dist = get_default_distribution()?;
+
+        let (major, minor) = major_minor(&dist.version)?;
+        assert_eq!(
+            probe_interpreter(&dist.python_exe)?,
+            format!("cpython {}.{}", major, minor)
+        );
+        assert!(probe_interpreter(Path::new("/nonexistent/python")).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_pip_global_args_color() {
+        let env = |key: &str| {
+            if key == color::CLICOLOR_FORCE_ENV {
+                Some("1".to_string())
+            } else {
+                None
+            }
+        };
+
+        // pip's output is captured, so it never uses colors.
+        for choice in &[ColorChoice::Auto, ColorChoice::Always, ColorChoice::Never] {
+            for is_terminal in &[false, true] {
+                let pip_color = choice.for_child(env, true, *is_terminal);
+
+                assert_eq!(
+                    pip_global_args(false, pip_color),
+                    args(&[
+                        "-m",
+                        "pip",
+                        "--disable-pip-version-check",
+                        "--no-input",
+                        "--no-color"
+                    ])
+                );
+                assert_eq!(
+                    pip_color.child_envs(),
+                    vec![("NO_COLOR", "1"), ("CLICOLOR_FORCE", "0")]
+                );
+            }
+        }
+
+        assert_eq!(
+            pip_global_args(true, ColorChoice::Always),
+            args(&[
+                "-m",
+                "pip",
+                "--disable-pip-version-check",
+                "--no-input",
+                "--verbose"
+            ])
+        );
+    }
 }
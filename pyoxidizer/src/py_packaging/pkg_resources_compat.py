@@ -0,0 +1,260 @@
+# This Source Code Form is subject to the terms of the Mozilla Public
+# License, v. 2.0. If a copy of the MPL was not distributed with this
+# file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+"""pkg_resources support for modules imported from memory.
+
+PyOxidizer embeds this module as ``_oxidized_pkg_resources`` in executables
+configured with ``pkg_resources_compat=True`` and ``pyembed`` calls
+``install()`` during interpreter initialization.
+
+Once ``pkg_resources`` is imported, a provider is registered for modules
+loaded by the in-memory importer, so ``resource_string()``,
+``resource_stream()`` and friends read embedded resources.
+``resource_filename()`` extracts resources into the ``pkg_resources``
+extraction directory on demand. Distributions whose ``.dist-info`` metadata
+PyOxidizer embedded are added to the working set, so
+``get_distribution()`` and ``iter_entry_points()`` find them.
+"""
+
+import io
+import os
+import sys
+
+#: Package whose resources are ``.dist-info`` metadata of installed packages.
+#:
+#: This needs to be kept in sync with ``DIST_INFO_PACKAGE``.
+DIST_INFO_PACKAGE = "_oxidized_dist_info"
+
+
+def _oxidized_finder():
+    """Find the in-memory importer on ``sys.meta_path``."""
+    for finder in sys.meta_path:
+        if type(finder).__name__ == "PyOxidizerFinder":
+            return finder
+
+    return None
+
+
+def _resource_names(reader):
+    if reader is None:
+        return []
+
+    return sorted(reader.contents())
+
+
+def _provider_class(pkg_resources):
+    class OxidizedProvider(pkg_resources.NullProvider):
+        """Provides resources of modules imported from memory."""
+
+        def __init__(self, module):
+            pkg_resources.NullProvider.__init__(self, module)
+
+            # Resources of a module are those of its package.
+            name = module.__name__
+            if hasattr(module, "__path__"):
+                self._package = name
+            else:
+                self._package = name.rpartition(".")[0]
+
+            self._reader = None
+            if self._package:
+                self._reader = self.loader.get_resource_reader(self._package)
+
+            self._names = _resource_names(self._reader)
+
+        def _name(self, resource_name):
+            return "/".join(p for p in resource_name.split("/") if p not in ("", "."))
+
+        def _isdir(self, name):
+            prefix = name + "/" if name else ""
+            return any(n.startswith(prefix) for n in self._names)
+
+        def has_resource(self, resource_name):
+            name = self._name(resource_name)
+            return name in self._names or self._isdir(name)
+
+        def resource_isdir(self, resource_name):
+            name = self._name(resource_name)
+            return name not in self._names and self._isdir(name)
+
+        def resource_listdir(self, resource_name):
+            name = self._name(resource_name)
+            prefix = name + "/" if name else ""
+
+            entries = []
+            for n in self._names:
+                if n.startswith(prefix):
+                    entry = n[len(prefix) :].split("/")[0]
+                    if entry not in entries:
+                        entries.append(entry)
+
+            return entries
+
+        def get_resource_string(self, manager, resource_name):
+            name = self._name(resource_name)
+            if self._reader is None or name not in self._names:
+                raise FileNotFoundError(
+                    "%s has no resource named %s" % (self._package, resource_name)
+                )
+
+            with self._reader.open_resource(name) as fh:
+                return fh.read()
+
+        def get_resource_stream(self, manager, resource_name):
+            return io.BytesIO(self.get_resource_string(manager, resource_name))
+
+        def get_resource_filename(self, manager, resource_name):
+            name = self._name(resource_name)
+
+            if self.resource_isdir(name):
+                for n in self._names:
+                    if n.startswith(name + "/"):
+                        self._extract(manager, n)
+
+                return self._cache_path(manager, name)
+
+            return self._extract(manager, name)
+
+        def _cache_path(self, manager, name):
+            import oxidized_app
+
+            key = oxidized_app.build_id or oxidized_app.resources_digest or "unknown"
+            parts = self._package.split(".") + [p for p in name.split("/") if p]
+
+            return manager.get_cache_path("oxidized-%s" % key[:16], parts)
+
+        def _extract(self, manager, name):
+            data = self.get_resource_string(manager, name)
+            path = self._cache_path(manager, name)
+
+            # Content is identified by the build, so existing files are current.
+            if os.path.isfile(path) and os.path.getsize(path) == len(data):
+                return path
+
+            temp_path = "%s.%d.tmp" % (path, os.getpid())
+            with open(temp_path, "wb") as fh:
+                fh.write(data)
+            manager.postprocess(temp_path, path)
+            os.replace(temp_path, path)
+
+            return path
+
+    return OxidizedProvider
+
+
+def _metadata_class(pkg_resources):
+    class DistInfoMetadata(pkg_resources.EmptyProvider):
+        """Provides embedded ``.dist-info`` metadata of a distribution."""
+
+        def __init__(self, reader, names, dist_info):
+            pkg_resources.EmptyProvider.__init__(self)
+            self._reader = reader
+            self._prefix = dist_info + "/"
+            self._names = [n[len(self._prefix) :] for n in names if n.startswith(self._prefix)]
+
+        def has_metadata(self, name):
+            return name in self._names
+
+        def get_metadata(self, name):
+            if name not in self._names:
+                raise FileNotFoundError(name)
+
+            with self._reader.open_resource(self._prefix + name) as fh:
+                return fh.read().decode("utf-8")
+
+        def get_metadata_lines(self, name):
+            return pkg_resources.yield_lines(self.get_metadata(name))
+
+        def metadata_isdir(self, name):
+            return False
+
+        def metadata_listdir(self, name):
+            return []
+
+    return DistInfoMetadata
+
+
+def _distributions(pkg_resources, finder):
+    """Obtain distributions whose metadata is embedded."""
+    try:
+        reader = finder.get_resource_reader(DIST_INFO_PACKAGE)
+    except Exception:
+        reader = None
+
+    names = _resource_names(reader)
+    metadata_class = _metadata_class(pkg_resources)
+
+    dist_infos = sorted(
+        set(n.split("/")[0] for n in names if n.split("/")[0].endswith(".dist-info"))
+    )
+
+    return [
+        pkg_resources.Distribution.from_location(
+            sys.executable, dist_info, metadata=metadata_class(reader, names, dist_info)
+        )
+        for dist_info in dist_infos
+    ]
+
+
+def _register(pkg_resources):
+    finder = _oxidized_finder()
+    if finder is None:
+        return
+
+    pkg_resources.register_loader_type(type(finder), _provider_class(pkg_resources))
+
+    for dist in _distributions(pkg_resources, finder):
+        pkg_resources.working_set.add(dist, entry=dist.location)
+
+
+class _InstallingLoader:
+    """Loader registering the shim once ``pkg_resources`` is executed."""
+
+    def __init__(self, loader):
+        self._loader = loader
+
+    def __getattr__(self, name):
+        return getattr(self._loader, name)
+
+    def create_module(self, spec):
+        create_module = getattr(self._loader, "create_module", None)
+        return create_module(spec) if create_module else None
+
+    def exec_module(self, module):
+        # pkg_resources finds providers by loader, so hide ourselves.
+        module.__loader__ = self._loader
+        module.__spec__.loader = self._loader
+
+        self._loader.exec_module(module)
+        _register(module)
+
+
+class _ImportHook:
+    """Meta path finder wrapping the loader of ``pkg_resources``."""
+
+    def find_spec(self, fullname, path, target=None):
+        if fullname != "pkg_resources":
+            return None
+
+        import importlib.util
+
+        # pkg_resources is only imported once, so we're done either way.
+        sys.meta_path.remove(self)
+
+        spec = importlib.util.find_spec(fullname)
+        if spec is not None and spec.loader is not None:
+            spec.loader = _InstallingLoader(spec.loader)
+
+        return spec
+
+
+def install():
+    """Register the shim, now or once ``pkg_resources`` is imported.
+
+    Importing ``pkg_resources`` is slow, so it isn't imported by us.
+    """
+    if "pkg_resources" in sys.modules:
+        _register(sys.modules["pkg_resources"])
+    else:
+        sys.meta_path.insert(0, _ImportHook())
@@ -0,0 +1,312 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/*!
+Support for `pkg_resources` in applications importing from memory.
+
+`pkg_resources` only knows how to find resources and distribution metadata
+of packages on the filesystem. With `pkg_resources_compat=True`, the
+`_oxidized_pkg_resources` module is embedded and installed at start-up and
+teaches `pkg_resources` about the in-memory importer.
+
+Packages installed with pip have their `.dist-info` metadata collected as
+resources of a reserved package, which backs `get_distribution()` and
+`iter_entry_points()`. Executables not opting in don't embed the shim nor
+the metadata.
+*/
+
+use anyhow::{Context, Result};
+use std::path::Path;
+
+use super::embedded_resource::EmbeddedPythonResourcesPrePackaged;
+use super::resource::{BytecodeOptimizationLevel, ResourceData, SourceModule};
+
+/// Name of the module installing the shim.
+///
+/// This needs to be kept in sync with `pyinterp.rs`.
+pub const PKG_RESOURCES_COMPAT_MODULE: &str = "_oxidized_pkg_resources";
+
+/// Package whose resources are `.dist-info` metadata of installed packages.
+///
+/// This needs to be kept in sync with `pkg_resources_compat.py`.
+pub const DIST_INFO_PACKAGE: &str = "_oxidized_dist_info";
+
+const PKG_RESOURCES_COMPAT_SOURCE: &str = include_str!("pkg_resources_compat.py");
+
+/// Collect the `.dist-info` metadata of distributions installed in a directory.
+///
+/// Resources are named `<distribution>.dist-info/<file>`. `RECORD` is
+/// omitted, as the paths it lists don't exist in an executable.
+pub fn dist_info_resources(root: &Path) -> Result<Vec<ResourceData>> {
+    let mut res = Vec::new();
+
+    if !root.is_dir() {
+        return Ok(res);
+    }
+
+    let mut entries = std::fs::read_dir(root)?.collect::<std::io::Result<Vec<_>>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+
+    for entry in entries {
+        let dir_name = entry.file_name().to_string_lossy().to_string();
+
+        if !dir_name.ends_with(".dist-info") || !entry.path().is_dir() {
+            continue;
+        }
+
+        for file in
+            walkdir::WalkDir::new(entry.path()).sort_by(|a, b| a.file_name().cmp(b.file_name()))
+        {
+            let file = file?;
+
+            if !file.file_type().is_file() || file.file_name() == "RECORD" {
+                continue;
+            }
+
+            let rel_path = file.path().strip_prefix(entry.path())?;
+            let name = rel_path
+                .components()
+                .map(|c| c.as_os_str().to_string_lossy().to_string())
+                .collect::<Vec<_>>()
+                .join("/");
+
+            res.push(ResourceData {
+                package: DIST_INFO_PACKAGE.to_string(),
+                name: format!("{}/{}", dir_name, name),
+                data: std::fs::read(file.path())
+                    .with_context(|| format!("reading {}", file.path().display()))?,
+            });
+        }
+    }
+
+    Ok(res)
+}
+
+/// Embed the shim if enabled, or drop collected metadata otherwise.
+pub fn apply_pkg_resources_compat(
+    resources: &mut EmbeddedPythonResourcesPrePackaged,
+    enabled: bool,
+) {
+    if enabled {
+        let module = SourceModule {
+            name: PKG_RESOURCES_COMPAT_MODULE.to_string(),
+            source: PKG_RESOURCES_COMPAT_SOURCE.as_bytes().to_vec(),
+            is_package: false,
+        };
+
+        resources.add_source_module(&module);
+        resources.add_bytecode_module(&module.as_bytecode_module(BytecodeOptimizationLevel::Zero));
+    } else {
+        resources.resources.remove(DIST_INFO_PACKAGE);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::*;
+
+    const APP: &str = "import pkg_resources\n\
+                       print(pkg_resources.resource_string('app', 'data/greeting.txt').decode())\n\
+                       with pkg_resources.resource_stream('app.sub', 'template.txt') as fh:\n\
+                       \x20   print(fh.read().decode())\n\
+                       print(pkg_resources.resource_exists('app', 'data/greeting.txt'), pkg_resources.resource_isdir('app', 'data'))\n\
+                       print(pkg_resources.resource_listdir('app', 'data'))\n\
+                       path = pkg_resources.resource_filename('app', 'data/greeting.txt')\n\
+                       print(open(path).read())\n\
+                       print(pkg_resources.get_distribution('app').version)\n\
+                       for ep in pkg_resources.iter_entry_points('app.plugins'):\n\
+                       \x20   print(ep.name, ep.load()())\n";
+
+    // Emulates the in-memory importer, serving modules and resources from
+    // a directory unknown to pkg_resources.
+    const IMPORTER: &str = "import importlib.abc, importlib.util, os, sys, types\n\
+                            root = sys.argv[1]\n\
+                            oxidized_app = types.ModuleType('oxidized_app')\n\
+                            oxidized_app.build_id = 'abcdef'\n\
+                            oxidized_app.resources_digest = None\n\
+                            sys.modules['oxidized_app'] = oxidized_app\n\
+                            class Reader:\n\
+                            \x20   def __init__(self, package):\n\
+                            \x20       self.dir = os.path.join(root, 'resources', package)\n\
+                            \x20   def open_resource(self, name):\n\
+                            \x20       return open(os.path.join(self.dir, name), 'rb')\n\
+                            \x20   def contents(self):\n\
+                            \x20       return [os.path.relpath(os.path.join(d, f), self.dir).replace(os.sep, '/') for d, _, fs in os.walk(self.dir) for f in fs]\n\
+                            class PyOxidizerFinder(importlib.abc.MetaPathFinder, importlib.abc.Loader):\n\
+                            \x20   def find_spec(self, name, path, target=None):\n\
+                            \x20       for suffix, package in (('__init__.py', True), ('.py', False)):\n\
+                            \x20           p = os.path.join(root, 'modules', name.replace('.', os.sep) + ('/' if package else '') + suffix.lstrip('/'))\n\
+                            \x20           if os.path.exists(p):\n\
+                            \x20               return importlib.util.spec_from_loader(name, self, is_package=package)\n\
+                            \x20   def exec_module(self, module):\n\
+                            \x20       base = os.path.join(root, 'modules', module.__name__.replace('.', os.sep))\n\
+                            \x20       p = os.path.join(base, '__init__.py') if os.path.isdir(base) else base + '.py'\n\
+                            \x20       exec(open(p).read(), module.__dict__)\n\
+                            \x20   def get_resource_reader(self, package):\n\
+                            \x20       return Reader(package) if os.path.isdir(os.path.join(root, 'resources', package)) else None\n\
+                            sys.meta_path.insert(0, PyOxidizerFinder())\n\
+                            shim = types.ModuleType('_oxidized_pkg_resources')\n\
+                            exec(open(os.path.join(root, 'shim.py')).read(), shim.__dict__)\n\
+                            shim.install()\n\
+                            exec(open(os.path.join(root, 'app.py')).read(), {})\n";
+
+    fn write_file(path: &Path, data: &[u8]) -> Result<()> {
+        std::fs::create_dir_all(path.parent().unwrap())?;
+        std::fs::write(path, data)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_dist_info_resources() -> Result<()> {
+        let temp_dir = tempdir::TempDir::new("pyoxidizer-test")?;
+        let dir = temp_dir.path();
+
+        write_file(
+            &dir.join("app-1.0.dist-info").join("METADATA"),
+            b"Name: app\n",
+        )?;
+        write_file(
+            &dir.join("app-1.0.dist-info").join("RECORD"),
+            b"app/__init__.py,,\n",
+        )?;
+        write_file(
+            &dir.join("app-1.0.dist-info")
+                .join("licenses")
+                .join("LICENSE"),
+            b"MIT",
+        )?;
+        write_file(&dir.join("app").join("__init__.py"), b"")?;
+
+        let resources = dist_info_resources(dir)?;
+        assert_eq!(
+            resources
+                .iter()
+                .map(|r| (r.package.as_str(), r.name.as_str(), r.data.as_slice()))
+                .collect::<Vec<_>>(),
+            vec![
+                (
+                    DIST_INFO_PACKAGE,
+                    "app-1.0.dist-info/METADATA",
+                    b"Name: app\n".as_ref()
+                ),
+                (
+                    DIST_INFO_PACKAGE,
+                    "app-1.0.dist-info/licenses/LICENSE",
+                    b"MIT".as_ref()
+                ),
+            ]
+        );
+
+        assert!(dist_info_resources(&dir.join("missing"))?.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_apply_pkg_resources_compat() {
+        let mut resources = EmbeddedPythonResourcesPrePackaged::default();
+        resources.add_resource(&ResourceData {
+            package: DIST_INFO_PACKAGE.to_string(),
+            name: "app-1.0.dist-info/METADATA".to_string(),
+            data: b"Name: app\n".to_vec(),
+        });
+
+        let mut enabled = resources.clone();
+        apply_pkg_resources_compat(&mut enabled, true);
+        assert!(enabled
+            .source_modules
+            .contains_key(PKG_RESOURCES_COMPAT_MODULE));
+        assert!(enabled
+            .bytecode_modules
+            .contains_key(PKG_RESOURCES_COMPAT_MODULE));
+        assert!(enabled.resources.contains_key(DIST_INFO_PACKAGE));
+
+        apply_pkg_resources_compat(&mut resources, false);
+        assert!(resources.resource_names().is_empty());
+    }
+
+    #[test]
+    fn test_pkg_resources_shim() -> Result<()> {
+        let dist = get_default_distribution()?;
+        let logger = get_logger()?;
+        dist.ensure_pip(&logger);
+
+        let temp_dir = tempdir::TempDir::new("pyoxidizer-test")?;
+        let dir = temp_dir.path();
+        let cache_dir = dir.join("cache");
+
+        write_file(&dir.join("shim.py"), PKG_RESOURCES_COMPAT_SOURCE.as_bytes())?;
+        write_file(&dir.join("importer.py"), IMPORTER.as_bytes())?;
+        write_file(&dir.join("app.py"), APP.as_bytes())?;
+
+        let modules = dir.join("modules").join("app");
+        write_file(&modules.join("__init__.py"), b"")?;
+        write_file(
+            &modules.join("plugins.py"),
+            b"def hello():\n    return 'hello plugin'\n",
+        )?;
+        write_file(&modules.join("sub").join("__init__.py"), b"")?;
+
+        let resources = dir.join("resources");
+        write_file(
+            &resources.join("app").join("data").join("greeting.txt"),
+            b"hello",
+        )?;
+        write_file(
+            &resources.join("app.sub").join("template.txt"),
+            b"Dear {name}",
+        )?;
+
+        let dist_info = dir.join("site-packages").join("app-1.0.dist-info");
+        write_file(
+            &dist_info.join("METADATA"),
+            b"Metadata-Version: 2.1\nName: app\nVersion: 1.0\n",
+        )?;
+        write_file(
+            &dist_info.join("entry_points.txt"),
+            b"[app.plugins]\nhello = app.plugins:hello\n",
+        )?;
+        for r in dist_info_resources(&dir.join("site-packages"))? {
+            write_file(&resources.join(&r.package).join(&r.name), &r.data)?;
+        }
+
+        let output = std::process::Command::new(&dist.python_exe)
+            .arg(dir.join("importer.py"))
+            .arg(dir)
+            .env("PYTHON_EGG_CACHE", &cache_dir)
+            .output()?;
+        assert!(
+            output.status.success(),
+            "{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+
+        assert_eq!(
+            String::from_utf8_lossy(&output.stdout)
+                .lines()
+                .collect::<Vec<_>>(),
+            vec![
+                "hello",
+                "Dear {name}",
+                "True True",
+                "['greeting.txt']",
+                "hello",
+                "1.0",
+                "hello hello plugin"
+            ]
+        );
+
+        // resource_filename() extracted the resource.
+        assert!(cache_dir
+            .join("oxidized-abcdef-tmp")
+            .join("app")
+            .join("data")
+            .join("greeting.txt")
+            .is_file());
+
+        Ok(())
+    }
+}
@@ -4,20 +4,119 @@
 
 use anyhow::Result;
 use itertools::Itertools;
+use sha2::Digest;
+use std::collections::BTreeMap;
 use std::fs::File;
 use std::io::Write;
 use std::path::{Path, PathBuf};
 
-use super::config::{EmbeddedPythonConfig, RawAllocator, RunMode, TerminfoResolution};
+use super::config::{
+    DetachedStdio, EmbeddedPythonConfig, ImporterPrecedence, RawAllocator, RunMode, SysExecutable,
+    TerminfoResolution,
+};
+use crate::environment::BUILD_SEMVER;
+
+/// Identifiers of a packaged application exposed by `oxidized_app`.
+///
+/// Applications use them to invalidate caches when embedded code changes.
+/// They only depend on what is packaged, not on paths or the build
+/// machine, so reproducible builds have the same identifiers.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AppIdentifiers {
+    /// SHA-256 of the packed module and resource data.
+    pub resources_digest: String,
+    /// SHA-256 of everything PyOxidizer embeds in the binary.
+    ///
+    /// Covers the packed data, the Python distribution, the embedded
+    /// Python config, run modes, the target and the PyOxidizer version.
+    /// The application's own Rust code isn't covered.
+    pub build_id: String,
+}
+
+/// Feed length-prefixed fields to a hasher, so field boundaries matter.
+fn hash_fields(fields: &[&[u8]]) -> String {
+    let mut hasher = sha2::Sha256::new();
+
+    for field in fields {
+        hasher.input(&(field.len() as u64).to_le_bytes());
+        hasher.input(field);
+    }
+
+    hex::encode(hasher.result())
+}
+
+impl AppIdentifiers {
+    /// Derive identifiers from the packed data blobs and what else is embedded.
+    #[allow(clippy::too_many_arguments)]
+    pub fn derive(
+        module_names: &[u8],
+        modules: &[u8],
+        resources: &[u8],
+        embedded: &EmbeddedPythonConfig,
+        run_mode: &RunMode,
+        multicall_run_modes: &BTreeMap<String, RunMode>,
+        distribution_sha256: &str,
+        target: &str,
+    ) -> AppIdentifiers {
+        let resources_digest = hash_fields(&[module_names, modules, resources]);
+
+        let build_id = hash_fields(&[
+            BUILD_SEMVER.as_bytes(),
+            target.as_bytes(),
+            distribution_sha256.to_lowercase().as_bytes(),
+            resources_digest.as_bytes(),
+            format!("{:?}", embedded).as_bytes(),
+            format!("{:?}", run_mode).as_bytes(),
+            format!("{:?}", multicall_run_modes).as_bytes(),
+        ]);
+
+        AppIdentifiers {
+            resources_digest,
+            build_id,
+        }
+    }
+
+    /// Cover modules and resources loaded from a shared resources file.
+    pub fn with_shared_resources(self, shared: &SharedResourcesFile) -> AppIdentifiers {
+        let sha256 = shared.sha256();
+
+        AppIdentifiers {
+            resources_digest: hash_fields(&[self.resources_digest.as_bytes(), sha256.as_bytes()]),
+            build_id: hash_fields(&[self.build_id.as_bytes(), sha256.as_bytes()]),
+        }
+    }
+}
+
+/// A file of modules and resources shared by several executables.
+///
+/// Executables verify the file was built along with them when loading it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SharedResourcesFile {
+    /// Path of the file, relative to the executables.
+    pub path: String,
+
+    /// Content of the file.
+    pub data: Vec<u8>,
+}
+
+impl SharedResourcesFile {
+    /// Hex SHA-256 of the file content.
+    pub fn sha256(&self) -> String {
+        hex::encode(sha2::Sha256::digest(&self.data))
+    }
+}
 
 /// Obtain the Rust source code to construct a PythonConfig instance.
 pub fn derive_python_config(
     embedded: &EmbeddedPythonConfig,
     run_mode: &RunMode,
+    multicall_run_modes: &BTreeMap<String, RunMode>,
     importlib_bootstrap_path: &PathBuf,
     importlib_bootstrap_external_path: &PathBuf,
     py_modules_path: &PathBuf,
     py_resources_path: &PathBuf,
+    shared_resources: Option<&SharedResourcesFile>,
+    identifiers: &AppIdentifiers,
 ) -> String {
     format!(
         "PythonConfig {{\n    \
@@ -26,6 +125,7 @@ pub fn derive_python_config(
          opt_level: {},\n    \
          use_custom_importlib: true,\n    \
          filesystem_importer: {},\n    \
+         importer_precedence: {},\n    \
          sys_paths: [{}].to_vec(),\n    \
          bytes_warning: {},\n    \
          import_site: {},\n    \
@@ -38,6 +138,7 @@ pub fn derive_python_config(
          legacy_windows_stdio: {},\n    \
          dont_write_bytecode: {},\n    \
          unbuffered_stdio: {},\n    \
+         detached_stdio: {},\n    \
          parser_debug: {},\n    \
          quiet: {},\n    \
          use_hash_seed: {},\n    \
@@ -46,14 +147,30 @@ pub fn derive_python_config(
          frozen_importlib_external_data: include_bytes!(r#\"{}\"#),\n    \
          py_modules_data: include_bytes!(r#\"{}\"#),\n    \
          py_resources_data: include_bytes!(r#\"{}\"#),\n    \
+         shared_resources: {},\n    \
+         resources_digest: Some(\"{}\".to_string()),\n    \
+         build_id: Some(\"{}\".to_string()),\n    \
          extra_extension_modules: vec![],\n    \
          argvb: false,\n    \
+         argv: None,\n    \
          sys_frozen: {},\n    \
          sys_meipass: {},\n    \
+         sys_executable: {},\n    \
+         sys_prefix: r###\"{}\"###.to_string(),\n    \
+         run_python_marker: {},\n    \
          raw_allocator: {},\n    \
          terminfo_resolution: {},\n    \
          write_modules_directory_env: {},\n    \
-         run: {},\n\
+         import_profile_env: {},\n    \
+         repl_history_file: {},\n    \
+         startup_module: {},\n    \
+         startup_code: {},\n    \
+         run_atexit: {},\n    \
+         wait_for_non_daemon_threads: {},\n    \
+         install_signal_handlers: {},\n    \
+         pkg_resources_compat: {},\n    \
+         run: {},\n    \
+         multicall_run_modes: vec![{}],\n\
          }}",
         match &embedded.stdio_encoding_name {
             Some(value) => format_args!("Some(\"{}\")", value).to_string(),
@@ -65,6 +182,10 @@ pub fn derive_python_config(
         },
         embedded.optimize_level,
         embedded.filesystem_importer,
+        match embedded.importer_precedence {
+            ImporterPrecedence::MemoryFirst => "ImporterPrecedence::MemoryFirst",
+            ImporterPrecedence::FilesystemFirst => "ImporterPrecedence::FilesystemFirst",
+        },
         &embedded
             .sys_paths
             .iter()
@@ -82,6 +203,13 @@ pub fn derive_python_config(
         embedded.legacy_windows_stdio,
         embedded.dont_write_bytecode,
         embedded.unbuffered_stdio,
+        match &embedded.detached_stdio {
+            DetachedStdio::Null => "DetachedStdio::Null".to_string(),
+            DetachedStdio::LogFile(path) => {
+                format!("DetachedStdio::LogFile(r###\"{}\"###.to_string())", path)
+            }
+            DetachedStdio::Buffer => "DetachedStdio::Buffer".to_string(),
+        },
         embedded.parser_debug,
         embedded.quiet,
         embedded.use_hash_seed,
@@ -90,10 +218,27 @@ pub fn derive_python_config(
         importlib_bootstrap_external_path.display(),
         py_modules_path.display(),
         py_resources_path.display(),
+        match shared_resources {
+            Some(shared) => format!(
+                "Some(SharedResources {{ path: r###\"$ORIGIN/{}\"###.to_string(), sha256: \"{}\".to_string() }})",
+                shared.path,
+                shared.sha256()
+            ),
+            None => "None".to_string(),
+        },
+        identifiers.resources_digest,
+        identifiers.build_id,
         embedded.sys_frozen,
         embedded.sys_meipass,
+        match embedded.sys_executable {
+            SysExecutable::Exe => "SysExecutable::Exe",
+            SysExecutable::Empty => "SysExecutable::Empty",
+        },
+        embedded.sys_prefix,
+        embedded.run_python_marker,
         match embedded.raw_allocator {
             RawAllocator::Jemalloc => "PythonRawAllocator::Jemalloc",
+            RawAllocator::Mimalloc => "PythonRawAllocator::Mimalloc",
             RawAllocator::Rust => "PythonRawAllocator::Rust",
             RawAllocator::System => "PythonRawAllocator::System",
         },
@@ -108,24 +253,127 @@ pub fn derive_python_config(
             Some(path) => "Some(\"".to_owned() + &path + "\".to_string())",
             _ => "None".to_owned(),
         },
-        match run_mode {
-            RunMode::Noop => "PythonRunMode::None".to_owned(),
-            RunMode::Repl => "PythonRunMode::Repl".to_owned(),
-            RunMode::Module { ref module } => {
-                "PythonRunMode::Module { module: \"".to_owned() + module + "\".to_string() }"
-            }
-            RunMode::Eval { ref code } => {
-                "PythonRunMode::Eval { code: r###\"".to_owned() + code + "\"###.to_string() }"
-            }
+        match &embedded.import_profile_env {
+            Some(key) => "Some(\"".to_owned() + &key + "\".to_string())",
+            _ => "None".to_owned(),
         },
+        match &embedded.repl_history_file {
+            Some(path) => "Some(r###\"".to_owned() + &path + "\"###.to_string())",
+            _ => "None".to_owned(),
+        },
+        match &embedded.startup_module {
+            Some(module) => "Some(\"".to_owned() + &module + "\".to_string())",
+            _ => "None".to_owned(),
+        },
+        match &embedded.startup_code {
+            Some(code) => "Some(r###\"".to_owned() + &code + "\"###.to_string())",
+            _ => "None".to_owned(),
+        },
+        embedded.run_atexit,
+        embedded.wait_for_non_daemon_threads,
+        embedded.install_signal_handlers,
+        embedded.pkg_resources_compat,
+        run_mode_rs(run_mode),
+        multicall_run_modes
+            .iter()
+            .map(|(name, run_mode)| format!(
+                "(\"{}\".to_string(), {})",
+                name,
+                run_mode_rs(run_mode)
+            ))
+            .join(", "),
     )
 }
 
-pub fn write_data_rs(path: &Path, python_config_rs: &str) -> Result<()> {
+/// Obtain the Rust source code to construct a PythonRunMode instance.
+fn run_mode_rs(run_mode: &RunMode) -> String {
+    match run_mode {
+        RunMode::Noop => "PythonRunMode::None".to_owned(),
+        RunMode::Embedding => "PythonRunMode::Embedding".to_owned(),
+        RunMode::Repl => "PythonRunMode::Repl".to_owned(),
+        RunMode::Module { ref module } => {
+            "PythonRunMode::Module { module: \"".to_owned() + module + "\".to_string() }"
+        }
+        RunMode::Eval { ref code } => {
+            "PythonRunMode::Eval { code: r###\"".to_owned() + code + "\"###.to_string() }"
+        }
+    }
+}
+
+/// Derive Rust code calling the application's functions registered as pre-init hooks.
+///
+/// The functions are defined by the application with `#[no_mangle]`, so the
+/// `pyembed` crate can declare them without depending on the application.
+fn pre_init_hooks_rs(hooks: &[String]) -> String {
+    let mut res = String::new();
+
+    if !hooks.is_empty() {
+        res.push_str("extern \"Rust\" {\n");
+        for hook in hooks {
+            res.push_str(&format!("    fn {}();\n", hook));
+        }
+        res.push_str("}\n\n");
+    }
+
+    res.push_str(
+        "/// Call functions registered with `pre_init_hooks` in the PyOxidizer config.\n\
+         ///\n\
+         /// Applications call this before initializing Python.\n\
+         #[allow(dead_code)]\n\
+         pub fn run_pre_init_hooks() {\n",
+    );
+
+    for hook in hooks {
+        res.push_str(&format!("    unsafe {{ {}() }};\n", hook));
+    }
+
+    res.push_str("}\n");
+
+    res
+}
+
+/// Derive Rust code calling the application's functions registered as post-finalize hooks.
+///
+/// Hooks are declared like pre-init hooks and receive the process exit code.
+fn post_finalize_hooks_rs(hooks: &[String]) -> String {
+    let mut res = String::new();
+
+    if !hooks.is_empty() {
+        res.push_str("extern \"Rust\" {\n");
+        for hook in hooks {
+            res.push_str(&format!("    fn {}(code: i32);\n", hook));
+        }
+        res.push_str("}\n\n");
+    }
+
+    res.push_str(
+        "/// Call functions registered with `post_finalize_hooks` in the PyOxidizer config.\n\
+         ///\n\
+         /// Applications call this with the process exit code after the\n\
+         /// interpreter is finalized.\n\
+         #[allow(dead_code, unused_variables)]\n\
+         pub fn run_post_finalize_hooks(code: i32) {\n",
+    );
+
+    for hook in hooks {
+        res.push_str(&format!("    unsafe {{ {}(code) }};\n", hook));
+    }
+
+    res.push_str("}\n");
+
+    res
+}
+
+pub fn write_data_rs(
+    path: &Path,
+    python_config_rs: &str,
+    pre_init_hooks: &[String],
+    post_finalize_hooks: &[String],
+) -> Result<()> {
     let mut f = File::create(&path)?;
 
     f.write_all(
-        b"use crate::{PythonConfig, PythonRawAllocator, PythonRunMode, TerminfoResolution};\n\n",
+        b"use crate::{DetachedStdio, ImporterPrecedence, PythonConfig, PythonRawAllocator, PythonRunMode, SharedResources, SysExecutable, TerminfoResolution};\n\n",
     )?;
 
     // Ideally we would have a const struct, but we need to do some
@@ -146,5 +394,377 @@ pub fn write_data_rs(path: &Path, python_config_rs: &str) -> Result<()> {
         indented
     ))?;
 
+    f.write_all(b"\n")?;
+    f.write_all(pre_init_hooks_rs(pre_init_hooks).as_bytes())?;
+    f.write_all(b"\n")?;
+    f.write_all(post_finalize_hooks_rs(post_finalize_hooks).as_bytes())?;
+
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn identifiers() -> AppIdentifiers {
+        AppIdentifiers {
+            resources_digest: "abc".to_string(),
+            build_id: "def".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_app_identifiers() {
+        let embedded = EmbeddedPythonConfig::default();
+        let derive = |modules: &[u8], run_mode: &RunMode, target: &str| {
+            AppIdentifiers::derive(
+                b"foo\n",
+                modules,
+                b"",
+                &embedded,
+                run_mode,
+                &BTreeMap::new(),
+                "0123ABCD",
+                target,
+            )
+        };
+
+        let ids = derive(
+            b"print('hello')",
+            &RunMode::Repl,
+            "x86_64-unknown-linux-gnu",
+        );
+        assert_eq!(ids.resources_digest.len(), 64);
+        assert_eq!(ids.build_id.len(), 64);
+
+        // Stable for the same input.
+        assert_eq!(
+            derive(
+                b"print('hello')",
+                &RunMode::Repl,
+                "x86_64-unknown-linux-gnu"
+            ),
+            ids
+        );
+
+        // Module content changes both.
+        let edited = derive(
+            b"print('hello!')",
+            &RunMode::Repl,
+            "x86_64-unknown-linux-gnu",
+        );
+        assert_ne!(edited.resources_digest, ids.resources_digest);
+        assert_ne!(edited.build_id, ids.build_id);
+
+        // Other embedded things only change the build ID.
+        for other in &[
+            derive(
+                b"print('hello')",
+                &RunMode::Noop,
+                "x86_64-unknown-linux-gnu",
+            ),
+            derive(b"print('hello')", &RunMode::Repl, "x86_64-apple-darwin"),
+        ] {
+            assert_eq!(other.resources_digest, ids.resources_digest);
+            assert_ne!(other.build_id, ids.build_id);
+        }
+
+        // Moving bytes between blobs changes the digest.
+        let moved = AppIdentifiers::derive(
+            b"foo\nprint",
+            b"('hello')",
+            b"",
+            &embedded,
+            &RunMode::Repl,
+            &BTreeMap::new(),
+            "0123ABCD",
+            "x86_64-unknown-linux-gnu",
+        );
+        assert_ne!(moved.resources_digest, ids.resources_digest);
+    }
+
+    #[test]
+    fn test_identifiers_rs() {
+        let path = PathBuf::from("data");
+        let code = derive_python_config(
+            &EmbeddedPythonConfig::default(),
+            &RunMode::Noop,
+            &BTreeMap::new(),
+            &path,
+            &path,
+            &path,
+            &path,
+            None,
+            &identifiers(),
+        );
+
+        assert!(code.contains("resources_digest: Some(\"abc\".to_string()),"));
+        assert!(code.contains("build_id: Some(\"def\".to_string()),"));
+        assert!(code.contains("shared_resources: None,"));
+    }
+
+    #[test]
+    fn test_pre_init_hooks_rs() {
+        assert_eq!(
+            pre_init_hooks_rs(&[]),
+            "/// Call functions registered with `pre_init_hooks` in the PyOxidizer config.\n\
+             ///\n\
+             /// Applications call this before initializing Python.\n\
+             #[allow(dead_code)]\n\
+             pub fn run_pre_init_hooks() {\n\
+             }\n"
+        );
+
+        let rs = pre_init_hooks_rs(&["parse_args".to_string(), "setup_logging".to_string()]);
+        assert!(rs.starts_with(
+            "extern \"Rust\" {\n    fn parse_args();\n    fn setup_logging();\n}\n\n"
+        ));
+        assert!(rs.ends_with(
+            "pub fn run_pre_init_hooks() {\n    \
+             unsafe { parse_args() };\n    \
+             unsafe { setup_logging() };\n\
+             }\n"
+        ));
+    }
+
+    #[test]
+    fn test_post_finalize_hooks_rs() {
+        assert!(post_finalize_hooks_rs(&[]).ends_with(
+            "#[allow(dead_code, unused_variables)]\n\
+             pub fn run_post_finalize_hooks(code: i32) {\n\
+             }\n"
+        ));
+
+        let rs = post_finalize_hooks_rs(&["flush_telemetry".to_string()]);
+        assert!(rs.starts_with("extern \"Rust\" {\n    fn flush_telemetry(code: i32);\n}\n\n"));
+        assert!(rs.ends_with(
+            "pub fn run_post_finalize_hooks(code: i32) {\n    \
+             unsafe { flush_telemetry(code) };\n\
+             }\n"
+        ));
+    }
+
+    #[test]
+    fn test_finalization_rs() {
+        let derive = |embedded: &EmbeddedPythonConfig| {
+            let path = PathBuf::from("data");
+
+            derive_python_config(
+                embedded,
+                &RunMode::Noop,
+                &BTreeMap::new(),
+                &path,
+                &path,
+                &path,
+                &path,
+                None,
+                &identifiers(),
+            )
+        };
+
+        let code = derive(&EmbeddedPythonConfig::default());
+        assert!(code.contains("run_atexit: true,"));
+        assert!(code.contains("wait_for_non_daemon_threads: true,"));
+        assert!(code.contains("install_signal_handlers: true,"));
+        assert!(code.contains("pkg_resources_compat: false,"));
+
+        let code = derive(&EmbeddedPythonConfig {
+            run_atexit: false,
+            wait_for_non_daemon_threads: false,
+            install_signal_handlers: false,
+            pkg_resources_compat: true,
+            ..EmbeddedPythonConfig::default()
+        });
+        assert!(code.contains("run_atexit: false,"));
+        assert!(code.contains("wait_for_non_daemon_threads: false,"));
+        assert!(code.contains("install_signal_handlers: false,"));
+        assert!(code.contains("pkg_resources_compat: true,"));
+    }
+
+    #[test]
+    fn test_importer_precedence() {
+        let derive = |precedence: ImporterPrecedence| {
+            let embedded = EmbeddedPythonConfig {
+                filesystem_importer: true,
+                importer_precedence: precedence,
+                ..EmbeddedPythonConfig::default()
+            };
+            let path = PathBuf::from("data");
+
+            derive_python_config(
+                &embedded,
+                &RunMode::Noop,
+                &BTreeMap::new(),
+                &path,
+                &path,
+                &path,
+                &path,
+                None,
+                &identifiers(),
+            )
+        };
+
+        assert!(derive(ImporterPrecedence::MemoryFirst)
+            .contains("importer_precedence: ImporterPrecedence::MemoryFirst,"));
+        assert!(derive(ImporterPrecedence::FilesystemFirst)
+            .contains("importer_precedence: ImporterPrecedence::FilesystemFirst,"));
+    }
+
+    #[test]
+    fn test_shared_resources() {
+        let shared = SharedResourcesFile {
+            path: "lib/shared-resources".to_string(),
+            data: b"shared".to_vec(),
+        };
+        assert_eq!(
+            shared.sha256(),
+            "a4d26868017c0ccffe2efe50944ef4211834660cca834c6e9f86dec6a88246fa"
+        );
+
+        let path = PathBuf::from("data");
+        let code = derive_python_config(
+            &EmbeddedPythonConfig::default(),
+            &RunMode::Noop,
+            &BTreeMap::new(),
+            &path,
+            &path,
+            &path,
+            &path,
+            Some(&shared),
+            &identifiers(),
+        );
+        assert!(code.contains(&format!(
+            "shared_resources: Some(SharedResources {{ path: r###\"$ORIGIN/lib/shared-resources\"###.to_string(), sha256: \"{}\".to_string() }}),",
+            shared.sha256()
+        )));
+
+        // Identifiers change with the shared data.
+        let ids = identifiers().with_shared_resources(&shared);
+        assert_ne!(ids.resources_digest, identifiers().resources_digest);
+        assert_ne!(ids.build_id, identifiers().build_id);
+
+        let other = SharedResourcesFile {
+            data: b"other".to_vec(),
+            ..shared.clone()
+        };
+        assert_ne!(identifiers().with_shared_resources(&other), ids);
+    }
+
+    #[test]
+    fn test_startup_hook() {
+        let path = PathBuf::from("data");
+
+        let embedded = EmbeddedPythonConfig {
+            startup_module: Some("myapp._startup".to_string()),
+            ..EmbeddedPythonConfig::default()
+        };
+        let code = derive_python_config(
+            &embedded,
+            &RunMode::Noop,
+            &BTreeMap::new(),
+            &path,
+            &path,
+            &path,
+            &path,
+            None,
+            &identifiers(),
+        );
+        assert!(code.contains("startup_module: Some(\"myapp._startup\".to_string()),"));
+        assert!(code.contains("startup_code: None,"));
+
+        let embedded = EmbeddedPythonConfig {
+            startup_code: Some("import logging\nlogging.basicConfig()".to_string()),
+            ..EmbeddedPythonConfig::default()
+        };
+        let code = derive_python_config(
+            &embedded,
+            &RunMode::Noop,
+            &BTreeMap::new(),
+            &path,
+            &path,
+            &path,
+            &path,
+            None,
+            &identifiers(),
+        );
+        assert!(code.contains("startup_module: None,"));
+        assert!(code.contains(
+            "startup_code: Some(r###\"import logging\nlogging.basicConfig()\"###.to_string()),"
+        ));
+    }
+
+    #[test]
+    fn test_detached_stdio() {
+        let path = PathBuf::from("data");
+        let derive = |detached_stdio: DetachedStdio| {
+            let embedded = EmbeddedPythonConfig {
+                detached_stdio,
+                ..EmbeddedPythonConfig::default()
+            };
+
+            derive_python_config(
+                &embedded,
+                &RunMode::Noop,
+                &BTreeMap::new(),
+                &path,
+                &path,
+                &path,
+                &path,
+                None,
+                &identifiers(),
+            )
+        };
+
+        assert!(derive(DetachedStdio::Null).contains("detached_stdio: DetachedStdio::Null,"));
+        assert!(derive(DetachedStdio::Buffer).contains("detached_stdio: DetachedStdio::Buffer,"));
+        assert!(
+            derive(DetachedStdio::LogFile("$ORIGIN/logs/{pid}.log".to_string())).contains(
+                "detached_stdio: DetachedStdio::LogFile(r###\"$ORIGIN/logs/{pid}.log\"###.to_string()),"
+            )
+        );
+    }
+
+    #[test]
+    fn test_multicall_run_modes() {
+        let path = PathBuf::from("data");
+        let embedded = EmbeddedPythonConfig::default();
+
+        let code = derive_python_config(
+            &embedded,
+            &RunMode::Repl,
+            &BTreeMap::new(),
+            &path,
+            &path,
+            &path,
+            &path,
+            None,
+            &identifiers(),
+        );
+        assert!(code.contains("multicall_run_modes: vec![],"));
+
+        let mut run_modes = BTreeMap::new();
+        run_modes.insert(
+            "myapp-worker".to_string(),
+            RunMode::Module {
+                module: "myapp.worker".to_string(),
+            },
+        );
+        run_modes.insert("myapp-noop".to_string(), RunMode::Noop);
+
+        let code = derive_python_config(
+            &embedded,
+            &RunMode::Repl,
+            &run_modes,
+            &path,
+            &path,
+            &path,
+            &path,
+            None,
+            &identifiers(),
+        );
+        assert!(code.contains("run: PythonRunMode::Repl,"));
+        assert!(code.contains(
+            "multicall_run_modes: vec![(\"myapp-noop\".to_string(), PythonRunMode::None), (\"myapp-worker\".to_string(), PythonRunMode::Module { module: \"myapp.worker\".to_string() })],"
+        ));
+    }
+}
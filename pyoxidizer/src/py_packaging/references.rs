@@ -0,0 +1,400 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/*!
+Cross-reference names mentioned by configs against packaged resources.
+
+Configs refer to Python modules by name in places like the run mode and
+startup hook, and packaged distributions do in their entry points. If those names go stale (e.g. after a module is renamed), the
+build succeeds but the application fails at run-time. This module detects
+that at build time.
+*/
+
+use anyhow::{anyhow, Result};
+use lazy_static::lazy_static;
+use slog::warn;
+use std::collections::{BTreeMap, BTreeSet};
+
+use super::config::{EmbeddedPythonConfig, RunMode};
+use super::filtering::{unmatched_patterns, CORE_MODULE_NAMES};
+use super::pkg_resources_compat::DIST_INFO_PACKAGE;
+
+lazy_static! {
+    static ref RE_IMPORT: regex::Regex =
+        regex::Regex::new(r"^import\s+([\w.]+(?:\s+as\s+\w+)?(?:\s*,\s*[\w.]+(?:\s+as\s+\w+)?)*)")
+            .unwrap();
+    static ref RE_FROM_IMPORT: regex::Regex =
+        regex::Regex::new(r"^from\s+([\w.]+)\s+import\b").unwrap();
+}
+
+/// A name in a config referring to packaged resources.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ResourceReference {
+    /// Describes where the reference comes from.
+    pub origin: String,
+    /// Resource name or glob pattern.
+    pub pattern: String,
+    /// Whether the reference not matching anything is an error.
+    ///
+    /// References derived heuristically are only warned about.
+    pub required: bool,
+}
+
+/// Obtain the names of modules imported by `import` statements in Python code.
+///
+/// This is a heuristic: only simple top-level statements are recognized.
+/// Relative imports are ignored.
+pub fn modules_imported_by_code(code: &str) -> Vec<String> {
+    let mut names = Vec::new();
+
+    for statement in code.split(|c| c == '\n' || c == ';') {
+        let statement = statement.trim();
+
+        if let Some(caps) = RE_FROM_IMPORT.captures(statement) {
+            let name = &caps[1];
+            if !name.starts_with('.') {
+                names.push(name.to_string());
+            }
+        } else if let Some(caps) = RE_IMPORT.captures(statement) {
+            for part in caps[1].split(',') {
+                if let Some(name) = part.split_whitespace().next() {
+                    names.push(name.to_string());
+                }
+            }
+        }
+    }
+
+    names
+}
+
+/// Obtain resource references from a run mode.
+///
+/// `origin` describes the run mode and prefixes the origin of references.
+fn run_mode_references(run_mode: &RunMode, origin: &str) -> Vec<ResourceReference> {
+    match run_mode {
+        RunMode::Module { module } => vec![ResourceReference {
+            origin: format!("{} module", origin),
+            pattern: module.clone(),
+            required: true,
+        }],
+        RunMode::Eval { code } => modules_imported_by_code(code)
+            .into_iter()
+            .map(|name| ResourceReference {
+                origin: format!("{} code", origin),
+                pattern: name,
+                required: false,
+            })
+            .collect(),
+        RunMode::Noop | RunMode::Repl | RunMode::Embedding => Vec::new(),
+    }
+}
+
+/// Obtain resource references from the embedded config and run mode.
+pub fn executable_references(
+    config: &EmbeddedPythonConfig,
+    run_mode: &RunMode,
+) -> Vec<ResourceReference> {
+    let mut refs = run_mode_references(run_mode, "run mode");
+
+    if let Some(module) = &config.startup_module {
+        refs.push(ResourceReference {
+            origin: "startup_module".to_string(),
+            pattern: module.clone(),
+            required: true,
+        });
+    }
+
+    if let Some(code) = &config.startup_code {
+        refs.extend(
+            modules_imported_by_code(code)
+                .into_iter()
+                .map(|name| ResourceReference {
+                    origin: "startup_code".to_string(),
+                    pattern: name,
+                    required: false,
+                }),
+        );
+    }
+
+    refs
+}
+
+/// Obtain resource references from run modes selected by program name.
+pub fn multicall_references(run_modes: &BTreeMap<String, RunMode>) -> Vec<ResourceReference> {
+    run_modes
+        .iter()
+        .flat_map(|(name, run_mode)| {
+            run_mode_references(run_mode, &format!("multicall_run_modes['{}']", name))
+        })
+        .collect()
+}
+
+/// Obtain resource references from entry points of packaged distributions.
+///
+/// `resources` maps packages to their resources, in which `.dist-info`
+/// metadata is collected. Distributions commonly declare entry points of
+/// optional modules, so the references only warn.
+pub fn entry_point_references(
+    resources: &BTreeMap<String, BTreeMap<String, Vec<u8>>>,
+) -> Vec<ResourceReference> {
+    let mut refs = Vec::new();
+
+    let dist_info = match resources.get(DIST_INFO_PACKAGE) {
+        Some(dist_info) => dist_info,
+        None => return refs,
+    };
+
+    for (name, data) in dist_info {
+        let dist = match name.split('/').collect::<Vec<_>>().as_slice() {
+            [dist, "entry_points.txt"] => dist.to_string(),
+            _ => continue,
+        };
+
+        let mut group = String::new();
+
+        for line in String::from_utf8_lossy(data).lines() {
+            let line = line.trim();
+
+            if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+                continue;
+            }
+
+            if line.starts_with('[') && line.ends_with(']') {
+                group = line[1..line.len() - 1].trim().to_string();
+                continue;
+            }
+
+            // e.g. `hello = mypkg.cli:main [extra]`.
+            let mut parts = line.splitn(2, '=');
+            let (entry, target) = match (parts.next(), parts.next()) {
+                (Some(entry), Some(target)) => (entry.trim(), target.trim()),
+                _ => continue,
+            };
+
+            let module = target
+                .split(|c: char| c == ':' || c == '[' || c.is_whitespace())
+                .next()
+                .unwrap_or("");
+
+            if !module.is_empty() {
+                refs.push(ResourceReference {
+                    origin: format!("{} entry point {}.{}", dist, group, entry),
+                    pattern: module.to_string(),
+                    required: false,
+                });
+            }
+        }
+    }
+
+    refs
+}
+
+/// Verify resource references match packaged resources.
+///
+/// Required references matching nothing are an error unless modules can
+/// also be imported from the filesystem, in which case they are warned
+/// about like other references.
+pub fn check_references(
+    logger: &slog::Logger,
+    refs: &[ResourceReference],
+    resource_names: &BTreeSet<String>,
+    filesystem_importer: bool,
+) -> Result<()> {
+    let mut names = resource_names.clone();
+    names.extend(CORE_MODULE_NAMES.iter().map(|s| s.to_string()));
+
+    let mut errors = Vec::new();
+
+    for r in refs {
+        for unmatched in unmatched_patterns(std::iter::once(&r.pattern), &names) {
+            let message = format!("{}: {}", r.origin, unmatched);
+
+            if r.required && !filesystem_importer {
+                errors.push(message);
+            } else {
+                warn!(logger, "warning: {}", message);
+            }
+        }
+    }
+
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(anyhow!("{}", errors.join("\n")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(values: &[&str]) -> BTreeSet<String> {
+        values.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn test_modules_imported_by_code() {
+        assert_eq!(
+            modules_imported_by_code(
+                "import sys; import mypkg.oldmodule as m, os\nfrom mypkg.cli import main\nfrom . import x\nmain()"
+            ),
+            vec![
+                "sys".to_string(),
+                "mypkg.oldmodule".to_string(),
+                "os".to_string(),
+                "mypkg.cli".to_string(),
+            ]
+        );
+        assert!(modules_imported_by_code("print('import foo')").is_empty());
+        assert!(modules_imported_by_code("importlib.reload(x)").is_empty());
+    }
+
+    #[test]
+    fn test_executable_references() {
+        let config = EmbeddedPythonConfig {
+            startup_module: Some("mypkg._startup".to_string()),
+            ..EmbeddedPythonConfig::default()
+        };
+
+        let refs = executable_references(
+            &config,
+            &RunMode::Module {
+                module: "mypkg.app".to_string(),
+            },
+        );
+        assert_eq!(
+            refs,
+            vec![
+                ResourceReference {
+                    origin: "run mode module".to_string(),
+                    pattern: "mypkg.app".to_string(),
+                    required: true,
+                },
+                ResourceReference {
+                    origin: "startup_module".to_string(),
+                    pattern: "mypkg._startup".to_string(),
+                    required: true,
+                },
+            ]
+        );
+
+        let refs = executable_references(
+            &EmbeddedPythonConfig::default(),
+            &RunMode::Eval {
+                code: "from mypkg.oldmodule import main; main()".to_string(),
+            },
+        );
+        assert_eq!(
+            refs,
+            vec![ResourceReference {
+                origin: "run mode code".to_string(),
+                pattern: "mypkg.oldmodule".to_string(),
+                required: false,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_multicall_references() {
+        let mut run_modes = BTreeMap::new();
+        run_modes.insert(
+            "myapp-worker".to_string(),
+            RunMode::Module {
+                module: "mypkg.worker".to_string(),
+            },
+        );
+        run_modes.insert("myapp-shell".to_string(), RunMode::Repl);
+
+        assert_eq!(
+            multicall_references(&run_modes),
+            vec![ResourceReference {
+                origin: "multicall_run_modes['myapp-worker'] module".to_string(),
+                pattern: "mypkg.worker".to_string(),
+                required: true,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_entry_point_references() {
+        let mut dist_info = BTreeMap::new();
+        dist_info.insert(
+            "app-1.0.dist-info/entry_points.txt".to_string(),
+            b"[console_scripts]\napp = app.cli:main\n\n[app.plugins]\n# comment\nhello = app.plugins.hello:plugin [extra]\nbare = app.bare\n".to_vec(),
+        );
+        dist_info.insert(
+            "app-1.0.dist-info/METADATA".to_string(),
+            b"Name: app\nentry = not.an.entry.point:main\n".to_vec(),
+        );
+
+        let mut resources = BTreeMap::new();
+        assert!(entry_point_references(&resources).is_empty());
+
+        resources.insert(DIST_INFO_PACKAGE.to_string(), dist_info);
+
+        let reference = |origin: &str, pattern: &str| ResourceReference {
+            origin: origin.to_string(),
+            pattern: pattern.to_string(),
+            required: false,
+        };
+
+        assert_eq!(
+            entry_point_references(&resources),
+            vec![
+                reference(
+                    "app-1.0.dist-info entry point console_scripts.app",
+                    "app.cli"
+                ),
+                reference(
+                    "app-1.0.dist-info entry point app.plugins.hello",
+                    "app.plugins.hello"
+                ),
+                reference("app-1.0.dist-info entry point app.plugins.bare", "app.bare"),
+            ]
+        );
+
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        assert!(check_references(
+            &logger,
+            &entry_point_references(&resources),
+            &names(&["app"]),
+            false
+        )
+        .is_ok());
+    }
+
+    #[test]
+    fn test_check_references() {
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let available = names(&["mypkg", "mypkg.app", "mypkg.newmodule"]);
+
+        let required = |pattern: &str| ResourceReference {
+            origin: "run mode module".to_string(),
+            pattern: pattern.to_string(),
+            required: true,
+        };
+
+        assert!(check_references(&logger, &[required("mypkg.app")], &available, false).is_ok());
+        assert!(check_references(&logger, &[required("sys")], &available, false).is_ok());
+
+        let err = check_references(&logger, &[required("mypkg.oldmodule")], &available, false)
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "run mode module: pattern 'mypkg.oldmodule' matched no packaged resources; did you mean 'mypkg.newmodule'?"
+        );
+
+        // Modules may come from the filesystem, so only warn.
+        assert!(
+            check_references(&logger, &[required("mypkg.oldmodule")], &available, true).is_ok()
+        );
+
+        // Heuristic references only warn.
+        let heuristic = ResourceReference {
+            required: false,
+            ..required("mypkg.oldmodule")
+        };
+        assert!(check_references(&logger, &[heuristic], &available, false).is_ok());
+    }
+}
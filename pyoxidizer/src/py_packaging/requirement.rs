@@ -0,0 +1,821 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/*!
+Python package requirements and versions.
+
+This implements enough of PEP 440 and PEP 508 to compare the versions of
+installed packages against the requirements that requested them.
+*/
+
+use anyhow::{anyhow, Context, Result};
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+
+/// `pip install` options consuming the following argument.
+const PIP_OPTIONS_WITH_VALUE: &[&str] = &[
+    "-e",
+    "--editable",
+    "-f",
+    "--find-links",
+    "-i",
+    "--index-url",
+    "--extra-index-url",
+    "-t",
+    "--target",
+    "--platform",
+    "--python-version",
+    "--implementation",
+    "--abi",
+    "--root",
+    "--prefix",
+    "--src",
+    "--upgrade-strategy",
+    "--install-option",
+    "--global-option",
+    "--no-binary",
+    "--only-binary",
+    "--progress-bar",
+    "--trusted-host",
+    "--cache-dir",
+    "--log",
+    "--proxy",
+    "--retries",
+    "--timeout",
+];
+
+/// Normalize a package name per PEP 503.
+pub fn normalize_name(name: &str) -> String {
+    let mut res = String::with_capacity(name.len());
+    let mut last_separator = false;
+
+    for c in name.chars() {
+        if c == '-' || c == '_' || c == '.' {
+            if !last_separator {
+                res.push('-');
+            }
+            last_separator = true;
+        } else {
+            res.push(c.to_ascii_lowercase());
+            last_separator = false;
+        }
+    }
+
+    res
+}
+
+/// A parsed PEP 440 version.
+#[derive(Clone, Debug)]
+pub struct Version {
+    pub epoch: u64,
+    pub release: Vec<u64>,
+    /// Pre-release phase (0 = a, 1 = b, 2 = rc) and number.
+    pub pre: Option<(u8, u64)>,
+    pub post: Option<u64>,
+    pub dev: Option<u64>,
+}
+
+/// Split a leading number off a string.
+fn take_number(s: &str) -> (Option<u64>, &str) {
+    let end = s
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or_else(|| s.len());
+    (s[..end].parse().ok(), &s[end..])
+}
+
+fn trim_separator(s: &str) -> &str {
+    s.trim_start_matches(|c| c == '.' || c == '-' || c == '_')
+}
+
+impl Version {
+    pub fn parse(s: &str) -> Option<Version> {
+        let s = s.trim().to_lowercase();
+        let s = s.trim_start_matches('v');
+        // Local version labels don't affect comparisons against public versions.
+        let s = s.split('+').next().unwrap();
+
+        let (epoch, mut rest) = match s.find('!') {
+            Some(pos) => (s[..pos].parse().ok()?, &s[pos + 1..]),
+            None => (0, s),
+        };
+
+        let mut release = Vec::new();
+        loop {
+            let (n, r) = take_number(rest);
+            release.push(n?);
+            rest = r;
+
+            if rest.starts_with('.') && rest[1..].starts_with(|c: char| c.is_ascii_digit()) {
+                rest = &rest[1..];
+            } else {
+                break;
+            }
+        }
+
+        let mut version = Version {
+            epoch,
+            release,
+            pre: None,
+            post: None,
+            dev: None,
+        };
+
+        for (prefix, phase) in &[
+            ("alpha", 0),
+            ("a", 0),
+            ("beta", 1),
+            ("b", 1),
+            ("rc", 2),
+            ("c", 2),
+            ("preview", 2),
+            ("pre", 2),
+        ] {
+            let candidate = trim_separator(rest);
+            if candidate.starts_with(prefix) {
+                let (n, r) = take_number(trim_separator(&candidate[prefix.len()..]));
+                version.pre = Some((*phase, n.unwrap_or(0)));
+                rest = r;
+                break;
+            }
+        }
+
+        for prefix in &["post", "rev", "r"] {
+            let candidate = trim_separator(rest);
+            if candidate.starts_with(prefix) {
+                let (n, r) = take_number(trim_separator(&candidate[prefix.len()..]));
+                version.post = Some(n.unwrap_or(0));
+                rest = r;
+                break;
+            }
+        }
+
+        // Implicit post release, e.g. `1.0-1`.
+        if version.post.is_none() && rest.starts_with('-') {
+            if let (Some(n), r) = take_number(&rest[1..]) {
+                version.post = Some(n);
+                rest = r;
+            }
+        }
+
+        let candidate = trim_separator(rest);
+        if candidate.starts_with("dev") {
+            let (n, r) = take_number(trim_separator(&candidate[3..]));
+            version.dev = Some(n.unwrap_or(0));
+            rest = r;
+        }
+
+        if rest.is_empty() {
+            Some(version)
+        } else {
+            None
+        }
+    }
+
+    /// Release segments without trailing zeros, for comparisons.
+    fn release_key(&self) -> &[u64] {
+        let end = self
+            .release
+            .iter()
+            .rposition(|v| *v != 0)
+            .map_or(0, |i| i + 1);
+        &self.release[..end]
+    }
+
+    fn sort_key(&self) -> (u64, &[u64], (i8, u64), i64, u64) {
+        let pre = match (self.pre, self.post, self.dev) {
+            (Some((phase, n)), _, _) => (phase as i8, n),
+            // A dev release of a final version sorts before its pre-releases.
+            (None, None, Some(_)) => (-1, 0),
+            (None, _, _) => (3, 0),
+        };
+
+        (
+            self.epoch,
+            self.release_key(),
+            pre,
+            self.post.map_or(-1, |v| v as i64),
+            self.dev.unwrap_or(std::u64::MAX),
+        )
+    }
+
+    /// Whether the release segments start with the given segments.
+    fn release_starts_with(&self, prefix: &[u64]) -> bool {
+        prefix
+            .iter()
+            .enumerate()
+            .all(|(i, v)| self.release.get(i).cloned().unwrap_or(0) == *v)
+    }
+}
+
+impl PartialEq for Version {
+    fn eq(&self, other: &Version) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Version {}
+
+impl PartialOrd for Version {
+    fn partial_cmp(&self, other: &Version) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Version {
+    fn cmp(&self, other: &Version) -> Ordering {
+        self.sort_key().cmp(&other.sort_key())
+    }
+}
+
+/// A single version specifier clause, like `>=1.0`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Specifier {
+    pub op: String,
+    pub version: String,
+}
+
+impl std::fmt::Display for Specifier {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}{}", self.op, self.version)
+    }
+}
+
+impl Specifier {
+    /// Whether a version satisfies this specifier.
+    ///
+    /// Versions that can't be parsed only satisfy exact string matches.
+    pub fn matches(&self, version: &str) -> bool {
+        if self.op == "===" {
+            return version == self.version;
+        }
+
+        let wildcard = self.version.ends_with(".*");
+        let spec_str = self.version.trim_end_matches(".*");
+
+        let (candidate, spec) = match (Version::parse(version), Version::parse(spec_str)) {
+            (Some(c), Some(s)) => (c, s),
+            _ => {
+                let equal = version == self.version;
+                return match self.op.as_str() {
+                    "!=" => !equal,
+                    _ => equal,
+                };
+            }
+        };
+
+        match self.op.as_str() {
+            "==" if wildcard => candidate.release_starts_with(&spec.release),
+            "!=" if wildcard => !candidate.release_starts_with(&spec.release),
+            "==" => candidate == spec,
+            "!=" => candidate != spec,
+            ">=" => candidate >= spec,
+            "<=" => candidate <= spec,
+            ">" => candidate > spec,
+            "<" => candidate < spec,
+            "~=" => {
+                let prefix = &spec.release[..std::cmp::max(spec.release.len(), 2) - 1];
+                candidate >= spec && candidate.release_starts_with(prefix)
+            }
+            _ => true,
+        }
+    }
+}
+
+/// A requirement on a Python package.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Requirement {
+    pub name: String,
+    pub specifiers: Vec<Specifier>,
+}
+
+impl std::fmt::Display for Requirement {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}{}", self.name, self.specifiers_string())
+    }
+}
+
+impl Requirement {
+    /// Parse a PEP 508 requirement string.
+    ///
+    /// Returns `None` for requirements we can't verify, such as URLs and
+    /// paths, and for requirements without version specifiers.
+    pub fn parse(s: &str) -> Option<Requirement> {
+        // Environment markers don't influence which version is acceptable.
+        let s = s.split(';').next().unwrap().trim();
+
+        if s.is_empty()
+            || s.starts_with('-')
+            || s.starts_with('.')
+            || s.starts_with('/')
+            || s.contains("://")
+            || s.contains('@')
+        {
+            return None;
+        }
+
+        let name_end = s
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.'))
+            .unwrap_or_else(|| s.len());
+        let name = &s[..name_end];
+        let mut rest = s[name_end..].trim();
+
+        if name.is_empty() {
+            return None;
+        }
+
+        if rest.starts_with('[') {
+            rest = rest[rest.find(']')? + 1..].trim();
+        }
+
+        let rest = rest.trim_start_matches('(').trim_end_matches(')');
+
+        let mut specifiers = Vec::new();
+        for clause in rest.split(',').map(|c| c.trim()).filter(|c| !c.is_empty()) {
+            let op_end = clause
+                .find(|c: char| !"=!<>~".contains(c))
+                .unwrap_or_else(|| clause.len());
+
+            specifiers.push(Specifier {
+                op: clause[..op_end].to_string(),
+                version: clause[op_end..].trim().to_string(),
+            });
+        }
+
+        if specifiers.is_empty() {
+            None
+        } else {
+            Some(Requirement {
+                name: name.to_string(),
+                specifiers,
+            })
+        }
+    }
+
+    /// The version specifiers, formatted like they are in requirements files.
+    pub fn specifiers_string(&self) -> String {
+        self.specifiers
+            .iter()
+            .map(|s| s.to_string())
+            .collect::<Vec<String>>()
+            .join(",")
+    }
+
+    /// Whether a version satisfies all specifiers.
+    pub fn matches(&self, version: &str) -> bool {
+        self.specifiers.iter().all(|s| s.matches(version))
+    }
+}
+
+/// Parse requirements from the content of a requirements file.
+///
+/// `-r` and `-c` references are resolved relative to the file's directory.
+/// `includes` are the files including this one, which must not be included
+/// again.
+fn requirements_from_file(
+    path: &Path,
+    includes: &mut Vec<PathBuf>,
+    res: &mut Vec<Requirement>,
+) -> Result<()> {
+    let data =
+        std::fs::read_to_string(path).with_context(|| format!("reading {}", path.display()))?;
+
+    let key = std::fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+    if let Some(pos) = includes.iter().position(|p| *p == key) {
+        return Err(anyhow!(
+            "requirements file {} includes itself: {}",
+            path.display(),
+            includes[pos..]
+                .iter()
+                .chain(std::iter::once(&key))
+                .map(|p| p.display().to_string())
+                .collect::<Vec<_>>()
+                .join(" -> ")
+        ));
+    }
+    let base_dir = path.parent().unwrap_or_else(|| Path::new(""));
+
+    let mut args = Vec::new();
+    for line in data.replace("\\\n", "").lines() {
+        let line = match line.find(" #") {
+            Some(pos) => &line[..pos],
+            None if line.trim_start().starts_with('#') => "",
+            None => line,
+        };
+
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        // Requirement lines may carry options such as `--hash`.
+        if line.starts_with('-') {
+            let mut parts = line.splitn(2, |c: char| c == ' ' || c == '=');
+            let flag = parts.next().unwrap();
+            let value = parts.next().unwrap_or("").trim();
+
+            if ["-r", "--requirement", "-c", "--constraint"].contains(&flag) {
+                args.push(flag.to_string());
+                args.push(base_dir.join(value).display().to_string());
+            }
+        } else {
+            args.push(line.split(" --").next().unwrap().to_string());
+        }
+    }
+
+    includes.push(key);
+    res.extend(requirements_from_args(&args, includes)?);
+    includes.pop();
+
+    Ok(())
+}
+
+/// Resolve version requirements from `pip install` arguments.
+///
+/// Requirements come from positional arguments and from requirements and
+/// constraints files referenced by `-r` and `-c`.
+pub fn requirements_from_pip_args(args: &[String]) -> Result<Vec<Requirement>> {
+    requirements_from_args(args, &mut Vec::new())
+}
+
+fn requirements_from_args(
+    args: &[String],
+    includes: &mut Vec<PathBuf>,
+) -> Result<Vec<Requirement>> {
+    let mut res = Vec::new();
+    let mut iter = args.iter();
+
+    while let Some(arg) = iter.next() {
+        let (flag, value) = if arg.starts_with("--") && arg.contains('=') {
+            let mut parts = arg.splitn(2, '=');
+            (parts.next().unwrap(), parts.next().map(|s| s.to_string()))
+        } else {
+            (arg.as_str(), None)
+        };
+
+        match flag {
+            "-r" | "--requirement" | "-c" | "--constraint" => {
+                if let Some(path) = value.or_else(|| iter.next().cloned()) {
+                    requirements_from_file(Path::new(&path), includes, &mut res)?;
+                }
+            }
+            _ if PIP_OPTIONS_WITH_VALUE.contains(&flag) => {
+                if value.is_none() {
+                    iter.next();
+                }
+            }
+            _ if flag.starts_with('-') => {}
+            _ => {
+                if let Some(req) = Requirement::parse(arg) {
+                    res.push(req);
+                }
+            }
+        }
+    }
+
+    Ok(res)
+}
+
+/// Read the names and versions of distributions installed in a directory.
+///
+/// Keys are normalized package names.
+pub fn find_installed_distributions(path: &Path) -> Result<BTreeMap<String, (String, String)>> {
+    let mut res = BTreeMap::new();
+
+    for entry in std::fs::read_dir(path)? {
+        let entry = entry?;
+        let dir_name = entry.file_name().to_string_lossy().to_string();
+
+        if !dir_name.ends_with(".dist-info") {
+            continue;
+        }
+
+        let metadata = std::fs::read_to_string(entry.path().join("METADATA"))
+            .or_else(|_| std::fs::read_to_string(entry.path().join("PKG-INFO")))
+            .unwrap_or_default();
+
+        let mut name = None;
+        let mut version = None;
+
+        for line in metadata.lines() {
+            if line.is_empty() {
+                break;
+            }
+
+            if line.starts_with("Name: ") {
+                name = Some(line[6..].trim().to_string());
+            } else if line.starts_with("Version: ") {
+                version = Some(line[9..].trim().to_string());
+            }
+        }
+
+        // Fall back to the directory name, which is `<name>-<version>.dist-info`.
+        let stem = &dir_name[..dir_name.len() - ".dist-info".len()];
+        let mut parts = stem.splitn(2, '-');
+        let name = name.unwrap_or_else(|| parts.next().unwrap().to_string());
+        let version = match version.or_else(|| parts.next().map(|s| s.to_string())) {
+            Some(version) => version,
+            None => continue,
+        };
+
+        res.insert(normalize_name(&name), (name, version));
+    }
+
+    Ok(res)
+}
+
+/// Describes an installed package not matching its requirement.
+#[derive(Clone, Debug, PartialEq)]
+pub struct VersionMismatch {
+    pub name: String,
+    pub requested: String,
+    pub resolved: String,
+}
+
+impl std::fmt::Display for VersionMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{}: requested {}; resolved {}",
+            self.name, self.requested, self.resolved
+        )
+    }
+}
+
+/// Find installed packages whose version doesn't satisfy a requirement.
+///
+/// Requirements for packages that aren't installed are ignored.
+pub fn find_version_mismatches(
+    requirements: &[Requirement],
+    installed: &BTreeMap<String, (String, String)>,
+) -> Vec<VersionMismatch> {
+    requirements
+        .iter()
+        .filter_map(|req| {
+            let (name, version) = installed.get(&normalize_name(&req.name))?;
+
+            if req.matches(version) {
+                None
+            } else {
+                Some(VersionMismatch {
+                    name: name.clone(),
+                    requested: req.specifiers_string(),
+                    resolved: version.clone(),
+                })
+            }
+        })
+        .collect()
+}
+
+/// Find installed packages not pinned by constraints.
+///
+/// Returns the names of installed packages that no constraint pins to an
+/// exact version. These packages may resolve differently in future builds.
+pub fn find_unconstrained_distributions(
+    constraints: &[Requirement],
+    installed: &BTreeMap<String, (String, String)>,
+) -> Vec<String> {
+    let pinned = constraints
+        .iter()
+        .filter(|c| c.specifiers.iter().any(|s| s.op == "==" || s.op == "==="))
+        .map(|c| normalize_name(&c.name))
+        .collect::<BTreeSet<_>>();
+
+    installed
+        .iter()
+        .filter(|(key, _)| !pinned.contains(*key))
+        .map(|(_, (name, _))| name.clone())
+        .collect()
+}
+
+/// Format a constraints file pinning packages to versions.
+pub fn format_constraints(versions: &BTreeMap<String, String>) -> String {
+    let mut res = "# Generated by `pyoxidizer freeze-deps`.\n".to_string();
+
+    for (name, version) in versions {
+        res.push_str(&format!("{}=={}\n", name, version));
+    }
+
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(spec: &str, version: &str) -> bool {
+        Requirement::parse(&format!("foo{}", spec))
+            .unwrap()
+            .matches(version)
+    }
+
+    #[test]
+    fn test_normalize_name() {
+        assert_eq!(normalize_name("Foo_Bar.baz"), "foo-bar-baz");
+        assert_eq!(normalize_name("foo--bar"), "foo-bar");
+    }
+
+    #[test]
+    fn test_version_ordering() {
+        let versions = [
+            "1.0.dev1",
+            "1.0a1",
+            "1.0a2.dev1",
+            "1.0a2",
+            "1.0b1",
+            "1.0rc1",
+            "1.0",
+            "1.0.post1",
+            "1.0.1",
+            "1.1",
+            "2!0.1",
+        ];
+
+        for pair in versions.windows(2) {
+            let a = Version::parse(pair[0]).unwrap();
+            let b = Version::parse(pair[1]).unwrap();
+            assert!(a < b, "{} < {}", pair[0], pair[1]);
+        }
+
+        assert_eq!(Version::parse("1.0"), Version::parse("1.0.0"));
+        assert!(Version::parse("1.0+local").is_some());
+        assert!(Version::parse("not-a-version").is_none());
+    }
+
+    #[test]
+    fn test_specifier_matches() {
+        assert!(matches("==2.28.2", "2.28.2"));
+        assert!(!matches("==2.28.2", "2.31.0"));
+        assert!(matches("==2.28.*", "2.28.9"));
+        assert!(!matches("==2.28.*", "2.29.0"));
+        assert!(matches(">=1.0,<2", "1.5"));
+        assert!(!matches(">=1.0,<2", "2.0"));
+        assert!(matches("~=1.4.2", "1.4.9"));
+        assert!(!matches("~=1.4.2", "1.5.0"));
+        assert!(matches("~=1.4", "1.9"));
+        assert!(!matches("~=1.4", "2.0"));
+        assert!(matches("!=1.0", "1.1"));
+        assert!(matches("===foobar", "foobar"));
+    }
+
+    #[test]
+    fn test_requirement_parse() {
+        assert_eq!(
+            Requirement::parse("requests[socks] == 2.28.2 ; python_version >= '3'"),
+            Some(Requirement {
+                name: "requests".to_string(),
+                specifiers: vec![Specifier {
+                    op: "==".to_string(),
+                    version: "2.28.2".to_string()
+                }],
+            })
+        );
+
+        assert!(Requirement::parse("requests").is_none());
+        assert!(Requirement::parse("./local/path").is_none());
+        assert!(Requirement::parse("foo @ https://example.com/foo.whl").is_none());
+    }
+
+    #[test]
+    fn test_requirements_from_pip_args() -> Result<()> {
+        let temp_dir = tempdir::TempDir::new("pyoxidizer-test")?;
+        let requirements_path = temp_dir.path().join("requirements.txt");
+        let constraints_path = temp_dir.path().join("constraints.txt");
+
+        std::fs::write(
+            &requirements_path,
+            "# comment\nrequests==2.28.2 \\\n    --hash=sha256:abcd\n-c constraints.txt\nsix\n",
+        )?;
+        std::fs::write(&constraints_path, "urllib3<2  # pinned\n")?;
+
+        let args = vec![
+            "--index-url".to_string(),
+            "https://example.com".to_string(),
+            "pyflakes==2.1.1".to_string(),
+            "-r".to_string(),
+            requirements_path.display().to_string(),
+        ];
+
+        let reqs = requirements_from_pip_args(&args)?
+            .iter()
+            .map(|r| r.to_string())
+            .collect::<Vec<String>>();
+        assert_eq!(
+            reqs,
+            vec!["pyflakes==2.1.1", "requests==2.28.2", "urllib3<2"]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_requirements_include_cycle() -> Result<()> {
+        let temp_dir = tempdir::TempDir::new("pyoxidizer-test")?;
+        let dir = temp_dir.path();
+
+        // Files included more than once without a cycle are fine.
+        std::fs::write(dir.join("base.txt"), "six==1.16.0\n")?;
+        std::fs::write(dir.join("dev.txt"), "-r base.txt\npytest\n")?;
+        std::fs::write(dir.join("all.txt"), "-r base.txt\n-r dev.txt\n")?;
+        let reqs = requirements_from_pip_args(&[
+            "-r".to_string(),
+            dir.join("all.txt").display().to_string(),
+        ])?;
+        assert_eq!(reqs.len(), 2);
+
+        std::fs::write(dir.join("a.txt"), "-r b.txt\n")?;
+        std::fs::write(dir.join("b.txt"), "-r ./a.txt\n")?;
+        let err = requirements_from_pip_args(&[
+            "-r".to_string(),
+            dir.join("a.txt").display().to_string(),
+        ])
+        .unwrap_err();
+        let message = err.to_string();
+        assert!(message.contains("includes itself"), "{}", message);
+        assert!(message.contains("b.txt"), "{}", message);
+
+        std::fs::write(dir.join("self.txt"), "-c self.txt\n")?;
+        assert!(requirements_from_pip_args(&[
+            "--requirement=".to_string() + &dir.join("self.txt").display().to_string()
+        ])
+        .is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_find_version_mismatches() -> Result<()> {
+        let temp_dir = tempdir::TempDir::new("pyoxidizer-test")?;
+
+        let dist_info = temp_dir.path().join("requests-2.31.0.dist-info");
+        std::fs::create_dir(&dist_info)?;
+        std::fs::write(
+            dist_info.join("METADATA"),
+            "Metadata-Version: 2.1\nName: requests\nVersion: 2.31.0\n\nBody\n",
+        )?;
+        std::fs::create_dir(temp_dir.path().join("six-1.16.0.dist-info"))?;
+
+        let installed = find_installed_distributions(temp_dir.path())?;
+        assert_eq!(
+            installed.get("six"),
+            Some(&("six".to_string(), "1.16.0".to_string()))
+        );
+
+        let reqs = vec![
+            Requirement::parse("requests==2.28.2").unwrap(),
+            Requirement::parse("six>=1.0").unwrap(),
+            Requirement::parse("missing==1.0").unwrap(),
+        ];
+
+        let mismatches = find_version_mismatches(&reqs, &installed);
+        assert_eq!(mismatches.len(), 1);
+        assert_eq!(
+            mismatches[0].to_string(),
+            "requests: requested ==2.28.2; resolved 2.31.0"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_find_unconstrained_distributions() {
+        let installed = vec![
+            ("black", "Black", "19.10b0"),
+            ("six", "six", "1.16.0"),
+            ("toml", "toml", "0.10.0"),
+        ]
+        .into_iter()
+        .map(|(k, name, version)| (k.to_string(), (name.to_string(), version.to_string())))
+        .collect::<BTreeMap<_, _>>();
+
+        let constraints = vec![
+            Requirement::parse("black==19.10b0").unwrap(),
+            Requirement::parse("six>=1.0").unwrap(),
+        ];
+
+        assert_eq!(
+            find_unconstrained_distributions(&constraints, &installed),
+            vec!["six".to_string(), "toml".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_format_constraints() -> Result<()> {
+        let temp_dir = tempdir::TempDir::new("pyoxidizer-test")?;
+        let path = temp_dir.path().join("constraints.txt");
+
+        let mut versions = BTreeMap::new();
+        versions.insert("black".to_string(), "19.10b0".to_string());
+        versions.insert("toml".to_string(), "0.10.0".to_string());
+        std::fs::write(&path, format_constraints(&versions))?;
+
+        let parsed = requirements_from_pip_args(&["-c".to_string(), path.display().to_string()])?;
+        assert_eq!(
+            parsed.iter().map(|r| r.to_string()).collect::<Vec<_>>(),
+            vec!["black==19.10b0", "toml==0.10.0"]
+        );
+
+        Ok(())
+    }
+}
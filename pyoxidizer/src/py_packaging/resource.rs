@@ -301,18 +301,28 @@ impl TryFrom<&PythonFileResource> for PythonResource {
 }
 
 impl PythonResource {
-    pub fn is_in_packages(&self, packages: &[String]) -> bool {
-        let name = match self {
+    /// The module name, or the package of resource data.
+    pub fn full_name(&self) -> &str {
+        match self {
             PythonResource::ModuleSource { name, .. } => name,
             PythonResource::ModuleBytecode { name, .. } => name,
             PythonResource::ModuleBytecodeRequest { name, .. } => name,
             PythonResource::Resource { package, .. } => package,
             PythonResource::BuiltExtensionModule(em) => &em.name,
             PythonResource::ExtensionModule { name, .. } => name,
-        };
+        }
+    }
+
+    /// The top-level package of the resource.
+    pub fn top_level_package(&self) -> &str {
+        self.full_name().split('.').next().unwrap()
+    }
+
+    pub fn is_in_packages(&self, packages: &[String]) -> bool {
+        let name = self.full_name();
 
         for package in packages {
-            if packages_from_module_name(&name).contains(package) {
+            if packages_from_module_name(name).contains(package) {
                 return true;
             }
         }
@@ -0,0 +1,143 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/*!
+Policies controlling how much of each Python module is retained.
+
+Source policies choose whether a module keeps its source, its bytecode or
+both. Source gives good tracebacks. Bytecode alone is smaller. Optimize
+policies choose the bytecode optimization level, e.g. level 2 strips
+docstrings.
+
+Policies map module name patterns to a treatment. Patterns are exact
+module names or globs like `myapp.*`, which also match `myapp` itself.
+Patterns prefixed with `stdlib:` only match modules from the Python
+distribution's standard library. When several patterns match a module, the
+longest pattern wins.
+*/
+
+use anyhow::{anyhow, Result};
+use std::collections::BTreeSet;
+
+use super::filtering::name_matches;
+
+/// Prefix of patterns only matching standard library modules.
+pub const STDLIB_PREFIX: &str = "stdlib:";
+
+/// Which representations of a module are packaged.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SourcePolicy {
+    SourceAndBytecode,
+    BytecodeOnly,
+    SourceOnly,
+}
+
+impl SourcePolicy {
+    pub fn parse(s: &str) -> Result<SourcePolicy> {
+        match s {
+            "source+bytecode" => Ok(SourcePolicy::SourceAndBytecode),
+            "bytecode-only" => Ok(SourcePolicy::BytecodeOnly),
+            "source-only" => Ok(SourcePolicy::SourceOnly),
+            _ => Err(anyhow!(
+                "invalid source policy {}; must be source+bytecode, bytecode-only or source-only",
+                s
+            )),
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            SourcePolicy::SourceAndBytecode => "source+bytecode",
+            SourcePolicy::BytecodeOnly => "bytecode-only",
+            SourcePolicy::SourceOnly => "source-only",
+        }
+    }
+}
+
+/// Whether a policy pattern matches a module.
+pub fn policy_pattern_matches(pattern: &str, name: &str, is_stdlib: bool) -> bool {
+    let pattern = if pattern.starts_with(STDLIB_PREFIX) {
+        if !is_stdlib {
+            return false;
+        }
+
+        &pattern[STDLIB_PREFIX.len()..]
+    } else {
+        pattern
+    };
+
+    name_matches(pattern, name)
+        || (pattern.ends_with(".*") && &pattern[..pattern.len() - 2] == name)
+}
+
+/// Find the policy applying to a module.
+///
+/// The longest matching pattern wins. Ties go to the pattern sorting first.
+pub fn resolve_policy<'a, T>(
+    policies: &'a [(String, T)],
+    name: &str,
+    is_stdlib: bool,
+) -> Option<&'a (String, T)> {
+    policies
+        .iter()
+        .filter(|(pattern, _)| policy_pattern_matches(pattern, name, is_stdlib))
+        .max_by(|(a, _), (b, _)| a.len().cmp(&b.len()).then_with(|| b.cmp(a)))
+}
+
+/// Modules a policy applied to.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RetentionBucket {
+    pub modules: BTreeSet<String>,
+    /// Change in bytes of packaged source and bytecode of these modules.
+    pub size_delta: i64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_source_policy_parse() {
+        for policy in &[
+            SourcePolicy::SourceAndBytecode,
+            SourcePolicy::BytecodeOnly,
+            SourcePolicy::SourceOnly,
+        ] {
+            assert_eq!(SourcePolicy::parse(policy.as_str()).unwrap(), *policy);
+        }
+
+        assert!(SourcePolicy::parse("none").is_err());
+    }
+
+    #[test]
+    fn test_policy_pattern_matches() {
+        assert!(policy_pattern_matches("*", "json", false));
+        assert!(policy_pattern_matches("myapp.*", "myapp", false));
+        assert!(policy_pattern_matches("myapp.*", "myapp.cli.main", false));
+        assert!(!policy_pattern_matches("myapp.*", "myapp2", false));
+        assert!(policy_pattern_matches("myapp", "myapp", false));
+        assert!(!policy_pattern_matches("myapp", "myapp.cli", false));
+        assert!(policy_pattern_matches("stdlib:*", "json", true));
+        assert!(!policy_pattern_matches("stdlib:*", "black", false));
+    }
+
+    #[test]
+    fn test_resolve_policy() {
+        let policies = vec![
+            ("*".to_string(), 0),
+            ("stdlib:*".to_string(), 2),
+            ("myapp.*".to_string(), 1),
+            ("myapp.cli".to_string(), 3),
+        ];
+
+        let resolve =
+            |name: &str, is_stdlib: bool| resolve_policy(&policies, name, is_stdlib).map(|p| p.1);
+
+        assert_eq!(resolve("black", false), Some(0));
+        assert_eq!(resolve("json", true), Some(2));
+        assert_eq!(resolve("myapp.util", false), Some(1));
+        assert_eq!(resolve("myapp.cli", false), Some(3));
+        assert_eq!(resolve_policy::<i32>(&[], "json", true), None);
+    }
+}
@@ -0,0 +1,323 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/*!
+Building Python extension modules written in Rust.
+
+A crate producing a `cdylib`, e.g. one using PyO3 or rust-cpython, is
+built with cargo against the Python distribution of the application. The
+bindings find the distribution's interpreter through `PYO3_PYTHON` and
+`PYTHON_SYS_EXECUTABLE`. The crate is built for the application's target
+and profile. All extension module crates share a target directory next to
+the one of the application: the configuration is evaluated while cargo
+holds the lock on the application's target directory.
+
+The built library is renamed to the file name Python imports the extension
+module from, e.g. `_fast.cpython-37m-x86_64-linux-gnu.so`. It is loaded from
+the filesystem: Rust static libraries can't be linked into `libpython` next
+to the Rust code of the executable.
+*/
+
+use anyhow::{anyhow, Context, Result};
+use slog::warn;
+use std::path::{Path, PathBuf};
+
+use super::abi::major_minor;
+use super::build_system::is_cross_compiling;
+use super::distribution::{ExtensionModule, ParsedPythonDistribution};
+use crate::app_packaging::library::{is_cdylib, library_name, shared_library_filename};
+use crate::environment::frozen;
+
+/// A Rust crate providing a Python extension module.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RustExtensionModule {
+    /// Full name of the Python module, e.g. `myapp._fast`.
+    pub name: String,
+    /// Path to the crate's `Cargo.toml`.
+    pub manifest_path: PathBuf,
+    /// Cargo features to enable.
+    pub features: Vec<String>,
+}
+
+/// Obtain the suffix of extension module files for a target.
+///
+/// The suffix is tagged with the Python version like those of extension
+/// modules built by distutils, so the file can't be loaded by another
+/// version.
+pub fn extension_module_suffix(target: &str, python_version: &str) -> Result<String> {
+    let (major, minor) = major_minor(python_version)?;
+
+    if target.contains("pc-windows") {
+        let platform = if target.starts_with("x86_64") {
+            "win_amd64"
+        } else {
+            "win32"
+        };
+
+        return Ok(format!(".cp{}{}-{}.pyd", major, minor, platform));
+    }
+
+    // Python 3.8 dropped the `m` ABI flag.
+    let abiflags = if major == 3 && minor < 8 { "m" } else { "" };
+
+    let platform = if target.contains("apple-darwin") {
+        "darwin".to_string()
+    } else if target.contains("-linux-") {
+        let arch = target.split('-').next().unwrap();
+        let env = target.rsplit('-').next().unwrap();
+        format!("{}-linux-{}", arch, env)
+    } else {
+        return Ok(".so".to_string());
+    };
+
+    Ok(format!(
+        ".cpython-{}{}{}-{}.so",
+        major, minor, abiflags, platform
+    ))
+}
+
+/// Obtain the arguments to build an extension module crate with cargo.
+fn cargo_args(
+    module: &RustExtensionModule,
+    target: &str,
+    target_dir: &Path,
+    release: bool,
+    offline: bool,
+) -> Vec<String> {
+    let mut args = vec![
+        "rustc".to_string(),
+        "--lib".to_string(),
+        "--manifest-path".to_string(),
+        module.manifest_path.display().to_string(),
+        "--target".to_string(),
+        target.to_string(),
+        "--target-dir".to_string(),
+        target_dir.display().to_string(),
+    ];
+
+    if release {
+        args.push("--release".to_string());
+    }
+
+    if !module.features.is_empty() {
+        args.push("--features".to_string());
+        args.push(module.features.join(" "));
+    }
+
+    if frozen() {
+        args.push("--frozen".to_string());
+    } else if offline {
+        args.push("--offline".to_string());
+    }
+
+    // Python symbols are resolved from the executable when the extension
+    // module is loaded. Only the extension module's crate is built with
+    // these flags, so dependencies are shared between extension modules.
+    if target.contains("apple-darwin") {
+        args.extend(
+            [
+                "--",
+                "-C",
+                "link-arg=-undefined",
+                "-C",
+                "link-arg=dynamic_lookup",
+            ]
+            .iter()
+            .map(|s| s.to_string()),
+        );
+    }
+
+    args
+}
+
+/// Obtain the environment variables to build an extension module crate.
+fn cargo_envs(dist: &ParsedPythonDistribution, host: &str, target: &str) -> Vec<(String, String)> {
+    let python_exe = dist.python_exe.display().to_string();
+
+    let mut envs = vec![
+        ("PYO3_PYTHON".to_string(), python_exe.clone()),
+        ("PYTHON_SYS_EXECUTABLE".to_string(), python_exe),
+    ];
+
+    if is_cross_compiling(host, target) {
+        if let Some(lib_dir) = dist.stdlib_path.parent() {
+            envs.push((
+                "PYO3_CROSS_LIB_DIR".to_string(),
+                lib_dir.display().to_string(),
+            ));
+        }
+        envs.push((
+            "PYO3_CROSS_PYTHON_VERSION".to_string(),
+            dist.version
+                .split('.')
+                .take(2)
+                .collect::<Vec<_>>()
+                .join("."),
+        ));
+    }
+
+    envs
+}
+
+/// Build a Rust extension module.
+///
+/// The crate is built into `target_dir` and the library is copied to
+/// `dest_dir` under the file name the module is imported from.
+///
+/// If `offline`, cargo doesn't access the network.
+#[allow(clippy::too_many_arguments)]
+pub fn build_rust_extension_module(
+    logger: &slog::Logger,
+    module: &RustExtensionModule,
+    dist: &ParsedPythonDistribution,
+    host: &str,
+    target: &str,
+    target_dir: &Path,
+    release: bool,
+    offline: bool,
+    dest_dir: &Path,
+) -> Result<ExtensionModule> {
+    let manifest = cargo_toml::Manifest::from_slice(
+        &std::fs::read(&module.manifest_path)
+            .with_context(|| format!("reading {}", module.manifest_path.display()))?,
+    )
+    .with_context(|| format!("parsing {}", module.manifest_path.display()))?;
+
+    if !is_cdylib(&manifest) {
+        return Err(anyhow!(
+            "{} must set crate-type = [\"cdylib\"] in its [lib] section",
+            module.manifest_path.display()
+        ));
+    }
+
+    let lib_name = library_name(&manifest)?;
+    let leaf = module.name.rsplit('.').next().unwrap();
+
+    warn!(
+        logger,
+        "building Rust extension module {} from {}",
+        module.name,
+        module.manifest_path.display()
+    );
+
+    let status = std::process::Command::new("cargo")
+        .args(cargo_args(module, target, target_dir, release, offline))
+        .envs(cargo_envs(dist, host, target))
+        .status()
+        .context("running cargo")?;
+
+    if !status.success() {
+        return Err(anyhow!(
+            "cargo failed to build Rust extension module {}",
+            module.name
+        ));
+    }
+
+    let built_path = target_dir
+        .join(target)
+        .join(if release { "release" } else { "debug" })
+        .join(shared_library_filename(target, &lib_name));
+
+    if !built_path.exists() {
+        return Err(anyhow!("{} does not exist", built_path.display()));
+    }
+
+    std::fs::create_dir_all(dest_dir)
+        .with_context(|| format!("creating {}", dest_dir.display()))?;
+    let dest_path = dest_dir.join(format!(
+        "{}{}",
+        leaf,
+        extension_module_suffix(target, &dist.version)?
+    ));
+    std::fs::copy(&built_path, &dest_path)
+        .with_context(|| format!("copying {}", built_path.display()))?;
+
+    Ok(ExtensionModule {
+        module: module.name.clone(),
+        init_fn: Some(format!("PyInit_{}", leaf)),
+        builtin_default: false,
+        disableable: true,
+        object_paths: Vec::new(),
+        static_library: None,
+        shared_library: Some(dest_path),
+        links: Vec::new(),
+        required: false,
+        variant: "default".to_string(),
+        licenses: None,
+        license_paths: None,
+        license_public_domain: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_extension_module_suffix() -> Result<()> {
+        assert_eq!(
+            extension_module_suffix("x86_64-unknown-linux-gnu", "3.7.5")?,
+            ".cpython-37m-x86_64-linux-gnu.so"
+        );
+        assert_eq!(
+            extension_module_suffix("x86_64-unknown-linux-musl", "3.8.1")?,
+            ".cpython-38-x86_64-linux-musl.so"
+        );
+        assert_eq!(
+            extension_module_suffix("x86_64-apple-darwin", "3.7.5")?,
+            ".cpython-37m-darwin.so"
+        );
+        assert_eq!(
+            extension_module_suffix("x86_64-pc-windows-msvc", "3.7.5")?,
+            ".cp37-win_amd64.pyd"
+        );
+        assert_eq!(
+            extension_module_suffix("i686-pc-windows-msvc", "3.7.5")?,
+            ".cp37-win32.pyd"
+        );
+        assert!(extension_module_suffix("x86_64-unknown-linux-gnu", "unknown").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_cargo_args() {
+        let module = RustExtensionModule {
+            name: "myapp._fast".to_string(),
+            manifest_path: PathBuf::from("rust/fast/Cargo.toml"),
+            features: vec!["simd".to_string(), "serde".to_string()],
+        };
+        let target_dir = PathBuf::from("build").join("target");
+
+        let args = cargo_args(&module, "x86_64-apple-darwin", &target_dir, true, false);
+        let expected = [
+            "rustc",
+            "--lib",
+            "--manifest-path",
+            &module.manifest_path.display().to_string(),
+            "--target",
+            "x86_64-apple-darwin",
+            "--target-dir",
+            &target_dir.display().to_string(),
+            "--release",
+            "--features",
+            "simd serde",
+        ]
+        .iter()
+        .map(|s| s.to_string())
+        .collect::<Vec<_>>();
+        assert_eq!(args[..expected.len()], expected[..]);
+        assert_eq!(args.last().unwrap(), "link-arg=dynamic_lookup");
+
+        let args = cargo_args(
+            &module,
+            "x86_64-unknown-linux-gnu",
+            &target_dir,
+            false,
+            false,
+        );
+        assert!(!args.contains(&"--release".to_string()));
+        assert!(!args.contains(&"--".to_string()));
+    }
+}
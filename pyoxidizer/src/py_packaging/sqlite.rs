@@ -0,0 +1,234 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/*!
+SQLite as bundled by Python distributions.
+
+SQLite records the options it was compiled with as strings in the
+library, which `PRAGMA compile_options` reports at run-time. The same
+strings are found by scanning the library, so the options are known
+without running anything, even for distributions of another platform.
+
+`require_sqlite_options()` in a config makes building fail when the
+SQLite library of the distribution lacks options the application needs.
+*/
+
+use anyhow::{anyhow, Result};
+use std::collections::BTreeSet;
+
+use super::distribution::ParsedPythonDistribution;
+
+/// Directory in `FILESYSTEM_RELATIVE_DIR` holding SQLite loadable extensions.
+///
+/// This needs to be kept in sync with `oxidized_app.py`.
+pub const SQLITE_EXTENSIONS_DIR: &str = "sqlite-extensions";
+
+/// Prefixes of options SQLite records, without the `SQLITE_` prefix.
+const OPTION_PREFIXES: &[&str] = &[
+    "ALLOW_",
+    "ATOMIC_INTRINSICS",
+    "COMPILER=",
+    "DEFAULT_",
+    "DISABLE_",
+    "DQS=",
+    "ENABLE_",
+    "HAVE_",
+    "LIKE_DOESNT_MATCH_BLOBS",
+    "MALLOC_SOFT_LIMIT=",
+    "MAX_",
+    "MUTEX_",
+    "OMIT_",
+    "SECURE_DELETE",
+    "SYSTEM_MALLOC",
+    "TEMP_STORE=",
+    "THREADSAFE=",
+    "USE_",
+];
+
+/// Whether a string is an option as SQLite records it.
+fn is_option(s: &str) -> bool {
+    let name = s.split('=').next().unwrap_or(s);
+
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_uppercase() || c.is_ascii_digit() || c == '_')
+        && s.chars().all(|c| c.is_ascii_graphic() || c == ' ')
+        && OPTION_PREFIXES.iter().any(|prefix| s.starts_with(prefix))
+}
+
+/// Find the compile options recorded in a SQLite library.
+///
+/// `data` is the content of a static or shared library containing SQLite.
+/// Options are returned sorted, without the `SQLITE_` prefix, like
+/// `PRAGMA compile_options` reports them. `None` if `data` doesn't
+/// appear to contain SQLite's list of options.
+pub fn library_compile_options(data: &[u8]) -> Option<Vec<String>> {
+    let mut options = BTreeSet::new();
+
+    for run in data.split(|b| *b == 0) {
+        // Strings are NUL terminated but can directly follow other data.
+        let start = run
+            .iter()
+            .rposition(|b| !(b.is_ascii_graphic() || *b == b' '))
+            .map_or(0, |pos| pos + 1);
+        let s = String::from_utf8_lossy(&run[start..]);
+
+        if is_option(&s) {
+            options.insert(s.to_string());
+        }
+    }
+
+    // Every build records its threading mode.
+    if options.iter().any(|o| o.starts_with("THREADSAFE=")) {
+        Some(options.into_iter().collect())
+    } else {
+        None
+    }
+}
+
+/// Normalize an option name given by a user.
+pub fn normalize_option(option: &str) -> String {
+    let option = option.trim().to_ascii_uppercase();
+
+    if option.starts_with("SQLITE_") {
+        option["SQLITE_".len()..].to_string()
+    } else {
+        option
+    }
+}
+
+/// Obtain required options missing from the available options.
+///
+/// Options without a value match available options with any value.
+pub fn missing_options(available: &[String], required: &[String]) -> Vec<String> {
+    required
+        .iter()
+        .map(|option| normalize_option(option))
+        .filter(|option| {
+            !available.iter().any(|have| {
+                have == option
+                    || (!option.contains('=') && have.starts_with(&format!("{}=", option)))
+            })
+        })
+        .collect()
+}
+
+/// Ensure the SQLite library of a distribution has the required options.
+pub fn check_sqlite_options(dist: &ParsedPythonDistribution, required: &[String]) -> Result<()> {
+    if !dist.extension_modules.contains_key("_sqlite3") {
+        return Err(anyhow!(
+            "SQLite options {} are required but the Python distribution has no _sqlite3 extension module",
+            required.join(", ")
+        ));
+    }
+
+    let available = match &dist.sqlite_compile_options {
+        Some(options) => options,
+        None => {
+            return Err(anyhow!(
+                "SQLite options {} are required but the compile options of the Python distribution's SQLite library are unknown",
+                required.join(", ")
+            ))
+        }
+    };
+
+    let missing = missing_options(available, required);
+    if !missing.is_empty() {
+        return Err(anyhow!(
+            "the SQLite library of the Python distribution lacks required compile options {}; it was compiled with {}",
+            missing.join(", "),
+            available.join(", ")
+        ));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::*;
+
+    fn library(options: &[&str]) -> Vec<u8> {
+        let mut data = b"\x7fELF\x02\x01\x01\0\0\0unrelated_symbol\0PRAGMA %s\0".to_vec();
+
+        for option in options {
+            // Strings in .rodata may follow binary data without a NUL.
+            data.extend_from_slice(b"\x01\x02");
+            data.extend_from_slice(option.as_bytes());
+            data.push(0);
+        }
+
+        data.extend_from_slice(b"ENABLE_%s is not an option\0");
+
+        data
+    }
+
+    #[test]
+    fn test_library_compile_options() {
+        assert_eq!(
+            library_compile_options(&library(&[
+                "COMPILER=gcc-8.3.0",
+                "ENABLE_FTS5",
+                "ENABLE_JSON1",
+                "THREADSAFE=1",
+            ])),
+            Some(vec![
+                "COMPILER=gcc-8.3.0".to_string(),
+                "ENABLE_FTS5".to_string(),
+                "ENABLE_JSON1".to_string(),
+                "THREADSAFE=1".to_string(),
+            ])
+        );
+
+        assert_eq!(library_compile_options(&library(&["ENABLE_FTS5"])), None);
+    }
+
+    #[test]
+    fn test_missing_options() {
+        let available = vec![
+            "ENABLE_FTS5".to_string(),
+            "MAX_VARIABLE_NUMBER=250000".to_string(),
+            "THREADSAFE=1".to_string(),
+        ];
+
+        let missing = |required: &[&str]| {
+            missing_options(
+                &available,
+                &required.iter().map(|s| s.to_string()).collect::<Vec<_>>(),
+            )
+        };
+
+        assert!(missing(&["ENABLE_FTS5", "sqlite_enable_fts5", "THREADSAFE"]).is_empty());
+        assert!(missing(&["MAX_VARIABLE_NUMBER=250000"]).is_empty());
+        assert_eq!(
+            missing(&["ENABLE_FTS5", "ENABLE_JSON1"]),
+            vec!["ENABLE_JSON1"]
+        );
+        assert_eq!(missing(&["THREADSAFE=2"]), vec!["THREADSAFE=2"]);
+    }
+
+    #[test]
+    fn test_check_sqlite_options() -> Result<()> {
+        let dist = get_default_distribution()?;
+        let available = dist.sqlite_compile_options.clone().unwrap();
+        let required = available
+            .iter()
+            .filter(|o| o.starts_with("ENABLE_"))
+            .cloned()
+            .collect::<Vec<_>>();
+
+        assert!(check_sqlite_options(&dist, &required).is_ok());
+
+        let err = check_sqlite_options(&dist, &["ENABLE_NOT_AN_OPTION".to_string()])
+            .err()
+            .unwrap();
+        assert!(err
+            .to_string()
+            .contains("lacks required compile options ENABLE_NOT_AN_OPTION;"));
+
+        Ok(())
+    }
+}
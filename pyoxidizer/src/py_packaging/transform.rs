@@ -0,0 +1,385 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/*!
+Transforming the source of Python modules before it is packaged.
+
+`register_source_transform()` in a config rewrites the source of modules
+matching a pattern, e.g. to strip development-only code from one flavor of
+a product. Patterns are the module name patterns of source policies.
+
+Transforms are applied when `PythonExecutable()` collects resources. Every
+representation of a module is derived from the transformed source: the
+packaged source and the source bytecode is compiled from. So the two can't
+diverge.
+*/
+
+use anyhow::{anyhow, Context, Result};
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use super::embedded_resource::EmbeddedPythonResourcesPrePackaged;
+use super::retention::policy_pattern_matches;
+
+/// Environment variable holding the name of the module being transformed.
+pub const MODULE_NAME_ENV: &str = "PYOXIDIZER_MODULE_NAME";
+
+/// How a transform rewrites source.
+#[derive(Clone, Debug)]
+pub enum SourceTransformAction {
+    /// Run a command with the source on stdin. Its stdout is the new source.
+    Command { args: Vec<String>, cwd: PathBuf },
+    /// Replace all matches of a regular expression.
+    Regex {
+        regex: regex::bytes::Regex,
+        replacement: String,
+    },
+}
+
+/// A transform of the source of modules matching a pattern.
+#[derive(Clone, Debug)]
+pub struct SourceTransform {
+    pub pattern: String,
+    pub action: SourceTransformAction,
+}
+
+impl SourceTransform {
+    /// Define a transform running a command from directory `cwd`.
+    pub fn command(pattern: &str, args: &[String], cwd: &Path) -> Result<SourceTransform> {
+        if args.is_empty() {
+            return Err(anyhow!("command must not be empty"));
+        }
+
+        Ok(SourceTransform {
+            pattern: pattern.to_string(),
+            action: SourceTransformAction::Command {
+                args: args.to_vec(),
+                cwd: cwd.to_path_buf(),
+            },
+        })
+    }
+
+    /// Define a transform replacing matches of a regular expression.
+    ///
+    /// `replacement` may reference capture groups like `$1`.
+    pub fn regex(pattern: &str, regex: &str, replacement: &str) -> Result<SourceTransform> {
+        let regex = regex::bytes::Regex::new(regex)
+            .map_err(|e| anyhow!("invalid regular expression: {}", e))?;
+
+        Ok(SourceTransform {
+            pattern: pattern.to_string(),
+            action: SourceTransformAction::Regex {
+                regex,
+                replacement: replacement.to_string(),
+            },
+        })
+    }
+
+    /// Describe the transform for messages.
+    pub fn describe(&self) -> String {
+        match &self.action {
+            SourceTransformAction::Command { args, .. } => {
+                format!("{} (command {})", self.pattern, args.join(" "))
+            }
+            SourceTransformAction::Regex { regex, .. } => {
+                format!("{} (regex {})", self.pattern, regex.as_str())
+            }
+        }
+    }
+
+    /// Transform the source of a module.
+    pub fn apply(&self, name: &str, source: &[u8]) -> Result<Vec<u8>> {
+        match &self.action {
+            SourceTransformAction::Command { args, cwd } => {
+                run_transform_command(args, cwd, name, source)
+            }
+            SourceTransformAction::Regex { regex, replacement } => Ok(regex
+                .replace_all(source, replacement.as_bytes())
+                .into_owned()),
+        }
+    }
+}
+
+fn run_transform_command(
+    args: &[String],
+    cwd: &Path,
+    name: &str,
+    source: &[u8],
+) -> Result<Vec<u8>> {
+    let mut child = Command::new(&args[0])
+        .args(&args[1..])
+        .current_dir(cwd)
+        .env(MODULE_NAME_ENV, name)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("running {}", args[0]))?;
+
+    // Writing from another thread keeps a command writing output before
+    // reading all its input from blocking.
+    let mut stdin = child.stdin.take().unwrap();
+    let input = source.to_vec();
+    let writer = std::thread::spawn(move || stdin.write_all(&input));
+
+    let output = child.wait_with_output()?;
+    let written = writer.join().expect("stdin writer panicked");
+
+    if !output.status.success() {
+        return Err(anyhow!(
+            "{} failed with {}: {}",
+            args.join(" "),
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+
+    // A command may stop reading once it has what it needs.
+    written.or_else(|e| match e.kind() {
+        std::io::ErrorKind::BrokenPipe => Ok(()),
+        _ => Err(e),
+    })?;
+
+    Ok(output.stdout)
+}
+
+/// Apply source transforms to modules.
+///
+/// Transforms matching a module apply in the order they are given.
+/// `stdlib` holds the names of the distribution's standard library modules.
+/// Returns the modules each transform applied to, keyed by its description.
+pub fn apply_source_transforms(
+    transforms: &[SourceTransform],
+    resources: &mut EmbeddedPythonResourcesPrePackaged,
+    stdlib: &BTreeSet<String>,
+) -> Result<BTreeMap<String, BTreeSet<String>>> {
+    let mut applied: BTreeMap<String, BTreeSet<String>> = BTreeMap::new();
+
+    let mut names = BTreeSet::new();
+    names.extend(resources.source_modules.keys().cloned());
+    names.extend(resources.bytecode_modules.keys().cloned());
+
+    for name in names {
+        let matching = transforms
+            .iter()
+            .filter(|t| policy_pattern_matches(&t.pattern, &name, stdlib.contains(&name)))
+            .collect::<Vec<_>>();
+
+        if matching.is_empty() {
+            continue;
+        }
+
+        // Source and bytecode usually carry the same source. It is only
+        // transformed once then, so both get the same result.
+        let mut transformed: BTreeMap<Vec<u8>, Vec<u8>> = BTreeMap::new();
+        let mut transform = |source: &[u8]| -> Result<Vec<u8>> {
+            if let Some(result) = transformed.get(source) {
+                return Ok(result.clone());
+            }
+
+            let mut result = source.to_vec();
+            for t in &matching {
+                result = t.apply(&name, &result).with_context(|| {
+                    format!(
+                        "transforming source of module {} with transform {}",
+                        name,
+                        t.describe()
+                    )
+                })?;
+            }
+
+            transformed.insert(source.to_vec(), result.clone());
+
+            Ok(result)
+        };
+
+        if let Some(module) = resources.source_modules.get_mut(&name) {
+            module.source = transform(&module.source)?;
+        }
+        if let Some(module) = resources.bytecode_modules.get_mut(&name) {
+            module.source = transform(&module.source)?;
+        }
+
+        for t in &matching {
+            applied
+                .entry(t.describe())
+                .or_default()
+                .insert(name.clone());
+        }
+    }
+
+    Ok(applied)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::py_packaging::resource::{BytecodeOptimizationLevel, SourceModule};
+    use crate::testutil::*;
+    use indoc::indoc;
+
+    const SOURCE: &str = "MODE = 'release'\n# pragma: dev-only\nMODE = 'dev'\nassert MODE\n";
+
+    // Drops asserts and the line following a dev-only pragma.
+    const STRIP_DEV: &str = indoc!(
+        r#"
+        import os, sys
+
+        skip = False
+        for line in sys.stdin:
+            if line.strip() == '# pragma: dev-only':
+                skip = True
+            elif skip:
+                skip = False
+            elif not line.startswith('assert '):
+                sys.stdout.write(line)
+
+        if os.environ['PYOXIDIZER_MODULE_NAME'] == 'broken':
+            sys.exit('cannot transform')
+        "#
+    );
+
+    fn resources(names: &[&str]) -> EmbeddedPythonResourcesPrePackaged {
+        let mut resources = EmbeddedPythonResourcesPrePackaged::default();
+
+        for name in names {
+            let module = SourceModule {
+                name: name.to_string(),
+                source: SOURCE.as_bytes().to_vec(),
+                is_package: false,
+            };
+            resources.add_source_module(&module);
+            resources
+                .add_bytecode_module(&module.as_bytecode_module(BytecodeOptimizationLevel::Zero));
+        }
+
+        resources
+    }
+
+    /// Run the packaged bytecode of a module and obtain its `MODE`.
+    fn packaged_mode(
+        resources: &EmbeddedPythonResourcesPrePackaged,
+        python_exe: &Path,
+        sha256: &str,
+        name: &str,
+    ) -> Result<String> {
+        let temp_dir = tempdir::TempDir::new("pyoxidizer-test")?;
+        let path = temp_dir.path().join("code");
+
+        let packaged = resources.package(python_exe, sha256)?;
+        std::fs::write(&path, &packaged.module_bytecodes[name].bytecode)?;
+
+        let output = Command::new(python_exe)
+            .arg("-c")
+            .arg("import marshal, sys; ns = {}; exec(marshal.loads(open(sys.argv[1], 'rb').read()), ns); print(ns['MODE'])")
+            .arg(&path)
+            .output()?;
+        assert!(output.status.success());
+
+        Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+    }
+
+    #[test]
+    fn test_regex_transform() -> Result<()> {
+        let mut resources = resources(&["myapp", "myapp.cli", "other"]);
+        let transforms = vec![
+            SourceTransform::regex("myapp.*", r"(?m)^# pragma: dev-only\n.*\n", "")?,
+            SourceTransform::regex("myapp.cli", r"(?m)^assert (.*)$", "# assert $1")?,
+        ];
+
+        let applied = apply_source_transforms(&transforms, &mut resources, &BTreeSet::new())?;
+        assert_eq!(
+            applied
+                .values()
+                .map(|names| names.len())
+                .collect::<Vec<_>>(),
+            vec![2, 1]
+        );
+
+        assert_eq!(
+            resources.source_modules["myapp"].source,
+            b"MODE = 'release'\nassert MODE\n".to_vec()
+        );
+        assert_eq!(
+            resources.source_modules["myapp.cli"].source,
+            b"MODE = 'release'\n# assert MODE\n".to_vec()
+        );
+        assert_eq!(
+            resources.bytecode_modules["myapp.cli"].source,
+            resources.source_modules["myapp.cli"].source
+        );
+        assert_eq!(
+            resources.source_modules["other"].source,
+            SOURCE.as_bytes().to_vec()
+        );
+
+        assert!(SourceTransform::regex("*", "(", "").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_command_transform() -> Result<()> {
+        let dist = get_default_distribution()?;
+        let temp_dir = tempdir::TempDir::new("pyoxidizer-test")?;
+        std::fs::write(temp_dir.path().join("strip_dev.py"), STRIP_DEV)?;
+
+        let transform = SourceTransform::command(
+            "myapp.*",
+            &[
+                dist.python_exe.display().to_string(),
+                "strip_dev.py".to_string(),
+            ],
+            temp_dir.path(),
+        )?;
+
+        let untransformed = resources(&["myapp"]);
+        let mut transformed = untransformed.clone();
+        apply_source_transforms(&[transform.clone()], &mut transformed, &BTreeSet::new())?;
+
+        assert_eq!(
+            transformed.source_modules["myapp"].source,
+            b"MODE = 'release'\n".to_vec()
+        );
+
+        // The bytecode is compiled from the transformed source.
+        assert_eq!(
+            packaged_mode(
+                &untransformed,
+                &dist.python_exe,
+                &dist.archive_sha256,
+                "myapp"
+            )?,
+            "dev"
+        );
+        assert_eq!(
+            packaged_mode(
+                &transformed,
+                &dist.python_exe,
+                &dist.archive_sha256,
+                "myapp"
+            )?,
+            "release"
+        );
+
+        let mut broken = resources(&["broken"]);
+        let err = apply_source_transforms(
+            &[SourceTransform {
+                pattern: "*".to_string(),
+                ..transform
+            }],
+            &mut broken,
+            &BTreeSet::new(),
+        )
+        .err()
+        .unwrap();
+        assert!(format!("{:#}", err)
+            .starts_with("transforming source of module broken with transform * (command "));
+        assert!(format!("{:#}", err).contains("cannot transform"));
+
+        Ok(())
+    }
+}
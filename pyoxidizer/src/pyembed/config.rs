@@ -5,13 +5,16 @@
 //! Data structures for configuring a Python interpreter.
 
 use python3_sys as pyffi;
-use std::ffi::CString;
+use std::ffi::{CString, OsStr, OsString};
+use std::path::Path;
 
 /// Defines which allocator to use for the raw domain.
 #[derive(Clone, Debug)]
 pub enum PythonRawAllocator {
     /// Use jemalloc.
     Jemalloc,
+    /// Use mimalloc.
+    Mimalloc,
     /// Use the Rust global allocator.
     Rust,
     /// Use the system allocator.
@@ -29,6 +32,11 @@ pub enum PythonRunMode {
     Module { module: String },
     /// Evaluate Python code from a string.
     Eval { code: String },
+    /// The interpreter is embedded in a host application, which runs code.
+    ///
+    /// Nothing is run by default and the host's process arguments aren't
+    /// exposed as `sys.argv`.
+    Embedding,
 }
 
 /// Defines `terminfo`` database resolution semantics.
@@ -42,6 +50,53 @@ pub enum TerminfoResolution {
     Static(String),
 }
 
+/// Defines the value of `sys.executable`.
+#[derive(Clone, Debug)]
+pub enum SysExecutable {
+    /// Use the path of the current executable.
+    Exe,
+    /// Use an empty string, signaling there is no separate Python interpreter.
+    Empty,
+}
+
+/// Defines which importer wins when a module is available from multiple sources.
+#[derive(Clone, Debug)]
+pub enum ImporterPrecedence {
+    /// Modules embedded in the binary shadow modules on the filesystem.
+    MemoryFirst,
+    /// Modules on the filesystem shadow modules embedded in the binary.
+    FilesystemFirst,
+}
+
+impl ImporterPrecedence {
+    /// The value exposed as `sys.oxidized_importer_precedence`.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ImporterPrecedence::MemoryFirst => "memory-first",
+            ImporterPrecedence::FilesystemFirst => "filesystem-first",
+        }
+    }
+}
+
+/// Defines what replaces standard streams that aren't available.
+///
+/// Processes without a console, such as Windows GUI applications, don't have
+/// valid standard stream handles.
+#[derive(Clone, Debug)]
+pub enum DetachedStdio {
+    /// Discard output.
+    Null,
+    /// Append output to a log file, which is rotated when it grows large.
+    ///
+    /// ``$ORIGIN`` resolves to the directory of the application and ``{pid}``
+    /// to the process ID. Relative paths are resolved against ``$ORIGIN``.
+    LogFile(String),
+    /// Retain recent output in memory.
+    ///
+    /// Output is retrievable with ``oxidized_app.stdio_buffer()``.
+    Buffer,
+}
+
 /// Defines an extra extension module to load.
 #[derive(Clone, Debug)]
 pub struct ExtensionModule {
@@ -52,6 +107,21 @@ pub struct ExtensionModule {
     pub init_func: unsafe extern "C" fn() -> *mut pyffi::PyObject,
 }
 
+/// A file of modules and resources shared with other executables.
+#[derive(Clone, Debug)]
+pub struct SharedResources {
+    /// Path of the file holding the shared data.
+    ///
+    /// `$ORIGIN` is replaced by the directory of the binary.
+    pub path: String,
+
+    /// Hex SHA-256 of the file.
+    ///
+    /// The file is rejected if it differs from the one the binary was built
+    /// with.
+    pub sha256: String,
+}
+
 /// Holds the configuration of an embedded Python interpreter.
 ///
 /// Instances of this struct can be used to construct Python interpreters.
@@ -75,6 +145,11 @@ pub struct PythonConfig {
     /// Whether to load the filesystem-based sys.meta_path finder.
     pub filesystem_importer: bool,
 
+    /// Order of the in-memory and filesystem-based sys.meta_path finders.
+    ///
+    /// Only relevant if the filesystem importer is enabled.
+    pub importer_precedence: ImporterPrecedence,
+
     /// Filesystem paths to add to sys.path.
     ///
     /// ``$ORIGIN`` will resolve to the directory of the application at
@@ -121,6 +196,9 @@ pub struct PythonConfig {
     /// Whether stdout and stderr streams should be unbuffered.
     pub unbuffered_stdio: bool,
 
+    /// What replaces standard streams that aren't available.
+    pub detached_stdio: DetachedStdio,
+
     /// Whether to enable parser debugging output.
     pub parser_debug: bool,
 
@@ -152,6 +230,24 @@ pub struct PythonConfig {
     /// likely comes from an include_bytes!(...) of a file generated by PyOxidizer.
     pub py_resources_data: &'static [u8],
 
+    /// Modules and resources shared with other executables.
+    ///
+    /// Embedded modules and resources take precedence over shared ones of the
+    /// same name.
+    pub shared_resources: Option<SharedResources>,
+
+    /// SHA-256 of the packed module and resource data.
+    ///
+    /// Exposed as ``oxidized_app.resources_digest``. Computed by PyOxidizer
+    /// when packaging.
+    pub resources_digest: Option<String>,
+
+    /// SHA-256 of everything PyOxidizer embedded in the binary.
+    ///
+    /// Exposed as ``oxidized_app.build_id``. Computed by PyOxidizer when
+    /// packaging.
+    pub build_id: Option<String>,
+
     /// Extra extension modules to make available to the interpreter.
     ///
     /// The values will effectively be passed to ``PyImport_ExtendInitTab()``.
@@ -163,6 +259,9 @@ pub struct PythonConfig {
     /// values passed to `int main()`.
     pub argvb: bool,
 
+    /// Arguments to expose as `sys.argv` instead of the process arguments.
+    pub argv: Option<Vec<OsString>>,
+
     /// Whether to set sys.frozen=True.
     ///
     /// Setting this will enable Python to emulate "frozen" binaries, such as
@@ -175,6 +274,22 @@ pub struct PythonConfig {
     /// of setting this attribute.
     pub sys_meipass: bool,
 
+    /// What to set `sys.executable` to.
+    pub sys_executable: SysExecutable,
+
+    /// Directory to use for `sys.prefix` and `sys.exec_prefix`.
+    ///
+    /// ``$ORIGIN`` will resolve to the directory of the application at
+    /// run-time. Relative paths are resolved against ``$ORIGIN``.
+    pub sys_prefix: String,
+
+    /// Whether to behave like a `python` executable when the first argument
+    /// is `--pyoxidizer-run-python`.
+    ///
+    /// This allows code spawning `sys.executable` to run Python instead of
+    /// the application's default run mode.
+    pub run_python_marker: bool,
+
     /// Which memory allocator to use for the raw domain.
     pub raw_allocator: PythonRawAllocator,
 
@@ -189,7 +304,437 @@ pub struct PythonConfig {
     /// loaded in ``sys.modules``.
     pub write_modules_directory_env: Option<String>,
 
+    /// Environment variable holding the path to write an import profile to.
+    ///
+    /// If this value is set and the environment variable it refers to is set,
+    /// the importer records the time taken to execute each module and the
+    /// resources read. On interpreter shutdown, the recording is written to
+    /// the path as JSON.
+    pub import_profile_env: Option<String>,
+
+    /// Path to a file to persist REPL history to.
+    ///
+    /// ``~`` is expanded to the current user's home directory. If not set,
+    /// history is not persisted across REPL sessions.
+    pub repl_history_file: Option<String>,
+
+    /// Name of a module to import before running code.
+    ///
+    /// The module is imported once interpreter initialization is complete
+    /// (including installation of the module importer) and before the run
+    /// mode executes. It serves the role of ``sitecustomize``. If importing
+    /// fails, the traceback is printed and initialization fails.
+    pub startup_module: Option<String>,
+
+    /// Python code to execute before running code.
+    ///
+    /// Behaves like ``startup_module`` except the code is executed in a fresh
+    /// namespace. Only one of the two should be set.
+    pub startup_code: Option<String>,
+
+    /// Whether functions registered with ``atexit`` are called when the
+    /// interpreter is finalized.
+    pub run_atexit: bool,
+
+    /// Whether finalizing the interpreter waits for non-daemon threads to
+    /// finish, like ``python`` does.
+    ///
+    /// If not, threads still running are abandoned like daemon threads.
+    pub wait_for_non_daemon_threads: bool,
+
+    /// Whether Python installs its signal handlers when initialized.
+    ///
+    /// Like ``python``, ``SIGINT`` then raises ``KeyboardInterrupt`` and
+    /// ``SIGPIPE`` and ``SIGXFSZ`` are ignored. If not, the process keeps
+    /// the handlers it has, so applications can install their own, e.g. an
+    /// event loop's, without Python's getting in the way.
+    pub install_signal_handlers: bool,
+
+    /// Whether to install the ``pkg_resources`` compatibility shim.
+    ///
+    /// ``pkg_resources`` then reads resources of modules imported from
+    /// memory and finds embedded distribution metadata. The
+    /// ``_oxidized_pkg_resources`` module must be embedded.
+    pub pkg_resources_compat: bool,
+
     /// Defines what code to run by default.
     ///
     pub run: PythonRunMode,
+
+    /// Run modes selected by the name the program is invoked as.
+    ///
+    /// Allows a single binary to act as multiple programs (like busybox)
+    /// through links to it. Names are matched against the file name of
+    /// ``argv[0]`` without a ``.exe`` extension, case-insensitively on
+    /// Windows. ``run`` is used if no name matches.
+    pub multicall_run_modes: Vec<(String, PythonRunMode)>,
+}
+
+/// Obtain the program name from ``argv[0]``.
+///
+/// This is the file name without a ``.exe`` extension.
+pub fn program_name(argv0: &OsStr) -> String {
+    let name = Path::new(argv0)
+        .file_name()
+        .unwrap_or(argv0)
+        .to_string_lossy()
+        .to_string();
+
+    if name.to_lowercase().ends_with(".exe") {
+        name[0..name.len() - 4].to_string()
+    } else {
+        name
+    }
+}
+
+/// Find the run mode for a program name.
+fn find_multicall_run_mode<'a>(
+    run_modes: &'a [(String, PythonRunMode)],
+    program: &str,
+    case_insensitive: bool,
+) -> Option<&'a PythonRunMode> {
+    run_modes
+        .iter()
+        .find(|(name, _)| {
+            if case_insensitive {
+                name.to_lowercase() == program.to_lowercase()
+            } else {
+                name == program
+            }
+        })
+        .map(|(_, run_mode)| run_mode)
+}
+
+impl PythonConfig {
+    /// Resolve the run mode for a program invoked as ``argv0``.
+    pub fn resolve_run_mode(&self, argv0: Option<&OsStr>) -> &PythonRunMode {
+        let program = match argv0 {
+            Some(argv0) => program_name(argv0),
+            None => return &self.run,
+        };
+
+        find_multicall_run_mode(&self.multicall_run_modes, &program, cfg!(windows))
+            .unwrap_or(&self.run)
+    }
+}
+
+/// Python code adjusting interpreter state before it is finalized.
+///
+/// `Py_FinalizeEx()` waits for non-daemon threads by calling
+/// `threading._shutdown()` if `threading` was imported, then calls `atexit`
+/// functions. Returns `None` if finalization behaves like `python`.
+pub fn pre_finalize_code(run_atexit: bool, wait_for_non_daemon_threads: bool) -> Option<String> {
+    let mut code = String::new();
+
+    if !wait_for_non_daemon_threads {
+        code.push_str(
+            "import sys\n\
+             if 'threading' in sys.modules:\n    \
+             sys.modules['threading']._shutdown = lambda: None\n",
+        );
+    }
+
+    if !run_atexit {
+        code.push_str("import atexit\natexit._clear()\n");
+    }
+
+    if code.is_empty() {
+        None
+    } else {
+        Some(code)
+    }
+}
+
+/// Exit code of a process after the interpreter is finalized.
+///
+/// Like `python`, 120 is used if finalization failed, e.g. because flushing
+/// buffered standard streams failed.
+pub fn finalized_exit_code(code: i32, finalize_result: i32) -> i32 {
+    if finalize_result < 0 {
+        120
+    } else {
+        code
+    }
+}
+
+/// Reserved argument running the code given as the next argument.
+pub const OVERRIDE_CODE_ARG: &str = "--pyoxidizer-c";
+
+/// Reserved argument running the file given as the next argument, or stdin for `-`.
+pub const OVERRIDE_EVAL_ARG: &str = "--pyoxidizer-eval";
+
+/// Reserved argument starting a REPL.
+pub const OVERRIDE_REPL_ARG: &str = "--pyoxidizer-repl";
+
+/// Code requested by reserved arguments.
+#[cfg(any(test, pyembed_runtime_code_override))]
+#[derive(Debug, PartialEq)]
+enum OverrideCode {
+    Inline(String),
+    File(OsString),
+    Stdin,
+    Repl,
+}
+
+/// Parse reserved arguments replacing the run mode.
+///
+/// The reserved argument must be the first argument. Returns the requested
+/// code and the arguments to expose as `sys.argv`, which are what `python`
+/// would set for the equivalent invocation.
+#[cfg(any(test, pyembed_runtime_code_override))]
+fn parse_runtime_code_override(
+    args: &[OsString],
+) -> Result<Option<(OverrideCode, Vec<OsString>)>, String> {
+    let flag = match args.get(1).and_then(|a| a.to_str()) {
+        Some(flag) => flag,
+        None => return Ok(None),
+    };
+
+    if flag == OVERRIDE_REPL_ARG {
+        let mut argv = vec![OsString::new()];
+        argv.extend(args[2..].iter().cloned());
+
+        return Ok(Some((OverrideCode::Repl, argv)));
+    }
+
+    if flag != OVERRIDE_CODE_ARG && flag != OVERRIDE_EVAL_ARG {
+        return Ok(None);
+    }
+
+    let value = args
+        .get(2)
+        .ok_or_else(|| format!("argument expected for {}", flag))?;
+
+    let (code, argv0) = if flag == OVERRIDE_CODE_ARG {
+        (
+            OverrideCode::Inline(value.to_string_lossy().to_string()),
+            OsString::from("-c"),
+        )
+    } else if value == "-" {
+        (OverrideCode::Stdin, value.clone())
+    } else {
+        (OverrideCode::File(value.clone()), value.clone())
+    };
+
+    let mut argv = vec![argv0];
+    argv.extend(args[3..].iter().cloned());
+
+    Ok(Some((code, argv)))
+}
+
+/// Resolve the run mode and `sys.argv` requested by reserved arguments.
+#[cfg(any(test, pyembed_runtime_code_override))]
+fn resolve_runtime_code_override(
+    args: &[OsString],
+) -> Result<Option<(PythonRunMode, Vec<OsString>)>, String> {
+    use std::io::Read;
+
+    let (code, argv) = match parse_runtime_code_override(args)? {
+        Some(v) => v,
+        None => return Ok(None),
+    };
+
+    let run = match code {
+        OverrideCode::Inline(code) => PythonRunMode::Eval { code },
+        OverrideCode::File(path) => PythonRunMode::Eval {
+            code: std::fs::read_to_string(&path)
+                .map_err(|e| format!("unable to read {}: {}", path.to_string_lossy(), e))?,
+        },
+        OverrideCode::Stdin => {
+            let mut code = String::new();
+            std::io::stdin()
+                .read_to_string(&mut code)
+                .map_err(|e| format!("unable to read code from stdin: {}", e))?;
+
+            PythonRunMode::Eval { code }
+        }
+        OverrideCode::Repl => PythonRunMode::Repl,
+    };
+
+    Ok(Some((run, argv)))
+}
+
+/// Replace the run mode with one requested by reserved process arguments.
+///
+/// When the first process argument is `--pyoxidizer-c <code>`,
+/// `--pyoxidizer-eval <path>` (`-` reads stdin) or `--pyoxidizer-repl`, the
+/// configured run mode is replaced with running that code or a REPL and the
+/// reserved arguments are removed from `sys.argv`.
+///
+/// This must be called before the interpreter is initialized. It only has
+/// an effect if the PyOxidizer config set `allow_runtime_code_override`.
+/// Otherwise support is compiled out and arguments are left alone.
+#[cfg(pyembed_runtime_code_override)]
+pub fn apply_runtime_code_override(config: &mut PythonConfig) -> Result<(), String> {
+    let args = std::env::args_os().collect::<Vec<_>>();
+
+    if let Some((run, argv)) = resolve_runtime_code_override(&args)? {
+        // The requested code wins over run modes selected by program name.
+        config.run = run;
+        config.multicall_run_modes.clear();
+        config.argv = Some(argv);
+    }
+
+    Ok(())
+}
+
+/// Replace the run mode with one requested by reserved process arguments.
+///
+/// Support was compiled out because the PyOxidizer config didn't set
+/// `allow_runtime_code_override`. So this does nothing.
+#[cfg(not(pyembed_runtime_code_override))]
+pub fn apply_runtime_code_override(_config: &mut PythonConfig) -> Result<(), String> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run_modes() -> Vec<(String, PythonRunMode)> {
+        vec![
+            (
+                "myapp-worker".to_string(),
+                PythonRunMode::Module {
+                    module: "myapp.worker".to_string(),
+                },
+            ),
+            ("myapp-repl".to_string(), PythonRunMode::Repl),
+        ]
+    }
+
+    fn module(run_mode: Option<&PythonRunMode>) -> Option<String> {
+        match run_mode {
+            Some(PythonRunMode::Module { module }) => Some(module.clone()),
+            Some(PythonRunMode::Repl) => Some("<repl>".to_string()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_program_name() {
+        assert_eq!(program_name(OsStr::new("myapp")), "myapp");
+        assert_eq!(
+            program_name(OsStr::new("/usr/bin/myapp-worker")),
+            "myapp-worker"
+        );
+        assert_eq!(
+            program_name(OsStr::new("./myapp-worker.exe")),
+            "myapp-worker"
+        );
+        assert_eq!(program_name(OsStr::new("MYAPP-WORKER.EXE")), "MYAPP-WORKER");
+    }
+
+    #[test]
+    fn test_parse_runtime_code_override() {
+        let args = |values: &[&str]| values.iter().map(OsString::from).collect::<Vec<_>>();
+
+        assert_eq!(parse_runtime_code_override(&args(&["myapp"])), Ok(None));
+        assert_eq!(
+            parse_runtime_code_override(&args(&["myapp", "--verbose", "--pyoxidizer-c"])),
+            Ok(None)
+        );
+        assert_eq!(
+            parse_runtime_code_override(&args(&["myapp", "--pyoxidizer-c", "print(1)", "a"])),
+            Ok(Some((
+                OverrideCode::Inline("print(1)".to_string()),
+                args(&["-c", "a"])
+            )))
+        );
+        assert_eq!(
+            parse_runtime_code_override(&args(&["myapp", "--pyoxidizer-eval", "-"])),
+            Ok(Some((OverrideCode::Stdin, args(&["-"]))))
+        );
+        assert_eq!(
+            parse_runtime_code_override(&args(&["myapp", "--pyoxidizer-eval", "s.py", "-v"])),
+            Ok(Some((
+                OverrideCode::File(OsString::from("s.py")),
+                args(&["s.py", "-v"])
+            )))
+        );
+        assert_eq!(
+            parse_runtime_code_override(&args(&["myapp", "--pyoxidizer-repl"])),
+            Ok(Some((OverrideCode::Repl, args(&[""]))))
+        );
+        assert_eq!(
+            parse_runtime_code_override(&args(&["myapp", "--pyoxidizer-c"])),
+            Err("argument expected for --pyoxidizer-c".to_string())
+        );
+    }
+
+    #[test]
+    fn test_resolve_runtime_code_override() {
+        let path = std::env::temp_dir().join(format!("pyembed-override-{}.py", std::process::id()));
+        std::fs::write(&path, "print('file')").unwrap();
+
+        let args = vec![
+            OsString::from("myapp"),
+            OsString::from("--pyoxidizer-eval"),
+            path.clone().into_os_string(),
+        ];
+        match resolve_runtime_code_override(&args) {
+            Ok(Some((PythonRunMode::Eval { code }, argv))) => {
+                assert_eq!(code, "print('file')");
+                assert_eq!(argv, vec![path.clone().into_os_string()]);
+            }
+            res => panic!("unexpected result: {:?}", res),
+        }
+
+        std::fs::remove_file(&path).unwrap();
+        assert!(resolve_runtime_code_override(&args)
+            .unwrap_err()
+            .starts_with("unable to read"));
+    }
+
+    #[test]
+    fn test_pre_finalize_code() {
+        assert_eq!(pre_finalize_code(true, true), None);
+        assert_eq!(
+            pre_finalize_code(false, true),
+            Some("import atexit\natexit._clear()\n".to_string())
+        );
+
+        // A lingering non-daemon thread isn't waited for.
+        let code = pre_finalize_code(true, false).unwrap();
+        assert!(code.contains("sys.modules['threading']._shutdown = lambda: None\n"));
+        assert!(!code.contains("atexit"));
+
+        let code = pre_finalize_code(false, false).unwrap();
+        assert!(code.contains("_shutdown"));
+        assert!(code.ends_with("atexit._clear()\n"));
+    }
+
+    #[test]
+    fn test_finalized_exit_code() {
+        assert_eq!(finalized_exit_code(0, 0), 0);
+        assert_eq!(finalized_exit_code(3, 0), 3);
+        assert_eq!(finalized_exit_code(0, -1), 120);
+        assert_eq!(finalized_exit_code(3, -1), 120);
+    }
+
+    #[test]
+    fn test_find_multicall_run_mode() {
+        let run_modes = run_modes();
+
+        assert_eq!(
+            module(find_multicall_run_mode(&run_modes, "myapp-worker", false)),
+            Some("myapp.worker".to_string())
+        );
+        assert_eq!(
+            module(find_multicall_run_mode(&run_modes, "myapp-repl", false)),
+            Some("<repl>".to_string())
+        );
+        assert_eq!(
+            module(find_multicall_run_mode(&run_modes, "myapp", false)),
+            None
+        );
+        assert_eq!(
+            module(find_multicall_run_mode(&run_modes, "MyApp-Worker", false)),
+            None
+        );
+        assert_eq!(
+            module(find_multicall_run_mode(&run_modes, "MyApp-Worker", true)),
+            Some("myapp.worker".to_string())
+        );
+    }
 }
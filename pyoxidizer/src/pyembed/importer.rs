@@ -10,6 +10,7 @@ for importing Python modules from memory.
 */
 
 use std::cell::RefCell;
+use std::collections::hash_map::Entry;
 use std::collections::{HashMap, HashSet};
 use std::ffi::CStr;
 use std::io::Cursor;
@@ -24,6 +25,7 @@ use cpython::{
 use python3_sys as pyffi;
 use python3_sys::{PyBUF_READ, PyMemoryView_FromMemory};
 
+use super::profile::SharedImportProfile;
 use super::pyinterp::PYOXIDIZER_IMPORTER_NAME;
 
 /// Obtain a Python memoryview referencing a memory slice.
@@ -37,7 +39,7 @@ fn get_memory_view(py: Python, data: &'static [u8]) -> Option<PyObject> {
 }
 
 /// Holds pointers to Python module data in memory.
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug)]
 struct PythonModuleData {
     source: Option<&'static [u8]>,
     bytecode: Option<&'static [u8]>,
@@ -251,6 +253,230 @@ impl PythonResourcesData {
     }
 }
 
+/// Magic bytes at the start of lazily indexed modules and resources data.
+const LAZY_INDEX_MAGIC: &[u8] = b"pyoxlz01";
+
+/// Parse the group index of lazily indexed data.
+///
+/// Returns `None` if the data is in the original format. Otherwise maps each
+/// top-level package to its group, which is data in the original format.
+fn parse_lazy_index(
+    data: &'static [u8],
+) -> Result<Option<HashMap<&'static str, &'static [u8]>>, &'static str> {
+    if !data.starts_with(LAZY_INDEX_MAGIC) {
+        return Ok(None);
+    }
+
+    let mut reader = Cursor::new(&data[LAZY_INDEX_MAGIC.len()..]);
+
+    let count = reader
+        .read_u32::<LittleEndian>()
+        .or_else(|_| Err("failed reading group count"))? as usize;
+
+    let mut index = Vec::with_capacity(count);
+    let mut total_names_length = 0;
+
+    for _ in 0..count {
+        let name_length = reader
+            .read_u32::<LittleEndian>()
+            .or_else(|_| Err("failed reading group name length"))? as usize;
+        let data_length = reader
+            .read_u32::<LittleEndian>()
+            .or_else(|_| Err("failed reading group data length"))? as usize;
+
+        index.push((name_length, data_length));
+        total_names_length += name_length;
+    }
+
+    let mut name_offset = LAZY_INDEX_MAGIC.len() + reader.position() as usize;
+    let mut data_offset = name_offset + total_names_length;
+    let mut res = HashMap::with_capacity(count);
+
+    for (name_length, data_length) in index {
+        if data_offset + data_length > data.len() {
+            return Err("lazy index data truncated");
+        }
+
+        let name =
+            unsafe { std::str::from_utf8_unchecked(&data[name_offset..name_offset + name_length]) };
+
+        name_offset += name_length;
+
+        res.insert(name, &data[data_offset..data_offset + data_length]);
+
+        data_offset += data_length;
+    }
+
+    Ok(Some(res))
+}
+
+/// Index of the modules and resources the importer knows about.
+///
+/// Lazily indexed data is grouped by top-level package. Groups are only
+/// parsed, or hydrated, once a name in their package is first looked up, so
+/// startup cost doesn't grow with the number of embedded modules.
+struct ImporterIndex {
+    /// Packages with parsed module data.
+    packages: HashSet<&'static str>,
+
+    /// Maps module name to the importer providing it.
+    known_modules: KnownModules,
+
+    /// Maps package name to its resources.
+    resources: HashMap<&'static str, Arc<Box<HashMap<&'static str, &'static [u8]>>>>,
+
+    /// Modules data not yet parsed, keyed by top-level package.
+    ///
+    /// A package has several groups if shared data also provides it. Later
+    /// groups take precedence.
+    pending_modules: HashMap<&'static str, Vec<&'static [u8]>>,
+
+    /// Resources data not yet parsed, keyed by top-level package.
+    pending_resources: HashMap<&'static str, Vec<&'static [u8]>>,
+}
+
+impl ImporterIndex {
+    /// Register modules from data in the original format.
+    ///
+    /// In-memory modules replace builtin and frozen modules of the same name.
+    fn add_modules(&mut self, data: &'static [u8]) -> Result<(), &'static str> {
+        let modules_data = PythonModulesData::from(data)?;
+
+        for (name, record) in modules_data.data {
+            if modules_data.packages.contains(name) {
+                self.packages.insert(name);
+            } else {
+                self.packages.remove(name);
+            }
+
+            self.known_modules.insert(
+                name,
+                KnownModuleFlavor::InMemory {
+                    module_data: record,
+                },
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Register resources from data in the original format.
+    ///
+    /// Resources replace those of the same name in the same package.
+    fn add_resources(&mut self, data: &'static [u8]) -> Result<(), &'static str> {
+        for (package, resources) in PythonResourcesData::from(data)?.packages {
+            match self.resources.entry(package) {
+                Entry::Occupied(mut existing) => {
+                    Arc::make_mut(existing.get_mut())
+                        .extend(resources.iter().map(|(name, data)| (*name, *data)));
+                }
+                Entry::Vacant(vacant) => {
+                    vacant.insert(resources);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Register modules and resources data in either format.
+    ///
+    /// Data added later takes precedence. Lazily indexed groups are queued
+    /// behind pending groups of the same package. Before adding data in the
+    /// original format, all pending groups are parsed, so the new data
+    /// replaces them.
+    fn add_data(
+        &mut self,
+        modules: &'static [u8],
+        resources: &'static [u8],
+    ) -> Result<(), &'static str> {
+        // It may seem inefficient to create a full HashMap of the parsed data instead of e.g.
+        // streaming it. But the overhead of iterators was measured to be more than building
+        // up a temporary HashMap.
+        match parse_lazy_index(modules)? {
+            Some(groups) => {
+                for (name, group) in groups {
+                    self.pending_modules
+                        .entry(name)
+                        .or_insert_with(Vec::new)
+                        .push(group);
+                }
+            }
+            None => {
+                self.hydrate_all()?;
+                self.add_modules(modules)?;
+            }
+        }
+
+        match parse_lazy_index(resources)? {
+            Some(groups) => {
+                for (name, group) in groups {
+                    self.pending_resources
+                        .entry(name)
+                        .or_insert_with(Vec::new)
+                        .push(group);
+                }
+            }
+            None => {
+                self.hydrate_all()?;
+                self.add_resources(resources)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Parse pending data for the top-level package of a module or package name.
+    fn hydrate(&mut self, name: &str) -> Result<(), &'static str> {
+        if self.pending_modules.is_empty() && self.pending_resources.is_empty() {
+            return Ok(());
+        }
+
+        let top_level = name.split('.').next().unwrap_or(name);
+
+        if let Some(groups) = self.pending_modules.remove(top_level) {
+            for data in groups {
+                self.add_modules(data)?;
+            }
+        }
+
+        if let Some(groups) = self.pending_resources.remove(top_level) {
+            for data in groups {
+                self.add_resources(data)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Parse all pending data.
+    fn hydrate_all(&mut self) -> Result<(), &'static str> {
+        let names = self
+            .pending_modules
+            .keys()
+            .chain(self.pending_resources.keys())
+            .cloned()
+            .collect::<Vec<_>>();
+
+        for name in names {
+            self.hydrate(name)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Hydrate the index for a name, raising a Python exception on failure.
+///
+/// The index is only borrowed for the duration of this call, so callers can
+/// safely call into Python afterwards.
+fn hydrate_index(py: Python, index: &RefCell<ImporterIndex>, name: &str) -> PyResult<()> {
+    match index.borrow_mut().hydrate(name) {
+        Ok(()) => Ok(()),
+        Err(msg) => Err(PyErr::new::<ValueError, _>(py, msg)),
+    }
+}
+
 #[allow(unused_doc_comments)]
 /// Python type to import modules.
 ///
@@ -266,17 +492,22 @@ py_class!(class PyOxidizerFinder |py| {
     data module_spec_type: PyObject;
     data decode_source: PyObject;
     data exec_fn: PyObject;
-    data packages: HashSet<&'static str>;
-    data known_modules: KnownModules;
-    data resources: HashMap<&'static str, Arc<Box<HashMap<&'static str, &'static [u8]>>>>;
+    data index: RefCell<ImporterIndex>;
     data resource_readers: RefCell<Box<HashMap<String, PyObject>>>;
+    data import_profile: Option<SharedImportProfile>;
 
     // Start of importlib.abc.MetaPathFinder interface.
 
     def find_spec(&self, fullname: &PyString, path: &PyObject, target: Option<PyObject> = None) -> PyResult<PyObject> {
         let key = fullname.to_string(py)?;
 
-        if let Some(flavor) = self.known_modules(py).get(&*key) {
+        hydrate_index(py, self.index(py), &key)?;
+        let (flavor, is_package) = {
+            let index = self.index(py).borrow();
+            (index.known_modules.get(&*key).cloned(), index.packages.contains(&*key))
+        };
+
+        if let Some(flavor) = flavor {
             match flavor {
                 KnownModuleFlavor::Builtin => {
                     // BuiltinImporter.find_spec() always returns None if `path` is defined.
@@ -287,8 +518,6 @@ py_class!(class PyOxidizerFinder |py| {
                     self.frozen_importer(py).call_method(py, "find_spec", (fullname, path, target), None)
                 }
                 KnownModuleFlavor::InMemory { .. } => {
-                    let is_package = self.packages(py).contains(&*key);
-
                     // TODO consider setting origin and has_location so __file__ will be
                     // populated.
 
@@ -325,8 +554,15 @@ py_class!(class PyOxidizerFinder |py| {
         let name = module.getattr(py, "__name__")?;
         let key = name.extract::<String>(py)?;
 
-        if let Some(flavor) = self.known_modules(py).get(&*key) {
-            match flavor {
+        hydrate_index(py, self.index(py), &key)?;
+        let flavor = self.index(py).borrow().known_modules.get(&*key).cloned();
+
+        if let Some(flavor) = flavor {
+            if let Some(profile) = self.import_profile(py) {
+                profile.lock().unwrap().begin();
+            }
+
+            let res = match flavor {
                 KnownModuleFlavor::Builtin => {
                     self.builtin_importer(py).call_method(py, "exec_module", (module,), None)
                 },
@@ -347,7 +583,13 @@ py_class!(class PyOxidizerFinder |py| {
                         }
                     }
                 },
+            };
+
+            if let Some(profile) = self.import_profile(py) {
+                profile.lock().unwrap().end(&key, flavor.profile_source(), flavor.data_len());
             }
+
+            res
         } else {
             // Raising here might make more sense, as exec_module() shouldn't
             // be called on the Loader that didn't create the module.
@@ -362,7 +604,10 @@ py_class!(class PyOxidizerFinder |py| {
     def get_code(&self, fullname: &PyString) -> PyResult<PyObject> {
         let key = fullname.to_string(py)?;
 
-        if let Some(flavor) = self.known_modules(py).get(&*key) {
+        hydrate_index(py, self.index(py), &key)?;
+        let flavor = self.index(py).borrow().known_modules.get(&*key).cloned();
+
+        if let Some(flavor) = flavor {
             match flavor {
                 KnownModuleFlavor::Frozen => {
                     let imp_module = self.imp_module(py);
@@ -391,7 +636,10 @@ py_class!(class PyOxidizerFinder |py| {
     def get_source(&self, fullname: &PyString) -> PyResult<PyObject> {
         let key = fullname.to_string(py)?;
 
-        if let Some(flavor) = self.known_modules(py).get(&*key) {
+        hydrate_index(py, self.index(py), &key)?;
+        let flavor = self.index(py).borrow().known_modules.get(&*key).cloned();
+
+        if let Some(flavor) = flavor {
             if let KnownModuleFlavor::InMemory { module_data } = flavor {
                 match module_data.get_source_memory_view(py) {
                     Some(value) => {
@@ -401,9 +649,10 @@ py_class!(class PyOxidizerFinder |py| {
                         let b = value.call_method(py, "tobytes", NoArgs, None)?;
                         self.decode_source(py).call(py, (b,), None)
                     },
-                    None => {
-                        Err(PyErr::new::<ImportError, _>(py, ("source not available", fullname)))
-                    }
+                    // Modules packaged as bytecode only have no source. Per
+                    // InspectLoader, that is None, so tracebacks omit source
+                    // lines instead of failing to load them.
+                    None => Ok(py.None()),
                 }
             } else {
                 Ok(py.None())
@@ -433,19 +682,31 @@ py_class!(class PyOxidizerFinder |py| {
             return Ok(reader.clone_ref(py));
         }
 
-        // Only create a reader if the name is a package.
-        if self.packages(py).contains(&*key) {
+        hydrate_index(py, self.index(py), &key)?;
+        let (is_package, resources) = {
+            let index = self.index(py).borrow();
+            (index.packages.contains(&*key), index.resources.get(&*key).cloned())
+        };
+
+        // Only create a reader if the name is a package. Resources not
+        // belonging to a module, like embedded config files, have one too.
+        if is_package || resources.is_some() {
 
             // Not all packages have known resources.
-            let resources = match self.resources(py).get(&*key) {
-                Some(v) => v.clone(),
+            let resources = match resources {
+                Some(v) => v,
                 None => {
                     let h: Box<HashMap<&'static str, &'static [u8]>> = Box::new(HashMap::new());
                     Arc::new(h)
                 }
             };
 
-            let reader = PyOxidizerResourceReader::create_instance(py, resources)?.into_object();
+            let reader = PyOxidizerResourceReader::create_instance(
+                py,
+                resources,
+                key.to_string(),
+                self.import_profile(py).clone(),
+            )?.into_object();
             resource_readers.insert(key.to_string(), reader.clone_ref(py));
 
             Ok(reader)
@@ -461,6 +722,8 @@ py_class!(class PyOxidizerFinder |py| {
 /// Implements importlib.abc.ResourceReader.
 py_class!(class PyOxidizerResourceReader |py| {
     data resources: Arc<Box<HashMap<&'static str, &'static [u8]>>>;
+    data package: String;
+    data import_profile: Option<SharedImportProfile>;
 
     /// Returns an opened, file-like object for binary reading of the resource.
     ///
@@ -469,6 +732,10 @@ py_class!(class PyOxidizerResourceReader |py| {
         let key = resource.to_string(py)?;
 
         if let Some(data) = self.resources(py).get(&*key) {
+            if let Some(profile) = self.import_profile(py) {
+                profile.lock().unwrap().record_resource(self.package(py), &key, data.len());
+            }
+
             match get_memory_view(py, data) {
                 Some(mv) => {
                     let io_module = py.import("io")?;
@@ -527,6 +794,70 @@ py_class!(class PyOxidizerResourceReader |py| {
     }
 });
 
+#[allow(unused_doc_comments)]
+/// Times executing a module the filesystem importer loads.
+///
+/// Instances replace the `exec_module` method of a loader instance.
+py_class!(class ProfiledExecModule |py| {
+    data exec_module: PyObject;
+    data name: String;
+    data bytes: usize;
+    data import_profile: SharedImportProfile;
+
+    def __call__(&self, module: PyObject) -> PyResult<PyObject> {
+        self.import_profile(py).lock().unwrap().begin();
+        let res = self.exec_module(py).call(py, (module,), None);
+        self.import_profile(py).lock().unwrap().end(self.name(py), "filesystem", *self.bytes(py));
+
+        res
+    }
+});
+
+#[allow(unused_doc_comments)]
+/// Python type timing imports of the filesystem importer.
+///
+/// Takes the place of PathFinder on sys.meta_path when profiling imports.
+/// Specs PathFinder finds are returned as-is, except their loader executes
+/// modules through `ProfiledExecModule`.
+py_class!(class ProfilingPathFinder |py| {
+    data path_finder: PyObject;
+    data import_profile: SharedImportProfile;
+
+    def find_spec(&self, fullname: &PyString, path: &PyObject, target: Option<PyObject> = None) -> PyResult<PyObject> {
+        let spec = self.path_finder(py).call_method(py, "find_spec", (fullname, path, target), None)?;
+        if spec == py.None() {
+            return Ok(spec);
+        }
+
+        let loader = spec.getattr(py, "loader")?;
+        let exec_module = match loader.getattr(py, "exec_module") {
+            Ok(exec_module) if loader != py.None() => exec_module,
+            _ => return Ok(spec),
+        };
+
+        let has_location = spec.getattr(py, "has_location")?.is_true(py)?;
+        let bytes = match spec.getattr(py, "origin")?.extract::<String>(py) {
+            Ok(ref origin) if has_location => std::fs::metadata(origin).map(|m| m.len() as usize).unwrap_or(0),
+            _ => 0,
+        };
+
+        let wrapped = ProfiledExecModule::create_instance(
+            py,
+            exec_module,
+            fullname.to_string(py)?.to_string(),
+            bytes,
+            self.import_profile(py).clone(),
+        )?;
+        loader.setattr(py, "exec_module", wrapped)?;
+
+        Ok(spec)
+    }
+
+    def invalidate_caches(&self) -> PyResult<PyObject> {
+        self.path_finder(py).call_method(py, "invalidate_caches", NoArgs, None)
+    }
+});
+
 const DOC: &[u8] = b"Binary representation of Python modules\0";
 
 /// Represents global module state to be passed at interpreter initialization time.
@@ -535,6 +866,9 @@ pub struct InitModuleState {
     /// Whether to register the filesystem importer on sys.meta_path.
     pub register_filesystem_importer: bool,
 
+    /// Whether the filesystem importer is registered before our importer.
+    pub filesystem_first: bool,
+
     /// Values to set on sys.path.
     pub sys_paths: Vec<String>,
 
@@ -543,6 +877,15 @@ pub struct InitModuleState {
 
     /// Raw data constituting Python resources data.
     pub py_resources_data: &'static [u8],
+
+    /// Modules and resources data shared with other executables, if any.
+    ///
+    /// Modules and resources in `py_modules_data` and `py_resources_data`
+    /// take precedence.
+    pub shared_data: Option<(&'static [u8], &'static [u8])>,
+
+    /// Where to record imports and resource reads, if profiling.
+    pub import_profile: Option<SharedImportProfile>,
 }
 
 /// Holds reference to next module state struct.
@@ -552,13 +895,34 @@ pub struct InitModuleState {
 pub static mut NEXT_MODULE_STATE: *const InitModuleState = std::ptr::null();
 
 /// Represents which importer to use for known modules.
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug)]
 enum KnownModuleFlavor {
     Builtin,
     Frozen,
     InMemory { module_data: PythonModuleData },
 }
 
+impl KnownModuleFlavor {
+    /// Where the module comes from, as reported in import profiles.
+    fn profile_source(&self) -> &'static str {
+        match self {
+            KnownModuleFlavor::Builtin => "builtin",
+            KnownModuleFlavor::Frozen => "frozen",
+            KnownModuleFlavor::InMemory { .. } => "memory",
+        }
+    }
+
+    /// Bytes of embedded module data read to import the module.
+    fn data_len(&self) -> usize {
+        match self {
+            KnownModuleFlavor::InMemory { module_data } => {
+                module_data.bytecode.map_or(0, |data| data.len())
+            }
+            _ => 0,
+        }
+    }
+}
+
 type KnownModules = HashMap<&'static str, KnownModuleFlavor>;
 
 /// State associated with each importer module instance.
@@ -571,6 +935,9 @@ struct ModuleState {
     /// Whether to register PathFinder on sys.meta_path.
     register_filesystem_importer: bool,
 
+    /// Whether PathFinder is registered before our importer.
+    filesystem_first: bool,
+
     /// Values to set on sys.path.
     sys_paths: Vec<String>,
 
@@ -580,6 +947,12 @@ struct ModuleState {
     /// Raw data constituting Python resources data.
     py_resources_data: &'static [u8],
 
+    /// Modules and resources data shared with other executables, if any.
+    shared_data: Option<(&'static [u8], &'static [u8])>,
+
+    /// Where to record imports and resource reads, if profiling.
+    import_profile: Option<SharedImportProfile>,
+
     /// Whether setup() has been called.
     setup_called: bool,
 }
@@ -617,10 +990,13 @@ fn module_init(py: Python, m: &PyModule) -> PyResult<()> {
 
     unsafe {
         state.register_filesystem_importer = (*NEXT_MODULE_STATE).register_filesystem_importer;
+        state.filesystem_first = (*NEXT_MODULE_STATE).filesystem_first;
         // TODO we could move the value if we wanted to avoid the clone().
         state.sys_paths = (*NEXT_MODULE_STATE).sys_paths.clone();
         state.py_modules_data = (*NEXT_MODULE_STATE).py_modules_data;
         state.py_resources_data = (*NEXT_MODULE_STATE).py_resources_data;
+        state.shared_data = (*NEXT_MODULE_STATE).shared_data;
+        state.import_profile = (*NEXT_MODULE_STATE).import_profile.clone();
     }
 
     state.setup_called = false;
@@ -690,19 +1066,12 @@ fn module_setup(
     let builtin_importer = meta_path.get_item(py, 0);
     let frozen_importer = meta_path.get_item(py, 1);
 
-    // It may seem inefficient to create a full HashMap of the parsed data instead of e.g.
-    // streaming it. But the overhead of iterators was measured to be more than building
-    // up a temporary HashMap.
-    let modules_data = match PythonModulesData::from(state.py_modules_data) {
-        Ok(v) => v,
-        Err(msg) => return Err(PyErr::new::<ValueError, _>(py, msg)),
-    };
-
     // Populate our known module lookup table with entries from builtins, frozens, and
     // finally us. Last write wins and has the same effect as registering our
     // meta path importer first. This should be safe. If nothing else, it allows
-    // some builtins to be overwritten by .py implemented modules.
-    let mut known_modules = KnownModules::with_capacity(modules_data.data.len() + 10);
+    // some builtins to be overwritten by .py implemented modules. Lazily indexed
+    // modules are written when their group is hydrated, which has the same effect.
+    let mut known_modules = KnownModules::with_capacity(100);
 
     for i in 0.. {
         let record = unsafe { pyffi::PyImport_Inittab.offset(i) };
@@ -746,19 +1115,27 @@ fn module_setup(
         known_modules.insert(name_str, KnownModuleFlavor::Frozen);
     }
 
-    for (name, record) in modules_data.data {
-        known_modules.insert(
-            name,
-            KnownModuleFlavor::InMemory {
-                module_data: record,
-            },
-        );
+    let mut index = ImporterIndex {
+        packages: HashSet::new(),
+        known_modules,
+        resources: HashMap::new(),
+        pending_modules: HashMap::new(),
+        pending_resources: HashMap::new(),
+    };
+
+    // Shared data goes first, so our own modules and resources replace shared
+    // ones of the same name.
+    let mut sources = Vec::with_capacity(2);
+    if let Some(shared) = state.shared_data {
+        sources.push(shared);
     }
+    sources.push((state.py_modules_data, state.py_resources_data));
 
-    let resources_data = match PythonResourcesData::from(state.py_resources_data) {
-        Ok(v) => v,
-        Err(msg) => return Err(PyErr::new::<ValueError, _>(py, msg)),
-    };
+    for (modules, resources) in sources {
+        if let Err(msg) = index.add_data(modules, resources) {
+            return Err(PyErr::new::<ValueError, _>(py, msg));
+        }
+    }
 
     let marshal_loads = marshal_module.get(py, "loads")?;
     let call_with_frames_removed = bootstrap_module.get(py, "_call_with_frames_removed")?;
@@ -788,6 +1165,10 @@ fn module_setup(
     let resource_readers: RefCell<Box<HashMap<String, PyObject>>> =
         RefCell::new(Box::new(HashMap::new()));
 
+    // Filesystem-first ordering needs these on sys.meta_path as well.
+    let builtin_finder = builtin_importer.clone_ref(py);
+    let frozen_finder = frozen_importer.clone_ref(py);
+
     let unified_importer = PyOxidizerFinder::create_instance(
         py,
         imp_module,
@@ -798,10 +1179,9 @@ fn module_setup(
         module_spec_type,
         decode_source,
         exec_fn,
-        modules_data.packages,
-        known_modules,
-        resources_data.packages,
+        RefCell::new(index),
         resource_readers,
+        state.import_profile.clone(),
     )?;
     meta_path_object.call_method(py, "clear", NoArgs, None)?;
     meta_path_object.call_method(py, "append", (unified_importer,), None)?;
@@ -832,9 +1212,27 @@ fn module_setup(
         let path_hooks = sys_module.get(py, "path_hooks")?;
         path_hooks.call_method(py, "append", (path_hook,), None)?;
 
-        let path_finder = frozen_importlib_external.get(py, "PathFinder")?;
+        // Finders on sys.meta_path are consulted in order. So whichever comes
+        // first wins when a module is both in memory and on the filesystem.
+        // Built-in and frozen modules must still win over the filesystem, as
+        // they do in a regular Python. Our importer handles those too, so when
+        // PathFinder comes first, the regular finders are put in front of it.
+        let mut path_finder = frozen_importlib_external.get(py, "PathFinder")?;
+        if let Some(profile) = &state.import_profile {
+            path_finder = ProfilingPathFinder::create_instance(py, path_finder, profile.clone())?
+                .into_object();
+        }
         let meta_path = sys_module.get(py, "meta_path")?;
-        meta_path.call_method(py, "append", (path_finder,), None)?;
+        if state.filesystem_first {
+            for (index, finder) in vec![builtin_finder, frozen_finder, path_finder]
+                .into_iter()
+                .enumerate()
+            {
+                meta_path.call_method(py, "insert", (index, finder), None)?;
+            }
+        } else {
+            meta_path.call_method(py, "append", (path_finder,), None)?;
+        }
     }
 
     // Ideally we should be calling Py_SetPath() before Py_Initialize() to set sys.path.
@@ -15,23 +15,32 @@ The most important types are [`PythonConfig`](struct.PythonConfig.html) and
 defines how a Python interpreter is to behave. A `MainPythonInterpreter`
 creates and manages that interpreter and serves as a high-level interface for
 running code in the interpreter.
+
+Interpreter initialization takes a noticeable amount of time. Applications
+which don't always need Python can use
+[`MainPythonInterpreter::lazy()`](struct.MainPythonInterpreter.html#method.lazy)
+to obtain a [`LazyPythonInterpreter`](struct.LazyPythonInterpreter.html)
+which defers initialization until the interpreter is first acquired.
 */
 
 mod config;
 mod data;
 mod importer;
 mod osutils;
+mod profile;
 mod pyalloc;
 mod pyinterp;
 mod pystr;
+mod shared;
 
 #[allow(unused_imports)]
 pub use crate::config::{
-    ExtensionModule, PythonConfig, PythonRawAllocator, PythonRunMode, TerminfoResolution,
+    apply_runtime_code_override, DetachedStdio, ExtensionModule, ImporterPrecedence, PythonConfig,
+    PythonRawAllocator, PythonRunMode, SharedResources, SysExecutable, TerminfoResolution,
 };
 
 #[allow(unused_imports)]
-pub use crate::data::default_python_config;
+pub use crate::data::{default_python_config, run_post_finalize_hooks, run_pre_init_hooks};
 
 #[allow(unused_imports)]
-pub use crate::pyinterp::MainPythonInterpreter;
+pub use crate::pyinterp::{LazyPythonInterpreter, MainPythonInterpreter, RUN_PYTHON_MARKER};
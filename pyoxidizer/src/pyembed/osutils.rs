@@ -5,6 +5,70 @@
 use lazy_static::lazy_static;
 use std::path::{Path, PathBuf};
 
+/// Resolve the path of the binary containing this code.
+///
+/// This is the current executable unless this code is part of a shared
+/// library, in which case it is the library.
+#[cfg(unix)]
+pub fn current_binary() -> Option<PathBuf> {
+    use std::ffi::{CStr, OsStr};
+    use std::os::unix::ffi::OsStrExt;
+
+    let mut info: libc::Dl_info = unsafe { std::mem::zeroed() };
+    let addr = current_binary as *const libc::c_void;
+
+    if unsafe { libc::dladdr(addr, &mut info) } == 0 || info.dli_fname.is_null() {
+        return std::env::current_exe().ok();
+    }
+
+    let path = PathBuf::from(OsStr::from_bytes(
+        unsafe { CStr::from_ptr(info.dli_fname) }.to_bytes(),
+    ));
+
+    // dladdr() reports the path the binary was loaded as, which may be
+    // relative.
+    std::fs::canonicalize(&path).ok().or(Some(path))
+}
+
+/// Resolve the path of the binary containing this code.
+#[cfg(not(unix))]
+pub fn current_binary() -> Option<PathBuf> {
+    std::env::current_exe().ok()
+}
+
+/// Whether the standard input, output and error streams are consoles.
+#[cfg(unix)]
+pub fn stdio_consoles() -> (bool, bool, bool) {
+    let is_console = |fd| unsafe { libc::isatty(fd) == 1 };
+
+    (is_console(0), is_console(1), is_console(2))
+}
+
+#[cfg(windows)]
+extern "system" {
+    fn GetStdHandle(std_handle: u32) -> *mut libc::c_void;
+    fn GetConsoleMode(console_handle: *mut libc::c_void, mode: *mut u32) -> i32;
+}
+
+/// Whether the standard input, output and error streams are consoles.
+#[cfg(windows)]
+pub fn stdio_consoles() -> (bool, bool, bool) {
+    // STD_INPUT_HANDLE, STD_OUTPUT_HANDLE and STD_ERROR_HANDLE.
+    let is_console = |std_handle: i32| {
+        let handle = unsafe { GetStdHandle(std_handle as u32) };
+
+        // GUI applications have NULL handles unless they are redirected.
+        if handle.is_null() || handle as isize == -1 {
+            return false;
+        }
+
+        let mut mode = 0;
+        unsafe { GetConsoleMode(handle, &mut mode) != 0 }
+    };
+
+    (is_console(-10), is_console(-11), is_console(-12))
+}
+
 /// terminfo directories for Debian based distributions.
 ///
 /// Search for `--with-terminfo-dirs` at
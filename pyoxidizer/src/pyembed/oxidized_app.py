@@ -0,0 +1,385 @@
+# This Source Code Form is subject to the terms of the Mozilla Public
+# License, v. 2.0. If a copy of the MPL was not distributed with this
+# file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+"""Information about the application embedding Python.
+
+This module is evaluated by the ``pyembed`` crate during interpreter
+initialization and registered as ``oxidized_app``.
+
+Processes without a console, such as Windows GUI applications, have no
+valid standard stream handles and CPython sets ``sys.stdin``, ``sys.stdout``
+and ``sys.stderr`` to ``None``. Code calling ``sys.stdout.write()`` then
+fails. Missing streams are replaced according to the ``detached_stdio``
+setting: output is discarded, appended to a rotated log file or retained in
+an in-memory buffer.
+
+``translation()`` loads gettext catalogs embedded as resources, which the
+filesystem based ``gettext.translation()`` cannot find.
+
+``resources_digest`` and ``build_id`` identify the embedded code, e.g. to
+invalidate caches derived from it.
+
+``load_sqlite_extension()`` loads SQLite extensions installed next to the
+application by ``FileManifest.add_sqlite_extension()``.
+
+``config_file()`` reads files embedded by ``embedded_config_file()``.
+"""
+
+import collections
+import io
+import os
+import sys
+import _thread
+
+#: SHA-256 of the embedded modules and resources, as a hex string.
+#:
+#: Changes whenever an embedded module or resource changes. ``None`` if
+#: unknown. Set by ``pyembed``.
+resources_digest = None
+
+#: SHA-256 of everything PyOxidizer embedded, as a hex string.
+#:
+#: Also covers the Python distribution, the embedded Python config, the
+#: run mode, the target and the PyOxidizer version, but not the
+#: application's Rust code. ``None`` if unknown. Set by ``pyembed``.
+build_id = None
+
+#: Directory of the application. Set by ``pyembed``.
+origin = None
+
+#: Directory relative to ``origin`` holding SQLite loadable extensions.
+#:
+#: This needs to be kept in sync with ``SQLITE_EXTENSIONS_DIR``.
+SQLITE_EXTENSIONS_DIR = os.path.join("lib", "sqlite-extensions")
+
+#: Package whose resources are files embedded by ``embedded_config_file()``.
+#:
+#: This needs to be kept in sync with ``EMBEDDED_CONFIG_PACKAGE``.
+EMBEDDED_CONFIG_PACKAGE = "_oxidized_config"
+
+#: Whether any standard stream is attached to a console.
+has_console = False
+
+#: Whether the corresponding standard stream is attached to a console.
+stdin_is_console = False
+stdout_is_console = False
+stderr_is_console = False
+
+#: Number of characters of output retained by the ``buffer`` mode.
+BUFFER_SIZE = 1024 * 1024
+
+#: Size in bytes at which the ``logfile`` mode rotates the log file.
+LOGFILE_MAX_BYTES = 1024 * 1024
+
+#: Number of rotated log files kept by the ``logfile`` mode.
+LOGFILE_BACKUPS = 3
+
+_buffer = None
+
+
+class _NullWriter(io.TextIOBase):
+    """Text stream discarding everything written to it."""
+
+    encoding = "utf-8"
+    errors = "strict"
+
+    def writable(self):
+        return True
+
+    def write(self, s):
+        return len(s)
+
+
+class _RingBuffer(io.TextIOBase):
+    """Text stream retaining the most recent output written to it."""
+
+    encoding = "utf-8"
+    errors = "strict"
+
+    def __init__(self, size):
+        self._chunks = collections.deque()
+        self._length = 0
+        self._size = size
+        self._lock = _thread.allocate_lock()
+
+    def writable(self):
+        return True
+
+    def write(self, s):
+        if not isinstance(s, str):
+            raise TypeError("write() argument must be str, not %s" % type(s).__name__)
+
+        with self._lock:
+            self._chunks.append(s)
+            self._length += len(s)
+
+            while self._length > self._size:
+                excess = self._length - self._size
+                first = self._chunks[0]
+
+                if len(first) <= excess:
+                    self._chunks.popleft()
+                    self._length -= len(first)
+                else:
+                    self._chunks[0] = first[excess:]
+                    self._length -= excess
+
+        return len(s)
+
+    def getvalue(self):
+        with self._lock:
+            return "".join(self._chunks)
+
+
+class _RotatingLogWriter(io.TextIOBase):
+    """Text stream appending to a log file, rotating it when it grows large.
+
+    Errors writing the log file disable it rather than failing the caller.
+    """
+
+    encoding = "utf-8"
+    errors = "backslashreplace"
+
+    def __init__(self, path, max_bytes, backups):
+        self._path = path
+        self._max_bytes = max_bytes
+        self._backups = backups
+        self._fh = None
+        self._size = 0
+        self._failed = False
+        self._lock = _thread.allocate_lock()
+
+    def writable(self):
+        return True
+
+    def _open(self):
+        directory = os.path.dirname(self._path)
+        if directory:
+            os.makedirs(directory, exist_ok=True)
+
+        self._fh = open(
+            self._path, "a", encoding="utf-8", errors="backslashreplace", buffering=1
+        )
+        self._size = os.path.getsize(self._path)
+
+    def _rotate(self):
+        self._fh.close()
+        self._fh = None
+
+        for i in range(self._backups - 1, 0, -1):
+            source = "%s.%d" % (self._path, i)
+            if os.path.exists(source):
+                os.replace(source, "%s.%d" % (self._path, i + 1))
+
+        if self._backups > 0:
+            os.replace(self._path, "%s.1" % self._path)
+        else:
+            os.remove(self._path)
+
+        self._open()
+
+    def write(self, s):
+        if not isinstance(s, str):
+            raise TypeError("write() argument must be str, not %s" % type(s).__name__)
+
+        with self._lock:
+            if self._failed:
+                return len(s)
+
+            try:
+                if self._fh is None:
+                    self._open()
+
+                size = len(s.encode("utf-8", "backslashreplace"))
+                if self._size and self._size + size > self._max_bytes:
+                    self._rotate()
+
+                self._fh.write(s)
+                self._size += size
+            except OSError:
+                self._failed = True
+
+        return len(s)
+
+    def flush(self):
+        with self._lock:
+            if self._fh is not None and not self._failed:
+                try:
+                    self._fh.flush()
+                except OSError:
+                    self._failed = True
+
+
+def _resolve_log_path(template, origin):
+    path = os.path.expanduser(template.replace("{pid}", str(os.getpid())))
+
+    # Relative paths are relative to the application, not the working
+    # directory, which is arbitrary for GUI applications.
+    return os.path.join(origin, path)
+
+
+def stdio_buffer():
+    """Obtain output retained by the ``buffer`` mode.
+
+    Returns ``None`` if output isn't being buffered.
+    """
+    if _buffer is None:
+        return None
+
+    return _buffer.getvalue()
+
+
+def _install(mode, log_path, app_origin, stdin_console, stdout_console, stderr_console):
+    global has_console, stdin_is_console, stdout_is_console, stderr_is_console, _buffer
+    global origin
+
+    origin = app_origin
+
+    stdin_is_console = stdin_console
+    stdout_is_console = stdout_console
+    stderr_is_console = stderr_console
+    has_console = stdin_console or stdout_console or stderr_console
+
+    if sys.stdin is None:
+        sys.stdin = sys.__stdin__ = io.StringIO()
+
+    if sys.stdout is not None and sys.stderr is not None:
+        return
+
+    if mode == "buffer":
+        _buffer = _RingBuffer(BUFFER_SIZE)
+        writer = _buffer
+    elif mode == "logfile":
+        writer = _RotatingLogWriter(
+            _resolve_log_path(log_path, app_origin), LOGFILE_MAX_BYTES, LOGFILE_BACKUPS
+        )
+    else:
+        writer = _NullWriter()
+
+    if sys.stdout is None:
+        sys.stdout = sys.__stdout__ = writer
+
+    if sys.stderr is None:
+        sys.stderr = sys.__stderr__ = writer
+
+
+def _catalog_languages(languages):
+    """Resolve the languages to search for catalogs like ``gettext.find()``."""
+    import gettext
+
+    if languages is None:
+        languages = []
+        for name in ("LANGUAGE", "LC_ALL", "LC_MESSAGES", "LANG"):
+            value = os.environ.get(name)
+            if value:
+                languages = value.split(":")
+                break
+
+    result = []
+    for language in list(languages) + ["C"]:
+        for expanded in gettext._expand_lang(language):
+            if expanded not in result:
+                result.append(expanded)
+
+    return result[: result.index("C")] if "C" in result else result
+
+
+def _embedded_resource(package, name):
+    """Read resource data of a package from the in-memory importer."""
+    for finder in sys.meta_path:
+        get_resource_reader = getattr(finder, "get_resource_reader", None)
+        if get_resource_reader is None:
+            continue
+
+        try:
+            reader = get_resource_reader(package)
+        except TypeError:
+            continue
+
+        if reader is None:
+            continue
+
+        try:
+            with reader.open_resource(name) as fh:
+                return fh.read()
+        except FileNotFoundError:
+            continue
+
+    return None
+
+
+def config_file(name):
+    """Read a file embedded by ``embedded_config_file()``.
+
+    Returns its content as ``bytes``. Raises ``FileNotFoundError`` if no
+    file of that name is embedded.
+    """
+    data = _embedded_resource(EMBEDDED_CONFIG_PACKAGE, name)
+    if data is None:
+        raise FileNotFoundError("no embedded config file named %s" % name)
+
+    return data
+
+
+def translation(
+    domain, localedir=None, languages=None, class_=None, fallback=False, package=None
+):
+    """Obtain translations for a gettext domain.
+
+    Compatible with ``gettext.translation()``. Catalogs embedded by the
+    ``locale_resources()`` config function are read from resources of
+    ``package``, which defaults to the domain name. Catalogs for later
+    languages become fallbacks of earlier ones. If no catalog is embedded,
+    catalogs are loaded from the filesystem by ``gettext.translation()``.
+    """
+    import gettext
+
+    if package is None:
+        package = domain
+
+    if class_ is None:
+        class_ = gettext.GNUTranslations
+
+    result = None
+    for language in _catalog_languages(languages):
+        data = _embedded_resource(
+            package, "locale/%s/LC_MESSAGES/%s.mo" % (language, domain)
+        )
+        if data is None:
+            continue
+
+        t = class_(io.BytesIO(data))
+        if result is None:
+            result = t
+        else:
+            result.add_fallback(t)
+
+    if result is not None:
+        return result
+
+    return gettext.translation(
+        domain, localedir=localedir, languages=languages, class_=class_, fallback=fallback
+    )
+
+
+def sqlite_extension_path(name):
+    """Obtain the path of a SQLite extension installed next to the application.
+
+    ``name`` may omit the platform's shared library suffix, which SQLite
+    appends when loading if needed.
+    """
+    return os.path.join(origin or "", SQLITE_EXTENSIONS_DIR, name)
+
+
+def load_sqlite_extension(connection, name):
+    """Load a SQLite extension installed next to the application.
+
+    ``connection`` is a ``sqlite3.Connection``. Loading extensions is only
+    enabled while the extension is loaded.
+    """
+    connection.enable_load_extension(True)
+    try:
+        connection.load_extension(sqlite_extension_path(name))
+    finally:
+        connection.enable_load_extension(False)
@@ -0,0 +1,244 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/*!
+Recording import timings and resource reads.
+
+When `PythonConfig.import_profile_env` names an environment variable and that
+variable is set, the importer records every module it executes and every
+resource it reads. Modules the filesystem importer loads are timed as well,
+as PathFinder is wrapped to time the loaders it returns. Modules imported by
+other means, such as finders the application adds to `sys.meta_path`, are
+recorded without timings. The recording is written as JSON to the path held
+by the environment variable when the interpreter is finalized.
+
+When profiling isn't enabled, the importer holds no profile and the only
+cost is checking for its absence.
+*/
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Version of the JSON report format.
+pub const IMPORT_PROFILE_VERSION: u32 = 1;
+
+/// A module imported while profiling.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ImportRecord {
+    pub name: String,
+    /// Where the module came from: `memory`, `builtin`, `frozen` or `filesystem`.
+    pub source: &'static str,
+    /// When executing the module started, relative to interpreter initialization.
+    pub start: Option<Duration>,
+    /// Time executing the module, including modules it imported.
+    pub cumulative: Option<Duration>,
+    /// Time executing the module, excluding modules it imported.
+    pub own: Option<Duration>,
+    /// Bytes of module data read.
+    pub bytes: usize,
+}
+
+/// Reads of a resource while profiling.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ResourceReads {
+    pub reads: usize,
+    pub bytes: usize,
+}
+
+/// Records imports and resource reads of an interpreter.
+#[derive(Debug)]
+pub struct ImportProfile {
+    started: Instant,
+    /// Imports in progress: their start and the time spent in nested imports.
+    stack: Vec<(Instant, Duration)>,
+    imports: Vec<ImportRecord>,
+    resources: BTreeMap<(String, String), ResourceReads>,
+}
+
+/// A profile shared by the importer and the interpreter writing it.
+pub type SharedImportProfile = Arc<Mutex<ImportProfile>>;
+
+impl Default for ImportProfile {
+    fn default() -> Self {
+        ImportProfile {
+            started: Instant::now(),
+            stack: Vec::new(),
+            imports: Vec::new(),
+            resources: BTreeMap::new(),
+        }
+    }
+}
+
+impl ImportProfile {
+    /// Note that executing a module has started.
+    pub fn begin(&mut self) {
+        self.stack.push((Instant::now(), Duration::default()));
+    }
+
+    /// Record the module whose execution was most recently begun.
+    pub fn end(&mut self, name: &str, source: &'static str, bytes: usize) {
+        let (start, nested) = match self.stack.pop() {
+            Some(v) => v,
+            None => return,
+        };
+
+        let cumulative = start.elapsed();
+
+        if let Some(parent) = self.stack.last_mut() {
+            parent.1 += cumulative;
+        }
+
+        self.imports.push(ImportRecord {
+            name: name.to_string(),
+            source,
+            start: Some(start.duration_since(self.started)),
+            cumulative: Some(cumulative),
+            own: Some(cumulative.checked_sub(nested).unwrap_or_default()),
+            bytes,
+        });
+    }
+
+    /// Record a module imported without our importer's involvement.
+    pub fn record_untimed(&mut self, name: &str, source: &'static str, bytes: usize) {
+        self.imports.push(ImportRecord {
+            name: name.to_string(),
+            source,
+            start: None,
+            cumulative: None,
+            own: None,
+            bytes,
+        });
+    }
+
+    /// Record a read of a resource.
+    pub fn record_resource(&mut self, package: &str, name: &str, bytes: usize) {
+        let entry = self
+            .resources
+            .entry((package.to_string(), name.to_string()))
+            .or_insert_with(ResourceReads::default);
+
+        entry.reads += 1;
+        entry.bytes += bytes;
+    }
+
+    /// Names of recorded modules.
+    pub fn module_names(&self) -> BTreeSet<String> {
+        self.imports.iter().map(|r| r.name.clone()).collect()
+    }
+
+    /// Serialize the profile to JSON.
+    pub fn to_json(&self) -> String {
+        let micros = |d: &Option<Duration>| match d {
+            Some(d) => (d.as_secs() * 1_000_000 + u64::from(d.subsec_micros())).to_string(),
+            None => "null".to_string(),
+        };
+
+        let imports = self
+            .imports
+            .iter()
+            .map(|r| {
+                format!(
+                    "    {{\"name\": {}, \"source\": {}, \"start_us\": {}, \"cumulative_us\": {}, \"self_us\": {}, \"bytes\": {}}}",
+                    json_string(&r.name),
+                    json_string(r.source),
+                    micros(&r.start),
+                    micros(&r.cumulative),
+                    micros(&r.own),
+                    r.bytes
+                )
+            })
+            .collect::<Vec<_>>();
+
+        let resources = self
+            .resources
+            .iter()
+            .map(|((package, name), reads)| {
+                format!(
+                    "    {{\"package\": {}, \"name\": {}, \"reads\": {}, \"bytes\": {}}}",
+                    json_string(package),
+                    json_string(name),
+                    reads.reads,
+                    reads.bytes
+                )
+            })
+            .collect::<Vec<_>>();
+
+        format!(
+            "{{\n  \"version\": {},\n  \"module_bytes\": {},\n  \"resource_bytes\": {},\n  \"imports\": [\n{}\n  ],\n  \"resources\": [\n{}\n  ]\n}}\n",
+            IMPORT_PROFILE_VERSION,
+            self.imports.iter().map(|r| r.bytes).sum::<usize>(),
+            self.resources.values().map(|r| r.bytes).sum::<usize>(),
+            imports.join(",\n"),
+            resources.join(",\n")
+        )
+    }
+}
+
+/// Format a string as a JSON string literal.
+fn json_string(value: &str) -> String {
+    let mut res = String::with_capacity(value.len() + 2);
+    res.push('"');
+
+    for c in value.chars() {
+        match c {
+            '"' => res.push_str("\\\""),
+            '\\' => res.push_str("\\\\"),
+            '\n' => res.push_str("\\n"),
+            '\r' => res.push_str("\\r"),
+            '\t' => res.push_str("\\t"),
+            c if (c as u32) < 0x20 => res.push_str(&format!("\\u{:04x}", c as u32)),
+            c => res.push(c),
+        }
+    }
+
+    res.push('"');
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_nested_imports() {
+        let mut profile = ImportProfile::default();
+
+        profile.begin();
+        profile.begin();
+        std::thread::sleep(Duration::from_millis(5));
+        profile.end("outer.inner", "memory", 10);
+        profile.end("outer", "memory", 20);
+        profile.record_untimed("fsmod", "filesystem", 30);
+
+        let inner = &profile.imports[0];
+        let outer = &profile.imports[1];
+        assert_eq!(inner.name, "outer.inner");
+        assert!(inner.own.unwrap() >= Duration::from_millis(5));
+        assert!(outer.cumulative.unwrap() >= inner.cumulative.unwrap());
+        assert!(outer.own.unwrap() < inner.own.unwrap());
+        assert_eq!(profile.imports[2].cumulative, None);
+
+        // Unbalanced ends are ignored.
+        profile.end("bogus", "memory", 0);
+        assert_eq!(profile.imports.len(), 3);
+    }
+
+    #[test]
+    fn test_to_json() {
+        let mut profile = ImportProfile::default();
+        profile.record_untimed("a\"b", "filesystem", 3);
+        profile.record_resource("pkg", "data.txt", 5);
+        profile.record_resource("pkg", "data.txt", 5);
+
+        let json = profile.to_json();
+        assert!(json.contains(
+            "{\"name\": \"a\\\"b\", \"source\": \"filesystem\", \"start_us\": null, \"cumulative_us\": null, \"self_us\": null, \"bytes\": 3}"
+        ));
+        assert!(json.contains(
+            "{\"package\": \"pkg\", \"name\": \"data.txt\", \"reads\": 2, \"bytes\": 10}"
+        ));
+        assert!(json.contains("\"module_bytes\": 3,\n  \"resource_bytes\": 10,"));
+    }
+}
@@ -7,10 +7,12 @@
 #[cfg(feature = "jemalloc-sys")]
 use jemalloc_sys as jemallocffi;
 use libc::{c_void, size_t};
+#[cfg(feature = "libmimalloc-sys")]
+use libmimalloc_sys as mimallocffi;
 use python3_sys as pyffi;
 use std::alloc;
 use std::collections::HashMap;
-#[cfg(feature = "jemalloc-sys")]
+#[cfg(any(feature = "jemalloc-sys", feature = "libmimalloc-sys"))]
 use std::ptr::null_mut;
 
 const MIN_ALIGN: usize = 16;
@@ -219,3 +221,67 @@ pub fn make_raw_jemalloc_allocator() -> pyffi::PyMemAllocatorEx {
         free: Some(raw_jemalloc_free),
     }
 }
+
+// A raw memory allocator calling mimalloc directly. mimalloc builds with
+// MSVC, so unlike jemalloc it is also available on Windows.
+
+#[cfg(feature = "libmimalloc-sys")]
+extern "C" fn raw_mimalloc_malloc(_ctx: *mut c_void, size: size_t) -> *mut c_void {
+    // Same semantics for zero bytes as for jemalloc.
+    let size = match size {
+        0 => 1,
+        val => val,
+    };
+
+    unsafe { mimallocffi::mi_malloc(size) as *mut c_void }
+}
+
+#[cfg(feature = "libmimalloc-sys")]
+extern "C" fn raw_mimalloc_calloc(_ctx: *mut c_void, nelem: size_t, elsize: size_t) -> *mut c_void {
+    // mi_calloc() checks the multiplication for overflow, so the arguments
+    // are passed through unless the result would be zero bytes.
+    let (nelem, elsize) = match (nelem, elsize) {
+        (0, _) | (_, 0) => (1, 1),
+        val => val,
+    };
+
+    unsafe { mimallocffi::mi_calloc(nelem, elsize) as *mut c_void }
+}
+
+#[cfg(feature = "libmimalloc-sys")]
+extern "C" fn raw_mimalloc_realloc(
+    ctx: *mut c_void,
+    ptr: *mut c_void,
+    new_size: size_t,
+) -> *mut c_void {
+    if ptr.is_null() {
+        return raw_mimalloc_malloc(ctx, new_size);
+    }
+
+    let new_size = match new_size {
+        0 => 1,
+        val => val,
+    };
+
+    unsafe { mimallocffi::mi_realloc(ptr as *mut _, new_size) as *mut c_void }
+}
+
+#[cfg(feature = "libmimalloc-sys")]
+extern "C" fn raw_mimalloc_free(_ctx: *mut c_void, ptr: *mut c_void) {
+    if ptr.is_null() {
+        return;
+    }
+
+    unsafe { mimallocffi::mi_free(ptr as *mut _) }
+}
+
+#[cfg(feature = "libmimalloc-sys")]
+pub fn make_raw_mimalloc_allocator() -> pyffi::PyMemAllocatorEx {
+    pyffi::PyMemAllocatorEx {
+        ctx: null_mut(),
+        malloc: Some(raw_mimalloc_malloc),
+        calloc: Some(raw_mimalloc_calloc),
+        realloc: Some(raw_mimalloc_realloc),
+        free: Some(raw_mimalloc_free),
+    }
+}
@@ -8,11 +8,12 @@ use libc::c_char;
 use python3_sys as pyffi;
 use std::collections::BTreeSet;
 use std::env;
-use std::ffi::CString;
+use std::ffi::{CString, OsString};
 use std::fs;
 use std::io::Write;
 use std::path::PathBuf;
 use std::ptr::null;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 use cpython::exc::{SystemExit, ValueError};
 use cpython::{
@@ -20,16 +21,70 @@ use cpython::{
     PyString, Python, PythonObject, ToPyObject,
 };
 
-use super::config::{PythonConfig, PythonRawAllocator, PythonRunMode, TerminfoResolution};
+use super::config::{
+    finalized_exit_code, pre_finalize_code, DetachedStdio, ImporterPrecedence, PythonConfig,
+    PythonRawAllocator, PythonRunMode, SysExecutable, TerminfoResolution,
+};
 use super::importer::PyInit__pyoxidizer_importer;
-use super::osutils::resolve_terminfo_dirs;
+use super::osutils::{current_binary, resolve_terminfo_dirs, stdio_consoles};
+use super::profile::SharedImportProfile;
 #[cfg(feature = "jemalloc-sys")]
 use super::pyalloc::make_raw_jemalloc_allocator;
+#[cfg(feature = "libmimalloc-sys")]
+use super::pyalloc::make_raw_mimalloc_allocator;
 use super::pyalloc::{make_raw_rust_memory_allocator, RawAllocator};
 use super::pystr::{osstring_to_bytes, osstring_to_str, OwnedPyStr};
+use super::shared::load_shared_resources;
 
 pub const PYOXIDIZER_IMPORTER_NAME: &[u8] = b"_pyoxidizer_importer\0";
 
+/// Argument that causes the executable to behave like a `python` executable.
+///
+/// Only honored when `PythonConfig.run_python_marker` is set and the marker
+/// is the first argument.
+pub const RUN_PYTHON_MARKER: &str = "--pyoxidizer-run-python";
+
+/// No interpreter has been initialized in this process.
+const RUNTIME_UNINITIALIZED: usize = 0;
+/// An interpreter has been initialized and not yet finalized.
+const RUNTIME_INITIALIZED: usize = 1;
+/// The interpreter has been finalized.
+const RUNTIME_FINALIZED: usize = 2;
+
+/// Tracks the lifecycle of the process's Python runtime.
+///
+/// CPython does not support initializing a second interpreter alongside the
+/// main one or reliably initializing again after finalization. So we only
+/// allow a single transition through these states.
+static RUNTIME_STATE: AtomicUsize = AtomicUsize::new(RUNTIME_UNINITIALIZED);
+
+/// Claim the process's Python runtime for a new interpreter.
+fn claim_runtime() -> Result<(), &'static str> {
+    match RUNTIME_STATE.compare_exchange(
+        RUNTIME_UNINITIALIZED,
+        RUNTIME_INITIALIZED,
+        Ordering::SeqCst,
+        Ordering::SeqCst,
+    ) {
+        Ok(_) => Ok(()),
+        Err(RUNTIME_INITIALIZED) => {
+            Err("a Python interpreter is already initialized in this process")
+        }
+        Err(_) => {
+            Err("a Python interpreter cannot be initialized after the interpreter was finalized")
+        }
+    }
+}
+
+/// Python source for REPL line editing and history support.
+const REPL_SHIM: &str = include_str!("repl.py");
+
+/// Python source for the `oxidized_app` module.
+const OXIDIZED_APP_MODULE: &str = include_str!("oxidized_app.py");
+
+/// Name of the embedded module installing the `pkg_resources` shim.
+const PKG_RESOURCES_COMPAT_MODULE: &str = "_oxidized_pkg_resources";
+
 const FROZEN_IMPORTLIB_NAME: &[u8] = b"_frozen_importlib\0";
 const FROZEN_IMPORTLIB_EXTERNAL_NAME: &[u8] = b"_frozen_importlib_external\0";
 
@@ -115,6 +170,16 @@ fn raw_jemallocator() -> pyffi::PyMemAllocatorEx {
     panic!("jemalloc is not available in this build configuration");
 }
 
+#[cfg(feature = "libmimalloc-sys")]
+fn raw_mimallocator() -> pyffi::PyMemAllocatorEx {
+    make_raw_mimalloc_allocator()
+}
+
+#[cfg(not(feature = "libmimalloc-sys"))]
+fn raw_mimallocator() -> pyffi::PyMemAllocatorEx {
+    panic!("mimalloc is not available in this build configuration");
+}
+
 #[cfg(unix)]
 fn set_windows_flags(_config: &PythonConfig) {}
 
@@ -146,18 +211,26 @@ pub struct MainPythonInterpreter<'a> {
     pub config: PythonConfig,
     frozen_modules: [pyffi::_frozen; 3],
     init_run: bool,
+    owns_runtime: bool,
     raw_allocator: Option<pyffi::PyMemAllocatorEx>,
     raw_rust_allocator: Option<RawAllocator>,
     gil: Option<GILGuard>,
     py: Option<Python<'a>>,
     program_name: Option<OwnedPyStr>,
+    import_profile: Option<(PathBuf, SharedImportProfile)>,
 }
 
 impl<'a> MainPythonInterpreter<'a> {
     /// Construct a Python interpreter from a configuration.
     ///
     /// The Python interpreter is initialized as a side-effect. The GIL is held.
+    ///
+    /// Only a single interpreter can be constructed per process. An error is
+    /// returned if an interpreter already exists or has been finalized. Use
+    /// [`MainPythonInterpreter::lazy()`](#method.lazy) to defer construction.
     pub fn new(config: PythonConfig) -> Result<MainPythonInterpreter<'a>, &'static str> {
+        claim_runtime()?;
+
         match config.terminfo_resolution {
             TerminfoResolution::Dynamic => {
                 if let Some(v) = resolve_terminfo_dirs() {
@@ -172,21 +245,30 @@ impl<'a> MainPythonInterpreter<'a> {
 
         let (raw_allocator, raw_rust_allocator) = match config.raw_allocator {
             PythonRawAllocator::Jemalloc => (Some(raw_jemallocator()), None),
+            PythonRawAllocator::Mimalloc => (Some(raw_mimallocator()), None),
             PythonRawAllocator::Rust => (None, Some(make_raw_rust_memory_allocator())),
             PythonRawAllocator::System => (None, None),
         };
 
         let frozen_modules = make_custom_frozen_modules(&config);
 
+        let import_profile = config
+            .import_profile_env
+            .as_ref()
+            .and_then(|key| env::var_os(key))
+            .map(|path| (PathBuf::from(path), SharedImportProfile::default()));
+
         let mut res = MainPythonInterpreter {
             config,
             frozen_modules,
             init_run: false,
+            owns_runtime: true,
             raw_allocator,
             raw_rust_allocator,
             gil: None,
             py: None,
             program_name: None,
+            import_profile,
         };
 
         res.init()?;
@@ -194,6 +276,15 @@ impl<'a> MainPythonInterpreter<'a> {
         Ok(res)
     }
 
+    /// Obtain a handle that constructs an interpreter on first use.
+    ///
+    /// Interpreter initialization isn't free. Applications which only
+    /// sometimes need Python (e.g. not when handling `--help`) can use
+    /// this to avoid paying for it when it isn't needed.
+    pub fn lazy(config: PythonConfig) -> LazyPythonInterpreter<'a> {
+        LazyPythonInterpreter::new(config)
+    }
+
     /// Initialize the interpreter.
     ///
     /// This mutates global state in the Python interpreter according to the
@@ -213,8 +304,21 @@ impl<'a> MainPythonInterpreter<'a> {
 
         let config = &self.config;
 
+        let embedding = match config.run {
+            PythonRunMode::Embedding => true,
+            _ => false,
+        };
+
         let exe = env::current_exe().or_else(|_| Err("could not obtain current exe"))?;
-        let origin = exe
+
+        // When embedded, resources are installed next to the library rather
+        // than the host executable. So $ORIGIN is the library's directory.
+        let binary = if embedding {
+            current_binary().unwrap_or_else(|| exe.clone())
+        } else {
+            exe.clone()
+        };
+        let origin = binary
             .parent()
             .ok_or_else(|| "unable to get exe parent")?
             .display()
@@ -226,6 +330,11 @@ impl<'a> MainPythonInterpreter<'a> {
             .map(|path| path.replace("$ORIGIN", &origin))
             .collect();
 
+        let shared_data = match &config.shared_resources {
+            Some(shared) => Some(load_shared_resources(shared, &origin)?),
+            None => None,
+        };
+
         // TODO should we call PyMem::SetupDebugHooks() if enabled?
         if let Some(raw_allocator) = &self.raw_allocator {
             unsafe {
@@ -272,9 +381,18 @@ impl<'a> MainPythonInterpreter<'a> {
         // TODO specify lifetimes so the compiler validates this for us.
         let module_state = super::importer::InitModuleState {
             register_filesystem_importer: self.config.filesystem_importer,
+            filesystem_first: match self.config.importer_precedence {
+                ImporterPrecedence::MemoryFirst => false,
+                ImporterPrecedence::FilesystemFirst => true,
+            },
             sys_paths,
             py_modules_data: config.py_modules_data,
             py_resources_data: config.py_resources_data,
+            shared_data,
+            import_profile: self
+                .import_profile
+                .as_ref()
+                .map(|(_, profile)| profile.clone()),
         };
 
         if config.use_custom_importlib {
@@ -390,7 +508,7 @@ impl<'a> MainPythonInterpreter<'a> {
          */
 
         unsafe {
-            pyffi::Py_Initialize();
+            pyffi::Py_InitializeEx(if config.install_signal_handlers { 1 } else { 0 });
         }
 
         // We shouldn't be accessing this pointer after Py_Initialize(). And the
@@ -412,7 +530,23 @@ impl<'a> MainPythonInterpreter<'a> {
         // will be derived from wchar_t on Windows and char* on POSIX. We can
         // convert these to Python str instances using a platform-specific
         // mechanism.
-        let args_objs = env::args_os()
+        //
+        // A host embedding the interpreter owns the process arguments. So
+        // sys.argv is set like it is for embedded interpreters not given any.
+        //
+        // Arguments from the config, e.g. with reserved arguments removed,
+        // replace the process arguments.
+        let process_args: Vec<OsString> = if embedding {
+            vec![OsString::new()]
+        } else if let Some(argv) = &config.argv {
+            argv.clone()
+        } else {
+            env::args_os().collect()
+        };
+
+        let args_objs = process_args
+            .iter()
+            .cloned()
             .map(|os_arg| osstring_to_str(py, os_arg))
             .collect::<Result<Vec<PyObject>, &'static str>>()?;
 
@@ -430,7 +564,8 @@ impl<'a> MainPythonInterpreter<'a> {
         }
 
         if config.argvb {
-            let args_objs: Vec<PyObject> = env::args_os()
+            let args_objs: Vec<PyObject> = process_args
+                .into_iter()
                 .map(|os_arg| osstring_to_bytes(py, os_arg))
                 .collect();
 
@@ -460,6 +595,18 @@ impl<'a> MainPythonInterpreter<'a> {
             _ => return Err("unable to set sys.oxidized"),
         }
 
+        // Expose the importer ordering so code can tell which copy of a
+        // module it will get.
+        let precedence = b"oxidized_importer_precedence\0";
+        let value = PyString::new(py, config.importer_precedence.as_str());
+
+        match value.with_borrowed_ptr(py, |py_value| unsafe {
+            pyffi::PySys_SetObject(precedence.as_ptr() as *const i8, py_value)
+        }) {
+            0 => (),
+            _ => return Err("unable to set sys.oxidized_importer_precedence"),
+        }
+
         if config.sys_frozen {
             let frozen = b"frozen\0";
 
@@ -483,6 +630,62 @@ impl<'a> MainPythonInterpreter<'a> {
             }
         }
 
+        if let SysExecutable::Empty = config.sys_executable {
+            let executable = b"executable\0";
+            let value = PyString::new(py, "");
+
+            match value.with_borrowed_ptr(py, |py_value| unsafe {
+                pyffi::PySys_SetObject(executable.as_ptr() as *const i8, py_value)
+            }) {
+                0 => (),
+                _ => return Err("unable to set sys.executable"),
+            }
+        }
+
+        // Py_SetPath() leaves the prefixes empty. Point them at something
+        // meaningful so code inspecting them finds a real directory.
+        let prefix = PathBuf::from(&origin).join(config.sys_prefix.replace("$ORIGIN", &origin));
+        let prefix = PyString::new(py, &prefix.display().to_string());
+
+        for attr in &[
+            &b"prefix\0"[..],
+            &b"exec_prefix\0"[..],
+            &b"base_prefix\0"[..],
+            &b"base_exec_prefix\0"[..],
+        ] {
+            match prefix.with_borrowed_ptr(py, |py_value| unsafe {
+                pyffi::PySys_SetObject(attr.as_ptr() as *const i8, py_value)
+            }) {
+                0 => (),
+                _ => return Err("unable to set sys.prefix"),
+            }
+        }
+
+        // Missing standard streams are replaced before any application code
+        // runs so writing to them doesn't fail.
+        if let Err(err) = install_oxidized_app_module(py, config, &origin) {
+            err.print(py);
+            return Err("unable to initialize oxidized_app module");
+        }
+
+        if config.pkg_resources_compat {
+            if let Err(err) = py
+                .import(PKG_RESOURCES_COMPAT_MODULE)
+                .and_then(|module| module.call(py, "install", NoArgs, None))
+            {
+                err.print(py);
+                return Err("unable to install pkg_resources compatibility shim");
+            }
+        }
+
+        // This must be the last step of initialization so the hook sees a
+        // fully configured interpreter. But it must run before the run mode
+        // so it can configure things before application code is imported.
+        if let Err(err) = run_startup_hook(py, config) {
+            err.print(py);
+            return Err("error running Python startup hook");
+        }
+
         Ok(py)
     }
 
@@ -516,12 +719,25 @@ impl<'a> MainPythonInterpreter<'a> {
     /// executed by default. Those settings will be loaded and executed.
     pub fn run(&mut self) -> PyResult<PyObject> {
         // clone() to avoid issues mixing mutable and immutable borrows of self.
-        let run = self.config.run.clone();
+        let run = self
+            .config
+            .resolve_run_mode(env::args_os().next().as_ref().map(|s| s.as_os_str()))
+            .clone();
 
         let py = self.acquire_gil();
 
+        // Re-invocations of the executable as a Python interpreter take
+        // precedence over the configured run mode.
+        if self.config.run_python_marker {
+            let args: Vec<OsString> = env::args_os().collect();
+
+            if args.len() > 1 && args[1] == RUN_PYTHON_MARKER {
+                return self.run_python(&args[2..]);
+            }
+        }
+
         match run {
-            PythonRunMode::None => Ok(py.None()),
+            PythonRunMode::None | PythonRunMode::Embedding => Ok(py.None()),
             PythonRunMode::Repl => self.run_repl(),
             PythonRunMode::Module { module } => self.run_module_as_main(&module),
             PythonRunMode::Eval { code } => self.run_code(&code),
@@ -658,6 +874,62 @@ impl<'a> MainPythonInterpreter<'a> {
         }
     }
 
+    /// Finalize the interpreter and obtain the process exit code.
+    ///
+    /// `code` is the exit code of the code that ran, as returned by
+    /// [`run_as_main()`](#method.run_as_main). Like `python`, 120 is returned
+    /// if finalization fails. Dropping the interpreter also finalizes it,
+    /// but finalization failures are lost.
+    pub fn finalize(mut self, code: i32) -> i32 {
+        finalized_exit_code(code, self.finalize_runtime())
+    }
+
+    /// Finalize the Python runtime if it hasn't been already.
+    ///
+    /// Returns the result of `Py_FinalizeEx()`.
+    fn finalize_runtime(&mut self) -> i32 {
+        if !self.owns_runtime {
+            return 0;
+        }
+        self.owns_runtime = false;
+
+        if let Some(key) = &self.config.write_modules_directory_env {
+            if let Ok(path) = env::var(key) {
+                let path = PathBuf::from(path);
+                let py = self.acquire_gil();
+
+                if let Err(msg) = write_modules_to_directory(py, &path) {
+                    eprintln!("error writing modules file: {}", msg);
+                }
+            }
+        }
+
+        if let Some((path, profile)) = self.import_profile.take() {
+            let py = self.acquire_gil();
+
+            if let Err(msg) = write_import_profile(py, &profile, &path) {
+                eprintln!("error writing import profile: {}", msg);
+            }
+        }
+
+        if let Some(code) = pre_finalize_code(
+            self.config.run_atexit,
+            self.config.wait_for_non_daemon_threads,
+        ) {
+            let py = self.acquire_gil();
+
+            if let Err(err) = py.run(&code, None, None) {
+                eprintln!("error preparing interpreter finalization:");
+                err.print(py);
+            }
+        }
+
+        let res = unsafe { pyffi::Py_FinalizeEx() };
+        RUNTIME_STATE.store(RUNTIME_FINALIZED, Ordering::SeqCst);
+
+        res
+    }
+
     /// Runs a Python module as the __main__ module.
     ///
     /// Returns the execution result of the module code.
@@ -719,8 +991,9 @@ impl<'a> MainPythonInterpreter<'a> {
             pyffi::Py_InspectFlag = 0;
         }
 
-        // readline is optional. We don't care if it fails.
-        if py.import("readline").is_ok() {}
+        // readline is optional. If it isn't available, we fall back to a
+        // pure Python line editor.
+        let have_readline = py.import("readline").is_ok();
 
         let sys = py.import("sys")?;
 
@@ -728,6 +1001,22 @@ impl<'a> MainPythonInterpreter<'a> {
             hook.call(py, NoArgs, None)?;
         }
 
+        let shim = PyModule::new(py, "_pyoxidizer_repl")?;
+        shim.dict(py)
+            .set_item(py, "__builtins__", py.import("builtins")?)?;
+        py.run(REPL_SHIM, Some(&shim.dict(py)), None)?;
+
+        let history_file = match &self.config.repl_history_file {
+            Some(path) => path.to_py_object(py).into_object(),
+            None => py.None(),
+        };
+
+        if have_readline {
+            shim.call(py, "setup_readline_history", (history_file,), None)?;
+        } else {
+            return shim.call(py, "interact", (history_file,), None);
+        }
+
         let stdin_filename = "<stdin>";
         let filename = CString::new(stdin_filename)
             .or_else(|_| Err(PyErr::new::<ValueError, _>(py, "could not create CString")))?;
@@ -786,6 +1075,70 @@ impl<'a> MainPythonInterpreter<'a> {
         }
     }
 
+    /// Run the interpreter like a `python` executable given arguments.
+    ///
+    /// Supports ``-c <code>``, ``-m <module>``, a script path, and ``-`` or no
+    /// arguments to read from stdin. Interpreter options preceding these are
+    /// accepted for compatibility but ignored, as interpreter settings are
+    /// fixed at build time.
+    ///
+    /// ``sys.argv`` is set to what ``python`` would set it to.
+    pub fn run_python(&mut self, args: &[OsString]) -> PyResult<PyObject> {
+        let py = self.acquire_gil();
+
+        let mut i = 0;
+        while i < args.len() {
+            let arg = args[i].to_string_lossy();
+
+            match arg.as_ref() {
+                "-c" | "-m" => {
+                    let value = args.get(i + 1).ok_or_else(|| {
+                        PyErr::new::<ValueError, _>(py, format!("argument expected for {}", arg))
+                    })?;
+                    let value = value.to_string_lossy().to_string();
+
+                    let mut argv = vec![OsString::from(arg.as_ref())];
+                    argv.extend(args[i + 2..].iter().cloned());
+                    set_sys_argv(py, &argv)?;
+
+                    return if arg == "-c" {
+                        self.run_code(&value)
+                    } else {
+                        self.run_module_as_main(&value)
+                    };
+                }
+                // Options taking a value.
+                "-W" | "-X" => {
+                    i += 2;
+                }
+                "-" => {
+                    set_sys_argv(py, &args[i..])?;
+                    return self.run_repl();
+                }
+                _ if arg.starts_with('-') && arg.len() > 1 => {
+                    i += 1;
+                }
+                _ => {
+                    set_sys_argv(py, &args[i..])?;
+
+                    let runpy = py.import("runpy")?;
+                    let kwargs = PyDict::new(py);
+                    kwargs.set_item(py, "run_name", "__main__")?;
+
+                    return runpy.call(
+                        py,
+                        "run_path",
+                        (args[i].to_string_lossy().to_string(),),
+                        Some(&kwargs),
+                    );
+                }
+            }
+        }
+
+        set_sys_argv(py, &[OsString::new()])?;
+        self.run_repl()
+    }
+
     /// Print a Python error.
     ///
     /// Under the hood this calls ``PyErr_PrintEx()``, which may call
@@ -796,6 +1149,76 @@ impl<'a> MainPythonInterpreter<'a> {
     }
 }
 
+/// Import the configured startup module and/or execute startup code.
+fn run_startup_hook(py: Python, config: &PythonConfig) -> PyResult<()> {
+    if let Some(module) = &config.startup_module {
+        py.import(module)?;
+    }
+
+    if let Some(code) = &config.startup_code {
+        let globals = PyDict::new(py);
+        globals.set_item(py, "__name__", "__oxidized_startup__")?;
+        globals.set_item(py, "__builtins__", py.import("builtins")?)?;
+
+        py.run(code, Some(&globals), None)?;
+    }
+
+    Ok(())
+}
+
+/// Register the `oxidized_app` module and replace missing standard streams.
+fn install_oxidized_app_module(py: Python, config: &PythonConfig, origin: &str) -> PyResult<()> {
+    let module = PyModule::new(py, "oxidized_app")?;
+    module
+        .dict(py)
+        .set_item(py, "__builtins__", py.import("builtins")?)?;
+    py.run(OXIDIZED_APP_MODULE, Some(&module.dict(py)), None)?;
+
+    // Precomputed when packaging, so reading them costs nothing.
+    module
+        .dict(py)
+        .set_item(py, "resources_digest", config.resources_digest.clone())?;
+    module
+        .dict(py)
+        .set_item(py, "build_id", config.build_id.clone())?;
+
+    let (mode, log_path) = match &config.detached_stdio {
+        DetachedStdio::Null => ("null", None),
+        DetachedStdio::LogFile(path) => ("logfile", Some(path.replace("$ORIGIN", origin))),
+        DetachedStdio::Buffer => ("buffer", None),
+    };
+    let (stdin_console, stdout_console, stderr_console) = stdio_consoles();
+
+    module.call(
+        py,
+        "_install",
+        (
+            mode,
+            log_path,
+            origin,
+            stdin_console,
+            stdout_console,
+            stderr_console,
+        ),
+        None,
+    )?;
+
+    py.import("sys")?
+        .get(py, "modules")?
+        .set_item(py, "oxidized_app", module)
+}
+
+/// Replace `sys.argv` with the given arguments.
+fn set_sys_argv(py: Python, args: &[OsString]) -> PyResult<()> {
+    let args = args
+        .iter()
+        .map(|arg| osstring_to_str(py, arg.clone()))
+        .collect::<Result<Vec<PyObject>, &'static str>>()
+        .or_else(|e| Err(PyErr::new::<ValueError, _>(py, e)))?;
+
+    py.import("sys")?.add(py, "argv", PyList::new(py, &args))
+}
+
 /// Write loaded Python modules to a directory.
 ///
 /// Given a Python interpreter and a path to a directory, this will create a
@@ -839,19 +1262,148 @@ fn write_modules_to_directory(py: Python, path: &PathBuf) -> Result<(), &'static
     Ok(())
 }
 
+/// Write the import profile to a file.
+///
+/// Modules the filesystem importer loads are timed as they are imported.
+/// Modules in ``sys.modules`` that weren't recorded, such as those loaded
+/// before our importer was set up or by finders added by the application,
+/// are added as untimed ``filesystem`` imports if they were loaded from a
+/// file.
+fn write_import_profile(
+    py: Python,
+    profile: &SharedImportProfile,
+    path: &PathBuf,
+) -> Result<(), &'static str> {
+    let profiled = profile
+        .lock()
+        .or_else(|_| Err("import profile is poisoned"))?
+        .module_names();
+
+    let modules = py
+        .import("sys")
+        .and_then(|sys| sys.get(py, "modules"))
+        .or_else(|_| Err("could not obtain sys.modules"))?;
+    let modules = modules
+        .cast_as::<PyDict>(py)
+        .or_else(|_| Err("sys.modules is not a dict"))?;
+
+    let mut untimed = Vec::new();
+    for (key, value) in modules.items(py) {
+        let name = match key.extract::<String>(py) {
+            Ok(name) => name,
+            Err(_) => continue,
+        };
+
+        if profiled.contains(&name) {
+            continue;
+        }
+
+        let spec = match value.getattr(py, "__spec__") {
+            Ok(spec) if spec != py.None() => spec,
+            _ => continue,
+        };
+
+        let has_location = spec
+            .getattr(py, "has_location")
+            .and_then(|v| v.is_true(py))
+            .unwrap_or(false);
+        let origin = spec
+            .getattr(py, "origin")
+            .and_then(|v| v.extract::<String>(py));
+
+        if let (true, Ok(origin)) = (has_location, origin) {
+            let size = fs::metadata(&origin).map(|m| m.len() as usize).unwrap_or(0);
+            untimed.push((name, size));
+        }
+    }
+
+    let mut profile = profile
+        .lock()
+        .or_else(|_| Err("import profile is poisoned"))?;
+
+    for (name, size) in untimed {
+        profile.record_untimed(&name, "filesystem", size);
+    }
+
+    if let Some(parent) = path.parent() {
+        if !parent.as_os_str().is_empty() {
+            fs::create_dir_all(parent)
+                .or_else(|_| Err("could not create directory for import profile"))?;
+        }
+    }
+
+    fs::write(path, profile.to_json()).or_else(|_| Err("could not write import profile"))
+}
+
 impl<'a> Drop for MainPythonInterpreter<'a> {
     fn drop(&mut self) {
-        if let Some(key) = &self.config.write_modules_directory_env {
-            if let Ok(path) = env::var(key) {
-                let path = PathBuf::from(path);
-                let py = self.acquire_gil();
+        self.finalize_runtime();
+    }
+}
 
-                if let Err(msg) = write_modules_to_directory(py, &path) {
-                    eprintln!("error writing modules file: {}", msg);
+/// A Python interpreter which is initialized on first use.
+///
+/// Constructing this type is cheap: nothing is done until
+/// [`acquire()`](#method.acquire) is called. If the interpreter is never
+/// acquired, the Python runtime is never initialized.
+///
+/// The same single interpreter per process restrictions as
+/// [`MainPythonInterpreter`](struct.MainPythonInterpreter.html) apply.
+pub struct LazyPythonInterpreter<'a> {
+    config: PythonConfig,
+    interpreter: Option<MainPythonInterpreter<'a>>,
+    init_error: Option<&'static str>,
+}
+
+impl<'a> LazyPythonInterpreter<'a> {
+    /// Construct an instance that will initialize an interpreter from a config.
+    pub fn new(config: PythonConfig) -> LazyPythonInterpreter<'a> {
+        LazyPythonInterpreter {
+            config,
+            interpreter: None,
+            init_error: None,
+        }
+    }
+
+    /// The config the interpreter is initialized from.
+    pub fn config(&self) -> &PythonConfig {
+        &self.config
+    }
+
+    /// Whether the interpreter has been initialized.
+    pub fn is_initialized(&self) -> bool {
+        self.interpreter.is_some()
+    }
+
+    /// Obtain the interpreter, initializing it if necessary.
+    ///
+    /// If initialization fails, the error is returned from this and all
+    /// subsequent calls. Initialization is not retried.
+    pub fn acquire(&mut self) -> Result<&mut MainPythonInterpreter<'a>, &'static str> {
+        if let Some(e) = self.init_error {
+            return Err(e);
+        }
+
+        if self.interpreter.is_none() {
+            match MainPythonInterpreter::new(self.config.clone()) {
+                Ok(interp) => self.interpreter = Some(interp),
+                Err(e) => {
+                    self.init_error = Some(e);
+                    return Err(e);
                 }
             }
         }
 
-        let _ = unsafe { pyffi::Py_FinalizeEx() };
+        Ok(self.interpreter.as_mut().unwrap())
+    }
+
+    /// Finalize the interpreter if it was initialized and obtain the exit code.
+    ///
+    /// See [`MainPythonInterpreter::finalize()`](struct.MainPythonInterpreter.html#method.finalize).
+    pub fn finalize(self, code: i32) -> i32 {
+        match self.interpreter {
+            Some(interp) => interp.finalize(code),
+            None => code,
+        }
     }
 }
@@ -0,0 +1,243 @@
+# This Source Code Form is subject to the terms of the Mozilla Public
+# License, v. 2.0. If a copy of the MPL was not distributed with this
+# file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+"""Line editing and history support for the embedded REPL.
+
+This module is evaluated by the ``pyembed`` crate when running the REPL.
+If ``readline`` is available, it is used and only history persistence is
+configured. Otherwise a minimal pure Python line editor supporting cursor
+movement and history is used to drive ``code.InteractiveConsole``.
+"""
+
+import atexit
+import os
+import sys
+
+
+def _resolve_history_path(path):
+    if not path:
+        return None
+
+    return os.path.abspath(os.path.expanduser(path))
+
+
+def setup_readline_history(path):
+    """Persist ``readline`` history to ``path``."""
+    import readline
+
+    path = _resolve_history_path(path)
+    if not path:
+        return
+
+    try:
+        readline.read_history_file(path)
+    except OSError:
+        pass
+
+    def save():
+        try:
+            readline.write_history_file(path)
+        except OSError:
+            pass
+
+    atexit.register(save)
+
+
+if os.name == "nt":
+    import msvcrt
+
+    _WINDOWS_KEYS = {
+        "H": "up",
+        "P": "down",
+        "K": "left",
+        "M": "right",
+        "G": "home",
+        "O": "end",
+        "S": "delete",
+    }
+
+    def _read_keys():
+        while True:
+            c = msvcrt.getwch()
+            if c in ("\x00", "\xe0"):
+                yield _WINDOWS_KEYS.get(msvcrt.getwch())
+            else:
+                yield c
+
+    class _RawMode(object):
+        def __enter__(self):
+            return self
+
+        def __exit__(self, *args):
+            return False
+
+
+else:
+    import codecs
+    import termios
+    import tty
+
+    _ESCAPE_KEYS = {
+        "A": "up",
+        "B": "down",
+        "C": "right",
+        "D": "left",
+        "H": "home",
+        "F": "end",
+        "3": "delete",
+    }
+
+    def _read_keys():
+        fd = sys.stdin.fileno()
+        decoder = codecs.getincrementaldecoder("utf-8")("replace")
+
+        def read_char():
+            while True:
+                c = decoder.decode(os.read(fd, 1))
+                if c:
+                    return c
+
+        while True:
+            c = read_char()
+            if c != "\x1b":
+                yield c
+                continue
+
+            if read_char() not in ("[", "O"):
+                yield None
+                continue
+
+            key = read_char()
+            # Sequences like ESC [ 3 ~ have a trailing ~.
+            if key.isdigit():
+                read_char()
+
+            yield _ESCAPE_KEYS.get(key)
+
+    class _RawMode(object):
+        def __enter__(self):
+            self._fd = sys.stdin.fileno()
+            self._old = termios.tcgetattr(self._fd)
+            tty.setcbreak(self._fd)
+            return self
+
+        def __exit__(self, *args):
+            termios.tcsetattr(self._fd, termios.TCSADRAIN, self._old)
+            return False
+
+
+class LineEditor(object):
+    """A minimal line editor with history."""
+
+    def __init__(self, history_path=None):
+        self.history = []
+        self.history_path = _resolve_history_path(history_path)
+
+        if self.history_path:
+            try:
+                with open(self.history_path, "r", encoding="utf-8") as fh:
+                    self.history = [l.rstrip("\n") for l in fh if l.strip()]
+            except OSError:
+                pass
+
+    def add_history(self, line):
+        if not line.strip() or (self.history and self.history[-1] == line):
+            return
+
+        self.history.append(line)
+
+        if self.history_path:
+            try:
+                with open(self.history_path, "a", encoding="utf-8") as fh:
+                    fh.write(line + "\n")
+            except OSError:
+                pass
+
+    def readline(self, prompt=""):
+        if not sys.stdin.isatty():
+            line = input(prompt)
+            self.add_history(line)
+            return line
+
+        out = sys.stdout
+        out.write(prompt)
+        out.flush()
+
+        buf = []
+        pos = 0
+        shown = 0
+        history_index = len(self.history)
+        pending = ""
+
+        with _RawMode():
+            for key in _read_keys():
+                if key in ("\r", "\n"):
+                    out.write("\r\n")
+                    break
+                elif key == "\x03":
+                    out.write("\r\n")
+                    raise KeyboardInterrupt
+                elif key in ("\x04", "\x1a"):
+                    if not buf:
+                        out.write("\r\n")
+                        raise EOFError
+                    continue
+                elif key in ("\x08", "\x7f"):
+                    if pos:
+                        del buf[pos - 1]
+                        pos -= 1
+                elif key == "delete":
+                    if pos < len(buf):
+                        del buf[pos]
+                elif key == "left":
+                    pos = max(0, pos - 1)
+                elif key == "right":
+                    pos = min(len(buf), pos + 1)
+                elif key in ("home", "\x01"):
+                    pos = 0
+                elif key in ("end", "\x05"):
+                    pos = len(buf)
+                elif key in ("up", "down"):
+                    if history_index == len(self.history):
+                        pending = "".join(buf)
+
+                    if key == "up":
+                        history_index = max(0, history_index - 1)
+                    else:
+                        history_index = min(len(self.history), history_index + 1)
+
+                    if history_index == len(self.history):
+                        buf = list(pending)
+                    else:
+                        buf = list(self.history[history_index])
+                    pos = len(buf)
+                elif key is not None and (key >= " " or key == "\t"):
+                    buf.insert(pos, key)
+                    pos += 1
+                else:
+                    continue
+
+                # Redraw the line, blanking out any leftover characters and
+                # moving the cursor back to its logical position.
+                line = "".join(buf)
+                blank = max(0, shown - len(line))
+                out.write(
+                    "\r" + prompt + line + " " * blank + "\b" * (blank + len(line) - pos)
+                )
+                out.flush()
+                shown = len(line)
+
+        line = "".join(buf)
+        self.add_history(line)
+        return line
+
+
+def interact(history_path=None):
+    """Run a REPL using the fallback line editor."""
+    import code
+
+    editor = LineEditor(history_path)
+    console = code.InteractiveConsole(locals=sys.modules["__main__"].__dict__)
+    console.raw_input = editor.readline
+    console.interact(banner="", exitmsg="")
@@ -0,0 +1,123 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/*!
+Modules and resources shared by several executables.
+
+Applications consisting of several executables can store the modules and
+resources they have in common in a file installed next to them instead of
+embedding a copy in every executable.
+
+The file starts with magic bytes followed by the little-endian u64 lengths of
+modules data and resources data, then that data. Both are in the same formats
+as embedded data, including lazily indexed data.
+*/
+
+use std::fs;
+
+use byteorder::{LittleEndian, ReadBytesExt};
+use sha2::{Digest, Sha256};
+
+use super::config::SharedResources;
+
+/// Magic bytes at the start of a shared resources file.
+pub const SHARED_RESOURCES_MAGIC: &[u8] = b"pyoxsr01";
+
+/// Split shared resources file content into modules and resources data.
+pub fn parse_shared_resources(data: &[u8]) -> Result<(&[u8], &[u8]), &'static str> {
+    if !data.starts_with(SHARED_RESOURCES_MAGIC) {
+        return Err("shared resources file has unknown format");
+    }
+
+    let mut header = &data[SHARED_RESOURCES_MAGIC.len()..];
+
+    let modules_length = header
+        .read_u64::<LittleEndian>()
+        .or_else(|_| Err("failed reading shared modules length"))?
+        as usize;
+    let resources_length = header
+        .read_u64::<LittleEndian>()
+        .or_else(|_| Err("failed reading shared resources length"))?
+        as usize;
+
+    if header.len() < modules_length || header.len() - modules_length != resources_length {
+        return Err("shared resources file truncated");
+    }
+
+    Ok(header.split_at(modules_length))
+}
+
+/// Read and verify the shared resources file an executable was built with.
+///
+/// `origin` replaces `$ORIGIN` in the file's path.
+///
+/// The file content is leaked, as the importer references it for the
+/// lifetime of the process. So are error messages, as interpreter
+/// initialization reports static strings.
+pub fn load_shared_resources(
+    shared: &SharedResources,
+    origin: &str,
+) -> Result<(&'static [u8], &'static [u8]), &'static str> {
+    let path = shared.path.replace("$ORIGIN", origin);
+
+    let fail = |message: String| -> &'static str { Box::leak(message.into_boxed_str()) };
+
+    let data = fs::read(&path).or_else(|e| {
+        Err(fail(format!(
+            "unable to read shared resources {}: {}",
+            path, e
+        )))
+    })?;
+
+    let digest = Sha256::digest(&data)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<String>();
+
+    if digest != shared.sha256 {
+        return Err(fail(format!(
+            "shared resources {} do not match this executable; expected SHA-256 {}, got {}",
+            path, shared.sha256, digest
+        )));
+    }
+
+    let data: &'static [u8] = Box::leak(data.into_boxed_slice());
+
+    parse_shared_resources(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use byteorder::WriteBytesExt;
+
+    fn shared_file(modules: &[u8], resources: &[u8]) -> Vec<u8> {
+        let mut data = SHARED_RESOURCES_MAGIC.to_vec();
+        data.write_u64::<LittleEndian>(modules.len() as u64)
+            .unwrap();
+        data.write_u64::<LittleEndian>(resources.len() as u64)
+            .unwrap();
+        data.extend(modules);
+        data.extend(resources);
+
+        data
+    }
+
+    #[test]
+    fn test_parse_shared_resources() {
+        let data = shared_file(b"modules", b"res");
+        assert_eq!(
+            parse_shared_resources(&data),
+            Ok((&b"modules"[..], &b"res"[..]))
+        );
+
+        let data = shared_file(b"", b"");
+        assert_eq!(parse_shared_resources(&data), Ok((&b""[..], &b""[..])));
+
+        let data = shared_file(b"modules", b"res");
+        assert!(parse_shared_resources(&data[..data.len() - 1]).is_err());
+        assert!(parse_shared_resources(&data[1..]).is_err());
+        assert!(parse_shared_resources(SHARED_RESOURCES_MAGIC).is_err());
+    }
+}
@@ -64,3 +64,34 @@ lazy_static! {
         res
     };
 }
+
+/// Targets that can be built for but have no default Python distribution.
+///
+/// No python-build-standalone release of the version the defaults are
+/// pinned to exists for them. Configs must provide a distribution with
+/// `PythonDistribution()` until a default is added.
+pub const TARGETS_WITHOUT_DEFAULT_DISTRIBUTION: &[&str] = &["aarch64-apple-darwin"];
+
+/// Describe the lack of a default Python distribution for a target.
+///
+/// Lists the targets having one, since the target is often detected from
+/// the host rather than given explicitly.
+pub fn no_default_distribution_message(target: &str) -> String {
+    if TARGETS_WITHOUT_DEFAULT_DISTRIBUTION.contains(&target) {
+        return format!(
+            "{} is supported but no default Python distribution exists for it yet; use PythonDistribution() to provide a distribution for {}",
+            target, target
+        );
+    }
+
+    format!(
+        "no default Python distribution is available for {}; default distributions exist for {}; pass --target to build for one of them or use PythonDistribution() to provide a distribution for {}",
+        target,
+        CPYTHON_BY_TRIPLE
+            .keys()
+            .map(|k| k.as_str())
+            .collect::<Vec<_>>()
+            .join(", "),
+        target
+    )
+}
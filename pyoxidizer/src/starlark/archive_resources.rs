@@ -0,0 +1,491 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/*!
+Embedding resources from tar and zip archives.
+
+`archive_resources()` reads the files of an archive in memory and turns
+them into resource data of a Python package named by their paths in the
+archive. Nothing is extracted to disk.
+
+Entry paths are validated before use: absolute paths and paths with `..`
+components are rejected, like tar's own checks when extracting Python
+distributions. Only regular files become resources.
+*/
+
+use anyhow::{anyhow, Context, Result};
+use starlark::environment::Environment;
+use starlark::values::{RuntimeError, Value, ValueError, ValueResult};
+use starlark::{
+    starlark_fun, starlark_module, starlark_signature, starlark_signature_extraction,
+    starlark_signatures,
+};
+use std::collections::{BTreeMap, HashMap};
+use std::io::Read;
+use std::path::Path;
+
+use super::env::{
+    optional_list_arg, optional_str_arg, record_input_path, required_str_arg,
+    resolve_resource_path_arg,
+};
+use super::python_resource::PythonResourceData;
+use crate::cancel;
+use crate::py_packaging::resource::ResourceData;
+
+/// Error code for archives that cannot be read.
+pub const ARCHIVE_ERROR_CODE: &str = "ARCHIVE";
+
+/// Formats of archives resources can be read from.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ArchiveFormat {
+    Tar,
+    TarGz,
+    TarZst,
+    Zip,
+}
+
+impl ArchiveFormat {
+    /// Determine the format of an archive from its file name.
+    pub fn from_path(path: &Path) -> Result<ArchiveFormat> {
+        let name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_lowercase())
+            .unwrap_or_default();
+
+        if name.ends_with(".tar") {
+            Ok(ArchiveFormat::Tar)
+        } else if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+            Ok(ArchiveFormat::TarGz)
+        } else if name.ends_with(".tar.zst") || name.ends_with(".tzst") {
+            Ok(ArchiveFormat::TarZst)
+        } else if name.ends_with(".zip") {
+            Ok(ArchiveFormat::Zip)
+        } else {
+            Err(anyhow!(
+                "unable to determine archive format of {}; supported extensions are .tar, .tar.gz, .tgz, .tar.zst, .tzst and .zip",
+                path.display()
+            ))
+        }
+    }
+}
+
+/// Validate and normalize the path of an archive entry.
+///
+/// Backslashes are treated as separators and `.` components are removed.
+/// Absolute paths, drive prefixes and `..` components are rejected.
+pub fn normalize_entry_path(path: &str) -> Result<String> {
+    if path.contains('\0') {
+        return Err(anyhow!("archive entry {:?} contains a NUL byte", path));
+    }
+
+    let path = path.replace('\\', "/");
+
+    if path.starts_with('/') {
+        return Err(anyhow!("archive entry {} has an absolute path", path));
+    }
+
+    let mut components = Vec::new();
+
+    for component in path.split('/') {
+        match component {
+            "" | "." => {}
+            ".." => {
+                return Err(anyhow!(
+                    "archive entry {} refers to a parent directory",
+                    path
+                ))
+            }
+            c if c.contains(':') => {
+                return Err(anyhow!("archive entry {} has a drive prefix", path))
+            }
+            c => components.push(c),
+        }
+    }
+
+    Ok(components.join("/"))
+}
+
+/// Read the regular files of an archive into memory.
+///
+/// Returns normalized entry paths and file content, sorted by path. If an
+/// archive holds a path more than once, the last entry wins.
+pub fn read_archive_files(path: &Path, format: ArchiveFormat) -> Result<BTreeMap<String, Vec<u8>>> {
+    let fh = std::fs::File::open(path).with_context(|| format!("opening {}", path.display()))?;
+    let reader = std::io::BufReader::new(fh);
+
+    match format {
+        ArchiveFormat::Tar => read_tar_files(reader),
+        ArchiveFormat::TarGz => read_tar_files(flate2::read::GzDecoder::new(reader)),
+        ArchiveFormat::TarZst => read_tar_files(zstd::stream::Decoder::new(reader)?),
+        ArchiveFormat::Zip => read_zip_files(reader),
+    }
+    .with_context(|| format!("reading archive {}", path.display()))
+}
+
+fn read_tar_files<R: Read>(reader: R) -> Result<BTreeMap<String, Vec<u8>>> {
+    let mut archive = tar::Archive::new(reader);
+    let mut files = BTreeMap::new();
+
+    for entry in archive.entries()? {
+        cancel::check_cancelled()?;
+
+        let mut entry = entry?;
+        let name = normalize_entry_path(&entry.path()?.to_string_lossy())?;
+
+        if !entry.header().entry_type().is_file() || name.is_empty() {
+            continue;
+        }
+
+        let mut data = Vec::new();
+        entry.read_to_end(&mut data)?;
+        files.insert(name, data);
+    }
+
+    Ok(files)
+}
+
+fn read_zip_files<R: Read + std::io::Seek>(reader: R) -> Result<BTreeMap<String, Vec<u8>>> {
+    let mut archive = zip::ZipArchive::new(reader)?;
+    let mut files = BTreeMap::new();
+
+    for index in 0..archive.len() {
+        cancel::check_cancelled()?;
+
+        let mut file = archive.by_index(index)?;
+        let raw_name = file.name().to_string();
+        let name = normalize_entry_path(&raw_name)?;
+
+        if raw_name.ends_with('/') || raw_name.ends_with('\\') || name.is_empty() {
+            continue;
+        }
+
+        let mut data = Vec::new();
+        file.read_to_end(&mut data)?;
+        files.insert(name, data);
+    }
+
+    Ok(files)
+}
+
+/// Obtain the resource name of an archive file.
+///
+/// Returns `None` for files outside `strip_prefix` or not selected by the
+/// glob patterns, which match against the name after stripping.
+pub fn archive_resource_name(
+    path: &str,
+    strip_prefix: &str,
+    includes: &[glob::Pattern],
+    excludes: &[glob::Pattern],
+) -> Option<String> {
+    let strip_prefix = strip_prefix.trim_matches('/');
+
+    let name = if strip_prefix.is_empty() {
+        path
+    } else if path.starts_with(strip_prefix) && path[strip_prefix.len()..].starts_with('/') {
+        &path[strip_prefix.len() + 1..]
+    } else {
+        return None;
+    };
+
+    let options = glob::MatchOptions {
+        require_literal_separator: true,
+        ..glob::MatchOptions::new()
+    };
+
+    if !includes.is_empty() && !includes.iter().any(|p| p.matches_with(name, options)) {
+        return None;
+    }
+
+    if excludes.iter().any(|p| p.matches_with(name, options)) {
+        return None;
+    }
+
+    Some(name.to_string())
+}
+
+fn archive_error(message: String) -> ValueError {
+    RuntimeError {
+        code: ARCHIVE_ERROR_CODE,
+        message,
+        label: "archive_resources()".to_string(),
+    }
+    .into()
+}
+
+fn glob_patterns(values: &Value) -> Result<Vec<glob::Pattern>, ValueError> {
+    match values.get_type() {
+        "list" => values
+            .into_iter()?
+            .map(|v| {
+                let pattern = v.to_string();
+                glob::Pattern::new(&pattern)
+                    .map_err(|e| archive_error(format!("invalid glob {}: {}", pattern, e)))
+            })
+            .collect(),
+        _ => Ok(Vec::new()),
+    }
+}
+
+starlark_module! { archive_resources_module =>
+    #[allow(clippy::ptr_arg)]
+    archive_resources(env env, path, dest_package, strip_prefix=None, includes=None, excludes=None) {
+        let path = required_str_arg("path", &path)?;
+        let dest_package = required_str_arg("dest_package", &dest_package)?;
+        let strip_prefix = optional_str_arg("strip_prefix", &strip_prefix)?;
+        optional_list_arg("includes", "string", &includes)?;
+        optional_list_arg("excludes", "string", &excludes)?;
+
+        let includes = glob_patterns(&includes)?;
+        let excludes = glob_patterns(&excludes)?;
+
+        let archive_path = resolve_resource_path_arg(&env, "archive_resources()", &path, false)?;
+        let format = ArchiveFormat::from_path(&archive_path)
+            .map_err(|e| archive_error(e.to_string()))?;
+
+        record_input_path(&env, &archive_path);
+
+        let files = read_archive_files(&archive_path, format)
+            .map_err(|e| archive_error(format!("{:#}", e)))?;
+
+        let strip_prefix = strip_prefix.unwrap_or_default();
+
+        let resources = files
+            .into_iter()
+            .filter_map(|(entry_path, data)| {
+                archive_resource_name(&entry_path, &strip_prefix, &includes, &excludes).map(|name| {
+                    Value::new(PythonResourceData {
+                        data: ResourceData {
+                            package: dest_package.clone(),
+                            name,
+                            data,
+                        },
+                    })
+                })
+            })
+            .collect::<Vec<_>>();
+
+        Ok(Value::from(resources))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::testutil::*;
+    use super::*;
+    use crate::app_packaging::environment::EnvironmentContext;
+    use std::io::Write;
+
+    const FILES: &[(&str, &[u8])] = &[
+        ("assets/logo.png", b"png"),
+        ("assets/css/site.css", b"body {}"),
+        ("assets/css/site.css.map", b"{}"),
+        ("README", b"readme"),
+    ];
+
+    fn tar_data(entries: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::new());
+
+        for (name, data) in entries {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder.append_data(&mut header, name, *data).unwrap();
+        }
+
+        builder.into_inner().unwrap()
+    }
+
+    fn write_tar_gz(path: &Path, entries: &[(&str, &[u8])]) -> Result<()> {
+        let mut encoder =
+            flate2::write::GzEncoder::new(std::fs::File::create(path)?, Default::default());
+        encoder.write_all(&tar_data(entries))?;
+        encoder.finish()?;
+
+        Ok(())
+    }
+
+    fn write_zip(path: &Path, entries: &[(&str, &[u8])]) -> Result<()> {
+        let mut writer = zip::ZipWriter::new(std::fs::File::create(path)?);
+
+        for (name, data) in entries {
+            writer.start_file(*name, zip::write::FileOptions::default())?;
+            writer.write_all(data)?;
+        }
+        writer.finish()?;
+
+        Ok(())
+    }
+
+    fn resource_names(value: Value) -> Vec<String> {
+        value
+            .into_iter()
+            .unwrap()
+            .map(|x| x.to_str())
+            .collect::<Vec<_>>()
+    }
+
+    #[test]
+    fn test_normalize_entry_path() -> Result<()> {
+        assert_eq!(normalize_entry_path("a/b.txt")?, "a/b.txt");
+        assert_eq!(normalize_entry_path("./a//b.txt")?, "a/b.txt");
+        assert_eq!(normalize_entry_path("a\\b.txt")?, "a/b.txt");
+        assert_eq!(normalize_entry_path("a/")?, "a");
+
+        for bad in &[
+            "../evil.txt",
+            "a/../../evil.txt",
+            "a\\..\\evil.txt",
+            "/etc/passwd",
+            "\\evil.txt",
+            "C:/evil.txt",
+            "a\0b",
+        ] {
+            assert!(normalize_entry_path(bad).is_err(), "{}", bad);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_archive_resource_name() -> Result<()> {
+        let css = vec![glob::Pattern::new("css/*")?];
+        let maps = vec![glob::Pattern::new("**/*.map")?];
+
+        assert_eq!(
+            archive_resource_name("assets/logo.png", "assets/", &[], &[]),
+            Some("logo.png".to_string())
+        );
+        assert_eq!(archive_resource_name("README", "assets", &[], &[]), None);
+        assert_eq!(archive_resource_name("assetsx/a", "assets", &[], &[]), None);
+        assert_eq!(
+            archive_resource_name("README", "", &[], &[]),
+            Some("README".to_string())
+        );
+        assert_eq!(
+            archive_resource_name("assets/logo.png", "assets", &css, &[]),
+            None
+        );
+        assert_eq!(
+            archive_resource_name("assets/css/site.css", "assets", &css, &maps),
+            Some("css/site.css".to_string())
+        );
+        assert_eq!(
+            archive_resource_name("assets/css/site.css.map", "assets", &css, &maps),
+            None
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_archive_resources_formats() -> Result<()> {
+        let temp_dir = tempdir::TempDir::new("pyoxidizer-test")?;
+
+        let tar_gz = temp_dir.path().join("assets.tar.gz");
+        write_tar_gz(&tar_gz, FILES)?;
+        let tar_zst = temp_dir.path().join("assets.tar.zst");
+        std::fs::write(&tar_zst, zstd::stream::encode_all(&tar_data(FILES)[..], 3)?)?;
+        let zip = temp_dir.path().join("assets.zip");
+        write_zip(&zip, FILES)?;
+
+        for path in &[&tar_gz, &tar_zst, &zip] {
+            let path = path.display().to_string().replace("\\", "/");
+
+            let mut env = starlark_env();
+            let v = starlark_eval_in_env(
+                &mut env,
+                &format!(
+                    "archive_resources(path='{}', strip_prefix='assets/', dest_package='myapp.assets', excludes=['**/*.map'])",
+                    path
+                ),
+            )
+            .unwrap();
+            assert_eq!(
+                resource_names(v),
+                vec![
+                    "PythonResourceData<package=myapp.assets, name=css/site.css>",
+                    "PythonResourceData<package=myapp.assets, name=logo.png>",
+                ]
+            );
+
+            let input_paths = env
+                .get("CONTEXT")
+                .unwrap()
+                .downcast_apply(|x: &EnvironmentContext| x.input_paths.clone());
+            assert!(input_paths.contains(Path::new(&path)));
+
+            let v = starlark_ok(&format!(
+                "archive_resources('{}', dest_package='data', includes=['assets/css/*.css'])",
+                path
+            ));
+            assert_eq!(
+                resource_names(v),
+                vec!["PythonResourceData<package=data, name=assets/css/site.css>"]
+            );
+        }
+
+        for (path, format) in &[
+            (&tar_gz, ArchiveFormat::TarGz),
+            (&tar_zst, ArchiveFormat::TarZst),
+            (&zip, ArchiveFormat::Zip),
+        ] {
+            assert_eq!(ArchiveFormat::from_path(path)?, *format);
+
+            let files = read_archive_files(path, *format)?;
+            assert_eq!(files.len(), FILES.len());
+            assert_eq!(files["assets/css/site.css"], b"body {}".to_vec());
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_archive_resources_malicious() -> Result<()> {
+        let temp_dir = tempdir::TempDir::new("pyoxidizer-test")?;
+
+        // tar::Builder refuses to write `..` paths, so set the raw name.
+        let mut header = tar::Header::new_gnu();
+        header.as_old_mut().name[..13].copy_from_slice(b"../evil.pth\0\0");
+        header.set_size(4);
+        header.set_mode(0o644);
+        header.set_cksum();
+        let mut builder = tar::Builder::new(Vec::new());
+        builder.append(&header, &b"evil"[..])?;
+        let tar = temp_dir.path().join("evil.tar");
+        std::fs::write(&tar, builder.into_inner()?)?;
+
+        let zip = temp_dir.path().join("evil.zip");
+        write_zip(&zip, &[("ok.txt", b"ok"), ("../../evil.pth", b"evil")])?;
+
+        for path in &[&tar, &zip] {
+            let path = path.display().to_string().replace("\\", "/");
+
+            let err = starlark_nok(&format!(
+                "archive_resources('{}', dest_package='myapp')",
+                path
+            ));
+            assert!(
+                err.message.contains("refers to a parent directory"),
+                "{}",
+                err.message
+            );
+        }
+
+        // Nothing was written next to the archives.
+        assert!(!temp_dir.path().join("evil.pth").exists());
+
+        starlark_nok(&format!(
+            "archive_resources('{}', dest_package='myapp')",
+            temp_dir
+                .path()
+                .join("evil.rar")
+                .display()
+                .to_string()
+                .replace("\\", "/")
+        ));
+
+        Ok(())
+    }
+}
@@ -3,22 +3,35 @@
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
 use starlark::environment::Environment;
-use starlark::values::{default_compare, TypedValue, Value, ValueError, ValueResult};
+use starlark::values::{
+    default_compare, RuntimeError, TypedValue, Value, ValueError, ValueResult,
+    INCORRECT_PARAMETER_TYPE_ERROR_CODE,
+};
 use starlark::{
     any, immutable, not_supported, starlark_fun, starlark_module, starlark_signature,
     starlark_signature_extraction, starlark_signatures,
 };
 use std::any::Any;
 use std::cmp::Ordering;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::path::PathBuf;
 
 use super::embedded_python_config::EmbeddedPythonConfig;
-use super::env::{required_str_arg, required_type_arg};
+use super::env::{
+    optional_dict_arg, optional_list_arg, optional_str_arg, optional_type_arg, required_bool_arg,
+    required_str_arg, required_type_arg,
+};
 use super::python_distribution::PythonDistribution;
 use super::python_run_mode::PythonRunMode;
-use crate::app_packaging::config::{BuildConfig as ConfigBuildConfig, Config as ConfigConfig};
+use crate::app_packaging::config::{
+    validate_hooks, validate_multicall_names, validate_rustflags, validate_signing_command,
+    BuildConfig as ConfigBuildConfig, Config as ConfigConfig, SplitDebugInfo,
+    DEFAULT_SIGNING_TIMEOUT,
+};
 use crate::app_packaging::environment::EnvironmentContext;
+use crate::app_packaging::output_layout::validate_output_layout;
+use crate::app_packaging::toolchain::Toolchain;
+use crate::licensing::LicenseCheckPolicy;
 use crate::py_packaging::config::{EmbeddedPythonConfig as ConfigEmbeddedPythonConfig, RunMode};
 use crate::py_packaging::distribution::PythonDistributionLocation;
 
@@ -64,20 +77,228 @@ starlark_module! { config_env =>
         application_name,
         embedded_python_config=None,
         python_distribution=None,
-        python_run_mode=None
+        python_run_mode=None,
+        multicall_run_modes=None,
+        rustflags=None,
+        target_cpu=None,
+        split_debug_info=None,
+        signing_command=None,
+        signing_timeout=None,
+        link_search_paths=None,
+        link_libraries=None,
+        pre_init_hooks=None,
+        post_finalize_hooks=None,
+        allow_rustc_bootstrap=true,
+        output_layout=None,
+        third_party_notices=false,
+        license_denylist=None,
+        license_allowlist=None,
+        license_exempt_extensions=None,
+        allow_external_paths=None,
+        rust_toolchain=None
     ) {
         let application_name = required_str_arg("application_name", &application_name)?;
         required_type_arg("embedded_python_config", "EmbeddedPythonConfig", &embedded_python_config)?;
         required_type_arg("python_distribution", "PythonDistribution", &python_distribution)?;
         required_type_arg("python_run_mode", "PythonRunMode", &python_run_mode)?;
+        optional_dict_arg("multicall_run_modes", "string", "PythonRunMode", &multicall_run_modes)?;
+        optional_list_arg("rustflags", "string", &rustflags)?;
+        let target_cpu = optional_str_arg("target_cpu", &target_cpu)?;
+        let split_debug_info = match optional_str_arg("split_debug_info", &split_debug_info)? {
+            None => SplitDebugInfo::Disabled,
+            Some(ref v) if v == "adjacent" => SplitDebugInfo::Adjacent,
+            Some(ref v) if v == "directory" => SplitDebugInfo::Directory,
+            Some(v) => return Err(RuntimeError {
+                code: INCORRECT_PARAMETER_TYPE_ERROR_CODE,
+                message: format!("split_debug_info must be 'adjacent' or 'directory'; got {}", v),
+                label: "split_debug_info".to_string(),
+            }.into()),
+        };
+
+        optional_list_arg("signing_command", "string", &signing_command)?;
+        optional_type_arg("signing_timeout", "int", &signing_timeout)?;
+        optional_list_arg("link_search_paths", "string", &link_search_paths)?;
+        optional_list_arg("link_libraries", "string", &link_libraries)?;
+        optional_list_arg("pre_init_hooks", "string", &pre_init_hooks)?;
+        optional_list_arg("post_finalize_hooks", "string", &post_finalize_hooks)?;
+        let allow_rustc_bootstrap = required_bool_arg("allow_rustc_bootstrap", &allow_rustc_bootstrap)?;
+        let output_layout = optional_str_arg("output_layout", &output_layout)?;
+        let third_party_notices = required_bool_arg("third_party_notices", &third_party_notices)?;
+        optional_list_arg("license_denylist", "string", &license_denylist)?;
+        optional_list_arg("license_allowlist", "string", &license_allowlist)?;
+        optional_list_arg("license_exempt_extensions", "string", &license_exempt_extensions)?;
+        optional_list_arg("allow_external_paths", "string", &allow_external_paths)?;
+        let rust_toolchain = optional_str_arg("rust_toolchain", &rust_toolchain)?;
+
+        if let Some(layout) = &output_layout {
+            validate_output_layout(layout).or_else(|e| Err(RuntimeError {
+                code: INCORRECT_PARAMETER_TYPE_ERROR_CODE,
+                message: e.to_string(),
+                label: "output_layout".to_string(),
+            }.into()))?;
+        }
+
+        let rustflags = match rustflags.get_type() {
+            "list" => rustflags.into_iter()?.map(|x| x.to_string()).collect(),
+            "NoneType" => Vec::new(),
+            _ => panic!("should have validated type above"),
+        };
+
+        validate_rustflags(&rustflags).or_else(|e| Err(RuntimeError {
+            code: INCORRECT_PARAMETER_TYPE_ERROR_CODE,
+            message: e.to_string(),
+            label: "rustflags".to_string(),
+        }.into()))?;
+
+        let signing_command = match signing_command.get_type() {
+            "list" => signing_command.into_iter()?.map(|x| x.to_str()).collect(),
+            "NoneType" => Vec::new(),
+            _ => panic!("should have validated type above"),
+        };
+
+        validate_signing_command(&signing_command).or_else(|e| Err(RuntimeError {
+            code: INCORRECT_PARAMETER_TYPE_ERROR_CODE,
+            message: e.to_string(),
+            label: "signing_command".to_string(),
+        }.into()))?;
+
+        let signing_timeout = match signing_timeout.get_type() {
+            "int" => match signing_timeout.to_int()? {
+                v if v > 0 => v as u64,
+                v => return Err(RuntimeError {
+                    code: INCORRECT_PARAMETER_TYPE_ERROR_CODE,
+                    message: format!("signing_timeout must be positive; got {}", v),
+                    label: "signing_timeout".to_string(),
+                }.into()),
+            },
+            _ => DEFAULT_SIGNING_TIMEOUT,
+        };
+
+        let multicall_run_modes = match multicall_run_modes.get_type() {
+            "dict" => multicall_run_modes.into_iter()?.map(|key| {
+                let run_mode = multicall_run_modes.at(key.clone()).unwrap()
+                    .downcast_apply(|x: &PythonRunMode| x.run_mode.clone());
+                (key.to_str(), run_mode)
+            }).collect::<BTreeMap<String, RunMode>>(),
+            "NoneType" => BTreeMap::new(),
+            _ => panic!("should have validated type above"),
+        };
+
+        validate_multicall_names(multicall_run_modes.keys()).or_else(|e| Err(RuntimeError {
+            code: INCORRECT_PARAMETER_TYPE_ERROR_CODE,
+            message: e.to_string(),
+            label: "multicall_run_modes".to_string(),
+        }.into()))?;
+
+        let pre_init_hooks: Vec<String> = match pre_init_hooks.get_type() {
+            "list" => pre_init_hooks.into_iter()?.map(|x| x.to_str()).collect(),
+            "NoneType" => Vec::new(),
+            _ => panic!("should have validated type above"),
+        };
+
+        validate_hooks("pre_init_hooks", &pre_init_hooks).or_else(|e| Err(RuntimeError {
+            code: INCORRECT_PARAMETER_TYPE_ERROR_CODE,
+            message: e.to_string(),
+            label: "pre_init_hooks".to_string(),
+        }.into()))?;
+
+        let post_finalize_hooks: Vec<String> = match post_finalize_hooks.get_type() {
+            "list" => post_finalize_hooks.into_iter()?.map(|x| x.to_str()).collect(),
+            "NoneType" => Vec::new(),
+            _ => panic!("should have validated type above"),
+        };
+
+        validate_hooks("post_finalize_hooks", &post_finalize_hooks).or_else(|e| Err(RuntimeError {
+            code: INCORRECT_PARAMETER_TYPE_ERROR_CODE,
+            message: e.to_string(),
+            label: "post_finalize_hooks".to_string(),
+        }.into()))?;
+
+        // Hooks are declared with different signatures, so a function can't be both.
+        if let Some(name) = post_finalize_hooks.iter().find(|name| pre_init_hooks.contains(name)) {
+            return Err(RuntimeError {
+                code: INCORRECT_PARAMETER_TYPE_ERROR_CODE,
+                message: format!("{} is in both pre_init_hooks and post_finalize_hooks", name),
+                label: "post_finalize_hooks".to_string(),
+            }.into());
+        }
+
+        let link_libraries = match link_libraries.get_type() {
+            "list" => link_libraries.into_iter()?.map(|x| x.to_str()).collect(),
+            "NoneType" => Vec::new(),
+            _ => panic!("should have validated type above"),
+        };
+
+        let license_check = LicenseCheckPolicy {
+            denylist: match license_denylist.get_type() {
+                "list" => license_denylist.into_iter()?.map(|x| x.to_str()).collect(),
+                _ => Vec::new(),
+            },
+            allowlist: match license_allowlist.get_type() {
+                "list" => Some(license_allowlist.into_iter()?.map(|x| x.to_str()).collect()),
+                _ => None,
+            },
+            exempt_extensions: match license_exempt_extensions.get_type() {
+                "list" => license_exempt_extensions.into_iter()?.map(|x| x.to_str()).collect(),
+                _ => BTreeSet::new(),
+            },
+        };
 
         let context = env.get("CONTEXT").expect("CONTEXT not set");
 
         let build_path = context.downcast_apply(|x: &EnvironmentContext| x.build_path.clone());
 
+        // Search paths are often system directories. So they aren't
+        // recorded as inputs, which would hash their content.
+        let link_search_paths = match link_search_paths.get_type() {
+            "list" => link_search_paths.into_iter()?.map(|x| {
+                context.downcast_apply(|c: &EnvironmentContext| c.resolve_path(&x.to_str()))
+            }).collect(),
+            "NoneType" => Vec::new(),
+            _ => panic!("should have validated type above"),
+        };
+
+        // Like search paths, whitelisted directories aren't inputs by
+        // themselves. Paths under them used by rules are.
+        let allow_external_paths = match allow_external_paths.get_type() {
+            "list" => allow_external_paths.into_iter()?.map(|x| {
+                context.downcast_apply(|c: &EnvironmentContext| c.resolve_path(&x.to_str()))
+            }).collect(),
+            "NoneType" => Vec::new(),
+            _ => panic!("should have validated type above"),
+        };
+
+        // Paths to cargo are relative to the config file.
+        let rust_toolchain = match rust_toolchain {
+            Some(value) => {
+                let cwd = context.downcast_apply(|x: &EnvironmentContext| x.cwd.clone());
+
+                Some(Toolchain::parse(&value, &cwd).or_else(|e| Err(RuntimeError {
+                    code: INCORRECT_PARAMETER_TYPE_ERROR_CODE,
+                    message: e.to_string(),
+                    label: "rust_toolchain".to_string(),
+                }.into()))?)
+            }
+            None => None,
+        };
+
         let build_config = ConfigBuildConfig {
             application_name,
             build_path,
+            rustflags,
+            target_cpu,
+            split_debug_info,
+            signing_command,
+            signing_timeout,
+            link_search_paths,
+            link_libraries,
+            pre_init_hooks,
+            post_finalize_hooks,
+            allow_rustc_bootstrap,
+            output_layout,
+            third_party_notices,
+            allow_external_paths,
+            rust_toolchain,
         };
 
         let embedded_python_config = embedded_python_config.downcast_apply(|x: &EmbeddedPythonConfig| -> ConfigEmbeddedPythonConfig {
@@ -99,6 +320,18 @@ starlark_module! { config_env =>
             embedded_python_config,
             python_distribution,
             run,
+            multicall_run_modes,
+            // Populated once evaluation completes.
+            python_package_versions: BTreeMap::new(),
+            python_package_hashes: BTreeMap::new(),
+            read_files: Vec::new(),
+            input_paths: BTreeSet::new(),
+            packaging_scripts: Vec::new(),
+            extension_module_license_policy: None,
+            required_sqlite_options: Vec::new(),
+            embedded_config_files: BTreeMap::new(),
+            license_check,
+            external_paths: Vec::new(),
         };
 
         let v = Value::new(Config { config });
@@ -112,6 +345,7 @@ starlark_module! { config_env =>
 #[cfg(test)]
 mod tests {
     use super::super::testutil::*;
+    use super::*;
     use indoc::indoc;
 
     #[test]
@@ -138,4 +372,286 @@ mod tests {
         let v = starlark_ok(content);
         assert_eq!(v.get_type(), "Config");
     }
+
+    #[test]
+    fn test_config_allow_rustc_bootstrap() {
+        let v = starlark_ok(indoc!(
+            r#"
+            Config(
+                application_name='myapp',
+                embedded_python_config=EmbeddedPythonConfig(),
+                python_distribution=default_python_distribution(),
+                python_run_mode=python_run_mode_repl(),
+            )
+        "#
+        ));
+        v.downcast_apply(|x: &Config| assert!(x.config.build_config.allow_rustc_bootstrap));
+
+        let v = starlark_ok(indoc!(
+            r#"
+            Config(
+                application_name='myapp',
+                embedded_python_config=EmbeddedPythonConfig(),
+                python_distribution=default_python_distribution(),
+                python_run_mode=python_run_mode_repl(),
+                allow_rustc_bootstrap=False,
+            )
+        "#
+        ));
+        v.downcast_apply(|x: &Config| assert!(!x.config.build_config.allow_rustc_bootstrap));
+    }
+
+    #[test]
+    fn test_config_license_check() {
+        let v = starlark_ok(indoc!(
+            r#"
+            Config(
+                application_name='myapp',
+                embedded_python_config=EmbeddedPythonConfig(),
+                python_distribution=default_python_distribution(),
+                python_run_mode=python_run_mode_repl(),
+                license_denylist=['GPL-2.0', 'GPL-3.0'],
+                license_exempt_extensions=['readline'],
+            )
+        "#
+        ));
+        v.downcast_apply(|x: &Config| {
+            let check = &x.config.license_check;
+            assert_eq!(check.denylist, vec!["GPL-2.0", "GPL-3.0"]);
+            assert_eq!(check.allowlist, None);
+            assert!(check.exempt_extensions.contains("readline"));
+        });
+
+        let err = starlark_nok(indoc!(
+            r#"
+            Config(
+                application_name='myapp',
+                embedded_python_config=EmbeddedPythonConfig(),
+                python_distribution=default_python_distribution(),
+                python_run_mode=python_run_mode_repl(),
+                license_allowlist='MIT',
+            )
+        "#
+        ));
+        assert!(err.message.contains("license_allowlist"));
+    }
+
+    #[test]
+    fn test_config_link_options() {
+        let content = indoc!(
+            r#"
+            Config(
+                application_name='myapp',
+                embedded_python_config=EmbeddedPythonConfig(),
+                python_distribution=default_python_distribution(),
+                python_run_mode=python_run_mode_repl(),
+                link_search_paths=['/opt/vendor/lib'],
+                link_libraries=['vendor'],
+                pre_init_hooks=['parse_args'],
+                post_finalize_hooks=['flush_telemetry'],
+            )
+        "#
+        );
+
+        let v = starlark_ok(content);
+        v.downcast_apply(|x: &Config| {
+            assert_eq!(
+                x.config.build_config.link_search_paths,
+                vec![PathBuf::from("/opt/vendor/lib")]
+            );
+            assert_eq!(
+                x.config.build_config.link_libraries,
+                vec!["vendor".to_string()]
+            );
+            assert_eq!(
+                x.config.build_config.pre_init_hooks,
+                vec!["parse_args".to_string()]
+            );
+            assert_eq!(
+                x.config.build_config.post_finalize_hooks,
+                vec!["flush_telemetry".to_string()]
+            );
+        });
+
+        let err = starlark_nok(indoc!(
+            r#"
+            Config(
+                application_name='myapp',
+                embedded_python_config=EmbeddedPythonConfig(),
+                python_distribution=default_python_distribution(),
+                python_run_mode=python_run_mode_repl(),
+                pre_init_hooks=['app::parse_args'],
+            )
+        "#
+        ));
+        assert!(err.message.contains("is not a Rust function name"));
+
+        let err = starlark_nok(indoc!(
+            r#"
+            Config(
+                application_name='myapp',
+                embedded_python_config=EmbeddedPythonConfig(),
+                python_distribution=default_python_distribution(),
+                python_run_mode=python_run_mode_repl(),
+                pre_init_hooks=['setup'],
+                post_finalize_hooks=['setup'],
+            )
+        "#
+        ));
+        assert_eq!(
+            err.message,
+            "setup is in both pre_init_hooks and post_finalize_hooks"
+        );
+    }
+
+    #[test]
+    fn test_config_rustflags() {
+        let content = indoc!(
+            r#"
+            Config(
+                application_name='myapp',
+                embedded_python_config=EmbeddedPythonConfig(),
+                python_distribution=default_python_distribution(),
+                python_run_mode=python_run_mode_repl(),
+                rustflags=['-C', 'codegen-units=1'],
+                target_cpu='x86-64-v2',
+            )
+        "#
+        );
+
+        let v = starlark_ok(content);
+        v.downcast_apply(|x: &Config| {
+            assert_eq!(
+                x.config.build_config.rustflags,
+                vec!["-C".to_string(), "codegen-units=1".to_string()]
+            );
+            assert_eq!(
+                x.config.build_config.target_cpu,
+                Some("x86-64-v2".to_string())
+            );
+        });
+    }
+
+    #[test]
+    fn test_config_rustflags_reserved() {
+        let content = indoc!(
+            r#"
+            Config(
+                application_name='myapp',
+                embedded_python_config=EmbeddedPythonConfig(),
+                python_distribution=default_python_distribution(),
+                python_run_mode=python_run_mode_repl(),
+                rustflags=['-C', 'target-feature=+crt-static'],
+            )
+        "#
+        );
+
+        let err = starlark_nok(content);
+        assert!(err.message.contains("crt-static"));
+    }
+
+    #[test]
+    fn test_config_split_debug_info() {
+        let content = indoc!(
+            r#"
+            Config(
+                application_name='myapp',
+                embedded_python_config=EmbeddedPythonConfig(),
+                python_distribution=default_python_distribution(),
+                python_run_mode=python_run_mode_repl(),
+                split_debug_info='directory',
+            )
+        "#
+        );
+
+        let v = starlark_ok(content);
+        v.downcast_apply(|x: &Config| {
+            assert_eq!(
+                x.config.build_config.split_debug_info,
+                SplitDebugInfo::Directory
+            );
+        });
+
+        let err = starlark_nok(&content.replace("'directory'", "'elsewhere'"));
+        assert!(err.message.contains("split_debug_info"));
+    }
+
+    #[test]
+    fn test_config_signing_command() {
+        let content = indoc!(
+            r#"
+            Config(
+                application_name='myapp',
+                embedded_python_config=EmbeddedPythonConfig(),
+                python_distribution=default_python_distribution(),
+                python_run_mode=python_run_mode_repl(),
+                signing_command=['./sign.sh', '--file={path}'],
+                signing_timeout=30,
+            )
+        "#
+        );
+
+        let v = starlark_ok(content);
+        v.downcast_apply(|x: &Config| {
+            assert_eq!(
+                x.config.build_config.signing_command,
+                vec!["./sign.sh".to_string(), "--file={path}".to_string()]
+            );
+            assert_eq!(x.config.build_config.signing_timeout, 30);
+        });
+
+        let v = starlark_ok(&content.replace("signing_timeout=30,", ""));
+        v.downcast_apply(|x: &Config| {
+            assert_eq!(
+                x.config.build_config.signing_timeout,
+                DEFAULT_SIGNING_TIMEOUT
+            );
+        });
+
+        let err = starlark_nok(&content.replace("'--file={path}'", "'--file'"));
+        assert!(err.message.contains("must include {path}"));
+
+        let err = starlark_nok(&content.replace("signing_timeout=30", "signing_timeout=0"));
+        assert!(err.message.contains("signing_timeout must be positive"));
+    }
+
+    #[test]
+    fn test_config_multicall_run_modes() {
+        let content = indoc!(
+            r#"
+            Config(
+                application_name='myapp',
+                embedded_python_config=EmbeddedPythonConfig(),
+                python_distribution=default_python_distribution(),
+                python_run_mode=python_run_mode_module('myapp.cli'),
+                multicall_run_modes={
+                    'myapp': python_run_mode_module('myapp.cli'),
+                    'myapp-worker': python_run_mode_module('myapp.worker'),
+                },
+            )
+        "#
+        );
+
+        let v = starlark_ok(content);
+        v.downcast_apply(|x: &Config| {
+            assert_eq!(
+                x.config.multicall_run_modes.keys().collect::<Vec<_>>(),
+                vec!["myapp", "myapp-worker"]
+            );
+            assert_eq!(
+                x.config.multicall_run_modes["myapp-worker"],
+                RunMode::Module {
+                    module: "myapp.worker".to_string()
+                }
+            );
+        });
+
+        let err = starlark_nok(&content.replace("'myapp-worker'", "'myapp-worker.exe'"));
+        assert!(err.message.contains("should not include .exe"));
+
+        let err = starlark_nok(
+            &content.replace("python_run_mode_module('myapp.worker')", "'myapp.worker'"),
+        );
+        assert!(err.message.contains("multicall_run_modes"));
+    }
 }
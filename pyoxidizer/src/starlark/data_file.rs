@@ -0,0 +1,370 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/*!
+Reading structured data files.
+
+`read_json()` and `read_toml()` allow configuration files to be driven by
+data maintained outside the configuration file, such as a build matrix.
+Files read this way are recorded so changes to them invalidate built
+artifacts.
+
+A config may be evaluated several times in one invocation. Parsed
+documents are memoized in the evaluation cache by path and content hash,
+so large data files are only parsed once and edits between evaluations are
+never served stale.
+*/
+
+use sha2::Digest;
+use starlark::environment::Environment;
+use starlark::values::dict::Dictionary;
+use starlark::values::{RuntimeError, Value, ValueError, ValueResult};
+use starlark::{
+    starlark_fun, starlark_module, starlark_signature, starlark_signature_extraction,
+    starlark_signatures,
+};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use super::env::{required_str_arg, resolve_path_arg};
+use super::eval::EvaluationCache;
+use crate::app_packaging::environment::EnvironmentContext;
+
+/// Error code for data files that cannot be read or parsed.
+pub const DATA_FILE_ERROR_CODE: &str = "DATA_FILE";
+
+/// Maximum size in bytes of a data file.
+///
+/// Starlark values are relatively heavyweight. This guards against
+/// accidentally pointing the evaluator at something huge.
+pub const MAX_DATA_FILE_SIZE: u64 = 4 * 1024 * 1024;
+
+/// Key identifying the content of a data file.
+pub(crate) type DataFileKey = (PathBuf, String);
+
+/// Parse a data file, reusing a previous parse of identical content.
+///
+/// Parse errors aren't memoized.
+fn parse_cached<T: Clone>(
+    cache: &Mutex<HashMap<DataFileKey, T>>,
+    path: &Path,
+    data: &str,
+    parse: fn(&Path, &str) -> Result<T, String>,
+) -> Result<T, String> {
+    let key = (
+        path.to_path_buf(),
+        hex::encode(sha2::Sha256::digest(data.as_bytes())),
+    );
+
+    if let Some(value) = cache.lock().unwrap().get(&key) {
+        return Ok(value.clone());
+    }
+
+    let value = parse(path, data)?;
+    cache.lock().unwrap().insert(key, value.clone());
+
+    Ok(value)
+}
+
+/// The evaluation cache of an environment.
+fn eval_cache(env: &Environment) -> EvaluationCache {
+    env.get("CONTEXT")
+        .expect("CONTEXT not defined")
+        .downcast_apply(|x: &EnvironmentContext| x.eval_cache.clone())
+}
+
+fn data_file_error(rule: &str, message: String) -> ValueError {
+    RuntimeError {
+        code: DATA_FILE_ERROR_CODE,
+        message,
+        label: format!("rule {}", rule),
+    }
+    .into()
+}
+
+/// Read a data file, enforcing the size limit and recording it as a build input.
+fn read_data_file(
+    env: &Environment,
+    rule: &str,
+    path: &str,
+) -> Result<(PathBuf, String), ValueError> {
+    let path = resolve_path_arg(env, rule, path, false)?;
+
+    let metadata = std::fs::metadata(&path)
+        .map_err(|e| data_file_error(rule, format!("unable to read {}: {}", path.display(), e)))?;
+
+    if metadata.len() > MAX_DATA_FILE_SIZE {
+        return Err(data_file_error(
+            rule,
+            format!(
+                "{} is {} bytes; data files may be at most {} bytes",
+                path.display(),
+                metadata.len(),
+                MAX_DATA_FILE_SIZE
+            ),
+        ));
+    }
+
+    let data = std::fs::read_to_string(&path)
+        .map_err(|e| data_file_error(rule, format!("unable to read {}: {}", path.display(), e)))?;
+
+    env.get("CONTEXT")
+        .expect("CONTEXT not defined")
+        .downcast_apply_mut(|x: &mut EnvironmentContext| {
+            if !x.read_files.contains(&path) {
+                x.read_files.push(path.clone());
+            }
+        });
+
+    Ok((path, data))
+}
+
+fn dict_from_pairs(pairs: Vec<(String, Value)>) -> ValueResult {
+    let mut dict = Dictionary::new();
+
+    for (key, value) in pairs {
+        dict.set_at(Value::from(key), value)?;
+    }
+
+    Ok(dict)
+}
+
+/// Convert a parsed JSON document to Starlark values.
+///
+/// Starlark has no floating point type, so floats are converted to strings.
+fn json_to_value(value: serde_json::Value) -> ValueResult {
+    Ok(match value {
+        serde_json::Value::Null => Value::new(None),
+        serde_json::Value::Bool(b) => Value::from(b),
+        serde_json::Value::Number(n) => match n.as_i64() {
+            Some(i) => Value::from(i),
+            None => Value::from(n.to_string()),
+        },
+        serde_json::Value::String(s) => Value::from(s),
+        serde_json::Value::Array(values) => Value::from(
+            values
+                .into_iter()
+                .map(json_to_value)
+                .collect::<Result<Vec<Value>, ValueError>>()?,
+        ),
+        serde_json::Value::Object(map) => dict_from_pairs(
+            map.into_iter()
+                .map(|(k, v)| Ok((k, json_to_value(v)?)))
+                .collect::<Result<Vec<_>, ValueError>>()?,
+        )?,
+    })
+}
+
+/// Convert a parsed TOML document to Starlark values.
+///
+/// Floats and datetimes are converted to strings.
+fn toml_to_value(value: toml::Value) -> ValueResult {
+    Ok(match value {
+        toml::Value::Boolean(b) => Value::from(b),
+        toml::Value::Integer(i) => Value::from(i),
+        toml::Value::Float(f) => Value::from(f.to_string()),
+        toml::Value::String(s) => Value::from(s),
+        toml::Value::Datetime(d) => Value::from(d.to_string()),
+        toml::Value::Array(values) => Value::from(
+            values
+                .into_iter()
+                .map(toml_to_value)
+                .collect::<Result<Vec<Value>, ValueError>>()?,
+        ),
+        toml::Value::Table(table) => dict_from_pairs(
+            table
+                .into_iter()
+                .map(|(k, v)| Ok((k, toml_to_value(v)?)))
+                .collect::<Result<Vec<_>, ValueError>>()?,
+        )?,
+    })
+}
+
+/// Format a parse error as `<path>:<line>: <message>`.
+fn parse_error_message(path: &Path, line: Option<usize>, message: &str) -> String {
+    match line {
+        Some(line) => format!("{}:{}: {}", path.display(), line, message),
+        None => format!("{}: {}", path.display(), message),
+    }
+}
+
+fn parse_json(path: &Path, data: &str) -> Result<serde_json::Value, String> {
+    serde_json::from_str(data).map_err(|e| {
+        // serde_json appends the location to its message. We report it ourselves.
+        let message = e.to_string();
+        let message = match message.rfind(" at line ") {
+            Some(pos) => message[0..pos].to_string(),
+            None => message,
+        };
+
+        parse_error_message(path, Some(e.line()), &format!("invalid JSON: {}", message))
+    })
+}
+
+fn parse_toml(path: &Path, data: &str) -> Result<toml::Value, String> {
+    data.parse::<toml::Value>().map_err(|e| {
+        // toml reports zero-based line numbers.
+        let line = e.line_col().map(|(line, _)| line + 1);
+
+        // The Display implementation includes the location. Avoid repeating it.
+        let message = e.to_string();
+        let message = match message.rfind(" at line ") {
+            Some(pos) => message[0..pos].to_string(),
+            None => message,
+        };
+
+        parse_error_message(path, line, &format!("invalid TOML: {}", message))
+    })
+}
+
+starlark_module! { data_file_module =>
+    #[allow(clippy::ptr_arg)]
+    read_json(env env, path) {
+        let path = required_str_arg("path", &path)?;
+
+        let (path, data) = read_data_file(&env, "read_json()", &path)?;
+        let value = parse_cached(&eval_cache(&env).json, &path, &data, parse_json).map_err(|e| data_file_error("read_json()", e))?;
+
+        json_to_value(value)
+    }
+
+    #[allow(clippy::ptr_arg)]
+    read_toml(env env, path) {
+        let path = required_str_arg("path", &path)?;
+
+        let (path, data) = read_data_file(&env, "read_toml()", &path)?;
+        let value = parse_cached(&eval_cache(&env).toml, &path, &data, parse_toml).map_err(|e| data_file_error("read_toml()", e))?;
+
+        toml_to_value(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::testutil::*;
+    use super::*;
+
+    fn write_temp(name: &str, content: &str) -> (tempdir::TempDir, String) {
+        let temp_dir = tempdir::TempDir::new("pyoxidizer-test").unwrap();
+        let path = temp_dir.path().join(name);
+        std::fs::write(&path, content).unwrap();
+
+        let path = path.display().to_string().replace("\\", "/");
+
+        (temp_dir, path)
+    }
+
+    #[test]
+    fn test_read_json() {
+        let (_temp_dir, path) = write_temp(
+            "matrix.json",
+            r#"{"targets": ["linux", "macos"], "jobs": 4, "debug": false, "extra": null, "ratio": 1.5}"#,
+        );
+
+        let mut env = starlark_env();
+        let v = starlark_eval_in_env(&mut env, &format!("read_json('{}')", path)).unwrap();
+        assert_eq!(v.get_type(), "dict");
+        assert_eq!(
+            v.at(Value::from("targets")).unwrap().to_repr(),
+            "[\"linux\", \"macos\"]"
+        );
+        assert_eq!(v.at(Value::from("jobs")).unwrap().to_int().unwrap(), 4);
+        assert!(!v.at(Value::from("debug")).unwrap().to_bool());
+        assert_eq!(v.at(Value::from("extra")).unwrap().get_type(), "NoneType");
+        assert_eq!(v.at(Value::from("ratio")).unwrap().to_str(), "1.5");
+
+        let read_files = env
+            .get("CONTEXT")
+            .unwrap()
+            .downcast_apply(|x: &EnvironmentContext| x.read_files.clone());
+        assert_eq!(read_files.len(), 1);
+        assert!(read_files[0].ends_with("matrix.json"));
+    }
+
+    #[test]
+    fn test_read_toml() {
+        let (_temp_dir, path) = write_temp(
+            "matrix.toml",
+            "python = 3.7\n\n[[target]]\nname = \"linux\"\njobs = 4\n",
+        );
+
+        let v = starlark_ok(&format!("read_toml('{}')", path));
+        assert_eq!(v.get_type(), "dict");
+        assert_eq!(v.at(Value::from("python")).unwrap().to_str(), "3.7");
+
+        let targets = v.at(Value::from("target")).unwrap();
+        assert_eq!(targets.get_type(), "list");
+        let target = targets.at(Value::from(0)).unwrap();
+        assert_eq!(target.at(Value::from("name")).unwrap().to_str(), "linux");
+        assert_eq!(target.at(Value::from("jobs")).unwrap().to_int().unwrap(), 4);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let (_temp_dir, path) = write_temp("bad.json", "{\n  \"a\": 1,\n  \"b\": \n}\n");
+        let err = starlark_nok(&format!("read_json('{}')", path));
+        assert!(err.message.contains("bad.json:4: invalid JSON"));
+
+        let (_temp_dir, path) = write_temp("bad.toml", "a = 1\nb = \n");
+        let err = starlark_nok(&format!("read_toml('{}')", path));
+        assert!(err.message.contains("bad.toml:2: invalid TOML"));
+    }
+
+    #[test]
+    fn test_parse_cached() {
+        let (temp_dir, path) = write_temp("matrix.toml", "jobs = 4\n");
+        let code = format!("read_toml('{}')['jobs']", path);
+        let file_path = temp_dir.path().join("matrix.toml");
+
+        let cache = EvaluationCache::default();
+        let eval = |cache: &EvaluationCache| {
+            starlark_eval_in_env(&mut starlark_env_with_cache(cache), &code)
+                .unwrap()
+                .to_int()
+                .unwrap()
+        };
+        let cached = || cache.toml.lock().unwrap().len();
+
+        assert_eq!(eval(&cache), 4);
+        assert_eq!(cached(), 1);
+
+        // Evaluating again reuses the parsed document.
+        assert_eq!(eval(&cache), 4);
+        assert_eq!(cached(), 1);
+
+        // Changed content is parsed again.
+        std::fs::write(&file_path, "jobs = 8\n").unwrap();
+        assert_eq!(eval(&cache), 8);
+        assert_eq!(cached(), 2);
+
+        // Evaluations with another cache don't share documents.
+        let other = EvaluationCache::default();
+        assert_eq!(eval(&other), 8);
+        assert_eq!(other.toml.lock().unwrap().len(), 1);
+        assert!(cache.json.lock().unwrap().is_empty());
+
+        // The file is still recorded as a build input on a cache hit.
+        let mut env = starlark_env_with_cache(&cache);
+        starlark_eval_in_env(&mut env, &code).unwrap();
+        let read_files = env
+            .get("CONTEXT")
+            .unwrap()
+            .downcast_apply(|x: &EnvironmentContext| x.read_files.clone());
+        assert_eq!(read_files.len(), 1);
+    }
+
+    #[test]
+    fn test_size_limit() {
+        let (_temp_dir, path) =
+            write_temp("huge.json", &" ".repeat(MAX_DATA_FILE_SIZE as usize + 1));
+        let err = starlark_nok(&format!("read_json('{}')", path));
+        assert!(err.message.contains("may be at most"));
+    }
+
+    #[test]
+    fn test_missing() {
+        starlark_nok("read_json('/does/not/exist.json')");
+    }
+}
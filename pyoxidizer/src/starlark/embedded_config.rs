@@ -0,0 +1,370 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/*!
+Embedding application configuration files.
+
+`embedded_config_file()` reads a file, e.g. default settings templated per
+customer, as resource data of a reserved package. `{NAME}` in its path is
+replaced with build variables, so the file can be selected with `--var`.
+`oxidized_app.config_file()` reads it at run time by name.
+
+A validation command can be given, which must succeed for evaluating the
+config to succeed, so an invalid file is never packaged. Names and SHA-256
+of embedded files are recorded in the build manifest.
+*/
+
+use anyhow::{anyhow, Result};
+use sha2::Digest;
+use starlark::environment::Environment;
+use starlark::values::{RuntimeError, Value, ValueError, ValueResult};
+use starlark::{
+    starlark_fun, starlark_module, starlark_signature, starlark_signature_extraction,
+    starlark_signatures,
+};
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+use super::env::{
+    optional_list_arg, record_input_path, required_str_arg, resolve_resource_path_arg,
+};
+use super::python_resource::PythonResourceData;
+use crate::app_packaging::build_vars::substitute_vars;
+use crate::app_packaging::environment::EnvironmentContext;
+use crate::py_packaging::resource::ResourceData;
+
+/// Error code for config files that cannot be embedded.
+pub const EMBEDDED_CONFIG_ERROR_CODE: &str = "EMBEDDED_CONFIG";
+
+/// Package whose resources are embedded config files.
+///
+/// This needs to be kept in sync with `oxidized_app.py`.
+pub const EMBEDDED_CONFIG_PACKAGE: &str = "_oxidized_config";
+
+const RULE: &str = "embedded_config_file()";
+
+fn embedded_config_error(message: String) -> ValueError {
+    RuntimeError {
+        code: EMBEDDED_CONFIG_ERROR_CODE,
+        message,
+        label: RULE.to_string(),
+    }
+    .into()
+}
+
+/// Whether a string is a valid name of an embedded config file.
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && !name.starts_with('.')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '.')
+}
+
+/// Run a command validating a config file.
+///
+/// `{path}` in arguments is replaced with the path of the file and `{NAME}`
+/// with build variables.
+pub fn validate_config_file(
+    args: &[String],
+    path: &Path,
+    vars: &BTreeMap<String, String>,
+    cwd: &Path,
+) -> Result<()> {
+    let mut vars = vars.clone();
+    vars.insert("path".to_string(), path.display().to_string());
+
+    let args = args
+        .iter()
+        .map(|arg| substitute_vars(arg, &vars))
+        .collect::<Result<Vec<_>>>()?;
+
+    if args.is_empty() {
+        return Err(anyhow!("validate_with must not be empty"));
+    }
+
+    let output = Command::new(&args[0])
+        .args(&args[1..])
+        .current_dir(cwd)
+        .stdin(Stdio::null())
+        .output()
+        .map_err(|e| anyhow!("unable to run {}: {}", args[0], e))?;
+
+    if !output.status.success() {
+        let mut message = String::from_utf8_lossy(&output.stderr).trim().to_string();
+        if message.is_empty() {
+            message = String::from_utf8_lossy(&output.stdout).trim().to_string();
+        }
+
+        return Err(anyhow!(
+            "validating {} with {} failed with {}: {}",
+            path.display(),
+            args.join(" "),
+            output.status,
+            message
+        ));
+    }
+
+    Ok(())
+}
+
+starlark_module! { embedded_config_module =>
+    #[allow(clippy::ptr_arg)]
+    embedded_config_file(env env, src, name, validate_with=None) {
+        let src = required_str_arg("src", &src)?;
+        let name = required_str_arg("name", &name)?;
+        optional_list_arg("validate_with", "string", &validate_with)?;
+
+        if !is_valid_name(&name) {
+            return Err(embedded_config_error(format!(
+                "{} is not a valid name; use letters, digits, _, - and .",
+                name
+            )));
+        }
+
+        let mut context = env.get("CONTEXT").expect("CONTEXT not defined");
+        let (vars, cwd) = context.downcast_apply(|x: &EnvironmentContext| (x.build_vars.clone(), x.cwd.clone()));
+
+        let src = substitute_vars(&src, &vars).map_err(|e| embedded_config_error(e.to_string()))?;
+        let path = resolve_resource_path_arg(&env, RULE, &src, false)?;
+
+        let data = std::fs::read(&path)
+            .map_err(|e| embedded_config_error(format!("unable to read {}: {}", path.display(), e)))?;
+
+        if validate_with.get_type() == "list" {
+            let args = validate_with.into_iter()?.map(|x| x.to_string()).collect::<Vec<_>>();
+
+            // Scripts and programs given by path are inputs of the build.
+            for arg in &args {
+                let path = context.downcast_apply(|x: &EnvironmentContext| x.resolve_path(arg));
+                if path.is_file() {
+                    record_input_path(&env, &path);
+                }
+            }
+
+            validate_config_file(&args, &path, &vars, &cwd)
+                .map_err(|e| embedded_config_error(e.to_string()))?;
+        }
+
+        let sha256 = hex::encode(sha2::Sha256::digest(&data));
+
+        let existing = context.downcast_apply(|x: &EnvironmentContext| x.embedded_config_files.get(&name).cloned());
+        match existing {
+            Some(ref digest) if digest != &sha256 => {
+                return Err(embedded_config_error(format!(
+                    "a different config file named {} is already embedded",
+                    name
+                )));
+            }
+            Some(_) => {}
+            None => {
+                context.downcast_apply_mut(|x: &mut EnvironmentContext| {
+                    x.embedded_config_files.insert(name.clone(), sha256.clone());
+                });
+            }
+        }
+
+        Ok(Value::new(PythonResourceData {
+            data: ResourceData {
+                package: EMBEDDED_CONFIG_PACKAGE.to_string(),
+                name,
+                data,
+            },
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::testutil::*;
+    use super::*;
+    use crate::testutil::*;
+
+    // Fails unless the file sets a license key.
+    const VALIDATE: &str = "import sys\n\
+                            if 'license_key:' not in open(sys.argv[1]).read():\n\
+                            \x20   sys.exit('%s: license_key is missing' % sys.argv[1])\n";
+
+    fn write_project(dir: &Path) -> Result<()> {
+        std::fs::create_dir_all(dir.join("configs"))?;
+        std::fs::create_dir_all(dir.join("tools"))?;
+
+        std::fs::write(
+            dir.join("configs").join("acme.yaml"),
+            "license_key: acme-1\nlanguage: en\n",
+        )?;
+        std::fs::write(
+            dir.join("configs").join("globex.yaml"),
+            "license_key: globex-2\nlanguage: de\n",
+        )?;
+        std::fs::write(dir.join("configs").join("broken.yaml"), "language: fr\n")?;
+        std::fs::write(dir.join("tools").join("validate_settings.py"), VALIDATE)?;
+
+        Ok(())
+    }
+
+    fn embed(dir: &Path, customer: &str) -> Result<Value, codemap_diagnostic::Diagnostic> {
+        let dist = get_default_distribution().unwrap();
+        let mut env = starlark_env_with_vars(&[("customer", customer)]);
+
+        starlark_eval_in_env(
+            &mut env,
+            &format!(
+                "embedded_config_file(src='{}/configs/{{customer}}.yaml', name='default_settings', validate_with=['{}', '{}/tools/validate_settings.py', '{{path}}'])",
+                dir.display().to_string().replace("\\", "/"),
+                dist.python_exe.display().to_string().replace("\\", "/"),
+                dir.display().to_string().replace("\\", "/"),
+            ),
+        )
+    }
+
+    #[test]
+    fn test_embedded_config_file() -> Result<()> {
+        let temp_dir = tempdir::TempDir::new("pyoxidizer-test")?;
+        write_project(temp_dir.path())?;
+
+        for (customer, content) in &[
+            ("acme", "license_key: acme-1\nlanguage: en\n"),
+            ("globex", "license_key: globex-2\nlanguage: de\n"),
+        ] {
+            let v = embed(temp_dir.path(), customer).unwrap();
+            assert_eq!(
+                v.to_str(),
+                "PythonResourceData<package=_oxidized_config, name=default_settings>"
+            );
+
+            let data = v.downcast_apply(|r: &PythonResourceData| r.data.data.clone());
+            assert_eq!(data, content.as_bytes());
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_embedded_config_file_recorded() -> Result<()> {
+        let temp_dir = tempdir::TempDir::new("pyoxidizer-test")?;
+        write_project(temp_dir.path())?;
+        let src = temp_dir.path().join("configs").join("acme.yaml");
+
+        let mut env = starlark_env_with_vars(&[("customer", "acme")]);
+        starlark_eval_in_env(
+            &mut env,
+            &format!(
+                "embedded_config_file(src='{}', name='default_settings')",
+                src.display().to_string().replace("\\", "/")
+            ),
+        )
+        .unwrap();
+
+        let (files, input_paths) =
+            env.get("CONTEXT")
+                .unwrap()
+                .downcast_apply(|x: &EnvironmentContext| {
+                    (x.embedded_config_files.clone(), x.input_paths.clone())
+                });
+        assert_eq!(
+            files.get("default_settings"),
+            Some(&hex::encode(sha2::Sha256::digest(&std::fs::read(&src)?)))
+        );
+        assert!(input_paths.contains(&src));
+
+        // The same name can't be used for different content.
+        let err = starlark_eval_in_env(
+            &mut env,
+            &format!(
+                "embedded_config_file(src='{}', name='default_settings')",
+                temp_dir
+                    .path()
+                    .join("configs")
+                    .join("globex.yaml")
+                    .display()
+                    .to_string()
+                    .replace("\\", "/")
+            ),
+        )
+        .unwrap_err();
+        assert!(err.message.contains("already embedded"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_embedded_config_file_errors() -> Result<()> {
+        let temp_dir = tempdir::TempDir::new("pyoxidizer-test")?;
+        write_project(temp_dir.path())?;
+
+        let err = embed(temp_dir.path(), "broken").unwrap_err();
+        assert!(err.message.starts_with("validating "));
+        assert!(err.message.contains("broken.yaml: license_key is missing"));
+
+        let err = embed(temp_dir.path(), "initech").unwrap_err();
+        assert!(err.message.contains("does not exist"));
+
+        let err = starlark_nok("embedded_config_file(src='configs/{customer}.yaml', name='x')");
+        assert!(err
+            .message
+            .contains("build variable customer is not defined; pass --var customer=VALUE"));
+
+        let err = starlark_nok("embedded_config_file(src='Cargo.toml', name='../settings')");
+        assert!(err.message.contains("is not a valid name"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_config_file_shim() -> Result<()> {
+        let temp_dir = tempdir::TempDir::new("pyoxidizer-test")?;
+        let dir = temp_dir.path();
+        std::fs::write(dir.join("default_settings"), "license_key: acme-1\n")?;
+        std::fs::write(
+            dir.join("oxidized_app.py"),
+            include_str!("../pyembed/oxidized_app.py"),
+        )?;
+
+        // Emulate the resource reader of the in-memory importer.
+        std::fs::write(
+            dir.join("test.py"),
+            "import os, sys, types\n\
+             m = types.ModuleType('oxidized_app')\n\
+             exec(open(os.path.join(sys.argv[1], 'oxidized_app.py')).read(), m.__dict__)\n\
+             class Reader:\n\
+             \x20   def open_resource(self, name):\n\
+             \x20       return open(os.path.join(sys.argv[1], name), 'rb')\n\
+             class Finder:\n\
+             \x20   def find_spec(self, *args):\n\
+             \x20       return None\n\
+             \x20   def get_resource_reader(self, package):\n\
+             \x20       return Reader() if package == '_oxidized_config' else None\n\
+             sys.meta_path.insert(0, Finder())\n\
+             print(m.config_file('default_settings').decode().strip())\n\
+             try:\n\
+             \x20   m.config_file('missing')\n\
+             except FileNotFoundError as e:\n\
+             \x20   print(e)\n",
+        )?;
+
+        let dist = get_default_distribution()?;
+        let output = std::process::Command::new(&dist.python_exe)
+            .arg(dir.join("test.py"))
+            .arg(dir)
+            .output()?;
+        assert!(
+            output.status.success(),
+            "{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        assert_eq!(
+            String::from_utf8_lossy(&output.stdout)
+                .lines()
+                .collect::<Vec<_>>(),
+            vec![
+                "license_key: acme-1",
+                "no embedded config file named missing"
+            ]
+        );
+
+        Ok(())
+    }
+}
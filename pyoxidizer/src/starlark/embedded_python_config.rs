@@ -13,11 +13,16 @@ use starlark::{
 };
 use std::any::Any;
 use std::cmp::Ordering;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 
-use super::env::{optional_list_arg, optional_str_arg, required_bool_arg, required_type_arg};
+use super::env::{
+    optional_list_arg, optional_str_arg, required_bool_arg, required_str_arg, required_type_arg,
+};
+use super::select::resolve_select;
 use crate::app_packaging::config::default_raw_allocator;
-use crate::py_packaging::config::{RawAllocator, TerminfoResolution};
+use crate::py_packaging::config::{
+    DetachedStdio, ImporterPrecedence, RawAllocator, SysExecutable, TerminfoResolution,
+};
 
 #[derive(Debug, Clone)]
 pub struct EmbeddedPythonConfig {
@@ -72,18 +77,83 @@ starlark_module! { embedded_python_config_module =>
         parser_debug=false,
         stdio_encoding=None,
         unbuffered_stdio=false,
+        detached_stdio="null",
         filesystem_importer=false,
+        importer_precedence="memory-first",
         quiet=false,
         sys_frozen=false,
         sys_meipass=false,
         sys_paths=None,
+        sys_executable="exe",
+        sys_prefix="$ORIGIN",
+        run_python_marker=false,
+        allow_runtime_code_override=false,
         raw_allocator=None,
         terminfo_resolution="dynamic",
         terminfo_dirs=None,
         use_hash_seed=false,
         verbose=0,
-        write_modules_directory_env=None
+        write_modules_directory_env=None,
+        import_profile_env=None,
+        repl_history_file=None,
+        startup_module=None,
+        startup_code=None,
+        run_atexit=true,
+        wait_for_non_daemon_threads=true,
+        install_signal_handlers=true,
+        pkg_resources_compat=false
     ) {
+        let build_target = env.get("BUILD_TARGET").unwrap().to_str();
+
+        // Any argument may be a select() choosing a value by target triple.
+        let mut target_selections = BTreeMap::new();
+        let mut resolve = |field: &str, value: Value| -> Result<Value, ValueError> {
+            let (value, arm) = resolve_select(field, &value, &build_target)?;
+            if let Some(arm) = arm {
+                target_selections.insert(field.to_string(), arm);
+            }
+            Ok(value)
+        };
+        let bytes_warning = resolve("bytes_warning", bytes_warning)?;
+        let dont_write_bytecode = resolve("dont_write_bytecode", dont_write_bytecode)?;
+        let ignore_environment = resolve("ignore_environment", ignore_environment)?;
+        let inspect = resolve("inspect", inspect)?;
+        let interactive = resolve("interactive", interactive)?;
+        let isolated = resolve("isolated", isolated)?;
+        let legacy_windows_fs_encoding = resolve("legacy_windows_fs_encoding", legacy_windows_fs_encoding)?;
+        let legacy_windows_stdio = resolve("legacy_windows_stdio", legacy_windows_stdio)?;
+        let no_site = resolve("no_site", no_site)?;
+        let no_user_site_directory = resolve("no_user_site_directory", no_user_site_directory)?;
+        let optimize_level = resolve("optimize_level", optimize_level)?;
+        let parser_debug = resolve("parser_debug", parser_debug)?;
+        let stdio_encoding = resolve("stdio_encoding", stdio_encoding)?;
+        let unbuffered_stdio = resolve("unbuffered_stdio", unbuffered_stdio)?;
+        let detached_stdio = resolve("detached_stdio", detached_stdio)?;
+        let filesystem_importer = resolve("filesystem_importer", filesystem_importer)?;
+        let importer_precedence = resolve("importer_precedence", importer_precedence)?;
+        let quiet = resolve("quiet", quiet)?;
+        let sys_frozen = resolve("sys_frozen", sys_frozen)?;
+        let sys_meipass = resolve("sys_meipass", sys_meipass)?;
+        let sys_paths = resolve("sys_paths", sys_paths)?;
+        let sys_executable = resolve("sys_executable", sys_executable)?;
+        let sys_prefix = resolve("sys_prefix", sys_prefix)?;
+        let run_python_marker = resolve("run_python_marker", run_python_marker)?;
+        let allow_runtime_code_override = resolve("allow_runtime_code_override", allow_runtime_code_override)?;
+        let raw_allocator = resolve("raw_allocator", raw_allocator)?;
+        let terminfo_resolution = resolve("terminfo_resolution", terminfo_resolution)?;
+        let terminfo_dirs = resolve("terminfo_dirs", terminfo_dirs)?;
+        let use_hash_seed = resolve("use_hash_seed", use_hash_seed)?;
+        let verbose = resolve("verbose", verbose)?;
+        let write_modules_directory_env = resolve("write_modules_directory_env", write_modules_directory_env)?;
+        let import_profile_env = resolve("import_profile_env", import_profile_env)?;
+        let repl_history_file = resolve("repl_history_file", repl_history_file)?;
+        let startup_module = resolve("startup_module", startup_module)?;
+        let startup_code = resolve("startup_code", startup_code)?;
+        let run_atexit = resolve("run_atexit", run_atexit)?;
+        let wait_for_non_daemon_threads = resolve("wait_for_non_daemon_threads", wait_for_non_daemon_threads)?;
+        let install_signal_handlers = resolve("install_signal_handlers", install_signal_handlers)?;
+        let pkg_resources_compat = resolve("pkg_resources_compat", pkg_resources_compat)?;
+
         required_type_arg("bytes_warning", "int", &bytes_warning)?;
         let dont_write_bytecode = required_bool_arg("dont_write_bytecode", &dont_write_bytecode)?;
         let ignore_environment = required_bool_arg("ignore_environment", &ignore_environment)?;
@@ -98,19 +168,39 @@ starlark_module! { embedded_python_config_module =>
         let parser_debug = required_bool_arg("parser_debug", &parser_debug)?;
         let stdio_encoding = optional_str_arg("stdio_encoding", &stdio_encoding)?;
         let unbuffered_stdio = required_bool_arg("unbuffered_stdio", &unbuffered_stdio)?;
+        let detached_stdio = required_str_arg("detached_stdio", &detached_stdio)?;
         let filesystem_importer = required_bool_arg("filesystem_importer", &filesystem_importer)?;
+        let importer_precedence = required_str_arg("importer_precedence", &importer_precedence)?;
         let quiet = required_bool_arg("quiet", &quiet)?;
         let sys_frozen = required_bool_arg("sys_frozen", &sys_frozen)?;
         let sys_meipass = required_bool_arg("sys_meipass", &sys_meipass)?;
         optional_list_arg("sys_paths", "string", &sys_paths)?;
+        let sys_executable = required_str_arg("sys_executable", &sys_executable)?;
+        let sys_prefix = required_str_arg("sys_prefix", &sys_prefix)?;
+        let run_python_marker = required_bool_arg("run_python_marker", &run_python_marker)?;
+        let allow_runtime_code_override = required_bool_arg("allow_runtime_code_override", &allow_runtime_code_override)?;
         let raw_allocator = optional_str_arg("raw_allocator", &raw_allocator)?;
         let terminfo_resolution = optional_str_arg("terminfo_resolution", &terminfo_resolution)?;
         let terminfo_dirs = optional_str_arg("terminfo_dirs", &terminfo_dirs)?;
         let use_hash_seed = required_bool_arg("use_hash_seed", &use_hash_seed)?;
         required_type_arg("verbose", "int", &verbose)?;
         let write_modules_directory_env = optional_str_arg("write_modules_directory_env", &write_modules_directory_env)?;
+        let import_profile_env = optional_str_arg("import_profile_env", &import_profile_env)?;
+        let repl_history_file = optional_str_arg("repl_history_file", &repl_history_file)?;
+        let startup_module = optional_str_arg("startup_module", &startup_module)?;
+        let startup_code = optional_str_arg("startup_code", &startup_code)?;
+        let run_atexit = required_bool_arg("run_atexit", &run_atexit)?;
+        let wait_for_non_daemon_threads = required_bool_arg("wait_for_non_daemon_threads", &wait_for_non_daemon_threads)?;
+        let install_signal_handlers = required_bool_arg("install_signal_handlers", &install_signal_handlers)?;
+        let pkg_resources_compat = required_bool_arg("pkg_resources_compat", &pkg_resources_compat)?;
 
-        let build_target = env.get("BUILD_TARGET").unwrap().to_str();
+        if startup_module.is_some() && startup_code.is_some() {
+            return Err(RuntimeError {
+                code: INCORRECT_PARAMETER_TYPE_ERROR_CODE,
+                message: "startup_module and startup_code are mutually exclusive".to_string(),
+                label: "only one of startup_module and startup_code may be set".to_string(),
+            }.into());
+        }
 
         let (stdio_encoding_name, stdio_encoding_errors) = if let Some(ref v) = stdio_encoding {
             let values: Vec<&str> = v.split(':').collect();
@@ -122,6 +212,7 @@ starlark_module! { embedded_python_config_module =>
         let raw_allocator = match raw_allocator {
             Some(x) => match x.as_ref() {
                 "jemalloc" => RawAllocator::Jemalloc,
+                "mimalloc" => RawAllocator::Mimalloc,
                 "rust" => RawAllocator::Rust,
                 "system" => RawAllocator::System,
                 _ => {
@@ -135,6 +226,45 @@ starlark_module! { embedded_python_config_module =>
             None => default_raw_allocator(&build_target),
         };
 
+        let sys_executable = match sys_executable.as_ref() {
+            "exe" => SysExecutable::Exe,
+            "empty" => SysExecutable::Empty,
+            _ => {
+                return Err(RuntimeError {
+                    code: INCORRECT_PARAMETER_TYPE_ERROR_CODE,
+                    message: "sys_executable must be 'exe' or 'empty'".to_string(),
+                    label: "sys_executable must be 'exe' or 'empty'".to_string(),
+                }.into());
+            }
+        };
+
+        let detached_stdio = match detached_stdio.as_ref() {
+            "null" => DetachedStdio::Null,
+            "buffer" => DetachedStdio::Buffer,
+            value if value.starts_with("logfile:") && value.len() > "logfile:".len() => {
+                DetachedStdio::LogFile(value["logfile:".len()..].to_string())
+            }
+            _ => {
+                return Err(RuntimeError {
+                    code: INCORRECT_PARAMETER_TYPE_ERROR_CODE,
+                    message: "detached_stdio must be 'null', 'buffer' or 'logfile:<path>'".to_string(),
+                    label: "detached_stdio must be 'null', 'buffer' or 'logfile:<path>'".to_string(),
+                }.into());
+            }
+        };
+
+        let importer_precedence = match importer_precedence.as_ref() {
+            "memory-first" => ImporterPrecedence::MemoryFirst,
+            "filesystem-first" => ImporterPrecedence::FilesystemFirst,
+            _ => {
+                return Err(RuntimeError {
+                    code: INCORRECT_PARAMETER_TYPE_ERROR_CODE,
+                    message: "importer_precedence must be 'memory-first' or 'filesystem-first'".to_string(),
+                    label: "importer_precedence must be 'memory-first' or 'filesystem-first'".to_string(),
+                }.into());
+            }
+        };
+
         let terminfo_resolution = match terminfo_resolution {
             Some(x) => match x.as_ref() {
                 "dynamic" => TerminfoResolution::Dynamic,
@@ -182,15 +312,30 @@ starlark_module! { embedded_python_config_module =>
             stdio_encoding_name,
             stdio_encoding_errors,
             unbuffered_stdio,
+            detached_stdio,
             filesystem_importer,
+            importer_precedence,
             sys_frozen,
             sys_meipass,
             sys_paths,
+            sys_executable,
+            sys_prefix,
+            run_python_marker,
+            allow_runtime_code_override,
             raw_allocator,
             terminfo_resolution,
             use_hash_seed,
             verbose: verbose.to_int().unwrap() as i32,
             write_modules_directory_env,
+            import_profile_env,
+            repl_history_file,
+            startup_module,
+            startup_code,
+            run_atexit,
+            wait_for_non_daemon_threads,
+            install_signal_handlers,
+            pkg_resources_compat,
+            target_selections,
         };
 
         Ok(Value::new(EmbeddedPythonConfig { config }))
@@ -201,6 +346,7 @@ starlark_module! { embedded_python_config_module =>
 mod tests {
     use super::super::testutil::*;
     use super::*;
+    use indoc::indoc;
 
     #[test]
     fn test_default() {
@@ -226,13 +372,28 @@ mod tests {
             stdio_encoding_name: None,
             stdio_encoding_errors: None,
             unbuffered_stdio: false,
+            detached_stdio: DetachedStdio::Null,
             filesystem_importer: false,
+            importer_precedence: ImporterPrecedence::MemoryFirst,
             sys_frozen: false,
             sys_meipass: false,
             sys_paths: Vec::new(),
+            sys_executable: SysExecutable::Exe,
+            sys_prefix: "$ORIGIN".to_string(),
+            run_python_marker: false,
+            allow_runtime_code_override: false,
             raw_allocator: default_raw_allocator(crate::app_packaging::repackage::HOST),
             terminfo_resolution: TerminfoResolution::Dynamic,
             write_modules_directory_env: None,
+            import_profile_env: None,
+            repl_history_file: None,
+            startup_module: None,
+            startup_code: None,
+            run_atexit: true,
+            wait_for_non_daemon_threads: true,
+            install_signal_handlers: true,
+            pkg_resources_compat: false,
+            target_selections: BTreeMap::new(),
         };
 
         c.downcast_apply(|x: &EmbeddedPythonConfig| assert_eq!(x.config, wanted));
@@ -277,12 +438,192 @@ mod tests {
         c.downcast_apply(|x: &EmbeddedPythonConfig| {
             assert_eq!(x.config.raw_allocator, RawAllocator::Jemalloc);
         });
+        let c = starlark_ok("EmbeddedPythonConfig(raw_allocator='mimalloc')");
+        c.downcast_apply(|x: &EmbeddedPythonConfig| {
+            assert_eq!(x.config.raw_allocator, RawAllocator::Mimalloc);
+        });
         let c = starlark_ok("EmbeddedPythonConfig(raw_allocator='rust')");
         c.downcast_apply(|x: &EmbeddedPythonConfig| {
             assert_eq!(x.config.raw_allocator, RawAllocator::Rust);
         });
     }
 
+    #[test]
+    fn test_repl_history_file() {
+        let c = starlark_ok("EmbeddedPythonConfig(repl_history_file='~/.myapp_history')");
+        c.downcast_apply(|x: &EmbeddedPythonConfig| {
+            assert_eq!(
+                x.config.repl_history_file,
+                Some("~/.myapp_history".to_string())
+            );
+        });
+    }
+
+    #[test]
+    fn test_finalization() {
+        let c = starlark_ok(
+            "EmbeddedPythonConfig(run_atexit=False, wait_for_non_daemon_threads=False)",
+        );
+        c.downcast_apply(|x: &EmbeddedPythonConfig| {
+            assert!(!x.config.run_atexit);
+            assert!(!x.config.wait_for_non_daemon_threads);
+        });
+
+        let err = starlark_nok("EmbeddedPythonConfig(run_atexit='no')");
+        assert!(err.message.contains("run_atexit"));
+    }
+
+    #[test]
+    fn test_install_signal_handlers() {
+        let c = starlark_ok("EmbeddedPythonConfig(install_signal_handlers=False)");
+        c.downcast_apply(|x: &EmbeddedPythonConfig| assert!(!x.config.install_signal_handlers));
+    }
+
+    #[test]
+    fn test_pkg_resources_compat() {
+        let c = starlark_ok("EmbeddedPythonConfig(pkg_resources_compat=True)");
+        c.downcast_apply(|x: &EmbeddedPythonConfig| assert!(x.config.pkg_resources_compat));
+    }
+
+    #[test]
+    fn test_import_profile_env() {
+        let c = starlark_ok("EmbeddedPythonConfig(import_profile_env='PYOXIDIZER_IMPORT_PROFILE')");
+        c.downcast_apply(|x: &EmbeddedPythonConfig| {
+            assert_eq!(
+                x.config.import_profile_env,
+                Some("PYOXIDIZER_IMPORT_PROFILE".to_string())
+            );
+        });
+    }
+
+    #[test]
+    fn test_detached_stdio() {
+        let c = starlark_ok("EmbeddedPythonConfig(detached_stdio='buffer')");
+        c.downcast_apply(|x: &EmbeddedPythonConfig| {
+            assert_eq!(x.config.detached_stdio, DetachedStdio::Buffer);
+        });
+
+        let c = starlark_ok("EmbeddedPythonConfig(detached_stdio='logfile:$ORIGIN/{pid}.log')");
+        c.downcast_apply(|x: &EmbeddedPythonConfig| {
+            assert_eq!(
+                x.config.detached_stdio,
+                DetachedStdio::LogFile("$ORIGIN/{pid}.log".to_string())
+            );
+        });
+
+        let err = starlark_nok("EmbeddedPythonConfig(detached_stdio='logfile:')");
+        assert!(err.message.contains("detached_stdio must be"));
+        let err = starlark_nok("EmbeddedPythonConfig(detached_stdio='console')");
+        assert!(err.message.contains("detached_stdio must be"));
+    }
+
+    #[test]
+    fn test_sys_executable() {
+        let c = starlark_ok("EmbeddedPythonConfig(sys_executable='empty')");
+        c.downcast_apply(|x: &EmbeddedPythonConfig| {
+            assert_eq!(x.config.sys_executable, SysExecutable::Empty);
+        });
+
+        let err = starlark_nok("EmbeddedPythonConfig(sys_executable='python')");
+        assert!(err.message.contains("sys_executable must be"));
+    }
+
+    #[test]
+    fn test_importer_precedence() {
+        let c = starlark_ok(
+            "EmbeddedPythonConfig(filesystem_importer=True, importer_precedence='filesystem-first')",
+        );
+        c.downcast_apply(|x: &EmbeddedPythonConfig| {
+            assert_eq!(
+                x.config.importer_precedence,
+                ImporterPrecedence::FilesystemFirst
+            );
+        });
+
+        let err = starlark_nok("EmbeddedPythonConfig(importer_precedence='disk')");
+        assert!(err.message.contains("importer_precedence must be"));
+    }
+
+    #[test]
+    fn test_startup_hook() {
+        let c = starlark_ok("EmbeddedPythonConfig(startup_module='myapp._startup')");
+        c.downcast_apply(|x: &EmbeddedPythonConfig| {
+            assert_eq!(x.config.startup_module, Some("myapp._startup".to_string()));
+            assert_eq!(x.config.startup_code, None);
+        });
+
+        let c = starlark_ok("EmbeddedPythonConfig(startup_code='import logging')");
+        c.downcast_apply(|x: &EmbeddedPythonConfig| {
+            assert_eq!(x.config.startup_code, Some("import logging".to_string()));
+        });
+
+        let err = starlark_nok("EmbeddedPythonConfig(startup_module='foo', startup_code='pass')");
+        assert!(err.message.contains("mutually exclusive"));
+    }
+
+    #[test]
+    fn test_sys_prefix() {
+        let c =
+            starlark_ok("EmbeddedPythonConfig(sys_prefix='$ORIGIN/lib', run_python_marker=True)");
+        c.downcast_apply(|x: &EmbeddedPythonConfig| {
+            assert_eq!(x.config.sys_prefix, "$ORIGIN/lib");
+            assert!(x.config.run_python_marker);
+        });
+    }
+
+    #[test]
+    fn test_allow_runtime_code_override() {
+        let c = starlark_ok("EmbeddedPythonConfig(allow_runtime_code_override=True)");
+        c.downcast_apply(|x: &EmbeddedPythonConfig| {
+            assert!(x.config.allow_runtime_code_override);
+            assert_eq!(
+                x.config.cargo_metadata(),
+                vec!["cargo:rustc-cfg=pyembed_runtime_code_override".to_string()]
+            );
+        });
+
+        let c = starlark_ok("EmbeddedPythonConfig()");
+        c.downcast_apply(|x: &EmbeddedPythonConfig| {
+            assert!(x.config.cargo_metadata().is_empty());
+        });
+    }
+
+    #[test]
+    fn test_select() {
+        let c = starlark_ok(indoc!(
+            r#"
+            EmbeddedPythonConfig(
+                raw_allocator=select({"*-pc-windows-*": "rust", "default": "system"}),
+                unbuffered_stdio=select({BUILD_TARGET: True, "default": False}),
+                legacy_windows_stdio=select({"*-pc-windows-*": True, "default": False}),
+            )
+            "#
+        ));
+
+        c.downcast_apply(|x: &EmbeddedPythonConfig| {
+            assert_eq!(x.config.raw_allocator, RawAllocator::System);
+            assert!(x.config.unbuffered_stdio);
+            assert!(!x.config.legacy_windows_stdio);
+
+            let mut wanted = BTreeMap::new();
+            wanted.insert("legacy_windows_stdio".to_string(), "default".to_string());
+            wanted.insert("raw_allocator".to_string(), "default".to_string());
+            wanted.insert(
+                "unbuffered_stdio".to_string(),
+                crate::app_packaging::repackage::HOST.to_string(),
+            );
+            assert_eq!(x.config.target_selections, wanted);
+        });
+    }
+
+    #[test]
+    fn test_select_not_exhaustive() {
+        let err = starlark_nok("EmbeddedPythonConfig(optimize_level=select({'foo-*': 2}))");
+        assert!(err.message.contains("requires a 'default' arm"));
+        assert!(err.message.contains("optimize_level"));
+        assert!(err.message.contains(crate::app_packaging::repackage::HOST));
+    }
+
     #[test]
     fn test_terminfo_resolution() {
         let c = starlark_ok("EmbeddedPythonConfig(terminfo_resolution=None)");
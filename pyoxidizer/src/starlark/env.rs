@@ -3,6 +3,7 @@
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
 use starlark::environment::{Environment, EnvironmentError};
+use starlark::values::dict::Dictionary;
 use starlark::values::{
     default_compare, RuntimeError, TypedValue, Value, ValueError, ValueResult,
     INCORRECT_PARAMETER_TYPE_ERROR_CODE,
@@ -14,9 +15,19 @@ use starlark::{
 use std::any::Any;
 use std::cmp::Ordering;
 use std::collections::HashMap;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
-use crate::app_packaging::environment::EnvironmentContext;
+use super::eval::expand_glob;
+use crate::app_packaging::environment::{EnvironmentContext, ExternalPath};
+use crate::py_packaging::filtering::is_glob_pattern;
+use crate::py_packaging::transform::SourceTransform;
+
+/// Error code for paths referenced by the config that don't exist.
+pub const PATH_NOT_FOUND_ERROR_CODE: &str = "PATH_NOT_FOUND";
+
+/// Error code for resource rules referencing paths outside the project
+/// which aren't allowed by `Config(allow_external_paths=...)`.
+pub const EXTERNAL_PATH_ERROR_CODE: &str = "EXTERNAL_PATH";
 
 pub fn required_type_arg(arg_name: &str, arg_type: &str, value: &Value) -> Result<(), ValueError> {
     let t = value.get_type();
@@ -241,19 +252,246 @@ starlark_module! { global_module =>
 
         Ok(Value::new(None))
     }
+
+    #[allow(clippy::ptr_arg)]
+    run_python_packaging_script(env env, path) {
+        let path = required_str_arg("path", &path)?;
+        let path = resolve_path_arg(env, "run_python_packaging_script", &path, false)?;
+        let mut context = env.get("CONTEXT").expect("CONTEXT not set");
+
+        context.downcast_apply_mut(|x: &mut EnvironmentContext| {
+            if !x.packaging_scripts.contains(&path) {
+                x.packaging_scripts.push(path.clone());
+            }
+        });
+
+        Ok(Value::new(None))
+    }
+
+    #[allow(clippy::ptr_arg)]
+    glob(env env, pattern) {
+        let pattern = required_str_arg("pattern", &pattern)?;
+        let context = env.get("CONTEXT").expect("CONTEXT not set");
+        let resolved = context.downcast_apply(|x: &EnvironmentContext| x.resolve_path(&pattern));
+
+        let resolved = resolved.display().to_string();
+        let cache = context.downcast_apply(|x: &EnvironmentContext| x.eval_cache.clone());
+
+        // The matches are memoized, as the pattern is expanded again when
+        // evaluating the config for another target.
+        let paths = cache.glob(&resolved).or_else(|e| {
+            Err(RuntimeError {
+                code: INCORRECT_PARAMETER_TYPE_ERROR_CODE,
+                message: format!("invalid glob pattern {}: {}", pattern, e),
+                label: "glob()".to_string(),
+            }.into())
+        })?;
+        record_glob_matches(env, &resolved, &paths);
+
+        Ok(Value::from(
+            paths
+                .iter()
+                .map(|p| Value::from(p.display().to_string()))
+                .collect::<Vec<Value>>(),
+        ))
+    }
+
+    #[allow(clippy::ptr_arg)]
+    register_source_transform(env env, pattern, command=None, regex=None, replacement="") {
+        let pattern = required_str_arg("pattern", &pattern)?;
+        optional_list_arg("command", "string", &command)?;
+        let regex = optional_str_arg("regex", &regex)?;
+        let replacement = required_str_arg("replacement", &replacement)?;
+
+        let mut context = env.get("CONTEXT").expect("CONTEXT not set");
+        let cwd = context.downcast_apply(|x: &EnvironmentContext| x.cwd.clone());
+
+        let transform = match (command.get_type(), regex) {
+            ("list", None) => {
+                let args = command.into_iter()?.map(|x| x.to_string()).collect::<Vec<_>>();
+
+                // Scripts and programs given by path are inputs of the build.
+                for arg in &args {
+                    let path = context.downcast_apply(|x: &EnvironmentContext| x.resolve_path(arg));
+                    if path.is_file() {
+                        record_input_path(env, &path);
+                    }
+                }
+
+                SourceTransform::command(&pattern, &args, &cwd)
+            }
+            ("NoneType", Some(regex)) => SourceTransform::regex(&pattern, &regex, &replacement),
+            _ => {
+                return Err(RuntimeError {
+                    code: INCORRECT_PARAMETER_TYPE_ERROR_CODE,
+                    message: "exactly one of command or regex must be given".to_string(),
+                    label: "register_source_transform()".to_string(),
+                }.into());
+            }
+        }.or_else(|e| Err(RuntimeError {
+            code: INCORRECT_PARAMETER_TYPE_ERROR_CODE,
+            message: e.to_string(),
+            label: "register_source_transform()".to_string(),
+        }.into()))?;
+
+        context.downcast_apply_mut(|x: &mut EnvironmentContext| {
+            x.source_transforms.push(transform.clone());
+        });
+
+        Ok(Value::new(None))
+    }
+}
+
+/// Resolve a path argument to a rule relative to the configuration file.
+///
+/// Unless `allow_missing` is set, an error is raised if the resolved path
+/// does not exist.
+pub fn resolve_path_arg(
+    env: &Environment,
+    rule: &str,
+    path: &str,
+    allow_missing: bool,
+) -> Result<PathBuf, ValueError> {
+    let context = env.get("CONTEXT").expect("CONTEXT not defined");
+    let resolved = context.downcast_apply(|x: &EnvironmentContext| x.resolve_path(path));
+
+    if !allow_missing && !resolved.exists() {
+        return Err(RuntimeError {
+            code: PATH_NOT_FOUND_ERROR_CODE,
+            message: format!(
+                "path '{}' does not exist (resolved to {})",
+                path,
+                resolved.display()
+            ),
+            label: format!("rule {}", rule),
+        }
+        .into());
+    }
+
+    if resolved.exists() {
+        record_input_path(env, &resolved);
+    }
+
+    Ok(resolved)
+}
+
+/// Resolve a path argument to a rule collecting resources.
+///
+/// Like `resolve_path_arg()`, but also records the path if it, or a
+/// symlink beneath it, leads outside the project directory. Whether
+/// recorded paths are allowed is decided once evaluation completes.
+pub fn resolve_resource_path_arg(
+    env: &Environment,
+    rule: &str,
+    path: &str,
+    allow_missing: bool,
+) -> Result<PathBuf, ValueError> {
+    let resolved = resolve_path_arg(env, rule, path, allow_missing)?;
+    record_external_paths(env, rule, &resolved);
+
+    Ok(resolved)
+}
+
+fn record_external_paths(env: &Environment, rule: &str, path: &Path) {
+    env.get("CONTEXT")
+        .expect("CONTEXT not defined")
+        .downcast_apply_mut(|x: &mut EnvironmentContext| {
+            let mut found = Vec::new();
+
+            if let Some(target) = x.external_target(path) {
+                found.push((path.to_path_buf(), target));
+            } else if path.is_dir() {
+                // Links aren't followed, so links to directories are
+                // checked without descending into them.
+                for entry in walkdir::WalkDir::new(path)
+                    .sort_by(|a, b| a.file_name().cmp(b.file_name()))
+                    .into_iter()
+                    .filter_map(|entry| entry.ok())
+                    .filter(|entry| entry.path_is_symlink())
+                {
+                    if let Some(target) = x.external_target(entry.path()) {
+                        found.push((entry.path().to_path_buf(), target));
+                    }
+                }
+            }
+
+            for (path, target) in found {
+                let external = ExternalPath {
+                    rule: rule.to_string(),
+                    path,
+                    target,
+                };
+
+                if !x.external_paths.contains(&external) {
+                    x.external_paths.push(external);
+                }
+            }
+        });
+}
+
+/// Record a filesystem path as an input to the configuration.
+///
+/// Recorded paths are captured by lockfiles.
+pub fn record_input_path(env: &Environment, path: &Path) {
+    env.get("CONTEXT")
+        .expect("CONTEXT not defined")
+        .downcast_apply_mut(|x: &mut EnvironmentContext| {
+            x.input_paths.insert(path.to_path_buf());
+        });
+}
+
+/// The directory a glob pattern searches, up to its first glob component.
+pub fn glob_base_dir(pattern: &str) -> PathBuf {
+    Path::new(pattern)
+        .components()
+        .take_while(|c| !is_glob_pattern(&c.as_os_str().to_string_lossy()))
+        .collect()
+}
+
+/// Expand an absolute glob pattern, recording the files it matches and the
+/// directory it searches as inputs to the configuration.
+///
+/// Recording the directory means files added to or removed from it are
+/// noticed, even when they change the set of matches.
+pub fn record_glob_input_paths(
+    env: &Environment,
+    pattern: &str,
+) -> Result<Vec<PathBuf>, glob::PatternError> {
+    let paths = expand_glob(pattern)?;
+    record_glob_matches(env, pattern, &paths);
+
+    Ok(paths)
+}
+
+/// Record the matches of a glob pattern and the directory it searches.
+fn record_glob_matches(env: &Environment, pattern: &str, paths: &[PathBuf]) {
+    for path in paths {
+        record_input_path(env, path);
+    }
+
+    let base = glob_base_dir(pattern);
+    if base.is_dir() {
+        record_input_path(env, &base);
+    }
 }
 
 /// Obtain a Starlark environment for evaluating PyOxidizer configurations.
 pub fn global_environment(context: &EnvironmentContext) -> Result<Environment, EnvironmentError> {
     let env = starlark::stdlib::global_environment();
     let env = global_module(env);
+    let env = super::archive_resources::archive_resources_module(env);
     let env = super::config::config_env(env);
+    let env = super::data_file::data_file_module(env);
+    let env = super::embedded_config::embedded_config_module(env);
     let env = super::file_resource::file_resource_env(env);
+    let env = super::locale::locale_module(env);
     let env = super::python_distribution::python_distribution_module(env);
     let env = super::embedded_python_config::embedded_python_config_module(env);
     let env = super::python_executable::python_executable_env(env);
     let env = super::python_resource::python_resource_env(env);
     let env = super::python_run_mode::python_run_mode_env(env);
+    let env = super::rust_extension::rust_extension_module(env);
+    let env = super::select::select_module(env);
 
     env.set("CONTEXT", Value::new(context.clone()))?;
 
@@ -264,12 +502,22 @@ pub fn global_environment(context: &EnvironmentContext) -> Result<Environment, E
     )?;
     env.set("BUILD_TARGET", Value::from(context.build_target.clone()))?;
 
+    let mut build_vars = Dictionary::new();
+    for (name, value) in &context.build_vars {
+        build_vars
+            .set_at(Value::from(name.clone()), Value::from(value.clone()))
+            .expect("strings are hashable");
+    }
+    env.set("BUILD_VARS", build_vars)?;
+
     Ok(env)
 }
 
 #[cfg(test)]
 pub mod tests {
+    use super::super::eval::EvaluationCache;
     use super::super::testutil::*;
+    use super::*;
 
     #[test]
     fn test_cwd() {
@@ -283,4 +531,214 @@ pub mod tests {
         let target = starlark_ok("BUILD_TARGET");
         assert_eq!(target.to_str(), crate::app_packaging::repackage::HOST);
     }
+
+    #[test]
+    fn test_build_vars() {
+        let mut env = starlark_env_with_vars(&[("customer", "acme")]);
+        let v = starlark_eval_in_env(&mut env, "BUILD_VARS['customer']").unwrap();
+        assert_eq!(v.to_str(), "acme");
+
+        let v = starlark_eval_in_env(&mut env, "BUILD_VARS.get('region', 'eu')").unwrap();
+        assert_eq!(v.to_str(), "eu");
+    }
+
+    #[test]
+    fn test_resolve_path_arg() {
+        let env = starlark_env();
+        let cwd = std::env::current_dir().unwrap();
+
+        let path = super::resolve_path_arg(&env, "test", "Cargo.toml", false).unwrap();
+        assert_eq!(path, cwd.join("Cargo.toml"));
+
+        let absolute = cwd.join("Cargo.toml").display().to_string();
+        let path = super::resolve_path_arg(&env, "test", &absolute, false).unwrap();
+        assert_eq!(path, cwd.join("Cargo.toml"));
+
+        let err = super::resolve_path_arg(&env, "test", "does-not-exist", false).unwrap_err();
+        match err {
+            super::ValueError::Runtime(e) => {
+                assert_eq!(e.code, super::PATH_NOT_FOUND_ERROR_CODE);
+                assert_eq!(
+                    e.message,
+                    format!(
+                        "path 'does-not-exist' does not exist (resolved to {})",
+                        cwd.join("does-not-exist").display()
+                    )
+                );
+                assert_eq!(e.label, "rule test");
+            }
+            _ => panic!("expected runtime error"),
+        }
+
+        let path = super::resolve_path_arg(&env, "test", "does-not-exist", true).unwrap();
+        assert_eq!(path, cwd.join("does-not-exist"));
+
+        // Only existing paths are recorded as inputs.
+        let input_paths = env
+            .get("CONTEXT")
+            .unwrap()
+            .downcast_apply(|x: &EnvironmentContext| x.input_paths.clone());
+        assert_eq!(
+            input_paths.into_iter().collect::<Vec<PathBuf>>(),
+            vec![cwd.join("Cargo.toml")]
+        );
+    }
+
+    #[test]
+    fn test_record_glob_input_paths() -> anyhow::Result<()> {
+        assert_eq!(glob_base_dir("/a/b/**/*.py"), PathBuf::from("/a/b"));
+        assert_eq!(glob_base_dir("/a/b[12]/c.py"), PathBuf::from("/a"));
+        assert_eq!(glob_base_dir("/a/b/c.py"), PathBuf::from("/a/b/c.py"));
+
+        let temp_dir = tempdir::TempDir::new("pyoxidizer-test")?;
+        let tree = temp_dir.path().join("python");
+        std::fs::create_dir_all(tree.join("myapp"))?;
+        std::fs::write(tree.join("myapp").join("cli.py"), "")?;
+
+        let env = starlark_env();
+        let pattern = format!("{}/**/*.py", tree.display());
+        assert_eq!(
+            record_glob_input_paths(&env, &pattern)?,
+            vec![tree.join("myapp").join("cli.py")]
+        );
+        let pattern = format!("{}/*.py", temp_dir.path().join("missing").display());
+        assert!(record_glob_input_paths(&env, &pattern)?.is_empty());
+
+        // The searched directory is recorded, so new modules are noticed.
+        let input_paths = env
+            .get("CONTEXT")
+            .unwrap()
+            .downcast_apply(|x: &EnvironmentContext| x.input_paths.clone());
+        assert_eq!(
+            input_paths.into_iter().collect::<Vec<PathBuf>>(),
+            vec![tree.clone(), tree.join("myapp").join("cli.py")]
+        );
+
+        // Unlike glob(), matches aren't memoized in the evaluation cache.
+        let cache = EvaluationCache::default();
+        let pattern = format!("{}/**/*.py", tree.display());
+        record_glob_input_paths(&starlark_env_with_cache(&cache), &pattern)?;
+        std::fs::write(tree.join("myapp").join("util.py"), "")?;
+        assert_eq!(
+            record_glob_input_paths(&starlark_env_with_cache(&cache), &pattern)?.len(),
+            2
+        );
+        assert!(cache.globs.lock().unwrap().is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_glob() -> anyhow::Result<()> {
+        let temp_dir = tempdir::TempDir::new("pyoxidizer-test")?;
+        let dir = temp_dir.path();
+        std::fs::write(dir.join("b.txt"), "")?;
+        std::fs::write(dir.join("a.txt"), "")?;
+        std::fs::write(dir.join("c.py"), "")?;
+        let code = format!(
+            "glob('{}/*.txt')",
+            dir.display().to_string().replace("\\", "/")
+        );
+
+        let cache = EvaluationCache::default();
+        let mut env = starlark_env_with_cache(&cache);
+        let v = starlark_eval_in_env(&mut env, &code).unwrap();
+        assert_eq!(
+            v.into_iter()
+                .unwrap()
+                .map(|x| PathBuf::from(x.to_str()))
+                .collect::<Vec<_>>(),
+            vec![dir.join("a.txt"), dir.join("b.txt")]
+        );
+
+        let input_paths = env
+            .get("CONTEXT")
+            .unwrap()
+            .downcast_apply(|x: &EnvironmentContext| x.input_paths.clone());
+        assert!(input_paths.contains(dir));
+        assert!(input_paths.contains(&dir.join("a.txt")));
+
+        // Matches are memoized for evaluations sharing the cache, keyed by
+        // the pattern.
+        std::fs::write(dir.join("d.txt"), "")?;
+        let mut env = starlark_env_with_cache(&cache);
+        let v = starlark_eval_in_env(&mut env, &code).unwrap();
+        assert_eq!(v.length().unwrap(), 2);
+        let v = starlark_eval_in_env(&mut env, &code.replace("*.txt", "[a-d].txt")).unwrap();
+        assert_eq!(v.length().unwrap(), 3);
+
+        let v = starlark_eval_in_env(&mut starlark_env(), &code).unwrap();
+        assert_eq!(v.length().unwrap(), 3);
+
+        let err = starlark_nok("glob('[')");
+        assert!(err.message.starts_with("invalid glob pattern ["));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_run_python_packaging_script() {
+        let mut env = starlark_env();
+        let cwd = std::env::current_dir().unwrap();
+
+        starlark_eval_in_env(&mut env, "run_python_packaging_script('Cargo.toml')").unwrap();
+        starlark_eval_in_env(&mut env, "run_python_packaging_script('Cargo.toml')").unwrap();
+
+        let scripts = env
+            .get("CONTEXT")
+            .unwrap()
+            .downcast_apply(|x: &EnvironmentContext| x.packaging_scripts.clone());
+        assert_eq!(scripts, vec![cwd.join("Cargo.toml")]);
+
+        let err = starlark_nok("run_python_packaging_script('does-not-exist.py')");
+        assert!(err
+            .message
+            .starts_with("path 'does-not-exist.py' does not exist"));
+    }
+
+    #[test]
+    fn test_register_source_transform() {
+        let mut env = starlark_env();
+        let cwd = std::env::current_dir().unwrap();
+
+        starlark_eval_in_env(
+            &mut env,
+            "register_source_transform('myapp.*', command=['python', 'Cargo.toml'])",
+        )
+        .unwrap();
+        starlark_eval_in_env(
+            &mut env,
+            "register_source_transform('myapp', regex='(?m)^assert .*$')",
+        )
+        .unwrap();
+
+        let context = env.get("CONTEXT").unwrap();
+        let transforms = context.downcast_apply(|x: &EnvironmentContext| {
+            x.source_transforms
+                .iter()
+                .map(|t| t.describe())
+                .collect::<Vec<_>>()
+        });
+        assert_eq!(
+            transforms,
+            vec![
+                "myapp.* (command python Cargo.toml)",
+                "myapp (regex (?m)^assert .*$)"
+            ]
+        );
+
+        // The script is an input, the program found on PATH isn't.
+        let input_paths = context.downcast_apply(|x: &EnvironmentContext| x.input_paths.clone());
+        assert!(input_paths.contains(&cwd.join("Cargo.toml")));
+        assert!(!input_paths.contains(&cwd.join("python")));
+
+        let err = starlark_nok("register_source_transform('myapp')");
+        assert_eq!(err.message, "exactly one of command or regex must be given");
+
+        let err = starlark_nok("register_source_transform('myapp', regex='(')");
+        assert!(err.message.starts_with("invalid regular expression"));
+
+        let err = starlark_nok("register_source_transform('myapp', command=[])");
+        assert_eq!(err.message, "command must not be empty");
+    }
 }
@@ -5,12 +5,68 @@
 use codemap::CodeMap;
 use codemap_diagnostic::{Diagnostic, Level};
 use starlark::environment::Environment;
-use std::path::Path;
+use starlark::eval::{EvalException, FileLoader};
+use std::collections::{BTreeSet, HashMap};
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
 
-use super::env::global_environment;
+use super::data_file::DataFileKey;
+use super::env::{
+    global_environment, record_input_path, EXTERNAL_PATH_ERROR_CODE, PATH_NOT_FOUND_ERROR_CODE,
+};
 use crate::app_packaging::config::Config;
-use crate::app_packaging::environment::EnvironmentContext;
+use crate::app_packaging::environment::{EnvironmentContext, ExternalPath};
+use crate::environment::canonicalize_path;
+
+/// Results memoized across the config evaluations of one invocation.
+///
+/// A multi-target build evaluates the config once per target. Clones of a
+/// cache share results, so the evaluations reuse the work of each other.
+/// Only results that don't depend on the build target or other
+/// per-evaluation inputs are memoized, keyed by everything they depend on.
+/// Results are dropped with the last clone of the cache.
+#[derive(Clone, Debug, Default)]
+pub struct EvaluationCache {
+    /// Documents parsed by `read_json()`, by path and content hash.
+    pub(crate) json: Arc<Mutex<HashMap<DataFileKey, serde_json::Value>>>,
+
+    /// Documents parsed by `read_toml()`, by path and content hash.
+    pub(crate) toml: Arc<Mutex<HashMap<DataFileKey, toml::Value>>>,
+
+    /// Sorted paths matched by glob patterns, by absolute pattern.
+    pub(crate) globs: Arc<Mutex<HashMap<String, Vec<PathBuf>>>>,
+}
+
+impl EvaluationCache {
+    /// Paths matching an absolute glob pattern, in sorted order.
+    ///
+    /// Files created after the first expansion of a pattern aren't seen by
+    /// later evaluations using this cache.
+    pub fn glob(&self, pattern: &str) -> Result<Vec<PathBuf>, glob::PatternError> {
+        if let Some(paths) = self.globs.lock().unwrap().get(pattern) {
+            return Ok(paths.clone());
+        }
+
+        let paths = expand_glob(pattern)?;
+
+        self.globs
+            .lock()
+            .unwrap()
+            .insert(pattern.to_string(), paths.clone());
+
+        Ok(paths)
+    }
+}
+
+/// Paths matching an absolute glob pattern, in sorted order.
+pub fn expand_glob(pattern: &str) -> Result<Vec<PathBuf>, glob::PatternError> {
+    let mut paths = glob::glob(pattern)?
+        .filter_map(|p| p.ok())
+        .collect::<Vec<PathBuf>>();
+    paths.sort();
+
+    Ok(paths)
+}
 
 /// Represents the result of evaluating a Starlark environment.
 pub struct EvalResult {
@@ -21,6 +77,61 @@ pub struct EvalResult {
     pub config: Config,
 }
 
+/// Evaluates files loaded with `load()`, recording their paths.
+///
+/// Behaves like the loader of `starlark::eval::simple::eval_file()`:
+/// paths are relative to the current directory and each file is only
+/// evaluated once.
+#[derive(Clone)]
+struct RecordingFileLoader {
+    codemap: Arc<Mutex<CodeMap>>,
+    parent_env: Environment,
+    loaded_envs: Arc<Mutex<HashMap<String, Environment>>>,
+    loaded_paths: Arc<Mutex<BTreeSet<PathBuf>>>,
+}
+
+impl RecordingFileLoader {
+    fn new(codemap: &Arc<Mutex<CodeMap>>, parent_env: Environment) -> Self {
+        RecordingFileLoader {
+            codemap: codemap.clone(),
+            parent_env,
+            loaded_envs: Arc::new(Mutex::new(HashMap::new())),
+            loaded_paths: Arc::new(Mutex::new(BTreeSet::new())),
+        }
+    }
+
+    /// Absolute paths of all files loaded so far.
+    fn loaded_paths(&self) -> BTreeSet<PathBuf> {
+        self.loaded_paths.lock().unwrap().clone()
+    }
+}
+
+impl FileLoader for RecordingFileLoader {
+    fn load(&self, path: &str) -> Result<Environment, EvalException> {
+        let resolved = match std::env::current_dir() {
+            Ok(cwd) => cwd.join(path),
+            Err(_) => PathBuf::from(path),
+        };
+        self.loaded_paths.lock().unwrap().insert(resolved);
+
+        if let Some(env) = self.loaded_envs.lock().unwrap().get(path) {
+            return Ok(env.clone());
+        }
+
+        let mut env = self.parent_env.child(path);
+        starlark::eval::eval_file(&self.codemap, path, false, &mut env, self.clone())
+            .map_err(EvalException::DiagnosedError)?;
+        env.freeze();
+
+        self.loaded_envs
+            .lock()
+            .unwrap()
+            .insert(path.to_string(), env.clone());
+
+        Ok(env)
+    }
+}
+
 pub fn evaluate_file(
     logger: &slog::Logger,
     path: &Path,
@@ -36,20 +147,52 @@ pub fn evaluate_file(
     })?;
 
     let map = Arc::new(Mutex::new(CodeMap::new()));
-    starlark::eval::simple::eval_file(&map, &path.display().to_string(), false, &mut env).or_else(
-        |e| {
-            let mut msg = Vec::new();
-            let raw_map = map.lock().unwrap();
-            {
-                let mut emitter = codemap_diagnostic::Emitter::vec(&mut msg, Some(&raw_map));
-                emitter.emit(&[e.clone()]);
-            }
+    let loader = RecordingFileLoader::new(&map, env.clone());
+    starlark::eval::eval_file(
+        &map,
+        &path.display().to_string(),
+        false,
+        &mut env,
+        loader.clone(),
+    )
+    .or_else(|e| {
+        let mut msg = Vec::new();
+        let raw_map = map.lock().unwrap();
+        {
+            let mut emitter = codemap_diagnostic::Emitter::vec(&mut msg, Some(&raw_map));
+            emitter.emit(&[e.clone()]);
+        }
+
+        slog::error!(logger, "{}", String::from_utf8_lossy(&msg));
 
-            slog::error!(logger, "{}", String::from_utf8_lossy(&msg));
+        // Include the location of the error in the message so it is
+        // meaningful without the rendered diagnostic.
+        let mut e = e;
+        if let Some(span) = e.spans.first() {
+            let loc = raw_map.look_up_span(span.span);
+            let filename = Path::new(loc.file.name())
+                .file_name()
+                .map(|x| x.to_string_lossy().to_string())
+                .unwrap_or_else(|| loc.file.name().to_string());
+            let location = format!("{}:{}", filename, loc.begin.line + 1);
 
-            Err(e)
-        },
-    )?;
+            e.message = match (&e.code, &span.label) {
+                (Some(code), Some(label)) if code == PATH_NOT_FOUND_ERROR_CODE => {
+                    format!("{} at {}: {}", label, location, e.message)
+                }
+                _ => format!("{}: {}", location, e.message),
+            };
+        }
+
+        Err(e)
+    })?;
+
+    // Files loaded with load() affect the result like the config file itself.
+    for loaded in loader.loaded_paths() {
+        if loaded.exists() {
+            record_input_path(&env, &loaded);
+        }
+    }
 
     let config = env.get("CONFIG").or_else(|_| {
         Err(Diagnostic {
@@ -69,10 +212,244 @@ pub fn evaluate_file(
         });
     }
 
+    let mut config =
+        config.downcast_apply(|x: &crate::starlark::config::Config| -> Config { x.config.clone() });
+
+    let context_value = env.get("CONTEXT").expect("CONTEXT not defined");
+    config.python_package_versions =
+        context_value.downcast_apply(|x: &EnvironmentContext| x.python_package_versions.clone());
+    config.python_package_hashes =
+        context_value.downcast_apply(|x: &EnvironmentContext| x.python_package_hashes.clone());
+    config.read_files = context_value.downcast_apply(|x: &EnvironmentContext| x.read_files.clone());
+    config.input_paths =
+        context_value.downcast_apply(|x: &EnvironmentContext| x.input_paths.clone());
+    config.packaging_scripts =
+        context_value.downcast_apply(|x: &EnvironmentContext| x.packaging_scripts.clone());
+    config.extension_module_license_policy = context_value
+        .downcast_apply(|x: &EnvironmentContext| x.extension_module_license_policy.clone());
+    config.required_sqlite_options =
+        context_value.downcast_apply(|x: &EnvironmentContext| x.required_sqlite_options.clone());
+    config.embedded_config_files =
+        context_value.downcast_apply(|x: &EnvironmentContext| x.embedded_config_files.clone());
+
+    // Rules may be declared before Config(), so external paths can only be
+    // checked once everything is evaluated.
+    let external_paths =
+        context_value.downcast_apply(|x: &EnvironmentContext| x.external_paths.clone());
+    check_external_paths(&external_paths, &config.build_config.allow_external_paths)?;
+    config.external_paths = external_paths;
+
     Ok(EvalResult {
         env,
         context: context.clone(),
-        config: config
-            .downcast_apply(|x: &crate::starlark::config::Config| -> Config { x.config.clone() }),
+        config,
     })
 }
+
+/// Ensure paths outside the project are under an allowed directory.
+fn check_external_paths(
+    external_paths: &[ExternalPath],
+    allowed: &[PathBuf],
+) -> Result<(), Diagnostic> {
+    let allowed = allowed
+        .iter()
+        .map(|p| canonicalize_path(p).unwrap_or_else(|_| p.clone()))
+        .collect::<Vec<_>>();
+
+    for external in external_paths {
+        if allowed.iter().any(|dir| external.target.starts_with(dir)) {
+            continue;
+        }
+
+        let path = if external.path == external.target {
+            external.path.display().to_string()
+        } else {
+            format!(
+                "{} (resolves to {})",
+                external.path.display(),
+                external.target.display()
+            )
+        };
+
+        return Err(Diagnostic {
+            level: Level::Error,
+            message: format!(
+                "rule {} references {} outside the project directory; add its directory to Config(allow_external_paths=[...]) to allow it",
+                external.rule, path
+            ),
+            code: Some(EXTERNAL_PATH_ERROR_CODE.to_string()),
+            spans: vec![],
+        });
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::*;
+
+    #[test]
+    fn test_loaded_files_recorded() {
+        let logger = get_logger().unwrap();
+        let temp_dir = tempdir::TempDir::new("pyoxidizer-test").unwrap();
+        let config_path = temp_dir.path().join("pyoxidizer.bzl");
+        let helpers_path = temp_dir.path().join("helpers.bzl");
+
+        std::fs::write(&helpers_path, "APP_NAME = 'myapp'\n").unwrap();
+        std::fs::write(
+            &config_path,
+            format!(
+                "load('{}', 'APP_NAME')\nCONFIG = Config(application_name=APP_NAME, embedded_python_config=EmbeddedPythonConfig(), python_distribution=default_python_distribution(), python_run_mode=python_run_mode_repl())\n",
+                helpers_path.display()
+            ),
+        )
+        .unwrap();
+
+        let context = EnvironmentContext::new(&logger, &config_path, env!("HOST")).unwrap();
+        let res = match evaluate_file(&logger, &config_path, &context) {
+            Ok(res) => res,
+            Err(e) => panic!("evaluation failed: {}", e.message),
+        };
+
+        assert_eq!(res.config.build_config.application_name, "myapp");
+        assert!(res.config.input_paths.contains(&helpers_path));
+    }
+
+    #[test]
+    fn test_missing_path_error_location() {
+        let logger = get_logger().unwrap();
+        let temp_dir = tempdir::TempDir::new("pyoxidizer-test").unwrap();
+        let config_path = temp_dir.path().join("pyoxidizer.bzl");
+
+        std::fs::write(
+            &config_path,
+            "\ndist = PythonDistribution(sha256='abc', local_path='dist/python.tar.zst')\n",
+        )
+        .unwrap();
+
+        let context =
+            EnvironmentContext::new(&logger, &config_path, "x86_64-unknown-linux-gnu").unwrap();
+
+        let err = match evaluate_file(&logger, &config_path, &context) {
+            Ok(_) => panic!("expected evaluation to fail"),
+            Err(e) => e,
+        };
+
+        assert_eq!(
+            err.message,
+            format!(
+                "rule PythonDistribution at pyoxidizer.bzl:2: path 'dist/python.tar.zst' does not exist (resolved to {})",
+                temp_dir.path().join("dist/python.tar.zst").display()
+            )
+        );
+    }
+
+    /// Evaluate a config in `project` collecting locales from `locale_path`.
+    fn eval_locale_config(
+        project: &Path,
+        locale_path: &str,
+        allow_external_paths: &str,
+    ) -> Result<EvalResult, Diagnostic> {
+        let logger = get_logger().unwrap();
+        let config_path = project.join("pyoxidizer.bzl");
+
+        std::fs::write(
+            &config_path,
+            format!(
+                "locales = locale_resources('{}', domains=['messages'])\nCONFIG = Config(application_name='myapp', embedded_python_config=EmbeddedPythonConfig(), python_distribution=default_python_distribution(), python_run_mode=python_run_mode_repl(), allow_external_paths={})\n",
+                locale_path, allow_external_paths
+            ),
+        )
+        .unwrap();
+
+        let context = EnvironmentContext::new(&logger, &config_path, env!("HOST")).unwrap();
+        evaluate_file(&logger, &config_path, &context)
+    }
+
+    #[test]
+    fn test_external_path_denied() {
+        let temp_dir = tempdir::TempDir::new("pyoxidizer-test").unwrap();
+        let project = temp_dir.path().join("project");
+        let outside = temp_dir.path().join("outside");
+        std::fs::create_dir_all(project.join("locale")).unwrap();
+        std::fs::create_dir_all(&outside).unwrap();
+
+        let res = match eval_locale_config(&project, "locale", "None") {
+            Ok(res) => res,
+            Err(e) => panic!("evaluation failed: {}", e.message),
+        };
+        assert!(res.config.external_paths.is_empty());
+
+        let err = match eval_locale_config(&project, "../outside", "None") {
+            Ok(_) => panic!("expected evaluation to fail"),
+            Err(e) => e,
+        };
+
+        assert_eq!(err.code, Some(EXTERNAL_PATH_ERROR_CODE.to_string()));
+        assert!(err.message.starts_with(&format!(
+            "rule locale_resources() references {}",
+            project.join("../outside").display()
+        )));
+        assert!(err.message.contains("allow_external_paths"));
+    }
+
+    #[test]
+    fn test_external_path_allowed() {
+        let temp_dir = tempdir::TempDir::new("pyoxidizer-test").unwrap();
+        let project = temp_dir.path().join("project");
+        let outside = temp_dir.path().join("outside");
+        std::fs::create_dir_all(&project).unwrap();
+        std::fs::create_dir_all(outside.join("locale")).unwrap();
+
+        let res = match eval_locale_config(&project, "../outside/locale", "['../outside']") {
+            Ok(res) => res,
+            Err(e) => panic!("evaluation failed: {}", e.message),
+        };
+
+        let outside = canonicalize_path(&outside).unwrap();
+        assert_eq!(res.config.external_paths.len(), 1);
+        assert_eq!(res.config.external_paths[0].rule, "locale_resources()");
+        assert_eq!(res.config.external_paths[0].target, outside.join("locale"));
+
+        // Whitelisting a sibling directory doesn't allow others.
+        std::fs::create_dir_all(temp_dir.path().join("other")).unwrap();
+        let err = match eval_locale_config(&project, "../outside/locale", "['../other']") {
+            Ok(_) => panic!("expected evaluation to fail"),
+            Err(e) => e,
+        };
+        assert_eq!(err.code, Some(EXTERNAL_PATH_ERROR_CODE.to_string()));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_external_path_symlink() {
+        let temp_dir = tempdir::TempDir::new("pyoxidizer-test").unwrap();
+        let project = temp_dir.path().join("project");
+        let outside = temp_dir.path().join("outside");
+        std::fs::create_dir_all(project.join("locale")).unwrap();
+        std::fs::create_dir_all(outside.join("de").join("LC_MESSAGES")).unwrap();
+
+        let link = project.join("locale").join("de");
+        std::os::unix::fs::symlink(outside.join("de"), &link).unwrap();
+
+        let err = match eval_locale_config(&project, "locale", "None") {
+            Ok(_) => panic!("expected evaluation to fail"),
+            Err(e) => e,
+        };
+        assert_eq!(err.code, Some(EXTERNAL_PATH_ERROR_CODE.to_string()));
+        assert!(err.message.contains(&format!(
+            "{} (resolves to {})",
+            link.display(),
+            canonicalize_path(&outside.join("de")).unwrap().display()
+        )));
+
+        let res = match eval_locale_config(&project, "locale", "['../outside']") {
+            Ok(res) => res,
+            Err(e) => panic!("evaluation failed: {}", e.message),
+        };
+        assert_eq!(res.config.external_paths.len(), 1);
+        assert_eq!(res.config.external_paths[0].path, link);
+    }
+}
@@ -2,7 +2,8 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
+use slog::warn;
 use starlark::environment::Environment;
 use starlark::values::{
     default_compare, RuntimeError, TypedValue, Value, ValueError, ValueResult,
@@ -17,18 +18,22 @@ use std::cmp::Ordering;
 use std::collections::HashMap;
 use std::path::{Path, PathBuf};
 
-use super::env::{required_bool_arg, required_str_arg};
+use super::config::Config;
+use super::env::{required_bool_arg, required_str_arg, resolve_resource_path_arg};
 use super::python_resource::{
     PythonBytecodeModule, PythonExtensionModule, PythonResourceData, PythonSourceModule,
 };
+use crate::app_packaging::config::normalize_env_rustflags;
 use crate::app_packaging::environment::EnvironmentContext;
 use crate::app_packaging::resource::{
-    FileContent as RawFileContent, FileManifest as RawFileManifest,
+    CaseCollisionPolicy, FileContent as RawFileContent, FileManifest as RawFileManifest,
 };
 use crate::project_building::build_python_executable;
 use crate::py_packaging::binary::PreBuiltPythonExecutable;
+use crate::py_packaging::compatibility::FILESYSTEM_RELATIVE_DIR;
 use crate::py_packaging::distribution::ExtensionModule;
 use crate::py_packaging::resource::{BytecodeModule, ResourceData, SourceModule};
+use crate::py_packaging::sqlite::SQLITE_EXTENSIONS_DIR;
 
 #[derive(Clone, Debug)]
 pub struct FileContent {
@@ -67,7 +72,7 @@ pub struct FileManifest {
 }
 
 impl FileManifest {
-    fn add_source_module(&mut self, prefix: &str, module: &SourceModule) -> Result<()> {
+    fn add_source_module(&mut self, prefix: &str, module: &SourceModule, rule: &str) -> Result<()> {
         let content = RawFileContent {
             data: module.source.clone(),
             executable: false,
@@ -86,7 +91,7 @@ impl FileManifest {
             module_path.file_name().unwrap().to_string_lossy()
         ));
 
-        self.manifest.add_file(&module_path, &content)
+        self.manifest.add_file_from(&module_path, &content, rule)
     }
 
     // TODO implement.
@@ -94,7 +99,12 @@ impl FileManifest {
         println!("support for adding bytecode modules not yet implemented");
     }
 
-    fn add_resource_data(&mut self, prefix: &str, resource: &ResourceData) -> Result<()> {
+    fn add_resource_data(
+        &mut self,
+        prefix: &str,
+        resource: &ResourceData,
+        rule: &str,
+    ) -> Result<()> {
         let mut dest_path = PathBuf::from(prefix);
         dest_path.extend(resource.package.split('.'));
         dest_path.push(&resource.name);
@@ -104,12 +114,68 @@ impl FileManifest {
             executable: false,
         };
 
-        self.manifest.add_file(&dest_path, &content)
+        self.manifest.add_file_from(&dest_path, &content, rule)
     }
 
-    // TODO implement.
-    fn add_extension_module(&self, _prefix: &str, _em: &ExtensionModule) {
-        println!("support for adding extension modules not yet implemented");
+    /// Add an extension module built as a shared library.
+    fn add_extension_module(
+        &mut self,
+        prefix: &str,
+        em: &ExtensionModule,
+        rule: &str,
+    ) -> Result<()> {
+        // TODO support extension modules only available as object files.
+        let path = match &em.shared_library {
+            Some(path) => path,
+            None => {
+                println!("support for adding extension modules not yet implemented");
+                return Ok(());
+            }
+        };
+
+        let content = RawFileContent {
+            data: std::fs::read(path)?,
+            executable: true,
+        };
+
+        let mut dest_path = PathBuf::from(prefix);
+        dest_path.extend(em.module.split('.'));
+        dest_path.set_file_name(path.file_name().unwrap());
+
+        self.manifest.add_file_from(&dest_path, &content, rule)
+    }
+
+    /// Add a SQLite loadable extension for an executable in `prefix`.
+    ///
+    /// `oxidized_app.load_sqlite_extension()` finds it there at run-time.
+    fn add_sqlite_extension(&mut self, prefix: &str, path: &Path, rule: &str) -> Result<()> {
+        let file_name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+
+        let shared_library = file_name.ends_with(".so")
+            || file_name.contains(".so.")
+            || file_name.ends_with(".dylib")
+            || file_name.ends_with(".dll");
+        if !shared_library {
+            return Err(anyhow!(
+                "{} is not a shared library (.so, .dylib or .dll)",
+                path.display()
+            ));
+        }
+
+        let content = RawFileContent {
+            data: std::fs::read(path)?,
+            executable: true,
+        };
+
+        let dest_path = Path::new(prefix)
+            .join(FILESYSTEM_RELATIVE_DIR)
+            .join(SQLITE_EXTENSIONS_DIR)
+            .join(file_name);
+
+        self.manifest.add_file_from(&dest_path, &content, rule)
     }
 
     fn add_python_executable(
@@ -118,9 +184,19 @@ impl FileManifest {
         prefix: &str,
         exe: &PreBuiltPythonExecutable,
         target: &str,
+        rustflags: &[String],
+        rule: &str,
     ) -> Result<()> {
-        let (filename, data) =
-            build_python_executable(logger, &exe.name, exe, env!("HOST"), target, "0", true)?;
+        let (filename, data) = build_python_executable(
+            logger,
+            &exe.name,
+            exe,
+            env!("HOST"),
+            target,
+            "0",
+            true,
+            rustflags,
+        )?;
 
         let content = RawFileContent {
             data,
@@ -128,7 +204,62 @@ impl FileManifest {
         };
 
         let path = Path::new(&prefix).join(filename);
-        self.manifest.add_file(&path, &content)?;
+        self.manifest.add_file_from(&path, &content, rule)?;
+
+        // Executables sharing resources can be installed together, as long
+        // as they were built with the same shared resources.
+        if let Some(shared) = exe.shared_resources_file()? {
+            let path = Path::new(&prefix).join(&shared.path);
+
+            if let Some(existing) = self.manifest.get(&path) {
+                if existing.data != shared.data {
+                    return Err(anyhow!(
+                        "{} differs from the shared resources of {}; executables installed together must share the same resources",
+                        path.display(),
+                        exe.name
+                    ));
+                }
+            }
+
+            self.manifest.add_file_from(
+                &path,
+                &RawFileContent {
+                    data: shared.data,
+                    executable: false,
+                },
+                rule,
+            )?;
+        }
+
+        // Packages which can't be imported from memory go next to the executable.
+        let (modules, resources) = exe.resources.filesystem_resources();
+        let extension_modules = exe.resources.filesystem_extension_modules();
+        if !modules.is_empty() || !resources.is_empty() || !extension_modules.is_empty() {
+            let lib_prefix = Path::new(&prefix)
+                .join(FILESYSTEM_RELATIVE_DIR)
+                .display()
+                .to_string();
+
+            warn!(
+                logger,
+                "installing {} modules, {} extension modules and {} resource files into {}",
+                modules.len(),
+                extension_modules.len(),
+                resources.len(),
+                lib_prefix
+            );
+
+            for module in &modules {
+                self.add_source_module(&lib_prefix, module, rule)?;
+            }
+            for em in &extension_modules {
+                self.add_extension_module(&lib_prefix, em, rule)?;
+            }
+            for resource in &resources {
+                self.add_resource_data(&lib_prefix, resource, rule)?;
+            }
+        }
+
         Ok(())
     }
 }
@@ -170,12 +301,13 @@ starlark_module! { file_resource_env =>
     #[allow(clippy::ptr_arg)]
     FileManifest.add_python_resource(env env, this, prefix, resource) {
         let prefix = required_str_arg("prefix", &prefix)?;
+        let rule = format!("add_python_resource({})", resource.to_str());
 
         this.downcast_apply_mut(|manifest: &mut FileManifest| -> Result<(), ValueError> {
             match resource.get_type() {
                 "PythonSourceModule" => {
                     let m = resource.downcast_apply(|m: &PythonSourceModule| m.module.clone());
-                    manifest.add_source_module(&prefix, &m).or_else(|e| {
+                    manifest.add_source_module(&prefix, &m, &rule).or_else(|e| {
                         Err(RuntimeError {
                             code: INCORRECT_PARAMETER_TYPE_ERROR_CODE,
                             message: e.to_string(),
@@ -191,7 +323,7 @@ starlark_module! { file_resource_env =>
                 },
                 "PythonResourceData" => {
                     let m = resource.downcast_apply(|m: &PythonResourceData| m.data.clone());
-                    manifest.add_resource_data(&prefix, &m).or_else(|e| {
+                    manifest.add_resource_data(&prefix, &m, &rule).or_else(|e| {
                         Err(RuntimeError {
                             code: INCORRECT_PARAMETER_TYPE_ERROR_CODE,
                             message: e.to_string(),
@@ -201,9 +333,13 @@ starlark_module! { file_resource_env =>
                 },
                 "PythonExtensionModule" => {
                     let m = resource.downcast_apply(|m: &PythonExtensionModule| m.em.clone());
-                    manifest.add_extension_module(&prefix, &m);
-
-                    Ok(())
+                    manifest.add_extension_module(&prefix, &m, &rule).or_else(|e| {
+                        Err(RuntimeError {
+                            code: INCORRECT_PARAMETER_TYPE_ERROR_CODE,
+                            message: e.to_string(),
+                            label: e.to_string(),
+                        }.into())
+                    })
                 },
                 "PythonExecutable" => {
                     let context = env.get("CONTEXT").expect("CONTEXT not defined");
@@ -211,9 +347,20 @@ starlark_module! { file_resource_env =>
                         (x.logger.clone(), x.build_target.clone())
                     });
 
+                    // Config() is usually evaluated later. Until it is, only
+                    // RUSTFLAGS of the environment apply.
+                    let env_rustflags = std::env::var("RUSTFLAGS").ok();
+                    let env_rustflags = env_rustflags.as_ref().map(String::as_str);
+                    let rustflags = match env.get("CONFIG") {
+                        Ok(config) if config.get_type() == "Config" => config.downcast_apply(|x: &Config| {
+                            x.config.build_config.resolve_rustflags(env_rustflags, true).0
+                        }),
+                        _ => normalize_env_rustflags(env_rustflags),
+                    };
+
                     let raw_exe = resource.0.borrow();
                     let exe = raw_exe.as_any().downcast_ref::<PreBuiltPythonExecutable>().unwrap();
-                    manifest.add_python_executable(&logger, &prefix, exe, &target).or_else(|e|
+                    manifest.add_python_executable(&logger, &prefix, exe, &target, &rustflags, &rule).or_else(|e|
                         Err(RuntimeError {
                             code: "PYOXIDIZER_BUILD",
                             message: e.to_string(),
@@ -246,26 +393,63 @@ starlark_module! { file_resource_env =>
     }
 
     #[allow(clippy::ptr_arg)]
-    FileManifest.install(env env, this, path, replace=true) {
+    FileManifest.add_sqlite_extension(env env, this, prefix, path) {
+        let prefix = required_str_arg("prefix", &prefix)?;
+        let path = required_str_arg("path", &path)?;
+        let rule = format!("add_sqlite_extension({})", path);
+
+        let path = resolve_resource_path_arg(&env, "add_sqlite_extension()", &path, false)?;
+
+        this.downcast_apply_mut(|manifest: &mut FileManifest| {
+            manifest.add_sqlite_extension(&prefix, &path, &rule)
+        }).or_else(|e| Err(RuntimeError {
+            code: INCORRECT_PARAMETER_TYPE_ERROR_CODE,
+            message: e.to_string(),
+            label: "add_sqlite_extension()".to_string(),
+        }.into()))?;
+
+        Ok(Value::new(None))
+    }
+
+    #[allow(clippy::ptr_arg)]
+    FileManifest.install(env env, this, path, replace=true, case_collision_policy="error") {
         let path = required_str_arg("path", &path)?;
         let replace = required_bool_arg("replace", &replace)?;
+        let case_collision_policy = required_str_arg("case_collision_policy", &case_collision_policy)?;
+
+        let policy = CaseCollisionPolicy::parse(&case_collision_policy).or_else(|e| {
+            Err(RuntimeError {
+                code: INCORRECT_PARAMETER_TYPE_ERROR_CODE,
+                message: e.to_string(),
+                label: "FileManifest.install()".to_string(),
+            }
+            .into())
+        })?;
 
         let context = env.get("CONTEXT").expect("CONTEXT not defined");
-        let build_path = context.downcast_apply(|x: &EnvironmentContext| x.build_path.clone());
+        let (logger, build_path, target) = context.downcast_apply(|x: &EnvironmentContext| {
+            (x.logger.clone(), x.build_path.clone(), x.build_target.clone())
+        });
 
         let dest_path = build_path.join(path);
 
-        this.downcast_apply(|manifest: &FileManifest| {
+        this.downcast_apply(|manifest: &FileManifest| -> Result<()> {
+            let mut manifest = manifest.manifest.clone();
+
+            for path in manifest.resolve_case_collisions(&target, policy)? {
+                warn!(logger, "not installing {} due to a case collision", path.display());
+            }
+
             if replace {
-                manifest.manifest.replace_path(&dest_path)
+                manifest.replace_path(&dest_path)
             } else {
-                manifest.manifest.write_to_path(&dest_path)
+                manifest.write_to_path(&dest_path)
             }
-        }.or_else(|e| Err(RuntimeError {
+        }).or_else(|e| Err(RuntimeError {
             code: "PYOXIDIZER_INSTALL",
             message: format!("error installing FileManifest: {}", e),
             label: "FileManifest.install()".to_string()
-        }.into())))?;
+        }.into()))?;
 
         Ok(Value::new(None))
     }
@@ -362,6 +546,65 @@ mod tests {
         });
     }
 
+    #[test]
+    fn test_add_python_extension_module() -> Result<()> {
+        let temp_dir = tempdir::TempDir::new("pyoxidizer-test")?;
+        let library_path = temp_dir
+            .path()
+            .join("_fast.cpython-37m-x86_64-linux-gnu.so");
+        std::fs::write(&library_path, b"library")?;
+
+        let m = Value::new(FileManifest {
+            manifest: RawFileManifest::default(),
+        });
+
+        let v = Value::new(PythonExtensionModule {
+            em: ExtensionModule {
+                module: "foo._fast".to_string(),
+                init_fn: Some("PyInit__fast".to_string()),
+                builtin_default: false,
+                disableable: true,
+                object_paths: vec![],
+                static_library: None,
+                shared_library: Some(library_path),
+                links: vec![],
+                required: false,
+                variant: "default".to_string(),
+                licenses: None,
+                license_paths: None,
+                license_public_domain: None,
+            },
+        });
+
+        let mut env = starlark_env();
+        env.set("m", m).unwrap();
+        env.set("v", v).unwrap();
+
+        starlark_eval_in_env(&mut env, "m.add_python_resource('lib', v)").unwrap();
+
+        let m = env.get("m").unwrap();
+        m.downcast_apply(|m: &FileManifest| {
+            let mut entries = m.manifest.entries();
+            let (p, c) = entries.next().unwrap();
+
+            assert_eq!(
+                p,
+                &PathBuf::from("lib/foo/_fast.cpython-37m-x86_64-linux-gnu.so")
+            );
+            assert_eq!(
+                c,
+                &RawFileContent {
+                    data: b"library".to_vec(),
+                    executable: true,
+                }
+            );
+
+            assert!(entries.next().is_none());
+        });
+
+        Ok(())
+    }
+
     #[test]
     fn test_add_python_resources() {
         starlark_ok("dist = default_python_distribution(); m = FileManifest(); m.add_python_resources('lib', dist.source_modules())");
@@ -430,4 +673,116 @@ mod tests {
 
         assert!(app_exe.exists());
     }
+
+    #[test]
+    fn test_install_case_collisions() {
+        let mut env = starlark_env();
+
+        let mut context = env
+            .get("CONTEXT")
+            .unwrap()
+            .downcast_apply(|x: &EnvironmentContext| x.clone());
+        context.build_target = "x86_64-pc-windows-msvc".to_string();
+        env.set("CONTEXT", Value::new(context.clone())).unwrap();
+
+        let module = |name: &str| {
+            Value::new(PythonSourceModule {
+                module: SourceModule {
+                    name: name.to_string(),
+                    source: name.as_bytes().to_vec(),
+                    is_package: false,
+                },
+            })
+        };
+
+        env.set(
+            "m",
+            Value::new(FileManifest {
+                manifest: RawFileManifest::default(),
+            }),
+        )
+        .unwrap();
+        env.set("a", module("PIL.Image")).unwrap();
+        env.set("b", module("PIL.image")).unwrap();
+
+        starlark_eval_in_env(&mut env, "m.add_python_resources('lib', [a, b])").unwrap();
+
+        let err = starlark_eval_in_env(&mut env, "m.install('case_collisions')").unwrap_err();
+        assert!(err.message.contains(
+            "lib/PIL/Image.py (from add_python_resource(PythonSourceModule<name=PIL.Image>))"
+        ));
+        assert!(err.message.contains(
+            "lib/PIL/image.py (from add_python_resource(PythonSourceModule<name=PIL.image>))"
+        ));
+
+        starlark_eval_in_env(
+            &mut env,
+            "m.install('case_collisions', case_collision_policy='first-wins')",
+        )
+        .unwrap();
+
+        let dest_path = context
+            .build_path
+            .join("case_collisions")
+            .join("lib")
+            .join("PIL");
+        let names = std::fs::read_dir(&dest_path)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec!["Image.py"]);
+        assert_eq!(
+            std::fs::read(dest_path.join("Image.py")).unwrap(),
+            b"PIL.Image".to_vec()
+        );
+
+        starlark_eval_in_env(
+            &mut env,
+            "m.install('case_collisions', case_collision_policy='last-wins')",
+        )
+        .unwrap_err();
+    }
+
+    #[test]
+    fn test_add_sqlite_extension() {
+        let temp_dir = tempdir::TempDir::new("pyoxidizer-test").unwrap();
+        let extension = temp_dir.path().join("mod_spatialite.so");
+        std::fs::write(&extension, b"extension").unwrap();
+        let other = temp_dir.path().join("mod_spatialite.txt");
+        std::fs::write(&other, b"").unwrap();
+
+        let mut env = starlark_env();
+        starlark_eval_in_env(&mut env, "m = FileManifest()").unwrap();
+        starlark_eval_in_env(
+            &mut env,
+            &format!(
+                "m.add_sqlite_extension('app', {:?})",
+                extension.display().to_string()
+            ),
+        )
+        .unwrap();
+
+        let m = env.get("m").unwrap();
+        m.downcast_apply(|m: &FileManifest| {
+            let entries = m.manifest.entries().collect::<Vec<_>>();
+            assert_eq!(entries.len(), 1);
+            assert_eq!(
+                entries[0].0,
+                &PathBuf::from("app/lib/sqlite-extensions/mod_spatialite.so")
+            );
+            assert!(entries[0].1.executable);
+        });
+
+        let err = starlark_eval_in_env(
+            &mut env,
+            &format!(
+                "m.add_sqlite_extension('app', {:?})",
+                other.display().to_string()
+            ),
+        )
+        .unwrap_err();
+        assert!(err
+            .message
+            .ends_with("mod_spatialite.txt is not a shared library (.so, .dylib or .dll)"));
+    }
 }
@@ -0,0 +1,359 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/*!
+Embedding compiled gettext message catalogs.
+
+`locale_resources()` collects `<locale>/LC_MESSAGES/<domain>.mo` files
+from a locale directory as resource data of a Python package, named
+`locale/<locale>/LC_MESSAGES/<domain>.mo`. `oxidized_app.translation()`
+loads these catalogs at run time.
+*/
+
+use anyhow::{anyhow, Result};
+use slog::warn;
+use starlark::environment::Environment;
+use starlark::values::{RuntimeError, Value, ValueError, ValueResult};
+use starlark::{
+    starlark_fun, starlark_module, starlark_signature, starlark_signature_extraction,
+    starlark_signatures,
+};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use super::env::{
+    optional_list_arg, optional_str_arg, record_input_path, required_list_arg, required_str_arg,
+    resolve_resource_path_arg,
+};
+use super::python_resource::PythonResourceData;
+use crate::app_packaging::environment::EnvironmentContext;
+use crate::py_packaging::resource::ResourceData;
+
+/// Error code for locale directories that cannot be read.
+pub const LOCALE_ERROR_CODE: &str = "LOCALE";
+
+/// A compiled message catalog in a locale directory.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LocaleCatalog {
+    pub locale: String,
+    pub domain: String,
+    pub path: PathBuf,
+}
+
+impl LocaleCatalog {
+    /// The resource name of the catalog relative to its package.
+    pub fn resource_name(&self) -> String {
+        format!("locale/{}/LC_MESSAGES/{}.mo", self.locale, self.domain)
+    }
+}
+
+/// Find compiled message catalogs of domains in a locale directory.
+///
+/// If `locales` is `None`, all locales having a catalog are included.
+/// Returns the catalogs and the requested `(locale, domain)` pairs without
+/// a catalog.
+pub fn find_locale_catalogs(
+    dir: &Path,
+    domains: &[String],
+    locales: Option<Vec<String>>,
+) -> Result<(Vec<LocaleCatalog>, Vec<(String, String)>)> {
+    let locales = match locales {
+        Some(locales) => locales,
+        None => {
+            let mut locales = Vec::new();
+
+            for entry in std::fs::read_dir(dir)
+                .map_err(|e| anyhow!("unable to read {}: {}", dir.display(), e))?
+            {
+                let entry = entry?;
+                if entry.path().join("LC_MESSAGES").is_dir() {
+                    locales.push(entry.file_name().to_string_lossy().to_string());
+                }
+            }
+
+            locales.sort();
+            locales
+        }
+    };
+
+    let mut catalogs = Vec::new();
+    let mut missing = Vec::new();
+
+    for locale in &locales {
+        for domain in domains {
+            let path = dir
+                .join(locale)
+                .join("LC_MESSAGES")
+                .join(format!("{}.mo", domain));
+
+            if path.is_file() {
+                catalogs.push(LocaleCatalog {
+                    locale: locale.clone(),
+                    domain: domain.clone(),
+                    path,
+                });
+            } else {
+                missing.push((locale.clone(), domain.clone()));
+            }
+        }
+    }
+
+    Ok((catalogs, missing))
+}
+
+fn locale_error(message: String) -> ValueError {
+    RuntimeError {
+        code: LOCALE_ERROR_CODE,
+        message,
+        label: "locale_resources()".to_string(),
+    }
+    .into()
+}
+
+starlark_module! { locale_module =>
+    #[allow(clippy::ptr_arg)]
+    locale_resources(env env, path, domains, locales=None, package=None) {
+        let path = required_str_arg("path", &path)?;
+        required_list_arg("domains", "string", &domains)?;
+        optional_list_arg("locales", "string", &locales)?;
+        let package = optional_str_arg("package", &package)?;
+
+        let dir = resolve_resource_path_arg(&env, "locale_resources()", &path, false)?;
+        let domains = domains.into_iter()?.map(|x| x.to_string()).collect::<Vec<_>>();
+        let locales = match locales.get_type() {
+            "list" => Some(locales.into_iter()?.map(|x| x.to_string()).collect::<Vec<_>>()),
+            _ => None,
+        };
+
+        let (catalogs, missing) = find_locale_catalogs(&dir, &domains, locales)
+            .map_err(|e| locale_error(e.to_string()))?;
+
+        let context = env.get("CONTEXT").expect("CONTEXT not defined");
+        let logger = context.downcast_apply(|x: &EnvironmentContext| x.logger.clone());
+
+        for (locale, domain) in missing {
+            warn!(logger, "no gettext catalog for domain {} in locale {} in {}", domain, locale, dir.display());
+        }
+
+        let mut resources = Vec::new();
+
+        for catalog in catalogs {
+            let data = std::fs::read(&catalog.path)
+                .map_err(|e| locale_error(format!("unable to read {}: {}", catalog.path.display(), e)))?;
+            record_input_path(&env, &catalog.path);
+
+            resources.push(Value::new(PythonResourceData {
+                data: ResourceData {
+                    package: package.clone().unwrap_or_else(|| catalog.domain.clone()),
+                    name: catalog.resource_name(),
+                    data,
+                },
+            }));
+        }
+
+        Ok(Value::from(resources))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::testutil::*;
+    use super::*;
+    use crate::testutil::*;
+    use std::collections::BTreeMap;
+
+    /// Produce a compiled message catalog in the GNU .mo format.
+    fn mo_catalog(messages: &[(&str, &str)]) -> Vec<u8> {
+        let mut messages = messages
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect::<BTreeMap<_, _>>();
+        messages.insert(
+            "".to_string(),
+            "Content-Type: text/plain; charset=UTF-8\n".to_string(),
+        );
+
+        let count = messages.len() as u32;
+        let originals_offset = 28;
+        let translations_offset = originals_offset + count * 8;
+        let mut data_offset = translations_offset + count * 8;
+
+        let mut tables = Vec::new();
+        let mut data = Vec::new();
+
+        for s in messages.keys().chain(messages.values()) {
+            tables.extend_from_slice(&(s.len() as u32).to_le_bytes());
+            tables.extend_from_slice(&data_offset.to_le_bytes());
+
+            data.extend_from_slice(s.as_bytes());
+            data.push(0);
+            data_offset += s.len() as u32 + 1;
+        }
+
+        let mut res = Vec::new();
+        for v in &[
+            0x9504_12de_u32,
+            0,
+            count,
+            originals_offset,
+            translations_offset,
+            0,
+            0,
+        ] {
+            res.extend_from_slice(&v.to_le_bytes());
+        }
+        res.extend(tables);
+        res.extend(data);
+
+        res
+    }
+
+    fn write_locale_dir(dir: &Path) -> Result<()> {
+        for (locale, hello) in &[("de", "Hallo"), ("fr", "Bonjour")] {
+            let messages = dir.join(locale).join("LC_MESSAGES");
+            std::fs::create_dir_all(&messages)?;
+            std::fs::write(
+                messages.join("myapp.mo"),
+                mo_catalog(&[("hello", hello), ("goodbye", "...")]),
+            )?;
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_find_locale_catalogs() -> Result<()> {
+        let temp_dir = tempdir::TempDir::new("pyoxidizer-test")?;
+        let dir = temp_dir.path();
+        write_locale_dir(dir)?;
+        std::fs::create_dir_all(dir.join("es"))?;
+
+        let domains = vec!["myapp".to_string()];
+
+        let (catalogs, missing) = find_locale_catalogs(dir, &domains, None)?;
+        assert_eq!(
+            catalogs
+                .iter()
+                .map(|c| c.resource_name())
+                .collect::<Vec<_>>(),
+            vec![
+                "locale/de/LC_MESSAGES/myapp.mo",
+                "locale/fr/LC_MESSAGES/myapp.mo"
+            ]
+        );
+        assert!(missing.is_empty());
+
+        let locales = vec!["en".to_string(), "de".to_string()];
+        let (catalogs, missing) = find_locale_catalogs(dir, &domains, Some(locales))?;
+        assert_eq!(catalogs.len(), 1);
+        assert_eq!(catalogs[0].locale, "de");
+        assert_eq!(missing, vec![("en".to_string(), "myapp".to_string())]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_locale_resources() -> Result<()> {
+        let temp_dir = tempdir::TempDir::new("pyoxidizer-test")?;
+        let dir = temp_dir.path().join("locale");
+        write_locale_dir(&dir)?;
+        let path = dir.display().to_string().replace("\\", "/");
+
+        let mut env = starlark_env();
+        let v = starlark_eval_in_env(
+            &mut env,
+            &format!(
+                "locale_resources('{}', domains=['myapp'], locales=['en', 'de', 'fr'])",
+                path
+            ),
+        )
+        .unwrap();
+        assert_eq!(v.get_type(), "list");
+        assert_eq!(
+            v.into_iter()
+                .unwrap()
+                .map(|x| x.to_str())
+                .collect::<Vec<_>>(),
+            vec![
+                "PythonResourceData<package=myapp, name=locale/de/LC_MESSAGES/myapp.mo>",
+                "PythonResourceData<package=myapp, name=locale/fr/LC_MESSAGES/myapp.mo>",
+            ]
+        );
+
+        let input_paths = env
+            .get("CONTEXT")
+            .unwrap()
+            .downcast_apply(|x: &EnvironmentContext| x.input_paths.clone());
+        assert!(input_paths.contains(&dir.join("de").join("LC_MESSAGES").join("myapp.mo")));
+
+        let v = starlark_ok(&format!(
+            "locale_resources('{}', domains=['myapp'], package='myapp.i18n')[0].package",
+            path
+        ));
+        assert_eq!(v.to_str(), "myapp.i18n");
+
+        starlark_nok("locale_resources('/does/not/exist', domains=['myapp'])");
+        starlark_nok(&format!("locale_resources('{}', domains='myapp')", path));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_translation_shim() -> Result<()> {
+        let temp_dir = tempdir::TempDir::new("pyoxidizer-test")?;
+        let dir = temp_dir.path();
+        write_locale_dir(&dir.join("locale"))?;
+
+        // Catalogs only present on the filesystem are found as a fallback.
+        let messages = dir.join("fs").join("es").join("LC_MESSAGES");
+        std::fs::create_dir_all(&messages)?;
+        std::fs::write(messages.join("myapp.mo"), mo_catalog(&[("hello", "Hola")]))?;
+
+        std::fs::write(
+            dir.join("oxidized_app.py"),
+            include_str!("../pyembed/oxidized_app.py"),
+        )?;
+
+        // Emulate the resource reader of the in-memory importer.
+        std::fs::write(
+            dir.join("test.py"),
+            "import io, os, sys, types\n\
+             m = types.ModuleType('oxidized_app')\n\
+             exec(open(os.path.join(sys.argv[1], 'oxidized_app.py')).read(), m.__dict__)\n\
+             class Reader:\n\
+             \x20   def open_resource(self, name):\n\
+             \x20       return open(os.path.join(sys.argv[1], name), 'rb')\n\
+             class Finder:\n\
+             \x20   def find_spec(self, *args):\n\
+             \x20       return None\n\
+             \x20   def get_resource_reader(self, package):\n\
+             \x20       return Reader() if package == 'myapp' else None\n\
+             sys.meta_path.insert(0, Finder())\n\
+             for languages in (['de'], ['fr_FR.UTF-8'], ['en', 'fr']):\n\
+             \x20   print(m.translation('myapp', languages=languages).gettext('hello'))\n\
+             fs = os.path.join(sys.argv[1], 'fs')\n\
+             print(m.translation('myapp', localedir=fs, languages=['es']).gettext('hello'))\n\
+             print(m.translation('myapp', languages=['en'], fallback=True).gettext('hello'))\n",
+        )?;
+
+        let dist = get_default_distribution()?;
+        let output = std::process::Command::new(&dist.python_exe)
+            .arg(dir.join("test.py"))
+            .arg(dir)
+            .output()?;
+        assert!(
+            output.status.success(),
+            "{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        assert_eq!(
+            String::from_utf8_lossy(&output.stdout)
+                .lines()
+                .collect::<Vec<_>>(),
+            vec!["Hallo", "Bonjour", "Bonjour", "Hola", "hello"]
+        );
+
+        Ok(())
+    }
+}
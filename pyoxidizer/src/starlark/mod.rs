@@ -8,14 +8,20 @@ The `starlark` module and related sub-modules define the
 define Oxidized Python binaries.
 */
 
+pub mod archive_resources;
 pub mod config;
+pub mod data_file;
+pub mod embedded_config;
 pub mod embedded_python_config;
 pub mod env;
 pub mod eval;
 pub mod file_resource;
+pub mod locale;
 pub mod python_distribution;
 pub mod python_executable;
 pub mod python_resource;
 pub mod python_run_mode;
+pub mod rust_extension;
+pub mod select;
 #[cfg(test)]
 mod testutil;
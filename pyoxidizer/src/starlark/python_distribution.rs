@@ -16,7 +16,7 @@ use starlark::{
 };
 use std::any::Any;
 use std::cmp::Ordering;
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap};
 use std::convert::TryFrom;
 use std::io::{BufRead, BufReader};
 use std::path::{Path, PathBuf};
@@ -24,13 +24,20 @@ use std::sync::Arc;
 
 use super::env::{
     optional_dict_arg, optional_list_arg, optional_str_arg, required_bool_arg, required_list_arg,
-    required_str_arg,
+    required_str_arg, resolve_path_arg,
 };
 use super::python_resource::{
     PythonEmbeddedResources, PythonExtensionModule, PythonResourceData, PythonSourceModule,
 };
 use crate::app_packaging::environment::EnvironmentContext;
+use crate::licensing::{ExtensionModuleLicensePolicy, LicenseConflictAction};
+use crate::py_packaging::abi::{find_incompatible_artifacts, incompatibility_error};
+use crate::py_packaging::build_system::{
+    check_compiled_builds, compiled_build_envs, cross_compile_error, find_compiled_build,
+    is_cross_compiling, read_refused_builds,
+};
 use crate::py_packaging::bytecode::{BytecodeCompiler, CompileMode};
+use crate::py_packaging::compatibility::{PackageLocation, PackageLocationPolicy};
 use crate::py_packaging::distribution::{
     is_stdlib_test_package, resolve_parsed_distribution, resolve_python_paths,
     ExtensionModuleFilter, ParsedPythonDistribution, PythonDistributionLocation,
@@ -38,9 +45,10 @@ use crate::py_packaging::distribution::{
 use crate::py_packaging::distutils::{prepare_hacked_distutils, read_built_extensions};
 use crate::py_packaging::embedded_resource::EmbeddedPythonResourcesPrePackaged;
 use crate::py_packaging::fsscan::{find_python_resources, PythonFileResource};
-use crate::py_packaging::pip::pip_install as raw_pip_install;
+use crate::py_packaging::pip::{pip_install as raw_pip_install, VersionMismatchPolicy};
 use crate::py_packaging::resource::{BytecodeOptimizationLevel, PythonResource};
-use crate::python_distributions::CPYTHON_BY_TRIPLE;
+use crate::py_packaging::sqlite::normalize_option;
+use crate::python_distributions::{no_default_distribution_message, CPYTHON_BY_TRIPLE};
 
 #[derive(Debug)]
 pub struct PythonDistribution {
@@ -48,18 +56,28 @@ pub struct PythonDistribution {
 
     dest_dir: PathBuf,
 
+    offline: bool,
+
     pub distribution: Option<Arc<ParsedPythonDistribution>>,
 
     compiler: Option<BytecodeCompiler>,
+
+    license_policy_applied: bool,
 }
 
 impl PythonDistribution {
-    fn from_location(location: PythonDistributionLocation, dest_dir: &Path) -> PythonDistribution {
+    fn from_location(
+        location: PythonDistributionLocation,
+        dest_dir: &Path,
+        offline: bool,
+    ) -> PythonDistribution {
         PythonDistribution {
             source: location,
             dest_dir: dest_dir.to_path_buf(),
+            offline,
             distribution: None,
             compiler: None,
+            license_policy_applied: false,
         }
     }
 
@@ -68,12 +86,40 @@ impl PythonDistribution {
             return;
         }
 
-        let dist = resolve_parsed_distribution(logger, &self.source, &self.dest_dir).unwrap();
+        let dist = resolve_parsed_distribution(logger, &self.source, &self.dest_dir, self.offline)
+            .unwrap();
         warn!(logger, "distribution info: {:#?}", dist.as_minimal_info());
 
         self.distribution = Some(Arc::new(dist));
     }
 
+    /// Resolve the distribution and restrict its extension modules to licenses
+    /// allowed by `extension_module_license_filter()`.
+    ///
+    /// The policy is applied before extension modules are first selected and
+    /// not again afterwards.
+    pub fn ensure_license_policy_applied(
+        &mut self,
+        logger: &slog::Logger,
+        policy: Option<&ExtensionModuleLicensePolicy>,
+    ) -> Result<()> {
+        self.ensure_distribution_resolved(logger);
+
+        if self.license_policy_applied {
+            return Ok(());
+        }
+
+        if let Some(policy) = policy {
+            let dist = Arc::get_mut(self.distribution.as_mut().unwrap())
+                .ok_or_else(|| anyhow!("distribution is in use; cannot apply license policy"))?;
+            policy.apply(logger, &mut dist.extension_modules)?;
+        }
+
+        self.license_policy_applied = true;
+
+        Ok(())
+    }
+
     /// Compile bytecode using this distribution.
     ///
     /// A bytecode compiler will be lazily instantiated and preserved for the
@@ -134,7 +180,28 @@ impl TypedValue for PythonDistribution {
     }
 }
 
-fn find_resources(path: &Path, state_dir: Option<&Path>) -> Result<Vec<PythonResource>> {
+/// Find resources in a directory.
+///
+/// Artifacts incompatible with `python_version` are an error. If `packages`
+/// is defined, only extension modules in those packages are checked.
+fn find_resources(
+    path: &Path,
+    state_dir: Option<&Path>,
+    python_version: &str,
+    packages: Option<&[String]>,
+) -> Result<Vec<PythonResource>> {
+    let incompatible = find_incompatible_artifacts(path, python_version)?
+        .into_iter()
+        .filter(|i| match (packages, &i.module) {
+            (None, _) => true,
+            (Some(packages), Some(module)) => packages
+                .iter()
+                .any(|p| module == p || module.starts_with(&format!("{}.", p))),
+            (Some(_), None) => false,
+        })
+        .collect::<Vec<_>>();
+    incompatibility_error(&incompatible, python_version)?;
+
     let mut res = Vec::new();
 
     for r in find_python_resources(&path) {
@@ -160,6 +227,27 @@ fn find_resources(path: &Path, state_dir: Option<&Path>) -> Result<Vec<PythonRes
     Ok(res)
 }
 
+/// Apply the license policy of the config to a distribution.
+pub fn apply_license_policy(
+    env: &Environment,
+    logger: &slog::Logger,
+    dist: &mut PythonDistribution,
+) -> Result<(), ValueError> {
+    let context = env.get("CONTEXT").expect("CONTEXT not defined");
+    let policy =
+        context.downcast_apply(|x: &EnvironmentContext| x.extension_module_license_policy.clone());
+
+    dist.ensure_license_policy_applied(logger, policy.as_ref())
+        .or_else(|e| {
+            Err(RuntimeError {
+                code: "LICENSE_POLICY",
+                message: e.to_string(),
+                label: "extension_module_license_filter()".to_string(),
+            }
+            .into())
+        })
+}
+
 pub fn resolve_default_python_distribution(env: &Environment, build_target: &str) -> ValueResult {
     match CPYTHON_BY_TRIPLE.get(build_target) {
         Some(dist) => {
@@ -171,23 +259,54 @@ pub fn resolve_default_python_distribution(env: &Environment, build_target: &str
             let context = env.get("CONTEXT").expect("CONTEXT not defined");
             let dest_dir = context
                 .downcast_apply(|x: &EnvironmentContext| x.python_distributions_path.clone());
+            let offline = context.downcast_apply(|x: &EnvironmentContext| x.offline);
 
             Ok(Value::new(PythonDistribution::from_location(
                 distribution,
                 &dest_dir,
+                offline,
             )))
         }
         None => Err(ValueError::Runtime(RuntimeError {
             code: "no_default_distribution",
-            message: format!(
-                "could not find default Python distribution for {}",
-                build_target
-            ),
+            message: no_default_distribution_message(build_target),
             label: "build_target".to_string(),
         })),
     }
 }
 
+/// Resolve paths to requirements and constraints files in `pip install` arguments.
+///
+/// Paths are resolved relative to the configuration file and must exist.
+fn resolve_pip_path_args(env: &Environment, args: &[String]) -> Result<Vec<String>, ValueError> {
+    const PATH_FLAGS: &[&str] = &["-r", "--requirement", "-c", "--constraint"];
+
+    let mut res = Vec::new();
+    let mut iter = args.iter();
+
+    while let Some(arg) = iter.next() {
+        if PATH_FLAGS.contains(&arg.as_str()) {
+            res.push(arg.clone());
+
+            if let Some(path) = iter.next() {
+                let path = resolve_resource_path_arg(env, "pip_install", path, false)?;
+                res.push(path.display().to_string());
+            }
+        } else if let Some(flag) = PATH_FLAGS
+            .iter()
+            .find(|flag| flag.starts_with("--") && arg.starts_with(&format!("{}=", flag)))
+        {
+            let path =
+                resolve_resource_path_arg(env, "pip_install", &arg[flag.len() + 1..], false)?;
+            res.push(format!("{}={}", flag, path.display()));
+        } else {
+            res.push(arg.clone());
+        }
+    }
+
+    Ok(res)
+}
+
 starlark_module! { python_distribution_module =>
     #[allow(non_snake_case, clippy::ptr_arg)]
     PythonDistribution(env env, sha256, local_path=None, url=None) {
@@ -204,8 +323,10 @@ starlark_module! { python_distribution_module =>
         }
 
         let distribution = if local_path.get_type() != "NoneType" {
+            let local_path = resolve_path_arg(&env, "PythonDistribution", &local_path.to_string(), false)?;
+
             PythonDistributionLocation::Local {
-                local_path: local_path.to_string(),
+                local_path: local_path.display().to_string(),
                 sha256: sha256.to_string(),
             }
         } else {
@@ -217,8 +338,9 @@ starlark_module! { python_distribution_module =>
 
         let context = env.get("CONTEXT").expect("CONTEXT not defined");
         let dest_dir = context.downcast_apply(|x: &EnvironmentContext| x.python_distributions_path.clone());
+        let offline = context.downcast_apply(|x: &EnvironmentContext| x.offline);
 
-        Ok(Value::new(PythonDistribution::from_location(distribution, &dest_dir)))
+        Ok(Value::new(PythonDistribution::from_location(distribution, &dest_dir, offline)))
     }
 
     #[allow(clippy::ptr_arg)]
@@ -251,13 +373,13 @@ starlark_module! { python_distribution_module =>
 
         let logger = context.downcast_apply(|x: &EnvironmentContext| x.logger.clone());
 
-        Ok(Value::from(this.downcast_apply_mut(|dist: &mut PythonDistribution| {
-            dist.ensure_distribution_resolved(&logger);
+        this.downcast_apply_mut(|dist: &mut PythonDistribution| {
+            apply_license_policy(env, &logger, dist)?;
 
-            dist.distribution.as_ref().unwrap().filter_extension_modules(&logger, &filter, preferred_variants.clone()).iter().map(|em| {
+            Ok(Value::from(dist.distribution.as_ref().unwrap().filter_extension_modules(&logger, &filter, preferred_variants.clone()).iter().map(|em| {
                 Value::new(PythonExtensionModule { em: em.clone() })
-            }).collect_vec()
-        })))
+            }).collect_vec()))
+        })
     }
 
     #[allow(clippy::ptr_arg)]
@@ -309,11 +431,37 @@ starlark_module! { python_distribution_module =>
     }
 
     #[allow(clippy::ptr_arg)]
-    PythonDistribution.pip_install(env env, this, args, extra_envs=None) {
+    PythonDistribution.pip_install(
+        env env,
+        this,
+        args,
+        extra_envs=None,
+        verify_versions="error",
+        constraints=None,
+        force_in_memory=None,
+        package_location_overrides=None
+    ) {
         required_list_arg("args", "string", &args)?;
+        optional_str_arg("constraints", &constraints)?;
         optional_dict_arg("extra_envs", "string", "string", &extra_envs)?;
+        optional_list_arg("force_in_memory", "string", &force_in_memory)?;
+        optional_dict_arg("package_location_overrides", "string", "string", &package_location_overrides)?;
+        let verify_versions = match required_str_arg("verify_versions", &verify_versions)?.as_str() {
+            "error" => VersionMismatchPolicy::Error,
+            "warn" => VersionMismatchPolicy::Warn,
+            v => return Err(RuntimeError {
+                code: INCORRECT_PARAMETER_TYPE_ERROR_CODE,
+                message: format!("verify_versions must be 'error' or 'warn'; got {}", v),
+                label: "verify_versions".to_string(),
+            }.into()),
+        };
 
         let args: Vec<String> = args.into_iter()?.map(|x| x.to_string()).collect();
+        let args = resolve_pip_path_args(&env, &args)?;
+        let constraints = match constraints.get_type() {
+            "NoneType" => None,
+            _ => Some(resolve_resource_path_arg(&env, "pip_install", &constraints.to_string(), false)?),
+        };
 
         let extra_envs = match extra_envs.get_type() {
             "dict" => extra_envs.into_iter()?.map(|key| {
@@ -325,15 +473,34 @@ starlark_module! { python_distribution_module =>
             _ => panic!("should have validated type above"),
         };
 
-        let context = env.get("CONTEXT").expect("CONTEXT not defined");
+        let mut location_policy = PackageLocationPolicy::default();
+        if force_in_memory.get_type() == "list" {
+            location_policy.force_in_memory = force_in_memory.into_iter()?.map(|x| x.to_string()).collect();
+        }
+        if package_location_overrides.get_type() == "dict" {
+            for key in package_location_overrides.into_iter()? {
+                let location = package_location_overrides.at(key.clone())?.to_string();
+                let location = PackageLocation::from_str(&location).or_else(|e| Err(RuntimeError {
+                    code: INCORRECT_PARAMETER_TYPE_ERROR_CODE,
+                    message: e.to_string(),
+                    label: "package_location_overrides".to_string(),
+                }.into()))?;
+
+                location_policy.overrides.insert(key.to_string(), location);
+            }
+        }
+
+        let mut context = env.get("CONTEXT").expect("CONTEXT not defined");
         let logger = context.downcast_apply(|x: &EnvironmentContext| x.logger.clone());
+        let build_target = context.downcast_apply(|x: &EnvironmentContext| x.build_target.clone());
+        let offline = context.downcast_apply(|x: &EnvironmentContext| x.offline);
 
-        let resources = this.downcast_apply_mut(|dist: &mut PythonDistribution| {
+        let result = this.downcast_apply_mut(|dist: &mut PythonDistribution| {
             dist.ensure_distribution_resolved(&logger);
 
             let dist = dist.distribution.as_ref().unwrap();
             // TODO get verbose flag from context.
-            raw_pip_install(&logger, &dist, false, &args, &extra_envs)
+            raw_pip_install(&logger, &dist, &build_target, false, offline, &args, constraints.as_ref().map(|p| p.as_path()), &extra_envs, verify_versions)
         }).or_else(|e| Err(
             RuntimeError {
                 code: "PIP_INSTALL_ERROR",
@@ -342,7 +509,26 @@ starlark_module! { python_distribution_module =>
             }.into()
         ))?;
 
-        Ok(Value::from(resources.iter().map(Value::from).collect::<Vec<Value>>()))
+        let packages = result.resources.iter().map(|r| r.top_level_package()).collect::<BTreeSet<_>>();
+
+        context.downcast_apply_mut(|x: &mut EnvironmentContext| {
+            x.python_package_versions.extend(result.versions.clone());
+            x.python_package_hashes.extend(result.hashes.clone());
+
+            for package in &packages {
+                match location_policy.resolve(package, &result.versions) {
+                    Some(decision) => {
+                        warn!(logger, "packaging {} relative to the executable instead of in memory: {}; set force_in_memory=[\"{}\"] to embed it anyway", decision.package, decision.reason, decision.package);
+                        x.filesystem_relative_packages.insert(decision.package, decision.reason);
+                    }
+                    None => {
+                        x.filesystem_relative_packages.remove(*package);
+                    }
+                }
+            }
+        });
+
+        Ok(Value::from(result.resources.iter().map(Value::from).collect::<Vec<Value>>()))
     }
 
     #[allow(clippy::ptr_arg)]
@@ -350,20 +536,31 @@ starlark_module! { python_distribution_module =>
         env env,
         this,
         path,
-        packages
+        packages,
+        allow_missing=false
     ) {
         let path = required_str_arg("path", &path)?;
         required_list_arg("packages", "string", &packages)?;
+        let allow_missing = required_bool_arg("allow_missing", &allow_missing)?;
+
+        let path = resolve_resource_path_arg(&env, "read_package_root", &path, allow_missing)?;
 
         let packages = packages.into_iter()?.map(|x| x.to_string()).collect::<Vec<String>>();
 
         let context = env.get("CONTEXT").expect("CONTEXT not defined");
         let logger = context.downcast_apply(|x: &EnvironmentContext| x.logger.clone());
 
+        if !path.exists() {
+            warn!(logger, "{} does not exist; no resources will be read", path.display());
+            return Ok(Value::from(Vec::<Value>::new()));
+        }
+
         let resources: Vec<PythonResource> = this.downcast_apply_mut(|dist: &mut PythonDistribution| -> Result<Vec<PythonResource>, ValueError> {
             dist.ensure_distribution_resolved(&logger);
 
-            find_resources(&Path::new(&path), None).or_else(|e| Err(
+            let dist = dist.distribution.as_ref().unwrap();
+
+            find_resources(&path, None, &dist.version, Some(packages.as_slice())).or_else(|e| Err(
                 RuntimeError {
                     code: "PACKAGE_ROOT_ERROR",
                     message: format!("could not find resources: {}", e),
@@ -385,21 +582,30 @@ starlark_module! { python_distribution_module =>
     PythonDistribution.read_virtualenv(
         env env,
         this,
-        path
+        path,
+        allow_missing=false
     ) {
         let path = required_str_arg("path", &path)?;
+        let allow_missing = required_bool_arg("allow_missing", &allow_missing)?;
+
+        let path = resolve_resource_path_arg(&env, "read_virtualenv", &path, allow_missing)?;
 
         let context = env.get("CONTEXT").expect("CONTEXT not defined");
         let logger = context.downcast_apply(|x: &EnvironmentContext| x.logger.clone());
 
+        if !path.exists() {
+            warn!(logger, "{} does not exist; no resources will be read", path.display());
+            return Ok(Value::from(Vec::<Value>::new()));
+        }
+
         let resources = this.downcast_apply_mut(|dist: &mut PythonDistribution| -> Result<Vec<PythonResource>, ValueError> {
             dist.ensure_distribution_resolved(&logger);
 
             let dist = dist.distribution.as_ref().unwrap();
 
-            let python_paths = resolve_python_paths(&Path::new(&path), &dist.version);
+            let python_paths = resolve_python_paths(&path, &dist.version);
 
-            find_resources(&python_paths.site_packages, None).or_else(|e| Err(
+            find_resources(&python_paths.site_packages, None, &dist.version, None).or_else(|e| Err(
                 RuntimeError {
                     code: "VIRTUALENV_ERROR",
                     message: format!("could not find resources: {}", e),
@@ -438,21 +644,29 @@ starlark_module! { python_distribution_module =>
             _ => panic!("should have validated type above"),
         };
 
-        let package_path = PathBuf::from(package_path);
+        let exec_cwd = resolve_resource_path_arg(&env, "setup_py_install", &package_path, false)?;
 
         let context = env.get("CONTEXT").expect("CONTEXT not defined");
-        let cwd = env.get("CWD").expect("CWD not defined").to_string();
         let logger = context.downcast_apply(|x: &EnvironmentContext| x.logger.clone());
+        let build_target = context.downcast_apply(|x: &EnvironmentContext| x.build_target.clone());
+        let host = env!("HOST");
+
+        find_compiled_build(&exec_cwd)
+            .and_then(|build| {
+                let builds = build.into_iter().collect::<Vec<_>>();
+                check_compiled_builds(&logger, &builds, host, &build_target)
+            })
+            .or_else(|e| Err(
+                RuntimeError {
+                    code: "SETUP_PY_ERROR",
+                    message: e.to_string(),
+                    label: "setup_py_install()".to_string(),
+                }.into()
+            ))?;
 
         // TODO most of the logic in this function should ideally be contained within
         // a library function, outside the context of Starlark.
 
-        let exec_cwd = if package_path.is_absolute() {
-            package_path
-        } else {
-            PathBuf::from(cwd).join(package_path)
-        };
-
         let temp_dir = tempdir::TempDir::new("pyoxidizer-setup-py-install").or_else(|e| Err(
             RuntimeError {
                 code: "SETUP_PY_ERROR",
@@ -487,6 +701,12 @@ starlark_module! { python_distribution_module =>
             )
             .expect("unable to hack distutils");
 
+            if is_cross_compiling(host, &build_target) {
+                envs.insert("PYOXIDIZER_CROSS_COMPILING".to_string(), "1".to_string());
+            } else {
+                envs.extend(compiled_build_envs(&dist));
+            }
+
             for (key, value) in &extra_envs {
                 envs.insert(key.clone(), value.clone());
             }
@@ -521,12 +741,20 @@ starlark_module! { python_distribution_module =>
             }
 
             let status = cmd.wait().unwrap();
+            let state_dir = PathBuf::from(envs.get("PYOXIDIZER_DISTUTILS_STATE_DIR").unwrap());
+
             if !status.success() {
-                panic!("error running setup.py");
+                match read_refused_builds(&state_dir) {
+                    Ok(ref refused) if !refused.is_empty() => return Err(RuntimeError {
+                        code: "SETUP_PY_ERROR",
+                        message: cross_compile_error(refused, host, &build_target).to_string(),
+                        label: "setup_py_install()".to_string(),
+                    }.into()),
+                    _ => panic!("error running setup.py"),
+                }
             }
 
-            let state_dir = PathBuf::from(envs.get("PYOXIDIZER_DISTUTILS_STATE_DIR").unwrap());
-            find_resources(&python_paths.site_packages, Some(&state_dir)).or_else(|e| Err(
+            find_resources(&python_paths.site_packages, Some(&state_dir), &dist.version, None).or_else(|e| Err(
                 RuntimeError {
                     code: "SETUP_PY_ERROR",
                     message: format!("could not find resources: {}", e),
@@ -546,13 +774,15 @@ starlark_module! { python_distribution_module =>
         preferred_extension_module_variants=None,
         include_sources=true,
         include_resources=false,
-        include_test=false)
+        include_test=false,
+        lazy_index=false)
     {
         let extension_module_filter = required_str_arg("extension_module_filter", &extension_module_filter)?;
         optional_dict_arg("preferred_extension_module_variants", "string", "string", &preferred_extension_module_variants)?;
         let include_sources = required_bool_arg("include_sources", &include_sources)?;
         let include_resources = required_bool_arg("include_resources", &include_resources)?;
         let include_test = required_bool_arg("include_test", &include_test)?;
+        let lazy_index = required_bool_arg("lazy_index", &lazy_index)?;
 
         let context = env.get("CONTEXT").expect("CONTEXT not defined");
         let logger = context.downcast_apply(|x: &EnvironmentContext| x.logger.clone());
@@ -579,9 +809,12 @@ starlark_module! { python_distribution_module =>
         };
 
         this.downcast_apply_mut(|dist: &mut PythonDistribution| {
-            dist.ensure_distribution_resolved(&logger);
+            apply_license_policy(env, &logger, dist)?;
 
-            let mut embedded = EmbeddedPythonResourcesPrePackaged::default();
+            let mut embedded = EmbeddedPythonResourcesPrePackaged {
+                lazy_index,
+                ..EmbeddedPythonResourcesPrePackaged::default()
+            };
 
             let dist_ref = dist.distribution.as_ref().unwrap();
 
@@ -627,6 +860,61 @@ starlark_module! { python_distribution_module =>
         })
     }
 
+    #[allow(clippy::ptr_arg)]
+    extension_module_license_filter(env env, allow, on_conflict="prefer-compatible-variant") {
+        required_list_arg("allow", "string", &allow)?;
+        let on_conflict = required_str_arg("on_conflict", &on_conflict)?;
+
+        let on_conflict = LicenseConflictAction::from_str(&on_conflict).or_else(|e| Err(RuntimeError {
+            code: INCORRECT_PARAMETER_TYPE_ERROR_CODE,
+            message: e.to_string(),
+            label: "invalid on_conflict value".to_string(),
+        }.into()))?;
+
+        let policy = ExtensionModuleLicensePolicy {
+            allow: allow.into_iter()?.map(|x| x.to_string()).collect(),
+            on_conflict,
+        };
+
+        let mut context = env.get("CONTEXT").expect("CONTEXT not defined");
+
+        context.downcast_apply_mut(|x: &mut EnvironmentContext| {
+            x.extension_module_license_policy = Some(policy.clone());
+        });
+
+        Ok(Value::new(None))
+    }
+
+    #[allow(clippy::ptr_arg)]
+    require_sqlite_options(env env, options) {
+        required_list_arg("options", "string", &options)?;
+
+        let options = options
+            .into_iter()?
+            .map(|x| normalize_option(&x.to_string()))
+            .collect::<Vec<_>>();
+
+        if options.iter().any(|o| o.is_empty()) {
+            return Err(RuntimeError {
+                code: INCORRECT_PARAMETER_TYPE_ERROR_CODE,
+                message: "SQLite options must not be empty".to_string(),
+                label: "require_sqlite_options()".to_string(),
+            }.into());
+        }
+
+        let mut context = env.get("CONTEXT").expect("CONTEXT not defined");
+
+        context.downcast_apply_mut(|x: &mut EnvironmentContext| {
+            for option in &options {
+                if !x.required_sqlite_options.contains(option) {
+                    x.required_sqlite_options.push(option.clone());
+                }
+            }
+        });
+
+        Ok(Value::new(None))
+    }
+
     #[allow(clippy::ptr_arg)]
     default_python_distribution(env env, build_target=None) {
         let build_target = match build_target.get_type() {
@@ -649,6 +937,38 @@ mod tests {
     use super::super::testutil::*;
     use super::*;
 
+    #[test]
+    fn test_find_resources_incompatible_extension() -> Result<()> {
+        let temp_dir = tempdir::TempDir::new("pyoxidizer-test")?;
+        let root = temp_dir.path();
+
+        for path in &[
+            "mypkg/__init__.py",
+            "mypkg/_speedups.cpython-39-x86_64-linux-gnu.so",
+            "otherpkg/__init__.py",
+        ] {
+            let path = root.join(path);
+            std::fs::create_dir_all(path.parent().unwrap())?;
+            std::fs::write(&path, b"")?;
+        }
+
+        let err = find_resources(root, None, "3.7.5", None).unwrap_err();
+        assert!(err.to_string().contains(
+            "_speedups.cpython-39-x86_64-linux-gnu.so: extension module ABI cpython-39 (expected cpython-37m)"
+        ));
+
+        let err = find_resources(root, None, "3.7.5", Some(&["mypkg".to_string()])).unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("artifacts incompatible with the Python 3.7.5 distribution:"));
+
+        // Only requested packages are checked.
+        assert!(find_resources(root, None, "3.7.5", Some(&["otherpkg".to_string()])).is_ok());
+        assert!(find_resources(root, None, "3.9.1", None).is_ok());
+
+        Ok(())
+    }
+
     #[test]
     fn test_default_python_distribution() {
         let dist = starlark_ok("default_python_distribution()");
@@ -672,6 +992,86 @@ mod tests {
         assert_eq!(err.message, "The type 'bool' is not str");
     }
 
+    #[test]
+    fn test_extension_module_license_filter() {
+        let mut env = starlark_env();
+
+        starlark_eval_in_env(
+            &mut env,
+            "extension_module_license_filter(['BSD-3-Clause', 'MIT'], on_conflict='omit')",
+        )
+        .unwrap();
+
+        let policy = env
+            .get("CONTEXT")
+            .unwrap()
+            .downcast_apply(|x: &EnvironmentContext| x.extension_module_license_policy.clone());
+        assert_eq!(
+            policy,
+            Some(ExtensionModuleLicensePolicy {
+                allow: vec!["BSD-3-Clause".to_string(), "MIT".to_string()],
+                on_conflict: LicenseConflictAction::Omit,
+            })
+        );
+
+        let err = starlark_nok("extension_module_license_filter(['MIT'], on_conflict='ignore')");
+        assert!(err
+            .message
+            .starts_with("ignore is not a valid license conflict action"));
+    }
+
+    #[test]
+    fn test_require_sqlite_options() {
+        let mut env = starlark_env();
+
+        starlark_eval_in_env(
+            &mut env,
+            "require_sqlite_options(['sqlite_enable_fts5', 'ENABLE_JSON1'])",
+        )
+        .unwrap();
+        starlark_eval_in_env(&mut env, "require_sqlite_options(['ENABLE_FTS5'])").unwrap();
+
+        let options = env
+            .get("CONTEXT")
+            .unwrap()
+            .downcast_apply(|x: &EnvironmentContext| x.required_sqlite_options.clone());
+        assert_eq!(options, vec!["ENABLE_FTS5", "ENABLE_JSON1"]);
+
+        let err = starlark_nok("require_sqlite_options([''])");
+        assert_eq!(err.message, "SQLite options must not be empty");
+    }
+
+    #[test]
+    fn test_to_embedded_resources_license_filter() {
+        let mut env = starlark_env();
+
+        // The licenses of the no-gpl extension module filter.
+        starlark_eval_in_env(
+            &mut env,
+            "extension_module_license_filter(['BSD-3-Clause', 'bzip2-1.0.6', 'MIT', 'OpenSSL', 'Sleepycat', 'X11', 'Zlib'])",
+        )
+        .unwrap();
+        let res = starlark_eval_in_env(
+            &mut env,
+            "default_python_distribution().to_embedded_resources()",
+        )
+        .unwrap();
+
+        let policy = env
+            .get("CONTEXT")
+            .unwrap()
+            .downcast_apply(|x: &EnvironmentContext| x.extension_module_license_policy.clone())
+            .unwrap();
+
+        res.downcast_apply(|embedded: &PythonEmbeddedResources| {
+            assert!(!embedded.embedded.extension_modules.is_empty());
+
+            for em in embedded.embedded.extension_modules.values() {
+                assert_eq!(policy.disallowed_licenses(em), Vec::<String>::new());
+            }
+        });
+    }
+
     #[test]
     fn test_python_distribution_no_args() {
         let err = starlark_nok("PythonDistribution()");
@@ -732,6 +1132,16 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_pip_install_missing_constraints() {
+        let err = starlark_nok(
+            "default_python_distribution().pip_install(['pyflakes==2.1.1'], constraints='missing-constraints.txt')",
+        );
+        assert!(err
+            .message
+            .starts_with("path 'missing-constraints.txt' does not exist"));
+    }
+
     #[test]
     fn test_pip_install_simple() {
         let resources =
@@ -772,4 +1182,20 @@ mod tests {
             assert!(embedded.embedded.source_modules.is_empty());
         });
     }
+
+    #[test]
+    fn test_to_embedded_resources_lazy_index() {
+        let embedded =
+            starlark_eval("default_python_distribution().to_embedded_resources()").unwrap();
+        embedded.downcast_apply(|embedded: &PythonEmbeddedResources| {
+            assert!(!embedded.embedded.lazy_index);
+        });
+
+        let embedded =
+            starlark_eval("default_python_distribution().to_embedded_resources(lazy_index=True)")
+                .unwrap();
+        embedded.downcast_apply(|embedded: &PythonEmbeddedResources| {
+            assert!(embedded.embedded.lazy_index);
+        });
+    }
 }
@@ -2,24 +2,31 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
+use slog::warn;
 use starlark::environment::Environment;
-use starlark::values::{default_compare, TypedValue, Value, ValueError, ValueResult};
+use starlark::values::{default_compare, RuntimeError, TypedValue, Value, ValueError, ValueResult};
 use starlark::{
     any, immutable, not_supported, starlark_fun, starlark_module, starlark_signature,
     starlark_signature_extraction, starlark_signatures,
 };
 use std::any::Any;
 use std::cmp::Ordering;
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap};
 
 use super::embedded_python_config::EmbeddedPythonConfig;
-use super::env::{required_str_arg, required_type_arg};
-use super::python_distribution::PythonDistribution;
+use super::env::{optional_type_arg, required_str_arg, required_type_arg};
+use super::python_distribution::{apply_license_policy, PythonDistribution};
 use super::python_resource::PythonEmbeddedResources;
 use super::python_run_mode::PythonRunMode;
 use crate::app_packaging::environment::EnvironmentContext;
-use crate::py_packaging::binary::PreBuiltPythonExecutable;
+use crate::py_packaging::binary::{PreBuiltPythonExecutable, SharedPythonResources};
+use crate::py_packaging::compatibility::FILESYSTEM_RELATIVE_DIR;
 use crate::py_packaging::distribution::ExtensionModuleFilter;
+use crate::py_packaging::pkg_resources_compat::apply_pkg_resources_compat;
+use crate::py_packaging::references::{
+    check_references, entry_point_references, executable_references,
+};
+use crate::py_packaging::transform::apply_source_transforms;
 
 impl TypedValue for PreBuiltPythonExecutable {
     immutable!();
@@ -49,26 +56,45 @@ impl TypedValue for PreBuiltPythonExecutable {
 
 starlark_module! { python_executable_env =>
     #[allow(non_snake_case, clippy::ptr_arg)]
-    PythonExecutable(env env, name, distribution, resources, config, run_mode) {
+    PythonExecutable(
+        env env,
+        name,
+        distribution,
+        resources,
+        config,
+        run_mode,
+        shared_resources=None,
+        shared_resources_path="shared-python-resources") {
         let name = required_str_arg("name", &name)?;
         required_type_arg("distribution", "PythonDistribution", &distribution)?;
         required_type_arg("resources", "PythonEmbeddedResources", &resources)?;
         required_type_arg("config", "EmbeddedPythonConfig", &config)?;
         required_type_arg("run_mode", "PythonRunMode", &run_mode)?;
+        optional_type_arg("shared_resources", "PythonEmbeddedResources", &shared_resources)?;
+        let shared_resources_path = required_str_arg("shared_resources_path", &shared_resources_path)?;
 
         let context = env.get("CONTEXT").expect("CONTEXT not defined");
-        let logger = context.downcast_apply(|x: &EnvironmentContext| x.logger.clone());
+        let (logger, filesystem_relative_packages, source_transforms) = context.downcast_apply(|x: &EnvironmentContext| {
+            (x.logger.clone(), x.filesystem_relative_packages.clone(), x.source_transforms.clone())
+        });
 
         let mut distribution = distribution.clone();
 
-        let distribution = distribution.downcast_apply_mut(|dist: &mut PythonDistribution| {
-            dist.ensure_distribution_resolved(&logger);
-            dist.distribution.as_ref().unwrap().clone()
-        });
+        let distribution = distribution.downcast_apply_mut(|dist: &mut PythonDistribution| -> Result<_, ValueError> {
+            apply_license_policy(env, &logger, dist)?;
+            Ok(dist.distribution.as_ref().unwrap().clone())
+        })?;
 
         let mut resources = resources.downcast_apply(|r: &PythonEmbeddedResources| r.embedded.clone());
-        let config = config.downcast_apply(|c: &EmbeddedPythonConfig| c.config.clone());
+        let mut config = config.downcast_apply(|c: &EmbeddedPythonConfig| c.config.clone());
         let run_mode = run_mode.downcast_apply(|m: &PythonRunMode| m.run_mode.clone());
+        let shared_resources = match shared_resources.get_type() {
+            "NoneType" => None,
+            _ => Some(SharedPythonResources {
+                path: shared_resources_path,
+                resources: shared_resources.downcast_apply(|r: &PythonEmbeddedResources| r.embedded.clone()),
+            }),
+        };
 
         // Always ensure minimal extension modules are present, otherwise we get
         // missing symbol errors at link time.
@@ -78,10 +104,86 @@ starlark_module! { python_executable_env =>
             }
         }
 
+        apply_pkg_resources_compat(&mut resources, config.pkg_resources_compat);
+
+        // Packages which can't be imported from memory are installed next to
+        // the executable and imported by the filesystem importer.
+        let names = resources.resource_names();
+        resources.filesystem_packages = filesystem_relative_packages
+            .keys()
+            .filter(|package| names.iter().any(|name| name.split('.').next() == Some(package.as_str())))
+            .cloned()
+            .collect();
+
+        if !resources.filesystem_packages.is_empty() {
+            let sys_path = format!("$ORIGIN/{}", FILESYSTEM_RELATIVE_DIR);
+            warn!(
+                logger,
+                "installing {} into {} next to the executable; enabling the filesystem importer",
+                resources.filesystem_packages.iter().cloned().collect::<Vec<_>>().join(", "),
+                FILESYSTEM_RELATIVE_DIR
+            );
+
+            config.filesystem_importer = true;
+            if !config.sys_paths.contains(&sys_path) {
+                config.sys_paths.push(sys_path);
+            }
+        }
+
+        let stdlib = distribution.py_modules.keys().cloned().collect::<BTreeSet<_>>();
+
+        // Transform sources first, so every representation of a module is
+        // derived from the transformed source.
+        if !source_transforms.is_empty() {
+            let applied = apply_source_transforms(&source_transforms, &mut resources, &stdlib).or_else(|e| Err(RuntimeError {
+                code: "SOURCE_TRANSFORM",
+                message: format!("{:#}", e),
+                label: "PythonExecutable()".to_string(),
+            }.into()))?;
+
+            for (transform, modules) in &applied {
+                warn!(logger, "source transform {}: {} modules", transform, modules.len());
+            }
+        }
+
+        if !resources.source_policies.is_empty() || !resources.optimize_policies.is_empty() {
+            let before = resources.clone();
+            let mut buckets = resources.apply_retention_policies(&stdlib);
+
+            resources.measure_retention_buckets(&before, &mut buckets, &distribution.python_exe).or_else(|e| Err(RuntimeError {
+                code: "RETENTION_POLICY",
+                message: e.to_string(),
+                label: "PythonExecutable()".to_string(),
+            }.into()))?;
+
+            for (label, bucket) in &buckets {
+                warn!(logger, "{}: {} modules; {:+} bytes", label, bucket.modules.len(), bucket.size_delta);
+            }
+        }
+
+        // Catch stale module names before they turn into run-time failures.
+        let mut refs = executable_references(&config, &run_mode);
+        refs.extend(entry_point_references(&resources.resources));
+        let mut names = resources.resource_names();
+        if let Some(shared) = &shared_resources {
+            names.extend(shared.resources.resource_names());
+        }
+        check_references(
+            &logger,
+            &refs,
+            &names,
+            config.filesystem_importer,
+        ).or_else(|e| Err(RuntimeError {
+            code: "RESOURCE_REFERENCE",
+            message: e.to_string(),
+            label: "PythonExecutable()".to_string(),
+        }.into()))?;
+
         Ok(Value::new(PreBuiltPythonExecutable {
             name,
             distribution,
             resources,
+            shared_resources,
             config,
             run_mode
         }))
@@ -120,4 +222,218 @@ mod tests {
             assert_eq!(exe.run_mode, crate::py_packaging::config::RunMode::Noop);
         });
     }
+
+    #[test]
+    fn test_shared_resources() {
+        let mut env = starlark_env();
+
+        starlark_eval_in_env(&mut env, "dist = default_python_distribution()").unwrap();
+        starlark_eval_in_env(&mut env, "resources = PythonEmbeddedResources()").unwrap();
+        starlark_eval_in_env(&mut env, "shared = PythonEmbeddedResources()").unwrap();
+        starlark_eval_in_env(
+            &mut env,
+            "shared.add_python_resources([m for m in dist.source_modules() if m.name in ('json', 'json.decoder')])",
+        )
+        .unwrap();
+        starlark_eval_in_env(&mut env, "config = EmbeddedPythonConfig()").unwrap();
+        // Modules of the shared resources can be referenced.
+        starlark_eval_in_env(&mut env, "run_mode = python_run_mode_module('json')").unwrap();
+
+        let exe = starlark_eval_in_env(
+            &mut env,
+            "PythonExecutable('testapp', dist, resources, config, run_mode, shared_resources=shared)",
+        )
+        .unwrap();
+
+        exe.downcast_apply(|exe: &PreBuiltPythonExecutable| {
+            let shared = exe.shared_resources.as_ref().unwrap();
+            assert_eq!(shared.path, "shared-python-resources");
+            assert_eq!(
+                shared.resources.source_modules.keys().collect::<Vec<_>>(),
+                vec!["json", "json.decoder"]
+            );
+        });
+
+        let exe = starlark_eval_in_env(
+            &mut env,
+            "PythonExecutable('testapp', dist, resources, config, run_mode, shared_resources=shared, shared_resources_path='lib/common')",
+        )
+        .unwrap();
+
+        exe.downcast_apply(|exe: &PreBuiltPythonExecutable| {
+            assert_eq!(exe.shared_resources.as_ref().unwrap().path, "lib/common");
+        });
+
+        let err = starlark_eval_in_env(
+            &mut env,
+            "PythonExecutable('testapp', dist, resources, config, run_mode)",
+        )
+        .unwrap_err();
+        assert!(err
+            .message
+            .contains("run mode module: pattern 'json' matched no packaged resources"));
+    }
+
+    #[test]
+    fn test_stale_run_mode_module() {
+        let mut env = starlark_env();
+
+        starlark_eval_in_env(&mut env, "dist = default_python_distribution()").unwrap();
+        starlark_eval_in_env(&mut env, "resources = PythonEmbeddedResources()").unwrap();
+        starlark_eval_in_env(&mut env, "config = EmbeddedPythonConfig()").unwrap();
+        starlark_eval_in_env(
+            &mut env,
+            "run_mode = python_run_mode_module('mypkg.oldmodule')",
+        )
+        .unwrap();
+
+        let err = starlark_eval_in_env(
+            &mut env,
+            "PythonExecutable('testapp', dist, resources, config, run_mode)",
+        )
+        .unwrap_err();
+        assert!(err
+            .message
+            .contains("run mode module: pattern 'mypkg.oldmodule' matched no packaged resources"));
+
+        // The filesystem importer could find the module at run-time.
+        starlark_eval_in_env(
+            &mut env,
+            "config = EmbeddedPythonConfig(filesystem_importer=True)",
+        )
+        .unwrap();
+        starlark_eval_in_env(
+            &mut env,
+            "PythonExecutable('testapp', dist, resources, config, run_mode)",
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn test_filesystem_relative_packages() {
+        let mut env = starlark_env();
+
+        // As recorded by pip_install() for a package known not to work from memory.
+        let mut context = env.get("CONTEXT").unwrap();
+        context.downcast_apply_mut(|x: &mut EnvironmentContext| {
+            x.filesystem_relative_packages
+                .insert("json".to_string(), "testing".to_string());
+            x.filesystem_relative_packages
+                .insert("pandas".to_string(), "testing".to_string());
+        });
+
+        starlark_eval_in_env(&mut env, "dist = default_python_distribution()").unwrap();
+        starlark_eval_in_env(&mut env, "resources = PythonEmbeddedResources()").unwrap();
+        starlark_eval_in_env(
+            &mut env,
+            "resources.add_python_resources([m for m in dist.source_modules() if m.name in ('json', 'json.decoder', 'csv')])",
+        )
+        .unwrap();
+        starlark_eval_in_env(&mut env, "run_mode = python_run_mode_noop()").unwrap();
+        starlark_eval_in_env(&mut env, "config = EmbeddedPythonConfig()").unwrap();
+
+        let exe = starlark_eval_in_env(
+            &mut env,
+            "PythonExecutable('testapp', dist, resources, config, run_mode)",
+        )
+        .unwrap();
+
+        exe.downcast_apply(|exe: &PreBuiltPythonExecutable| {
+            assert_eq!(
+                exe.resources.filesystem_packages.iter().collect::<Vec<_>>(),
+                vec!["json"]
+            );
+            assert!(exe.config.filesystem_importer);
+            assert_eq!(exe.config.sys_paths, vec!["$ORIGIN/lib".to_string()]);
+
+            let (modules, _) = exe.resources.filesystem_resources();
+            assert_eq!(
+                modules.iter().map(|m| m.name.as_str()).collect::<Vec<_>>(),
+                vec!["json", "json.decoder"]
+            );
+        });
+    }
+
+    #[test]
+    fn test_source_transforms() {
+        let mut env = starlark_env();
+
+        starlark_eval_in_env(&mut env, "dist = default_python_distribution()").unwrap();
+        starlark_eval_in_env(&mut env, "resources = PythonEmbeddedResources()").unwrap();
+        starlark_eval_in_env(
+            &mut env,
+            "resources.add_python_resources([m for m in dist.source_modules() if m.name in ('json', 'json.decoder')])",
+        )
+        .unwrap();
+        starlark_eval_in_env(
+            &mut env,
+            "register_source_transform('json.decoder', regex='(?s).+', replacement='x = 1\\n')",
+        )
+        .unwrap();
+        starlark_eval_in_env(&mut env, "run_mode = python_run_mode_noop()").unwrap();
+        starlark_eval_in_env(&mut env, "config = EmbeddedPythonConfig()").unwrap();
+
+        let exe = starlark_eval_in_env(
+            &mut env,
+            "PythonExecutable('testapp', dist, resources, config, run_mode)",
+        )
+        .unwrap();
+
+        exe.downcast_apply(|exe: &PreBuiltPythonExecutable| {
+            assert_eq!(
+                exe.resources.source_modules["json.decoder"].source,
+                b"x = 1\n".to_vec()
+            );
+            assert_eq!(
+                exe.resources.bytecode_modules["json.decoder"].source,
+                b"x = 1\n".to_vec()
+            );
+            assert_ne!(
+                exe.resources.source_modules["json"].source,
+                b"x = 1\n".to_vec()
+            );
+        });
+    }
+
+    #[test]
+    fn test_retention_policies() {
+        let mut env = starlark_env();
+
+        starlark_eval_in_env(&mut env, "dist = default_python_distribution()").unwrap();
+        starlark_eval_in_env(&mut env, "resources = PythonEmbeddedResources()").unwrap();
+        starlark_eval_in_env(
+            &mut env,
+            "resources.add_python_resources([m for m in dist.source_modules() if m.name in ('json', 'json.decoder')])",
+        )
+        .unwrap();
+        starlark_eval_in_env(
+            &mut env,
+            "resources.source_policy({'json': 'source+bytecode', '*': 'bytecode-only'})",
+        )
+        .unwrap();
+        starlark_eval_in_env(&mut env, "resources.optimize_policy({'stdlib:*': 2})").unwrap();
+        starlark_eval_in_env(&mut env, "run_mode = python_run_mode_noop()").unwrap();
+        starlark_eval_in_env(&mut env, "config = EmbeddedPythonConfig()").unwrap();
+
+        let exe = starlark_eval_in_env(
+            &mut env,
+            "PythonExecutable('testapp', dist, resources, config, run_mode)",
+        )
+        .unwrap();
+
+        exe.downcast_apply(|exe: &PreBuiltPythonExecutable| {
+            assert_eq!(
+                exe.resources.source_modules.keys().collect::<Vec<_>>(),
+                vec!["json"]
+            );
+            assert_eq!(
+                exe.resources.bytecode_modules["json.decoder"].optimize_level,
+                crate::py_packaging::resource::BytecodeOptimizationLevel::Two
+            );
+        });
+
+        let err =
+            starlark_eval_in_env(&mut env, "resources.source_policy({'*': 'none'})").unwrap_err();
+        assert!(err.message.starts_with("invalid source policy none"));
+    }
 }
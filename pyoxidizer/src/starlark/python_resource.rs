@@ -16,13 +16,17 @@ use std::cmp::Ordering;
 use std::collections::HashMap;
 use std::path::{Path, PathBuf};
 
-use super::env::{optional_list_arg, required_bool_arg, required_type_arg};
+use super::env::{
+    optional_list_arg, record_glob_input_paths, required_bool_arg, required_dict_arg,
+    required_type_arg, resolve_resource_path_arg,
+};
 use crate::app_packaging::environment::EnvironmentContext;
 use crate::py_packaging::distribution::ExtensionModule;
 use crate::py_packaging::embedded_resource::EmbeddedPythonResourcesPrePackaged;
 use crate::py_packaging::resource::{
     BytecodeModule, BytecodeOptimizationLevel, PythonResource, ResourceData, SourceModule,
 };
+use crate::py_packaging::retention::SourcePolicy;
 
 #[derive(Debug, Clone)]
 pub struct PythonSourceModule {
@@ -364,8 +368,13 @@ impl<'a> From<&'a PythonResource> for Value {
 
 starlark_module! { python_resource_env =>
     #[allow(non_snake_case, clippy::ptr_arg)]
-    PythonEmbeddedResources(env _env) {
-        let embedded = EmbeddedPythonResourcesPrePackaged::default();
+    PythonEmbeddedResources(env _env, lazy_index=false) {
+        let lazy_index = required_bool_arg("lazy_index", &lazy_index)?;
+
+        let embedded = EmbeddedPythonResourcesPrePackaged {
+            lazy_index,
+            ..EmbeddedPythonResourcesPrePackaged::default()
+        };
 
         Ok(Value::new(PythonEmbeddedResources { embedded }))
     }
@@ -521,27 +530,92 @@ starlark_module! { python_resource_env =>
         Ok(Value::new(None))
     }
 
+    #[allow(clippy::ptr_arg)]
+    PythonEmbeddedResources.source_policy(this, policies) {
+        required_dict_arg("policies", "string", "string", &policies)?;
+
+        let policies = policies.into_iter()?.map(|k| {
+            let policy = policies.at(k.clone())?.to_string();
+
+            let policy = SourcePolicy::parse(&policy).or_else(|e| Err(RuntimeError {
+                code: INCORRECT_PARAMETER_TYPE_ERROR_CODE,
+                message: e.to_string(),
+                label: "source_policy()".to_string(),
+            }.into()))?;
+
+            Ok((k.to_string(), policy))
+        }).collect::<Result<Vec<_>, ValueError>>()?;
+
+        this.downcast_apply_mut(|embedded: &mut PythonEmbeddedResources| {
+            embedded.embedded.source_policies = policies.clone();
+        });
+
+        Ok(Value::new(None))
+    }
+
+    #[allow(clippy::ptr_arg)]
+    PythonEmbeddedResources.optimize_policy(this, policies) {
+        required_dict_arg("policies", "string", "int", &policies)?;
+
+        let policies = policies.into_iter()?.map(|k| {
+            let level = match policies.at(k.clone())?.to_int()? {
+                0 => BytecodeOptimizationLevel::Zero,
+                1 => BytecodeOptimizationLevel::One,
+                2 => BytecodeOptimizationLevel::Two,
+                i => {
+                    return Err(RuntimeError {
+                        code: INCORRECT_PARAMETER_TYPE_ERROR_CODE,
+                        message: format!("optimize level must be 0, 1, or 2: got {}", i),
+                        label: "optimize_policy()".to_string(),
+                    }.into());
+                }
+            };
+
+            Ok((k.to_string(), level))
+        }).collect::<Result<Vec<_>, ValueError>>()?;
+
+        this.downcast_apply_mut(|embedded: &mut PythonEmbeddedResources| {
+            embedded.embedded.optimize_policies = policies.clone();
+        });
+
+        Ok(Value::new(None))
+    }
+
     #[allow(clippy::ptr_arg)]
     PythonEmbeddedResources.filter_from_files(
         env env,
         this,
         files=None,
-        glob_files=None) {
+        glob_files=None,
+        allow_no_match=true) {
         optional_list_arg("files", "string", &files)?;
         optional_list_arg("glob_files", "string", &glob_files)?;
+        let allow_no_match = required_bool_arg("allow_no_match", &allow_no_match)?;
 
         let files = match files.get_type() {
-            "list" => files.into_iter()?.map(|x| PathBuf::from(x.to_string())).collect(),
+            "list" => files.into_iter()?.map(|x| {
+                resolve_resource_path_arg(&env, "filter_from_files", &x.to_string(), false)
+            }).collect::<Result<Vec<PathBuf>, ValueError>>()?,
             "NoneType" => Vec::new(),
             _ => panic!("type should have been validated above"),
         };
 
+        // Glob patterns are resolved relative to the config file but aren't
+        // validated, as an empty match is reported as an error later.
         let glob_files = match glob_files.get_type() {
-            "list" => glob_files.into_iter()?.map(|x| x.to_string()).collect(),
+            "list" => glob_files.into_iter()?.map(|x| {
+                resolve_resource_path_arg(&env, "filter_from_files", &x.to_string(), true)
+                    .map(|p| p.display().to_string())
+            }).collect::<Result<Vec<String>, ValueError>>()?,
             "NoneType" => Vec::new(),
             _ => panic!("type should have been validated above"),
         };
 
+        for pattern in &glob_files {
+            // Invalid patterns are reported when filtering.
+            record_glob_input_paths(&env, pattern).ok();
+        }
+
         let files_refs = files.iter().map(|x| x.as_ref()).collect::<Vec<&Path>>();
         let glob_files_refs = glob_files.iter().map(|x| x.as_ref()).collect::<Vec<&str>>();
 
@@ -549,7 +623,7 @@ starlark_module! { python_resource_env =>
         let logger = context.downcast_apply(|x: &EnvironmentContext| x.logger.clone());
 
         this.downcast_apply_mut(|embedded: &mut PythonEmbeddedResources| {
-            embedded.embedded.filter_from_files(&logger, &files_refs, &glob_files_refs)
+            embedded.embedded.filter_from_files(&logger, &files_refs, &glob_files_refs, allow_no_match)
         }).or_else(|e| Err(
             RuntimeError {
                 code: "RUNTIME_ERROR",
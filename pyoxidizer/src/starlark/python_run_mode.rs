@@ -55,6 +55,11 @@ starlark_module! { python_run_mode_env =>
         Ok(Value::new(PythonRunMode { run_mode: RunMode::Noop }))
     }
 
+    #[allow(clippy::ptr_arg)]
+    python_run_mode_embedding(call_stack _stack) {
+        Ok(Value::new(PythonRunMode { run_mode: RunMode::Embedding }))
+    }
+
     #[allow(clippy::ptr_arg)]
     python_run_mode_repl(call_stack _stack) {
         Ok(Value::new(PythonRunMode { run_mode: RunMode::Repl }))
@@ -86,6 +91,12 @@ mod tests {
         v.downcast_apply(|x: &PythonRunMode| assert_eq!(x.run_mode, RunMode::Noop));
     }
 
+    #[test]
+    fn test_run_mode_embedding() {
+        let v = starlark_ok("python_run_mode_embedding()");
+        v.downcast_apply(|x: &PythonRunMode| assert_eq!(x.run_mode, RunMode::Embedding));
+    }
+
     #[test]
     fn test_run_mode_repl() {
         let v = starlark_ok("python_run_mode_repl()");
@@ -0,0 +1,163 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/*!
+Building extension modules from Rust crates of the project.
+
+`rust_extension_module()` builds a crate with cargo and returns a
+`PythonExtensionModule`. The module's top-level package is installed next
+to the executable, from where the filesystem importer loads the extension
+module.
+*/
+
+use starlark::environment::Environment;
+use starlark::values::{RuntimeError, Value, ValueError, ValueResult};
+use starlark::{
+    starlark_fun, starlark_module, starlark_signature, starlark_signature_extraction,
+    starlark_signatures,
+};
+use std::collections::HashMap;
+use std::path::Path;
+
+use super::env::{
+    optional_list_arg, record_input_path, required_str_arg, resolve_resource_path_arg,
+};
+use super::python_distribution::{resolve_default_python_distribution, PythonDistribution};
+use super::python_resource::PythonExtensionModule;
+use crate::app_packaging::environment::EnvironmentContext;
+use crate::app_packaging::repackage::HOST;
+use crate::py_packaging::rust_extension::{build_rust_extension_module, RustExtensionModule};
+
+/// Error code for Rust extension modules that fail to build.
+pub const RUST_EXTENSION_ERROR_CODE: &str = "RUST_EXTENSION_ERROR";
+
+/// Files of a crate which affect the extension module built from it.
+const CRATE_INPUTS: &[&str] = &["Cargo.toml", "Cargo.lock", "build.rs", "src"];
+
+fn rust_extension_error(message: String) -> ValueError {
+    RuntimeError {
+        code: RUST_EXTENSION_ERROR_CODE,
+        message,
+        label: "rust_extension_module()".to_string(),
+    }
+    .into()
+}
+
+/// Record the files of a crate as inputs to the configuration.
+///
+/// The crate directory itself isn't recorded, as it may hold a `target`
+/// directory from building the crate on its own.
+fn record_crate_inputs(env: &Environment, crate_dir: &Path) {
+    for name in CRATE_INPUTS {
+        let path = crate_dir.join(name);
+        if path.exists() {
+            record_input_path(env, &path);
+        }
+    }
+}
+
+starlark_module! { rust_extension_module =>
+    #[allow(clippy::ptr_arg)]
+    rust_extension_module(env env, name, manifest, features=None, distribution=None) {
+        let name = required_str_arg("name", &name)?;
+        let manifest = required_str_arg("manifest", &manifest)?;
+        optional_list_arg("features", "string", &features)?;
+
+        let manifest_path = resolve_resource_path_arg(&env, "rust_extension_module()", &manifest, false)?;
+        let features = match features.get_type() {
+            "list" => features.into_iter()?.map(|x| x.to_string()).collect(),
+            _ => Vec::new(),
+        };
+
+        if let Some(crate_dir) = manifest_path.parent() {
+            record_crate_inputs(&env, crate_dir);
+        }
+
+        let mut context = env.get("CONTEXT").expect("CONTEXT not defined");
+        let (logger, build_target, build_path, release, offline) = context.downcast_apply(|x: &EnvironmentContext| {
+            (x.logger.clone(), x.build_target.clone(), x.build_path.clone(), x.release, x.offline)
+        });
+
+        let mut distribution = match distribution.get_type() {
+            "NoneType" => resolve_default_python_distribution(&env, &build_target)?,
+            "PythonDistribution" => distribution,
+            t => {
+                return Err(ValueError::TypeNotX {
+                    object_type: t.to_string(),
+                    op: "PythonDistribution".to_string(),
+                })
+            }
+        };
+
+        let module = RustExtensionModule {
+            name: name.clone(),
+            manifest_path,
+            features,
+        };
+
+        let profile = if release { "release" } else { "debug" };
+        let base_dir = build_path.join("rust_extensions");
+
+        let em = distribution.downcast_apply_mut(|dist: &mut PythonDistribution| {
+            dist.ensure_distribution_resolved(&logger);
+
+            build_rust_extension_module(
+                &logger,
+                &module,
+                dist.distribution.as_ref().unwrap(),
+                HOST,
+                &build_target,
+                &base_dir.join("target"),
+                release,
+                offline,
+                &base_dir.join("modules").join(&build_target).join(profile),
+            )
+        }).map_err(|e| rust_extension_error(format!("error building Rust extension module {}: {}", name, e)))?;
+
+        // Rust extension modules are shared libraries. They can't be loaded
+        // from memory.
+        let package = name.split('.').next().unwrap().to_string();
+        context.downcast_apply_mut(|x: &mut EnvironmentContext| {
+            x.filesystem_relative_packages.insert(
+                package.clone(),
+                format!("contains Rust extension module {}, a shared library", name),
+            );
+        });
+
+        Ok(Value::new(PythonExtensionModule { em }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::testutil::*;
+    use super::*;
+
+    #[test]
+    fn test_rust_extension_module_missing_manifest() {
+        let err = starlark_nok(
+            "rust_extension_module(name='myapp._fast', manifest='does-not-exist/Cargo.toml')",
+        );
+        assert!(err.message.contains("does not exist"));
+    }
+
+    #[test]
+    fn test_rust_extension_module_bad_features() {
+        let err = starlark_nok(
+            "rust_extension_module(name='myapp._fast', manifest='Cargo.toml', features='simd')",
+        );
+        assert!(err.message.contains("features"));
+    }
+
+    #[test]
+    fn test_rust_extension_module_not_cdylib() {
+        // The crate of PyOxidizer doesn't build a cdylib.
+        let manifest = Path::new(env!("CARGO_MANIFEST_DIR")).join("Cargo.toml");
+        let err = starlark_nok(&format!(
+            "rust_extension_module(name='myapp._fast', manifest='{}')",
+            manifest.display().to_string().replace('\\', "/")
+        ));
+        assert!(err.message.contains("crate-type"));
+    }
+}
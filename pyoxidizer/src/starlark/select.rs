@@ -0,0 +1,274 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/*!
+Target-conditional values.
+
+`select()` allows configuration values to vary by the target triple being
+built, allowing a single configuration file to serve multiple targets.
+*/
+
+use starlark::environment::Environment;
+use starlark::values::{
+    default_compare, RuntimeError, TypedValue, Value, ValueError, ValueResult,
+    INCORRECT_PARAMETER_TYPE_ERROR_CODE,
+};
+use starlark::{
+    any, immutable, not_supported, starlark_fun, starlark_module, starlark_signature,
+    starlark_signature_extraction, starlark_signatures,
+};
+use std::any::Any;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+
+/// Error code for selects not having a value for the target being built.
+pub const SELECT_NOT_EXHAUSTIVE_ERROR_CODE: &str = "SELECT_NOT_EXHAUSTIVE";
+
+/// Name of the arm used when no other arm matches.
+pub const SELECT_DEFAULT_ARM: &str = "default";
+
+/// A value that depends on the target triple being built.
+#[derive(Clone)]
+pub struct Select {
+    /// Glob patterns matched against the target triple and their values.
+    ///
+    /// Patterns are evaluated in order. The first match wins.
+    pub arms: Vec<(String, Value)>,
+
+    /// Value to use when no pattern matches.
+    ///
+    /// It is required. Selects without it fail to resolve, even for targets
+    /// a pattern matches.
+    pub default: Option<Value>,
+}
+
+impl Select {
+    /// Resolve the value for a target triple.
+    ///
+    /// Returns the name of the matching arm and its value.
+    fn resolve(&self, target: &str) -> Option<(String, Value)> {
+        for (pattern, value) in &self.arms {
+            if let Ok(p) = glob::Pattern::new(pattern) {
+                if p.matches(target) {
+                    return Some((pattern.clone(), value.clone()));
+                }
+            }
+        }
+
+        match &self.default {
+            Some(value) => Some((SELECT_DEFAULT_ARM.to_string(), value.clone())),
+            None => None,
+        }
+    }
+}
+
+impl TypedValue for Select {
+    immutable!();
+    any!();
+    not_supported!(binop);
+    not_supported!(container);
+    not_supported!(function);
+    not_supported!(get_hash);
+    not_supported!(to_int);
+
+    fn to_str(&self) -> String {
+        let mut arms = self
+            .arms
+            .iter()
+            .map(|(pattern, value)| format!("{:?}: {}", pattern, value.to_repr()))
+            .collect::<Vec<String>>();
+
+        if let Some(value) = &self.default {
+            arms.push(format!("{:?}: {}", SELECT_DEFAULT_ARM, value.to_repr()));
+        }
+
+        format!("select({{{}}})", arms.join(", "))
+    }
+
+    fn to_repr(&self) -> String {
+        self.to_str()
+    }
+
+    fn get_type(&self) -> &'static str {
+        "Select"
+    }
+
+    fn to_bool(&self) -> bool {
+        true
+    }
+
+    fn compare(&self, other: &dyn TypedValue, _recursion: u32) -> Result<Ordering, ValueError> {
+        default_compare(self, other)
+    }
+}
+
+/// Resolve a possibly target-conditional argument value.
+///
+/// If `value` is a `Select`, the arm matching `target` is chosen and its
+/// value returned along with the name of the arm. Other values are returned
+/// as-is.
+///
+/// Selects without a default arm are rejected here rather than by
+/// `select()`, so the error can name `field` and `target`.
+pub fn resolve_select(
+    field: &str,
+    value: &Value,
+    target: &str,
+) -> Result<(Value, Option<String>), ValueError> {
+    if value.get_type() != "Select" {
+        return Ok((value.clone(), None));
+    }
+
+    let select = value.downcast_apply(|x: &Select| x.clone());
+
+    // Targets nobody thought of must fail loudly rather than silently
+    // get some arm's value.
+    match select.resolve(target) {
+        Some((arm, value)) if select.default.is_some() => Ok((value, Some(arm))),
+        _ => Err(RuntimeError {
+            code: SELECT_NOT_EXHAUSTIVE_ERROR_CODE,
+            message: format!(
+                "select() for {} requires a '{}' arm for targets not matching {} (building for {})",
+                field,
+                SELECT_DEFAULT_ARM,
+                select
+                    .arms
+                    .iter()
+                    .map(|(pattern, _)| format!("{:?}", pattern))
+                    .collect::<Vec<_>>()
+                    .join(", "),
+                target
+            ),
+            label: format!("no value for {} on targets other than these", field),
+        }
+        .into()),
+    }
+}
+
+starlark_module! { select_module =>
+    #[allow(clippy::ptr_arg)]
+    select(arms) {
+        if arms.get_type() != "dict" {
+            return Err(RuntimeError {
+                code: INCORRECT_PARAMETER_TYPE_ERROR_CODE,
+                message: format!("select() expects a dict; got type {}", arms.get_type()),
+                label: format!("expected type dict; got {}", arms.get_type()),
+            }.into());
+        }
+
+        let mut select = Select { arms: Vec::new(), default: None };
+
+        for key in arms.into_iter()? {
+            if key.get_type() != "string" {
+                return Err(RuntimeError {
+                    code: INCORRECT_PARAMETER_TYPE_ERROR_CODE,
+                    message: format!("select() keys must be strings; got type {}", key.get_type()),
+                    label: format!("expected type string; got {}", key.get_type()),
+                }.into());
+            }
+
+            let pattern = key.to_str();
+            let value = arms.at(key)?;
+
+            if value.get_type() == "Select" {
+                return Err(RuntimeError {
+                    code: INCORRECT_PARAMETER_TYPE_ERROR_CODE,
+                    message: "select() values cannot be select()".to_string(),
+                    label: "nested select()".to_string(),
+                }.into());
+            }
+
+            if pattern == SELECT_DEFAULT_ARM {
+                select.default = Some(value);
+                continue;
+            }
+
+            if let Err(e) = glob::Pattern::new(&pattern) {
+                return Err(RuntimeError {
+                    code: INCORRECT_PARAMETER_TYPE_ERROR_CODE,
+                    message: format!("invalid target triple pattern {}: {}", pattern, e),
+                    label: format!("invalid pattern {}", pattern),
+                }.into());
+            }
+
+            select.arms.push((pattern, value));
+        }
+
+        // A missing default arm is reported when the select is resolved,
+        // where the argument and target triple are known.
+        Ok(Value::new(select))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::testutil::*;
+    use super::*;
+
+    #[test]
+    fn test_select_resolve() {
+        let v = starlark_ok(
+            "select({'*-pc-windows-*': 'system', 'x86_64-*-linux-*': 'rust', 'default': 'jemalloc'})",
+        );
+        assert_eq!(v.get_type(), "Select");
+
+        let resolve = |target: &str| {
+            let (value, arm) = resolve_select("raw_allocator", &v, target).unwrap();
+            (value.to_str(), arm.unwrap())
+        };
+
+        assert_eq!(
+            resolve("x86_64-pc-windows-msvc"),
+            ("system".to_string(), "*-pc-windows-*".to_string())
+        );
+        assert_eq!(
+            resolve("x86_64-unknown-linux-gnu"),
+            ("rust".to_string(), "x86_64-*-linux-*".to_string())
+        );
+        assert_eq!(
+            resolve("x86_64-apple-darwin"),
+            ("jemalloc".to_string(), "default".to_string())
+        );
+    }
+
+    #[test]
+    fn test_select_not_exhaustive() {
+        let v = starlark_ok("select({'*-pc-windows-*': 'system'})");
+
+        // The default arm is required even for targets a pattern matches.
+        for target in &["x86_64-apple-darwin", "x86_64-pc-windows-msvc"] {
+            let err = resolve_select("raw_allocator", &v, target).err().unwrap();
+            match err {
+                ValueError::Runtime(e) => {
+                    assert_eq!(e.code, SELECT_NOT_EXHAUSTIVE_ERROR_CODE);
+                    assert!(e.message.contains("requires a 'default' arm"));
+                    assert!(e.message.contains("raw_allocator"));
+                    assert!(e.message.contains(target));
+                    assert!(e.message.contains("\"*-pc-windows-*\""));
+                }
+                _ => panic!("unexpected error"),
+            }
+        }
+
+        let v = starlark_ok("select({'default': 'system'})");
+        let (value, arm) = resolve_select("raw_allocator", &v, "x86_64-apple-darwin").unwrap();
+        assert_eq!(value.to_str(), "system");
+        assert_eq!(arm, Some("default".to_string()));
+    }
+
+    #[test]
+    fn test_select_plain_value() {
+        let v = starlark_ok("'system'");
+        let (value, arm) = resolve_select("raw_allocator", &v, "x86_64-apple-darwin").unwrap();
+        assert_eq!(value.to_str(), "system");
+        assert!(arm.is_none());
+    }
+
+    #[test]
+    fn test_select_invalid() {
+        starlark_nok("select(['foo'])");
+        starlark_nok("select({1: 'foo'})");
+        starlark_nok("select({'[': 'foo'})");
+    }
+}
@@ -10,10 +10,25 @@ use starlark::eval;
 use starlark::values::Value;
 
 use super::env::global_environment;
+use super::eval::EvaluationCache;
 use crate::app_packaging::environment::EnvironmentContext;
 use crate::logging::PrintlnDrain;
 
 pub fn starlark_env() -> Environment {
+    starlark_env_with_vars(&[])
+}
+
+/// Obtain an environment with build variables defined.
+pub fn starlark_env_with_vars(vars: &[(&str, &str)]) -> Environment {
+    starlark_env_with(vars, &EvaluationCache::default())
+}
+
+/// Obtain an environment memoizing results in `cache`.
+pub fn starlark_env_with_cache(cache: &EvaluationCache) -> Environment {
+    starlark_env_with(&[], cache)
+}
+
+fn starlark_env_with(vars: &[(&str, &str)], cache: &EvaluationCache) -> Environment {
     let logger = slog::Logger::root(
         PrintlnDrain {
             min_level: slog::Level::Error,
@@ -27,8 +42,13 @@ pub fn starlark_env() -> Environment {
     let cwd = std::env::current_dir().expect("unable to determine CWD");
     let config_path = cwd.join("dummy");
 
-    let context = EnvironmentContext::new(&logger, &config_path, build_target)
+    let mut context = EnvironmentContext::new(&logger, &config_path, build_target)
         .expect("unable to create EnvironmentContext");
+    context.build_vars = vars
+        .iter()
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .collect();
+    context.eval_cache = cache.clone();
 
     global_environment(&context).expect("unable to get global environment")
 }
@@ -0,0 +1,17 @@
+"""The {{program_name}} command line tool."""
+
+import argparse
+
+
+def main(argv=None):
+    parser = argparse.ArgumentParser(prog="{{program_name}}", description="Greet somebody.")
+    parser.add_argument("name", help="who to greet")
+    parser.add_argument("--shout", action="store_true", help="greet loudly")
+    args = parser.parse_args(argv)
+
+    greeting = "Hello, %s!" % args.name
+    print(greeting.upper() if args.shout else greeting)
+
+    # The return value is the exit code of the process. argparse exits with
+    # 2 for invalid arguments.
+    return 0
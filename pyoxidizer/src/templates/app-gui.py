@@ -0,0 +1,26 @@
+"""The {{program_name}} GUI application."""
+
+import argparse
+import sys
+
+
+def main(argv=None):
+    parser = argparse.ArgumentParser(prog="{{program_name}}")
+    parser.add_argument("--title", default="{{program_name}}", help="window title")
+    args = parser.parse_args(argv)
+
+    try:
+        import tkinter
+    except ImportError:
+        # Without a console, this is written to the log file configured with
+        # detached_stdio in pyoxidizer.bzl.
+        print("tkinter is not available in this Python distribution", file=sys.stderr)
+        return 1
+
+    root = tkinter.Tk()
+    root.title(args.title)
+    tkinter.Label(root, text="Hello from {{program_name}}!").pack(padx=40, pady=20)
+    tkinter.Button(root, text="Quit", command=root.destroy).pack(pady=(0, 20))
+    root.mainloop()
+
+    return 0
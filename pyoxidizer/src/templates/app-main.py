@@ -0,0 +1,5 @@
+import sys
+
+from {{python_package}} import main
+
+sys.exit(main())
@@ -0,0 +1,76 @@
+"""The {{program_name}} service."""
+
+import asyncio
+import logging
+import signal
+import sys
+
+logger = logging.getLogger("{{program_name}}")
+
+# Seconds between heartbeat messages.
+HEARTBEAT_INTERVAL = 60.0
+
+# Signals sent by service managers, Ctrl+C and, on Windows, Ctrl+Break.
+STOP_SIGNALS = [
+    getattr(signal, name)
+    for name in ("SIGTERM", "SIGINT", "SIGBREAK")
+    if hasattr(signal, name)
+]
+
+
+async def serve(stop):
+    logger.info("ready")
+
+    # Do the work of the service here.
+    while not stop.is_set():
+        try:
+            await asyncio.wait_for(stop.wait(), HEARTBEAT_INTERVAL)
+        except asyncio.TimeoutError:
+            logger.info("still running")
+
+
+def main():
+    logging.basicConfig(
+        level=logging.INFO, format="%(asctime)s %(name)s %(levelname)s %(message)s"
+    )
+
+    if sys.platform == "win32":
+        loop = asyncio.ProactorEventLoop()
+    else:
+        loop = asyncio.new_event_loop()
+    asyncio.set_event_loop(loop)
+
+    stop = asyncio.Event()
+
+    def request_stop(signum):
+        logger.info("received signal %d, stopping", signum)
+        stop.set()
+
+    # Stop cleanly when asked to.
+    for signum in STOP_SIGNALS:
+        try:
+            loop.add_signal_handler(signum, request_stop, signum)
+        except NotImplementedError:
+            # Event loops on Windows don't support signal handlers, so
+            # Python's are used to hand signals to the loop.
+            signal.signal(
+                signum,
+                lambda signum, frame: loop.call_soon_threadsafe(request_stop, signum),
+            )
+
+    if sys.platform == "win32":
+        # Python only runs signal handlers while the loop isn't waiting for
+        # I/O, so wake it up regularly.
+        def wakeup():
+            loop.call_later(0.5, wakeup)
+
+        wakeup()
+
+    try:
+        loop.run_until_complete(serve(stop))
+    finally:
+        loop.close()
+
+    logger.info("stopped")
+
+    return 0
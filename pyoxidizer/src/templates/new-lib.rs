@@ -0,0 +1,89 @@
+//! C ABI for embedding Python in a host application.
+//!
+//! `pyoxidizer build` writes a `{{lib_name}}.h` header declaring these
+//! functions next to the built library.
+
+// Code between the BEGIN and END PYOXIDIZER GENERATED CODE markers is replaced
+// by `pyoxidizer upgrade-project`. Everything else is yours to edit.
+
+// BEGIN PYOXIDIZER GENERATED CODE: c-abi
+use pyembed::{default_python_config, run_pre_init_hooks, MainPythonInterpreter};
+use std::ffi::CStr;
+use std::os::raw::{c_char, c_int};
+
+// The interpreter is owned by the library between calls to init and
+// shutdown. Hosts must not call these functions concurrently.
+static mut INTERPRETER: Option<MainPythonInterpreter<'static>> = None;
+
+/// Initialize the embedded Python interpreter.
+///
+/// Returns 0 on success.
+#[no_mangle]
+pub extern "C" fn {{lib_name}}_init() -> c_int {
+    unsafe {
+        if INTERPRETER.is_some() {
+            return 0;
+        }
+
+        // Call functions registered with `pre_init_hooks` in the PyOxidizer
+        // config. They must be defined with `#[no_mangle]`.
+        run_pre_init_hooks();
+
+        // Load the default Python configuration as derived by the PyOxidizer
+        // config file used at build time.
+        match MainPythonInterpreter::new(default_python_config()) {
+            Ok(interp) => {
+                INTERPRETER = Some(interp);
+                0
+            }
+            Err(msg) => {
+                eprintln!("{}", msg);
+                1
+            }
+        }
+    }
+}
+
+/// Execute Python code in the `__main__` module.
+///
+/// Returns 0 on success, 1 if an exception was raised (it is printed to
+/// stderr) and -1 if the interpreter isn't initialized or `code` isn't UTF-8.
+///
+/// # Safety
+///
+/// `code` must be a valid NUL terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn {{lib_name}}_eval(code: *const c_char) -> c_int {
+    let interp = match INTERPRETER.as_mut() {
+        Some(interp) => interp,
+        None => return -1,
+    };
+
+    if code.is_null() {
+        return -1;
+    }
+
+    let code = match CStr::from_ptr(code).to_str() {
+        Ok(code) => code,
+        Err(_) => return -1,
+    };
+
+    match interp.run_code(code) {
+        Ok(_) => 0,
+        Err(err) => {
+            interp.print_err(err);
+            1
+        }
+    }
+}
+
+/// Shut down the embedded Python interpreter.
+///
+/// The interpreter can't be initialized again afterwards.
+#[no_mangle]
+pub extern "C" fn {{lib_name}}_shutdown() {
+    unsafe {
+        INTERPRETER = None;
+    }
+}
+// END PYOXIDIZER GENERATED CODE: c-abi
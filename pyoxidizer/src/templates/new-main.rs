@@ -1,30 +1,72 @@
-use pyembed::{default_python_config, MainPythonInterpreter};
+{{#if gui~}}
+// Don't open a console window when started on Windows. Standard streams
+// are handled by `detached_stdio` in the PyOxidizer config.
+#![windows_subsystem = "windows"]
 
-fn main() {
-    // The following code is in a block so the MainPythonInterpreter is destroyed in an
-    // orderly manner, before process exit.
-    let code = {
-        // Load the default Python configuration as derived by the PyOxidizer config
-        // file used at build time.
-        let config = default_python_config();
-
-        // Construct a new Python interpreter using that config, handling any errors
-        // from construction.
-        match MainPythonInterpreter::new(config) {
-            Ok(mut interp) => {
-                // And run it using the default run configuration as specified by the
-                // configuration. If an uncaught Python exception is raised, handle it.
-                // This includes the special SystemExit, which is a request to terminate the
-                // process.
-                interp.run_as_main()
-            }
-            Err(msg) => {
-                eprintln!("{}", msg);
-                1
-            }
+{{/if~}}
+// Code between the BEGIN and END PYOXIDIZER GENERATED CODE markers is replaced
+// by `pyoxidizer upgrade-project`. Everything else is yours to edit.
+
+// BEGIN PYOXIDIZER GENERATED CODE: run-python
+/// Run the embedded Python interpreter and obtain the process exit code.
+///
+/// Functions registered with `pre_init_hooks` in the PyOxidizer config are
+/// called first and functions registered with `post_finalize_hooks` are
+/// called with the exit code once the interpreter is finalized. They must be
+/// defined with `#[no_mangle]`.
+fn run_python() -> i32 {
+    pyembed::run_pre_init_hooks();
+
+    // Load the default Python configuration as derived by the PyOxidizer config
+    // file used at build time.
+    let mut config = pyembed::default_python_config();
+
+    // Reserved arguments replace the run mode if `allow_runtime_code_override`
+    // is set in the PyOxidizer config. Otherwise this does nothing.
+    if let Err(msg) = pyembed::apply_runtime_code_override(&mut config) {
+        eprintln!("{}", msg);
+        return 1;
+    }
+
+    // Construct a new Python interpreter using that config, handling any errors
+    // from construction.
+    //
+    // If your application only needs Python some of the time (e.g. not when
+    // printing --help), use `MainPythonInterpreter::lazy(config)` instead and
+    // call `.acquire()` when Python is needed to avoid initializing it otherwise.
+    let code = match pyembed::MainPythonInterpreter::new(config) {
+        Ok(mut interp) => {
+            // And run it using the default run configuration as specified by the
+            // configuration. If an uncaught Python exception is raised, handle it.
+            // This includes the special SystemExit, which is a request to terminate the
+            // process.
+            let code = interp.run_as_main();
+
+            // Finalize the interpreter, waiting for non-daemon threads and calling
+            // `atexit` functions unless the config says otherwise. Like `python`,
+            // the exit code becomes 120 if finalization fails.
+            interp.finalize(code)
+        }
+        Err(msg) => {
+            eprintln!("{}", msg);
+            1
         }
     };
 
+    pyembed::run_post_finalize_hooks(code);
+
+    code
+}
+// END PYOXIDIZER GENERATED CODE: run-python
+
+fn main() {
+    // Code running before Python is initialized, e.g. custom argument
+    // parsing, goes here.
+
+    // The interpreter is destroyed in an orderly manner when run_python()
+    // returns, before process exit.
+    let code = run_python();
+
     // And exit the process according to code execution results.
     std::process::exit(code);
 }
@@ -55,7 +55,13 @@ embedded = dist.to_embedded_resources(
 
 {{#each pip_install_simple}}
 embedded.add_python_resources(dist.pip_install("{{{ this }}}"))
-{{/each}}
+{{/each}}{{#if python_package}}
+# Add the application's Python package from the app directory next to this
+# file.
+embedded.add_python_resources(dist.read_package_root(
+    path="app",
+    packages=["{{python_package}}"],
+)){{/if}}
 
 # Read Python files from a local directory and add them to our embedded
 # context, taking just the resources belonging to the `foo` and `bar`
@@ -76,7 +82,13 @@ embedded.add_python_resources(dist.pip_install("{{{ this }}}"))
 # This variable defines the configuration of the
 # embedded Python interpreter
 embedded_python_config = EmbeddedPythonConfig(
-#     bytes_warning=0,
+{{#if service}}    # Write log messages as they are emitted.
+    unbuffered_stdio=True,
+{{/if}}{{#if gui}}    # Without a console, write output to a log file next to the executable.
+    detached_stdio="logfile:$ORIGIN/{{program_name}}.log",
+{{/if}}{{#unless python_package}}    # Persist history of the interactive REPL across sessions.
+    repl_history_file="~/.{{program_name}}_history",
+{{/unless}}#     bytes_warning=0,
 #     dont_write_bytecode=True,
 #     ignore_environment=True,
 #     inspect=False,
@@ -90,16 +102,28 @@ embedded_python_config = EmbeddedPythonConfig(
 #     parser_debug=False,
 #     stdio_encoding=None,
 #     unbuffered_stdio=False,
+#     detached_stdio="null",
 #     filesystem_importer=False,
+#     importer_precedence="memory-first",
 #     sys_frozen=False,
 #     sys_meipass=False,
 #     sys_paths=None,
+#     sys_executable="exe",
+#     sys_prefix="$ORIGIN",
+#     run_python_marker=False,
+#     allow_runtime_code_override=False,
 #     raw_allocator=None,
 #     terminfo_resolution="dynamic",
 #     terminfo_dirs=None,
 #     use_hash_seed=False,
 #     verbose=0,
 #     write_modules_directory_env=None,
+#     import_profile_env=None,
+{{#if python_package}}#     repl_history_file=None,
+{{/if}}#     startup_module=None,
+#     startup_code=None,
+#     install_signal_handlers=True,
+#     pkg_resources_compat=False,
 )
 
 # What the Python interpreter should run by default. This value can be
@@ -122,6 +146,17 @@ python_run_mode = python_run_mode_eval(r"""{{{code}}}""")
 {{~else~}}
 #python_run_mode = python_run_mode_eval("from mypackage import main; main()")
 {{~/if}}
+{{#if python_package}}
+
+# Run the application's package.
+python_run_mode = python_run_mode_module("{{python_package}}.__main__")
+{{/if~}}
+{{#if lib}}
+
+# Embed the interpreter in a host application loading this library. The
+# host decides what code to run.
+python_run_mode = python_run_mode_embedding()
+{{/if}}
 
 # Produce a Python executable from a Python distribution, embedded
 # resources, and other options. The returned object represents the
@@ -154,6 +189,12 @@ Config(
     embedded_python_config=embedded_python_config,
     python_distribution=dist,
     python_run_mode=python_run_mode,
+    # Run modes selected by the name the executable is invoked as. Extra
+    # names are installed as links next to the executable. Other names
+    # use python_run_mode.
+    # multicall_run_modes={
+    #     "{{program_name}}-worker": python_run_mode_module("mypackage.worker"),
+    # },
 )
 
 # END OF COMMON USER-ADJUSTED SETTINGS.
@@ -2,14 +2,24 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
-use std::env;
-use std::path::PathBuf;
-use std::process;
+// Code between the BEGIN and END PYOXIDIZER GENERATED CODE markers is replaced
+// by `pyoxidizer upgrade-project`. Everything else is yours to edit. Extra
+// linker inputs can usually be declared with `link_search_paths` and
+// `link_libraries` in the PyOxidizer config instead.
 
+// BEGIN PYOXIDIZER GENERATED CODE: pyoxidizer-build
 /// Path to pyoxidizer executable this file was created with.
 const DEFAULT_PYOXIDIZER_EXE: &str = r#"{{{pyoxidizer_exe}}}"#;
 
-fn main() {
+/// Generate PyOxidizer artifacts and register them with cargo.
+fn pyoxidizer_build() {
+    use std::env;
+    use std::path::PathBuf;
+    use std::process;
+
+    // Enabled by the PyOxidizer config through the artifacts' cargo metadata.
+    println!("cargo:rustc-check-cfg=cfg(pyembed_runtime_code_override)");
+
     // We support using pre-built artifacts, in which case we emit the
     // cargo metadata lines from the "original" build to "register" the
     // artifacts with this cargo invocation.
@@ -63,3 +73,10 @@ fn main() {
         }
     }
 }
+// END PYOXIDIZER GENERATED CODE: pyoxidizer-build
+
+fn main() {
+    pyoxidizer_build();
+
+    // Your build logic goes here.
+}
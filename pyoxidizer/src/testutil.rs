@@ -5,6 +5,7 @@
 use anyhow::Result;
 use lazy_static::lazy_static;
 use slog::{Drain, Logger};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
 use crate::logging::PrintlnDrain;
@@ -30,7 +31,8 @@ lazy_static! {
         let target = env!("HOST");
 
         Arc::new(
-            default_distribution(&logger, target, path).expect("unable to obtain distribution"),
+            default_distribution(&logger, target, path, false)
+                .expect("unable to obtain distribution"),
         )
     };
 }
@@ -38,3 +40,73 @@ lazy_static! {
 pub fn get_default_distribution() -> Result<Arc<ParsedPythonDistribution>> {
     Ok(DEFAULT_DISTRIBUTION.clone())
 }
+
+/// Build a tar archive of files and symlinks.
+pub fn tar_fixture(files: &[(&str, &[u8])], symlinks: &[(&str, &str)]) -> Result<Vec<u8>> {
+    let mut builder = tar::Builder::new(Vec::new());
+
+    for (path, data) in files {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(data.len() as u64);
+        header.set_mode(0o644);
+        builder.append_data(&mut header, path, *data)?;
+    }
+
+    for (path, target) in symlinks {
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Symlink);
+        header.set_size(0);
+        header.set_mode(0o777);
+        header.set_link_name(target)?;
+        builder.append_data(&mut header, path, std::io::empty())?;
+    }
+
+    Ok(builder.into_inner()?)
+}
+
+/// Run an executable the way a GUI application without a console runs.
+///
+/// The standard streams are closed, so Python has no valid handles.
+#[cfg(unix)]
+pub fn run_detached(exe_path: &std::path::Path, args: &[&str]) -> Result<std::process::ExitStatus> {
+    use std::os::unix::process::CommandExt;
+
+    let mut cmd = std::process::Command::new(exe_path);
+    cmd.args(args);
+    unsafe {
+        cmd.pre_exec(|| {
+            libc::close(0);
+            libc::close(1);
+            libc::close(2);
+            Ok(())
+        });
+    }
+
+    Ok(cmd.status()?)
+}
+
+/// Write a tree of `count` modules in nested packages.
+pub fn generate_tree(root: &Path, count: usize) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+
+    for i in 0..count {
+        let dir = root
+            .join(format!("pkg{}", i % 10))
+            .join(format!("sub{}", i % 7));
+        std::fs::create_dir_all(&dir)?;
+
+        let path = dir.join(format!("mod{}.py", i));
+        std::fs::write(&path, format!("VALUE = {}\n", i))?;
+        files.push(path);
+    }
+
+    Ok(files)
+}
+
+/// cargo_metadata.txt lines for a tree, as written before collapsing.
+pub fn tree_metadata(root: &Path, files: &[PathBuf]) -> String {
+    std::iter::once(root)
+        .chain(files.iter().map(|p| p.as_path()))
+        .map(|p| format!("cargo:rerun-if-changed={}\n", p.display()))
+        .collect()
+}
@@ -350,7 +350,14 @@ fn configure_from_path(expected_version: &PythonVersion) -> Result<String, Strin
             //
             // static-nobundle removes the build-time library requirement,
             // allowing a downstream consumer to provide the pythonXY library.
-            println!("cargo:rustc-link-lib=static-nobundle=pythonXY");
+            //
+            // Rust 1.61 stabilized the equivalent -bundle link modifier,
+            // which doesn't need a Nightly rustc.
+            if rustc_supports_bundle_modifier() {
+                println!("cargo:rustc-link-lib=static:-bundle=pythonXY");
+            } else {
+                println!("cargo:rustc-link-lib=static-nobundle=pythonXY");
+            }
         }
     }
 
@@ -368,6 +375,27 @@ fn configure_from_path(expected_version: &PythonVersion) -> Result<String, Strin
     return Ok(interpreter_path);
 }
 
+/// Whether rustc accepts the `-bundle` link modifier (Rust 1.61+).
+fn rustc_supports_bundle_modifier() -> bool {
+    let rustc = env::var("RUSTC").unwrap_or_else(|_| "rustc".to_owned());
+    let output = match Command::new(rustc).arg("-V").output() {
+        Ok(output) => output,
+        Err(_) => return false,
+    };
+
+    let version = String::from_utf8_lossy(&output.stdout);
+    let re = Regex::new(r"^rustc (\d+)\.(\d+)\.").unwrap();
+
+    match re.captures(&version) {
+        Some(caps) => {
+            let major: u32 = caps[1].parse().unwrap_or(0);
+            let minor: u32 = caps[2].parse().unwrap_or(0);
+            (major, minor) >= (1, 61)
+        }
+        None => false,
+    }
+}
+
 /// Determine the python version we're supposed to be building
 /// from the features passed via the environment.
 ///